@@ -0,0 +1,40 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The synthetic inputs of the benchmarks.
+
+/// The sizes of the inputs, from a small header to a large object.
+pub const SIZES: &[usize] = &[1 << 10, 64 << 10, 1 << 20, 16 << 20];
+
+/// `size` bytes compressing about as well as an object file: symbol names
+/// and instructions repeated with variations, among noise.
+pub fn object(size: usize) -> Vec<u8> {
+    let mut object = Vec::with_capacity(size + 64);
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let mut i = 0;
+    while object.len() < size {
+        object.extend_from_slice(
+            format!("_ZN7project6module{}8function{}E", i % 97, i % 13).as_bytes(),
+        );
+        for _ in 0..4 {
+            // xorshift
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            object.extend_from_slice(&state.to_le_bytes());
+        }
+        object.extend_from_slice(b"\x55\x48\x89\xe5\x48\x83\xec\x10\xc9\xc3");
+        i += 1;
+    }
+    object.truncate(size);
+    object
+}
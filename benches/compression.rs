@@ -0,0 +1,94 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The compression of the objects of the cache entries: zstd at several
+//! levels, against the `COMPRESSION_LEVEL` the entries are written with, and
+//! the writing and reading of whole entries with and without compression.
+//!
+//! How small each level makes the objects is printed along, since that is
+//! the other half of the tradeoff.
+
+use std::io::Cursor;
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use sccache::cache::{CacheRead, CacheWrite, Compression, COMPRESSION_LEVEL};
+
+mod common;
+
+const LEVELS: &[i32] = &[1, COMPRESSION_LEVEL, 9, 19];
+
+/// The objects larger than this take too long at the highest levels.
+const MAX_SIZE: usize = 1 << 20;
+
+fn zstd_levels(c: &mut Criterion) {
+    let mut group = c.benchmark_group("zstd");
+    for &size in common::SIZES.iter().filter(|&&size| size <= MAX_SIZE) {
+        let object = common::object(size);
+        group.throughput(Throughput::Bytes(size as u64));
+        for &level in LEVELS {
+            let compressed = zstd::bulk::compress(&object, level).unwrap();
+            println!(
+                "zstd level {} compresses {} bytes to {} ({:.1}%)",
+                level,
+                size,
+                compressed.len(),
+                compressed.len() as f64 * 100.0 / size as f64
+            );
+            let id = format!("{}/level-{}", size, level);
+            group.bench_with_input(BenchmarkId::new("compress", &id), &object, |b, object| {
+                b.iter(|| zstd::bulk::compress(object, level).unwrap())
+            });
+            group.bench_with_input(
+                BenchmarkId::new("decompress", &id),
+                &compressed,
+                |b, compressed| b.iter(|| zstd::bulk::decompress(compressed, size).unwrap()),
+            );
+        }
+    }
+    group.finish();
+}
+
+fn entries(c: &mut Criterion) {
+    let mut group = c.benchmark_group("entry");
+    for &size in common::SIZES {
+        let object = common::object(size);
+        group.throughput(Throughput::Bytes(size as u64));
+        for compression in [Compression::Zstd, Compression::None] {
+            let write = || {
+                let mut entry = CacheWrite::with_compression(compression);
+                entry
+                    .put_object("obj", &mut Cursor::new(&object), None)
+                    .unwrap();
+                entry.finish().unwrap()
+            };
+            let id = format!("{}/{:?}", size, compression);
+            group.bench_function(BenchmarkId::new("write", &id), |b| b.iter(write));
+            let bytes = write();
+            group.bench_with_input(BenchmarkId::new("read", &id), &bytes, |b, bytes| {
+                b.iter_batched(
+                    || bytes.clone(),
+                    |bytes| {
+                        let mut entry = CacheRead::from(Cursor::new(bytes)).unwrap();
+                        let mut out = Vec::with_capacity(size);
+                        entry.get_object("obj", &mut out).unwrap();
+                        out
+                    },
+                    BatchSize::LargeInput,
+                )
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, zstd_levels, entries);
+criterion_main!(benches);
@@ -0,0 +1,49 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The hashing of the inputs of the cache keys, with the BLAKE3 `Digest`
+//! sccache uses and, for comparison, with SHA-256.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use sccache::util::Digest;
+use sha2::{Digest as _, Sha256};
+
+mod common;
+
+fn hashing(c: &mut Criterion) {
+    let mut group = c.benchmark_group("hash");
+    for &size in common::SIZES {
+        let input = common::object(size);
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new("blake3", size), &input, |b, input| {
+            b.iter(|| Digest::reader_sync(&input[..]).unwrap())
+        });
+        group.bench_with_input(
+            BenchmarkId::new("blake3-update", size),
+            &input,
+            |b, input| {
+                b.iter(|| {
+                    let mut digest = Digest::new();
+                    digest.update(input);
+                    digest.finish()
+                })
+            },
+        );
+        group.bench_with_input(BenchmarkId::new("sha256", size), &input, |b, input| {
+            b.iter(|| Sha256::digest(input))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, hashing);
+criterion_main!(benches);
@@ -0,0 +1,220 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The puts and gets of the local disk cache, in a temporary directory. The
+//! entries are stored uncompressed, so that only the storage is measured, see
+//! the `compression` benchmarks for the rest.
+//!
+//! With S3, the reads of a large entry from a remote cache on a link with a
+//! high latency, whole and in parts.
+
+use std::io::Cursor;
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use sccache::cache::disk::DiskCache;
+use sccache::cache::{
+    Cache, CacheMode, CacheWrite, Compression, PreprocessorCacheModeConfig, Storage,
+};
+
+mod common;
+
+/// The size of the cache, small enough for the entries put to be evicted
+/// rather than to fill the disk.
+const CACHE_SIZE: u64 = 512 << 20;
+
+fn entry(object: &[u8]) -> CacheWrite {
+    let mut entry = CacheWrite::with_compression(Compression::None);
+    entry
+        .put_object("obj", &mut Cursor::new(object), None)
+        .unwrap();
+    entry
+}
+
+fn storage(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let tempdir = tempfile::tempdir().unwrap();
+    let cache = DiskCache::new(
+        tempdir.path(),
+        CACHE_SIZE,
+        runtime.handle(),
+        PreprocessorCacheModeConfig::default(),
+        CacheMode::ReadWrite,
+    );
+
+    let mut group = c.benchmark_group("disk");
+    for &size in common::SIZES {
+        let object = common::object(size);
+        group.throughput(Throughput::Bytes(size as u64));
+
+        let mut count = 0u64;
+        group.bench_with_input(BenchmarkId::new("put", size), &object, |b, object| {
+            b.iter_batched(
+                || {
+                    count += 1;
+                    (format!("{:016x}{:08x}", count, size), entry(object))
+                },
+                |(key, entry)| runtime.block_on(cache.put(&key, entry)).unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+
+        let key = format!("{:024x}", size);
+        runtime.block_on(cache.put(&key, entry(&object))).unwrap();
+        group.bench_with_input(BenchmarkId::new("get", size), &key, |b, key| {
+            b.iter(|| match runtime.block_on(cache.get(key)).unwrap() {
+                Cache::Hit(mut entry) => {
+                    let mut out = Vec::with_capacity(size);
+                    entry.get_object("obj", &mut out).unwrap();
+                    out
+                }
+                _ => panic!("Expected a hit"),
+            })
+        });
+
+        group.bench_function(BenchmarkId::new("miss", size), |b| {
+            b.iter(|| runtime.block_on(cache.get("ffffffffffffffff")).unwrap())
+        });
+    }
+    group.finish();
+}
+
+/// Reading from an S3 bucket served locally over a simulated link, each
+/// response waiting for a round trip, and then for another one every
+/// `WINDOW` bytes, as TCP does on a link with a high latency.
+#[cfg(feature = "s3")]
+mod remote {
+    use std::time::Duration;
+
+    use criterion::{BenchmarkId, Criterion, Throughput};
+    use opendal::Operator;
+    use sccache::cache::multipart::MultipartDownload;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+
+    const RTT: Duration = Duration::from_millis(20);
+    const WINDOW: usize = 64 << 10;
+    const SIZE: usize = 4 << 20;
+
+    /// Answer the `GET`s and `HEAD`s of any path with `object`, or the range
+    /// of it requested.
+    async fn serve(stream: TcpStream, object: &'static [u8]) {
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).await.unwrap_or(0) == 0 {
+                return;
+            }
+            let mut range = None;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).await.unwrap();
+                let Some((name, value)) = header.trim_end().split_once(": ") else {
+                    break;
+                };
+                if name.eq_ignore_ascii_case("range") {
+                    let (first, last) = value["bytes=".len()..].split_once('-').unwrap();
+                    let first: usize = first.parse().unwrap();
+                    let last = last
+                        .parse()
+                        .map_or(object.len() - 1, |last: usize| last.min(object.len() - 1));
+                    range = Some(first..last + 1);
+                }
+            }
+            let (status, body, extra) = match range {
+                Some(range) => (
+                    206,
+                    &object[range.clone()],
+                    format!(
+                        "content-range: bytes {}-{}/{}\r\n",
+                        range.start,
+                        range.end - 1,
+                        object.len()
+                    ),
+                ),
+                None => (200, object, String::new()),
+            };
+            tokio::time::sleep(RTT).await;
+            let head = format!(
+                "HTTP/1.1 {} OK\r\ncontent-length: {}\r\netag: \"1\"\r\n{}\r\n",
+                status,
+                body.len(),
+                extra
+            );
+            writer.write_all(head.as_bytes()).await.unwrap();
+            if line.starts_with("HEAD") {
+                continue;
+            }
+            for chunk in body.chunks(WINDOW) {
+                writer.write_all(chunk).await.unwrap();
+                tokio::time::sleep(RTT).await;
+            }
+        }
+    }
+
+    pub fn remote(c: &mut Criterion) {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let entry = super::entry(&super::common::object(SIZE)).finish().unwrap();
+        let object: &'static [u8] = Vec::leak(entry);
+        let listener = runtime.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        runtime.spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(serve(stream, object));
+            }
+        });
+        let operator = {
+            let _guard = runtime.enter();
+            let mut builder = opendal::services::S3::default();
+            builder
+                .bucket("bucket")
+                .endpoint(&endpoint)
+                .region("us-east-1")
+                .allow_anonymous()
+                .disable_config_load()
+                .disable_ec2_metadata();
+            Operator::new(builder).unwrap().finish()
+        };
+
+        let mut group = c.benchmark_group("remote");
+        group.sample_size(10);
+        group.throughput(Throughput::Bytes(object.len() as u64));
+        let whole = MultipartDownload {
+            threshold: None,
+            part_size: 512 << 10,
+            concurrency: 8,
+        };
+        let parts = MultipartDownload {
+            threshold: Some(1 << 20),
+            ..whole
+        };
+        for (name, download) in [("whole", whole), ("parts", parts)] {
+            group.bench_function(BenchmarkId::new(name, SIZE), |b| {
+                b.iter(|| runtime.block_on(download.read(&operator, "entry")).unwrap())
+            });
+        }
+        group.finish();
+    }
+}
+
+#[cfg(feature = "s3")]
+criterion_group!(benches, storage, remote::remote);
+#[cfg(not(feature = "s3"))]
+criterion_group!(benches, storage);
+criterion_main!(benches);
@@ -0,0 +1,121 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reference helper for the custom storage backend (see `docs/Custom.md`).
+//!
+//! Stores every entry as a file in the directory named by `SCCACHE_CUSTOM_DIR`.
+//! Use it with `SCCACHE_CUSTOM_BACKEND=/path/to/custom_backend_dir`.
+
+use serde_json::{json, Value};
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+const PROTOCOL_VERSION: u64 = 1;
+
+/// Read a frame, returns `None` once sccache closed our stdin.
+fn read_frame(r: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    match r.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let mut buf = vec![0; u32::from_be_bytes(len) as usize];
+    r.read_exact(&mut buf)?;
+    Ok(Some(buf))
+}
+
+fn write_frame(w: &mut impl Write, data: &[u8]) -> io::Result<()> {
+    w.write_all(&(data.len() as u32).to_be_bytes())?;
+    w.write_all(data)
+}
+
+fn write_json(w: &mut impl Write, value: Value) -> io::Result<()> {
+    write_frame(w, value.to_string().as_bytes())
+}
+
+/// Map a key to a file, refusing anything that could escape the directory.
+fn entry_path(root: &Path, key: &str) -> Option<PathBuf> {
+    let valid = !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    valid.then(|| root.join(key))
+}
+
+fn main() -> io::Result<()> {
+    let root = PathBuf::from(env::var_os("SCCACHE_CUSTOM_DIR").unwrap_or_else(|| {
+        eprintln!("custom_backend_dir: SCCACHE_CUSTOM_DIR must be set");
+        std::process::exit(1)
+    }));
+    fs::create_dir_all(&root)?;
+
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let mut input = stdin.lock();
+    let mut output = stdout.lock();
+
+    while let Some(frame) = read_frame(&mut input)? {
+        let req: Value = serde_json::from_slice(&frame)?;
+        let op = req["op"].as_str().unwrap_or_default();
+        let path = req["key"].as_str().and_then(|key| entry_path(&root, key));
+        match (op, path) {
+            ("hello", _) => {
+                if req["version"].as_u64() != Some(PROTOCOL_VERSION) {
+                    eprintln!("custom_backend_dir: unsupported version {}", req["version"]);
+                }
+                write_json(
+                    &mut output,
+                    json!({"status": "hello", "version": PROTOCOL_VERSION}),
+                )?;
+            }
+            ("get", Some(path)) => match fs::read(path) {
+                Ok(data) => {
+                    write_json(&mut output, json!({"status": "hit"}))?;
+                    write_frame(&mut output, &data)?;
+                }
+                Err(_) => write_json(&mut output, json!({"status": "miss"}))?,
+            },
+            ("contains", Some(path)) => {
+                let status = if path.is_file() { "hit" } else { "miss" };
+                write_json(&mut output, json!({ "status": status }))?;
+            }
+            ("put", path) => {
+                let data = read_frame(&mut input)?.unwrap_or_default();
+                // Write to a temporary file first so that readers never see a
+                // partial entry.
+                let res = path
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid key"))
+                    .and_then(|path| {
+                        let tmp = path.with_extension("tmp");
+                        fs::write(&tmp, data)?;
+                        fs::rename(tmp, path)
+                    });
+                match res {
+                    Ok(()) => write_json(&mut output, json!({"status": "ok"}))?,
+                    Err(e) => write_json(
+                        &mut output,
+                        json!({"status": "error", "message": e.to_string()}),
+                    )?,
+                }
+            }
+            (op, _) => write_json(
+                &mut output,
+                json!({"status": "error", "message": format!("invalid request {op:?}")}),
+            )?,
+        }
+        output.flush()?;
+    }
+    Ok(())
+}
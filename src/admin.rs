@@ -0,0 +1,260 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The admin socket of the server, `SCCACHE_ADMIN_ADDR`, which takes the
+//! requests managing the server, e.g. to get or zero its statistics, flush
+//! its cache writes or shut it down, while the socket of the clients only
+//! takes their compilations. The socket of the clients can then be reachable
+//! by many users while the admin one is restricted, e.g. a Unix socket only
+//! the user running the server may connect to.
+
+use std::env;
+use std::io;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+
+use crate::access_log::Peer;
+use crate::protocol::Request;
+use crate::server::{bind_listener, Acceptor};
+
+/// Where the admin socket listens.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AdminAddr {
+    Tcp(SocketAddr),
+    /// The path of a Unix socket, which the server creates readable and
+    /// writable by its user only.
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+/// Get the address of the admin socket from `SCCACHE_ADMIN_ADDR`, a
+/// `host:port` or, on Unix, the path of a Unix socket.
+pub fn addr() -> Option<AdminAddr> {
+    let value = env::var_os("SCCACHE_ADMIN_ADDR").filter(|value| !value.is_empty())?;
+    if let Some(addr) = value.to_str().and_then(|value| value.parse().ok()) {
+        return Some(AdminAddr::Tcp(addr));
+    }
+    #[cfg(unix)]
+    return Some(AdminAddr::Unix(value.into()));
+    #[cfg(not(unix))]
+    {
+        warn!("Invalid SCCACHE_ADMIN_ADDR {:?}, not using it", value);
+        None
+    }
+}
+
+/// Listen on the admin socket at `addr`, replacing the Unix socket a
+/// previous server left, if any. Fails with `AddrInUse` if another server
+/// listens on it, or if the path isn't a socket.
+pub async fn bind(addr: &AdminAddr) -> io::Result<Acceptor> {
+    match addr {
+        AdminAddr::Tcp(addr) => Ok(bind_listener(*addr, 16).await?.into()),
+        #[cfg(unix)]
+        AdminAddr::Unix(path) => {
+            remove_stale_socket(path)?;
+            // Create the socket readable and writable by the user only, rather
+            // than changing its mode once others may have connected.
+            let umask = unsafe { libc::umask(0o177) };
+            let listener = std::os::unix::net::UnixListener::bind(path);
+            unsafe { libc::umask(umask) };
+            let listener = listener?;
+            listener.set_nonblocking(true)?;
+            Ok(Acceptor::Unix(tokio::net::UnixListener::from_std(
+                listener,
+            )?))
+        }
+    }
+}
+
+/// Remove the Unix socket at `addr`, once the server stopped listening on
+/// it, unless another server listens on it by now.
+pub fn unbind(addr: &AdminAddr) {
+    #[cfg(unix)]
+    if let AdminAddr::Unix(path) = addr {
+        let _ = remove_stale_socket(path);
+    }
+    #[cfg(not(unix))]
+    let _ = addr;
+}
+
+/// Remove the Unix socket at `path` if no server listens on it anymore. Any
+/// other file is left for binding to fail with `AddrInUse`.
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {}
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => return Ok(()),
+    }
+    match std::os::unix::net::UnixStream::connect(path) {
+        Ok(_) => Err(io::ErrorKind::AddrInUse.into()),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => std::fs::remove_file(path),
+        Err(e) => Err(e),
+    }
+}
+
+/// What the connections of a socket of the server may request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    /// Anything, without an admin socket.
+    All,
+    /// Compilations only, besides the admin socket.
+    Compile,
+    /// The admin requests only, on the admin socket.
+    Admin,
+}
+
+/// Whether `request` manages the server rather than compiles.
+fn is_admin_request(request: &Request) -> bool {
+    match request {
+        Request::ZeroStats
+        | Request::GetStats
+        | Request::DistStatus
+        | Request::Flush
+        | Request::Shutdown
+        | Request::ListEntries(_)
+        | Request::RemoveEntry(_) => true,
+        Request::Authenticate(_)
+        | Request::Compile(_)
+        | Request::Explain(..)
+        | Request::Stdin(_) => false,
+    }
+}
+
+impl Role {
+    /// Check that `peer` may send `request` on a socket of this role, or
+    /// return why not.
+    pub fn check(self, peer: &Peer, request: &Request) -> Result<(), &'static str> {
+        match self {
+            Role::All => Ok(()),
+            Role::Compile if is_admin_request(request) => {
+                Err("The server takes the admin requests on SCCACHE_ADMIN_ADDR only")
+            }
+            Role::Compile => Ok(()),
+            Role::Admin if !is_peer_allowed(peer) => {
+                Err("The admin socket only takes the requests of the user running the server")
+            }
+            Role::Admin if is_admin_request(request) => Ok(()),
+            Role::Admin if matches!(request, Request::Authenticate(_)) => Ok(()),
+            Role::Admin => Err("The admin socket doesn't take compilations"),
+        }
+    }
+}
+
+/// Whether `peer` may use the admin socket: on a Unix socket, only the user
+/// running the server and root may, by the credentials of their process. On
+/// TCP, anyone who has the token of the server may.
+fn is_peer_allowed(peer: &Peer) -> bool {
+    #[cfg(unix)]
+    if let Some(uid) = peer.uid {
+        return uid == 0 || uid == unsafe { libc::geteuid() };
+    }
+    #[cfg(not(unix))]
+    let _ = peer;
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serial_test::serial;
+
+    #[test]
+    #[serial]
+    fn test_addr() {
+        env::remove_var("SCCACHE_ADMIN_ADDR");
+        assert_eq!(addr(), None);
+        env::set_var("SCCACHE_ADMIN_ADDR", "127.0.0.1:4227");
+        assert_eq!(
+            addr(),
+            Some(AdminAddr::Tcp("127.0.0.1:4227".parse().unwrap()))
+        );
+        #[cfg(unix)]
+        {
+            env::set_var("SCCACHE_ADMIN_ADDR", "/run/sccache/admin.sock");
+            assert_eq!(
+                addr(),
+                Some(AdminAddr::Unix("/run/sccache/admin.sock".into()))
+            );
+        }
+        env::remove_var("SCCACHE_ADMIN_ADDR");
+    }
+
+    #[test]
+    fn test_role_check() {
+        let tcp = Peer::default();
+        let compile = Request::Stdin(vec![]);
+        for request in [&Request::GetStats, &Request::Shutdown, &compile] {
+            assert!(Role::All.check(&tcp, request).is_ok());
+        }
+        assert!(Role::Compile.check(&tcp, &compile).is_ok());
+        assert!(Role::Compile.check(&tcp, &Request::Flush).is_err());
+        assert!(Role::Admin.check(&tcp, &Request::Flush).is_ok());
+        assert!(Role::Admin.check(&tcp, &compile).is_err());
+        assert!(Role::Admin
+            .check(&tcp, &Request::Authenticate("token".into()))
+            .is_ok());
+
+        #[cfg(unix)]
+        {
+            let peer = |uid| Peer {
+                addr: "unix".to_owned(),
+                uid: Some(uid),
+                pid: None,
+            };
+            let uid = unsafe { libc::geteuid() };
+            assert!(Role::Admin.check(&peer(uid), &Request::GetStats).is_ok());
+            assert!(Role::Admin.check(&peer(0), &Request::GetStats).is_ok());
+            assert!(Role::Admin
+                .check(&peer(uid + 1), &Request::GetStats)
+                .is_err());
+            // The compilations of the other users are taken on the other socket.
+            assert!(Role::Compile.check(&peer(uid + 1), &compile).is_ok());
+        }
+    }
+
+    #[tokio::test]
+    #[cfg(unix)]
+    async fn test_bind_unix() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("admin.sock");
+        let addr = AdminAddr::Unix(path.clone());
+        let listener = bind(&addr).await.unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // Another server can't take over the socket, nor remove it.
+        let err = bind(&addr).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        unbind(&addr);
+        assert!(path.exists());
+
+        // But replaces the socket once no server listens on it.
+        drop(listener);
+        let listener = bind(&addr).await.unwrap();
+        drop(listener);
+        unbind(&addr);
+        assert!(!path.exists());
+
+        // Any other file is left alone.
+        std::fs::write(&path, "").unwrap();
+        let err = bind(&addr).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        unbind(&addr);
+        assert!(path.exists());
+    }
+}
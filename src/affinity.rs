@@ -0,0 +1,286 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Pinning the threads of the server to the cores of
+//! `SCCACHE_SERVER_CPU_AFFINITY`, e.g. `0-1`, to keep them off the cores of
+//! the compilers, on Linux and Windows. It does nothing elsewhere.
+//!
+//! The compilers the server runs aren't pinned with it: they run on the cores
+//! of `SCCACHE_COMPILER_CPU_AFFINITY`, and on those the server started on
+//! without it. On Linux, a child starts on the cores of the thread spawning
+//! it, which are set back before it runs the compiler. On Windows, a child
+//! starts on the cores of its parent process, which the server leaves alone,
+//! and is moved to the compiler cores right after it is spawned.
+
+use once_cell::sync::Lazy;
+use std::env;
+use std::sync::Once;
+
+use crate::errors::*;
+
+/// The cores past the last one the affinity can be set to, the size of a
+/// `cpu_set_t` on Linux, and of the mask of a processor group on Windows.
+#[cfg(windows)]
+const MAX_CORES: usize = usize::BITS as usize;
+#[cfg(not(windows))]
+const MAX_CORES: usize = 1024;
+
+/// The cores of `SCCACHE_SERVER_CPU_AFFINITY`.
+static SERVER_CORES: Lazy<Option<Vec<usize>>> =
+    Lazy::new(|| cores_from_env("SCCACHE_SERVER_CPU_AFFINITY"));
+
+/// The affinity of the compilers, if it has to be set: the cores of
+/// `SCCACHE_COMPILER_CPU_AFFINITY`, or, with server cores, those the server
+/// started on.
+static COMPILER_AFFINITY: Lazy<Option<imp::Affinity>> = Lazy::new(|| {
+    if let Some(cores) = cores_from_env("SCCACHE_COMPILER_CPU_AFFINITY") {
+        return Some(imp::Affinity::new(&cores));
+    }
+    SERVER_CORES.as_ref()?;
+    imp::Affinity::current()
+});
+
+fn cores_from_env(name: &str) -> Option<Vec<usize>> {
+    let cores = env::var(name).ok()?;
+    match parse_cores(&cores) {
+        Ok(cores) => Some(cores),
+        Err(e) => {
+            warn!("Ignoring {}={:?}: {:#}", name, cores, e);
+            None
+        }
+    }
+}
+
+/// Parse a comma-separated list of cores and ranges of cores, e.g. `0-3,8`.
+fn parse_cores(cores: &str) -> Result<Vec<usize>> {
+    let mut list = vec![];
+    for range in cores.split(',').map(str::trim) {
+        let (first, last) = range.split_once('-').unwrap_or((range, range));
+        let core = |core: &str| {
+            core.trim()
+                .parse::<usize>()
+                .with_context(|| format!("invalid core {:?}", core))
+        };
+        let (first, last) = (core(first)?, core(last)?);
+        if first > last {
+            bail!("invalid range of cores {:?}", range);
+        }
+        if last >= MAX_CORES {
+            bail!(
+                "core {} is past the last supported, {}",
+                last,
+                MAX_CORES - 1
+            );
+        }
+        list.extend(first..=last);
+    }
+    list.sort_unstable();
+    list.dedup();
+    Ok(list)
+}
+
+/// Pin the current thread to the cores of `SCCACHE_SERVER_CPU_AFFINITY`, if
+/// set, as the server does for its threads.
+pub fn pin_server_thread() {
+    let Some(cores) = &*SERVER_CORES else {
+        return;
+    };
+    // Get the affinity of the compilers before that of the server replaces it.
+    Lazy::force(&COMPILER_AFFINITY);
+    if let Err(e) = imp::Affinity::new(cores).set_current_thread() {
+        static WARNING: Once = Once::new();
+        WARNING.call_once(|| warn!("Failed to pin the server to {:?}: {}", cores, e));
+    }
+}
+
+/// Make `command` run on the cores of the compilers. Only Linux needs it.
+pub fn configure_compiler(command: &mut std::process::Command) {
+    #[cfg(target_os = "linux")]
+    if let Some(affinity) = *COMPILER_AFFINITY {
+        affinity.configure(command);
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = command;
+}
+
+/// Move `child`, a compiler just spawned, to the cores of the compilers.
+/// Only Windows needs it.
+pub fn pin_compiler(child: &tokio::process::Child) {
+    #[cfg(windows)]
+    if let Some(affinity) = *COMPILER_AFFINITY {
+        if let Err(e) = affinity.set_process(child) {
+            debug!("Failed to set the affinity of the compiler: {}", e);
+        }
+    }
+    #[cfg(not(windows))]
+    let _ = child;
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::io;
+    use std::mem;
+    use std::os::unix::process::CommandExt;
+
+    #[derive(Clone, Copy)]
+    pub struct Affinity(pub libc::cpu_set_t);
+
+    impl Affinity {
+        pub fn new(cores: &[usize]) -> Affinity {
+            unsafe {
+                let mut set: libc::cpu_set_t = mem::zeroed();
+                for &core in cores {
+                    libc::CPU_SET(core, &mut set);
+                }
+                Affinity(set)
+            }
+        }
+
+        /// The affinity of the current thread.
+        pub fn current() -> Option<Affinity> {
+            unsafe {
+                let mut set: libc::cpu_set_t = mem::zeroed();
+                if libc::sched_getaffinity(0, mem::size_of_val(&set), &mut set) != 0 {
+                    return None;
+                }
+                Some(Affinity(set))
+            }
+        }
+
+        pub fn set_current_thread(&self) -> io::Result<()> {
+            set_current_thread(&self.0)
+        }
+
+        /// Set the affinity of the process `command` starts, before it runs
+        /// the program.
+        pub fn configure(self, command: &mut std::process::Command) {
+            // Only a system call, which is safe between fork and exec.
+            unsafe { command.pre_exec(move || set_current_thread(&self.0)) };
+        }
+    }
+
+    fn set_current_thread(set: &libc::cpu_set_t) -> io::Result<()> {
+        match unsafe { libc::sched_setaffinity(0, mem::size_of_val(set), set) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use std::io;
+    use winapi::um::processthreadsapi::GetCurrentThread;
+    use winapi::um::winbase::{SetProcessAffinityMask, SetThreadAffinityMask};
+
+    #[derive(Clone, Copy)]
+    pub struct Affinity(usize);
+
+    impl Affinity {
+        pub fn new(cores: &[usize]) -> Affinity {
+            Affinity(cores.iter().fold(0, |mask, core| mask | 1 << core))
+        }
+
+        /// The compilers already start on the cores of the server process,
+        /// which its threads being pinned doesn't change.
+        pub fn current() -> Option<Affinity> {
+            None
+        }
+
+        pub fn set_current_thread(&self) -> io::Result<()> {
+            match unsafe { SetThreadAffinityMask(GetCurrentThread(), self.0) } {
+                0 => Err(io::Error::last_os_error()),
+                _ => Ok(()),
+            }
+        }
+
+        pub fn set_process(&self, child: &tokio::process::Child) -> io::Result<()> {
+            let Some(handle) = child.raw_handle() else {
+                return Ok(());
+            };
+            match unsafe { SetProcessAffinityMask(handle as _, self.0) } {
+                0 => Err(io::Error::last_os_error()),
+                _ => Ok(()),
+            }
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+mod imp {
+    use std::io;
+
+    #[derive(Clone, Copy)]
+    pub struct Affinity;
+
+    impl Affinity {
+        pub fn new(_cores: &[usize]) -> Affinity {
+            Affinity
+        }
+
+        pub fn current() -> Option<Affinity> {
+            None
+        }
+
+        pub fn set_current_thread(&self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_cores() {
+        assert_eq!(parse_cores("3").unwrap(), vec![3]);
+        assert_eq!(parse_cores("0-3, 8,2").unwrap(), vec![0, 1, 2, 3, 8]);
+        assert!(parse_cores("").is_err());
+        assert!(parse_cores("0-").is_err());
+        assert!(parse_cores("3-1").is_err());
+        assert!(parse_cores("first").is_err());
+        assert!(parse_cores(&MAX_CORES.to_string()).is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_compiler_affinity() {
+        use std::process::Command;
+
+        let cores_allowed = |command: &mut Command| {
+            let output = command
+                .args(["Cpus_allowed_list", "/proc/self/status"])
+                .output()
+                .unwrap();
+            String::from_utf8(output.stdout).unwrap()
+        };
+        let all = cores_allowed(&mut Command::new("grep"));
+        let original = imp::Affinity::current().unwrap();
+        let first = (0..MAX_CORES)
+            .find(|&core| unsafe { libc::CPU_ISSET(core, &original.0) })
+            .unwrap();
+        std::thread::spawn(move || {
+            imp::Affinity::new(&[first]).set_current_thread().unwrap();
+            assert_eq!(
+                cores_allowed(&mut Command::new("grep")),
+                format!("Cpus_allowed_list:\t{}\n", first)
+            );
+            // The compilers don't inherit the cores of the server.
+            let mut command = Command::new("grep");
+            original.configure(&mut command);
+            assert_eq!(cores_allowed(&mut command), all);
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -97,7 +97,7 @@ struct OverlaySpec {
 struct DeflatedToolchain {
     path: PathBuf,
     build_count: u64,
-    ctime: Instant,
+    last_used: Instant,
 }
 
 pub struct OverlayBuilder {
@@ -173,7 +173,7 @@ impl OverlayBuilder {
         let DeflatedToolchain {
             path: toolchain_dir,
             build_count: id,
-            ctime: _,
+            last_used: _,
         } = {
             let mut toolchain_dir_map = self.toolchain_dir_map.lock().unwrap();
             // Create the toolchain dir (if necessary) while we have an exclusive lock
@@ -184,6 +184,7 @@ impl OverlayBuilder {
                     .get_mut(tc)
                     .expect("Key missing after checking");
                 entry.build_count += 1;
+                entry.last_used = Instant::now();
                 entry.clone()
             } else {
                 trace!("Creating toolchain directory for {}", tc.archive_id);
@@ -215,18 +216,17 @@ impl OverlayBuilder {
                 let entry = DeflatedToolchain {
                     path: toolchain_dir,
                     build_count: 1,
-                    ctime: Instant::now(),
+                    last_used: Instant::now(),
                 };
 
                 toolchain_dir_map.insert(tc.clone(), entry.clone());
                 if toolchain_dir_map.len() > tccache.len() {
                     let dir_map = toolchain_dir_map.clone();
                     let mut entries: Vec<_> = dir_map.iter().collect();
-                    // In the pathological case, creation time for unpacked
-                    // toolchains could be the opposite of the least recently
-                    // recently used, so we clear out half of the accumulated
-                    // toolchains to prevent repeated sort/delete cycles.
-                    entries.sort_by(|a, b| (a.1).ctime.cmp(&(b.1).ctime));
+                    // Keep the most recently used toolchains unpacked, and
+                    // clear out half of the accumulated ones to prevent
+                    // repeated sort/delete cycles.
+                    entries.sort_by(|a, b| (a.1).last_used.cmp(&(b.1).last_used));
                     entries.truncate(entries.len() / 2);
                     for (tc, _) in entries {
                         warn!("Removing old un-compressed toolchain: {:?}", tc);
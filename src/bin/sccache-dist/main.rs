@@ -9,17 +9,18 @@ use sccache::config::{
 };
 use sccache::dist::{
     self, AllocJobResult, AssignJobResult, BuilderIncoming, CompileCommand, HeartbeatServerResult,
-    InputsReader, JobAlloc, JobAuthorizer, JobComplete, JobId, JobState, RunJobResult,
-    SchedulerIncoming, SchedulerOutgoing, SchedulerStatusResult, ServerId, ServerIncoming,
-    ServerNonce, ServerOutgoing, SubmitToolchainResult, TcCache, Toolchain, ToolchainReader,
-    UpdateJobStateResult,
+    InputsReader, JobAlloc, JobAuthorizer, JobComplete, JobId, JobOutcome, JobState,
+    JobStatusResult, RunJobResult, SchedulerIncoming, SchedulerOutgoing, SchedulerStatusResult,
+    ServerId, ServerIncoming, ServerNonce, ServerOutgoing, ServerStatusResult,
+    SubmitToolchainResult, TcCache, Toolchain, ToolchainReader, UpdateJobStateResult,
 };
 use sccache::util::daemonize;
 use sccache::util::BASE64_URL_SAFE_ENGINE;
 use serde::{Deserialize, Serialize};
-use std::collections::{btree_map, BTreeMap, HashMap, HashSet};
+use std::collections::{btree_map, BTreeMap, HashMap, HashSet, VecDeque};
 use std::env;
 use std::io;
+use std::net::IpAddr;
 use std::path::Path;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Mutex, MutexGuard};
@@ -156,6 +157,7 @@ fn run(command: Command) -> Result<i32> {
             public_addr,
             client_auth,
             server_auth,
+            toolchain_affinity,
         }) => {
             let check_client_auth: Box<dyn dist::http::ClientAuthCheck> = match client_auth {
                 scheduler_config::ClientAuth::Insecure => Box::new(token_check::EqCheck::new(
@@ -210,7 +212,7 @@ fn run(command: Command) -> Result<i32> {
             };
 
             daemonize()?;
-            let scheduler = Scheduler::new();
+            let scheduler = Scheduler::new(toolchain_affinity);
             let http_scheduler = dist::http::Scheduler::new(
                 public_addr,
                 scheduler,
@@ -313,21 +315,41 @@ const MAX_PER_CORE_LOAD: f64 = 10f64;
 const SERVER_REMEMBER_ERROR_TIMEOUT: Duration = Duration::from_secs(300);
 const UNCLAIMED_PENDING_TIMEOUT: Duration = Duration::from_secs(300);
 const UNCLAIMED_READY_TIMEOUT: Duration = Duration::from_secs(60);
+// How many finished jobs are remembered across all the clients, and how many
+// of them are reported to a client asking for the status
+const MAX_RECENT_JOBS: usize = 1000;
+const MAX_RECENT_JOBS_PER_CLIENT: usize = 20;
+// How many toolchains the scheduler remembers a server having, the most
+// recently assigned
+const MAX_TOOLCHAINS_PER_SERVER: usize = 64;
 
 #[derive(Copy, Clone)]
 struct JobDetail {
+    client: IpAddr,
     server_id: ServerId,
     state: JobState,
 }
 
+struct RecentJob {
+    client: IpAddr,
+    status: JobStatusResult,
+}
+
 // To avoid deadlicking, make sure to do all locking at once (i.e. no further locking in a downward scope),
 // in alphabetical order
 pub struct Scheduler {
     job_count: AtomicUsize,
 
+    // How much more loaded a server having the toolchain of a job may be than
+    // the least loaded one, and still get it
+    toolchain_affinity: f64,
+
     // Currently running jobs, can never be Complete
     jobs: Mutex<BTreeMap<JobId, JobDetail>>,
 
+    // Finished jobs, oldest first
+    recent_jobs: Mutex<VecDeque<RecentJob>>,
+
     servers: Mutex<HashMap<ServerId, ServerDetails>>,
 }
 
@@ -341,21 +363,70 @@ struct ServerDetails {
     num_cpus: usize,
     server_nonce: ServerNonce,
     job_authorizer: Box<dyn JobAuthorizer>,
+    // The toolchains the server has, as of when a job was last assigned with
+    // them. The server may have evicted them since.
+    toolchains: HashMap<String, Instant>,
+    toolchain_hits: u64,
+    toolchain_misses: u64,
+}
+
+impl ServerDetails {
+    fn record_toolchain(&mut self, tc: &Toolchain, need_toolchain: bool) {
+        if need_toolchain {
+            self.toolchain_misses += 1;
+        } else {
+            self.toolchain_hits += 1;
+        }
+        self.toolchains
+            .insert(tc.archive_id.clone(), Instant::now());
+        if self.toolchains.len() > MAX_TOOLCHAINS_PER_SERVER {
+            let oldest = self
+                .toolchains
+                .iter()
+                .min_by_key(|(_, &last_assigned)| last_assigned)
+                .map(|(archive_id, _)| archive_id.clone())
+                .expect("No toolchain to forget");
+            self.toolchains.remove(&oldest);
+        }
+    }
 }
 
 impl Scheduler {
-    pub fn new() -> Self {
+    pub fn new(toolchain_affinity: f64) -> Self {
         Scheduler {
             job_count: AtomicUsize::new(0),
+            toolchain_affinity,
             jobs: Mutex::new(BTreeMap::new()),
+            recent_jobs: Mutex::new(VecDeque::new()),
             servers: Mutex::new(HashMap::new()),
         }
     }
 
+    fn record_job(
+        recent_jobs: &mut MutexGuard<VecDeque<RecentJob>>,
+        client: IpAddr,
+        job_id: JobId,
+        server_id: ServerId,
+        outcome: JobOutcome,
+    ) {
+        if recent_jobs.len() == MAX_RECENT_JOBS {
+            recent_jobs.pop_front();
+        }
+        recent_jobs.push_back(RecentJob {
+            client,
+            status: JobStatusResult {
+                job_id,
+                server_id,
+                outcome,
+            },
+        });
+    }
+
     fn prune_servers(
         &self,
         servers: &mut MutexGuard<HashMap<ServerId, ServerDetails>>,
         jobs: &mut MutexGuard<BTreeMap<JobId, JobDetail>>,
+        recent_jobs: &mut MutexGuard<VecDeque<RecentJob>>,
     ) {
         let now = Instant::now();
 
@@ -382,11 +453,18 @@ impl Scheduler {
                 );
                 // A job may be missing here if it failed to allocate
                 // initially, so just warn if it's not present.
-                if jobs.remove(&job_id).is_none() {
-                    warn!(
+                match jobs.remove(&job_id) {
+                    Some(detail) => Self::record_job(
+                        recent_jobs,
+                        detail.client,
+                        job_id,
+                        server_id,
+                        JobOutcome::Lost,
+                    ),
+                    None => warn!(
                         "Non-terminated job {} assignment originally failed.",
                         job_id
-                    );
+                    ),
                 }
             }
         }
@@ -395,7 +473,7 @@ impl Scheduler {
 
 impl Default for Scheduler {
     fn default() -> Self {
-        Self::new()
+        Self::new(scheduler_config::DEFAULT_TOOLCHAIN_AFFINITY)
     }
 }
 
@@ -403,6 +481,7 @@ impl SchedulerIncoming for Scheduler {
     fn handle_alloc_job(
         &self,
         requester: &dyn SchedulerOutgoing,
+        client: IpAddr,
         tc: Toolchain,
     ) -> Result<AllocJobResult> {
         let (job_id, server_id, auth) = {
@@ -413,6 +492,9 @@ impl SchedulerIncoming for Scheduler {
                 let mut best = None;
                 let mut best_err = None;
                 let mut best_load: f64 = MAX_PER_CORE_LOAD;
+                // The least loaded of the servers having the toolchain
+                let mut best_cached = None;
+                let mut best_cached_load: f64 = MAX_PER_CORE_LOAD;
                 let now = Instant::now();
                 for (&server_id, details) in servers.iter_mut() {
                     let load = details.jobs_assigned.len() as f64 / details.num_cpus as f64;
@@ -449,18 +531,41 @@ impl SchedulerIncoming for Scheduler {
                                 }
                             }
                         }
-                    } else if load < best_load {
-                        best = Some((server_id, details));
-                        trace!("Selected {:?} as the server with the best load", server_id);
-                        best_load = load;
-                        if load == 0f64 {
-                            break;
+                    } else {
+                        let has_toolchain = details.toolchains.contains_key(&tc.archive_id);
+                        if has_toolchain && load < best_cached_load {
+                            best_cached = Some(server_id);
+                            best_cached_load = load;
+                        }
+                        if load < best_load {
+                            best = Some((server_id, details));
+                            trace!("Selected {:?} as the server with the best load", server_id);
+                            best_load = load;
+                            if load == 0f64 && has_toolchain {
+                                break;
+                            }
                         }
                     }
                 }
 
+                // Prefer a server which doesn't need the toolchain, unless
+                // it is too loaded
+                let choice = match best_cached {
+                    Some(server_id) if best_cached_load <= best_load + self.toolchain_affinity => {
+                        trace!(
+                            "Selected {:?} as the server with the toolchain {}",
+                            server_id,
+                            tc.archive_id
+                        );
+                        servers
+                            .get_mut(&server_id)
+                            .map(|details| (server_id, details))
+                    }
+                    _ => best.or(best_err),
+                };
+
                 // Assign the job to our best choice
-                if let Some((server_id, server_details)) = best.or(best_err) {
+                if let Some((server_id, server_details)) = choice {
                     let job_count = self.job_count.fetch_add(1, Ordering::SeqCst) as u64;
                     let job_id = JobId(job_count);
                     assert!(server_details.jobs_assigned.insert(job_id));
@@ -498,10 +603,18 @@ impl SchedulerIncoming for Scheduler {
             state,
             need_toolchain,
         } = requester
-            .do_assign_job(server_id, job_id, tc, auth.clone())
+            .do_assign_job(server_id, job_id, tc.clone(), auth.clone())
             .with_context(|| {
                 // LOCKS
+                let mut recent_jobs = self.recent_jobs.lock().unwrap();
                 let mut servers = self.servers.lock().unwrap();
+                Self::record_job(
+                    &mut recent_jobs,
+                    client,
+                    job_id,
+                    server_id,
+                    JobOutcome::AssignFailed,
+                );
                 if let Some(entry) = servers.get_mut(&server_id) {
                     entry.last_error = Some(Instant::now());
                     entry.jobs_unclaimed.remove(&job_id);
@@ -517,13 +630,24 @@ impl SchedulerIncoming for Scheduler {
         {
             // LOCKS
             let mut jobs = self.jobs.lock().unwrap();
+            let mut servers = self.servers.lock().unwrap();
 
+            if let Some(details) = servers.get_mut(&server_id) {
+                details.record_toolchain(&tc, need_toolchain);
+            }
             info!(
                 "Job {} successfully assigned and saved with state {:?}",
                 job_id, state
             );
             assert!(jobs
-                .insert(job_id, JobDetail { server_id, state })
+                .insert(
+                    job_id,
+                    JobDetail {
+                        client,
+                        server_id,
+                        state
+                    }
+                )
                 .is_none());
         }
         let job_alloc = JobAlloc {
@@ -550,9 +674,10 @@ impl SchedulerIncoming for Scheduler {
 
         // LOCKS
         let mut jobs = self.jobs.lock().unwrap();
+        let mut recent_jobs = self.recent_jobs.lock().unwrap();
         let mut servers = self.servers.lock().unwrap();
 
-        self.prune_servers(&mut servers, &mut jobs);
+        self.prune_servers(&mut servers, &mut jobs, &mut recent_jobs);
 
         match servers.get_mut(&server_id) {
             Some(ref mut details) if details.server_nonce == server_nonce => {
@@ -605,12 +730,19 @@ impl SchedulerIncoming for Scheduler {
                                 job_id
                             );
                         }
-                        if jobs.remove(&job_id).is_none() {
-                            warn!(
+                        match jobs.remove(&job_id) {
+                            Some(detail) => Self::record_job(
+                                &mut recent_jobs,
+                                detail.client,
+                                job_id,
+                                server_id,
+                                JobOutcome::Lost,
+                            ),
+                            None => warn!(
                                 "Unknown stale job for server {}: {}",
                                 server_id.addr(),
                                 job_id
-                            );
+                            ),
                         }
                     }
                 }
@@ -618,13 +750,20 @@ impl SchedulerIncoming for Scheduler {
                 return Ok(HeartbeatServerResult { is_new: false });
             }
             Some(ref mut details) if details.server_nonce != server_nonce => {
-                for job_id in details.jobs_assigned.iter() {
-                    if jobs.remove(job_id).is_none() {
-                        warn!(
+                for &job_id in details.jobs_assigned.iter() {
+                    match jobs.remove(&job_id) {
+                        Some(detail) => Self::record_job(
+                            &mut recent_jobs,
+                            detail.client,
+                            job_id,
+                            server_id,
+                            JobOutcome::Lost,
+                        ),
+                        None => warn!(
                             "Unknown job found when replacing server {}: {}",
                             server_id.addr(),
                             job_id
-                        );
+                        ),
                     }
                 }
             }
@@ -641,6 +780,9 @@ impl SchedulerIncoming for Scheduler {
                 num_cpus,
                 server_nonce,
                 job_authorizer,
+                toolchains: HashMap::new(),
+                toolchain_hits: 0,
+                toolchain_misses: 0,
             },
         );
         Ok(HeartbeatServerResult { is_new: true })
@@ -654,6 +796,7 @@ impl SchedulerIncoming for Scheduler {
     ) -> Result<UpdateJobStateResult> {
         // LOCKS
         let mut jobs = self.jobs.lock().unwrap();
+        let mut recent_jobs = self.recent_jobs.lock().unwrap();
         let mut servers = self.servers.lock().unwrap();
 
         if let btree_map::Entry::Occupied(mut entry) = jobs.entry(job_id) {
@@ -683,7 +826,14 @@ impl SchedulerIncoming for Scheduler {
                     entry.get_mut().state = job_state
                 }
                 (JobState::Started, JobState::Complete) => {
-                    let (job_id, _) = entry.remove_entry();
+                    let (job_id, detail) = entry.remove_entry();
+                    Self::record_job(
+                        &mut recent_jobs,
+                        detail.client,
+                        job_id,
+                        server_id,
+                        JobOutcome::Complete,
+                    );
                     if let Some(entry) = server_details {
                         assert!(entry.jobs_assigned.remove(&job_id))
                     } else {
@@ -699,17 +849,47 @@ impl SchedulerIncoming for Scheduler {
         Ok(UpdateJobStateResult::Success)
     }
 
-    fn handle_status(&self) -> Result<SchedulerStatusResult> {
+    fn handle_status(&self, client: IpAddr) -> Result<SchedulerStatusResult> {
         // LOCKS
         let mut jobs = self.jobs.lock().unwrap();
+        let mut recent_jobs = self.recent_jobs.lock().unwrap();
         let mut servers = self.servers.lock().unwrap();
 
-        self.prune_servers(&mut servers, &mut jobs);
+        self.prune_servers(&mut servers, &mut jobs, &mut recent_jobs);
+
+        let mut server_statuses: Vec<_> = servers
+            .iter()
+            .map(|(&server_id, details)| ServerStatusResult {
+                server_id,
+                num_cpus: details.num_cpus,
+                jobs_assigned: details.jobs_assigned.len(),
+                toolchain_hits: details.toolchain_hits,
+                toolchain_misses: details.toolchain_misses,
+            })
+            .collect();
+        server_statuses.sort_by_key(|s| s.server_id.addr());
+        let mut client_jobs: Vec<_> = recent_jobs
+            .iter()
+            .rev()
+            .filter(|job| job.client == client)
+            .take(MAX_RECENT_JOBS_PER_CLIENT)
+            .map(|job| job.status.clone())
+            .collect();
+        client_jobs.reverse();
 
         Ok(SchedulerStatusResult {
             num_servers: servers.len(),
             num_cpus: servers.values().map(|v| v.num_cpus).sum(),
-            in_progress: jobs.len(),
+            running: jobs
+                .values()
+                .filter(|job| job.state == JobState::Started)
+                .count(),
+            queued: jobs
+                .values()
+                .filter(|job| job.state != JobState::Started)
+                .count(),
+            servers: server_statuses,
+            recent_jobs: client_jobs,
         })
     }
 }
@@ -816,3 +996,187 @@ impl ServerIncoming for Server {
         res
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashSet;
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    struct NoAuth;
+
+    impl JobAuthorizer for NoAuth {
+        fn generate_token(&self, job_id: JobId) -> Result<String> {
+            Ok(job_id.to_string())
+        }
+        fn verify_token(&self, _job_id: JobId, _token: &str) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Servers needing the toolchain of a job unless they were already
+    /// assigned a job of that toolchain.
+    #[derive(Default)]
+    struct Servers {
+        toolchains: Mutex<HashSet<(ServerId, String)>>,
+    }
+
+    impl SchedulerOutgoing for Servers {
+        fn do_assign_job(
+            &self,
+            server_id: ServerId,
+            _job_id: JobId,
+            tc: Toolchain,
+            _auth: String,
+        ) -> Result<AssignJobResult> {
+            let need_toolchain = self
+                .toolchains
+                .lock()
+                .unwrap()
+                .insert((server_id, tc.archive_id));
+            Ok(AssignJobResult {
+                state: JobState::Ready,
+                need_toolchain,
+            })
+        }
+    }
+
+    fn client() -> IpAddr {
+        Ipv4Addr::LOCALHOST.into()
+    }
+
+    /// A scheduler with two servers of 4 CPUs each, so that each job adds
+    /// 0.25 to the load of its server.
+    fn scheduler(toolchain_affinity: f64) -> (Scheduler, [ServerId; 2]) {
+        let scheduler = Scheduler::new(toolchain_affinity);
+        let servers = [10501, 10502].map(|port| {
+            let server_id = ServerId::new(SocketAddr::from((Ipv4Addr::LOCALHOST, port)));
+            scheduler
+                .handle_heartbeat_server(server_id, ServerNonce::new(), 4, Box::new(NoAuth))
+                .unwrap();
+            server_id
+        });
+        (scheduler, servers)
+    }
+
+    fn alloc(scheduler: &Scheduler, servers: &Servers, archive_id: &str) -> ServerId {
+        let tc = Toolchain {
+            archive_id: archive_id.to_owned(),
+        };
+        match scheduler.handle_alloc_job(servers, client(), tc).unwrap() {
+            AllocJobResult::Success { job_alloc, .. } => job_alloc.server_id,
+            AllocJobResult::Fail { msg } => panic!("Failed to allocate a job: {}", msg),
+        }
+    }
+
+    /// Allocate a job of the toolchain "a" and one of the toolchain "b",
+    /// which goes to the other, less loaded, server. Return the server of
+    /// each.
+    fn alloc_toolchains(
+        scheduler: &Scheduler,
+        servers: &Servers,
+        server_ids: [ServerId; 2],
+    ) -> (ServerId, ServerId) {
+        let with_a = alloc(scheduler, servers, "a");
+        let with_b = alloc(scheduler, servers, "b");
+        assert_ne!(with_a, with_b);
+        assert!(server_ids.contains(&with_a) && server_ids.contains(&with_b));
+        (with_a, with_b)
+    }
+
+    #[test]
+    fn test_alloc_job_toolchain_affinity() {
+        let (scheduler, server_ids) = scheduler(0.5);
+        let servers = Servers::default();
+        let (with_a, _) = alloc_toolchains(&scheduler, &servers, server_ids);
+        // The server having the toolchain gets the jobs while at most 0.5
+        // more loaded than the other: at a load of 0.25, 0.5 and 0.75
+        // against 0.25.
+        for _ in 0..3 {
+            assert_eq!(alloc(&scheduler, &servers, "a"), with_a);
+        }
+    }
+
+    #[test]
+    fn test_alloc_job_least_loaded() {
+        let (scheduler, server_ids) = scheduler(0.0);
+        let servers = Servers::default();
+        let (with_a, with_b) = alloc_toolchains(&scheduler, &servers, server_ids);
+        // At the same load, the server having the toolchain wins.
+        assert_eq!(alloc(&scheduler, &servers, "a"), with_a);
+        // Then it is more loaded than the margin allows, and the least
+        // loaded server gets the job, needing the toolchain.
+        assert_eq!(alloc(&scheduler, &servers, "a"), with_b);
+
+        // Whatever the margin, a server having the toolchain doesn't get
+        // jobs once as loaded as it can be.
+        let (scheduler, server_ids) = self::scheduler(f64::MAX);
+        let servers = Servers::default();
+        let (with_a, with_b) = alloc_toolchains(&scheduler, &servers, server_ids);
+        let max_jobs = (MAX_PER_CORE_LOAD * 4.0) as usize;
+        for _ in 1..max_jobs {
+            assert_eq!(alloc(&scheduler, &servers, "a"), with_a);
+        }
+        assert_eq!(alloc(&scheduler, &servers, "a"), with_b);
+    }
+
+    #[test]
+    fn test_status_running_and_queued() {
+        let (scheduler, _) = scheduler(0.5);
+        let servers = Servers::default();
+        let alloc = || {
+            let tc = Toolchain {
+                archive_id: "a".to_owned(),
+            };
+            match scheduler.handle_alloc_job(&servers, client(), tc).unwrap() {
+                AllocJobResult::Success { job_alloc, .. } => job_alloc,
+                AllocJobResult::Fail { msg } => panic!("Failed to allocate a job: {}", msg),
+            }
+        };
+        let first = alloc();
+        alloc();
+        let status = scheduler.handle_status(client()).unwrap();
+        assert_eq!((status.running, status.queued), (0, 2));
+
+        // The queued jobs are the ones which haven't started yet.
+        scheduler
+            .handle_update_job_state(first.job_id, first.server_id, JobState::Started)
+            .unwrap();
+        let status = scheduler.handle_status(client()).unwrap();
+        assert_eq!((status.running, status.queued), (1, 1));
+        scheduler
+            .handle_update_job_state(first.job_id, first.server_id, JobState::Complete)
+            .unwrap();
+        let status = scheduler.handle_status(client()).unwrap();
+        assert_eq!((status.running, status.queued), (0, 1));
+    }
+
+    #[test]
+    fn test_status_toolchain_counts() {
+        let (scheduler, server_ids) = scheduler(0.5);
+        let servers = Servers::default();
+        let (with_a, with_b) = alloc_toolchains(&scheduler, &servers, server_ids);
+        for _ in 0..3 {
+            alloc(&scheduler, &servers, "a");
+        }
+        // Beyond the margin, the other server needs the toolchain "a" too.
+        assert_eq!(alloc(&scheduler, &servers, "a"), with_b);
+        assert_eq!(alloc(&scheduler, &servers, "b"), with_b);
+
+        let status = scheduler.handle_status(client()).unwrap();
+        let counts = |server_id| {
+            let server = status
+                .servers
+                .iter()
+                .find(|server| server.server_id == server_id)
+                .expect("Missing server in the status");
+            (
+                server.jobs_assigned,
+                server.toolchain_hits,
+                server.toolchain_misses,
+            )
+        };
+        assert_eq!(counts(with_a), (4, 3, 1));
+        assert_eq!(counts(with_b), (3, 1, 2));
+    }
+}
@@ -0,0 +1,267 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Portable cache archives, see `sccache --export` and `sccache --import`.
+//!
+//! An archive is a zstd compressed tarball. It starts with `sccache-archive.json`,
+//! which holds the format version, and then has two files for every entry:
+//! `entries/<key>.blake3` with the hex encoded BLAKE3 digest of the entry, and
+//! `entries/<key>` with the entry itself, as stored by the backends.
+
+use crate::cache::{Cache, CacheWrite, Storage};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::time::SystemTime;
+
+use crate::errors::*;
+
+const ARCHIVE_VERSION: u32 = 1;
+const MANIFEST_PATH: &str = "sccache-archive.json";
+const ENTRIES_DIR: &str = "entries/";
+const CHECKSUM_SUFFIX: &str = ".blake3";
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+}
+
+/// The outcome of an import.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    /// Entries added to the cache.
+    pub imported: usize,
+    /// Entries skipped because they were already in the cache.
+    pub skipped: usize,
+}
+
+/// Keys end up in paths, both in the archive and in the backends.
+fn is_valid_key(key: &str) -> bool {
+    key.len() >= 3 && key.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+fn append<W: Write>(builder: &mut tar::Builder<W>, path: &str, data: &[u8]) -> Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    let mtime = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    header.set_mtime(mtime);
+    builder
+        .append_data(&mut header, path, data)
+        .with_context(|| format!("Failed to add {} to the archive", path))
+}
+
+/// Write all the entries of `storage` to `out`, returning how many were written.
+///
+/// Fails if `storage` can't list its entries, as Redis, Memcached, the GitHub
+/// Actions cache and the custom backends can't.
+pub async fn export<W: Write>(storage: &dyn Storage, out: W) -> Result<usize> {
+    let keys = storage.list().await.with_context(|| {
+        format!(
+            "Can't export {}: exporting needs a cache that can list its entries, \
+             which Redis, Memcached, the GitHub Actions cache and custom backends can't",
+            storage.location()
+        )
+    })?;
+    let mut builder = tar::Builder::new(zstd::stream::write::Encoder::new(out, 3)?);
+    let manifest = Manifest {
+        version: ARCHIVE_VERSION,
+    };
+    append(&mut builder, MANIFEST_PATH, &serde_json::to_vec(&manifest)?)?;
+
+    let mut count = 0;
+    for key in keys.into_iter().filter(|key| is_valid_key(key)) {
+        let entry = match storage.get(&key).await? {
+            Cache::Hit(entry) => entry.into_bytes()?,
+            // The entry was evicted since it was listed.
+            Cache::Miss | Cache::Recache => continue,
+        };
+        let checksum = blake3::hash(&entry).to_hex();
+        let path = format!("{}{}", ENTRIES_DIR, key);
+        append(
+            &mut builder,
+            &format!("{}{}", path, CHECKSUM_SUFFIX),
+            checksum.as_bytes(),
+        )?;
+        append(&mut builder, &path, &entry)?;
+        count += 1;
+    }
+    builder.into_inner()?.finish()?;
+    Ok(count)
+}
+
+/// Put all the entries of the archive read from `input` in `storage`, skipping
+/// those it already has.
+///
+/// Fails if an entry doesn't match its checksum.
+pub async fn import<R: Read>(storage: &dyn Storage, input: R) -> Result<ImportReport> {
+    let mut archive = tar::Archive::new(zstd::stream::read::Decoder::new(input)?);
+    let mut entries = archive.entries().context("Failed to read the archive")?;
+
+    match entries.next() {
+        Some(Ok(mut entry)) if entry.path()?.to_str() == Some(MANIFEST_PATH) => {
+            let manifest: Manifest = serde_json::from_reader(&mut entry)?;
+            if manifest.version != ARCHIVE_VERSION {
+                bail!("Unsupported archive version {}", manifest.version);
+            }
+        }
+        Some(Err(e)) => return Err(e).context("Failed to read the archive"),
+        _ => bail!("Not an sccache archive"),
+    }
+
+    let mut report = ImportReport::default();
+    let mut checksum: Option<(String, String)> = None;
+    for entry in entries {
+        let mut entry = entry.context("Failed to read the archive")?;
+        let path = entry.path()?.to_string_lossy().into_owned();
+        let name = match path.strip_prefix(ENTRIES_DIR) {
+            Some(name) => name,
+            None => bail!("Unexpected file in the archive: {}", path),
+        };
+        let mut data = vec![];
+        entry.read_to_end(&mut data)?;
+
+        if let Some(key) = name.strip_suffix(CHECKSUM_SUFFIX) {
+            checksum = Some((key.to_owned(), String::from_utf8(data)?));
+            continue;
+        }
+        if !is_valid_key(name) {
+            bail!("Invalid cache key in the archive: {}", name);
+        }
+        match checksum.take() {
+            Some((key, expected)) if key == name => {
+                if blake3::hash(&data).to_hex().as_str() != expected {
+                    bail!("Checksum mismatch for entry {}", name);
+                }
+            }
+            _ => bail!("Missing checksum for entry {}", name),
+        }
+
+        if let Cache::Hit(_) = storage.get(name).await? {
+            report.skipped += 1;
+            continue;
+        }
+        storage
+            .put(name, CacheWrite::from_bytes(data)?)
+            .await
+            .with_context(|| format!("Failed to store entry {}", name))?;
+        report.imported += 1;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::disk::DiskCache;
+    use crate::cache::{CacheMode, PreprocessorCacheModeConfig};
+    use crate::test::mock_storage::MockStorage;
+
+    fn disk_cache(dir: &std::path::Path, runtime: &tokio::runtime::Runtime) -> DiskCache {
+        DiskCache::new(
+            dir,
+            u64::MAX,
+            runtime.handle(),
+            PreprocessorCacheModeConfig::default(),
+            CacheMode::ReadWrite,
+        )
+    }
+
+    #[test]
+    fn test_export_import() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let tempdir = tempfile::Builder::new()
+            .prefix("sccache_test_archive")
+            .tempdir()
+            .unwrap();
+        let source = disk_cache(&tempdir.path().join("source"), &runtime);
+        let dest = disk_cache(&tempdir.path().join("dest"), &runtime);
+
+        runtime.block_on(async {
+            for key in ["aaaa", "bbbb"] {
+                let mut entry = CacheWrite::new();
+                entry.put_stdout(key.as_bytes()).unwrap();
+                source.put(key, entry).await.unwrap();
+            }
+            dest.put("bbbb", CacheWrite::new()).await.unwrap();
+
+            let mut archive = vec![];
+            assert_eq!(export(&source, &mut archive).await.unwrap(), 2);
+            let report = import(&dest, &archive[..]).await.unwrap();
+            assert_eq!(
+                report,
+                ImportReport {
+                    imported: 1,
+                    skipped: 1
+                }
+            );
+            match dest.get("aaaa").await.unwrap() {
+                Cache::Hit(mut entry) => assert_eq!(entry.get_stdout(), b"aaaa"),
+                e => panic!("Unexpected cache result: {:?}", e),
+            }
+        });
+    }
+
+    #[test]
+    fn test_export_unlisted() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        // Like Redis, the mock storage can't list its entries.
+        let storage = MockStorage::new(None, false);
+        let err = runtime
+            .block_on(export(&storage, vec![]))
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.starts_with("Can't export") && err.contains("Redis"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_import_checksum_mismatch() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let tempdir = tempfile::Builder::new()
+            .prefix("sccache_test_archive")
+            .tempdir()
+            .unwrap();
+        let dest = disk_cache(tempdir.path(), &runtime);
+
+        let mut builder = tar::Builder::new(zstd::stream::write::Encoder::new(vec![], 3).unwrap());
+        append(&mut builder, MANIFEST_PATH, br#"{"version":1}"#).unwrap();
+        append(&mut builder, "entries/aaaa.blake3", b"0000").unwrap();
+        append(
+            &mut builder,
+            "entries/aaaa",
+            &CacheWrite::new().finish().unwrap(),
+        )
+        .unwrap();
+        let archive = builder.into_inner().unwrap().finish().unwrap();
+
+        runtime.block_on(async {
+            let err = import(&dest, &archive[..]).await.unwrap_err();
+            assert_eq!(err.to_string(), "Checksum mismatch for entry aaaa");
+            assert!(matches!(dest.get("aaaa").await.unwrap(), Cache::Miss));
+        });
+    }
+}
@@ -14,23 +14,61 @@
 
 #[cfg(feature = "azure")]
 use crate::cache::azure::AzureBlobCache;
+use crate::cache::configured::with_entry_config;
+use crate::cache::custom::CustomCache;
+use crate::cache::dictionary::{self, Dictionary};
 use crate::cache::disk::DiskCache;
+#[cfg(feature = "s3")]
+use crate::cache::fallback::FallbackStorage;
 #[cfg(feature = "gcs")]
 use crate::cache::gcs::GCSCache;
 #[cfg(feature = "gha")]
 use crate::cache::gha::GHACache;
+#[cfg(feature = "http-cache")]
+use crate::cache::http::HttpCache;
+#[cfg(any(
+    feature = "azure",
+    feature = "gcs",
+    feature = "gha",
+    feature = "memcached",
+    feature = "redis",
+    feature = "s3",
+    feature = "webdav",
+    feature = "oss",
+))]
+use crate::cache::layout;
+#[cfg(feature = "s3")]
+use crate::cache::limited::limit_concurrency;
+use crate::cache::limited::{get_remote_fetch_concurrency, limit_fetches};
 #[cfg(feature = "memcached")]
 use crate::cache::memcached::MemcachedCache;
+use crate::cache::memory::with_memory_cache;
+use crate::cache::metadata::EntryMetadata;
 #[cfg(feature = "oss")]
 use crate::cache::oss::OSSCache;
 #[cfg(feature = "redis")]
 use crate::cache::redis::RedisCache;
 #[cfg(feature = "s3")]
 use crate::cache::s3::S3Cache;
+use crate::cache::shadow::ShadowStorage;
+use crate::cache::tiered::TieredStorage;
+#[cfg(any(
+    feature = "azure",
+    feature = "gcs",
+    feature = "gha",
+    feature = "memcached",
+    feature = "redis",
+    feature = "s3",
+    feature = "webdav",
+    feature = "oss",
+    feature = "http-cache",
+))]
+use crate::cache::timeout::with_timeout;
 #[cfg(feature = "webdav")]
 use crate::cache::webdav::WebdavCache;
-use crate::compiler::PreprocessorCacheEntry;
+use crate::compiler::{get_cache_salt, host_target, PreprocessorCacheEntry};
 use crate::config::Config;
+use crate::config::{self, CacheType, EntryConfig};
 #[cfg(any(
     feature = "azure",
     feature = "gcs",
@@ -39,17 +77,31 @@ use crate::config::Config;
     feature = "redis",
     feature = "s3",
     feature = "webdav",
-    feature = "oss"
+    feature = "oss",
 ))]
-use crate::config::{self, CacheType};
+use crate::retry::Backoff;
+#[cfg(any(
+    feature = "azure",
+    feature = "gha",
+    feature = "memcached",
+    feature = "redis",
+    feature = "webdav",
+    feature = "oss",
+))]
+use crate::retry::RetriedOperator;
+use crate::umask;
 use async_trait::async_trait;
 use fs_err as fs;
+use futures::stream::BoxStream;
 use serde::{Deserialize, Serialize};
+use std::env;
 use std::fmt;
 use std::io::{self, Cursor, Read, Seek, Write};
+use std::ops::Add;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use tempfile::NamedTempFile;
 use zip::write::FileOptions;
 use zip::{CompressionMethod, ZipArchive, ZipWriter};
@@ -95,6 +147,232 @@ pub struct FileObjectSource {
     pub optional: bool,
 }
 
+/// A key that no entry has, looked up to check that a cache can be reached.
+const PROBE_KEY: &str = "sccache-probe";
+
+/// The zstd level the objects of cache entries are compressed with.
+pub const COMPRESSION_LEVEL: i32 = 3;
+
+/// The media type of cache entries, for the remote caches storing one.
+const ENTRY_CONTENT_TYPE: &str = "application/vnd.sccache.entry+zip";
+
+/// How the objects of the cache entries are compressed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    /// zstd at `COMPRESSION_LEVEL`.
+    #[default]
+    Zstd,
+    /// Stored as is.
+    None,
+}
+
+impl Compression {
+    /// Get the compression of the entries written from now on,
+    /// `SCCACHE_CACHE_COMPRESSION`.
+    pub fn from_env() -> Compression {
+        match env::var("SCCACHE_CACHE_COMPRESSION") {
+            Ok(name) => name.parse().unwrap_or_else(|e| {
+                warn!("Ignoring SCCACHE_CACHE_COMPRESSION: {:#}", e);
+                Compression::default()
+            }),
+            Err(_) => Compression::default(),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Compression::Zstd => "zstd",
+            Compression::None => "none",
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Compression> {
+        match s {
+            "zstd" => Ok(Compression::Zstd),
+            "none" => Ok(Compression::None),
+            _ => bail!("unknown compression {:?}, expected zstd or none", s),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::Zstd => write!(f, "zstd level {}", COMPRESSION_LEVEL),
+            Compression::None => write!(f, "none"),
+        }
+    }
+}
+
+/// How a cache entry was written: the compression of its objects and the ID of
+/// the zstd dictionary they were compressed with, if any, the hash of its key,
+/// a digest of the salt of its key, if any, and the target it was made on,
+/// see `host_target`. It is stored in the
+/// comment of its archive, and as the content type of the object by the
+/// remote caches which can store one, so that the entries still read once
+/// `SCCACHE_CACHE_COMPRESSION` changes, and that a `HEAD` tells how to read
+/// one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EntryFormat {
+    pub compression: Compression,
+    pub dictionary: Option<u32>,
+    pub hash: String,
+    pub salt: Option<String>,
+    pub target: Option<String>,
+}
+
+impl EntryFormat {
+    /// The format of the entries written with `compression`, and
+    /// `dictionary` if it is zstd.
+    pub fn new(compression: Compression, dictionary: Option<&Dictionary>) -> EntryFormat {
+        EntryFormat {
+            compression,
+            dictionary: dictionary
+                .filter(|_| compression == Compression::Zstd)
+                .map(|dictionary| dictionary.id),
+            hash: "blake3".to_owned(),
+            salt: get_cache_salt()
+                .map(|salt| blake3::hash(salt.as_bytes()).to_hex()[..16].to_owned()),
+            target: Some(host_target().to_owned()),
+        }
+    }
+
+    /// Whether the entry was made on the target sccache runs on. The entries
+    /// of older versions don't tell, and are taken to be.
+    pub fn is_for_host(&self) -> bool {
+        self.target
+            .as_deref()
+            .map_or(true, |target| target == host_target())
+    }
+
+    /// Parse `params`, e.g. `compression=zstd; hash=blake3`, as in the
+    /// comment of an archive or after the media type of its content type.
+    /// The entries of older versions have none, and are compressed with zstd.
+    pub fn parse(params: &str) -> Result<EntryFormat> {
+        let mut format = EntryFormat {
+            compression: Compression::Zstd,
+            dictionary: None,
+            hash: "blake3".to_owned(),
+            salt: None,
+            target: None,
+        };
+        for param in params.split(';').map(str::trim) {
+            match param.split_once('=') {
+                Some(("compression", compression)) => format.compression = compression.parse()?,
+                Some(("dict", id)) => {
+                    format.dictionary = Some(
+                        id.parse()
+                            .with_context(|| format!("invalid dictionary ID {:?}", id))?,
+                    )
+                }
+                Some(("hash", hash)) => format.hash = hash.to_owned(),
+                Some(("salt", salt)) => format.salt = Some(salt.to_owned()),
+                Some(("target", target)) => format.target = Some(target.to_owned()),
+                // The media type, or what later versions add.
+                _ => {}
+            }
+        }
+        Ok(format)
+    }
+
+    fn params(&self) -> String {
+        let mut params = format!(
+            "compression={}; hash={}",
+            self.compression.name(),
+            self.hash
+        );
+        if let Some(id) = self.dictionary {
+            params.push_str(&format!("; dict={}", id));
+        }
+        if let Some(salt) = &self.salt {
+            params.push_str("; salt=");
+            params.push_str(salt);
+        }
+        if let Some(target) = &self.target {
+            params.push_str("; target=");
+            params.push_str(target);
+        }
+        params
+    }
+
+    /// The content type of the objects of the entries of this format.
+    pub fn content_type(&self) -> String {
+        format!("{}; {}", ENTRY_CONTENT_TYPE, self.params())
+    }
+}
+
+/// How much of the start of an object is looked at to tell whether it is
+/// worth compressing.
+const COMPRESSIBILITY_SAMPLE: usize = 64 * 1024;
+
+/// The largest block of a zstd frame.
+const ZSTD_MAX_BLOCK_SIZE: usize = 128 * 1024;
+
+/// Whether `sample`, the start of an object, looks already compressed: it
+/// starts with the magic bytes of a compressed format, or its bytes are
+/// almost uniformly distributed.
+fn is_incompressible(sample: &[u8]) -> bool {
+    const MAGICS: &[&[u8]] = &[
+        b"\x1f\x8b",           // gzip
+        b"\x28\xb5\x2f\xfd",   // zstd
+        b"\xfd7zXZ\x00",       // xz
+        b"BZh",                // bzip2
+        b"\x04\x22\x4d\x18",   // lz4
+        b"7z\xbc\xaf\x27\x1c", // 7z
+        b"PK\x03\x04",         // zip
+    ];
+    if MAGICS.iter().any(|magic| sample.starts_with(magic)) {
+        return true;
+    }
+    // Too few bytes to tell from their distribution, and not worth it.
+    if sample.len() < 4096 {
+        return false;
+    }
+    let mut counts = [0usize; 256];
+    for &b in sample {
+        counts[b as usize] += 1;
+    }
+    let len = sample.len() as f64;
+    let entropy: f64 = counts
+        .iter()
+        .filter(|&&n| n > 0)
+        .map(|&n| {
+            let p = n as f64 / len;
+            -p * p.log2()
+        })
+        .sum();
+    // In bits per byte, compiler outputs are far below.
+    entropy > 7.9
+}
+
+/// Copy `from` to `to` as a zstd frame of raw blocks, which any zstd decoder
+/// reads back although nothing was compressed.
+fn copy_uncompressed(from: &mut dyn Read, to: &mut dyn Write) -> io::Result<()> {
+    // The magic number, then a frame header without the size of the content
+    // nor a checksum, and with a window of the size of the largest block.
+    to.write_all(&[0x28, 0xb5, 0x2f, 0xfd, 0x00, 0x38])?;
+    let mut block = vec![0; ZSTD_MAX_BLOCK_SIZE];
+    loop {
+        let n = match from.read(&mut block) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        // The size, then the raw type (0) and whether it is the last block,
+        // which the empty one at the end is.
+        let header = ((n as u32) << 3) | u32::from(n == 0);
+        to.write_all(&header.to_le_bytes()[..3])?;
+        if n == 0 {
+            return Ok(());
+        }
+        to.write_all(&block[..n])?;
+    }
+}
+
 /// Result of a cache lookup.
 pub enum Cache {
     /// Result was found in cache.
@@ -132,6 +410,9 @@ impl<T: Read + Seek + Send> ReadSeek for T {}
 /// Data stored in the compiler cache.
 pub struct CacheRead {
     zip: ZipArchive<Box<dyn ReadSeek>>,
+    size: u64,
+    format: EntryFormat,
+    dictionary: Option<Arc<Dictionary>>,
 }
 
 /// Represents a failure to decompress stored object data.
@@ -152,9 +433,59 @@ impl CacheRead {
     where
         R: ReadSeek + 'static,
     {
+        CacheRead::with_dictionaries(reader, dictionary::find)
+    }
+
+    /// Create a cache entry from `reader`, getting the dictionary it was
+    /// compressed with, if any, from `find`.
+    pub fn with_dictionaries<R>(
+        mut reader: R,
+        find: impl FnOnce(u32) -> Option<Arc<Dictionary>>,
+    ) -> Result<CacheRead>
+    where
+        R: ReadSeek + 'static,
+    {
+        let size = reader.seek(io::SeekFrom::End(0))?;
         let z = ZipArchive::new(Box::new(reader) as Box<dyn ReadSeek>)
             .context("Failed to parse cache entry")?;
-        Ok(CacheRead { zip: z })
+        let format = EntryFormat::parse(&String::from_utf8_lossy(z.comment()))
+            .context("Failed to parse cache entry")?;
+        let dictionary = format.dictionary.and_then(find);
+        Ok(CacheRead {
+            zip: z,
+            size,
+            format,
+            dictionary,
+        })
+    }
+
+    /// How this cache entry was written.
+    pub fn format(&self) -> &EntryFormat {
+        &self.format
+    }
+
+    /// The size of this cache entry, as it is stored.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The names of the objects of this cache entry.
+    pub fn object_names(&self) -> Vec<String> {
+        self.zip.file_names().map(str::to_owned).collect()
+    }
+
+    /// Return the reader this cache entry is read from.
+    pub fn into_inner(self) -> Box<dyn ReadSeek> {
+        self.zip.into_inner()
+    }
+
+    /// Return the raw bytes of this cache entry, as they are stored.
+    pub fn into_bytes(self) -> Result<Vec<u8>> {
+        let mut reader = self.into_inner();
+        reader.rewind()?;
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes)?;
+        Ok(bytes)
     }
 
     /// Get an object from this cache entry at `name` and write it to `to`.
@@ -163,12 +494,32 @@ impl CacheRead {
     where
         T: Write,
     {
-        let file = self.zip.by_name(name).or(Err(DecompressionFailure))?;
+        let mut file = self.zip.by_name(name).or(Err(DecompressionFailure))?;
         if file.compression() != CompressionMethod::Stored {
             bail!(DecompressionFailure);
         }
         let mode = file.unix_mode();
-        zstd::stream::copy_decode(file, to).or(Err(DecompressionFailure))?;
+        match (self.format.compression, self.format.dictionary) {
+            (Compression::Zstd, None) => {
+                zstd::stream::copy_decode(file, to).or(Err(DecompressionFailure))?;
+            }
+            (Compression::Zstd, Some(id)) => {
+                let Some(dictionary) = &self.dictionary else {
+                    return Err(Error::new(DecompressionFailure).context(format!(
+                        "compressed with the unknown zstd dictionary {}",
+                        id
+                    )));
+                };
+                let mut decoder = zstd::stream::read::Decoder::with_prepared_dictionary(
+                    io::BufReader::new(file),
+                    &dictionary.decoder,
+                )?;
+                io::copy(&mut decoder, to).or(Err(DecompressionFailure))?;
+            }
+            (Compression::None, _) => {
+                io::copy(&mut file, to).or(Err(DecompressionFailure))?;
+            }
+        }
         Ok(mode)
     }
 
@@ -182,6 +533,21 @@ impl CacheRead {
         self.get_bytes("stderr")
     }
 
+    /// Get the build which made this cache entry, if it was given one.
+    pub fn get_build_id(&mut self) -> Option<String> {
+        let build_id = self.get_bytes("build-id");
+        (!build_id.is_empty()).then(|| String::from_utf8_lossy(&build_id).into_owned())
+    }
+
+    /// Get the exit code of the compiler from this cache entry, entries
+    /// without one are for successful compilations.
+    pub fn get_exit_code(&mut self) -> i32 {
+        std::str::from_utf8(&self.get_bytes("exit_code"))
+            .ok()
+            .and_then(|code| code.parse().ok())
+            .unwrap_or(0)
+    }
+
     fn get_bytes(&mut self, name: &str) -> Vec<u8> {
         let mut bytes = Vec::new();
         drop(self.get_object(name, &mut bytes));
@@ -196,6 +562,9 @@ impl CacheRead {
     where
         T: IntoIterator<Item = FileObjectSource> + Send + Sync + 'static,
     {
+        // The outputs get the mode the compiler would create them with for
+        // the client, when its umask is known.
+        let umask = umask::of_task();
         pool.spawn_blocking(move || {
             for FileObjectSource {
                 key,
@@ -208,15 +577,20 @@ impl CacheRead {
                     None => bail!("Output file without a parent directory!"),
                 };
                 // Write the cache entry to a tempfile and then atomically
-                // move it to its final location so that other rustc invocations
-                // happening in parallel don't see a partially-written file.
+                // move it to its final location, with its mode, so that the
+                // build tools and the other compilations reading it in
+                // parallel don't see a partially-written file.
                 let mut tmp = NamedTempFile::new_in(dir)?;
                 match (self.get_object(&key, &mut tmp), optional) {
                     (Ok(mode), _) => {
-                        tmp.persist(&path)?;
+                        let mode = match umask {
+                            Some(umask) => mode.map(|mode| umask::restored_mode(mode, umask)),
+                            None => mode,
+                        };
                         if let Some(mode) = mode {
-                            set_file_mode(&path, mode)?;
+                            set_file_mode(tmp.path(), mode)?;
                         }
+                        tmp.persist(&path)?;
                     }
                     (Err(e), false) => return Err(e),
                     // skip if no object found and it's optional
@@ -232,23 +606,97 @@ impl CacheRead {
 /// Data to be stored in the compiler cache.
 pub struct CacheWrite {
     zip: ZipWriter<io::Cursor<Vec<u8>>>,
+    format: EntryFormat,
+    dictionary: Option<Arc<Dictionary>>,
+    metadata: Option<EntryMetadata>,
 }
 
 impl CacheWrite {
-    /// Create a new, empty cache entry.
+    /// Create a new, empty cache entry, compressed with
+    /// `SCCACHE_CACHE_COMPRESSION` and the first dictionary of
+    /// `SCCACHE_ZSTD_DICT`, if any.
     pub fn new() -> CacheWrite {
+        CacheWrite::compressed(Compression::from_env())
+    }
+
+    /// Create a new, empty cache entry, compressed with `compression` and
+    /// the first dictionary of `SCCACHE_ZSTD_DICT` if it is zstd.
+    pub fn compressed(compression: Compression) -> CacheWrite {
+        CacheWrite::with_format(compression, dictionary::for_writing())
+    }
+
+    /// Create a new, empty cache entry, compressed with `compression`.
+    pub fn with_compression(compression: Compression) -> CacheWrite {
+        CacheWrite::with_format(compression, None)
+    }
+
+    /// Create a new, empty cache entry, compressed with zstd and
+    /// `dictionary`.
+    pub fn with_dictionary(dictionary: Arc<Dictionary>) -> CacheWrite {
+        CacheWrite::with_format(Compression::Zstd, Some(dictionary))
+    }
+
+    fn with_format(compression: Compression, dictionary: Option<Arc<Dictionary>>) -> CacheWrite {
+        let format = EntryFormat::new(compression, dictionary.as_deref());
+        let dictionary = dictionary.filter(|_| format.dictionary.is_some());
+        let mut zip = ZipWriter::new(io::Cursor::new(vec![]));
+        zip.set_comment(format.params());
         CacheWrite {
-            zip: ZipWriter::new(io::Cursor::new(vec![])),
+            zip,
+            format,
+            dictionary,
+            metadata: None,
         }
     }
 
-    /// Create a new cache entry populated with the contents of `objects`.
-    pub async fn from_objects<T>(objects: T, pool: &tokio::runtime::Handle) -> Result<CacheWrite>
+    /// Create a cache entry from the raw bytes of an existing one, see
+    /// `CacheRead::into_bytes`. It keeps its format.
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<CacheWrite> {
+        let comment = ZipArchive::new(io::Cursor::new(&bytes))
+            .context("Failed to parse cache entry")?
+            .comment()
+            .to_owned();
+        let format = EntryFormat::parse(&String::from_utf8_lossy(&comment))
+            .context("Failed to parse cache entry")?;
+        let zip =
+            ZipWriter::new_append(io::Cursor::new(bytes)).context("Failed to parse cache entry")?;
+        let dictionary = format.dictionary.and_then(dictionary::find);
+        Ok(CacheWrite {
+            zip,
+            format,
+            dictionary,
+            metadata: None,
+        })
+    }
+
+    /// How this cache entry is written.
+    pub fn format(&self) -> &EntryFormat {
+        &self.format
+    }
+
+    /// What this cache entry was made with, stored along with it by the
+    /// remote caches which can.
+    pub fn metadata(&self) -> Option<&EntryMetadata> {
+        self.metadata.as_ref()
+    }
+
+    /// Set what this cache entry was made with.
+    pub fn set_metadata(&mut self, metadata: EntryMetadata) {
+        self.metadata = Some(metadata);
+    }
+
+    /// Create a new cache entry populated with the contents of `objects`,
+    /// compressed with `compression`.
+    pub async fn from_objects<T>(
+        objects: T,
+        compression: Compression,
+        pool: &tokio::runtime::Handle,
+    ) -> Result<CacheWrite>
     where
         T: IntoIterator<Item = FileObjectSource> + Send + Sync + 'static,
     {
         pool.spawn_blocking(move || {
-            let mut entry = CacheWrite::new();
+            let mut entry = CacheWrite::compressed(compression);
             for FileObjectSource {
                 key,
                 path,
@@ -290,7 +738,34 @@ impl CacheWrite {
         self.zip
             .start_file(name, opts)
             .context("Failed to start cache entry object")?;
-        zstd::stream::copy_encode(from, &mut self.zip, 3)?;
+        let mut sample = Vec::with_capacity(COMPRESSIBILITY_SAMPLE);
+        from.take(COMPRESSIBILITY_SAMPLE as u64)
+            .read_to_end(&mut sample)?;
+        let incompressible = is_incompressible(&sample);
+        let mut from = Cursor::new(sample).chain(from);
+        match (self.format.compression, self.format.dictionary) {
+            // Compressing what is already compressed costs time for nothing.
+            (Compression::Zstd, _) if incompressible => {
+                copy_uncompressed(&mut from, &mut self.zip)?
+            }
+            (Compression::Zstd, None) => {
+                zstd::stream::copy_encode(from, &mut self.zip, COMPRESSION_LEVEL)?
+            }
+            (Compression::Zstd, Some(id)) => {
+                let Some(dictionary) = &self.dictionary else {
+                    bail!("the zstd dictionary {} of the cache entry isn't loaded", id);
+                };
+                let mut encoder = zstd::stream::write::Encoder::with_prepared_dictionary(
+                    &mut self.zip,
+                    &dictionary.encoder,
+                )?;
+                io::copy(&mut from, &mut encoder)?;
+                encoder.finish()?;
+            }
+            (Compression::None, _) => {
+                io::copy(&mut from, &mut self.zip)?;
+            }
+        }
         Ok(())
     }
 
@@ -302,6 +777,20 @@ impl CacheWrite {
         self.put_bytes("stderr", bytes)
     }
 
+    /// Record the build which made this cache entry, e.g. the commit it
+    /// built, for the storages without metadata.
+    pub fn put_build_id(&mut self, build_id: &str) -> Result<()> {
+        self.put_bytes("build-id", build_id.as_bytes())
+    }
+
+    /// Store the exit code of the compiler, nothing is stored for 0.
+    pub fn put_exit_code(&mut self, code: i32) -> Result<()> {
+        if code != 0 {
+            return self.put_bytes("exit_code", code.to_string().as_bytes());
+        }
+        Ok(())
+    }
+
     fn put_bytes(&mut self, name: &str, bytes: &[u8]) -> Result<()> {
         if !bytes.is_empty() {
             let mut cursor = Cursor::new(bytes);
@@ -312,7 +801,7 @@ impl CacheWrite {
 
     /// Finish writing data to the cache entry writer, and return the data.
     pub fn finish(self) -> Result<Vec<u8>> {
-        let CacheWrite { mut zip } = self;
+        let CacheWrite { mut zip, .. } = self;
         let cur = zip.finish().context("Failed to finish cache entry zip")?;
         Ok(cur.into_inner())
     }
@@ -324,6 +813,77 @@ impl Default for CacheWrite {
     }
 }
 
+/// The state of the eviction of a storage evicting entries in sweeps, once it
+/// is larger than its high watermark, until it is down to its low watermark.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EvictionState {
+    /// The size from which entries are evicted, in bytes.
+    pub high_watermark: u64,
+    /// The size down to which entries are evicted, in bytes.
+    pub low_watermark: u64,
+    /// Whether a sweep is running.
+    pub sweeping: bool,
+}
+
+/// What a storage, and the storages it wraps, counted since the counts were
+/// last taken, see `Storage::take_counts`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StorageCounts {
+    /// The requests retried after failing temporarily.
+    pub retries: u64,
+    /// The hits served from memory.
+    pub memory_hits: u64,
+    /// The hits served from the replica of the cache, the cache failing.
+    pub fallback_hits: u64,
+    /// The fetches which waited for a slot.
+    pub fetches_queued: u64,
+    /// How long the fetches waited for a slot, in total.
+    pub fetch_queue_duration: Duration,
+}
+
+impl Add for StorageCounts {
+    type Output = StorageCounts;
+
+    fn add(self, other: StorageCounts) -> StorageCounts {
+        StorageCounts {
+            retries: self.retries + other.retries,
+            memory_hits: self.memory_hits + other.memory_hits,
+            fallback_hits: self.fallback_hits + other.fallback_hits,
+            fetches_queued: self.fetches_queued + other.fetches_queued,
+            fetch_queue_duration: self.fetch_queue_duration + other.fetch_queue_duration,
+        }
+    }
+}
+
+/// An entry of a cache, as `Storage::list_entries` lists it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct EntryInfo {
+    pub key: String,
+    /// The size of the entry as stored, in bytes.
+    pub size: u64,
+    /// When the entry was written, if the storage knows.
+    pub modified: Option<SystemTime>,
+    /// The build which wrote the entry, see `SCCACHE_BUILD_ID`, if the
+    /// storage lists it.
+    pub build_id: Option<String>,
+}
+
+/// The entries of a cache, as `Storage::list_entries` finds them.
+pub type EntryStream = BoxStream<'static, Result<EntryInfo>>;
+
+/// How full a storage is, and how much it evicted since it was opened, for
+/// the storages tracking their entries locally.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheOccupancy {
+    /// The number of files in the cache: the entries and, when they are
+    /// deduplicated, their payloads.
+    pub entries: u64,
+    /// How many of them were evicted.
+    pub evictions: u64,
+    /// The size of the files evicted, in bytes.
+    pub evicted_bytes: u64,
+}
+
 /// An interface to cache storage.
 #[async_trait]
 pub trait Storage: Send + Sync {
@@ -358,15 +918,85 @@ pub trait Storage: Send + Sync {
         Ok(CacheMode::ReadWrite)
     }
 
+    /// Whether the storage is known to be read-only, e.g. once its check
+    /// found it to be, without checking it again.
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    /// Check that the storage can be reached and that the credentials are
+    /// accepted, without writing anything to it.
+    ///
+    /// The default implementation looks up an entry that doesn't exist.
+    async fn probe(&self) -> Result<()> {
+        self.get(PROBE_KEY).await.map(drop)
+    }
+
     /// Get the storage location.
     fn location(&self) -> String;
 
+    /// List the keys of all the entries in the cache.
+    ///
+    /// Backends that can't enumerate their entries return an error, which is
+    /// the default.
+    async fn list(&self) -> Result<Vec<String>> {
+        bail!("Listing entries is not supported by {}", self.location())
+    }
+
+    /// List the entries whose keys start with `prefix`, as they are found,
+    /// so that a large remote cache is listed without holding all of its
+    /// entries at once. An entry stored in several layouts of a remote cache
+    /// is listed once for each.
+    ///
+    /// Backends that can't enumerate their entries return an error, which is
+    /// the default.
+    async fn list_entries(&self, _prefix: &str) -> Result<EntryStream> {
+        bail!("Listing entries is not supported by {}", self.location())
+    }
+
+    /// Remove the entry of `key`, returning whether there was one.
+    ///
+    /// Backends that can't remove their entries return an error, which is
+    /// the default.
+    async fn delete(&self, _key: &str) -> Result<bool> {
+        bail!("Removing entries is not supported by {}", self.location())
+    }
+
     /// Get the current storage usage, if applicable.
     async fn current_size(&self) -> Result<Option<u64>>;
 
     /// Get the maximum storage size, if applicable.
     async fn max_size(&self) -> Result<Option<u64>>;
 
+    /// Get how many requests to the storage are running, if they are counted.
+    fn requests_in_flight(&self) -> Option<usize> {
+        None
+    }
+
+    /// Get what the storage counted since the last call, and reset it.
+    fn take_counts(&self) -> StorageCounts {
+        StorageCounts::default()
+    }
+
+    /// Get the state of the eviction, for storages evicting entries in sweeps
+    /// between watermarks.
+    fn eviction_state(&self) -> Option<EvictionState> {
+        None
+    }
+
+    /// Get how full the storage is and what it evicted, for the storages
+    /// which track their entries locally, e.g. not the remote ones, whose
+    /// size is unknown.
+    fn occupancy(&self) -> Option<CacheOccupancy> {
+        None
+    }
+
+    /// Get how the entries put in the storage are written, as the
+    /// environment says unless it was built from a `Config`.
+    fn entry_config(&self) -> EntryConfig {
+        EntryConfig::from_env()
+    }
+
     /// Return the config for preprocessor cache mode if applicable
     fn preprocessor_cache_mode_config(&self) -> PreprocessorCacheModeConfig {
         // Enable by default, only in local mode
@@ -419,6 +1049,12 @@ pub struct PreprocessorCacheModeConfig {
     /// If true (default), will add the current working directory in the hash to
     /// distinguish two compilations from different directories.
     pub hash_working_directory: bool,
+    /// If true, don't disable preprocessor cache mode for include files
+    /// modified after the compilation started.
+    pub ignore_include_file_mtime: bool,
+    /// If true, don't disable preprocessor cache mode for include files
+    /// whose status changed after the compilation started.
+    pub ignore_include_file_ctime: bool,
 }
 
 impl Default for PreprocessorCacheModeConfig {
@@ -430,6 +1066,8 @@ impl Default for PreprocessorCacheModeConfig {
             ignore_time_macros: false,
             skip_system_headers: false,
             hash_working_directory: true,
+            ignore_include_file_mtime: false,
+            ignore_include_file_ctime: false,
         }
     }
 }
@@ -442,6 +1080,29 @@ impl PreprocessorCacheModeConfig {
             ..Default::default()
         }
     }
+
+    /// Relax the checks set by the comma-separated `sloppiness`, named after
+    /// those of ccache, e.g. `time_macros,system_headers`.
+    pub fn apply_sloppiness(&mut self, sloppiness: &str) -> Result<()> {
+        for option in sloppiness.split(',').map(str::trim) {
+            match option {
+                "" => {}
+                "time_macros" => self.ignore_time_macros = true,
+                "system_headers" => self.skip_system_headers = true,
+                "include_file_mtime" => self.ignore_include_file_mtime = true,
+                "include_file_ctime" => self.ignore_include_file_ctime = true,
+                "file_stat_matches" => self.file_stat_matches = true,
+                "file_stat_matches_ctime" => self.use_ctime_for_stat = false,
+                option => bail!(
+                    "unknown sloppiness {:?}, expected time_macros, system_headers, \
+                     include_file_mtime, include_file_ctime, file_stat_matches or \
+                     file_stat_matches_ctime",
+                    option
+                ),
+            }
+        }
+        Ok(())
+    }
 }
 
 /// Implement storage for operator.
@@ -453,31 +1114,66 @@ impl PreprocessorCacheModeConfig {
     feature = "redis",
     feature = "s3",
     feature = "webdav",
+    feature = "oss",
 ))]
 #[async_trait]
 impl Storage for opendal::Operator {
     async fn get(&self, key: &str) -> Result<Cache> {
-        match self.read(&normalize_key(key)).await {
-            Ok(res) => {
-                let hit = CacheRead::from(io::Cursor::new(res))?;
-                Ok(Cache::Hit(hit))
-            }
-            Err(e) if e.kind() == opendal::ErrorKind::NotFound => Ok(Cache::Miss),
-            Err(e) => {
-                warn!("Got unexpected error: {:?}", e);
-                Ok(Cache::Miss)
+        let download = crate::cache::multipart::multipart_download();
+        for layout in layout::remote() {
+            match download.read(self, &layout.path(key)).await {
+                Ok(res) => {
+                    let hit = CacheRead::from(io::Cursor::new(res))?;
+                    return Ok(Cache::Hit(hit));
+                }
+                Err(e) if e.kind() == opendal::ErrorKind::NotFound => {}
+                Err(e) => {
+                    warn!("Got unexpected error: {:?}", e);
+                    return Ok(Cache::Miss);
+                }
             }
         }
+        Ok(Cache::Miss)
     }
 
     async fn put(&self, key: &str, entry: CacheWrite) -> Result<Duration> {
         let start = std::time::Instant::now();
 
-        self.write(&normalize_key(key), entry.finish()?).await?;
+        let content_type = entry.format().content_type();
+        let entry = entry.finish()?;
+        let path = layout::remote()[0].path(key);
+        if self.info().full_capability().write_with_content_type {
+            self.write_with(&path, entry)
+                .content_type(&content_type)
+                .await?;
+        } else {
+            self.write(&path, entry).await?;
+        }
 
         Ok(start.elapsed())
     }
 
+    async fn probe(&self) -> Result<()> {
+        use opendal::ErrorKind;
+
+        // Unlike `get`, don't take errors for misses.
+        match self.read(".sccache_check").await {
+            Ok(_) => Ok(()),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            Err(err) => {
+                let hint = match err.kind() {
+                    ErrorKind::PermissionDenied => {
+                        "check the credentials and the permissions they grant"
+                    }
+                    ErrorKind::ConfigInvalid => "check the configuration of the cache",
+                    ErrorKind::RateLimited => "the cache is rate limiting requests, try later",
+                    _ => "check that the cache is reachable from this machine",
+                };
+                bail!("failed to read from the cache, {}: {}", hint, err)
+            }
+        }
+    }
+
     async fn check(&self) -> Result<CacheMode> {
         use opendal::ErrorKind;
 
@@ -534,6 +1230,82 @@ impl Storage for opendal::Operator {
         )
     }
 
+    async fn list(&self) -> Result<Vec<String>> {
+        if !self.info().full_capability().list {
+            bail!("Listing entries is not supported by {}", self.location());
+        }
+        let entries = self.list_with("/").recursive(true).await?;
+        let mut keys: Vec<_> = entries
+            .iter()
+            .filter(|entry| entry.metadata().is_file())
+            .filter_map(|entry| {
+                layout::remote()
+                    .iter()
+                    .find_map(|layout| layout.key_of(entry.path()))
+            })
+            .map(ToOwned::to_owned)
+            .collect();
+        // An entry may be in several layouts.
+        keys.sort_unstable();
+        keys.dedup();
+        Ok(keys)
+    }
+
+    async fn list_entries(&self, prefix: &str) -> Result<EntryStream> {
+        use futures::{StreamExt, TryStreamExt};
+        use opendal::Metakey;
+
+        if !self.info().full_capability().list {
+            bail!("Listing entries is not supported by {}", self.location());
+        }
+        let lister = self
+            .lister_with("/")
+            .recursive(true)
+            .metakey(Metakey::ContentLength | Metakey::LastModified)
+            .await?;
+        let prefix = prefix.to_owned();
+        let entries = lister.err_into().try_filter_map(move |entry| {
+            let metadata = entry.metadata();
+            let info = metadata
+                .is_file()
+                .then(|| {
+                    layout::remote()
+                        .iter()
+                        .find_map(|layout| layout.key_of(entry.path()))
+                })
+                .flatten()
+                .filter(|key| key.starts_with(&prefix))
+                .map(|key| EntryInfo {
+                    key: key.to_owned(),
+                    size: metadata.content_length(),
+                    modified: metadata.last_modified().map(SystemTime::from),
+                    // Only in the metadata of the object, which listing skips.
+                    build_id: None,
+                });
+            futures::future::ready(Ok(info))
+        });
+        Ok(entries.boxed())
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool> {
+        use opendal::ErrorKind;
+
+        if !self.info().full_capability().delete {
+            bail!("Removing entries is not supported by {}", self.location());
+        }
+        let mut found = false;
+        for layout in layout::remote() {
+            let path = layout.path(key);
+            match self.stat(&path).await {
+                Ok(_) => found = true,
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            }
+            opendal::Operator::delete(self, &path).await?;
+        }
+        Ok(found)
+    }
+
     async fn current_size(&self) -> Result<Option<u64>> {
         Ok(None)
     }
@@ -549,12 +1321,78 @@ pub(in crate::cache) fn normalize_key(key: &str) -> String {
 }
 
 /// Get a suitable `Storage` implementation from configuration.
-#[allow(clippy::cognitive_complexity)] // TODO simplify!
 pub fn storage_from_config(
     config: &Config,
     pool: &tokio::runtime::Handle,
 ) -> Result<Arc<dyn Storage>> {
-    if let Some(cache_type) = &config.cache {
+    let mut storage = configured_storage(config.cache.as_ref(), config, pool)?;
+    if config.cache.is_some() && config.fallback_cache.local_tier {
+        let local = Arc::new(disk_cache_from_config(config, pool)?);
+        storage = Arc::new(TieredStorage::new(local, storage));
+    }
+    let Some(shadow) = &config.shadow else {
+        return Ok(storage);
+    };
+    let other = configured_storage(Some(&shadow.cache), config, pool)
+        .context("Failed to create the shadow cache")?;
+    Ok(Arc::new(ShadowStorage::new(
+        storage,
+        other,
+        shadow.read_rate,
+        shadow.authoritative,
+    )))
+}
+
+/// Get the storage of `cache`, or else the local disk cache, with the other
+/// settings of `config`.
+fn configured_storage(
+    cache: Option<&CacheType>,
+    config: &Config,
+    pool: &tokio::runtime::Handle,
+) -> Result<Arc<dyn Storage>> {
+    let storage = with_entry_config(remote_or_disk_storage(cache, config, pool)?, config.entries);
+    // Only the remote caches share a link with the other machines.
+    if cache.is_some() {
+        return Ok(limit_fetches(storage, get_remote_fetch_concurrency()));
+    }
+    Ok(storage)
+}
+
+/// Build the storage of the bucket of `c`, without its fallback.
+#[cfg(feature = "s3")]
+fn s3_storage(c: &config::S3CacheConfig) -> Result<Arc<dyn Storage>> {
+    let storage = S3Cache::build(
+        &c.bucket,
+        c.region.as_deref(),
+        &c.key_prefix,
+        c.no_credentials,
+        c.endpoint.as_deref(),
+        c.use_ssl,
+        c.server_side_encryption,
+        &c.request,
+    )
+    .map_err(|err| anyhow!("create s3 cache failed: {err:?}"))?;
+
+    limit_concurrency(
+        with_timeout(Arc::new(storage.with_retries(Backoff::remote())), "s3"),
+        c.max_concurrency,
+    )
+}
+
+#[allow(clippy::cognitive_complexity)] // TODO simplify!
+fn remote_or_disk_storage(
+    cache: Option<&CacheType>,
+    config: &Config,
+    pool: &tokio::runtime::Handle,
+) -> Result<Arc<dyn Storage>> {
+    // Not in the match below, which would be left with this single arm when
+    // built without the remote backends.
+    if let Some(CacheType::Custom(config::CustomCacheConfig { helper })) = cache {
+        debug!("Init custom cache with helper {}", helper.display());
+
+        return Ok(Arc::new(CustomCache::new(helper)));
+    }
+    if let Some(cache_type) = cache {
         match cache_type {
             #[cfg(feature = "azure")]
             CacheType::Azure(config::AzureCacheConfig {
@@ -565,7 +1403,10 @@ pub fn storage_from_config(
                 debug!("Init azure cache with container {container}, key_prefix {key_prefix}");
                 let storage = AzureBlobCache::build(connection_string, container, key_prefix)
                     .map_err(|err| anyhow!("create azure cache failed: {err:?}"))?;
-                return Ok(Arc::new(storage));
+                return Ok(with_timeout(
+                    Arc::new(RetriedOperator::new(storage, &Backoff::remote())),
+                    cache_type.name(),
+                ));
             }
             #[cfg(feature = "gcs")]
             CacheType::GCS(config::GCSCacheConfig {
@@ -588,7 +1429,10 @@ pub fn storage_from_config(
                 )
                 .map_err(|err| anyhow!("create gcs cache failed: {err:?}"))?;
 
-                return Ok(Arc::new(storage));
+                return Ok(with_timeout(
+                    Arc::new(storage.with_retries(Backoff::remote())),
+                    cache_type.name(),
+                ));
             }
             #[cfg(feature = "gha")]
             CacheType::GHA(config::GHACacheConfig { ref version, .. }) => {
@@ -596,7 +1440,10 @@ pub fn storage_from_config(
 
                 let storage = GHACache::build(version)
                     .map_err(|err| anyhow!("create gha cache failed: {err:?}"))?;
-                return Ok(Arc::new(storage));
+                return Ok(with_timeout(
+                    Arc::new(RetriedOperator::new(storage, &Backoff::remote())),
+                    cache_type.name(),
+                ));
             }
             #[cfg(feature = "memcached")]
             CacheType::Memcached(config::MemcachedCacheConfig {
@@ -616,7 +1463,10 @@ pub fn storage_from_config(
                     *expiration,
                 )
                 .map_err(|err| anyhow!("create memcached cache failed: {err:?}"))?;
-                return Ok(Arc::new(storage));
+                return Ok(with_timeout(
+                    Arc::new(RetriedOperator::new(storage, &Backoff::remote())),
+                    cache_type.name(),
+                ));
             }
             #[cfg(feature = "redis")]
             CacheType::Redis(config::RedisCacheConfig {
@@ -663,7 +1513,10 @@ pub fn storage_from_config(
                     _ => bail!("Only one of `endpoint`, `cluster_endpoints`, `url` must be set"),
                 }
                 .map_err(|err| anyhow!("create redis cache failed: {err:?}"))?;
-                return Ok(Arc::new(storage));
+                return Ok(with_timeout(
+                    Arc::new(RetriedOperator::new(storage, &Backoff::remote())),
+                    cache_type.name(),
+                ));
             }
             #[cfg(feature = "s3")]
             CacheType::S3(ref c) => {
@@ -671,19 +1524,20 @@ pub fn storage_from_config(
                     "Init s3 cache with bucket {}, endpoint {:?}",
                     c.bucket, c.endpoint
                 );
-
-                let storage = S3Cache::build(
-                    &c.bucket,
-                    c.region.as_deref(),
-                    &c.key_prefix,
-                    c.no_credentials,
-                    c.endpoint.as_deref(),
-                    c.use_ssl,
-                    c.server_side_encryption,
-                )
-                .map_err(|err| anyhow!("create s3 cache failed: {err:?}"))?;
-
-                return Ok(Arc::new(storage));
+                let storage = s3_storage(c)?;
+                let Some(fallback) = c.fallback_config() else {
+                    return Ok(storage);
+                };
+                debug!(
+                    "Init s3 fallback cache with bucket {}, region {:?}, endpoint {:?}",
+                    fallback.bucket, fallback.region, fallback.endpoint
+                );
+                let replicate = c.fallback.as_ref().is_some_and(|f| f.replicate);
+                return Ok(Arc::new(FallbackStorage::new(
+                    storage,
+                    s3_storage(&fallback)?,
+                    replicate,
+                )));
             }
             #[cfg(feature = "webdav")]
             CacheType::Webdav(ref c) => {
@@ -698,7 +1552,10 @@ pub fn storage_from_config(
                 )
                 .map_err(|err| anyhow!("create webdav cache failed: {err:?}"))?;
 
-                return Ok(Arc::new(storage));
+                return Ok(with_timeout(
+                    Arc::new(RetriedOperator::new(storage, &Backoff::remote())),
+                    cache_type.name(),
+                ));
             }
             #[cfg(feature = "oss")]
             CacheType::OSS(ref c) => {
@@ -715,7 +1572,23 @@ pub fn storage_from_config(
                 )
                 .map_err(|err| anyhow!("create oss cache failed: {err:?}"))?;
 
-                return Ok(Arc::new(storage));
+                return Ok(with_timeout(
+                    Arc::new(RetriedOperator::new(storage, &Backoff::remote())),
+                    cache_type.name(),
+                ));
+            }
+            #[cfg(feature = "http-cache")]
+            CacheType::Http(ref c) => {
+                debug!("Init http cache with endpoint {}", c.endpoint);
+
+                let storage = HttpCache::build(
+                    &c.endpoint,
+                    c.token.as_deref(),
+                    c.rw_mode.into(),
+                    &c.request,
+                )
+                .context("create http cache failed")?;
+                return Ok(with_timeout(Arc::new(storage), cache_type.name()));
             }
             #[allow(unreachable_patterns)]
             // if we build only with `cargo build --no-default-features`
@@ -724,17 +1597,38 @@ pub fn storage_from_config(
         }
     }
 
+    let storage = Arc::new(disk_cache_from_config(config, pool)?);
+    Ok(with_memory_cache(
+        storage,
+        config.fallback_cache.memory_cache_size,
+    ))
+}
+
+/// Get the local disk cache configured by `config.fallback_cache`.
+pub fn disk_cache_from_config(config: &Config, pool: &tokio::runtime::Handle) -> Result<DiskCache> {
     let (dir, size) = (&config.fallback_cache.dir, config.fallback_cache.size);
     let preprocessor_cache_mode_config = config.fallback_cache.preprocessor_cache_mode;
     let rw_mode = config.fallback_cache.rw_mode.into();
+    let (high, low) = (
+        config.fallback_cache.high_watermark,
+        config.fallback_cache.low_watermark,
+    );
+    if !(1..=100).contains(&high) || !(1..=high).contains(&low) {
+        bail!(
+            "The eviction watermarks of the disk cache must be percentages, the low one \
+             not above the high one, got {}% and {}%",
+            high,
+            low
+        );
+    }
     debug!("Init disk cache with dir {:?}, size {}", dir, size);
-    Ok(Arc::new(DiskCache::new(
-        dir,
-        size,
-        pool,
-        preprocessor_cache_mode_config,
-        rw_mode,
-    )))
+    let cache = DiskCache::new(dir, size, pool, preprocessor_cache_mode_config, rw_mode)
+        .with_dedup(config.fallback_cache.dedup)
+        .with_watermarks(high, low);
+    Ok(match config::temp_dir() {
+        Some(temp_dir) => cache.with_temp_dir(&temp_dir),
+        None => cache,
+    })
 }
 
 #[cfg(test)]
@@ -742,6 +1636,237 @@ mod test {
     use super::*;
     use crate::config::CacheModeConfig;
 
+    #[test]
+    fn test_put_incompressible_object() {
+        use rand::RngCore;
+
+        let mut random = vec![0; 300 * 1024];
+        rand::thread_rng().fill_bytes(&mut random);
+        let text = "int main() { return 0; }\n".repeat(10000).into_bytes();
+        let gzip = [b"\x1f\x8b".as_slice(), &text[..100]].concat();
+        assert!(is_incompressible(&random[..COMPRESSIBILITY_SAMPLE]));
+        assert!(is_incompressible(&gzip));
+        assert!(!is_incompressible(&text[..COMPRESSIBILITY_SAMPLE]));
+        assert!(!is_incompressible(b""));
+
+        for (data, compressed) in [
+            (&random, false),
+            (&text, true),
+            (&gzip, false),
+            (&vec![], false),
+        ] {
+            let mut entry = CacheWrite::new();
+            entry
+                .put_object("obj", &mut Cursor::new(data), None)
+                .unwrap();
+            let entry = entry.finish().unwrap();
+            if compressed {
+                assert!(entry.len() < data.len() / 10);
+            } else {
+                assert!(entry.len() > data.len());
+            }
+            let mut entry = CacheRead::from(Cursor::new(entry)).unwrap();
+            let mut read = vec![];
+            entry.get_object("obj", &mut read).unwrap();
+            assert_eq!(&read, data);
+        }
+    }
+
+    #[test]
+    fn test_entry_format() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let tempdir = tempfile::Builder::new()
+            .prefix("sccache_test_entry_format")
+            .tempdir()
+            .unwrap();
+        let cache = DiskCache::new(
+            tempdir.path(),
+            u64::MAX,
+            runtime.handle(),
+            PreprocessorCacheModeConfig::default(),
+            CacheMode::ReadWrite,
+        );
+        let text = "int main() { return 0; }\n".repeat(1000);
+        runtime.block_on(async {
+            // Entries written with either compression read back whichever
+            // the reader would write.
+            for (key, compression) in [("aaaa", Compression::Zstd), ("bbbb", Compression::None)] {
+                let mut entry = CacheWrite::with_compression(compression);
+                entry.put_stdout(text.as_bytes()).unwrap();
+                cache.put(key, entry).await.unwrap();
+            }
+            let mut sizes = vec![];
+            for key in ["aaaa", "bbbb"] {
+                let Cache::Hit(mut entry) = cache.get(key).await.unwrap() else {
+                    panic!("no entry for {}", key);
+                };
+                assert_eq!(entry.get_stdout(), text.as_bytes());
+                sizes.push(entry.size());
+                let compression = entry.format().compression;
+                // Copying an entry keeps its compression.
+                let mut copy = CacheWrite::from_bytes(entry.into_bytes().unwrap()).unwrap();
+                assert_eq!(copy.format().compression, compression);
+                copy.put_stderr(b"warning").unwrap();
+                let mut copy = CacheRead::from(Cursor::new(copy.finish().unwrap())).unwrap();
+                assert_eq!(copy.get_stdout(), text.as_bytes());
+                assert_eq!(copy.get_stderr(), b"warning");
+            }
+            assert!(sizes[0] < sizes[1]);
+        });
+
+        // The entries of older versions have no format, and are compressed
+        // with zstd.
+        let mut zip = ZipWriter::new(Cursor::new(vec![]));
+        zip.start_file(
+            "stdout",
+            FileOptions::default().compression_method(CompressionMethod::Stored),
+        )
+        .unwrap();
+        zstd::stream::copy_encode(text.as_bytes(), &mut zip, COMPRESSION_LEVEL).unwrap();
+        let old = zip.finish().unwrap().into_inner();
+        let mut entry = CacheRead::from(Cursor::new(old)).unwrap();
+        assert_eq!(entry.format().compression, Compression::Zstd);
+        assert_eq!(entry.get_stdout(), text.as_bytes());
+
+        let format = EntryFormat {
+            compression: Compression::None,
+            dictionary: None,
+            hash: "blake3".to_owned(),
+            salt: Some("0123456789abcdef".to_owned()),
+            target: Some("aarch64-linux-64-little".to_owned()),
+        };
+        let content_type = format.content_type();
+        assert_eq!(
+            content_type,
+            "application/vnd.sccache.entry+zip; compression=none; hash=blake3; \
+             salt=0123456789abcdef; target=aarch64-linux-64-little"
+        );
+        assert_eq!(EntryFormat::parse(&content_type).unwrap(), format);
+        assert_eq!(
+            format.is_for_host(),
+            host_target() == "aarch64-linux-64-little"
+        );
+        assert!(EntryFormat::new(Compression::Zstd, None).is_for_host());
+        // The entries of older versions don't tell their target.
+        assert!(EntryFormat::parse("compression=zstd")
+            .unwrap()
+            .is_for_host());
+        assert_eq!(
+            EntryFormat::parse("compression=zstd; dict=1234; hash=blake3")
+                .unwrap()
+                .dictionary,
+            Some(1234)
+        );
+        assert!(EntryFormat::parse("compression=lz4").is_err());
+    }
+
+    /// The outputs restored under the umask of a client get the mode a
+    /// compiler run directly under it creates them with, whatever the mode
+    /// they were stored with.
+    #[cfg(unix)]
+    #[test]
+    fn test_extract_objects_umask() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        // What a compiler run directly under a restrictive umask creates.
+        let status = std::process::Command::new("sh")
+            .args(["-c", "umask 077 && : > direct.o"])
+            .current_dir(dir)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+        let direct = mode(&dir.join("direct.o"));
+        assert_eq!(direct, 0o600);
+
+        // An entry stored by a client with the usual umask.
+        let mut entry = CacheWrite::new();
+        entry
+            .put_object("obj", &mut Cursor::new(b"object"), Some(0o100644))
+            .unwrap();
+        let entry = entry.finish().unwrap();
+        let extract = |umask, name: &str| {
+            let entry = CacheRead::from(Cursor::new(entry.clone())).unwrap();
+            let outputs = vec![FileObjectSource {
+                key: "obj".to_owned(),
+                path: dir.join(name),
+                optional: false,
+            }];
+            runtime
+                .block_on(umask::scope(
+                    umask,
+                    entry.extract_objects(outputs, runtime.handle()),
+                ))
+                .unwrap();
+            mode(&dir.join(name))
+        };
+        assert_eq!(extract(Some(0o077), "restored.o"), direct);
+        // Without the umask of the client, the mode stored is kept.
+        assert_eq!(extract(None, "kept.o"), 0o644);
+    }
+
+    /// A build tool reading an output while it is restored sees the previous
+    /// one or the whole restored one, never a part of it.
+    #[test]
+    fn test_extract_objects_atomic() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("foo.o");
+        let previous = vec![b'a'; 1024 * 1024];
+        let restored = vec![b'b'; 4 * 1024 * 1024];
+        fs::write(&path, &previous).unwrap();
+        let mut entry = CacheWrite::new();
+        entry
+            .put_object("obj", &mut Cursor::new(&restored), Some(0o100644))
+            .unwrap();
+        let entry = entry.finish().unwrap();
+
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let reader = {
+            let (path, done) = (path.clone(), done.clone());
+            std::thread::spawn(move || {
+                let mut reads = vec![];
+                while !done.load(std::sync::atomic::Ordering::Acquire) {
+                    reads.push(fs::read(&path).unwrap());
+                }
+                reads
+            })
+        };
+        for _ in 0..10 {
+            let entry = CacheRead::from(Cursor::new(entry.clone())).unwrap();
+            let outputs = vec![FileObjectSource {
+                key: "obj".to_owned(),
+                path: path.clone(),
+                optional: false,
+            }];
+            runtime
+                .block_on(entry.extract_objects(outputs, runtime.handle()))
+                .unwrap();
+        }
+        done.store(true, std::sync::atomic::Ordering::Release);
+        for read in reader.join().unwrap() {
+            assert!(
+                read == previous || read == restored,
+                "read {} bytes",
+                read.len()
+            );
+        }
+        assert_eq!(fs::read(&path).unwrap(), restored);
+    }
+
     #[test]
     fn test_normalize_key() {
         assert_eq!(
@@ -750,6 +1875,38 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_disk_cache_watermarks() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut config = Config::default();
+        config.fallback_cache.dir = tempdir.path().to_owned();
+        config.fallback_cache.size = 1000;
+        config.fallback_cache.high_watermark = 80;
+        config.fallback_cache.low_watermark = 60;
+        let cache = storage_from_config(&config, runtime.handle()).unwrap();
+        runtime
+            .block_on(cache.put("abcd", CacheWrite::new()))
+            .unwrap();
+        assert_eq!(
+            cache.eviction_state(),
+            Some(EvictionState {
+                high_watermark: 800,
+                low_watermark: 600,
+                sweeping: false,
+            })
+        );
+
+        for (high, low) in [(0, 0), (101, 90), (80, 90)] {
+            config.fallback_cache.high_watermark = high;
+            config.fallback_cache.low_watermark = low;
+            assert!(storage_from_config(&config, runtime.handle()).is_err());
+        }
+    }
+
     #[test]
     fn test_read_write_mode_local() {
         let runtime = tokio::runtime::Builder::new_current_thread()
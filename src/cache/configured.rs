@@ -0,0 +1,132 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The storages built from a `Config`, which tell how the entries put in
+//! them are written as it says, rather than as the environment does.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use crate::cache::{
+    Cache, CacheMode, CacheOccupancy, CacheWrite, EntryStream, EvictionState, Storage,
+    StorageCounts,
+};
+use crate::compiler::PreprocessorCacheEntry;
+use crate::config::EntryConfig;
+use crate::errors::*;
+
+use super::PreprocessorCacheModeConfig;
+
+/// A storage whose entries are written as `entries` says.
+pub struct ConfiguredStorage {
+    storage: Arc<dyn Storage>,
+    entries: EntryConfig,
+}
+
+/// Wrap `storage` in a `ConfiguredStorage`, unless `entries` is what the
+/// environment says anyway.
+pub fn with_entry_config(storage: Arc<dyn Storage>, entries: EntryConfig) -> Arc<dyn Storage> {
+    if storage.entry_config() == entries {
+        return storage;
+    }
+    Arc::new(ConfiguredStorage { storage, entries })
+}
+
+#[async_trait]
+impl Storage for ConfiguredStorage {
+    async fn get(&self, key: &str) -> Result<Cache> {
+        self.storage.get(key).await
+    }
+
+    async fn put(&self, key: &str, entry: CacheWrite) -> Result<Duration> {
+        self.storage.put(key, entry).await
+    }
+
+    async fn check(&self) -> Result<CacheMode> {
+        self.storage.check().await
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.storage.is_read_only()
+    }
+
+    async fn probe(&self) -> Result<()> {
+        self.storage.probe().await
+    }
+
+    fn location(&self) -> String {
+        self.storage.location()
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        self.storage.list().await
+    }
+
+    async fn list_entries(&self, prefix: &str) -> Result<EntryStream> {
+        self.storage.list_entries(prefix).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool> {
+        self.storage.delete(key).await
+    }
+
+    async fn current_size(&self) -> Result<Option<u64>> {
+        self.storage.current_size().await
+    }
+
+    async fn max_size(&self) -> Result<Option<u64>> {
+        self.storage.max_size().await
+    }
+
+    fn requests_in_flight(&self) -> Option<usize> {
+        self.storage.requests_in_flight()
+    }
+
+    fn take_counts(&self) -> StorageCounts {
+        self.storage.take_counts()
+    }
+
+    fn eviction_state(&self) -> Option<EvictionState> {
+        self.storage.eviction_state()
+    }
+
+    fn occupancy(&self) -> Option<CacheOccupancy> {
+        self.storage.occupancy()
+    }
+
+    fn entry_config(&self) -> EntryConfig {
+        self.entries
+    }
+
+    fn preprocessor_cache_mode_config(&self) -> PreprocessorCacheModeConfig {
+        self.storage.preprocessor_cache_mode_config()
+    }
+
+    async fn get_preprocessor_cache_entry(
+        &self,
+        key: &str,
+    ) -> Result<Option<Box<dyn crate::lru_disk_cache::ReadSeek>>> {
+        self.storage.get_preprocessor_cache_entry(key).await
+    }
+
+    async fn put_preprocessor_cache_entry(
+        &self,
+        key: &str,
+        preprocessor_cache_entry: PreprocessorCacheEntry,
+    ) -> Result<()> {
+        self.storage
+            .put_preprocessor_cache_entry(key, preprocessor_cache_entry)
+            .await
+    }
+}
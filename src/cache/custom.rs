@@ -0,0 +1,421 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A storage backend implemented by an external helper executable.
+//!
+//! The helper is spawned lazily and kept alive for the lifetime of the server.
+//! It reads requests from its stdin and writes responses to its stdout, one
+//! request at a time. Every message is a frame made of a 4 byte big-endian
+//! length followed by that many bytes. Requests and responses are JSON
+//! documents; a `put` request and a `hit` response to a `get` request are
+//! followed by a second frame holding the raw cache entry.
+//!
+//! See `docs/Custom.md` for the full description of the protocol.
+
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::sync::Mutex;
+use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+
+use crate::cache::{Cache, CacheMode, CacheRead, CacheWrite, Storage};
+use crate::errors::*;
+
+/// The version of the helper protocol, exchanged in the `hello` handshake.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// A request sent from sccache to the helper.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Request {
+    /// First request sent after spawning the helper.
+    Hello { version: u32 },
+    /// Look up an entry, answered with `hit` (followed by the entry) or `miss`.
+    Get { key: String },
+    /// Check for an entry without transferring it, answered with `hit` or `miss`.
+    Contains { key: String },
+    /// Store the entry in the following frame, answered with `ok`.
+    Put { key: String },
+}
+
+/// A response sent from the helper to sccache.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Response {
+    /// Answer to `hello`, with the protocol version spoken by the helper.
+    Hello {
+        version: u32,
+        #[serde(default)]
+        read_only: bool,
+    },
+    Ok,
+    Hit,
+    Miss,
+    Error {
+        message: String,
+    },
+}
+
+fn codec() -> LengthDelimitedCodec {
+    LengthDelimitedCodec::builder()
+        .length_field_length(4)
+        .max_frame_length(u32::MAX as usize)
+        .new_codec()
+}
+
+/// A running helper process.
+struct Helper {
+    // Kept so that the helper gets killed once the connection is dropped.
+    _child: Child,
+    reader: FramedRead<ChildStdout, LengthDelimitedCodec>,
+    writer: FramedWrite<ChildStdin, LengthDelimitedCodec>,
+    mode: CacheMode,
+    /// Set while an exchange is in progress, so that a helper left midway,
+    /// by an error or by a dropped request, is not used again.
+    dirty: bool,
+}
+
+impl Helper {
+    async fn spawn(path: &Path) -> Result<Helper> {
+        let mut child = Command::new(path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("failed to spawn custom backend helper {:?}", path))?;
+        let stdin = child.stdin.take().expect("helper stdin is piped");
+        let stdout = child.stdout.take().expect("helper stdout is piped");
+        let mut helper = Helper {
+            _child: child,
+            reader: FramedRead::new(stdout, codec()),
+            writer: FramedWrite::new(stdin, codec()),
+            mode: CacheMode::ReadWrite,
+            dirty: false,
+        };
+
+        helper
+            .send(&Request::Hello {
+                version: PROTOCOL_VERSION,
+            })
+            .await?;
+        match helper.recv().await? {
+            Response::Hello { version, read_only } if version == PROTOCOL_VERSION => {
+                if read_only {
+                    helper.mode = CacheMode::ReadOnly;
+                }
+                Ok(helper)
+            }
+            Response::Hello { version, .. } => bail!(
+                "custom backend helper speaks protocol version {}, expected {}",
+                version,
+                PROTOCOL_VERSION
+            ),
+            res => bail!("unexpected response to hello from custom backend helper: {res:?}"),
+        }
+    }
+
+    async fn send(&mut self, req: &Request) -> Result<()> {
+        self.writer
+            .send(Bytes::from(serde_json::to_vec(req)?))
+            .await?;
+        Ok(())
+    }
+
+    async fn send_data(&mut self, data: Vec<u8>) -> Result<()> {
+        self.writer.send(Bytes::from(data)).await?;
+        Ok(())
+    }
+
+    async fn recv_data(&mut self) -> Result<Vec<u8>> {
+        match self.reader.next().await {
+            Some(frame) => Ok(frame?.to_vec()),
+            None => bail!("custom backend helper closed its stdout"),
+        }
+    }
+
+    async fn recv(&mut self) -> Result<Response> {
+        let frame = self.recv_data().await?;
+        serde_json::from_slice(&frame).context("invalid response from custom backend helper")
+    }
+
+    /// Send `req` and wait for its response, and for the entry following a
+    /// `hit` response to a `get` request.
+    async fn call(
+        &mut self,
+        req: Request,
+        data: Option<Vec<u8>>,
+    ) -> Result<(Response, Option<Vec<u8>>)> {
+        self.dirty = true;
+        self.send(&req).await?;
+        if let Some(data) = data {
+            self.send_data(data).await?;
+        }
+        let res = match (&req, self.recv().await?) {
+            (Request::Get { .. }, Response::Hit) => (Response::Hit, Some(self.recv_data().await?)),
+            (_, res) => (res, None),
+        };
+        self.dirty = false;
+        Ok(res)
+    }
+}
+
+/// A cache that forwards all the requests to a helper executable.
+pub struct CustomCache {
+    helper: PathBuf,
+    conn: Mutex<Option<Helper>>,
+}
+
+impl CustomCache {
+    /// Create a new `CustomCache`. The helper is spawned on first use.
+    pub fn new(helper: &Path) -> CustomCache {
+        CustomCache {
+            helper: helper.to_owned(),
+            conn: Mutex::new(None),
+        }
+    }
+
+    /// Get the helper, spawning a new one if there is none yet or if the
+    /// stream of the last one may be out of sync.
+    async fn connect<'a>(&self, conn: &'a mut Option<Helper>) -> Result<&'a mut Helper> {
+        if !matches!(conn, Some(helper) if !helper.dirty) {
+            // Drop the last helper first, which kills it.
+            *conn = None;
+            *conn = Some(Helper::spawn(&self.helper).await?);
+        }
+        Ok(conn.as_mut().expect("helper was just spawned"))
+    }
+
+    async fn call(
+        &self,
+        req: Request,
+        data: Option<Vec<u8>>,
+    ) -> Result<(Response, Option<Vec<u8>>)> {
+        let mut conn = self.conn.lock().await;
+        let helper = self.connect(&mut conn).await?;
+        match helper.call(req, data).await? {
+            (Response::Error { message }, _) => bail!("custom backend helper error: {message}"),
+            res => Ok(res),
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for CustomCache {
+    async fn get(&self, key: &str) -> Result<Cache> {
+        match self.call(Request::Get { key: key.into() }, None).await? {
+            (Response::Hit, Some(data)) => Ok(Cache::Hit(CacheRead::from(Cursor::new(data))?)),
+            (Response::Miss, _) => Ok(Cache::Miss),
+            (res, _) => bail!("unexpected response to get from custom backend helper: {res:?}"),
+        }
+    }
+
+    async fn put(&self, key: &str, entry: CacheWrite) -> Result<Duration> {
+        let start = Instant::now();
+        let data = entry.finish()?;
+        match self
+            .call(Request::Put { key: key.into() }, Some(data))
+            .await?
+        {
+            (Response::Ok, _) => Ok(start.elapsed()),
+            (res, _) => bail!("unexpected response to put from custom backend helper: {res:?}"),
+        }
+    }
+
+    async fn check(&self) -> Result<CacheMode> {
+        let mut conn = self.conn.lock().await;
+        Ok(self.connect(&mut conn).await?.mode)
+    }
+
+    fn location(&self) -> String {
+        format!("Custom backend, helper: {}", self.helper.display())
+    }
+
+    async fn current_size(&self) -> Result<Option<u64>> {
+        Ok(None)
+    }
+
+    async fn max_size(&self) -> Result<Option<u64>> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serial_test::serial;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_protocol_messages() {
+        let to_json = |req: &Request| serde_json::to_string(req).unwrap();
+        assert_eq!(
+            to_json(&Request::Hello { version: 1 }),
+            r#"{"op":"hello","version":1}"#
+        );
+        assert_eq!(
+            to_json(&Request::Get { key: "abc".into() }),
+            r#"{"op":"get","key":"abc"}"#
+        );
+        assert_eq!(
+            to_json(&Request::Put { key: "abc".into() }),
+            r#"{"op":"put","key":"abc"}"#
+        );
+
+        let from_json = |res: &str| serde_json::from_str::<Response>(res).unwrap();
+        assert_eq!(
+            from_json(r#"{"status":"hello","version":1}"#),
+            Response::Hello {
+                version: 1,
+                read_only: false
+            }
+        );
+        assert_eq!(from_json(r#"{"status":"miss"}"#), Response::Miss);
+        assert_eq!(
+            from_json(r#"{"status":"error","message":"nope"}"#),
+            Response::Error {
+                message: "nope".into()
+            }
+        );
+    }
+
+    /// The helper to check, either given by `SCCACHE_CUSTOM_BACKEND_TEST_HELPER`
+    /// or the reference helper from the `custom_backend_dir` example.
+    fn test_helper() -> Option<PathBuf> {
+        if let Some(helper) = std::env::var_os("SCCACHE_CUSTOM_BACKEND_TEST_HELPER") {
+            return Some(helper.into());
+        }
+        // Test executables live in `target/<profile>/deps`.
+        let exe = std::env::current_exe().ok()?;
+        let helper = exe.parent()?.parent()?.join("examples").join(format!(
+            "custom_backend_dir{}",
+            std::env::consts::EXE_SUFFIX
+        ));
+        helper.exists().then_some(helper)
+    }
+
+    async fn contains(cache: &CustomCache, key: &str) -> bool {
+        match cache
+            .call(Request::Contains { key: key.into() }, None)
+            .await
+        {
+            Ok((Response::Hit, _)) => true,
+            Ok((Response::Miss, _)) => false,
+            res => panic!("unexpected {res:?}"),
+        }
+    }
+
+    /// Conformance checks any helper is expected to pass.
+    async fn check_conformance(cache: Arc<CustomCache>) {
+        assert_eq!(cache.check().await.unwrap(), CacheMode::ReadWrite);
+
+        let key = "0123456789abcdef";
+        assert!(matches!(cache.get(key).await.unwrap(), Cache::Miss));
+        assert!(!contains(&cache, key).await);
+
+        let mut entry = CacheWrite::new();
+        entry.put_stdout(b"some stdout").unwrap();
+        cache.put(key, entry).await.unwrap();
+        assert!(contains(&cache, key).await);
+        match cache.get(key).await.unwrap() {
+            Cache::Hit(mut hit) => assert_eq!(hit.get_stdout(), b"some stdout"),
+            res => panic!("unexpected {res:?}"),
+        }
+
+        // Entries larger than the default codec frame limit.
+        let big = vec![0x5a; 16 * 1024 * 1024];
+        let mut entry = CacheWrite::new();
+        entry.put_object("obj", &mut &big[..], None).unwrap();
+        cache.put("big", entry).await.unwrap();
+        match cache.get("big").await.unwrap() {
+            Cache::Hit(mut hit) => {
+                let mut obj = vec![];
+                hit.get_object("obj", &mut obj).unwrap();
+                assert_eq!(obj, big);
+            }
+            res => panic!("unexpected {res:?}"),
+        }
+
+        // A helper left midway by a dropped request is replaced.
+        let pid = |conn: &Option<Helper>| conn.as_ref().unwrap()._child.id();
+        let old = {
+            let mut conn = cache.conn.lock().await;
+            conn.as_mut().unwrap().dirty = true;
+            pid(&conn)
+        };
+        assert!(matches!(cache.get("big").await.unwrap(), Cache::Hit(_)));
+        let conn = cache.conn.lock().await;
+        assert!(!conn.as_ref().unwrap().dirty);
+        assert_ne!(pid(&conn), old);
+        drop(conn);
+
+        // Concurrent requests are serialized on the helper's stdio.
+        let tasks = (0..8).map(|i| {
+            let cache = cache.clone();
+            tokio::spawn(async move {
+                let key = format!("concurrent{i}");
+                let mut entry = CacheWrite::new();
+                entry.put_stderr(key.as_bytes()).unwrap();
+                cache.put(&key, entry).await.unwrap();
+                match cache.get(&key).await.unwrap() {
+                    Cache::Hit(mut hit) => assert_eq!(hit.get_stderr(), key.as_bytes()),
+                    res => panic!("unexpected {res:?}"),
+                }
+            })
+        });
+        for task in tasks.collect::<Vec<_>>() {
+            task.await.unwrap();
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_custom_backend_conformance() {
+        let Some(helper) = test_helper() else {
+            eprintln!("custom_backend_dir example not built, skipping");
+            return;
+        };
+        let tempdir = tempfile::Builder::new()
+            .prefix("sccache_test_custom")
+            .tempdir()
+            .unwrap();
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .worker_threads(2)
+            .build()
+            .unwrap();
+
+        temp_env::with_var("SCCACHE_CUSTOM_DIR", Some(tempdir.path()), || {
+            runtime.block_on(check_conformance(Arc::new(CustomCache::new(&helper))))
+        });
+    }
+
+    #[test]
+    fn test_custom_backend_missing_helper() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let cache = CustomCache::new(Path::new("/nonexistent/sccache-helper"));
+        runtime.block_on(async {
+            assert!(cache.check().await.is_err());
+            assert!(cache.get("abc").await.is_err());
+        });
+    }
+}
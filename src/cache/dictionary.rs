@@ -0,0 +1,261 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! zstd dictionaries for compressing the objects of cache entries, which are
+//! mostly small and alike, so that each compresses with what they have in
+//! common instead of on its own, see `sccache --train-dict`.
+//!
+//! `SCCACHE_ZSTD_DICT` is a list of dictionary files, separated as in `PATH`.
+//! The first compresses the entries written, and all of them decompress the
+//! entries read, found by the ID the entries record, see `EntryFormat`, so
+//! that the entries written with the previous dictionaries still read once
+//! another was trained.
+
+use once_cell::sync::Lazy;
+use std::env;
+use std::path::Path;
+use std::sync::Arc;
+use zstd::dict::{DecoderDictionary, EncoderDictionary};
+
+use crate::cache::{Cache, Storage, COMPRESSION_LEVEL};
+use crate::errors::*;
+
+/// The size of the dictionaries trained, about what zstd suggests.
+pub const DICTIONARY_SIZE: usize = 110 * 1024;
+
+/// How many entries are sampled at most to train a dictionary.
+const MAX_SAMPLED_ENTRIES: usize = 2000;
+
+/// The objects larger than this gain little from a dictionary, and are left
+/// out of the samples.
+const MAX_SAMPLE_SIZE: usize = 256 * 1024;
+
+/// One in this many samples is kept out of the training to measure the
+/// dictionary.
+const MEASURE_ONE_IN: usize = 5;
+
+/// The dictionaries of `SCCACHE_ZSTD_DICT`.
+static DICTIONARIES: Lazy<Vec<Arc<Dictionary>>> = Lazy::new(|| {
+    let Some(paths) = env::var_os("SCCACHE_ZSTD_DICT") else {
+        return vec![];
+    };
+    env::split_paths(&paths)
+        .filter(|path| !path.as_os_str().is_empty())
+        .filter_map(|path| match Dictionary::open(&path) {
+            Ok(dictionary) => Some(Arc::new(dictionary)),
+            Err(e) => {
+                warn!("Not using the zstd dictionary {}: {:#}", path.display(), e);
+                None
+            }
+        })
+        .collect()
+});
+
+/// A zstd dictionary, prepared for compressing at `COMPRESSION_LEVEL` and for
+/// decompressing.
+pub struct Dictionary {
+    pub id: u32,
+    pub encoder: EncoderDictionary<'static>,
+    pub decoder: DecoderDictionary<'static>,
+}
+
+impl Dictionary {
+    /// Prepare the dictionary `bytes`, which must have the header of the
+    /// dictionaries zstd trains, holding its ID.
+    pub fn new(bytes: &[u8]) -> Result<Dictionary> {
+        let Some(id) = zstd::zstd_safe::get_dict_id_from_dict(bytes) else {
+            bail!("not a zstd dictionary");
+        };
+        Ok(Dictionary {
+            id: id.get(),
+            encoder: EncoderDictionary::copy(bytes, COMPRESSION_LEVEL),
+            decoder: DecoderDictionary::copy(bytes),
+        })
+    }
+
+    fn open(path: &Path) -> Result<Dictionary> {
+        let bytes = std::fs::read(path).context("failed to read it")?;
+        Dictionary::new(&bytes)
+    }
+}
+
+/// The dictionary the entries written from now on are compressed with, if
+/// any.
+pub fn for_writing() -> Option<Arc<Dictionary>> {
+    DICTIONARIES.first().cloned()
+}
+
+/// The dictionary with `id`, to decompress an entry written with it.
+pub fn find(id: u32) -> Option<Arc<Dictionary>> {
+    DICTIONARIES
+        .iter()
+        .find(|dictionary| dictionary.id == id)
+        .cloned()
+}
+
+/// A dictionary trained from the entries of a cache, and how it does on the
+/// objects left out of the training.
+#[derive(Debug)]
+pub struct Training {
+    pub dictionary: Vec<u8>,
+    pub id: u32,
+    /// The entries and objects sampled.
+    pub entries: usize,
+    pub samples: usize,
+    /// The size of the objects measured, and their total size compressed
+    /// one by one without and with the dictionary.
+    pub measured_size: u64,
+    pub compressed_size: u64,
+    pub compressed_size_with_dictionary: u64,
+}
+
+impl Training {
+    /// How much smaller the objects compress with the dictionary, e.g. 1.5
+    /// when they take two thirds of the space they take without it.
+    pub fn improvement(&self) -> f64 {
+        self.compressed_size as f64 / self.compressed_size_with_dictionary.max(1) as f64
+    }
+}
+
+/// Train a dictionary of at most `size` bytes from the objects of up to
+/// `MAX_SAMPLED_ENTRIES` entries of `storage`, spread across its keys.
+pub async fn train(storage: &dyn Storage, size: usize) -> Result<Training> {
+    let keys = storage.list().await?;
+    let step = keys.len() / MAX_SAMPLED_ENTRIES + 1;
+    let mut entries = 0;
+    let mut samples = vec![];
+    for key in keys.iter().step_by(step) {
+        let mut entry = match storage.get(key).await? {
+            Cache::Hit(entry) => entry,
+            // The entry was evicted since it was listed.
+            Cache::Miss | Cache::Recache => continue,
+        };
+        entries += 1;
+        for name in entry.object_names() {
+            let mut object = vec![];
+            if entry.get_object(&name, &mut object).is_ok()
+                && !object.is_empty()
+                && object.len() <= MAX_SAMPLE_SIZE
+            {
+                samples.push(object);
+            }
+        }
+    }
+    let sample_count = samples.len();
+    let (measured, training): (Vec<_>, Vec<_>) = samples
+        .into_iter()
+        .enumerate()
+        .partition(|(i, _)| i % MEASURE_ONE_IN == 0);
+    let training: Vec<_> = training.into_iter().map(|(_, sample)| sample).collect();
+    let dictionary = zstd::dict::from_samples(&training, size).with_context(|| {
+        format!(
+            "failed to train a dictionary from {} objects of {} entries",
+            sample_count, entries
+        )
+    })?;
+    let id = Dictionary::new(&dictionary)?.id;
+
+    let mut compressor = zstd::bulk::Compressor::new(COMPRESSION_LEVEL)?;
+    let mut compressor_with_dictionary =
+        zstd::bulk::Compressor::with_dictionary(COMPRESSION_LEVEL, &dictionary)?;
+    let mut training = Training {
+        dictionary: vec![],
+        id,
+        entries,
+        samples: sample_count,
+        measured_size: 0,
+        compressed_size: 0,
+        compressed_size_with_dictionary: 0,
+    };
+    for (_, sample) in measured {
+        training.measured_size += sample.len() as u64;
+        training.compressed_size += compressor.compress(&sample)?.len() as u64;
+        training.compressed_size_with_dictionary +=
+            compressor_with_dictionary.compress(&sample)?.len() as u64;
+    }
+    training.dictionary = dictionary;
+    Ok(training)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::disk::DiskCache;
+    use crate::cache::{
+        CacheMode, CacheRead, CacheWrite, Compression, PreprocessorCacheModeConfig,
+    };
+    use crate::test::utils::TestFixture;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_train_dictionary() {
+        let f = TestFixture::new();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let storage = DiskCache::new(
+            f.tempdir.path(),
+            u64::MAX,
+            runtime.handle(),
+            PreprocessorCacheModeConfig::default(),
+            CacheMode::ReadWrite,
+        );
+        // Small objects with much in common, as those of a project are.
+        let object = |i: usize| {
+            let mut object = b"\x7fELF\x02\x01\x01".to_vec();
+            for j in 0..24 {
+                object.extend_from_slice(
+                    format!(".text.project_module_{}_function_{} ", (i * 7 + j) % 97, j).as_bytes(),
+                );
+                object.extend_from_slice(&[(i % 251) as u8, (j * i % 253) as u8, 0, 0]);
+                object.extend_from_slice(b"__cxa_begin_catch _Unwind_Resume memcpy");
+            }
+            object
+        };
+        runtime.block_on(async {
+            for i in 0..400 {
+                let mut entry = CacheWrite::with_compression(Compression::Zstd);
+                entry
+                    .put_object("obj", &mut Cursor::new(object(i)), None)
+                    .unwrap();
+                storage.put(&format!("key{:04}", i), entry).await.unwrap();
+            }
+        });
+
+        let training = runtime.block_on(train(&storage, 16 * 1024)).unwrap();
+        assert_eq!(training.entries, 400);
+        assert_eq!(training.samples, 400);
+        assert!(training.dictionary.len() <= 16 * 1024);
+        assert!(training.improvement() > 1.0, "{:?}", training.improvement());
+
+        // An entry compressed with the dictionary records it, and reads with
+        // it.
+        let dictionary = Arc::new(Dictionary::new(&training.dictionary).unwrap());
+        assert_eq!(dictionary.id, training.id);
+        let mut entry = CacheWrite::with_dictionary(dictionary.clone());
+        assert_eq!(entry.format().dictionary, Some(training.id));
+        entry
+            .put_object("obj", &mut Cursor::new(object(1000)), None)
+            .unwrap();
+        let bytes = entry.finish().unwrap();
+        let mut entry =
+            CacheRead::with_dictionaries(Cursor::new(bytes.clone()), |_| Some(dictionary)).unwrap();
+        assert_eq!(entry.format().dictionary, Some(training.id));
+        let mut out = vec![];
+        entry.get_object("obj", &mut out).unwrap();
+        assert_eq!(out, object(1000));
+        // Without it, the entry can't be read.
+        let mut entry = CacheRead::with_dictionaries(Cursor::new(bytes), |_| None).unwrap();
+        assert!(entry.get_object("obj", &mut vec![]).is_err());
+    }
+}
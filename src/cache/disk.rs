@@ -12,68 +12,326 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::cache::{Cache, CacheMode, CacheRead, CacheWrite, Storage};
+use crate::cache::{
+    Cache, CacheMode, CacheOccupancy, CacheRead, CacheWrite, EntryInfo, EntryStream, EvictionState,
+    Storage,
+};
 use crate::compiler::PreprocessorCacheEntry;
-use crate::lru_disk_cache::LruDiskCache;
-use crate::lru_disk_cache::{Error as LruError, ReadSeek};
+use crate::config;
+use crate::lru_disk_cache::{Error as LruError, ReadSeek, Result as LruResult};
+use crate::lru_disk_cache::{ShardedLruDiskCache, DEFAULT_SHARDS};
+use crate::util::Digest;
 use async_trait::async_trait;
+use fs_err::File;
+use futures::StreamExt;
+use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
-use std::io::{BufWriter, Write};
+use std::io::{self, BufWriter, Read, Seek, Write};
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
 use crate::errors::*;
 
+use super::layout::{self, KeyLayout};
 use super::{normalize_key, PreprocessorCacheModeConfig};
 
-enum LazyDiskCache {
-    Uninit { root: OsString, max_size: u64 },
-    Init(LruDiskCache),
+/// A `ShardedLruDiskCache` created on first use.
+struct LazyDiskCache {
+    root: OsString,
+    max_size: u64,
+    /// The high and low watermarks, in percent of `max_size`.
+    watermarks: (u8, u8),
+    /// Where the entries being inserted are written, if not in `root`.
+    temp_dir: Option<PathBuf>,
+    /// The references to the payloads, if identical entries are stored once.
+    refs: Option<Arc<PayloadRefs>>,
+    cache: Mutex<Option<Arc<ShardedLruDiskCache>>>,
 }
 
 impl LazyDiskCache {
-    fn get_or_init(&mut self) -> Result<&mut LruDiskCache> {
-        match self {
-            LazyDiskCache::Uninit { root, max_size } => {
-                *self = LazyDiskCache::Init(LruDiskCache::new(&root, *max_size)?);
-                self.get_or_init()
-            }
-            LazyDiskCache::Init(d) => Ok(d),
+    fn new(root: OsString, max_size: u64) -> Self {
+        LazyDiskCache {
+            root,
+            max_size,
+            watermarks: DEFAULT_WATERMARKS,
+            temp_dir: None,
+            refs: None,
+            cache: Mutex::new(None),
         }
     }
 
-    fn get(&mut self) -> Option<&mut LruDiskCache> {
-        match self {
-            LazyDiskCache::Uninit { .. } => None,
-            LazyDiskCache::Init(d) => Some(d),
+    fn get_or_init(&self) -> Result<Arc<ShardedLruDiskCache>> {
+        let mut cache = self.cache.lock().unwrap();
+        if cache.is_none() {
+            let (high, low) = self.watermarks;
+            let mut lru = ShardedLruDiskCache::new(&self.root, self.max_size, DEFAULT_SHARDS)?
+                .with_watermarks(high, low);
+            if let Some(dir) = &self.temp_dir {
+                lru = lru.with_temp_dir(dir.clone())?;
+            }
+            *cache = Some(Arc::new(lru));
         }
+        Ok(cache.clone().unwrap())
+    }
+
+    fn get(&self) -> Option<Arc<ShardedLruDiskCache>> {
+        self.cache.lock().unwrap().clone()
     }
 
     fn capacity(&self) -> u64 {
-        match self {
-            LazyDiskCache::Uninit { max_size, .. } => *max_size,
-            LazyDiskCache::Init(d) => d.capacity(),
-        }
+        self.max_size
     }
 
     fn path(&self) -> &Path {
-        match self {
-            LazyDiskCache::Uninit { root, .. } => root.as_ref(),
-            LazyDiskCache::Init(d) => d.path(),
+        self.root.as_ref()
+    }
+}
+
+/// The default high and low watermarks of the eviction, in percent of the
+/// maximum size.
+const DEFAULT_WATERMARKS: (u8, u8) = (
+    config::DEFAULT_EVICTION_HIGH_WATERMARK,
+    config::DEFAULT_EVICTION_LOW_WATERMARK,
+);
+
+/// Evict the least recently used entries of `cache` on a background thread
+/// of `pool` if it grew past its capacity, so that the insertions don't wait
+/// for the files to be removed.
+fn sweep_in_background(cache: &LazyDiskCache, pool: &tokio::runtime::Handle) {
+    let Some(lru) = cache.get() else {
+        return;
+    };
+    let Some(evicted) = lru.start_sweep() else {
+        return;
+    };
+    trace!("Sweeping {} entries from the disk cache", evicted.len());
+    let refs = cache.refs.clone();
+    pool.spawn_blocking(move || {
+        for key in evicted {
+            remove_evicted(&lru, refs.as_deref(), &key);
+        }
+        lru.finish_sweep();
+    });
+}
+
+/// Remove the file of the entry `key` evicted from `lru`, and with `refs`,
+/// the payload it pointed to if no other entry does.
+fn remove_evicted(lru: &ShardedLruDiskCache, refs: Option<&PayloadRefs>, key: &OsStr) {
+    let payload = refs.and_then(|_| read_pointer_at(lru, key.as_ref()));
+    if let Err(e) = lru.remove_evicted(key) {
+        warn!("Failed to remove {:?} from the disk cache: {}", key, e);
+        return;
+    }
+    // Unless the entry was added back since.
+    if let (Some(refs), Some(digest)) = (refs, payload) {
+        if !lru.path().join(key).exists() {
+            release_payload(lru, refs, &digest);
+        }
+    }
+}
+
+/// What an entry of a cache storing identical entries once holds instead of
+/// the entry itself: this prefix, then the digest of the entry, its payload.
+const POINTER_PREFIX: &[u8] = b"sccache-payload:";
+
+/// The key of the payload of digest `digest`, apart from the entries.
+fn payload_key(digest: &str) -> PathBuf {
+    Path::new("payloads").join(&digest[..2]).join(digest)
+}
+
+/// Read the digest of the payload `file` points to, unless it is an entry
+/// itself, leaving it at its start.
+fn read_pointer(file: &mut File) -> io::Result<Option<String>> {
+    // Much larger than a pointer, much smaller than most entries.
+    if file.metadata()?.len() > 256 {
+        return Ok(None);
+    }
+    let mut data = vec![];
+    file.read_to_end(&mut data)?;
+    file.rewind()?;
+    Ok(data
+        .strip_prefix(POINTER_PREFIX)
+        .and_then(|digest| std::str::from_utf8(digest).ok())
+        .filter(|digest| digest.len() > 2)
+        .map(ToOwned::to_owned))
+}
+
+/// Read the digest of the payload the entry `key` points to, without using
+/// the entry.
+fn read_pointer_at(lru: &ShardedLruDiskCache, key: &Path) -> Option<String> {
+    let mut file = File::open(lru.path().join(key)).ok()?;
+    read_pointer(&mut file).ok().flatten()
+}
+
+/// Read the build which made the entry `key`, if it was given one, without
+/// using the entry.
+fn read_build_id_at(lru: &ShardedLruDiskCache, key: &Path) -> Option<String> {
+    let mut file = File::open(lru.path().join(key)).ok()?;
+    if let Some(digest) = read_pointer(&mut file).ok()? {
+        file = File::open(lru.path().join(payload_key(&digest))).ok()?;
+    }
+    CacheRead::from(file).ok()?.get_build_id()
+}
+
+/// The count of the entries pointing to each payload of a cache storing
+/// identical entries once.
+///
+/// The payloads are used whenever the entries pointing to them are, after
+/// them, so that they are evicted after them. The count removes the payloads
+/// as soon as the last entry pointing to them is evicted, rather than once
+/// they are the least recently used.
+#[derive(Default)]
+struct PayloadRefs {
+    /// The counts by digest, from the entries on disk when first used.
+    counts: Mutex<Option<HashMap<String, u64>>>,
+}
+
+impl PayloadRefs {
+    fn with_counts<T>(
+        &self,
+        lru: &ShardedLruDiskCache,
+        f: impl FnOnce(&mut HashMap<String, u64>) -> T,
+    ) -> T {
+        let mut counts = self.counts.lock().unwrap();
+        f(counts.get_or_insert_with(|| {
+            let mut counts = HashMap::new();
+            for key in lru.keys() {
+                if let Some(digest) = read_pointer_at(lru, key.as_ref()) {
+                    *counts.entry(digest).or_default() += 1;
+                }
+            }
+            counts
+        }))
+    }
+
+    fn acquire(&self, lru: &ShardedLruDiskCache, digest: &str) {
+        self.with_counts(lru, |counts| {
+            *counts.entry(digest.to_owned()).or_default() += 1;
+        })
+    }
+
+    /// Drop a reference to the payload `digest`, and return whether it was
+    /// the last.
+    fn release(&self, lru: &ShardedLruDiskCache, digest: &str) -> bool {
+        self.with_counts(lru, |counts| match counts.get_mut(digest) {
+            Some(count) if *count > 1 => {
+                *count -= 1;
+                false
+            }
+            _ => {
+                counts.remove(digest);
+                true
+            }
+        })
+    }
+}
+
+/// Drop a reference to the payload `digest` of `lru`, removing it if it was
+/// the last.
+fn release_payload(lru: &ShardedLruDiskCache, refs: &PayloadRefs, digest: &str) {
+    if refs.release(lru, digest) {
+        trace!("Removing the payload {} from the disk cache", digest);
+        if let Err(e) = lru.remove(payload_key(digest)) {
+            warn!(
+                "Failed to remove the payload {} from the disk cache: {}",
+                digest, e
+            );
+        }
+    }
+}
+
+/// Store `data` in `lru` at `key` as a pointer to its payload, storing the
+/// payload unless an identical entry already did.
+fn store_deduplicated(
+    lru: &ShardedLruDiskCache,
+    refs: &PayloadRefs,
+    key: &Path,
+    data: &[u8],
+) -> Result<()> {
+    let mut digest = Digest::new();
+    digest.update(data);
+    let digest = digest.finish();
+    let mut pointer = POINTER_PREFIX.to_vec();
+    pointer.extend_from_slice(digest.as_bytes());
+    let previous = read_pointer_at(lru, key);
+    // Before the pointer is there to be counted.
+    refs.acquire(lru, &digest);
+    if let Err(e) = store_entry(lru, Some(refs), key, &pointer, |f, data| f.write_all(data)) {
+        release_payload(lru, refs, &digest);
+        return Err(e);
+    }
+    if let Some(previous) = previous {
+        release_payload(lru, refs, &previous);
+    }
+    // Using the payload after the pointer keeps it from being evicted first.
+    // If storing it fails, the pointer is a cache miss.
+    let payload = payload_key(&digest);
+    if lru.get_file(&payload).is_err() {
+        store_entry(lru, Some(refs), &payload, data, |f, data| f.write_all(data))?;
+    }
+    Ok(())
+}
+
+/// Whether `e` is due to the disk being full.
+fn is_disk_full(e: &io::Error) -> bool {
+    // `ERROR_HANDLE_DISK_FULL` and `ERROR_DISK_FULL`
+    let codes: &[i32] = if cfg!(windows) {
+        &[39, 112]
+    } else {
+        &[libc::ENOSPC]
+    };
+    e.raw_os_error().is_some_and(|code| codes.contains(&code))
+}
+
+/// Store `data` in `lru` at `key` with `write`. If the disk is full, evict
+/// the least recently used entries to free as much space as `data` takes and
+/// try once more, and fail with `DiskFull` if it still is.
+fn store_entry(
+    lru: &ShardedLruDiskCache,
+    refs: Option<&PayloadRefs>,
+    key: &Path,
+    data: &[u8],
+    write: impl Fn(&mut std::fs::File, &[u8]) -> io::Result<()>,
+) -> Result<()> {
+    let try_store = || -> LruResult<()> {
+        let mut f = lru.prepare_add(key, data.len() as u64)?;
+        if let Err(e) = write(f.as_file_mut(), data) {
+            lru.discard(f);
+            return Err(e.into());
         }
+        lru.commit(f)
+    };
+    match try_store() {
+        Err(LruError::Io(e)) if is_disk_full(&e) => {}
+        res => return Ok(res?),
+    }
+    let evicted = lru.evict(data.len() as u64);
+    warn!(
+        "The disk of the cache is full, evicting {} entries to store {:?}",
+        evicted.len(),
+        key
+    );
+    for key in evicted {
+        remove_evicted(lru, refs, &key);
+    }
+    match try_store() {
+        Err(LruError::Io(e)) if is_disk_full(&e) => Err(Error::from(e).context(DiskFull)),
+        res => Ok(res?),
     }
 }
 
 /// A cache that stores entries at local disk paths.
 pub struct DiskCache {
-    /// `LruDiskCache` does all the real work here.
-    lru: Arc<Mutex<LazyDiskCache>>,
+    /// `ShardedLruDiskCache` does all the real work here.
+    lru: Arc<LazyDiskCache>,
     /// Thread pool to execute disk I/O
     pool: tokio::runtime::Handle,
     preprocessor_cache_mode_config: PreprocessorCacheModeConfig,
-    preprocessor_cache: Arc<Mutex<LazyDiskCache>>,
+    preprocessor_cache: Arc<LazyDiskCache>,
     rw_mode: CacheMode,
+    /// Where the entries are, the first one for writing, see `layout`.
+    layouts: Vec<KeyLayout>,
 }
 
 impl DiskCache {
@@ -86,51 +344,109 @@ impl DiskCache {
         rw_mode: CacheMode,
     ) -> DiskCache {
         DiskCache {
-            lru: Arc::new(Mutex::new(LazyDiskCache::Uninit {
-                root: root.as_ref().to_os_string(),
-                max_size,
-            })),
+            lru: Arc::new(LazyDiskCache::new(root.as_ref().to_os_string(), max_size)),
             pool: pool.clone(),
             preprocessor_cache_mode_config,
-            preprocessor_cache: Arc::new(Mutex::new(LazyDiskCache::Uninit {
-                root: Path::new(root.as_ref())
+            preprocessor_cache: Arc::new(LazyDiskCache::new(
+                Path::new(root.as_ref())
                     .join("preprocessor")
                     .into_os_string(),
                 max_size,
-            })),
+            )),
             rw_mode,
+            layouts: layout::local().to_vec(),
         }
     }
-}
 
-/// Make a path to the cache entry with key `key`.
-fn make_key_path(key: &str) -> PathBuf {
-    Path::new(&key[0..1]).join(&key[1..2]).join(key)
+    /// Write the entries with the first of `layouts`, and look them up with
+    /// each in turn, instead of those of `SCCACHE_CACHE_SHARD_DEPTH`.
+    #[cfg(test)]
+    pub fn with_layouts(mut self, layouts: Vec<KeyLayout>) -> DiskCache {
+        assert!(!layouts.is_empty());
+        self.layouts = layouts;
+        self
+    }
+
+    /// Write the entries being inserted in `dir` before moving them into the
+    /// cache, rather than in the cache directory.
+    pub fn with_temp_dir(mut self, dir: &Path) -> DiskCache {
+        for lru in [&mut self.lru, &mut self.preprocessor_cache] {
+            let lru = Arc::get_mut(lru).expect("The cache isn't shared yet");
+            // Caches sharing `dir` each remove the leftovers in their own
+            // directory only.
+            let mut digest = Digest::new();
+            digest.update(lru.root.to_string_lossy().as_bytes());
+            lru.temp_dir = Some(dir.join(format!("sccache-{}", &digest.finish()[..16])));
+        }
+        self
+    }
+
+    /// Store identical entries once, the entries pointing to their payload,
+    /// which is counted once in the size of the cache.
+    pub fn with_dedup(mut self, dedup: bool) -> DiskCache {
+        Arc::get_mut(&mut self.lru)
+            .expect("The cache isn't shared yet")
+            .refs = dedup.then(Default::default);
+        self
+    }
+
+    /// Evict entries once the cache is larger than `high` percent of its
+    /// maximum size, until it is down to `low` percent of it.
+    pub fn with_watermarks(mut self, high: u8, low: u8) -> DiskCache {
+        for lru in [&mut self.lru, &mut self.preprocessor_cache] {
+            Arc::get_mut(lru)
+                .expect("The cache isn't shared yet")
+                .watermarks = (high, low);
+        }
+        self
+    }
 }
 
 #[async_trait]
 impl Storage for DiskCache {
     async fn get(&self, key: &str) -> Result<Cache> {
         trace!("DiskCache::get({})", key);
-        let path = make_key_path(key);
+        let paths: Vec<_> = self
+            .layouts
+            .iter()
+            .map(|layout| layout.local_path(key))
+            .collect();
         let lru = self.lru.clone();
         let key = key.to_owned();
 
         self.pool
             .spawn_blocking(move || {
-                let io = match lru.lock().unwrap().get_or_init()?.get(&path) {
-                    Ok(f) => f,
+                let lru = lru.get_or_init()?;
+                let get_file = |path: &Path| match lru.get_file(path) {
+                    Ok(f) => Ok(Some(f)),
                     Err(LruError::FileNotInCache) => {
                         trace!("DiskCache::get({}): FileNotInCache", key);
-                        return Ok(Cache::Miss);
+                        Ok(None)
                     }
                     Err(LruError::Io(e)) => {
                         trace!("DiskCache::get({}): IoError: {:?}", key, e);
-                        return Err(e.into());
+                        Err(Error::from(e))
                     }
                     Err(_) => unreachable!(),
                 };
-                let hit = CacheRead::from(io)?;
+                let mut found = None;
+                for path in &paths {
+                    found = get_file(path)?;
+                    if found.is_some() {
+                        break;
+                    }
+                }
+                let Some(mut file) = found else {
+                    return Ok(Cache::Miss);
+                };
+                // The entries stored while deduplicating stay readable without.
+                if let Some(digest) = read_pointer(&mut file)? {
+                    let Some(payload) = get_file(&payload_key(&digest))? else {
+                        return Ok(Cache::Miss);
+                    };
+                    file = payload;
+                }
+                let hit = CacheRead::from(file)?;
                 Ok(Cache::Hit(hit))
             })
             .await?
@@ -146,50 +462,179 @@ impl Storage for DiskCache {
         }
 
         let lru = self.lru.clone();
-        let key = make_key_path(key);
+        let key = self.layouts[0].local_path(key);
 
-        self.pool
+        let duration = self
+            .pool
             .spawn_blocking(move || {
                 let start = Instant::now();
                 let v = entry.finish()?;
-                let mut f = lru
-                    .lock()
-                    .unwrap()
-                    .get_or_init()?
-                    .prepare_add(key, v.len() as u64)?;
-                f.as_file_mut().write_all(&v)?;
-                lru.lock().unwrap().get().unwrap().commit(f)?;
-                Ok(start.elapsed())
+                let refs = lru.refs.clone();
+                let lru = lru.get_or_init()?;
+                match refs {
+                    Some(refs) => store_deduplicated(&lru, &refs, &key, &v)?,
+                    None => store_entry(&lru, None, &key, &v, |f, data| f.write_all(data))?,
+                }
+                Ok::<_, Error>(start.elapsed())
             })
-            .await?
+            .await??;
+        sweep_in_background(&self.lru, &self.pool);
+        Ok(duration)
     }
 
     async fn check(&self) -> Result<CacheMode> {
         Ok(self.rw_mode)
     }
 
+    fn is_read_only(&self) -> bool {
+        self.rw_mode == CacheMode::ReadOnly
+    }
+
+    async fn probe(&self) -> Result<()> {
+        // Unlike the other operations, don't create the cache directory.
+        let root = self.lru.path().to_owned();
+        let existing = root
+            .ancestors()
+            .find(|p| p.exists())
+            .ok_or_else(|| anyhow!("none of the parents of {} exist", root.display()))?;
+        if !existing.is_dir() {
+            bail!("{} is not a directory", existing.display());
+        }
+        std::fs::read_dir(existing)
+            .with_context(|| format!("failed to read the directory {}", existing.display()))?;
+        Ok(())
+    }
+
     fn location(&self) -> String {
-        format!("Local disk: {:?}", self.lru.lock().unwrap().path())
+        format!("Local disk: {:?}", self.lru.path())
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        let lru = self.lru.clone();
+        let layouts = self.layouts.clone();
+        self.pool
+            .spawn_blocking(move || {
+                let mut keys: Vec<_> = lru
+                    .get_or_init()?
+                    .keys()
+                    .into_iter()
+                    .filter_map(|path| {
+                        // Skip anything that isn't a cache entry, e.g. the
+                        // payloads of deduplicated entries.
+                        let path = Path::new(&path);
+                        let key = layouts
+                            .iter()
+                            .find_map(|layout| layout.key_of_local(path))?;
+                        Some(key.to_owned())
+                    })
+                    .collect();
+                // An entry may be in several layouts.
+                keys.sort_unstable();
+                keys.dedup();
+                Ok(keys)
+            })
+            .await?
+    }
+
+    async fn list_entries(&self, prefix: &str) -> Result<EntryStream> {
+        let lru = self.lru.clone();
+        let layouts = self.layouts.clone();
+        let prefix = prefix.to_owned();
+        let entries = self
+            .pool
+            .spawn_blocking(move || {
+                let lru = lru.get_or_init()?;
+                let mut found: Vec<_> = lru
+                    .keys()
+                    .into_iter()
+                    .filter_map(|path| {
+                        let key = layouts
+                            .iter()
+                            .find_map(|layout| layout.key_of_local(Path::new(&path)))?;
+                        Some((key.to_owned(), path))
+                    })
+                    .filter(|(key, _)| key.starts_with(&prefix))
+                    .collect();
+                // An entry may be in several layouts.
+                found.sort_unstable();
+                found.dedup_by(|a, b| a.0 == b.0);
+                let entries: Vec<_> = found
+                    .into_iter()
+                    // The entries evicted meanwhile are left out.
+                    .filter_map(|(key, path)| {
+                        let metadata = std::fs::metadata(lru.path().join(&path)).ok()?;
+                        Some(EntryInfo {
+                            key,
+                            size: metadata.len(),
+                            modified: metadata.modified().ok(),
+                            build_id: read_build_id_at(&lru, Path::new(&path)),
+                        })
+                    })
+                    .collect();
+                Ok::<_, Error>(entries)
+            })
+            .await??;
+        Ok(futures::stream::iter(entries.into_iter().map(Ok)).boxed())
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool> {
+        let paths: Vec<_> = self
+            .layouts
+            .iter()
+            .map(|layout| layout.local_path(key))
+            .collect();
+        let lru = self.lru.clone();
+        self.pool
+            .spawn_blocking(move || {
+                let refs = lru.refs.clone();
+                let lru = lru.get_or_init()?;
+                let mut found = false;
+                for path in paths {
+                    if !lru.path().join(&path).exists() {
+                        continue;
+                    }
+                    let payload = refs.as_ref().and_then(|_| read_pointer_at(&lru, &path));
+                    lru.remove(&path)?;
+                    found = true;
+                    if let (Some(refs), Some(digest)) = (&refs, payload) {
+                        release_payload(&lru, refs, &digest);
+                    }
+                }
+                Ok(found)
+            })
+            .await?
     }
 
     async fn current_size(&self) -> Result<Option<u64>> {
-        Ok(self.lru.lock().unwrap().get().map(|l| l.size()))
+        Ok(self.lru.get().map(|l| l.size()))
     }
     async fn max_size(&self) -> Result<Option<u64>> {
-        Ok(Some(self.lru.lock().unwrap().capacity()))
+        Ok(Some(self.lru.capacity()))
+    }
+    fn eviction_state(&self) -> Option<EvictionState> {
+        let lru = self.lru.get()?;
+        let (high_watermark, low_watermark) = lru.watermarks();
+        Some(EvictionState {
+            high_watermark,
+            low_watermark,
+            sweeping: lru.is_sweeping(),
+        })
+    }
+    fn occupancy(&self) -> Option<CacheOccupancy> {
+        let lru = self.lru.get()?;
+        let (evictions, evicted_bytes) = lru.evictions();
+        Some(CacheOccupancy {
+            entries: lru.len() as u64,
+            evictions,
+            evicted_bytes,
+        })
     }
     fn preprocessor_cache_mode_config(&self) -> PreprocessorCacheModeConfig {
         self.preprocessor_cache_mode_config
     }
     async fn get_preprocessor_cache_entry(&self, key: &str) -> Result<Option<Box<dyn ReadSeek>>> {
         let key = normalize_key(key);
-        Ok(self
-            .preprocessor_cache
-            .lock()
-            .unwrap()
-            .get_or_init()?
-            .get(key)
-            .ok())
+        Ok(self.preprocessor_cache.get_or_init()?.get(key).ok())
     }
     async fn put_preprocessor_cache_entry(
         &self,
@@ -201,19 +646,315 @@ impl Storage for DiskCache {
         }
 
         let key = normalize_key(key);
-        let mut f = self
-            .preprocessor_cache
-            .lock()
-            .unwrap()
-            .get_or_init()?
-            .prepare_add(key, 0)?;
+        let lru = self.preprocessor_cache.get_or_init()?;
+        let mut f = lru.prepare_add(key, 0)?;
         preprocessor_cache_entry.serialize_to(BufWriter::new(f.as_file_mut()))?;
-        Ok(self
-            .preprocessor_cache
-            .lock()
-            .unwrap()
-            .get()
-            .unwrap()
-            .commit(f)?)
+        lru.commit(f)?;
+        sweep_in_background(&self.preprocessor_cache, &self.pool);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::TryStreamExt;
+
+    #[test]
+    fn test_store_entry_disk_full() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let lru = ShardedLruDiskCache::new(tempdir.path(), 1000, 4).unwrap();
+        let disk_full =
+            || io::Error::from_raw_os_error(if cfg!(windows) { 112 } else { libc::ENOSPC });
+        for key in ["a", "b", "c"] {
+            store_entry(&lru, None, Path::new(key), &[0; 10], |f, data| {
+                f.write_all(data)
+            })
+            .unwrap();
+        }
+
+        // The disk has room again once the oldest entry is evicted.
+        let full = std::sync::atomic::AtomicBool::new(true);
+        store_entry(&lru, None, Path::new("d"), &[0; 10], |f, data| {
+            if full.swap(false, std::sync::atomic::Ordering::Relaxed) {
+                return Err(disk_full());
+            }
+            f.write_all(data)
+        })
+        .unwrap();
+        assert!(!tempdir.path().join("a").exists());
+        assert!(lru.get("d").is_ok());
+        assert_eq!(lru.len(), 3);
+        assert_eq!(lru.size(), 30);
+
+        // Or not.
+        let err = store_entry(
+            &lru,
+            None,
+            Path::new("e"),
+            &[0; 10],
+            |_, _| Err(disk_full()),
+        )
+        .unwrap_err();
+        assert!(err.downcast_ref::<DiskFull>().is_some());
+        assert_eq!(lru.len(), 2);
+        assert_eq!(lru.size(), 20);
+
+        // Other errors are not retried.
+        let err = store_entry(&lru, None, Path::new("e"), &[0; 10], |_, _| {
+            Err(io::Error::new(io::ErrorKind::PermissionDenied, "denied"))
+        })
+        .unwrap_err();
+        assert!(err.downcast_ref::<DiskFull>().is_none());
+        assert_eq!(lru.len(), 2);
+    }
+
+    #[test]
+    fn test_dedup() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let tempdir = tempfile::tempdir().unwrap();
+        let cache = DiskCache::new(
+            tempdir.path(),
+            u64::MAX,
+            runtime.handle(),
+            PreprocessorCacheModeConfig::default(),
+            CacheMode::ReadWrite,
+        )
+        .with_dedup(true);
+        let object = vec![7; 10_000];
+        let entry = |object: &[u8]| {
+            let mut entry = CacheWrite::new();
+            entry.put_object("obj", &mut &object[..], None).unwrap();
+            entry
+        };
+        let size = entry(&object).finish().unwrap().len() as u64;
+        runtime.block_on(async {
+            cache.put("aaaa", entry(&object)).await.unwrap();
+            cache.put("bbbb", entry(&object)).await.unwrap();
+            cache.put("cccc", entry(&[8; 10_000])).await.unwrap();
+        });
+        // The identical entries share a payload.
+        let lru = cache.lru.get().unwrap();
+        assert_eq!(lru.len(), 5);
+        assert!(lru.size() < size * 2 + 512);
+        for key in ["aaaa", "bbbb"] {
+            match runtime.block_on(cache.get(key)).unwrap() {
+                Cache::Hit(mut entry) => {
+                    let mut read = Vec::new();
+                    entry.get_object("obj", &mut read).unwrap();
+                    assert_eq!(read, object);
+                }
+                _ => panic!("Unexpected cache miss"),
+            }
+        }
+
+        // Evicting an entry keeps the payload until the last one pointing to
+        // it is evicted.
+        let refs = cache.lru.refs.as_deref();
+        let payload = tempdir.path().join("payloads");
+        let payload_count = || {
+            walkdir::WalkDir::new(&payload)
+                .into_iter()
+                .filter(|e| e.as_ref().unwrap().file_type().is_file())
+                .count()
+        };
+        assert_eq!(payload_count(), 2);
+        for key in lru.evict(1) {
+            assert_eq!(key, KeyLayout::LOCAL.local_path("cccc"));
+            remove_evicted(&lru, refs, &key);
+        }
+        assert_eq!(payload_count(), 1);
+        for key in lru.evict(1) {
+            assert_eq!(key, KeyLayout::LOCAL.local_path("aaaa"));
+            remove_evicted(&lru, refs, &key);
+        }
+        assert_eq!(payload_count(), 1);
+        assert!(matches!(
+            runtime.block_on(cache.get("bbbb")).unwrap(),
+            Cache::Hit(_)
+        ));
+        for key in lru.evict(1) {
+            assert_eq!(key, KeyLayout::LOCAL.local_path("bbbb"));
+            remove_evicted(&lru, refs, &key);
+        }
+        assert_eq!(payload_count(), 0);
+        assert_eq!(lru.size(), 0);
+
+        // The payloads are counted again after a restart.
+        let cache = DiskCache::new(
+            tempdir.path(),
+            u64::MAX,
+            runtime.handle(),
+            PreprocessorCacheModeConfig::default(),
+            CacheMode::ReadWrite,
+        )
+        .with_dedup(true);
+        runtime.block_on(async {
+            cache.put("aaaa", entry(&object)).await.unwrap();
+            cache.put("bbbb", entry(&object)).await.unwrap();
+        });
+        let lru = cache.lru.get().unwrap();
+        let refs = PayloadRefs::default();
+        let digest = read_pointer_at(&lru, &KeyLayout::LOCAL.local_path("aaaa")).unwrap();
+        assert!(!refs.release(&lru, &digest));
+        assert!(refs.release(&lru, &digest));
+    }
+
+    #[test]
+    fn test_put_sweeps_in_background() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .worker_threads(1)
+            .build()
+            .unwrap();
+        let tempdir = tempfile::tempdir().unwrap();
+        let entry = || {
+            let mut entry = CacheWrite::new();
+            entry.put_stdout(&[1; 100]).unwrap();
+            entry
+        };
+        let size = entry().finish().unwrap().len() as u64;
+        let cache = DiskCache::new(
+            tempdir.path(),
+            size * 10,
+            runtime.handle(),
+            PreprocessorCacheModeConfig::default(),
+            CacheMode::ReadWrite,
+        );
+        runtime.block_on(async {
+            for i in 0..11 {
+                cache.put(&format!("key{}", i), entry()).await.unwrap();
+            }
+            // The put crossing the capacity started a sweep down to 90% of it.
+            let max_size = cache.max_size().await.unwrap().unwrap();
+            assert!(cache.current_size().await.unwrap().unwrap() <= max_size * 9 / 10);
+            // And the evictions are counted right away.
+            assert_eq!(
+                cache.occupancy(),
+                Some(CacheOccupancy {
+                    entries: 9,
+                    evictions: 2,
+                    evicted_bytes: size * 2,
+                })
+            );
+            for _ in 0..100 {
+                if !tempdir.path().join("k/e/key0").exists() {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            assert!(!tempdir.path().join("k/e/key0").exists());
+            assert!(matches!(cache.get("key0").await.unwrap(), Cache::Miss));
+            assert!(matches!(cache.get("key10").await.unwrap(), Cache::Hit(_)));
+        });
+    }
+
+    #[test]
+    fn test_shard_depths() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let entry = || {
+            let mut entry = CacheWrite::new();
+            entry.put_stdout(b"stdout").unwrap();
+            entry
+        };
+        for (depth, path) in [
+            (0, "0123abcd"),
+            (1, "01/0123abcd"),
+            (2, "01/23/0123abcd"),
+            (4, "01/23/ab/cd/0123abcd"),
+        ] {
+            let tempdir = tempfile::tempdir().unwrap();
+            let cache = |layouts| {
+                DiskCache::new(
+                    tempdir.path(),
+                    u64::MAX,
+                    runtime.handle(),
+                    PreprocessorCacheModeConfig::default(),
+                    CacheMode::ReadWrite,
+                )
+                .with_layouts(layouts)
+            };
+            // An entry written before the depth changed is still found.
+            let legacy = cache(vec![KeyLayout::LOCAL]);
+            runtime.block_on(legacy.put("4567abcd", entry())).unwrap();
+            assert!(tempdir.path().join("4/5/4567abcd").is_file());
+
+            let cache = cache(vec![KeyLayout::sharded(depth), KeyLayout::LOCAL]);
+            runtime.block_on(async {
+                cache.put("0123abcd", entry()).await.unwrap();
+                assert!(tempdir.path().join(path).is_file());
+                assert!(matches!(
+                    cache.get("0123abcd").await.unwrap(),
+                    Cache::Hit(_)
+                ));
+                assert!(matches!(
+                    cache.get("4567abcd").await.unwrap(),
+                    Cache::Hit(_)
+                ));
+                assert!(matches!(cache.get("89abcdef").await.unwrap(), Cache::Miss));
+                let mut keys = cache.list().await.unwrap();
+                keys.sort();
+                assert_eq!(keys, ["0123abcd", "4567abcd"]);
+            });
+            // The old layout alone doesn't find the new entry.
+            assert!(matches!(
+                runtime.block_on(legacy.get("0123abcd")).unwrap(),
+                Cache::Miss
+            ));
+        }
+    }
+
+    #[test]
+    fn test_list_entries_and_delete() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let tempdir = tempfile::tempdir().unwrap();
+        let cache = DiskCache::new(
+            tempdir.path(),
+            u64::MAX,
+            runtime.handle(),
+            PreprocessorCacheModeConfig::default(),
+            CacheMode::ReadWrite,
+        );
+        runtime.block_on(async {
+            for key in ["0123abcd", "0145abcd", "0167abcd", "89abcdef"] {
+                let mut entry = CacheWrite::new();
+                entry.put_stdout(key.as_bytes()).unwrap();
+                cache.put(key, entry).await.unwrap();
+            }
+            let cache = &cache;
+            let list = |prefix: &'static str| async move {
+                let entries: Vec<EntryInfo> = cache
+                    .list_entries(prefix)
+                    .await
+                    .unwrap()
+                    .try_collect()
+                    .await
+                    .unwrap();
+                entries
+            };
+            let keys = |entries: &[EntryInfo]| -> Vec<String> {
+                entries.iter().map(|e| e.key.clone()).collect()
+            };
+
+            // The entries are listed in the order of their keys.
+            let entries = list("01").await;
+            assert_eq!(keys(&entries), ["0123abcd", "0145abcd", "0167abcd"]);
+            assert!(entries.iter().all(|e| e.size > 0 && e.modified.is_some()));
+            assert_eq!(list("").await.len(), 4);
+
+            assert!(cache.delete("0145abcd").await.unwrap());
+            assert!(!cache.delete("0145abcd").await.unwrap());
+            assert!(matches!(cache.get("0145abcd").await.unwrap(), Cache::Miss));
+            assert_eq!(keys(&list("01").await), ["0123abcd", "0167abcd"]);
+        });
     }
 }
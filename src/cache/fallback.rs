@@ -0,0 +1,319 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A replica of the cache, e.g. the bucket replicated to another region,
+//! which the lookups fall back to when the cache fails or times out, see
+//! `SCCACHE_S3_FALLBACK_ENDPOINT`.
+//!
+//! The entries are only written to the cache, which is expected to be
+//! replicated, unless the storage is told to write them to the replica too,
+//! in the background.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use crate::cache::{
+    Cache, CacheMode, CacheOccupancy, CacheWrite, EntryStream, EvictionState, Storage,
+    StorageCounts,
+};
+use crate::compiler::PreprocessorCacheEntry;
+use crate::config::EntryConfig;
+use crate::errors::*;
+
+use super::PreprocessorCacheModeConfig;
+
+/// A storage looking up its entries in `fallback` when `primary` fails.
+pub struct FallbackStorage {
+    primary: Arc<dyn Storage>,
+    fallback: Arc<dyn Storage>,
+    replicate: bool,
+    /// The hits served from the replica since they were last taken.
+    hits: AtomicU64,
+}
+
+impl FallbackStorage {
+    /// Look up the entries in `primary`, then in `fallback` if `primary`
+    /// fails, writing them to `primary`, and to `fallback` too if
+    /// `replicate`.
+    pub fn new(
+        primary: Arc<dyn Storage>,
+        fallback: Arc<dyn Storage>,
+        replicate: bool,
+    ) -> FallbackStorage {
+        FallbackStorage {
+            primary,
+            fallback,
+            replicate,
+            hits: AtomicU64::new(0),
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for FallbackStorage {
+    async fn get(&self, key: &str) -> Result<Cache> {
+        // A miss in the cache is a miss: the replica would miss it too.
+        let e = match self.primary.get(key).await {
+            Ok(found) => return Ok(found),
+            Err(e) => e,
+        };
+        warn!(
+            "Failed to look up {} in the cache, looking it up in the fallback {}: {:#}",
+            key,
+            self.fallback.location(),
+            e
+        );
+        match self.fallback.get(key).await {
+            Ok(Cache::Hit(entry)) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                debug!("Found {} in the fallback cache", key);
+                Ok(Cache::Hit(entry))
+            }
+            Ok(found) => {
+                debug!("Missed {} in the fallback cache, the cache failing", key);
+                Ok(found)
+            }
+            Err(fallback_err) => {
+                warn!(
+                    "Failed to look up {} in the fallback cache too: {:#}",
+                    key, fallback_err
+                );
+                Err(e)
+            }
+        }
+    }
+
+    async fn put(&self, key: &str, entry: CacheWrite) -> Result<Duration> {
+        if !self.replicate {
+            return self.primary.put(key, entry).await;
+        }
+        let metadata = entry.metadata().cloned();
+        let bytes = entry.finish()?;
+        let copy = |bytes| -> Result<CacheWrite> {
+            let mut entry = CacheWrite::from_bytes(bytes)?;
+            if let Some(metadata) = &metadata {
+                entry.set_metadata(metadata.clone());
+            }
+            Ok(entry)
+        };
+        let replica = copy(bytes.clone())?;
+        let fallback = self.fallback.clone();
+        let replica_key = key.to_owned();
+        tokio::spawn(async move {
+            if let Err(e) = fallback.put(&replica_key, replica).await {
+                warn!(
+                    "Failed to write {} to the fallback cache: {:?}",
+                    replica_key, e
+                );
+            }
+        });
+        self.primary.put(key, copy(bytes)?).await
+    }
+
+    async fn check(&self) -> Result<CacheMode> {
+        match self.primary.check().await {
+            Ok(mode) => Ok(mode),
+            Err(e) => {
+                warn!(
+                    "Failed to check the cache, checking the fallback {}: {:#}",
+                    self.fallback.location(),
+                    e
+                );
+                self.fallback.check().await
+            }
+        }
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.primary.is_read_only()
+    }
+
+    async fn probe(&self) -> Result<()> {
+        let Err(e) = self.primary.probe().await else {
+            return Ok(());
+        };
+        self.fallback
+            .probe()
+            .await
+            .with_context(|| format!("Failed to probe the fallback cache, after: {:#}", e))?;
+        warn!(
+            "Failed to probe the cache, using the fallback {}: {:#}",
+            self.fallback.location(),
+            e
+        );
+        Ok(())
+    }
+
+    fn location(&self) -> String {
+        format!(
+            "{} (fallback: {})",
+            self.primary.location(),
+            self.fallback.location()
+        )
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        self.primary.list().await
+    }
+
+    async fn list_entries(&self, prefix: &str) -> Result<EntryStream> {
+        self.primary.list_entries(prefix).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool> {
+        // The replica would serve the entry otherwise, as long as the cache
+        // fails.
+        let (found, fallback_found) =
+            futures::join!(self.primary.delete(key), self.fallback.delete(key));
+        if let Err(e) = fallback_found {
+            warn!("Failed to remove {} from the fallback cache: {:?}", key, e);
+        }
+        found
+    }
+
+    async fn current_size(&self) -> Result<Option<u64>> {
+        self.primary.current_size().await
+    }
+
+    async fn max_size(&self) -> Result<Option<u64>> {
+        self.primary.max_size().await
+    }
+
+    fn requests_in_flight(&self) -> Option<usize> {
+        self.primary.requests_in_flight()
+    }
+
+    fn take_counts(&self) -> StorageCounts {
+        let hits = self.hits.swap(0, Ordering::Relaxed);
+        self.primary.take_counts()
+            + self.fallback.take_counts()
+            + StorageCounts {
+                fallback_hits: hits,
+                ..Default::default()
+            }
+    }
+
+    fn eviction_state(&self) -> Option<EvictionState> {
+        self.primary.eviction_state()
+    }
+
+    fn occupancy(&self) -> Option<CacheOccupancy> {
+        self.primary.occupancy()
+    }
+
+    fn entry_config(&self) -> EntryConfig {
+        self.primary.entry_config()
+    }
+
+    fn preprocessor_cache_mode_config(&self) -> PreprocessorCacheModeConfig {
+        self.primary.preprocessor_cache_mode_config()
+    }
+
+    async fn get_preprocessor_cache_entry(
+        &self,
+        key: &str,
+    ) -> Result<Option<Box<dyn crate::lru_disk_cache::ReadSeek>>> {
+        self.primary.get_preprocessor_cache_entry(key).await
+    }
+
+    async fn put_preprocessor_cache_entry(
+        &self,
+        key: &str,
+        preprocessor_cache_entry: PreprocessorCacheEntry,
+    ) -> Result<()> {
+        self.primary
+            .put_preprocessor_cache_entry(key, preprocessor_cache_entry)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::disk::DiskCache;
+    use crate::test::mock_storage::MockStorage;
+
+    fn entry(contents: &[u8]) -> CacheWrite {
+        let mut entry = CacheWrite::new();
+        entry.put_object("obj", &mut &contents[..], None).unwrap();
+        entry
+    }
+
+    fn object(cache: Cache) -> Vec<u8> {
+        let Cache::Hit(mut entry) = cache else {
+            panic!("Expected a hit");
+        };
+        let mut object = vec![];
+        entry.get_object("obj", &mut object).unwrap();
+        object
+    }
+
+    #[test]
+    fn test_fallback_storage() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let tempdir = tempfile::Builder::new()
+            .prefix("sccache_test_fallback")
+            .tempdir()
+            .unwrap();
+        let fallback: Arc<dyn Storage> = Arc::new(DiskCache::new(
+            tempdir.path(),
+            u64::MAX,
+            runtime.handle(),
+            PreprocessorCacheModeConfig::default(),
+            CacheMode::ReadWrite,
+        ));
+        runtime
+            .block_on(fallback.put("0123456789abcdef", entry(b"replica")))
+            .unwrap();
+        let primary = Arc::new(MockStorage::new(None, false));
+        let storage = FallbackStorage::new(primary.clone(), fallback.clone(), false);
+
+        // A miss in the cache isn't looked up in the replica.
+        primary.next_get(Ok(Cache::Miss));
+        let found = runtime.block_on(storage.get("0123456789abcdef")).unwrap();
+        assert!(matches!(found, Cache::Miss));
+        assert_eq!(storage.take_counts().fallback_hits, 0);
+
+        // While the cache failing is.
+        primary.next_get(Err(anyhow!("unreachable")));
+        let found = runtime.block_on(storage.get("0123456789abcdef")).unwrap();
+        assert_eq!(object(found), b"replica");
+        assert_eq!(storage.take_counts().fallback_hits, 1);
+
+        // The replica missing it too is a miss.
+        primary.next_get(Err(anyhow!("unreachable")));
+        let found = runtime.block_on(storage.get("1123456789abcdef")).unwrap();
+        assert!(matches!(found, Cache::Miss));
+        assert_eq!(storage.take_counts().fallback_hits, 0);
+
+        // The entries are written to the replica when asked.
+        let storage = FallbackStorage::new(primary, fallback.clone(), true);
+        runtime
+            .block_on(storage.put("2123456789abcdef", entry(b"written")))
+            .unwrap();
+        let found = runtime.block_on(async {
+            loop {
+                match fallback.get("2123456789abcdef").await.unwrap() {
+                    Cache::Miss => tokio::task::yield_now().await,
+                    found => break found,
+                }
+            }
+        });
+        assert_eq!(object(found), b"written");
+    }
+}
@@ -13,11 +13,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::cache::metadata::{ObjectPut, WithMetadata};
 use crate::cache::CacheMode;
 use crate::errors::*;
+use opendal::raw::{build_abs_path, normalize_root, percent_encode_path, HttpClient};
 use opendal::Operator;
 use opendal::{layers::LoggingLayer, services::Gcs};
-use reqsign::{GoogleToken, GoogleTokenLoad};
+use reqsign::{
+    GoogleCredentialLoader, GoogleSigner, GoogleToken, GoogleTokenLoad, GoogleTokenLoader,
+};
+use reqwest::header::{HeaderValue, CONTENT_TYPE};
 use reqwest::Client;
 use serde::Deserialize;
 use url::Url;
@@ -30,6 +35,9 @@ fn rw_to_scope(mode: CacheMode) -> &'static str {
 }
 
 /// A cache that stores entries in Google Cloud Storage
+/// The endpoint of the XML API of GCS, which opendal uses too.
+const ENDPOINT: &str = "https://storage.googleapis.com";
+
 pub struct GCSCache;
 
 impl GCSCache {
@@ -41,46 +49,118 @@ impl GCSCache {
         service_account: Option<&str>,
         rw_mode: CacheMode,
         credential_url: Option<&str>,
-    ) -> Result<Operator> {
+    ) -> Result<WithMetadata<GcsPut>> {
+        Self::build_at(
+            ENDPOINT,
+            bucket,
+            key_prefix,
+            cred_path,
+            service_account,
+            rw_mode,
+            credential_url,
+        )
+    }
+
+    fn build_at(
+        endpoint: &str,
+        bucket: &str,
+        key_prefix: &str,
+        cred_path: Option<&str>,
+        service_account: Option<&str>,
+        rw_mode: CacheMode,
+        credential_url: Option<&str>,
+    ) -> Result<WithMetadata<GcsPut>> {
+        let http_client = HttpClient::new()?;
+        let client = http_client.client();
         let mut builder = Gcs::default();
+        builder.http_client(http_client);
+        builder.endpoint(endpoint);
         builder.bucket(bucket);
         builder.root(key_prefix);
         builder.scope(rw_to_scope(rw_mode));
 
+        // The token of the entries written with their metadata is loaded as
+        // opendal loads its own.
+        let mut credential = GoogleCredentialLoader::default();
+        let mut token = GoogleTokenLoader::new(rw_to_scope(rw_mode), client.clone());
+
         if let Some(service_account) = service_account {
             builder.service_account(service_account);
+            token = token.with_service_account(service_account);
         }
 
         if let Some(path) = cred_path {
             builder.credential_path(path);
+            credential = credential.with_path(path);
+        }
+        if let Ok(Some(credential)) = credential.load() {
+            token = token.with_credentials(credential);
         }
 
         if let Some(cred_url) = credential_url {
             let _ = Url::parse(cred_url)
                 .map_err(|err| anyhow!("gcs credential url is invalid: {err:?}"))?;
 
-            builder.customed_token_loader(Box::new(TaskClusterTokenLoader {
+            let loader = || TaskClusterTokenLoader {
                 scope: rw_to_scope(rw_mode).to_string(),
                 url: cred_url.to_string(),
-            }));
+            };
+            builder.customed_token_loader(Box::new(loader()));
+            token = token.with_customed_token_loader(Box::new(loader()));
         }
 
         let op = Operator::new(builder)?
             .layer(LoggingLayer::default())
             .finish();
-        Ok(op)
+        let put = GcsPut {
+            endpoint: endpoint.to_owned(),
+            bucket: bucket.to_owned(),
+            root: normalize_root(key_prefix),
+            token,
+        };
+        Ok(WithMetadata::new(op, client, put))
+    }
+}
+
+/// Writes the entries of a GCS cache with their metadata, as `x-goog-meta-*`,
+/// through the XML API.
+pub struct GcsPut {
+    endpoint: String,
+    bucket: String,
+    root: String,
+    token: GoogleTokenLoader,
+}
+
+#[async_trait::async_trait]
+impl ObjectPut for GcsPut {
+    fn metadata_prefix(&self) -> &'static str {
+        "x-goog-meta-"
+    }
+
+    async fn request(&self, path: &str, content_type: &str) -> Result<reqwest::Request> {
+        let url = format!(
+            "{}/{}/{}",
+            self.endpoint,
+            self.bucket,
+            percent_encode_path(&build_abs_path(&self.root, path))
+        );
+        let mut request = reqwest::Request::new(reqwest::Method::PUT, Url::parse(&url)?);
+        request
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_str(content_type)?);
+        Ok(request)
+    }
+
+    async fn sign(&self, request: &mut reqwest::Request) -> Result<()> {
+        let token = self
+            .token
+            .load()
+            .await?
+            .context("no valid GCS credentials found")?;
+        GoogleSigner::new("storage").sign(request, &token)
     }
 }
 
-/// TaskClusterTokenLoeader is used to load tokens from [TaskCluster](https://taskcluster.net/)
-///
-/// This feature is required to run [mozilla's CI](https://searchfox.org/mozilla-central/source/build/mozconfig.cache#67-84):
-///
-/// ```txt
-/// export SCCACHE_GCS_CREDENTIALS_URL=http://taskcluster/auth/v1/gcp/credentials/$SCCACHE_GCS_PROJECT/${bucket}@$SCCACHE_GCS_PROJECT.iam.gserviceaccount.com"
-/// ```
-///
-/// Reference: [gcpCredentials](https://docs.taskcluster.net/docs/reference/platform/auth/api#gcpCredentials)
 #[derive(Debug)]
 struct TaskClusterTokenLoader {
     scope: String,
@@ -121,3 +201,58 @@ struct TaskClusterToken {
     access_token: String,
     expire_time: String,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::metadata::EntryMetadata;
+    use crate::cache::{CacheWrite, Storage};
+    use crate::test::mock_bucket::MockBucket;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_put_metadata() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let bucket = MockBucket::start().await;
+            bucket.insert("/token", br#"{"accessToken": "secret"}"#);
+            let storage = GCSCache::build_at(
+                &bucket.endpoint,
+                "bucket",
+                "prefix",
+                None,
+                None,
+                CacheMode::ReadWrite,
+                Some(&format!("{}/token", bucket.endpoint)),
+            )
+            .unwrap();
+
+            let mut entry = CacheWrite::new();
+            entry.put_stdout(b"hello").unwrap();
+            entry.set_metadata(EntryMetadata {
+                compiler: "rustc",
+                compiler_version: Some("rustc 1.80.0".to_owned()),
+                key_version: "6".to_owned(),
+                created: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+                build_id: None,
+            });
+            storage.put("abcdef", entry).await.unwrap();
+            let requests = bucket.requests();
+            let put = requests.iter().find(|r| r.method == "PUT").unwrap();
+            assert_eq!(put.path, "/bucket/prefix/a/b/c/abcdef");
+            for (name, value) in [
+                ("x-goog-meta-created", "2023-11-14T22:13:20Z"),
+                ("x-goog-meta-compiler", "rustc"),
+                ("x-goog-meta-compiler-version", "rustc 1.80.0"),
+                ("x-goog-meta-sccache-version", env!("CARGO_PKG_VERSION")),
+                ("x-goog-meta-key-version", "6"),
+                ("authorization", "Bearer secret"),
+            ] {
+                assert_eq!(put.headers.get(name).map(String::as_str), Some(value));
+            }
+        });
+    }
+}
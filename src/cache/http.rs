@@ -0,0 +1,383 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A cache on a plain HTTP key-value service, `SCCACHE_HTTP_ENDPOINT`.
+//!
+//! The service only has to answer `GET {endpoint}/{key}` with the entry and
+//! `200`, or `404` when it has none, and to store the body of
+//! `PUT {endpoint}/{key}`, answering with any `2xx`. The requests carry the
+//! token, if any, as `Authorization: Bearer <token>`.
+//!
+//! The connections are kept open and reused across requests.
+
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+
+use crate::cache::request;
+use crate::cache::{Cache, CacheMode, CacheRead, CacheWrite, Storage, StorageCounts};
+use crate::clock::{self, SharedClock};
+use crate::config::HttpRequestConfig;
+use crate::errors::*;
+use crate::retry::{send_with_retries, Backoff, RetryCount};
+
+/// A cache storing each entry at the URL of its key under an endpoint.
+pub struct HttpCache {
+    client: Client,
+    endpoint: String,
+    token: Option<String>,
+    rw_mode: CacheMode,
+    backoff: Backoff,
+    retries: RetryCount,
+    clock: SharedClock,
+}
+
+impl HttpCache {
+    pub fn build(
+        endpoint: &str,
+        token: Option<&str>,
+        rw_mode: CacheMode,
+        request: &HttpRequestConfig,
+    ) -> Result<HttpCache> {
+        if !endpoint.starts_with("http://") && !endpoint.starts_with("https://") {
+            bail!("the endpoint {:?} is not an HTTP URL", endpoint);
+        }
+        let builder = Client::builder().pool_idle_timeout(Duration::from_secs(90));
+        let (builder, endpoint) = request::configure(builder, request, Some(endpoint))?;
+        let endpoint = endpoint.unwrap_or_default();
+        let client = builder
+            .build()
+            .context("failed to create the HTTP client")?;
+        Ok(HttpCache {
+            client,
+            endpoint: endpoint.trim_end_matches('/').to_owned(),
+            token: token.map(ToOwned::to_owned),
+            rw_mode,
+            backoff: Backoff::remote(),
+            retries: RetryCount::default(),
+            clock: clock::system(),
+        })
+    }
+
+    fn url(&self, key: &str) -> String {
+        format!("{}/{}", self.endpoint, key)
+    }
+
+    /// Send the request `build` makes, retrying it when the connection fails
+    /// or the service answers with a `5xx`.
+    async fn send(&self, build: impl Fn() -> RequestBuilder) -> Result<Response> {
+        send_with_retries(&self.backoff, &self.clock, &self.retries, || {
+            let mut request = build();
+            if let Some(token) = &self.token {
+                request = request.bearer_auth(token);
+            }
+            async move { request.send().await.context("failed to reach the cache") }
+        })
+        .await
+    }
+}
+
+/// The error of a request answered with `status`.
+fn status_error(status: StatusCode) -> Error {
+    match status {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => anyhow!(
+            "the cache refused the request with {}, check SCCACHE_HTTP_TOKEN",
+            status
+        ),
+        _ => anyhow!("the cache answered with {}", status),
+    }
+}
+
+#[async_trait]
+impl Storage for HttpCache {
+    async fn get(&self, key: &str) -> Result<Cache> {
+        let url = self.url(key);
+        let response = self.send(|| self.client.get(&url)).await?;
+        match response.status() {
+            StatusCode::OK => {
+                let body = response.bytes().await.context("failed to read the entry")?;
+                Ok(Cache::Hit(CacheRead::from(Cursor::new(body.to_vec()))?))
+            }
+            StatusCode::NOT_FOUND => Ok(Cache::Miss),
+            status => Err(status_error(status)),
+        }
+    }
+
+    async fn put(&self, key: &str, entry: CacheWrite) -> Result<Duration> {
+        if self.rw_mode == CacheMode::ReadOnly {
+            bail!("Cannot write to a read-only cache");
+        }
+        let start = Instant::now();
+        let url = self.url(key);
+        let content_type = entry.format().content_type();
+        let body = bytes::Bytes::from(entry.finish()?);
+        let response = self
+            .send(|| {
+                self.client
+                    .put(&url)
+                    .header(reqwest::header::CONTENT_TYPE, &content_type)
+                    .body(body.clone())
+            })
+            .await?;
+        if !response.status().is_success() {
+            return Err(status_error(response.status()));
+        }
+        Ok(start.elapsed())
+    }
+
+    async fn check(&self) -> Result<CacheMode> {
+        self.probe().await?;
+        Ok(self.rw_mode)
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.rw_mode == CacheMode::ReadOnly
+    }
+
+    fn location(&self) -> String {
+        format!("HTTP, endpoint: {}", self.endpoint)
+    }
+
+    async fn current_size(&self) -> Result<Option<u64>> {
+        Ok(None)
+    }
+
+    async fn max_size(&self) -> Result<Option<u64>> {
+        Ok(None)
+    }
+
+    fn take_counts(&self) -> StorageCounts {
+        StorageCounts {
+            retries: self.retries.take(),
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::FakeClock;
+    use crate::retry::DEFAULT_REMOTE_BACKOFF;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    /// A key-value HTTP/1.1 server, keeping the connections open.
+    struct MockServer {
+        endpoint: String,
+        entries: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+        connections: Arc<AtomicUsize>,
+        /// The statuses answered to the next requests, before serving them.
+        failures: Arc<Mutex<Vec<u16>>>,
+    }
+
+    impl MockServer {
+        async fn start(token: Option<&'static str>) -> MockServer {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let endpoint = format!("http://{}/cache", listener.local_addr().unwrap());
+            let entries = Arc::new(Mutex::new(HashMap::new()));
+            let connections = Arc::new(AtomicUsize::new(0));
+            let failures = Arc::new(Mutex::new(vec![]));
+            let server = MockServer {
+                endpoint,
+                entries: entries.clone(),
+                connections: connections.clone(),
+                failures: failures.clone(),
+            };
+            tokio::spawn(async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    connections.fetch_add(1, Ordering::SeqCst);
+                    let (entries, failures) = (entries.clone(), failures.clone());
+                    tokio::spawn(async move {
+                        let (reader, mut writer) = stream.into_split();
+                        let mut reader = BufReader::new(reader);
+                        loop {
+                            let mut line = String::new();
+                            if reader.read_line(&mut line).await.unwrap_or(0) == 0 {
+                                return;
+                            }
+                            let mut parts = line.split_whitespace();
+                            let method = parts.next().unwrap().to_owned();
+                            let path = parts.next().unwrap().to_owned();
+                            let mut length = 0;
+                            let mut authorized = token.is_none();
+                            loop {
+                                let mut header = String::new();
+                                reader.read_line(&mut header).await.unwrap();
+                                let header = header.trim_end();
+                                if header.is_empty() {
+                                    break;
+                                }
+                                let (name, value) = header.split_once(": ").unwrap();
+                                match name.to_ascii_lowercase().as_str() {
+                                    "content-length" => length = value.parse().unwrap(),
+                                    "authorization" => {
+                                        authorized =
+                                            token.map(|t| format!("Bearer {}", t)).as_deref()
+                                                == Some(value)
+                                    }
+                                    _ => {}
+                                }
+                            }
+                            let mut body = vec![0; length];
+                            reader.read_exact(&mut body).await.unwrap();
+                            let failure = failures.lock().unwrap().pop();
+                            let key = path.strip_prefix("/cache/").unwrap().to_owned();
+                            let (status, body) = match (failure, method.as_str()) {
+                                (Some(status), _) => (status, vec![]),
+                                _ if !authorized => (401, vec![]),
+                                (None, "GET") => match entries.lock().unwrap().get(&key) {
+                                    Some(entry) => (200, entry.clone()),
+                                    None => (404, vec![]),
+                                },
+                                (None, "PUT") => {
+                                    entries.lock().unwrap().insert(key, body);
+                                    (201, vec![])
+                                }
+                                _ => (405, vec![]),
+                            };
+                            let head = format!(
+                                "HTTP/1.1 {} Mock\r\ncontent-length: {}\r\n\r\n",
+                                status,
+                                body.len()
+                            );
+                            writer.write_all(head.as_bytes()).await.unwrap();
+                            writer.write_all(&body).await.unwrap();
+                        }
+                    });
+                }
+            });
+            server
+        }
+    }
+
+    fn entry(contents: &[u8]) -> CacheWrite {
+        let mut entry = CacheWrite::new();
+        entry
+            .put_object("obj", &mut Cursor::new(contents), None)
+            .unwrap();
+        entry
+    }
+
+    fn read(cache: Cache) -> Vec<u8> {
+        match cache {
+            Cache::Hit(mut entry) => {
+                let mut out = vec![];
+                entry.get_object("obj", &mut out).unwrap();
+                out
+            }
+            _ => panic!("Expected a hit"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http_cache() {
+        let server = MockServer::start(Some("secret")).await;
+        let cache = HttpCache::build(
+            &format!("{}/", server.endpoint),
+            Some("secret"),
+            CacheMode::ReadWrite,
+            &Default::default(),
+        )
+        .unwrap();
+        assert_eq!(cache.check().await.unwrap(), CacheMode::ReadWrite);
+        assert!(matches!(cache.get("abcd").await.unwrap(), Cache::Miss));
+        cache.put("abcd", entry(b"contents")).await.unwrap();
+        assert!(server.entries.lock().unwrap().contains_key("abcd"));
+        assert_eq!(read(cache.get("abcd").await.unwrap()), b"contents");
+        // All the requests went through the same connection.
+        assert_eq!(server.connections.load(Ordering::SeqCst), 1);
+
+        // A 5xx is retried, a 4xx is not.
+        server.failures.lock().unwrap().extend([503, 500]);
+        assert_eq!(read(cache.get("abcd").await.unwrap()), b"contents");
+        server.failures.lock().unwrap().push(400);
+        assert!(cache.get("abcd").await.is_err());
+
+        let cache = HttpCache::build(
+            &server.endpoint,
+            Some("wrong"),
+            CacheMode::ReadWrite,
+            &Default::default(),
+        )
+        .unwrap();
+        let err = cache.check().await.unwrap_err();
+        assert!(format!("{:#}", err).contains("401"), "{:#}", err);
+    }
+
+    #[tokio::test]
+    async fn test_http_cache_backoff() {
+        let server = MockServer::start(None).await;
+        let mut cache = HttpCache::build(
+            &server.endpoint,
+            None,
+            CacheMode::ReadWrite,
+            &Default::default(),
+        )
+        .unwrap();
+        let clock = FakeClock::new();
+        cache.clock = clock.clone();
+        cache.backoff = DEFAULT_REMOTE_BACKOFF;
+        server.failures.lock().unwrap().extend([503; 3]);
+        let get = tokio::spawn(async move { cache.get("abcd").await });
+        // Each retry waits for twice as long as the previous one, and up to
+        // half as long again.
+        for n in 1..=3 {
+            while clock.sleeps().len() < n {
+                tokio::task::yield_now().await;
+            }
+            clock.advance(Duration::from_secs(10));
+        }
+        assert!(matches!(get.await.unwrap().unwrap(), Cache::Miss));
+        let sleeps = clock.sleeps();
+        assert_eq!(sleeps.len(), 3);
+        for (sleep, delay) in sleeps.into_iter().zip([100, 200, 400]) {
+            let delay = Duration::from_millis(delay);
+            assert!(sleep >= delay && sleep <= delay * 3 / 2, "{:?}", sleep);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http_cache_read_only() {
+        let server = MockServer::start(None).await;
+        server
+            .entries
+            .lock()
+            .unwrap()
+            .insert("abcd".into(), entry(b"contents").finish().unwrap());
+        let cache = HttpCache::build(
+            &server.endpoint,
+            None,
+            CacheMode::ReadOnly,
+            &Default::default(),
+        )
+        .unwrap();
+        assert_eq!(cache.check().await.unwrap(), CacheMode::ReadOnly);
+        assert_eq!(read(cache.get("abcd").await.unwrap()), b"contents");
+        assert!(cache.put("efgh", entry(b"other")).await.is_err());
+        assert!(!server.entries.lock().unwrap().contains_key("efgh"));
+
+        assert!(HttpCache::build(
+            "ftp://example.com",
+            None,
+            CacheMode::ReadOnly,
+            &Default::default()
+        )
+        .is_err());
+    }
+}
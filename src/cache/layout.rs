@@ -0,0 +1,184 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Where the entries of a cache are under their keys, on the local disk and
+//! in the remote caches, e.g. S3, set with `SCCACHE_CACHE_SHARD_DEPTH`.
+//!
+//! Sharded `n` levels deep, an entry is under `n` directories named by the
+//! next two characters of its key each, e.g. `01/23/0123abcd` two levels deep,
+//! and it is at its key, flat, with `0`. By default the entries are where
+//! sccache always put them, under directories of one character: two levels
+//! deep on the local disk and three in the remote caches.
+//!
+//! `SCCACHE_CACHE_SHARD_DEPTH` is a comma-separated list of depths. The
+//! entries are written with the first one, and looked up with each in turn,
+//! then at the default place, so that the entries written before the depth
+//! changed are still found.
+
+use once_cell::sync::Lazy;
+use std::env;
+use std::path::{Path, PathBuf};
+
+/// The deepest entries can be sharded.
+pub const MAX_SHARD_DEPTH: usize = 8;
+
+/// The depths of `SCCACHE_CACHE_SHARD_DEPTH`.
+static SHARD_DEPTHS: Lazy<Vec<usize>> = Lazy::new(|| {
+    let Ok(depths) = env::var("SCCACHE_CACHE_SHARD_DEPTH") else {
+        return vec![];
+    };
+    parse_shard_depths(&depths).unwrap_or_else(|| {
+        warn!(
+            "Ignoring SCCACHE_CACHE_SHARD_DEPTH={:?}, which is no list of depths up to {}",
+            depths, MAX_SHARD_DEPTH
+        );
+        vec![]
+    })
+});
+
+static LOCAL_LAYOUTS: Lazy<Vec<KeyLayout>> = Lazy::new(|| layouts(KeyLayout::LOCAL));
+static REMOTE_LAYOUTS: Lazy<Vec<KeyLayout>> = Lazy::new(|| layouts(KeyLayout::REMOTE));
+
+/// How the path of an entry is made from its key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyLayout {
+    /// How many directories the entry is under.
+    depth: usize,
+    /// How many characters of the key name each of them.
+    width: usize,
+}
+
+impl KeyLayout {
+    /// The default layout of the local disk cache, e.g. `0/1/0123abcd`.
+    pub const LOCAL: KeyLayout = KeyLayout { depth: 2, width: 1 };
+    /// The default layout of the remote caches, e.g. `0/1/2/0123abcd`.
+    pub const REMOTE: KeyLayout = KeyLayout { depth: 3, width: 1 };
+
+    /// The entries under `depth` directories of two characters.
+    pub fn sharded(depth: usize) -> KeyLayout {
+        KeyLayout { depth, width: 2 }
+    }
+
+    /// The directories the entry `key` is under. A key too short for all of
+    /// them is under fewer.
+    fn dirs<'a>(&self, key: &'a str) -> impl Iterator<Item = &'a str> {
+        let width = self.width;
+        (0..self.depth).map_while(move |level| key.get(level * width..(level + 1) * width))
+    }
+
+    /// The path of the entry `key`, separated by `/`.
+    pub fn path(&self, key: &str) -> String {
+        let mut path = String::with_capacity(key.len() + self.depth * (self.width + 1));
+        for dir in self.dirs(key) {
+            path.push_str(dir);
+            path.push('/');
+        }
+        path.push_str(key);
+        path
+    }
+
+    /// The path of the entry `key` on the local disk.
+    pub fn local_path(&self, key: &str) -> PathBuf {
+        self.dirs(key).chain([key]).collect()
+    }
+
+    /// The key of the entry at `path`, separated by `/`, if it is where this
+    /// layout puts one.
+    pub fn key_of<'a>(&self, path: &'a str) -> Option<&'a str> {
+        let key = path.rsplit('/').next()?;
+        (key.len() > 2 && key.is_ascii() && self.path(key) == path).then_some(key)
+    }
+
+    /// The key of the entry at `path` on the local disk.
+    pub fn key_of_local<'a>(&self, path: &'a Path) -> Option<&'a str> {
+        let key = path.file_name()?.to_str()?;
+        (key.len() > 2 && key.is_ascii() && self.local_path(key) == path).then_some(key)
+    }
+}
+
+/// Parse a comma-separated list of depths, e.g. `2` or `2,0`.
+fn parse_shard_depths(depths: &str) -> Option<Vec<usize>> {
+    depths
+        .split(',')
+        .map(|depth| {
+            depth
+                .trim()
+                .parse()
+                .ok()
+                .filter(|&depth| depth <= MAX_SHARD_DEPTH)
+        })
+        .collect()
+}
+
+/// The layouts of `SCCACHE_CACHE_SHARD_DEPTH`, followed by `default`.
+fn layouts(default: KeyLayout) -> Vec<KeyLayout> {
+    let mut layouts: Vec<_> = SHARD_DEPTHS
+        .iter()
+        .map(|&depth| KeyLayout::sharded(depth))
+        .collect();
+    layouts.push(default);
+    layouts
+}
+
+/// The layouts of the local disk cache, the first one for writing.
+pub fn local() -> &'static [KeyLayout] {
+    &LOCAL_LAYOUTS
+}
+
+/// The layouts of the remote caches, the first one for writing.
+pub fn remote() -> &'static [KeyLayout] {
+    &REMOTE_LAYOUTS
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const KEY: &str = "0123456789abcdef";
+
+    #[test]
+    fn test_key_layouts() {
+        for (layout, path) in [
+            (KeyLayout::sharded(0), "0123456789abcdef"),
+            (KeyLayout::sharded(1), "01/0123456789abcdef"),
+            (KeyLayout::sharded(2), "01/23/0123456789abcdef"),
+            (KeyLayout::sharded(3), "01/23/45/0123456789abcdef"),
+            (KeyLayout::LOCAL, "0/1/0123456789abcdef"),
+            (KeyLayout::REMOTE, "0/1/2/0123456789abcdef"),
+        ] {
+            assert_eq!(layout.path(KEY), path);
+            assert_eq!(layout.key_of(path), Some(KEY));
+            let local_path = layout.local_path(KEY);
+            assert_eq!(local_path, Path::new(path));
+            assert_eq!(layout.key_of_local(&local_path), Some(KEY));
+        }
+        // The paths of the other layouts aren't taken for entries.
+        assert_eq!(KeyLayout::sharded(2).key_of("0/1/0123456789abcdef"), None);
+        assert_eq!(KeyLayout::sharded(0).key_of("01/0123456789abcdef"), None);
+        assert_eq!(KeyLayout::REMOTE.key_of("0/1/0123456789abcdef"), None);
+        assert_eq!(
+            KeyLayout::LOCAL.key_of("payloads/01/0123456789abcdef"),
+            None
+        );
+        // A short key is under fewer directories.
+        assert_eq!(KeyLayout::sharded(3).path("abcd"), "ab/cd/abcd");
+    }
+
+    #[test]
+    fn test_parse_shard_depths() {
+        assert_eq!(parse_shard_depths("2"), Some(vec![2]));
+        assert_eq!(parse_shard_depths("3, 2,0"), Some(vec![3, 2, 0]));
+        assert_eq!(parse_shard_depths("deep"), None);
+        assert_eq!(parse_shard_depths("2,9"), None);
+        assert_eq!(parse_shard_depths(""), None);
+    }
+}
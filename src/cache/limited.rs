@@ -0,0 +1,422 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::env;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use crate::cache::{
+    Cache, CacheMode, CacheOccupancy, CacheWrite, EntryStream, EvictionState, Storage,
+    StorageCounts,
+};
+use crate::compiler::PreprocessorCacheEntry;
+use crate::config::EntryConfig;
+use crate::errors::*;
+
+use super::PreprocessorCacheModeConfig;
+
+/// A storage letting at most `max_concurrency` requests reach the underlying
+/// storage at once, the others waiting for their turn.
+///
+/// Retries of throttled requests happen in the underlying storage, and keep
+/// their slot while backing off.
+pub struct LimitedStorage {
+    storage: Arc<dyn Storage>,
+    permits: Arc<Semaphore>,
+    max_concurrency: usize,
+}
+
+impl LimitedStorage {
+    pub fn new(storage: Arc<dyn Storage>, max_concurrency: usize) -> Self {
+        LimitedStorage {
+            storage,
+            permits: Arc::new(Semaphore::new(max_concurrency)),
+            max_concurrency,
+        }
+    }
+
+    async fn acquire(&self) -> SemaphorePermit<'_> {
+        self.permits
+            .acquire()
+            .await
+            .expect("The semaphore is never closed")
+    }
+}
+
+/// Wrap `storage` in a `LimitedStorage` if `max_concurrency` is set.
+pub fn limit_concurrency(
+    storage: Arc<dyn Storage>,
+    max_concurrency: Option<usize>,
+) -> Result<Arc<dyn Storage>> {
+    match max_concurrency {
+        None => Ok(storage),
+        Some(0) => bail!("The maximum concurrency of the cache must be at least 1"),
+        Some(max_concurrency) => {
+            debug!(
+                "Limiting the cache to {} concurrent requests",
+                max_concurrency
+            );
+            Ok(Arc::new(LimitedStorage::new(storage, max_concurrency)))
+        }
+    }
+}
+
+/// Get the maximum number of entries fetched from the remote cache at once
+/// from `SCCACHE_REMOTE_FETCH_CONCURRENCY`, if any.
+pub fn get_remote_fetch_concurrency() -> Option<usize> {
+    let value = env::var("SCCACHE_REMOTE_FETCH_CONCURRENCY").ok()?;
+    match value.parse() {
+        Ok(0) | Err(_) => {
+            warn!(
+                "Invalid SCCACHE_REMOTE_FETCH_CONCURRENCY {:?}, not limiting the fetches",
+                value
+            );
+            None
+        }
+        Ok(max) => Some(max),
+    }
+}
+
+/// A storage letting at most `max_fetches` entries be fetched from the
+/// underlying storage at once, the other lookups waiting for their turn.
+/// Unlike `LimitedStorage`, the writes and the other requests aren't
+/// limited, so that a slow link is shared by the downloads without holding
+/// back the rest.
+pub struct FetchLimitedStorage {
+    storage: Arc<dyn Storage>,
+    permits: Semaphore,
+    /// The fetches which waited for a slot since they were last taken, and
+    /// how long they waited in total, in microseconds.
+    queued: AtomicU64,
+    queue_micros: AtomicU64,
+}
+
+impl FetchLimitedStorage {
+    pub fn new(storage: Arc<dyn Storage>, max_fetches: usize) -> Self {
+        FetchLimitedStorage {
+            storage,
+            permits: Semaphore::new(max_fetches),
+            queued: AtomicU64::new(0),
+            queue_micros: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Wrap `storage` in a `FetchLimitedStorage` if `max_fetches` is set.
+pub fn limit_fetches(storage: Arc<dyn Storage>, max_fetches: Option<usize>) -> Arc<dyn Storage> {
+    match max_fetches {
+        None => storage,
+        Some(max_fetches) => {
+            debug!("Limiting the cache to {} concurrent fetches", max_fetches);
+            Arc::new(FetchLimitedStorage::new(storage, max_fetches))
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for FetchLimitedStorage {
+    async fn get(&self, key: &str) -> Result<Cache> {
+        let _permit = match self.permits.try_acquire() {
+            Ok(permit) => permit,
+            Err(_) => {
+                let start = Instant::now();
+                let permit = self
+                    .permits
+                    .acquire()
+                    .await
+                    .expect("The semaphore is never closed");
+                self.queued.fetch_add(1, Ordering::Relaxed);
+                self.queue_micros
+                    .fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+                permit
+            }
+        };
+        self.storage.get(key).await
+    }
+
+    async fn put(&self, key: &str, entry: CacheWrite) -> Result<Duration> {
+        self.storage.put(key, entry).await
+    }
+
+    async fn check(&self) -> Result<CacheMode> {
+        self.storage.check().await
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.storage.is_read_only()
+    }
+
+    async fn probe(&self) -> Result<()> {
+        self.storage.probe().await
+    }
+
+    fn location(&self) -> String {
+        self.storage.location()
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        self.storage.list().await
+    }
+
+    async fn list_entries(&self, prefix: &str) -> Result<EntryStream> {
+        self.storage.list_entries(prefix).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool> {
+        self.storage.delete(key).await
+    }
+
+    async fn current_size(&self) -> Result<Option<u64>> {
+        self.storage.current_size().await
+    }
+
+    async fn max_size(&self) -> Result<Option<u64>> {
+        self.storage.max_size().await
+    }
+
+    fn requests_in_flight(&self) -> Option<usize> {
+        self.storage.requests_in_flight()
+    }
+
+    fn take_counts(&self) -> StorageCounts {
+        let micros = self.queue_micros.swap(0, Ordering::Relaxed);
+        self.storage.take_counts()
+            + StorageCounts {
+                fetches_queued: self.queued.swap(0, Ordering::Relaxed),
+                fetch_queue_duration: Duration::from_micros(micros),
+                ..Default::default()
+            }
+    }
+
+    fn eviction_state(&self) -> Option<EvictionState> {
+        self.storage.eviction_state()
+    }
+
+    fn occupancy(&self) -> Option<CacheOccupancy> {
+        self.storage.occupancy()
+    }
+
+    fn entry_config(&self) -> EntryConfig {
+        self.storage.entry_config()
+    }
+
+    fn preprocessor_cache_mode_config(&self) -> PreprocessorCacheModeConfig {
+        self.storage.preprocessor_cache_mode_config()
+    }
+
+    async fn get_preprocessor_cache_entry(
+        &self,
+        key: &str,
+    ) -> Result<Option<Box<dyn crate::lru_disk_cache::ReadSeek>>> {
+        self.storage.get_preprocessor_cache_entry(key).await
+    }
+
+    async fn put_preprocessor_cache_entry(
+        &self,
+        key: &str,
+        preprocessor_cache_entry: PreprocessorCacheEntry,
+    ) -> Result<()> {
+        self.storage
+            .put_preprocessor_cache_entry(key, preprocessor_cache_entry)
+            .await
+    }
+}
+
+#[async_trait]
+impl Storage for LimitedStorage {
+    async fn get(&self, key: &str) -> Result<Cache> {
+        let _permit = self.acquire().await;
+        self.storage.get(key).await
+    }
+
+    async fn put(&self, key: &str, entry: CacheWrite) -> Result<Duration> {
+        let _permit = self.acquire().await;
+        self.storage.put(key, entry).await
+    }
+
+    async fn check(&self) -> Result<CacheMode> {
+        let _permit = self.acquire().await;
+        self.storage.check().await
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.storage.is_read_only()
+    }
+
+    async fn probe(&self) -> Result<()> {
+        let _permit = self.acquire().await;
+        self.storage.probe().await
+    }
+
+    fn location(&self) -> String {
+        self.storage.location()
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        let _permit = self.acquire().await;
+        self.storage.list().await
+    }
+
+    async fn list_entries(&self, prefix: &str) -> Result<EntryStream> {
+        // The listing keeps its slot until its stream is dropped.
+        let permit = self
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("The semaphore is never closed");
+        let entries = self.storage.list_entries(prefix).await?;
+        Ok(entries
+            .map(move |entry| {
+                let _permit = &permit;
+                entry
+            })
+            .boxed())
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool> {
+        let _permit = self.acquire().await;
+        self.storage.delete(key).await
+    }
+
+    async fn current_size(&self) -> Result<Option<u64>> {
+        self.storage.current_size().await
+    }
+
+    async fn max_size(&self) -> Result<Option<u64>> {
+        self.storage.max_size().await
+    }
+
+    fn requests_in_flight(&self) -> Option<usize> {
+        Some(self.max_concurrency - self.permits.available_permits())
+    }
+
+    fn take_counts(&self) -> StorageCounts {
+        self.storage.take_counts()
+    }
+
+    fn eviction_state(&self) -> Option<EvictionState> {
+        self.storage.eviction_state()
+    }
+
+    fn occupancy(&self) -> Option<CacheOccupancy> {
+        self.storage.occupancy()
+    }
+
+    fn entry_config(&self) -> EntryConfig {
+        self.storage.entry_config()
+    }
+
+    fn preprocessor_cache_mode_config(&self) -> PreprocessorCacheModeConfig {
+        self.storage.preprocessor_cache_mode_config()
+    }
+
+    async fn get_preprocessor_cache_entry(
+        &self,
+        key: &str,
+    ) -> Result<Option<Box<dyn crate::lru_disk_cache::ReadSeek>>> {
+        self.storage.get_preprocessor_cache_entry(key).await
+    }
+
+    async fn put_preprocessor_cache_entry(
+        &self,
+        key: &str,
+        preprocessor_cache_entry: PreprocessorCacheEntry,
+    ) -> Result<()> {
+        self.storage
+            .put_preprocessor_cache_entry(key, preprocessor_cache_entry)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::mock_storage::MockStorage;
+
+    #[test]
+    fn test_limited_storage_caps_concurrency() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let mock = Arc::new(MockStorage::new(Some(Duration::from_millis(100)), false));
+        let storage = Arc::new(LimitedStorage::new(mock.clone(), 2));
+        assert_eq!(storage.requests_in_flight(), Some(0));
+
+        runtime.block_on(async {
+            let gets: Vec<_> = (0..5)
+                .map(|_| {
+                    mock.next_get(Ok(Cache::Miss));
+                    let storage = storage.clone();
+                    tokio::spawn(async move { storage.get("key").await })
+                })
+                .collect();
+            // Let all the gets start, only two of them get a slot.
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            assert_eq!(storage.requests_in_flight(), Some(2));
+            for get in gets {
+                assert!(matches!(get.await.unwrap().unwrap(), Cache::Miss));
+            }
+        });
+        assert_eq!(storage.requests_in_flight(), Some(0));
+    }
+
+    #[test]
+    fn test_fetch_limited_storage() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let mock = Arc::new(MockStorage::new(Some(Duration::from_millis(100)), false));
+        let storage = Arc::new(FetchLimitedStorage::new(mock.clone(), 1));
+
+        runtime.block_on(async {
+            let start = Instant::now();
+            let gets: Vec<_> = (0..2)
+                .map(|_| {
+                    mock.next_get(Ok(Cache::Miss));
+                    let storage = storage.clone();
+                    tokio::spawn(async move { storage.get("key").await })
+                })
+                .collect();
+            // The writes don't wait for the fetches.
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            storage.put("key", CacheWrite::new()).await.unwrap();
+            assert!(start.elapsed() < Duration::from_millis(200));
+            for get in gets {
+                assert!(matches!(get.await.unwrap().unwrap(), Cache::Miss));
+            }
+            // The fetches ran one after the other.
+            assert!(start.elapsed() >= Duration::from_millis(200));
+        });
+        let counts = storage.take_counts();
+        assert_eq!(counts.fetches_queued, 1);
+        assert!(counts.fetch_queue_duration >= Duration::from_millis(50));
+        assert_eq!(storage.take_counts(), StorageCounts::default());
+    }
+
+    #[test]
+    fn test_limit_concurrency() {
+        let mock: Arc<dyn Storage> = Arc::new(MockStorage::new(None, false));
+        let storage = limit_concurrency(mock.clone(), None).unwrap();
+        assert_eq!(storage.requests_in_flight(), None);
+        let storage = limit_concurrency(mock.clone(), Some(4)).unwrap();
+        assert_eq!(storage.requests_in_flight(), Some(0));
+        assert!(limit_concurrency(mock, Some(0)).is_err());
+    }
+}
@@ -0,0 +1,320 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A small in-memory cache of the recently fetched entries, in front of
+//! another storage, so that entries restored again and again in a build are
+//! served from memory.
+
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use crate::cache::{
+    Cache, CacheMode, CacheOccupancy, CacheRead, CacheWrite, EntryStream, EvictionState, Storage,
+    StorageCounts,
+};
+use crate::compiler::PreprocessorCacheEntry;
+use crate::config::EntryConfig;
+use crate::errors::*;
+use crate::lru_disk_cache::{LruCache, Meter};
+
+use super::PreprocessorCacheModeConfig;
+
+/// Measure the entries by their size.
+struct EntrySize;
+
+impl<K> Meter<K, Arc<[u8]>> for EntrySize {
+    type Measure = usize;
+    fn measure<Q: ?Sized>(&self, _: &Q, v: &Arc<[u8]>) -> usize
+    where
+        K: Borrow<Q>,
+    {
+        v.len()
+    }
+}
+
+/// A storage keeping the entries it fetched from the underlying storage in
+/// memory, up to `max_size` bytes, evicting the least recently used ones.
+///
+/// Entries larger than a quarter of `max_size` aren't kept, so that a single
+/// one doesn't evict all the others.
+pub struct MemoryCache {
+    storage: Arc<dyn Storage>,
+    entries: Mutex<LruCache<String, Arc<[u8]>, RandomState, EntrySize>>,
+    max_entry_size: u64,
+    /// The hits served from memory since they were last taken.
+    hits: AtomicU64,
+    /// Bumped, along with dropping the entry from memory, before and after
+    /// each write of the underlying storage, so that an entry fetched while
+    /// one was running isn't kept.
+    generation: AtomicU64,
+}
+
+impl MemoryCache {
+    pub fn new(storage: Arc<dyn Storage>, max_size: u64) -> Self {
+        MemoryCache {
+            storage,
+            entries: Mutex::new(LruCache::with_meter(max_size, EntrySize)),
+            max_entry_size: max_size / 4,
+            hits: AtomicU64::new(0),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    fn lookup(&self, key: &str) -> Option<Arc<[u8]>> {
+        self.entries.lock().unwrap().get(key).cloned()
+    }
+
+    /// Drop the entry `key` from memory, and the entries being fetched.
+    fn invalidate(&self, key: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.remove(key);
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Keep the entry `hit` read from the underlying storage, if it is small
+    /// enough and no write ran since `generation`, and return it.
+    fn keep(&self, key: &str, hit: CacheRead, generation: u64) -> Result<CacheRead> {
+        let mut reader = hit.into_inner();
+        let size = reader.seek(SeekFrom::End(0))?;
+        reader.rewind()?;
+        if size > self.max_entry_size {
+            return CacheRead::from(reader);
+        }
+        let mut bytes = Vec::with_capacity(size as usize);
+        reader.read_to_end(&mut bytes)?;
+        let bytes: Arc<[u8]> = bytes.into();
+        let mut entries = self.entries.lock().unwrap();
+        if self.generation.load(Ordering::SeqCst) == generation {
+            entries.insert(key.to_owned(), bytes.clone());
+        }
+        CacheRead::from(Cursor::new(bytes))
+    }
+}
+
+/// Put a `MemoryCache` of `max_size` bytes in front of `storage`, unless
+/// `max_size` is 0.
+pub fn with_memory_cache(storage: Arc<dyn Storage>, max_size: u64) -> Arc<dyn Storage> {
+    if max_size == 0 {
+        return storage;
+    }
+    debug!(
+        "Keeping up to {} bytes of cache entries in memory",
+        max_size
+    );
+    Arc::new(MemoryCache::new(storage, max_size))
+}
+
+#[async_trait]
+impl Storage for MemoryCache {
+    async fn get(&self, key: &str) -> Result<Cache> {
+        if let Some(bytes) = self.lookup(key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(Cache::Hit(CacheRead::from(Cursor::new(bytes))?));
+        }
+        let generation = self.generation.load(Ordering::SeqCst);
+        match self.storage.get(key).await? {
+            Cache::Hit(hit) => Ok(Cache::Hit(self.keep(key, hit, generation)?)),
+            other => Ok(other),
+        }
+    }
+
+    async fn put(&self, key: &str, entry: CacheWrite) -> Result<Duration> {
+        self.invalidate(key);
+        let res = self.storage.put(key, entry).await;
+        self.invalidate(key);
+        res
+    }
+
+    async fn check(&self) -> Result<CacheMode> {
+        self.storage.check().await
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.storage.is_read_only()
+    }
+
+    async fn probe(&self) -> Result<()> {
+        self.storage.probe().await
+    }
+
+    fn location(&self) -> String {
+        self.storage.location()
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        self.storage.list().await
+    }
+
+    async fn list_entries(&self, prefix: &str) -> Result<EntryStream> {
+        self.storage.list_entries(prefix).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool> {
+        self.invalidate(key);
+        let res = self.storage.delete(key).await;
+        self.invalidate(key);
+        res
+    }
+
+    async fn current_size(&self) -> Result<Option<u64>> {
+        self.storage.current_size().await
+    }
+
+    async fn max_size(&self) -> Result<Option<u64>> {
+        self.storage.max_size().await
+    }
+
+    fn requests_in_flight(&self) -> Option<usize> {
+        self.storage.requests_in_flight()
+    }
+
+    fn take_counts(&self) -> StorageCounts {
+        let hits = self.hits.swap(0, Ordering::Relaxed);
+        self.storage.take_counts()
+            + StorageCounts {
+                memory_hits: hits,
+                ..Default::default()
+            }
+    }
+
+    fn eviction_state(&self) -> Option<EvictionState> {
+        self.storage.eviction_state()
+    }
+
+    fn occupancy(&self) -> Option<CacheOccupancy> {
+        self.storage.occupancy()
+    }
+
+    fn entry_config(&self) -> EntryConfig {
+        self.storage.entry_config()
+    }
+
+    fn preprocessor_cache_mode_config(&self) -> PreprocessorCacheModeConfig {
+        self.storage.preprocessor_cache_mode_config()
+    }
+
+    async fn get_preprocessor_cache_entry(
+        &self,
+        key: &str,
+    ) -> Result<Option<Box<dyn crate::lru_disk_cache::ReadSeek>>> {
+        self.storage.get_preprocessor_cache_entry(key).await
+    }
+
+    async fn put_preprocessor_cache_entry(
+        &self,
+        key: &str,
+        preprocessor_cache_entry: PreprocessorCacheEntry,
+    ) -> Result<()> {
+        self.storage
+            .put_preprocessor_cache_entry(key, preprocessor_cache_entry)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::mock_storage::MockStorage;
+
+    fn entry(stdout: &[u8]) -> CacheRead {
+        let mut entry = CacheWrite::new();
+        entry.put_stdout(stdout).unwrap();
+        CacheRead::from(Cursor::new(entry.finish().unwrap())).unwrap()
+    }
+
+    #[test]
+    fn test_memory_cache() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let mock = Arc::new(MockStorage::new(None, false));
+        let storage = MemoryCache::new(mock.clone(), 1024 * 1024);
+
+        runtime.block_on(async {
+            // A single get reaches the underlying storage, later ones are
+            // served from memory.
+            mock.next_get(Ok(Cache::Hit(entry(b"first"))));
+            for _ in 0..3 {
+                match storage.get("key").await.unwrap() {
+                    Cache::Hit(mut hit) => assert_eq!(hit.get_stdout(), b"first"),
+                    other => panic!("unexpected {:?}", other),
+                }
+            }
+            assert_eq!(storage.take_counts().memory_hits, 2);
+
+            // Misses aren't kept.
+            mock.next_get(Ok(Cache::Miss));
+            assert!(matches!(storage.get("other").await.unwrap(), Cache::Miss));
+
+            // Writing the key drops it from memory.
+            storage.put("key", CacheWrite::new()).await.unwrap();
+            mock.next_get(Ok(Cache::Hit(entry(b"second"))));
+            match storage.get("key").await.unwrap() {
+                Cache::Hit(mut hit) => assert_eq!(hit.get_stdout(), b"second"),
+                other => panic!("unexpected {:?}", other),
+            }
+            assert_eq!(storage.take_counts().memory_hits, 0);
+        });
+    }
+
+    #[test]
+    fn test_memory_cache_fetch_during_write() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        let mock = Arc::new(MockStorage::new(Some(Duration::from_millis(10)), false));
+        let storage = MemoryCache::new(mock.clone(), 1024 * 1024);
+
+        runtime.block_on(async {
+            // The entry fetched while the key is written may be the previous
+            // one, it isn't kept.
+            mock.next_get(Ok(Cache::Hit(entry(b"previous"))));
+            let (put, hit) = futures::join!(storage.put("key", CacheWrite::new()), async {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                storage.get("key").await
+            });
+            put.unwrap();
+            assert!(matches!(hit.unwrap(), Cache::Hit(_)));
+            mock.next_get(Ok(Cache::Hit(entry(b"written"))));
+            match storage.get("key").await.unwrap() {
+                Cache::Hit(mut hit) => assert_eq!(hit.get_stdout(), b"written"),
+                other => panic!("unexpected {:?}", other),
+            }
+        });
+    }
+
+    #[test]
+    fn test_memory_cache_skips_large_entries() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let mock = Arc::new(MockStorage::new(None, false));
+        let storage = MemoryCache::new(mock.clone(), 64);
+
+        runtime.block_on(async {
+            for _ in 0..2 {
+                mock.next_get(Ok(Cache::Hit(entry(b"too large to be kept"))));
+                match storage.get("key").await.unwrap() {
+                    Cache::Hit(mut hit) => assert_eq!(hit.get_stdout(), b"too large to be kept"),
+                    other => panic!("unexpected {:?}", other),
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,278 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The metadata of the cache entries, e.g. the compiler they were made with
+//! and when, which the remote caches having a place for it store along with
+//! the entries, for the tools managing the cache outside of sccache, such as
+//! a garbage collection applying a retention policy.
+//!
+//! opendal can't set the metadata of the objects it writes, so GCS writes the
+//! entries with `PUT` requests made and signed here, all its other requests
+//! still going through opendal, and S3 makes all its requests itself, see
+//! `S3Storage`. The other backends store the entries without their metadata.
+
+use std::time::SystemTime;
+
+use crate::compiler::host_target;
+
+#[cfg(feature = "gcs")]
+pub use self::storage::{ObjectPut, WithMetadata};
+
+/// The longest value of the metadata, the whole of it being limited to 2 KiB
+/// by S3.
+const MAX_VALUE_LEN: usize = 256;
+
+/// What a cache entry was made with, and when.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EntryMetadata {
+    /// The kind of the compiler, e.g. `gcc` or `rustc`.
+    pub compiler: &'static str,
+    /// The version of the compiler, as it reports it, if it's known.
+    pub compiler_version: Option<String>,
+    /// The version of the hash keys of the compiler, which changes when the
+    /// keys of the same compilations do.
+    pub key_version: String,
+    /// When the entry was made.
+    pub created: SystemTime,
+    /// The build which made the entry, e.g. the commit it built, from the
+    /// `SCCACHE_BUILD_ID` of the client.
+    pub build_id: Option<String>,
+}
+
+impl EntryMetadata {
+    /// The names and the values of the metadata, the values limited to the
+    /// printable ASCII which the headers of HTTP carry.
+    pub fn pairs(&self) -> Vec<(&'static str, String)> {
+        let created = chrono::DateTime::<chrono::Utc>::from(self.created)
+            .to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+        let mut pairs = vec![("created", created), ("compiler", self.compiler.to_owned())];
+        if let Some(version) = &self.compiler_version {
+            pairs.push(("compiler-version", printable(version)));
+        }
+        pairs.push(("sccache-version", env!("CARGO_PKG_VERSION").to_owned()));
+        pairs.push(("key-version", printable(&self.key_version)));
+        pairs.push(("target", host_target().to_owned()));
+        if let Some(build_id) = &self.build_id {
+            pairs.push(("build-id", printable(build_id)));
+        }
+        pairs
+    }
+}
+
+/// Replace the characters of `value` which aren't printable ASCII, and cut
+/// it to `MAX_VALUE_LEN`.
+fn printable(value: &str) -> String {
+    let value: String = value
+        .chars()
+        .take(MAX_VALUE_LEN)
+        .map(|c| if c.is_ascii_graphic() { c } else { ' ' })
+        .collect();
+    value.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(feature = "gcs")]
+mod storage {
+    use std::time::{Duration, Instant};
+
+    use async_trait::async_trait;
+    use opendal::Operator;
+
+    use crate::cache::{layout, Cache, CacheMode, CacheWrite, EntryStream, Storage, StorageCounts};
+    use crate::clock::{self, SharedClock};
+    use crate::errors::*;
+    use crate::retry::{send_with_retries, with_retries, Backoff, RetryCount};
+
+    /// The requests writing the objects of a backend, with their metadata.
+    #[async_trait]
+    pub trait ObjectPut: Send + Sync + 'static {
+        /// The prefix of the headers setting the metadata, e.g. `x-amz-meta-`.
+        fn metadata_prefix(&self) -> &'static str;
+
+        /// Make the request writing the object at `path`, without its body.
+        async fn request(&self, path: &str, content_type: &str) -> Result<reqwest::Request>;
+
+        /// Sign `request`, once its headers and its body are set.
+        async fn sign(&self, request: &mut reqwest::Request) -> Result<()>;
+
+        /// Take note of `response`, returning whether to sign the request
+        /// again and send it once more, e.g. with the clock corrected.
+        fn received(&self, _response: &reqwest::Response) -> bool {
+            false
+        }
+    }
+
+    /// A remote cache writing its entries with their metadata through `put`,
+    /// and going through `operator` for everything else, including writing
+    /// the entries without metadata.
+    pub struct WithMetadata<P> {
+        operator: Operator,
+        client: reqwest::Client,
+        put: P,
+        backoff: Backoff,
+        retries: RetryCount,
+        clock: SharedClock,
+    }
+
+    impl<P: ObjectPut> WithMetadata<P> {
+        /// Send the requests of `put` with `client`.
+        pub fn new(operator: Operator, client: reqwest::Client, put: P) -> Self {
+            WithMetadata {
+                operator,
+                client,
+                put,
+                backoff: Backoff::fixed(Duration::ZERO, 0),
+                retries: RetryCount::default(),
+                clock: clock::system(),
+            }
+        }
+
+        /// Retry the requests failing temporarily as `backoff` says.
+        pub fn with_retries(self, backoff: Backoff) -> Self {
+            WithMetadata {
+                operator: with_retries(self.operator, &backoff, &self.retries),
+                backoff,
+                ..self
+            }
+        }
+    }
+
+    #[async_trait]
+    impl<P: ObjectPut> Storage for WithMetadata<P> {
+        async fn get(&self, key: &str) -> Result<Cache> {
+            Storage::get(&self.operator, key).await
+        }
+
+        async fn put(&self, key: &str, entry: CacheWrite) -> Result<Duration> {
+            let Some(metadata) = entry.metadata().cloned() else {
+                return Storage::put(&self.operator, key, entry).await;
+            };
+            let start = Instant::now();
+            let content_type = entry.format().content_type();
+            let body = bytes::Bytes::from(entry.finish()?);
+            let path = layout::remote()[0].path(key);
+            let pairs = metadata.pairs();
+            let send = || async {
+                let mut request = self.put.request(&path, &content_type).await?;
+                for (name, value) in &pairs {
+                    request.headers_mut().insert(
+                        reqwest::header::HeaderName::try_from(format!(
+                            "{}{}",
+                            self.put.metadata_prefix(),
+                            name
+                        ))?,
+                        reqwest::header::HeaderValue::try_from(value)?,
+                    );
+                }
+                *request.body_mut() = Some(body.clone().into());
+                self.put.sign(&mut request).await?;
+                self.client
+                    .execute(request)
+                    .await
+                    .context("failed to reach the cache")
+            };
+            let response = send_with_retries(&self.backoff, &self.clock, &self.retries, || async {
+                let response = send().await?;
+                if self.put.received(&response) {
+                    return send().await;
+                }
+                Ok(response)
+            })
+            .await?;
+            let status = response.status();
+            if !status.is_success() {
+                let body = response.text().await.unwrap_or_default();
+                bail!("the cache answered with {}: {}", status, body.trim());
+            }
+            Ok(start.elapsed())
+        }
+
+        async fn check(&self) -> Result<CacheMode> {
+            Storage::check(&self.operator).await
+        }
+
+        async fn probe(&self) -> Result<()> {
+            Storage::probe(&self.operator).await
+        }
+
+        fn location(&self) -> String {
+            Storage::location(&self.operator)
+        }
+
+        async fn list(&self) -> Result<Vec<String>> {
+            Storage::list(&self.operator).await
+        }
+
+        async fn list_entries(&self, prefix: &str) -> Result<EntryStream> {
+            Storage::list_entries(&self.operator, prefix).await
+        }
+
+        async fn delete(&self, key: &str) -> Result<bool> {
+            Storage::delete(&self.operator, key).await
+        }
+
+        async fn current_size(&self) -> Result<Option<u64>> {
+            Storage::current_size(&self.operator).await
+        }
+
+        async fn max_size(&self) -> Result<Option<u64>> {
+            Storage::max_size(&self.operator).await
+        }
+
+        fn take_counts(&self) -> StorageCounts {
+            StorageCounts {
+                retries: self.retries.take(),
+                ..Default::default()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_entry_metadata_pairs() {
+        let metadata = EntryMetadata {
+            compiler: "gcc",
+            compiler_version: Some("gcc 13.2.0\n\u{e9}dition".to_owned()),
+            key_version: "11".to_owned(),
+            created: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            build_id: None,
+        };
+        assert_eq!(
+            metadata.pairs(),
+            vec![
+                ("created", "2023-11-14T22:13:20Z".to_owned()),
+                ("compiler", "gcc".to_owned()),
+                ("compiler-version", "gcc 13.2.0 dition".to_owned()),
+                ("sccache-version", env!("CARGO_PKG_VERSION").to_owned()),
+                ("key-version", "11".to_owned()),
+                ("target", host_target().to_owned()),
+            ]
+        );
+        let long = EntryMetadata {
+            compiler_version: Some("x".repeat(1000)),
+            ..metadata.clone()
+        };
+        assert_eq!(long.pairs()[2].1.len(), MAX_VALUE_LEN);
+        let built = EntryMetadata {
+            build_id: Some("1c26a3a\n".to_owned()),
+            ..metadata
+        };
+        assert_eq!(
+            built.pairs().last(),
+            Some(&("build-id", "1c26a3a".to_owned()))
+        );
+    }
+}
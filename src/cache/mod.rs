@@ -12,24 +12,54 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+pub mod archive;
 #[cfg(feature = "azure")]
 pub mod azure;
 #[allow(clippy::module_inception)]
 pub mod cache;
+pub mod configured;
+pub mod custom;
+pub mod dictionary;
 pub mod disk;
+pub mod fallback;
 #[cfg(feature = "gcs")]
 pub mod gcs;
 #[cfg(feature = "gha")]
 pub mod gha;
+#[cfg(feature = "http-cache")]
+pub mod http;
+pub mod layout;
+pub mod limited;
 #[cfg(feature = "memcached")]
 pub mod memcached;
+pub mod memory;
+pub mod metadata;
+#[cfg(any(
+    feature = "azure",
+    feature = "gcs",
+    feature = "gha",
+    feature = "memcached",
+    feature = "redis",
+    feature = "s3",
+    feature = "webdav",
+    feature = "oss",
+))]
+pub mod multipart;
 #[cfg(feature = "oss")]
 pub mod oss;
+pub mod overrides;
+pub mod prefetch;
 pub mod readonly;
 #[cfg(feature = "redis")]
 pub mod redis;
+pub mod reloading;
+#[cfg(any(feature = "s3", feature = "http-cache"))]
+pub mod request;
 #[cfg(feature = "s3")]
 pub mod s3;
+pub mod shadow;
+pub mod tiered;
+pub mod timeout;
 #[cfg(feature = "webdav")]
 pub mod webdav;
 
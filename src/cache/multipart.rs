@@ -0,0 +1,311 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reading the large entries of the remote caches in parts, each part with
+//! its own ranged request, several at once, for the links on which a single
+//! stream is too slow, e.g. those with a high latency.
+//!
+//! It is enabled by `SCCACHE_MULTIPART_DOWNLOAD_THRESHOLD`, the size from
+//! which the entries are read in parts. The size of an entry is then looked
+//! up before reading it, which costs a round trip for every entry, so that
+//! it's only worth it if the large entries are slow to read. The key-value
+//! stores, which can't read ranges, read the entries whole.
+//!
+//! The parts are read from the same version of the entry, if the backend
+//! tells its ETag, and the entry put back together is checked against the
+//! CRC-32 of each of its objects, which a zip archive records.
+
+use std::env;
+use std::io::{self, Cursor};
+use std::ops::Range;
+
+use async_trait::async_trait;
+use futures::{StreamExt, TryStreamExt};
+use once_cell::sync::Lazy;
+use opendal::{Error, ErrorKind, Operator, Scheme};
+use zip::ZipArchive;
+
+use crate::config::parse_size;
+
+/// The default size of the parts, in bytes.
+pub const DEFAULT_PART_SIZE: u64 = 8 << 20;
+/// The default number of parts of an entry read at once.
+pub const DEFAULT_CONCURRENCY: usize = 8;
+
+/// How the large entries are read in parts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MultipartDownload {
+    /// The size from which the entries are read in parts, if they are.
+    pub threshold: Option<u64>,
+    /// The size of each part but the last.
+    pub part_size: u64,
+    /// How many parts of an entry are read at once.
+    pub concurrency: usize,
+}
+
+static MULTIPART_DOWNLOAD: Lazy<MultipartDownload> = Lazy::new(MultipartDownload::from_env);
+
+/// How the large entries are read in parts, set by
+/// `SCCACHE_MULTIPART_DOWNLOAD_THRESHOLD`, `SCCACHE_MULTIPART_DOWNLOAD_PART_SIZE`
+/// and `SCCACHE_MULTIPART_DOWNLOAD_CONCURRENCY`.
+pub fn multipart_download() -> &'static MultipartDownload {
+    &MULTIPART_DOWNLOAD
+}
+
+/// Parse the size of `var`, a number of bytes with an optional `K`, `M`, `G`
+/// or `T` suffix, greater than 0.
+fn size_from_env(var: &str) -> Option<u64> {
+    let value = env::var(var).ok()?;
+    let size = parse_size(&value).filter(|size| *size > 0);
+    if size.is_none() {
+        warn!("Ignoring {}={:?}, which is no size", var, value);
+    }
+    size
+}
+
+impl MultipartDownload {
+    fn from_env() -> MultipartDownload {
+        let concurrency = match env::var("SCCACHE_MULTIPART_DOWNLOAD_CONCURRENCY") {
+            Ok(s) => s.parse().ok().filter(|n| *n > 0).unwrap_or_else(|| {
+                warn!(
+                    "Invalid SCCACHE_MULTIPART_DOWNLOAD_CONCURRENCY {:?}, using {}",
+                    s, DEFAULT_CONCURRENCY
+                );
+                DEFAULT_CONCURRENCY
+            }),
+            Err(_) => DEFAULT_CONCURRENCY,
+        };
+        MultipartDownload {
+            threshold: size_from_env("SCCACHE_MULTIPART_DOWNLOAD_THRESHOLD"),
+            part_size: size_from_env("SCCACHE_MULTIPART_DOWNLOAD_PART_SIZE")
+                .unwrap_or(DEFAULT_PART_SIZE),
+            concurrency,
+        }
+    }
+
+    /// Read the object at `path` of `objects`, in parts if it's large enough
+    /// and the backend can read ranges.
+    pub async fn read(&self, objects: &impl Objects, path: &str) -> opendal::Result<Vec<u8>> {
+        let Some(threshold) = self.threshold else {
+            return objects.read(path).await;
+        };
+        if !objects.reads_ranges() {
+            return objects.read(path).await;
+        }
+        let (size, etag) = objects.stat(path).await?;
+        if size < threshold || size <= self.part_size {
+            return objects.read(path).await;
+        }
+        let start = std::time::Instant::now();
+        let ranges: Vec<_> = (0..size)
+            .step_by(self.part_size as usize)
+            .map(|start| start..(start + self.part_size).min(size))
+            .collect();
+        let count = ranges.len();
+        let parts: Vec<Vec<u8>> = futures::stream::iter(ranges)
+            .map(|range| {
+                let expected = range.end - range.start;
+                let etag = etag.as_deref();
+                async move {
+                    let part = objects.read_range(path, range, etag).await?;
+                    if part.len() as u64 != expected {
+                        return Err(Error::new(
+                            ErrorKind::Unexpected,
+                            "a part of the entry has the wrong size",
+                        ));
+                    }
+                    Ok(part)
+                }
+            })
+            .buffered(self.concurrency)
+            .try_collect()
+            .await?;
+        let entry = parts.concat();
+        verify(&entry).map_err(|e| {
+            Error::new(ErrorKind::Unexpected, "the entry read in parts is corrupt").set_source(e)
+        })?;
+        debug!(
+            "Read {} in {} parts of {} bytes in {:?}",
+            path,
+            count,
+            self.part_size,
+            start.elapsed()
+        );
+        Ok(entry)
+    }
+}
+
+/// The objects of a backend, which may be read in parts.
+#[async_trait]
+pub trait Objects: Sync {
+    /// Whether the objects are read in parts with ranged requests, and their
+    /// size can be looked up.
+    fn reads_ranges(&self) -> bool;
+
+    /// Read the whole object at `path`.
+    async fn read(&self, path: &str) -> opendal::Result<Vec<u8>>;
+
+    /// Look up the size of the object at `path`, and its ETag if the parts
+    /// can be read from the version it tags.
+    async fn stat(&self, path: &str) -> opendal::Result<(u64, Option<String>)>;
+
+    /// Read `range` of the object at `path`, from the version tagged `etag`.
+    async fn read_range(
+        &self,
+        path: &str,
+        range: Range<u64>,
+        etag: Option<&str>,
+    ) -> opendal::Result<Vec<u8>>;
+}
+
+#[async_trait]
+impl Objects for Operator {
+    fn reads_ranges(&self) -> bool {
+        let info = self.info();
+        reads_ranges(info.scheme()) && info.full_capability().stat
+    }
+
+    async fn read(&self, path: &str) -> opendal::Result<Vec<u8>> {
+        Operator::read(self, path).await
+    }
+
+    async fn stat(&self, path: &str) -> opendal::Result<(u64, Option<String>)> {
+        let metadata = Operator::stat(self, path).await?;
+        let etag = metadata
+            .etag()
+            .filter(|_| self.info().full_capability().read_with_if_match)
+            .map(ToOwned::to_owned);
+        Ok((metadata.content_length(), etag))
+    }
+
+    async fn read_range(
+        &self,
+        path: &str,
+        range: Range<u64>,
+        etag: Option<&str>,
+    ) -> opendal::Result<Vec<u8>> {
+        let mut read = self.read_with(path).range(range);
+        if let Some(etag) = etag {
+            read = read.if_match(etag);
+        }
+        read.await
+    }
+}
+
+/// Whether the backend reads the ranges of the objects with ranged requests.
+/// The key-value stores, e.g. Redis, claim to, but read the whole value.
+fn reads_ranges(scheme: Scheme) -> bool {
+    matches!(
+        scheme,
+        Scheme::Azblob | Scheme::Gcs | Scheme::Ghac | Scheme::S3 | Scheme::Webdav
+    )
+}
+
+/// Check the objects of `entry` against their CRC-32, which reading them to
+/// their end does.
+fn verify(entry: &[u8]) -> zip::result::ZipResult<()> {
+    let mut archive = ZipArchive::new(Cursor::new(entry))?;
+    for i in 0..archive.len() {
+        io::copy(&mut archive.by_index(i)?, &mut io::sink())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::CacheWrite;
+
+    fn entry(size: usize) -> Vec<u8> {
+        let mut entry = CacheWrite::new();
+        let object: Vec<u8> = (0..size).map(|i| (i * 7 % 251) as u8).collect();
+        entry.put_object("obj", &mut &object[..], None).unwrap();
+        entry.finish().unwrap()
+    }
+
+    #[test]
+    fn test_verify() {
+        let mut entry = entry(4096);
+        verify(&entry).unwrap();
+        // Flip a byte of the object, in the middle of the archive.
+        let middle = entry.len() / 2;
+        entry[middle] ^= 0xff;
+        assert!(verify(&entry).is_err());
+        assert!(verify(b"not an entry").is_err());
+    }
+
+    #[cfg(feature = "s3")]
+    #[test]
+    fn test_read_in_parts() {
+        use crate::test::mock_bucket::MockBucket;
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let bucket = MockBucket::start().await;
+            let mut builder = opendal::services::S3::default();
+            builder
+                .bucket("bucket")
+                .endpoint(&bucket.endpoint)
+                .region("us-east-1")
+                .allow_anonymous()
+                .disable_config_load()
+                .disable_ec2_metadata();
+            let operator = Operator::new(builder).unwrap().finish();
+            let large = entry(100_000);
+            let small = entry(10);
+            bucket.insert("/bucket/large", &large);
+            bucket.insert("/bucket/small", &small);
+            bucket.insert("/bucket/corrupt", &large[..large.len() - 1]);
+            let download = MultipartDownload {
+                threshold: Some(1000),
+                part_size: 16 << 10,
+                concurrency: 4,
+            };
+
+            assert_eq!(download.read(&operator, "large").await.unwrap(), large);
+            let requests = bucket.requests();
+            let ranges: Vec<_> = requests
+                .iter()
+                .filter(|r| r.method == "GET")
+                .map(|r| r.headers["range"].as_str())
+                .collect();
+            assert_eq!(ranges.len(), large.len().div_ceil(16 << 10));
+            assert_eq!(ranges[0], "bytes=0-16383");
+            // The parts are read from the version of the entry looked up.
+            assert!(requests
+                .iter()
+                .filter(|r| r.method == "GET")
+                .all(|r| r.headers.contains_key("if-match")));
+
+            // The small entries are read whole.
+            assert_eq!(download.read(&operator, "small").await.unwrap(), small);
+            let requests = bucket.requests();
+            assert_eq!(requests.iter().filter(|r| r.method == "GET").count(), 1);
+
+            // So are all of them when it's not enabled.
+            let whole = MultipartDownload {
+                threshold: None,
+                ..download
+            };
+            assert_eq!(whole.read(&operator, "large").await.unwrap(), large);
+            let requests = bucket.requests();
+            assert_eq!(requests.iter().filter(|r| r.method == "GET").count(), 1);
+
+            assert!(download.read(&operator, "corrupt").await.is_err());
+            let missing = download.read(&operator, "missing").await.unwrap_err();
+            assert_eq!(missing.kind(), ErrorKind::NotFound);
+        });
+    }
+}
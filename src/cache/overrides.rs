@@ -0,0 +1,235 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The caches a compilation may use instead of the one of the server,
+//! chosen with `SCCACHE_CACHE_OVERRIDE` in its environment among those the
+//! server allows in `SCCACHE_CACHE_OVERRIDES_ALLOWED`.
+//!
+//! Only the allowed caches can be chosen, so that a client can't make the
+//! server send the outputs of compilations, or its credentials, anywhere
+//! else.
+
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsString;
+use std::sync::Arc;
+
+use crate::cache::{storage_from_config, Storage};
+use crate::config::{CacheType, Config, RedisCacheConfig, S3CacheConfig, WebdavCacheConfig};
+use crate::errors::*;
+
+/// The variable of the environment of a compilation choosing its cache.
+pub const CACHE_OVERRIDE_ENV_VAR: &str = "SCCACHE_CACHE_OVERRIDE";
+
+/// The caches the compilations may choose, by their URL.
+#[derive(Default)]
+pub struct CacheOverrides {
+    storages: HashMap<String, Arc<dyn Storage>>,
+}
+
+impl CacheOverrides {
+    pub fn new(storages: HashMap<String, Arc<dyn Storage>>) -> Self {
+        CacheOverrides { storages }
+    }
+
+    /// Build the caches of `SCCACHE_CACHE_OVERRIDES_ALLOWED`, a
+    /// comma-separated list of URLs, see `parse_cache_override`. The other
+    /// settings of an S3 bucket are those of the cache of `config`, if it is
+    /// one. The URLs which can't be used are logged and left out.
+    pub fn from_env(config: &Config, pool: &tokio::runtime::Handle) -> Self {
+        let Ok(allowed) = env::var("SCCACHE_CACHE_OVERRIDES_ALLOWED") else {
+            return Self::default();
+        };
+        let mut storages = HashMap::new();
+        for url in allowed
+            .split(',')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+        {
+            let storage = parse_cache_override(url, config.cache.as_ref()).and_then(|cache| {
+                let config = Config {
+                    cache: Some(cache),
+                    entries: config.entries,
+                    ..Default::default()
+                };
+                storage_from_config(&config, pool)
+            });
+            match storage {
+                Ok(storage) => {
+                    storages.insert(url.to_owned(), storage);
+                }
+                Err(e) => warn!("Not allowing the cache override {}: {:#}", url, e),
+            }
+        }
+        Self::new(storages)
+    }
+
+    /// Get the cache a compilation with `env_vars` chooses, along with its
+    /// URL, or `None` if it doesn't choose one.
+    pub fn choose(
+        &self,
+        env_vars: &[(OsString, OsString)],
+    ) -> Result<Option<(String, Arc<dyn Storage>)>> {
+        let Some((_, url)) = env_vars
+            .iter()
+            .find(|(var, _)| var == CACHE_OVERRIDE_ENV_VAR)
+        else {
+            return Ok(None);
+        };
+        let url = url.to_string_lossy();
+        if url.is_empty() {
+            return Ok(None);
+        }
+        match self.storages.get(&*url) {
+            Some(storage) => Ok(Some((url.into_owned(), storage.clone()))),
+            None => Err(Error::new(CacheError(
+                "the server doesn't allow this cache, see SCCACHE_CACHE_OVERRIDES_ALLOWED",
+            ))
+            .context(format!("{}={}", CACHE_OVERRIDE_ENV_VAR, url))),
+        }
+    }
+}
+
+/// Get the cache of `url`, one of `s3://<bucket>[/<prefix>]`,
+/// `redis://...` or `rediss://...`, and `http://...` or `https://...` for
+/// WebDAV. An S3 bucket takes the other settings of `base`, if it is one.
+pub fn parse_cache_override(url: &str, base: Option<&CacheType>) -> Result<CacheType> {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        bail!("not a URL");
+    };
+    let (cache, enabled) = match scheme {
+        "s3" => {
+            let (bucket, key_prefix) = rest.split_once('/').unwrap_or((rest, ""));
+            if bucket.is_empty() {
+                bail!("no bucket");
+            }
+            let key_prefix = key_prefix.trim_end_matches('/').to_owned();
+            let cache = match base {
+                Some(CacheType::S3(base)) => S3CacheConfig {
+                    bucket: bucket.to_owned(),
+                    key_prefix,
+                    region: base.region.clone(),
+                    no_credentials: base.no_credentials,
+                    endpoint: base.endpoint.clone(),
+                    use_ssl: base.use_ssl,
+                    server_side_encryption: base.server_side_encryption,
+                    max_concurrency: base.max_concurrency,
+                    request: base.request.clone(),
+                    fallback: None,
+                },
+                _ => S3CacheConfig {
+                    bucket: bucket.to_owned(),
+                    key_prefix,
+                    region: None,
+                    no_credentials: false,
+                    endpoint: None,
+                    use_ssl: None,
+                    server_side_encryption: None,
+                    max_concurrency: None,
+                    request: Default::default(),
+                    fallback: None,
+                },
+            };
+            (CacheType::S3(cache), cfg!(feature = "s3"))
+        }
+        "redis" | "rediss" => (
+            CacheType::Redis(RedisCacheConfig {
+                endpoint: Some(url.to_owned()),
+                ..Default::default()
+            }),
+            cfg!(feature = "redis"),
+        ),
+        "http" | "https" => (
+            CacheType::Webdav(WebdavCacheConfig {
+                endpoint: url.to_owned(),
+                key_prefix: String::new(),
+                username: None,
+                password: None,
+                token: None,
+            }),
+            cfg!(feature = "webdav"),
+        ),
+        _ => bail!("unsupported scheme {:?}", scheme),
+    };
+    if !enabled {
+        bail!("the {} cache isn't built in", cache.name());
+    }
+    Ok(cache)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::mock_storage::MockStorage;
+
+    #[test]
+    fn test_parse_cache_override() {
+        let base = CacheType::S3(S3CacheConfig {
+            bucket: "production".to_owned(),
+            key_prefix: "sccache".to_owned(),
+            region: Some("eu-west-1".to_owned()),
+            no_credentials: false,
+            endpoint: None,
+            use_ssl: Some(true),
+            server_side_encryption: None,
+            max_concurrency: Some(8),
+            request: Default::default(),
+            fallback: None,
+        });
+        match parse_cache_override("s3://experimental/a/b/", Some(&base)).unwrap() {
+            CacheType::S3(s3) => {
+                assert_eq!(s3.bucket, "experimental");
+                assert_eq!(s3.key_prefix, "a/b");
+                assert_eq!(s3.region.as_deref(), Some("eu-west-1"));
+                assert_eq!(s3.max_concurrency, Some(8));
+            }
+            cache => panic!("Unexpected cache {:?}", cache),
+        }
+        match parse_cache_override("s3://experimental", None).unwrap() {
+            CacheType::S3(s3) => {
+                assert_eq!(s3.key_prefix, "");
+                assert_eq!(s3.region, None);
+            }
+            cache => panic!("Unexpected cache {:?}", cache),
+        }
+        assert!(matches!(
+            parse_cache_override("redis://127.0.0.1:6379", None).unwrap(),
+            CacheType::Redis(RedisCacheConfig { endpoint: Some(endpoint), .. })
+                if endpoint == "redis://127.0.0.1:6379"
+        ));
+        assert!(matches!(
+            parse_cache_override("https://dav.example.com/cache", None).unwrap(),
+            CacheType::Webdav(_)
+        ));
+        for url in ["s3://", "ftp://example.com", "experimental"] {
+            assert!(parse_cache_override(url, None).is_err(), "{}", url);
+        }
+    }
+
+    #[test]
+    fn test_choose_cache_override() {
+        let storage: Arc<dyn Storage> = Arc::new(MockStorage::new(None, false));
+        let overrides =
+            CacheOverrides::new(HashMap::from([("s3://experimental".to_owned(), storage)]));
+        let env = |url: &str| vec![(OsString::from(CACHE_OVERRIDE_ENV_VAR), url.into())];
+        assert!(overrides.choose(&[]).unwrap().is_none());
+        assert!(overrides.choose(&env("")).unwrap().is_none());
+        let (url, _) = overrides
+            .choose(&env("s3://experimental"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(url, "s3://experimental");
+        let err = overrides.choose(&env("s3://elsewhere")).err().unwrap();
+        assert!(err.downcast_ref::<CacheError>().is_some());
+        assert!(format!("{:#}", err).starts_with("SCCACHE_CACHE_OVERRIDE=s3://elsewhere: "));
+    }
+}
@@ -0,0 +1,161 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Warm the local disk cache from a remote cache, see `sccache --prefetch`.
+
+use crate::cache::{Cache, CacheWrite, Storage};
+use futures::stream::{self, StreamExt};
+use std::collections::HashSet;
+
+use crate::errors::*;
+
+/// How many entries are fetched from the remote cache at the same time.
+const PREFETCH_PARALLELISM: usize = 16;
+
+/// The outcome of a prefetch.
+#[derive(Debug, Default)]
+pub struct PrefetchReport {
+    /// Keys copied from the remote cache to the local one.
+    pub fetched: usize,
+    /// Keys that were already in the local cache.
+    pub already_cached: usize,
+    /// Keys that are not in the remote cache.
+    pub missing: Vec<String>,
+    /// Keys that could not be copied, along with the reason.
+    pub failed: Vec<(String, String)>,
+}
+
+enum Outcome {
+    Fetched,
+    AlreadyCached,
+    Missing,
+}
+
+/// Parse a list of cache keys.
+///
+/// Only the first word of every line is used, so that a build manifest listing
+/// a key and whatever else per line works too. Blank lines and lines starting
+/// with `#` are skipped, and duplicate keys are only returned once.
+pub fn parse_keys(contents: &str) -> Vec<String> {
+    let mut seen = HashSet::new();
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_whitespace().next())
+        .filter(|key| seen.insert(*key))
+        .map(ToOwned::to_owned)
+        .collect()
+}
+
+async fn prefetch_one(remote: &dyn Storage, local: &dyn Storage, key: &str) -> Result<Outcome> {
+    if let Cache::Hit(_) = local.get(key).await? {
+        return Ok(Outcome::AlreadyCached);
+    }
+    match remote.get(key).await? {
+        Cache::Hit(entry) => {
+            let entry = CacheWrite::from_bytes(entry.into_bytes()?)?;
+            local.put(key, entry).await?;
+            Ok(Outcome::Fetched)
+        }
+        Cache::Miss | Cache::Recache => Ok(Outcome::Missing),
+    }
+}
+
+/// Copy the entries for `keys` from `remote` to `local`, several at a time.
+///
+/// Keys missing from `remote` are not errors, they are listed in the report.
+pub async fn prefetch(
+    remote: &dyn Storage,
+    local: &dyn Storage,
+    keys: Vec<String>,
+) -> PrefetchReport {
+    let mut results = stream::iter(keys)
+        .map(|key| async move {
+            let res = prefetch_one(remote, local, &key).await;
+            (key, res)
+        })
+        .buffer_unordered(PREFETCH_PARALLELISM);
+
+    let mut report = PrefetchReport::default();
+    while let Some((key, res)) = results.next().await {
+        match res {
+            Ok(Outcome::Fetched) => report.fetched += 1,
+            Ok(Outcome::AlreadyCached) => report.already_cached += 1,
+            Ok(Outcome::Missing) => report.missing.push(key),
+            Err(e) => {
+                debug!("prefetch of {} failed: {:?}", key, e);
+                report.failed.push((key, format!("{:#}", e)));
+            }
+        }
+    }
+    report.missing.sort();
+    report.failed.sort();
+    report
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::disk::DiskCache;
+    use crate::cache::{CacheMode, PreprocessorCacheModeConfig};
+
+    #[test]
+    fn test_parse_keys() {
+        let keys = parse_keys("# keys\nabc\n\n  def  out/foo.o\nabc\n#ghi\n");
+        assert_eq!(keys, vec!["abc", "def"]);
+    }
+
+    #[test]
+    fn test_prefetch() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let tempdir = tempfile::Builder::new()
+            .prefix("sccache_test_prefetch")
+            .tempdir()
+            .unwrap();
+        let disk = |name| {
+            DiskCache::new(
+                tempdir.path().join(name),
+                u64::MAX,
+                runtime.handle(),
+                PreprocessorCacheModeConfig::default(),
+                CacheMode::ReadWrite,
+            )
+        };
+        let remote = disk("remote");
+        let local = disk("local");
+
+        runtime.block_on(async {
+            for key in ["aaaa", "bbbb"] {
+                let mut entry = CacheWrite::new();
+                entry.put_stdout(key.as_bytes()).unwrap();
+                remote.put(key, entry).await.unwrap();
+            }
+            local.put("bbbb", CacheWrite::new()).await.unwrap();
+
+            let keys = parse_keys("aaaa\nbbbb\ncccc\n");
+            let report = prefetch(&remote, &local, keys).await;
+            assert_eq!(report.fetched, 1);
+            assert_eq!(report.already_cached, 1);
+            assert_eq!(report.missing, vec!["cccc"]);
+            assert!(report.failed.is_empty());
+
+            match local.get("aaaa").await.unwrap() {
+                Cache::Hit(mut entry) => assert_eq!(entry.get_stdout(), b"aaaa"),
+                e => panic!("Unexpected cache result: {:?}", e),
+            }
+        });
+    }
+}
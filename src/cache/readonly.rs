@@ -15,8 +15,12 @@ use std::time::Duration;
 
 use async_trait::async_trait;
 
-use crate::cache::{Cache, CacheMode, CacheWrite, Storage};
+use crate::cache::{
+    Cache, CacheMode, CacheOccupancy, CacheWrite, EntryStream, EvictionState, Storage,
+    StorageCounts,
+};
 use crate::compiler::PreprocessorCacheEntry;
+use crate::config::EntryConfig;
 use crate::errors::*;
 
 use super::PreprocessorCacheModeConfig;
@@ -44,11 +48,38 @@ impl Storage for ReadOnlyStorage {
         Ok(CacheMode::ReadOnly)
     }
 
+    /// The ReadOnlyStorage cache is always read-only.
+    fn is_read_only(&self) -> bool {
+        true
+    }
+
+    /// Check that the underlying storage can be reached.
+    async fn probe(&self) -> Result<()> {
+        self.0.probe().await
+    }
+
     /// Get the storage location.
     fn location(&self) -> String {
         self.0.location()
     }
 
+    /// List the keys of all the entries in the cache.
+    async fn list(&self) -> Result<Vec<String>> {
+        self.0.list().await
+    }
+
+    /// List the entries in the cache.
+    async fn list_entries(&self, prefix: &str) -> Result<EntryStream> {
+        self.0.list_entries(prefix).await
+    }
+
+    /// Remove an entry from the cache.
+    ///
+    /// The ReadOnlyStorage cache can't be written to.
+    async fn delete(&self, _key: &str) -> Result<bool> {
+        Err(anyhow!("Cannot write to read-only storage"))
+    }
+
     /// Get the current storage usage, if applicable.
     async fn current_size(&self) -> Result<Option<u64>> {
         self.0.current_size().await
@@ -59,6 +90,29 @@ impl Storage for ReadOnlyStorage {
         self.0.max_size().await
     }
 
+    /// Get how many requests to the storage are running, if they are counted.
+    fn requests_in_flight(&self) -> Option<usize> {
+        self.0.requests_in_flight()
+    }
+
+    fn take_counts(&self) -> StorageCounts {
+        self.0.take_counts()
+    }
+
+    /// Get the state of the eviction, if the storage evicts in sweeps.
+    fn eviction_state(&self) -> Option<EvictionState> {
+        self.0.eviction_state()
+    }
+
+    fn occupancy(&self) -> Option<CacheOccupancy> {
+        self.0.occupancy()
+    }
+
+    /// Get how the entries put in the storage are written.
+    fn entry_config(&self) -> EntryConfig {
+        self.0.entry_config()
+    }
+
     /// Return the config for preprocessor cache mode if applicable
     fn preprocessor_cache_mode_config(&self) -> PreprocessorCacheModeConfig {
         self.0.preprocessor_cache_mode_config()
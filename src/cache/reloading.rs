@@ -0,0 +1,208 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A storage which can be replaced while the server runs, e.g. by one built
+//! with rotated credentials.
+
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use crate::cache::{
+    Cache, CacheMode, CacheOccupancy, CacheWrite, EntryStream, EvictionState, Storage,
+    StorageCounts,
+};
+use crate::compiler::PreprocessorCacheEntry;
+use crate::config::EntryConfig;
+use crate::errors::*;
+
+use super::PreprocessorCacheModeConfig;
+
+/// A storage passing the requests to the storage it currently holds. The
+/// requests already running when it is replaced complete on the previous
+/// one.
+pub struct ReloadingStorage {
+    storage: RwLock<Arc<dyn Storage>>,
+    /// What the previous storages counted, not taken yet.
+    previous_counts: Mutex<StorageCounts>,
+}
+
+impl ReloadingStorage {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        ReloadingStorage {
+            storage: RwLock::new(storage),
+            previous_counts: Mutex::default(),
+        }
+    }
+
+    /// Pass the next requests to `storage`.
+    pub fn replace(&self, storage: Arc<dyn Storage>) {
+        let previous = std::mem::replace(&mut *self.storage.write().unwrap(), storage);
+        let mut counts = self.previous_counts.lock().unwrap();
+        *counts = *counts + previous.take_counts();
+    }
+
+    fn current(&self) -> Arc<dyn Storage> {
+        self.storage.read().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl Storage for ReloadingStorage {
+    async fn get(&self, key: &str) -> Result<Cache> {
+        self.current().get(key).await
+    }
+
+    async fn put(&self, key: &str, entry: CacheWrite) -> Result<Duration> {
+        self.current().put(key, entry).await
+    }
+
+    async fn check(&self) -> Result<CacheMode> {
+        self.current().check().await
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.current().is_read_only()
+    }
+
+    async fn probe(&self) -> Result<()> {
+        self.current().probe().await
+    }
+
+    fn location(&self) -> String {
+        self.current().location()
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        self.current().list().await
+    }
+
+    async fn list_entries(&self, prefix: &str) -> Result<EntryStream> {
+        self.current().list_entries(prefix).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool> {
+        self.current().delete(key).await
+    }
+
+    async fn current_size(&self) -> Result<Option<u64>> {
+        self.current().current_size().await
+    }
+
+    async fn max_size(&self) -> Result<Option<u64>> {
+        self.current().max_size().await
+    }
+
+    fn requests_in_flight(&self) -> Option<usize> {
+        self.current().requests_in_flight()
+    }
+
+    fn take_counts(&self) -> StorageCounts {
+        let previous = std::mem::take(&mut *self.previous_counts.lock().unwrap());
+        previous + self.current().take_counts()
+    }
+
+    fn eviction_state(&self) -> Option<EvictionState> {
+        self.current().eviction_state()
+    }
+
+    fn occupancy(&self) -> Option<CacheOccupancy> {
+        self.current().occupancy()
+    }
+
+    fn entry_config(&self) -> EntryConfig {
+        self.current().entry_config()
+    }
+
+    fn preprocessor_cache_mode_config(&self) -> PreprocessorCacheModeConfig {
+        self.current().preprocessor_cache_mode_config()
+    }
+
+    async fn get_preprocessor_cache_entry(
+        &self,
+        key: &str,
+    ) -> Result<Option<Box<dyn crate::lru_disk_cache::ReadSeek>>> {
+        self.current().get_preprocessor_cache_entry(key).await
+    }
+
+    async fn put_preprocessor_cache_entry(
+        &self,
+        key: &str,
+        preprocessor_cache_entry: PreprocessorCacheEntry,
+    ) -> Result<()> {
+        self.current()
+            .put_preprocessor_cache_entry(key, preprocessor_cache_entry)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::memory::MemoryCache;
+    use crate::cache::CacheRead;
+    use crate::test::mock_storage::MockStorage;
+
+    #[test]
+    fn test_reloading_storage() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let first = Arc::new(MockStorage::new(None, false));
+        let second = Arc::new(MockStorage::new(None, false));
+        let storage = ReloadingStorage::new(first.clone());
+        first.next_get(Ok(Cache::Miss));
+        assert!(matches!(
+            runtime.block_on(storage.get("key")),
+            Ok(Cache::Miss)
+        ));
+        storage.replace(second.clone());
+        second.next_get(Ok(Cache::Miss));
+        assert!(matches!(
+            runtime.block_on(storage.get("key")),
+            Ok(Cache::Miss)
+        ));
+    }
+
+    #[test]
+    fn test_reloading_storage_counts() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        // Each storage serves its second lookup from memory.
+        let memory_cache = || {
+            let mock = Arc::new(MockStorage::new(None, false));
+            let mut entry = CacheWrite::new();
+            entry.put_stdout(b"stdout").unwrap();
+            let entry = CacheRead::from(std::io::Cursor::new(entry.finish().unwrap())).unwrap();
+            mock.next_get(Ok(Cache::Hit(entry)));
+            Arc::new(MemoryCache::new(mock, 1024 * 1024))
+        };
+        let storage = ReloadingStorage::new(memory_cache());
+        let lookups = || {
+            runtime.block_on(async {
+                for _ in 0..2 {
+                    assert!(matches!(storage.get("key").await, Ok(Cache::Hit(_))));
+                }
+            })
+        };
+        lookups();
+        // What the previous storage counted isn't lost.
+        storage.replace(memory_cache());
+        lookups();
+        assert_eq!(storage.take_counts().memory_hits, 2);
+        assert_eq!(storage.take_counts(), StorageCounts::default());
+    }
+}
@@ -0,0 +1,122 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The `request` options of the backends over HTTP, for the stores behind
+//! gateways and proxies, applied to their HTTP clients.
+//!
+//! The extra headers are added by the client as it sends a request, after
+//! S3 signed it, so they aren't signed. Signature V4 allows that for all the
+//! headers but `Host` and the `x-amz-*` ones, which the configuration
+//! rejects. The `Host` is set instead by sending the requests to the host
+//! `host`, so that they are signed with it, and pointing the client at the
+//! addresses of the endpoint for it.
+
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{ClientBuilder, Proxy, Url};
+
+use crate::config::HttpRequestConfig;
+use crate::errors::*;
+
+/// Apply `config` to `builder`, the client of the backend at `endpoint`.
+/// Returns the endpoint the requests are then sent to.
+pub fn configure(
+    mut builder: ClientBuilder,
+    config: &HttpRequestConfig,
+    endpoint: Option<&str>,
+) -> Result<(ClientBuilder, Option<String>)> {
+    if !config.extra_headers.is_empty() {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &config.extra_headers {
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes())?,
+                HeaderValue::from_str(value)?,
+            );
+        }
+        builder = builder.default_headers(headers);
+    }
+    if let Some(proxy) = &config.proxy {
+        builder = builder.proxy(Proxy::all(proxy).context("invalid proxy")?);
+    }
+    let Some(host) = &config.host else {
+        return Ok((builder, endpoint.map(ToOwned::to_owned)));
+    };
+    let endpoint = endpoint.context("setting the host requires an endpoint")?;
+    let mut url =
+        Url::parse(endpoint).with_context(|| format!("invalid endpoint {:?}", endpoint))?;
+    let addrs = url
+        .socket_addrs(|| None)
+        .with_context(|| format!("failed to resolve the endpoint {}", endpoint))?;
+    url.set_host(Some(host))
+        .with_context(|| format!("invalid host {:?}", host))?;
+    let endpoint = url.as_str().to_owned();
+    Ok((builder.resolve_to_addrs(host, &addrs), Some(endpoint)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_configure() {
+        let config = HttpRequestConfig {
+            extra_headers: [("X-Route".to_owned(), "eu".to_owned())].into(),
+            proxy: None,
+            host: Some("store.internal".to_owned()),
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/cache", listener.local_addr().unwrap());
+        let (builder, endpoint) =
+            configure(ClientBuilder::new(), &config, Some(&endpoint)).unwrap();
+        let endpoint = endpoint.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert_eq!(endpoint, format!("http://store.internal:{}/cache", port));
+
+        // The request goes to the endpoint, with the host and the headers.
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = vec![];
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\ncontent-length: 0\r\n\r\n")
+                .unwrap();
+            String::from_utf8(request).unwrap().to_ascii_lowercase()
+        });
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let client = builder.build().unwrap();
+        let response = runtime.block_on(client.get(&endpoint).send()).unwrap();
+        assert_eq!(response.status(), 204);
+        let request = server.join().unwrap();
+        assert!(
+            request.contains(&format!("host: store.internal:{}\r\n", port)),
+            "{}",
+            request
+        );
+        assert!(request.contains("x-route: eu\r\n"), "{}", request);
+
+        assert!(configure(ClientBuilder::new(), &config, None).is_err());
+        let config = HttpRequestConfig {
+            proxy: Some("http://proxy.internal:3128".to_owned()),
+            ..Default::default()
+        };
+        let (_, endpoint) = configure(ClientBuilder::new(), &config, Some("https://s3")).unwrap();
+        assert_eq!(endpoint.as_deref(), Some("https://s3"));
+    }
+}
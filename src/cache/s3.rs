@@ -10,17 +10,49 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::io;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use async_trait::async_trait;
+use futures::{StreamExt, TryStreamExt};
+use hmac::{Hmac, Mac};
 use opendal::layers::LoggingLayer;
 use opendal::raw::HttpClient;
 use opendal::services::S3;
-use opendal::Operator;
-use reqwest::ClientBuilder;
+use opendal::{ErrorKind, Operator};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqsign::{AwsConfig, AwsCredential, AwsCredentialLoad, AwsDefaultLoader};
+use reqwest::header::{
+    HeaderName, HeaderValue, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, DATE, ETAG, HOST,
+    IF_MATCH, RANGE,
+};
+use reqwest::{ClientBuilder, Method, StatusCode, Url};
+use serde::Deserialize;
+use sha2::digest::Output;
+use sha2::{Digest, Sha256};
 
+use crate::cache::metadata::EntryMetadata;
+use crate::cache::multipart::{multipart_download, Objects};
+use crate::cache::{
+    layout, request, Cache, CacheMode, CacheRead, CacheWrite, EntryInfo, EntryStream, Storage,
+    StorageCounts,
+};
+use crate::clock::{self, Clock, SharedClock, SkewedClock};
+use crate::config::HttpRequestConfig;
 use crate::errors::*;
+use crate::retry::{retry_async, send_with_retries, Backoff, RetryCount};
+
+/// How long before they expire the credentials are loaded again, more than the
+/// 2 minutes before which reqsign stops signing with them.
+const CREDENTIAL_REFRESH_MARGIN: Duration = Duration::from_secs(5 * 60);
 
 pub struct S3Cache;
 
 impl S3Cache {
+    #[allow(clippy::too_many_arguments)]
     pub fn build(
         bucket: &str,
         region: Option<&str>,
@@ -29,9 +61,43 @@ impl S3Cache {
         endpoint: Option<&str>,
         use_ssl: Option<bool>,
         server_side_encryption: Option<bool>,
-    ) -> Result<Operator> {
+        request: &HttpRequestConfig,
+    ) -> Result<S3Storage> {
+        Self::build_with_clock(
+            bucket,
+            region,
+            key_prefix,
+            no_credentials,
+            endpoint,
+            use_ssl,
+            server_side_encryption,
+            request,
+            clock::system(),
+        )
+    }
+
+    /// Like `build`, signing the requests with the time of `clock`, once
+    /// corrected by the one of S3.
+    #[allow(clippy::too_many_arguments)]
+    fn build_with_clock(
+        bucket: &str,
+        region: Option<&str>,
+        key_prefix: &str,
+        no_credentials: bool,
+        endpoint: Option<&str>,
+        use_ssl: Option<bool>,
+        server_side_encryption: Option<bool>,
+        request: &HttpRequestConfig,
+        clock: SharedClock,
+    ) -> Result<S3Storage> {
+        let clock = SkewedClock::new(clock, &format!("the S3 bucket {}", bucket));
+        let endpoint = endpoint
+            .map(|endpoint| endpoint_resolver(endpoint, use_ssl))
+            .transpose()?;
+        let (http_client, endpoint) = http_client(request, endpoint.as_deref())?;
+        let client = http_client.client();
         let mut builder = S3::default();
-        builder.http_client(set_user_agent());
+        builder.http_client(http_client);
         builder.bucket(bucket);
         builder.root(key_prefix);
 
@@ -39,7 +105,8 @@ impl S3Cache {
             builder.region(region);
         }
 
-        if no_credentials {
+        let mut signing_region = region.map(ToOwned::to_owned);
+        let credential = if no_credentials {
             builder.disable_config_load();
             // Disable EC2 metadata to avoid OpenDAL trying to load
             // credentials from EC2 metadata.
@@ -49,10 +116,24 @@ impl S3Cache {
             // Allow anonymous access to S3 so that OpenDAL will not
             // throw error when no credentials are provided.
             builder.allow_anonymous();
-        }
+            None
+        } else {
+            let mut config = AwsConfig::default().from_profile().from_env();
+            if let Some(region) = region {
+                config.region = Some(region.to_owned());
+            }
+            signing_region = config.region.clone();
+            let credential = SharedCredentialLoader::with_clock(
+                move |client| Box::new(AwsDefaultLoader::new(client, config.clone())),
+                clock.clone(),
+                Backoff::remote(),
+            );
+            builder.customed_credential_load(Box::new(credential.clone()));
+            Some(credential)
+        };
 
         if let Some(endpoint) = endpoint {
-            builder.endpoint(&endpoint_resolver(endpoint, use_ssl)?);
+            builder.endpoint(&endpoint);
         }
 
         if server_side_encryption.unwrap_or_default() {
@@ -62,15 +143,696 @@ impl S3Cache {
         let op = Operator::new(builder)?
             .layer(LoggingLayer::default())
             .finish();
-        Ok(op)
+        Ok(S3Storage {
+            operator: op,
+            // opendal doesn't build without a region.
+            region: signing_region.unwrap_or_default(),
+            credential,
+            client,
+            clock,
+            backoff: Backoff::fixed(Duration::ZERO, 0),
+            retries: RetryCount::default(),
+        })
+    }
+}
+
+/// How long the URLs presigned by opendal are valid, the requests being made
+/// from them right away.
+const PRESIGN_EXPIRY: Duration = Duration::from_secs(60 * 60);
+
+/// An S3 cache, making its requests itself instead of going through opendal,
+/// to sign all of them with the time of S3, measured from the `Date` of the
+/// first response, for S3 not to reject them when the clock of the machine
+/// is off: opendal signs with the time of the machine, which it can't be
+/// told otherwise. opendal still makes the URLs of the requests, and the
+/// headers of the writes, e.g. those of the encryption.
+///
+/// The entries are written with their metadata, as `x-amz-meta-*`, which S3
+/// requires to be signed.
+#[derive(Clone)]
+pub struct S3Storage {
+    operator: Operator,
+    region: String,
+    /// The credentials, unless the requests are anonymous.
+    credential: Option<SharedCredentialLoader>,
+    client: reqwest::Client,
+    clock: Arc<SkewedClock>,
+    backoff: Backoff,
+    retries: RetryCount,
+}
+
+/// A page of the objects of a bucket, as `ListObjectsV2` answers.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListBucketResult {
+    #[serde(default)]
+    is_truncated: bool,
+    next_continuation_token: Option<String>,
+    #[serde(default)]
+    contents: Vec<ListedObject>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListedObject {
+    key: String,
+    size: u64,
+    last_modified: Option<String>,
+}
+
+impl S3Storage {
+    /// Retry the requests failing temporarily as `backoff` says.
+    pub fn with_retries(self, backoff: Backoff) -> Self {
+        S3Storage { backoff, ..self }
+    }
+
+    /// Make the request `method`ing the object at `path`.
+    async fn request(&self, method: Method, path: &str) -> Result<reqwest::Request> {
+        let presigned = self.operator.presign_read(path, PRESIGN_EXPIRY).await?;
+        let mut url = Url::parse(&presigned.uri().to_string())?;
+        url.set_query(None);
+        Ok(reqwest::Request::new(method, url))
+    }
+
+    /// Make the request writing the object at `path`, without its body.
+    async fn put_request(&self, path: &str, content_type: &str) -> Result<reqwest::Request> {
+        let presigned = self
+            .operator
+            .presign_write_with(path, PRESIGN_EXPIRY)
+            .content_type(content_type)
+            .await?;
+        let mut url = Url::parse(&presigned.uri().to_string())?;
+        url.set_query(None);
+        let mut request = reqwest::Request::new(Method::PUT, url);
+        for (name, value) in presigned.header() {
+            if name != HOST {
+                request.headers_mut().insert(name, value.clone());
+            }
+        }
+        request
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_str(content_type)?);
+        Ok(request)
+    }
+
+    async fn sign(&self, request: &mut reqwest::Request) -> Result<()> {
+        let Some(loader) = &self.credential else {
+            return Ok(());
+        };
+        let credential = loader
+            .load_credential(self.client.clone())
+            .await?
+            .context("no valid S3 credentials found")?;
+        sign_v4(
+            request,
+            &credential,
+            &self.region,
+            self.clock.system_now().into(),
+        )?;
+        // The client sets it, as opendal does.
+        request.headers_mut().remove(HOST);
+        Ok(())
+    }
+
+    /// Take note of `response`, returning whether to sign the request again
+    /// and send it once more, with the clock corrected.
+    fn received(&self, response: &reqwest::Response) -> bool {
+        // The clock is measured again when a request is rejected, e.g. with
+        // `RequestTimeTooSkewed`, which may be for the time it was signed at.
+        let rejected = response.status() == StatusCode::FORBIDDEN;
+        if self.clock.measured() && !rejected {
+            return false;
+        }
+        let Some(date) = response
+            .headers()
+            .get(DATE)
+            .and_then(|date| date.to_str().ok())
+            .and_then(|date| chrono::DateTime::parse_from_rfc2822(date).ok())
+        else {
+            return false;
+        };
+        self.clock.observe(date.into()) && rejected
+    }
+
+    /// Sign `request` and send it, retrying it as `backoff` says, and once
+    /// more when it was rejected for the time it was signed at.
+    async fn send(&self, request: reqwest::Request) -> Result<reqwest::Response> {
+        let send = || async {
+            let mut request = request
+                .try_clone()
+                .context("the request can't be sent again")?;
+            self.sign(&mut request).await?;
+            self.client
+                .execute(request)
+                .await
+                .context("failed to reach the cache")
+        };
+        let clock: SharedClock = self.clock.clone();
+        send_with_retries(&self.backoff, &clock, &self.retries, || async {
+            let response = send().await?;
+            if self.received(&response) {
+                return send().await;
+            }
+            Ok(response)
+        })
+        .await
+    }
+
+    /// Send `request`, failing with the error opendal would for its status.
+    async fn fetch(&self, request: Result<reqwest::Request>) -> opendal::Result<reqwest::Response> {
+        let response = async { self.send(request?).await }.await.map_err(|e| {
+            opendal::Error::new(ErrorKind::Unexpected, "failed to send the request").set_source(e)
+        })?;
+        if response.status().is_success() {
+            return Ok(response);
+        }
+        let status = response.status();
+        let kind = match status {
+            StatusCode::NOT_FOUND => ErrorKind::NotFound,
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => ErrorKind::PermissionDenied,
+            StatusCode::PRECONDITION_FAILED => ErrorKind::ConditionNotMatch,
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => {
+                ErrorKind::RateLimited
+            }
+            _ => ErrorKind::Unexpected,
+        };
+        let body = response.text().await.unwrap_or_default();
+        Err(opendal::Error::new(
+            kind,
+            &format!("the cache answered with {}: {}", status, body.trim()),
+        ))
+    }
+
+    /// Write `body` to the object at `path`, with `metadata` if any.
+    async fn write(
+        &self,
+        path: &str,
+        content_type: &str,
+        body: Vec<u8>,
+        metadata: Option<&EntryMetadata>,
+    ) -> Result<()> {
+        let mut request = self.put_request(path, content_type).await?;
+        for (name, value) in metadata.iter().flat_map(|metadata| metadata.pairs()) {
+            request.headers_mut().insert(
+                HeaderName::try_from(format!("x-amz-meta-{}", name))?,
+                HeaderValue::try_from(value)?,
+            );
+        }
+        *request.body_mut() = Some(body.into());
+        self.fetch(Ok(request)).await?;
+        Ok(())
+    }
+
+    /// List a page of the objects whose keys start with `prefix`, at `bucket`,
+    /// from `token`.
+    async fn list_page(
+        &self,
+        mut bucket: Url,
+        prefix: &str,
+        token: Option<String>,
+    ) -> Result<ListBucketResult> {
+        bucket
+            .query_pairs_mut()
+            .append_pair("list-type", "2")
+            .append_pair("prefix", prefix);
+        if let Some(token) = &token {
+            bucket
+                .query_pairs_mut()
+                .append_pair("continuation-token", token);
+        }
+        let request = reqwest::Request::new(Method::GET, bucket);
+        let response = self.fetch(Ok(request)).await?;
+        let page = quick_xml::de::from_str(&response.text().await?)
+            .context("failed to parse the objects of the bucket")?;
+        Ok(page)
+    }
+}
+
+#[async_trait]
+impl Objects for S3Storage {
+    fn reads_ranges(&self) -> bool {
+        true
+    }
+
+    async fn read(&self, path: &str) -> opendal::Result<Vec<u8>> {
+        let response = self.fetch(self.request(Method::GET, path).await).await?;
+        let body = response.bytes().await.map_err(|e| {
+            opendal::Error::new(ErrorKind::Unexpected, "failed to read the object").set_source(e)
+        })?;
+        Ok(body.to_vec())
+    }
+
+    async fn stat(&self, path: &str) -> opendal::Result<(u64, Option<String>)> {
+        let response = self.fetch(self.request(Method::HEAD, path).await).await?;
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+        };
+        let size = header(CONTENT_LENGTH)
+            .and_then(|length| length.parse().ok())
+            .ok_or_else(|| opendal::Error::new(ErrorKind::Unexpected, "the object has no size"))?;
+        Ok((size, header(ETAG).map(ToOwned::to_owned)))
+    }
+
+    async fn read_range(
+        &self,
+        path: &str,
+        range: Range<u64>,
+        etag: Option<&str>,
+    ) -> opendal::Result<Vec<u8>> {
+        let request = async {
+            let mut request = self.request(Method::GET, path).await?;
+            let headers = request.headers_mut();
+            headers.insert(
+                RANGE,
+                HeaderValue::from_str(&format!("bytes={}-{}", range.start, range.end - 1))?,
+            );
+            if let Some(etag) = etag {
+                headers.insert(IF_MATCH, HeaderValue::from_str(etag)?);
+            }
+            Ok(request)
+        };
+        let response = self.fetch(request.await).await?;
+        let body = response.bytes().await.map_err(|e| {
+            opendal::Error::new(ErrorKind::Unexpected, "failed to read the object").set_source(e)
+        })?;
+        Ok(body.to_vec())
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn get(&self, key: &str) -> Result<Cache> {
+        let download = multipart_download();
+        for layout in layout::remote() {
+            match download.read(self, &layout.path(key)).await {
+                Ok(res) => {
+                    let hit = CacheRead::from(io::Cursor::new(res))?;
+                    return Ok(Cache::Hit(hit));
+                }
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => {
+                    warn!("Got unexpected error: {:?}", e);
+                    return Ok(Cache::Miss);
+                }
+            }
+        }
+        Ok(Cache::Miss)
+    }
+
+    async fn put(&self, key: &str, entry: CacheWrite) -> Result<Duration> {
+        let start = Instant::now();
+        let content_type = entry.format().content_type();
+        let metadata = entry.metadata().cloned();
+        let body = entry.finish()?;
+        let path = layout::remote()[0].path(key);
+        self.write(&path, &content_type, body, metadata.as_ref())
+            .await?;
+        Ok(start.elapsed())
+    }
+
+    async fn check(&self) -> Result<CacheMode> {
+        let path = ".sccache_check";
+
+        // Read is required, as for the other remote caches.
+        match Objects::read(self, path).await {
+            Ok(_) => (),
+            Err(err) if err.kind() == ErrorKind::NotFound => (),
+            Err(err) if err.kind() == ErrorKind::RateLimited => {
+                eprintln!("cache storage read check: {err:?}, but we decide to keep running")
+            }
+            Err(err) => bail!("cache storage failed to read: {:?}", err),
+        };
+
+        let mode = match self
+            .write(path, "text/plain", b"Hello, World!".to_vec(), None)
+            .await
+        {
+            Ok(()) => CacheMode::ReadWrite,
+            // Tolerate all write errors because we can do read at least.
+            Err(err) => {
+                eprintln!("storage write check failed: {err:?}");
+                CacheMode::ReadOnly
+            }
+        };
+        debug!("storage check result: {mode:?}");
+        Ok(mode)
+    }
+
+    async fn probe(&self) -> Result<()> {
+        // Unlike `get`, don't take errors for misses.
+        match Objects::read(self, ".sccache_check").await {
+            Ok(_) => Ok(()),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            Err(err) => {
+                let hint = match err.kind() {
+                    ErrorKind::PermissionDenied => {
+                        "check the credentials and the permissions they grant"
+                    }
+                    ErrorKind::RateLimited => "the cache is rate limiting requests, try later",
+                    _ => "check that the cache is reachable from this machine",
+                };
+                bail!("failed to read from the cache, {}: {}", hint, err)
+            }
+        }
+    }
+
+    fn location(&self) -> String {
+        Storage::location(&self.operator)
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        let mut keys: Vec<_> = self
+            .list_entries("")
+            .await?
+            .map_ok(|entry| entry.key)
+            .try_collect()
+            .await?;
+        // An entry may be in several layouts.
+        keys.sort_unstable();
+        keys.dedup();
+        Ok(keys)
+    }
+
+    async fn list_entries(&self, prefix: &str) -> Result<EntryStream> {
+        // The keys of the objects in the bucket start with the root of the
+        // cache, which the URL of the bucket is the one of, without it.
+        let root = self
+            .operator
+            .info()
+            .root()
+            .trim_start_matches('/')
+            .to_owned();
+        let mut bucket = self.request(Method::GET, "/").await?.url().clone();
+        let path = percent_decode_str(bucket.path())
+            .decode_utf8()?
+            .into_owned();
+        let path = path
+            .strip_suffix(&root)
+            .with_context(|| format!("the URL of the bucket is unexpected: {}", bucket))?;
+        bucket.set_path(path);
+
+        let (this, keys) = (self.clone(), root.clone());
+        let pages = futures::stream::try_unfold(Some(None), move |token| {
+            let (this, bucket, keys) = (this.clone(), bucket.clone(), keys.clone());
+            async move {
+                let Some(token) = token else {
+                    return Ok::<_, Error>(None);
+                };
+                let page = this.list_page(bucket, &keys, token).await?;
+                let next = page
+                    .next_continuation_token
+                    .filter(|_| page.is_truncated)
+                    .map(Some);
+                Ok(Some((
+                    futures::stream::iter(page.contents).map(Ok::<_, Error>),
+                    next,
+                )))
+            }
+        });
+        let prefix = prefix.to_owned();
+        let entries = pages
+            .try_flatten()
+            .try_filter_map(move |object: ListedObject| {
+                let info = object
+                    .key
+                    .strip_prefix(&root)
+                    .and_then(|path| {
+                        layout::remote()
+                            .iter()
+                            .find_map(|layout| layout.key_of(path))
+                    })
+                    .filter(|key| key.starts_with(&prefix))
+                    .map(|key| EntryInfo {
+                        key: key.to_owned(),
+                        size: object.size,
+                        modified: object
+                            .last_modified
+                            .as_deref()
+                            .and_then(|date| chrono::DateTime::parse_from_rfc3339(date).ok())
+                            .map(SystemTime::from),
+                        // Only in the metadata of the object, which listing skips.
+                        build_id: None,
+                    });
+                futures::future::ready(Ok(info))
+            });
+        Ok(entries.boxed())
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool> {
+        let mut found = false;
+        for layout in layout::remote() {
+            let path = layout.path(key);
+            match Objects::stat(self, &path).await {
+                Ok(_) => found = true,
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            }
+            self.fetch(self.request(Method::DELETE, &path).await)
+                .await?;
+        }
+        Ok(found)
+    }
+
+    async fn current_size(&self) -> Result<Option<u64>> {
+        Ok(None)
+    }
+
+    async fn max_size(&self) -> Result<Option<u64>> {
+        Ok(None)
+    }
+
+    fn take_counts(&self) -> StorageCounts {
+        StorageCounts {
+            retries: self.retries.take(),
+            ..Default::default()
+        }
+    }
+}
+
+type NewCredentialLoader = dyn Fn(reqwest::Client) -> Box<dyn AwsCredentialLoad> + Send + Sync;
+
+/// The credentials of an S3 cache, shared by all its requests instead of being
+/// loaded for each of them, e.g. by assuming a role. They are loaded again
+/// shortly before they expire, in the background while the requests keep
+/// signing with them, and only the requests finding no valid credentials wait
+/// for them, all on the same loading.
+#[derive(Clone)]
+pub struct SharedCredentialLoader {
+    inner: Arc<SharedCredential>,
+}
+
+struct SharedCredential {
+    /// A loader is made for each loading, so that its own caching doesn't
+    /// return the credentials being replaced.
+    new_loader: Box<NewCredentialLoader>,
+    credential: RwLock<Option<AwsCredential>>,
+    loading: tokio::sync::Mutex<()>,
+    refreshing: AtomicBool,
+    clock: SharedClock,
+    /// How to retry a loading failing, e.g. with the instance metadata
+    /// service busy.
+    backoff: Backoff,
+}
+
+impl SharedCredentialLoader {
+    pub fn new(
+        new_loader: impl Fn(reqwest::Client) -> Box<dyn AwsCredentialLoad> + Send + Sync + 'static,
+    ) -> Self {
+        Self::with_clock(new_loader, clock::system(), Backoff::remote())
+    }
+
+    /// Like `new`, telling whether the credentials expire soon with `clock`,
+    /// and retrying the loadings as `backoff` says.
+    pub fn with_clock(
+        new_loader: impl Fn(reqwest::Client) -> Box<dyn AwsCredentialLoad> + Send + Sync + 'static,
+        clock: SharedClock,
+        backoff: Backoff,
+    ) -> Self {
+        SharedCredentialLoader {
+            inner: Arc::new(SharedCredential {
+                new_loader: Box::new(new_loader),
+                credential: RwLock::new(None),
+                loading: tokio::sync::Mutex::new(()),
+                refreshing: AtomicBool::new(false),
+                clock,
+                backoff,
+            }),
+        }
+    }
+
+    fn expires_soon(&self, credential: &AwsCredential) -> bool {
+        let now = chrono::DateTime::<chrono::Utc>::from(self.inner.clock.system_now());
+        credential.expires_in.is_some_and(|expiry| {
+            expiry - chrono::Duration::from_std(CREDENTIAL_REFRESH_MARGIN).unwrap() <= now
+        })
+    }
+
+    fn current(&self) -> Option<AwsCredential> {
+        self.inner.credential.read().unwrap().clone()
+    }
+
+    /// Load the credentials, unless they were loaded while waiting for the
+    /// previous loading, and are valid, or don't expire soon if `refresh`.
+    async fn load(&self, client: reqwest::Client, refresh: bool) -> Result<Option<AwsCredential>> {
+        let _loading = self.inner.loading.lock().await;
+        if let Some(credential) = self.current().filter(|c| {
+            if refresh {
+                !self.expires_soon(c)
+            } else {
+                c.is_valid()
+            }
+        }) {
+            return Ok(Some(credential));
+        }
+        let start = Instant::now();
+        let credential = retry_async(&self.inner.backoff, &self.inner.clock, || {
+            let loader = (self.inner.new_loader)(client.clone());
+            let client = client.clone();
+            async move { loader.load_credential(client).await }
+        })
+        .await?;
+        debug!("Loaded the S3 credentials in {:?}", start.elapsed());
+        *self.inner.credential.write().unwrap() = credential.clone();
+        Ok(credential)
     }
 }
 
-/// Set the user agent (helps with monitoring on the server side)
-fn set_user_agent() -> HttpClient {
+#[async_trait]
+impl AwsCredentialLoad for SharedCredentialLoader {
+    async fn load_credential(&self, client: reqwest::Client) -> Result<Option<AwsCredential>> {
+        match self.current() {
+            Some(credential) if !self.expires_soon(&credential) => Ok(Some(credential)),
+            Some(credential) if credential.is_valid() => {
+                if !self.inner.refreshing.swap(true, Ordering::AcqRel) {
+                    let this = self.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = this.load(client, true).await {
+                            warn!("Failed to refresh the S3 credentials: {:#}", e);
+                        }
+                        this.inner.refreshing.store(false, Ordering::Release);
+                    });
+                }
+                Ok(Some(credential))
+            }
+            _ => self.load(client, false).await,
+        }
+    }
+}
+
+/// The characters of the paths which aren't percent-encoded when signed.
+const URI_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// The characters of the queries which aren't percent-encoded when signed.
+const QUERY_ENCODE_SET: &AsciiSet = &URI_ENCODE_SET.add(b'/');
+
+/// Sign `request` with the AWS Signature Version 4 in its headers, at
+/// `time`, as `AwsV4Signer` does at the time of the machine, which it can't
+/// be told otherwise.
+fn sign_v4(
+    request: &mut reqwest::Request,
+    credential: &AwsCredential,
+    region: &str,
+    time: chrono::DateTime<chrono::Utc>,
+) -> Result<()> {
+    let (method, url) = (request.method().clone(), request.url().clone());
+    let host = match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+        None => url.host_str().unwrap_or_default().to_owned(),
+    };
+    let timestamp = time.format("%Y%m%dT%H%M%SZ").to_string();
+    let headers = request.headers_mut();
+    headers.insert(HOST, HeaderValue::from_str(&host)?);
+    headers.insert("x-amz-date", HeaderValue::from_str(&timestamp)?);
+    headers.insert(
+        "x-amz-content-sha256",
+        HeaderValue::from_static("UNSIGNED-PAYLOAD"),
+    );
+    if let Some(token) = &credential.session_token {
+        let mut token = HeaderValue::from_str(token)?;
+        token.set_sensitive(true);
+        headers.insert("x-amz-security-token", token);
+    }
+
+    let headers = request.headers();
+    let mut names: Vec<_> = headers.keys().map(|name| name.as_str()).collect();
+    names.sort_unstable();
+    let signed_headers = names.join(";");
+    let path = percent_decode_str(url.path()).decode_utf8()?;
+    let mut query: Vec<_> = url
+        .query_pairs()
+        .map(|(name, value)| {
+            format!(
+                "{}={}",
+                utf8_percent_encode(&name, QUERY_ENCODE_SET),
+                utf8_percent_encode(&value, QUERY_ENCODE_SET)
+            )
+        })
+        .collect();
+    query.sort_unstable();
+    let mut canonical_request = format!(
+        "{}\n{}\n{}\n",
+        method,
+        utf8_percent_encode(&path, URI_ENCODE_SET),
+        query.join("&")
+    );
+    for name in &names {
+        let value = headers[*name].to_str()?.trim_matches(' ');
+        canonical_request.push_str(&format!("{}:{}\n", name, value));
+    }
+    canonical_request.push_str(&format!("\n{}\nUNSIGNED-PAYLOAD", signed_headers));
+
+    let date = time.format("%Y%m%d").to_string();
+    let scope = format!("{}/{}/s3/aws4_request", date, region);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{:x}",
+        timestamp,
+        scope,
+        Sha256::digest(canonical_request.as_bytes())
+    );
+    let key = [region, "s3", "aws4_request"].iter().fold(
+        hmac_sha256(
+            format!("AWS4{}", credential.secret_access_key).as_bytes(),
+            date.as_bytes(),
+        ),
+        |key, part| hmac_sha256(&key, part.as_bytes()),
+    );
+    let mut authorization = HeaderValue::from_str(&format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={:x}",
+        credential.access_key_id,
+        scope,
+        signed_headers,
+        hmac_sha256(&key, string_to_sign.as_bytes())
+    ))?;
+    authorization.set_sensitive(true);
+    request.headers_mut().insert(AUTHORIZATION, authorization);
+    Ok(())
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Output<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes()
+}
+
+/// Make the client of the bucket at `endpoint`, with the user agent (helps
+/// with monitoring on the server side) and the `request` options. Returns the
+/// endpoint the requests are then sent to.
+fn http_client(
+    request: &HttpRequestConfig,
+    endpoint: Option<&str>,
+) -> Result<(HttpClient, Option<String>)> {
     let user_agent = format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
     let client_builder = ClientBuilder::new().user_agent(user_agent);
-    HttpClient::build(client_builder).unwrap()
+    let (client_builder, endpoint) = request::configure(client_builder, request, endpoint)?;
+    Ok((HttpClient::build(client_builder)?, endpoint))
 }
 
 /// Resolve given endpoint along with use_ssl settings.
@@ -103,6 +865,415 @@ fn endpoint_resolver(endpoint: &str, use_ssl: Option<bool>) -> Result<String> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::cache::multipart::MultipartDownload;
+    use crate::clock::FakeClock;
+    use crate::test::mock_bucket::MockBucket;
+    use serial_test::serial;
+    use std::env;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::UNIX_EPOCH;
+    use tokio::sync::Notify;
+
+    /// Loads credentials expiring after `expiry` by `clock`, once `ready` is
+    /// notified.
+    struct CountingLoader {
+        loads: Arc<AtomicUsize>,
+        expiry: chrono::Duration,
+        ready: Arc<Notify>,
+        clock: SharedClock,
+    }
+
+    #[async_trait]
+    impl AwsCredentialLoad for CountingLoader {
+        async fn load_credential(&self, _: reqwest::Client) -> Result<Option<AwsCredential>> {
+            self.ready.notified().await;
+            let n = self.loads.fetch_add(1, Ordering::SeqCst);
+            Ok(Some(AwsCredential {
+                access_key_id: format!("key{}", n),
+                secret_access_key: "secret".to_owned(),
+                session_token: None,
+                expires_in: Some(chrono::DateTime::from(self.clock.system_now()) + self.expiry),
+            }))
+        }
+    }
+
+    #[test]
+    fn test_shared_credential_loader() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()
+            .unwrap();
+        let loads = Arc::new(AtomicUsize::new(0));
+        let ready = Arc::new(Notify::new());
+        // The first credentials are about to expire, the next ones aren't.
+        let loader = {
+            let loads = loads.clone();
+            let ready = ready.clone();
+            SharedCredentialLoader::new(move |_| {
+                let first = loads.load(Ordering::SeqCst) == 0;
+                Box::new(CountingLoader {
+                    loads: loads.clone(),
+                    expiry: chrono::Duration::minutes(if first { 3 } else { 60 }),
+                    ready: ready.clone(),
+                    clock: clock::system(),
+                })
+            })
+        };
+        let client = reqwest::Client::new();
+        runtime.block_on(async {
+            // All the requests wait for the same loading.
+            let requests: Vec<_> = (0..32)
+                .map(|_| {
+                    let loader = loader.clone();
+                    let client = client.clone();
+                    tokio::spawn(async move { loader.load_credential(client).await })
+                })
+                .collect();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            ready.notify_one();
+            for request in requests {
+                let credential = request.await.unwrap().unwrap().unwrap();
+                assert_eq!(credential.access_key_id, "key0");
+            }
+            assert_eq!(loads.load(Ordering::SeqCst), 1);
+
+            // The credentials are refreshed in the background, the requests
+            // not waiting for it.
+            for _ in 0..8 {
+                let credential = loader.load_credential(client.clone()).await.unwrap();
+                assert_eq!(credential.unwrap().access_key_id, "key0");
+            }
+            ready.notify_one();
+            while loader.inner.refreshing.load(Ordering::Acquire) {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            let credential = loader.load_credential(client.clone()).await.unwrap();
+            assert_eq!(credential.unwrap().access_key_id, "key1");
+            assert_eq!(loads.load(Ordering::SeqCst), 2);
+        });
+    }
+
+    #[test]
+    fn test_shared_credential_refresh_time() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()
+            .unwrap();
+        let clock = FakeClock::new();
+        let loads = Arc::new(AtomicUsize::new(0));
+        let ready = Arc::new(Notify::new());
+        let loader = {
+            let (loads, ready, loader_clock) = (loads.clone(), ready.clone(), clock.clone());
+            SharedCredentialLoader::with_clock(
+                move |_| {
+                    Box::new(CountingLoader {
+                        loads: loads.clone(),
+                        expiry: chrono::Duration::minutes(60),
+                        ready: ready.clone(),
+                        clock: loader_clock.clone(),
+                    })
+                },
+                clock.clone(),
+                Backoff::remote(),
+            )
+        };
+        let client = reqwest::Client::new();
+        let key =
+            |credential: Result<Option<AwsCredential>>| credential.unwrap().unwrap().access_key_id;
+        runtime.block_on(async {
+            ready.notify_one();
+            assert_eq!(key(loader.load_credential(client.clone()).await), "key0");
+
+            // Up to the refresh margin, the credentials are kept.
+            clock.advance(Duration::from_secs(54 * 60));
+            assert_eq!(key(loader.load_credential(client.clone()).await), "key0");
+            assert!(!loader.inner.refreshing.load(Ordering::Acquire));
+            assert_eq!(loads.load(Ordering::SeqCst), 1);
+
+            // Past it, they are refreshed.
+            clock.advance(Duration::from_secs(2 * 60));
+            ready.notify_one();
+            assert_eq!(key(loader.load_credential(client.clone()).await), "key0");
+            while loader.inner.refreshing.load(Ordering::Acquire) {
+                tokio::task::yield_now().await;
+            }
+            assert_eq!(key(loader.load_credential(client.clone()).await), "key1");
+            assert_eq!(loads.load(Ordering::SeqCst), 2);
+        });
+    }
+
+    /// Fails to load credentials `failures` times, then loads some.
+    struct FlakyLoader {
+        failures: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl AwsCredentialLoad for FlakyLoader {
+        async fn load_credential(&self, _: reqwest::Client) -> Result<Option<AwsCredential>> {
+            if self
+                .failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok()
+            {
+                bail!("the instance metadata service is busy");
+            }
+            Ok(Some(AwsCredential {
+                access_key_id: "key".to_owned(),
+                secret_access_key: "secret".to_owned(),
+                session_token: None,
+                expires_in: None,
+            }))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shared_credential_retry() {
+        let clock = FakeClock::new();
+        let failures = Arc::new(AtomicUsize::new(2));
+        let loader = {
+            let failures = failures.clone();
+            SharedCredentialLoader::with_clock(
+                move |_| {
+                    Box::new(FlakyLoader {
+                        failures: failures.clone(),
+                    })
+                },
+                clock.clone(),
+                Backoff::fixed(Duration::from_secs(1), 2),
+            )
+        };
+        let load = {
+            let loader = loader.clone();
+            tokio::spawn(async move { loader.load_credential(reqwest::Client::new()).await })
+        };
+        for n in 1..=2 {
+            while clock.sleeps().len() < n {
+                tokio::task::yield_now().await;
+            }
+            clock.advance(Duration::from_secs(1));
+        }
+        let credential = load.await.unwrap().unwrap().unwrap();
+        assert_eq!(credential.access_key_id, "key");
+        assert_eq!(failures.load(Ordering::SeqCst), 0);
+
+        // Past the retries, the loading fails.
+        failures.store(3, Ordering::SeqCst);
+        *loader.inner.credential.write().unwrap() = None;
+        let load =
+            tokio::spawn(async move { loader.load_credential(reqwest::Client::new()).await });
+        for n in 3..=4 {
+            while clock.sleeps().len() < n {
+                tokio::task::yield_now().await;
+            }
+            clock.advance(Duration::from_secs(1));
+        }
+        assert!(load.await.unwrap().is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn test_put_metadata() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        env::set_var("AWS_ACCESS_KEY_ID", "AKIDEXAMPLE");
+        env::set_var("AWS_SECRET_ACCESS_KEY", "secret");
+        runtime.block_on(async {
+            let bucket = MockBucket::start().await;
+            let storage = S3Cache::build(
+                "bucket",
+                Some("us-east-1"),
+                "prefix",
+                false,
+                Some(&bucket.endpoint),
+                None,
+                Some(true),
+                &HttpRequestConfig::default(),
+            )
+            .unwrap();
+
+            let mut entry = CacheWrite::new();
+            entry.put_stdout(b"hello").unwrap();
+            entry.set_metadata(EntryMetadata {
+                compiler: "gcc",
+                compiler_version: Some("gcc 13.2.0".to_owned()),
+                key_version: "11".to_owned(),
+                created: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+                build_id: Some("1c26a3a".to_owned()),
+            });
+            storage.put("abcdef", entry).await.unwrap();
+            let put = bucket.requests().remove(0);
+            assert_eq!(put.method, "PUT");
+            assert_eq!(put.path, "/bucket/prefix/a/b/c/abcdef");
+            for (name, value) in [
+                ("x-amz-meta-created", "2023-11-14T22:13:20Z"),
+                ("x-amz-meta-compiler", "gcc"),
+                ("x-amz-meta-compiler-version", "gcc 13.2.0"),
+                ("x-amz-meta-sccache-version", env!("CARGO_PKG_VERSION")),
+                ("x-amz-meta-key-version", "11"),
+                ("x-amz-meta-build-id", "1c26a3a"),
+                ("x-amz-server-side-encryption", "AES256"),
+            ] {
+                assert_eq!(put.headers.get(name).map(String::as_str), Some(value));
+                // S3 rejects the `x-amz-*` headers which aren't signed.
+                let authorization = &put.headers["authorization"];
+                assert!(authorization.contains(name), "{}", authorization);
+            }
+            assert!(put.headers.contains_key("content-type"));
+
+            let Cache::Hit(mut hit) = storage.get("abcdef").await.unwrap() else {
+                panic!("the entry isn't found");
+            };
+            assert_eq!(hit.get_stdout(), b"hello");
+            let get = bucket.requests().remove(0);
+            assert_eq!(get.method, "GET");
+            assert!(get.headers.contains_key("authorization"));
+        });
+        env::remove_var("AWS_ACCESS_KEY_ID");
+        env::remove_var("AWS_SECRET_ACCESS_KEY");
+    }
+
+    #[test]
+    fn test_sign_v4() {
+        let credential = AwsCredential {
+            access_key_id: "AKIDEXAMPLE".to_owned(),
+            secret_access_key: "secret".to_owned(),
+            session_token: Some("token".to_owned()),
+            expires_in: None,
+        };
+        let request = |url| {
+            let url = Url::parse(url).unwrap();
+            let mut request = reqwest::Request::new(Method::PUT, url);
+            for (name, value) in [
+                ("content-type", "application/octet-stream"),
+                ("x-amz-meta-compiler-version", "gcc 13.2.0"),
+            ] {
+                request
+                    .headers_mut()
+                    .insert(name, HeaderValue::from_static(value));
+            }
+            request
+        };
+        for url in [
+            "http://127.0.0.1:9000/bucket/prefix/a/b/c/abc%2Bdef",
+            "http://127.0.0.1:9000/bucket/?list-type=2&prefix=prefix%2F&continuation-token=a%2Bb",
+        ] {
+            // Signed as reqsign does, at the time it signs.
+            let mut expected = request(url);
+            reqsign::AwsV4Signer::new("s3", "eu-west-1")
+                .sign(&mut expected, &credential)
+                .unwrap();
+            let time = chrono::NaiveDateTime::parse_from_str(
+                expected.headers()["x-amz-date"].to_str().unwrap(),
+                "%Y%m%dT%H%M%SZ",
+            )
+            .unwrap()
+            .and_utc();
+            let mut signed = request(url);
+            sign_v4(&mut signed, &credential, "eu-west-1", time).unwrap();
+            assert_eq!(signed.headers(), expected.headers(), "{}", url);
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_skewed_clock() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        env::set_var("AWS_ACCESS_KEY_ID", "AKIDEXAMPLE");
+        env::set_var("AWS_SECRET_ACCESS_KEY", "secret");
+        // The clock of the machine is an hour ahead of the one of S3.
+        let clock = FakeClock::new();
+        clock.advance(Duration::from_secs(60 * 60));
+        runtime.block_on(async {
+            let bucket = MockBucket::start().await;
+            let storage = S3Cache::build_with_clock(
+                "bucket",
+                Some("us-east-1"),
+                "prefix",
+                false,
+                Some(&bucket.endpoint),
+                None,
+                None,
+                &HttpRequestConfig::default(),
+                clock.clone(),
+            )
+            .unwrap();
+            let entry = || {
+                let mut entry = CacheWrite::new();
+                entry.put_stdout(b"hello").unwrap();
+                entry.set_metadata(EntryMetadata {
+                    compiler: "gcc",
+                    compiler_version: None,
+                    key_version: "11".to_owned(),
+                    created: UNIX_EPOCH,
+                    build_id: None,
+                });
+                entry
+            };
+
+            let methods = || -> Vec<_> {
+                bucket
+                    .requests()
+                    .into_iter()
+                    .map(|request| request.method)
+                    .collect()
+            };
+
+            // The first request is rejected, and sent again with the time of
+            // S3.
+            bucket.insert("/bucket/prefix/a/b/c/abcdef", &entry().finish().unwrap());
+            assert!(matches!(
+                storage.get("abcdef").await.unwrap(),
+                Cache::Hit(_)
+            ));
+            assert_eq!(methods(), ["GET", "GET"]);
+
+            // All the next ones are signed with it right away.
+            storage.put("bcdef0", entry()).await.unwrap();
+            assert_eq!(methods(), ["PUT"]);
+            assert_eq!(storage.list().await.unwrap(), ["abcdef", "bcdef0"]);
+            assert_eq!(methods(), ["GET"]);
+            let entries: Vec<_> = storage
+                .list_entries("bc")
+                .await
+                .unwrap()
+                .try_collect()
+                .await
+                .unwrap();
+            assert_eq!(entries.len(), 1);
+            assert_eq!(entries[0].key, "bcdef0");
+            assert!(entries[0].modified.is_some());
+            bucket.requests();
+
+            // So are those reading the large entries in parts.
+            let download = MultipartDownload {
+                threshold: Some(1),
+                part_size: 64,
+                concurrency: 2,
+            };
+            let path = layout::remote()[0].path("abcdef");
+            let read = download.read(&storage, &path).await.unwrap();
+            assert_eq!(read, entry().finish().unwrap());
+            let requests = bucket.requests();
+            assert_eq!(requests[0].method, "HEAD");
+            assert!(requests[1..]
+                .iter()
+                .all(|r| r.headers.contains_key("range") && r.headers.contains_key("if-match")));
+            assert!(storage.delete("abcdef").await.unwrap());
+            assert_eq!(methods(), ["HEAD", "DELETE"]);
+            assert!(matches!(storage.get("abcdef").await.unwrap(), Cache::Miss));
+            storage.probe().await.unwrap();
+            assert_eq!(storage.check().await.unwrap(), CacheMode::ReadWrite);
+        });
+        env::remove_var("AWS_ACCESS_KEY_ID");
+        env::remove_var("AWS_SECRET_ACCESS_KEY");
+    }
 
     #[test]
     fn test_endpoint_resolver() -> Result<()> {
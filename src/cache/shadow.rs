@@ -0,0 +1,364 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A second cache, the shadow, written along with the configured one, the
+//! primary, to check a new backend against the one in use before migrating
+//! to it, see `SCCACHE_SHADOW_CACHE`.
+//!
+//! The entries are written to both caches, and looked up in the
+//! authoritative one, the primary unless `SCCACHE_SHADOW_AUTHORITATIVE`
+//! says otherwise. A fraction of the lookups, `SCCACHE_SHADOW_READ_RATE`,
+//! are also looked up in the other cache, and the entries found in both
+//! compared, the differences being logged.
+
+use std::io::Cursor;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use crate::cache::{
+    Cache, CacheMode, CacheOccupancy, CacheRead, CacheWrite, EntryStream, EvictionState, Storage,
+    StorageCounts,
+};
+use crate::compiler::PreprocessorCacheEntry;
+use crate::config::EntryConfig;
+use crate::errors::*;
+
+use super::PreprocessorCacheModeConfig;
+
+/// A storage writing its entries to both `primary` and `shadow`, and
+/// comparing a fraction of the entries looked up in both.
+pub struct ShadowStorage {
+    primary: Arc<dyn Storage>,
+    shadow: Arc<dyn Storage>,
+    read_rate: f64,
+    authoritative: bool,
+    mismatches: AtomicUsize,
+}
+
+impl ShadowStorage {
+    /// Write to both `primary` and `shadow`, and look up the entries in
+    /// `shadow` if it's `authoritative`, else in `primary`, comparing the
+    /// fraction `read_rate` of them with the other.
+    pub fn new(
+        primary: Arc<dyn Storage>,
+        shadow: Arc<dyn Storage>,
+        read_rate: f64,
+        authoritative: bool,
+    ) -> ShadowStorage {
+        ShadowStorage {
+            primary,
+            shadow,
+            read_rate,
+            authoritative,
+            mismatches: AtomicUsize::new(0),
+        }
+    }
+
+    /// How many of the lookups compared found different entries.
+    pub fn mismatches(&self) -> usize {
+        self.mismatches.load(Ordering::Relaxed)
+    }
+
+    /// The storage answering the lookups, and the other one, with their
+    /// names.
+    fn storages(&self) -> ((&'static str, &dyn Storage), (&'static str, &dyn Storage)) {
+        let primary = ("primary", &*self.primary);
+        let shadow = ("shadow", &*self.shadow);
+        if self.authoritative {
+            (shadow, primary)
+        } else {
+            (primary, shadow)
+        }
+    }
+
+    /// The storage answering the lookups.
+    fn authoritative_storage(&self) -> &dyn Storage {
+        self.storages().0 .1
+    }
+
+    /// Log that the entry of `key` differs, as `what` says.
+    fn mismatch(&self, key: &str, what: &str) {
+        self.mismatches.fetch_add(1, Ordering::Relaxed);
+        let ((name, storage), (other_name, other)) = self.storages();
+        warn!(
+            "Shadow cache mismatch for {}: {} ({}: {}, {}: {})",
+            key,
+            what,
+            name,
+            storage.location(),
+            other_name,
+            other.location()
+        );
+    }
+
+    /// Compare `hit`, the entry of `key` found in the authoritative storage,
+    /// if any, with the one of `other`, and return it.
+    async fn compare(
+        &self,
+        key: &str,
+        hit: Option<CacheRead>,
+        other: &dyn Storage,
+    ) -> Result<Cache> {
+        let other = match other.get(key).await {
+            Ok(Cache::Hit(entry)) => Some(entry.into_bytes()?),
+            Ok(_) => None,
+            Err(e) => {
+                debug!("Shadow cache lookup of {} failed: {:?}", key, e);
+                return Ok(hit.map_or(Cache::Miss, Cache::Hit));
+            }
+        };
+        let Some(hit) = hit else {
+            if other.is_some() {
+                self.mismatch(key, "only found in the other cache");
+            }
+            return Ok(Cache::Miss);
+        };
+        let bytes = hit.into_bytes()?;
+        match other {
+            Some(other) if other == bytes => {}
+            Some(_) => self.mismatch(key, "the entries differ"),
+            None => self.mismatch(key, "missing from the other cache"),
+        }
+        Ok(Cache::Hit(CacheRead::from(Cursor::new(bytes))?))
+    }
+}
+
+#[async_trait]
+impl Storage for ShadowStorage {
+    async fn get(&self, key: &str) -> Result<Cache> {
+        let ((_, storage), (_, other)) = self.storages();
+        let found = storage.get(key).await?;
+        if self.read_rate <= 0.0 || rand::random::<f64>() >= self.read_rate {
+            return Ok(found);
+        }
+        match found {
+            Cache::Hit(entry) => self.compare(key, Some(entry), other).await,
+            Cache::Miss => self.compare(key, None, other).await,
+            Cache::Recache => Ok(Cache::Recache),
+        }
+    }
+
+    async fn put(&self, key: &str, entry: CacheWrite) -> Result<Duration> {
+        let metadata = entry.metadata().cloned();
+        let bytes = entry.finish()?;
+        let copy = |bytes| -> Result<CacheWrite> {
+            let mut entry = CacheWrite::from_bytes(bytes)?;
+            if let Some(metadata) = &metadata {
+                entry.set_metadata(metadata.clone());
+            }
+            Ok(entry)
+        };
+        let ((_, storage), (other_name, other)) = self.storages();
+        let (put, other_put) = futures::join!(
+            storage.put(key, copy(bytes.clone())?),
+            other.put(key, copy(bytes)?)
+        );
+        if let Err(e) = other_put {
+            warn!(
+                "Failed to write {} to the {} cache: {:?}",
+                key, other_name, e
+            );
+        }
+        put
+    }
+
+    async fn check(&self) -> Result<CacheMode> {
+        self.authoritative_storage().check().await
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.authoritative_storage().is_read_only()
+    }
+
+    async fn probe(&self) -> Result<()> {
+        self.primary.probe().await?;
+        self.shadow
+            .probe()
+            .await
+            .context("Failed to probe the shadow cache")
+    }
+
+    fn location(&self) -> String {
+        format!(
+            "{} (shadow: {})",
+            self.primary.location(),
+            self.shadow.location()
+        )
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        self.authoritative_storage().list().await
+    }
+
+    async fn list_entries(&self, prefix: &str) -> Result<EntryStream> {
+        self.authoritative_storage().list_entries(prefix).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool> {
+        let ((_, storage), (other_name, other)) = self.storages();
+        let (found, other_found) = futures::join!(storage.delete(key), other.delete(key));
+        if let Err(e) = other_found {
+            warn!(
+                "Failed to remove {} from the {} cache: {:?}",
+                key, other_name, e
+            );
+        }
+        found
+    }
+
+    async fn current_size(&self) -> Result<Option<u64>> {
+        self.authoritative_storage().current_size().await
+    }
+
+    async fn max_size(&self) -> Result<Option<u64>> {
+        self.authoritative_storage().max_size().await
+    }
+
+    fn requests_in_flight(&self) -> Option<usize> {
+        self.authoritative_storage().requests_in_flight()
+    }
+
+    fn take_counts(&self) -> StorageCounts {
+        self.primary.take_counts() + self.shadow.take_counts()
+    }
+
+    fn eviction_state(&self) -> Option<EvictionState> {
+        self.authoritative_storage().eviction_state()
+    }
+
+    fn occupancy(&self) -> Option<CacheOccupancy> {
+        self.authoritative_storage().occupancy()
+    }
+
+    fn entry_config(&self) -> EntryConfig {
+        self.primary.entry_config()
+    }
+
+    fn preprocessor_cache_mode_config(&self) -> PreprocessorCacheModeConfig {
+        self.primary.preprocessor_cache_mode_config()
+    }
+
+    async fn get_preprocessor_cache_entry(
+        &self,
+        key: &str,
+    ) -> Result<Option<Box<dyn crate::lru_disk_cache::ReadSeek>>> {
+        self.primary.get_preprocessor_cache_entry(key).await
+    }
+
+    async fn put_preprocessor_cache_entry(
+        &self,
+        key: &str,
+        preprocessor_cache_entry: PreprocessorCacheEntry,
+    ) -> Result<()> {
+        self.primary
+            .put_preprocessor_cache_entry(key, preprocessor_cache_entry)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::disk::DiskCache;
+
+    fn disk_cache(dir: &std::path::Path, runtime: &tokio::runtime::Runtime) -> Arc<dyn Storage> {
+        Arc::new(DiskCache::new(
+            dir,
+            u64::MAX,
+            runtime.handle(),
+            PreprocessorCacheModeConfig::default(),
+            CacheMode::ReadWrite,
+        ))
+    }
+
+    fn entry(contents: &[u8]) -> CacheWrite {
+        let mut entry = CacheWrite::new();
+        entry.put_object("obj", &mut &contents[..], None).unwrap();
+        entry
+    }
+
+    fn object(cache: Cache) -> Vec<u8> {
+        let Cache::Hit(mut entry) = cache else {
+            panic!("Expected a hit");
+        };
+        let mut object = vec![];
+        entry.get_object("obj", &mut object).unwrap();
+        object
+    }
+
+    #[test]
+    fn test_shadow_storage() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let tempdir = tempfile::Builder::new()
+            .prefix("sccache_test_shadow")
+            .tempdir()
+            .unwrap();
+        let primary = disk_cache(&tempdir.path().join("primary"), &runtime);
+        let shadow = disk_cache(&tempdir.path().join("shadow"), &runtime);
+        let storage = ShadowStorage::new(primary.clone(), shadow.clone(), 1.0, false);
+
+        runtime
+            .block_on(storage.put("0123456789abcdef", entry(b"both")))
+            .unwrap();
+        for cache in [&primary, &shadow] {
+            let found = runtime.block_on(cache.get("0123456789abcdef")).unwrap();
+            assert_eq!(object(found), b"both");
+        }
+        let found = runtime.block_on(storage.get("0123456789abcdef")).unwrap();
+        assert_eq!(object(found), b"both");
+        assert_eq!(storage.mismatches(), 0);
+
+        // An entry missing from the shadow is still found.
+        runtime
+            .block_on(primary.put("1123456789abcdef", entry(b"primary")))
+            .unwrap();
+        let found = runtime.block_on(storage.get("1123456789abcdef")).unwrap();
+        assert_eq!(object(found), b"primary");
+        assert_eq!(storage.mismatches(), 1);
+
+        // An entry only in the shadow isn't.
+        runtime
+            .block_on(shadow.put("2123456789abcdef", entry(b"shadow")))
+            .unwrap();
+        let found = runtime.block_on(storage.get("2123456789abcdef")).unwrap();
+        assert!(matches!(found, Cache::Miss));
+        assert_eq!(storage.mismatches(), 2);
+
+        // Nor does a miss in both differ.
+        let found = runtime.block_on(storage.get("3123456789abcdef")).unwrap();
+        assert!(matches!(found, Cache::Miss));
+        assert_eq!(storage.mismatches(), 2);
+
+        // Different entries are compared.
+        runtime
+            .block_on(shadow.put("1123456789abcdef", entry(b"other")))
+            .unwrap();
+        let found = runtime.block_on(storage.get("1123456789abcdef")).unwrap();
+        assert_eq!(object(found), b"primary");
+        assert_eq!(storage.mismatches(), 3);
+
+        // When the shadow is authoritative, its entries are found instead.
+        let storage = ShadowStorage::new(primary, shadow, 0.0, true);
+        let found = runtime.block_on(storage.get("2123456789abcdef")).unwrap();
+        assert_eq!(object(found), b"shadow");
+        let found = runtime.block_on(storage.get("1123456789abcdef")).unwrap();
+        assert_eq!(object(found), b"other");
+        // None of which are compared at a rate of 0.
+        assert_eq!(storage.mismatches(), 0);
+    }
+}
@@ -0,0 +1,241 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The local disk cache as a tier in front of a remote cache, see
+//! `SCCACHE_LOCAL_TIER`, so that the entries fetched once, or prefetched with
+//! `sccache --prefetch`, are then read from the local disk.
+
+use std::io::Cursor;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use crate::cache::{
+    Cache, CacheMode, CacheOccupancy, CacheRead, CacheWrite, EntryStream, EvictionState, Storage,
+    StorageCounts,
+};
+use crate::compiler::PreprocessorCacheEntry;
+use crate::config::EntryConfig;
+use crate::errors::*;
+
+use super::PreprocessorCacheModeConfig;
+
+/// A storage looking up its entries in `local` first, then in `remote`,
+/// copying the entries found there to `local`. The entries are written to
+/// both.
+///
+/// The local tier is best effort: its failures are logged, and the entries
+/// are then looked up in, or written to, `remote` only.
+pub struct TieredStorage {
+    local: Arc<dyn Storage>,
+    remote: Arc<dyn Storage>,
+}
+
+impl TieredStorage {
+    pub fn new(local: Arc<dyn Storage>, remote: Arc<dyn Storage>) -> TieredStorage {
+        TieredStorage { local, remote }
+    }
+
+    /// Copy `bytes` of the entry of `key` found in `remote` to `local`.
+    async fn keep(&self, key: &str, bytes: Vec<u8>) {
+        let res = async { self.local.put(key, CacheWrite::from_bytes(bytes)?).await };
+        if let Err(e) = res.await {
+            warn!("Failed to copy {} to the local cache: {:#}", key, e);
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for TieredStorage {
+    async fn get(&self, key: &str) -> Result<Cache> {
+        match self.local.get(key).await {
+            Ok(Cache::Hit(entry)) => {
+                debug!("Found {} in the local cache", key);
+                return Ok(Cache::Hit(entry));
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Failed to look up {} in the local cache: {:#}", key, e),
+        }
+        match self.remote.get(key).await? {
+            Cache::Hit(entry) => {
+                let bytes = entry.into_bytes()?;
+                self.keep(key, bytes.clone()).await;
+                Ok(Cache::Hit(CacheRead::from(Cursor::new(bytes))?))
+            }
+            found => Ok(found),
+        }
+    }
+
+    async fn put(&self, key: &str, entry: CacheWrite) -> Result<Duration> {
+        let metadata = entry.metadata().cloned();
+        let bytes = entry.finish()?;
+        let mut copy = CacheWrite::from_bytes(bytes.clone())?;
+        if let Some(metadata) = metadata.clone() {
+            copy.set_metadata(metadata);
+        }
+        if let Err(e) = self.local.put(key, copy).await {
+            warn!("Failed to write {} to the local cache: {:#}", key, e);
+        }
+        let mut entry = CacheWrite::from_bytes(bytes)?;
+        if let Some(metadata) = metadata {
+            entry.set_metadata(metadata);
+        }
+        self.remote.put(key, entry).await
+    }
+
+    async fn check(&self) -> Result<CacheMode> {
+        self.remote.check().await
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.remote.is_read_only()
+    }
+
+    async fn probe(&self) -> Result<()> {
+        self.remote.probe().await
+    }
+
+    fn location(&self) -> String {
+        format!(
+            "{}, with a local tier in {}",
+            self.remote.location(),
+            self.local.location()
+        )
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        self.remote.list().await
+    }
+
+    async fn list_entries(&self, prefix: &str) -> Result<EntryStream> {
+        self.remote.list_entries(prefix).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool> {
+        let local = self.local.delete(key).await?;
+        Ok(self.remote.delete(key).await? || local)
+    }
+
+    async fn current_size(&self) -> Result<Option<u64>> {
+        self.remote.current_size().await
+    }
+
+    async fn max_size(&self) -> Result<Option<u64>> {
+        self.remote.max_size().await
+    }
+
+    fn requests_in_flight(&self) -> Option<usize> {
+        self.remote.requests_in_flight()
+    }
+
+    fn take_counts(&self) -> StorageCounts {
+        self.local.take_counts() + self.remote.take_counts()
+    }
+
+    fn eviction_state(&self) -> Option<EvictionState> {
+        self.remote.eviction_state()
+    }
+
+    fn occupancy(&self) -> Option<CacheOccupancy> {
+        self.remote.occupancy()
+    }
+
+    fn entry_config(&self) -> EntryConfig {
+        self.remote.entry_config()
+    }
+
+    fn preprocessor_cache_mode_config(&self) -> PreprocessorCacheModeConfig {
+        self.remote.preprocessor_cache_mode_config()
+    }
+
+    async fn get_preprocessor_cache_entry(
+        &self,
+        key: &str,
+    ) -> Result<Option<Box<dyn crate::lru_disk_cache::ReadSeek>>> {
+        self.remote.get_preprocessor_cache_entry(key).await
+    }
+
+    async fn put_preprocessor_cache_entry(
+        &self,
+        key: &str,
+        preprocessor_cache_entry: PreprocessorCacheEntry,
+    ) -> Result<()> {
+        self.remote
+            .put_preprocessor_cache_entry(key, preprocessor_cache_entry)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::disk::DiskCache;
+    use crate::cache::prefetch::{parse_keys, prefetch};
+    use crate::test::mock_storage::MockStorage;
+
+    #[test]
+    fn test_tiered_storage() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let tempdir = tempfile::Builder::new()
+            .prefix("sccache_test_tiered")
+            .tempdir()
+            .unwrap();
+        let disk = |name| {
+            Arc::new(DiskCache::new(
+                tempdir.path().join(name),
+                u64::MAX,
+                runtime.handle(),
+                PreprocessorCacheModeConfig::default(),
+                CacheMode::ReadWrite,
+            ))
+        };
+        let remote = disk("remote");
+        let local = disk("local");
+
+        runtime.block_on(async {
+            for key in ["aaaa", "bbbb"] {
+                let mut entry = CacheWrite::new();
+                entry.put_stdout(key.as_bytes()).unwrap();
+                remote.put(key, entry).await.unwrap();
+            }
+            let report = prefetch(&*remote, &*local, parse_keys("aaaa\n")).await;
+            assert_eq!(report.fetched, 1);
+
+            // A prefetched entry is a local hit, even once gone from the
+            // remote cache.
+            let mock = Arc::new(MockStorage::new(None, false));
+            let storage = TieredStorage::new(local.clone(), mock);
+            match storage.get("aaaa").await.unwrap() {
+                Cache::Hit(mut entry) => assert_eq!(entry.get_stdout(), b"aaaa"),
+                e => panic!("Unexpected cache result: {:?}", e),
+            }
+
+            // The remote hits are copied to the local tier.
+            let storage = TieredStorage::new(local.clone(), remote.clone());
+            assert!(matches!(local.get("bbbb").await.unwrap(), Cache::Miss));
+            match storage.get("bbbb").await.unwrap() {
+                Cache::Hit(mut entry) => assert_eq!(entry.get_stdout(), b"bbbb"),
+                e => panic!("Unexpected cache result: {:?}", e),
+            }
+            assert!(matches!(local.get("bbbb").await.unwrap(), Cache::Hit(_)));
+
+            // The entries are written to both.
+            storage.put("cccc", CacheWrite::new()).await.unwrap();
+            assert!(matches!(local.get("cccc").await.unwrap(), Cache::Hit(_)));
+            assert!(matches!(remote.get("cccc").await.unwrap(), Cache::Hit(_)));
+        });
+    }
+}
@@ -0,0 +1,238 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Timeouts of the requests to the remote caches, so that a hung backend
+//! turns into cache misses and failed writes instead of stalling compilations.
+
+use std::env;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use crate::cache::{
+    Cache, CacheMode, CacheOccupancy, CacheWrite, EntryStream, EvictionState, Storage,
+    StorageCounts,
+};
+use crate::compiler::PreprocessorCacheEntry;
+use crate::config::EntryConfig;
+use crate::errors::*;
+
+use super::PreprocessorCacheModeConfig;
+
+/// The default timeout of a request, retries included.
+pub const DEFAULT_REMOTE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Get the timeout of the requests to the cache `backend`, as in
+/// `CacheType::name`, from `SCCACHE_<BACKEND>_TIMEOUT`, or else
+/// `SCCACHE_REMOTE_TIMEOUT`, in seconds. A timeout of 0 disables it.
+pub fn get_remote_timeout(backend: &str) -> Option<Duration> {
+    let vars = [
+        format!("SCCACHE_{}_TIMEOUT", backend.to_uppercase()),
+        "SCCACHE_REMOTE_TIMEOUT".to_owned(),
+    ];
+    for var in &vars {
+        let Ok(s) = env::var(var) else {
+            continue;
+        };
+        match s.parse::<f64>() {
+            Ok(secs) if secs.is_finite() && secs >= 0.0 => {
+                return (secs > 0.0).then(|| Duration::from_secs_f64(secs))
+            }
+            _ => warn!(
+                "Invalid {} {:?}, using {:?}",
+                var, s, DEFAULT_REMOTE_TIMEOUT
+            ),
+        }
+        break;
+    }
+    Some(DEFAULT_REMOTE_TIMEOUT)
+}
+
+/// A storage failing the requests to the underlying storage which take
+/// longer than `timeout` with a `CacheTimeout` error.
+///
+/// The request is dropped when it times out, which cancels it and closes its
+/// connection rather than leaving it to complete in the background.
+pub struct TimeoutStorage {
+    storage: Arc<dyn Storage>,
+    timeout: Duration,
+}
+
+impl TimeoutStorage {
+    pub fn new(storage: Arc<dyn Storage>, timeout: Duration) -> Self {
+        TimeoutStorage { storage, timeout }
+    }
+
+    async fn run<T>(&self, request: impl Future<Output = Result<T>>) -> Result<T> {
+        match tokio::time::timeout(self.timeout, request).await {
+            Ok(res) => res,
+            Err(_) => Err(CacheTimeout(self.timeout).into()),
+        }
+    }
+}
+
+/// Wrap the storage of the cache `backend` in a `TimeoutStorage`, unless its
+/// timeout is disabled.
+pub fn with_timeout(storage: Arc<dyn Storage>, backend: &str) -> Arc<dyn Storage> {
+    match get_remote_timeout(backend) {
+        None => storage,
+        Some(timeout) => {
+            debug!("Timing out {} cache requests after {:?}", backend, timeout);
+            Arc::new(TimeoutStorage::new(storage, timeout))
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for TimeoutStorage {
+    async fn get(&self, key: &str) -> Result<Cache> {
+        self.run(self.storage.get(key)).await
+    }
+
+    async fn put(&self, key: &str, entry: CacheWrite) -> Result<Duration> {
+        self.run(self.storage.put(key, entry)).await
+    }
+
+    async fn check(&self) -> Result<CacheMode> {
+        self.run(self.storage.check()).await
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.storage.is_read_only()
+    }
+
+    async fn probe(&self) -> Result<()> {
+        self.run(self.storage.probe()).await
+    }
+
+    fn location(&self) -> String {
+        self.storage.location()
+    }
+
+    // Listing a whole cache may legitimately take long.
+    async fn list(&self) -> Result<Vec<String>> {
+        self.storage.list().await
+    }
+
+    async fn list_entries(&self, prefix: &str) -> Result<EntryStream> {
+        self.storage.list_entries(prefix).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool> {
+        self.run(self.storage.delete(key)).await
+    }
+
+    async fn current_size(&self) -> Result<Option<u64>> {
+        self.storage.current_size().await
+    }
+
+    async fn max_size(&self) -> Result<Option<u64>> {
+        self.storage.max_size().await
+    }
+
+    fn requests_in_flight(&self) -> Option<usize> {
+        self.storage.requests_in_flight()
+    }
+
+    fn take_counts(&self) -> StorageCounts {
+        self.storage.take_counts()
+    }
+
+    fn eviction_state(&self) -> Option<EvictionState> {
+        self.storage.eviction_state()
+    }
+
+    fn occupancy(&self) -> Option<CacheOccupancy> {
+        self.storage.occupancy()
+    }
+
+    fn entry_config(&self) -> EntryConfig {
+        self.storage.entry_config()
+    }
+
+    fn preprocessor_cache_mode_config(&self) -> PreprocessorCacheModeConfig {
+        self.storage.preprocessor_cache_mode_config()
+    }
+
+    async fn get_preprocessor_cache_entry(
+        &self,
+        key: &str,
+    ) -> Result<Option<Box<dyn crate::lru_disk_cache::ReadSeek>>> {
+        self.run(self.storage.get_preprocessor_cache_entry(key))
+            .await
+    }
+
+    async fn put_preprocessor_cache_entry(
+        &self,
+        key: &str,
+        preprocessor_cache_entry: PreprocessorCacheEntry,
+    ) -> Result<()> {
+        self.run(
+            self.storage
+                .put_preprocessor_cache_entry(key, preprocessor_cache_entry),
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::mock_storage::MockStorage;
+    use serial_test::serial;
+
+    #[test]
+    #[serial]
+    fn test_get_remote_timeout() {
+        env::remove_var("SCCACHE_S3_TIMEOUT");
+        env::remove_var("SCCACHE_REMOTE_TIMEOUT");
+        assert_eq!(get_remote_timeout("s3"), Some(DEFAULT_REMOTE_TIMEOUT));
+        env::set_var("SCCACHE_REMOTE_TIMEOUT", "10");
+        assert_eq!(get_remote_timeout("s3"), Some(Duration::from_secs(10)));
+        // The timeout of the backend wins.
+        env::set_var("SCCACHE_S3_TIMEOUT", "0.5");
+        assert_eq!(get_remote_timeout("s3"), Some(Duration::from_millis(500)));
+        assert_eq!(get_remote_timeout("redis"), Some(Duration::from_secs(10)));
+        env::set_var("SCCACHE_S3_TIMEOUT", "0");
+        assert_eq!(get_remote_timeout("s3"), None);
+        env::set_var("SCCACHE_S3_TIMEOUT", "soon");
+        assert_eq!(get_remote_timeout("s3"), Some(DEFAULT_REMOTE_TIMEOUT));
+        env::remove_var("SCCACHE_S3_TIMEOUT");
+        env::remove_var("SCCACHE_REMOTE_TIMEOUT");
+    }
+
+    #[test]
+    fn test_timeout_storage() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let slow = Arc::new(MockStorage::new(Some(Duration::from_secs(10)), false));
+        let storage = TimeoutStorage::new(slow, Duration::from_millis(10));
+        let err = runtime.block_on(storage.get("key")).unwrap_err();
+        assert!(err.downcast_ref::<CacheTimeout>().is_some());
+        let err = runtime
+            .block_on(storage.put("key", CacheWrite::new()))
+            .unwrap_err();
+        assert!(err.downcast_ref::<CacheTimeout>().is_some());
+
+        let fast = Arc::new(MockStorage::new(None, false));
+        let storage = TimeoutStorage::new(fast.clone(), Duration::from_secs(10));
+        fast.next_get(Ok(Cache::Miss));
+        assert!(matches!(
+            runtime.block_on(storage.get("key")),
+            Ok(Cache::Miss)
+        ));
+    }
+}
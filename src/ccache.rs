@@ -0,0 +1,180 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Running as `ccache`, for the build systems which only know about it.
+//!
+//! `ccache <compiler> <args>` compiles as `sccache <compiler> <args>` does,
+//! and the ccache options which have an equivalent are run as the matching
+//! sccache commands, in turn. The options managing the ccache cache only,
+//! e.g. `--cleanup`, are ignored with a warning.
+
+use crate::cmdline::{Command, StatsFormat};
+use crate::config::parse_size;
+use std::ffi::OsString;
+
+use crate::errors::*;
+
+/// The ccache options ignored, and whether they take a value.
+const IGNORED: &[(&str, Option<&str>, bool)] = &[
+    ("-c", Some("--cleanup"), false),
+    ("-x", Some("--show-compression"), false),
+    ("-F", Some("--max-files"), true),
+    ("-o", Some("--set-config"), true),
+    ("-X", Some("--recompress"), true),
+    ("--evict-older-than", None, true),
+];
+
+/// Parse a ccache size, e.g. `5G` or `500MB`, in GiB without a unit.
+fn parse_ccache_size(value: &str) -> Option<u64> {
+    let value = value.trim();
+    if value.bytes().all(|b| b.is_ascii_digit()) {
+        return parse_size(&format!("{}G", value));
+    }
+    let number = value.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let unit = &value[number.len()..];
+    let unit = match unit.to_ascii_uppercase().as_str() {
+        "K" | "KB" | "KIB" => "K",
+        "M" | "MB" | "MIB" => "M",
+        "G" | "GB" | "GIB" => "G",
+        "T" | "TB" | "TIB" => "T",
+        _ => return None,
+    };
+    parse_size(&format!("{}{}", number, unit))
+}
+
+/// Parse the arguments of `ccache`, after the program name. Return `None`
+/// if they are a compiler command, and the commands to run otherwise.
+pub fn parse_options(args: &[OsString]) -> Result<Option<Vec<Command>>> {
+    match args.first() {
+        None => bail!("ccache: a compiler command or an option is expected"),
+        Some(first) if !first.to_string_lossy().starts_with('-') => return Ok(None),
+        Some(_) => {}
+    }
+    let mut commands = vec![];
+    let mut args = args.iter().map(|arg| arg.to_string_lossy().into_owned());
+    while let Some(arg) = args.next() {
+        // The value of `--option=value`, or `-Ovalue`.
+        let (option, mut value) = match arg.split_once('=') {
+            Some((option, value)) if option.starts_with("--") => {
+                (option.to_owned(), Some(value.to_owned()))
+            }
+            _ if !arg.starts_with("--") && arg.len() > 2 && arg.is_char_boundary(2) => {
+                (arg[..2].to_owned(), Some(arg[2..].to_owned()))
+            }
+            _ => (arg.clone(), None),
+        };
+        let mut value = || {
+            value
+                .take()
+                .or_else(|| args.next())
+                .with_context(|| format!("ccache: {} expects a value", option))
+        };
+        match option.as_str() {
+            "-s" | "--show-stats" => commands.push(Command::ShowStats(StatsFormat::Text, false)),
+            "-z" | "--zero-stats" => commands.push(Command::ZeroStats),
+            "-C" | "--clear" => commands.push(Command::ClearCache),
+            "-M" | "--max-size" => {
+                let size = value()?;
+                // Unlike ccache, sccache has no unlimited size.
+                let size = parse_ccache_size(&size)
+                    .filter(|size| *size > 0)
+                    .with_context(|| format!("ccache: invalid size {:?}", size))?;
+                commands.push(Command::SetMaxCacheSize(size));
+            }
+            "-p" | "--show-config" => commands.push(Command::Info { json: false }),
+            "-V" | "--version" => commands.push(Command::Version),
+            option => {
+                let ignored = IGNORED
+                    .iter()
+                    .find(|(short, long, _)| *short == option || *long == Some(option));
+                let Some((_, _, takes_value)) = ignored else {
+                    bail!("ccache: the option {} isn't supported by sccache", arg);
+                };
+                if *takes_value {
+                    value()?;
+                }
+                eprintln!(
+                    "sccache: warning: Ignoring the ccache option {}, which has no equivalent",
+                    arg
+                );
+            }
+        }
+    }
+    Ok(Some(commands))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Option<Vec<String>>> {
+        let args: Vec<OsString> = args.iter().map(Into::into).collect();
+        // `Command` isn't comparable, its names are.
+        Ok(parse_options(&args)?.map(|commands| {
+            commands
+                .iter()
+                .map(|command| match command {
+                    Command::ShowStats(..) => "stats".to_owned(),
+                    Command::ZeroStats => "zero".to_owned(),
+                    Command::ClearCache => "clear".to_owned(),
+                    Command::SetMaxCacheSize(size) => format!("max {}", size),
+                    Command::Info { .. } => "info".to_owned(),
+                    Command::Version => "version".to_owned(),
+                    _ => "other".to_owned(),
+                })
+                .collect()
+        }))
+    }
+
+    #[test]
+    fn test_parse_options() {
+        assert_eq!(parse(&["gcc", "-c", "foo.c"]).unwrap(), None);
+        assert_eq!(
+            parse(&["-z", "--show-stats", "-C", "-p"]).unwrap(),
+            Some(vec![
+                "zero".into(),
+                "stats".into(),
+                "clear".into(),
+                "info".into()
+            ])
+        );
+        let gib: u64 = 1024 * 1024 * 1024;
+        for args in [
+            &["-M", "5"][..],
+            &["-M5G"],
+            &["--max-size", "5GB"],
+            &["--max-size=5GiB"],
+        ] {
+            assert_eq!(
+                parse(args).unwrap(),
+                Some(vec![format!("max {}", 5 * gib)]),
+                "{:?}",
+                args
+            );
+        }
+        assert_eq!(
+            parse(&["-M", "500M"]).unwrap(),
+            Some(vec![format!("max {}", 500 * 1024 * 1024)])
+        );
+        assert!(parse(&["-M", "lots"]).is_err());
+        assert!(parse(&["-M", "0"]).is_err());
+        assert!(parse(&["-M"]).is_err());
+        // The options of the ccache cache are ignored, with their values.
+        assert_eq!(
+            parse(&["--cleanup", "-F", "100", "--set-config=max_files=5", "-s"]).unwrap(),
+            Some(vec!["stats".into()])
+        );
+        assert_eq!(parse(&["--version"]).unwrap(), Some(vec!["version".into()]));
+        assert!(parse(&["--debug"]).is_err());
+        assert!(parse(&[]).is_err());
+    }
+}
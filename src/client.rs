@@ -12,38 +12,201 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::admin::{self, AdminAddr};
+use crate::discovery;
 use crate::errors::*;
 use crate::protocol::{Request, Response};
+use crate::retry::{retry_sync, Backoff};
+use crate::server::{self, ServerInfo};
 use crate::util;
 use byteorder::{BigEndian, ByteOrder};
-use retry::{delay::Fixed, retry};
-use std::io::{self, BufReader, BufWriter, Read};
-use std::net::TcpStream;
+#[cfg(unix)]
+use std::env;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::pin::Pin;
+use std::task::{self, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+use tokio::runtime::Handle;
+
+pub use crate::protocol::PROTOCOL_VERSION;
 
 /// A connection to an sccache server.
 pub struct ServerConnection {
     /// A reader for the socket connected to the server.
-    reader: BufReader<TcpStream>,
+    reader: BufReader<Box<dyn Read + Send>>,
     /// A writer for the socket connected to the server.
-    writer: BufWriter<TcpStream>,
+    writer: BufWriter<Box<dyn ServerSocket>>,
+}
+
+/// The writing half of the socket connected to the server.
+trait ServerSocket: Write + Send {
+    /// Shut down writing, so that the server sees the end of the requests.
+    fn shutdown_write(&self) -> io::Result<()>;
+}
+
+impl ServerSocket for TcpStream {
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
 }
 
+#[cfg(unix)]
+impl ServerSocket for UnixStream {
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+}
+
+/// A socket whose reads and writes are retried when a signal interrupts
+/// them, which `read_exact` and `write_all` do but not the single reads and
+/// writes, e.g. waiting for the server to close the connection.
+struct RetryInterrupted<S>(S);
+
+impl<S: Read> Read for RetryInterrupted<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.0.read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                res => return res,
+            }
+        }
+    }
+}
+
+impl<S: Write> Write for RetryInterrupted<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        loop {
+            match self.0.write(buf) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                res => return res,
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        loop {
+            match self.0.flush() {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                res => return res,
+            }
+        }
+    }
+}
+
+impl<S: ServerSocket> ServerSocket for RetryInterrupted<S> {
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.0.shutdown_write()
+    }
+}
+
+/// The size of the pipe and of the copies of `AsyncServerConnection`.
+const BRIDGE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// The size of the chunks of stdin sent to the server.
+const STDIN_CHUNK_SIZE: usize = 64 * 1024;
+
+/// How long and how many times to retry connecting to a server which is
+/// starting.
+const CONNECT_BACKOFF: Backoff = Backoff::fixed(Duration::from_millis(500), 10);
+
 impl ServerConnection {
     /// Create a new connection using `stream`.
     pub fn new(stream: TcpStream) -> io::Result<ServerConnection> {
         let writer = stream.try_clone()?;
-        Ok(ServerConnection {
-            reader: BufReader::new(stream),
-            writer: BufWriter::new(writer),
-        })
+        Ok(ServerConnection::from_halves(stream, writer))
+    }
+
+    /// Create a new connection using the Unix socket `stream`.
+    #[cfg(unix)]
+    pub fn from_unix(stream: UnixStream) -> io::Result<ServerConnection> {
+        let writer = stream.try_clone()?;
+        Ok(ServerConnection::from_halves(stream, writer))
+    }
+
+    fn from_halves(
+        reader: impl Read + Send + 'static,
+        writer: impl ServerSocket + 'static,
+    ) -> ServerConnection {
+        ServerConnection {
+            reader: BufReader::new(Box::new(RetryInterrupted(reader))),
+            writer: BufWriter::new(Box::new(RetryInterrupted(writer))),
+        }
     }
 
     /// Send `request` to the server, read and return a `Response`.
     pub fn request(&mut self, request: Request) -> Result<Response> {
         trace!("ServerConnection::request");
-        util::write_length_prefixed_bincode(&mut self.writer, request)?;
+        self.send(request)?;
         trace!("ServerConnection::request: sent request");
-        self.read_one_response()
+        self.read_request_response()
+    }
+
+    /// Send `request` to the server followed by `stdin`, in chunks as it is
+    /// read, see `Request::Stdin`, read and return a `Response`.
+    pub fn request_with_stdin(
+        &mut self,
+        request: Request,
+        stdin: &mut dyn Read,
+    ) -> Result<Response> {
+        trace!("ServerConnection::request_with_stdin");
+        self.send(request)?;
+        let mut buf = vec![0; STDIN_CHUNK_SIZE];
+        loop {
+            let n = match stdin.read(&mut buf) {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e).context("failed to read stdin"),
+            };
+            // Also sends the empty chunk marking the end of stdin.
+            self.send(Request::Stdin(buf[..n].to_vec()))?;
+            if n == 0 {
+                break;
+            }
+        }
+        trace!("ServerConnection::request_with_stdin: sent request and stdin");
+        self.read_request_response()
+    }
+
+    fn send(&mut self, request: Request) -> Result<()> {
+        // The server would close the connection without an answer.
+        let size = bincode::serialized_size(&request)?;
+        let max = server::get_max_frame_length();
+        if size > max as u64 {
+            return Err(RequestTooLarge(size, max).into());
+        }
+        util::write_length_prefixed_bincode(&mut self.writer, request)
+    }
+
+    fn read_request_response(&mut self) -> Result<Response> {
+        match self.read_one_response()? {
+            Response::Unauthorized => bail!(
+                "The server requires a token: SCCACHE_SERVER_TOKEN is missing or doesn't match"
+            ),
+            Response::Refused(reason) => bail!("{}", reason),
+            response => Ok(response),
+        }
+    }
+
+    /// Authenticate the connection with `token`, if the server requires
+    /// one.
+    pub fn authenticate(&mut self, token: &str) -> Result<()> {
+        match self.request(Request::Authenticate(token.to_owned()))? {
+            Response::Authenticated => Ok(()),
+            _ => bail!("Unexpected server response!"),
+        }
+    }
+
+    /// Authenticate the connection with `SCCACHE_SERVER_TOKEN`, if it is set.
+    fn authenticate_from_env(mut self) -> io::Result<ServerConnection> {
+        if let Some(token) = server::get_server_token() {
+            self.authenticate(&token)
+                .map_err(|e| io::Error::new(io::ErrorKind::PermissionDenied, format!("{:#}", e)))?;
+        }
+        Ok(self)
     }
 
     /// Read a single `Response` from the server.
@@ -60,13 +223,156 @@ impl ServerConnection {
         trace!("Done reading");
         Ok(bincode::deserialize(&data)?)
     }
+
+    /// Wait for the server to close the connection.
+    pub fn wait_closed(&mut self) -> io::Result<()> {
+        trace!("ServerConnection::wait_closed");
+        let mut buf = [0; 64];
+        while self.reader.read(&mut buf)? > 0 {}
+        Ok(())
+    }
+
+    /// Bridge the connection to async code, e.g. to hand a connection made
+    /// and authenticated by the sync client to an async one. This must be
+    /// called from within a Tokio runtime.
+    pub fn into_async(self) -> AsyncServerConnection {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let ServerConnection {
+            mut reader,
+            mut writer,
+        } = self;
+        let (stream, bridge) = tokio::io::duplex(BRIDGE_BUFFER_SIZE);
+        let (mut requests, mut responses) = tokio::io::split(bridge);
+        let handle = Handle::current();
+        let reader_handle = handle.clone();
+        tokio::task::spawn_blocking(move || -> io::Result<()> {
+            let mut buf = vec![0; BRIDGE_BUFFER_SIZE];
+            loop {
+                let n = reader.read(&mut buf)?;
+                if n == 0 {
+                    return reader_handle.block_on(responses.shutdown());
+                }
+                reader_handle.block_on(responses.write_all(&buf[..n]))?;
+            }
+        });
+        tokio::task::spawn_blocking(move || -> io::Result<()> {
+            let mut buf = vec![0; BRIDGE_BUFFER_SIZE];
+            loop {
+                let n = handle.block_on(requests.read(&mut buf))?;
+                if n == 0 {
+                    writer.flush()?;
+                    return writer.get_ref().shutdown_write();
+                }
+                // Flushing the stream can't reach through the pipe, so each
+                // write is flushed instead.
+                writer.write_all(&buf[..n])?;
+                writer.flush()?;
+            }
+        });
+        AsyncServerConnection { stream }
+    }
+}
+
+/// A `ServerConnection` as an `AsyncRead + AsyncWrite` stream of the
+/// length-prefixed bincode messages of the protocol, so that sync and async
+/// clients can interoperate while the client moves to async.
+///
+/// The bridge is not free: two blocking tasks copy the bytes between the
+/// stream and the socket for as long as the connection is open, occupying two
+/// threads of the blocking pool of the runtime, and each byte goes through an
+/// in-memory pipe on the way. It suits the few connections of a command, not
+/// one per compilation in a hot path. Shutting down the stream shuts down
+/// writing to the server, and the tasks end once the server closes the
+/// connection.
+pub struct AsyncServerConnection {
+    stream: DuplexStream,
+}
+
+impl AsyncRead for AsyncServerConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for AsyncServerConnection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
 }
 
-/// Establish a TCP connection to an sccache server listening on `port`.
+/// Establish a TCP connection to an sccache server listening on `port`, on
+/// loopback or the address of `SCCACHE_SERVER_ADDR`, and authenticate it with
+/// `SCCACHE_SERVER_TOKEN`, if it is set.
+///
+/// If `SCCACHE_SERVER_UDS` is set, connect to the Unix socket at that path
+/// instead, e.g. one systemd listens on for the server. Otherwise, the server
+/// at the address of the discovery file, if any, is connected to first, the
+/// file being removed if it is unreachable.
 pub fn connect_to_server(port: u16) -> io::Result<ServerConnection> {
     trace!("connect_to_server({})", port);
-    let stream = TcpStream::connect(("127.0.0.1", port))?;
-    ServerConnection::new(stream)
+    #[cfg(unix)]
+    if let Some(path) = env::var_os("SCCACHE_SERVER_UDS") {
+        return ServerConnection::from_unix(UnixStream::connect(path)?)?.authenticate_from_env();
+    }
+    if let Some(addr) = discovery::read() {
+        match TcpStream::connect(addr) {
+            Ok(stream) => return ServerConnection::new(stream)?.authenticate_from_env(),
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                debug!("The server at {} of the discovery file is gone", addr);
+                discovery::withdraw(addr);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    // No server listens on port 0, which the server only binds to listen on
+    // a port the OS picks, written to the discovery file.
+    if port == 0 {
+        return Err(io::ErrorKind::ConnectionRefused.into());
+    }
+    let mut addr = server::listen_addr(port);
+    // A server listening on all the interfaces is reachable on loopback.
+    if addr.ip().is_unspecified() {
+        addr.set_ip(match addr {
+            SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    let stream = TcpStream::connect(addr)?;
+    ServerConnection::new(stream)?.authenticate_from_env()
+}
+
+/// Connect to the admin socket of the server, `SCCACHE_ADMIN_ADDR`, for the
+/// requests managing it, or to the server on `port` as `connect_to_server`
+/// does without one.
+pub fn connect_to_admin(port: u16) -> io::Result<ServerConnection> {
+    trace!("connect_to_admin({})", port);
+    match admin::addr() {
+        Some(AdminAddr::Tcp(addr)) => {
+            ServerConnection::new(TcpStream::connect(addr)?)?.authenticate_from_env()
+        }
+        #[cfg(unix)]
+        Some(AdminAddr::Unix(path)) => {
+            ServerConnection::from_unix(UnixStream::connect(path)?)?.authenticate_from_env()
+        }
+        None => connect_to_server(port),
+    }
 }
 
 /// Attempt to establish a TCP connection to an sccache server listening on `port`.
@@ -79,11 +385,140 @@ pub fn connect_with_retry(port: u16) -> io::Result<ServerConnection> {
     //   if the process exited.
     // * Send a pipe handle to the server process so it can notify
     //   us once it starts the server instead of us polling.
-    match retry(Fixed::from_millis(500).take(10), || connect_to_server(port)) {
+    match retry_sync(&CONNECT_BACKOFF, || connect_to_server(port)) {
         Ok(conn) => Ok(conn),
         Err(e) => Err(io::Error::new(
             io::ErrorKind::TimedOut,
-            format!("Connection to server timed out: {:?}", e),
+            format!("Connection to server timed out: {}", e),
         )),
     }
 }
+
+/// Get the statistics of the sccache server listening on `addr`, as printed by
+/// `sccache --show-stats --stats-format=json`.
+///
+/// The server must run the same `PROTOCOL_VERSION` as this library, which is
+/// also recorded in the returned `ServerInfo`. The connection is
+/// authenticated with `SCCACHE_SERVER_TOKEN`, if it is set.
+pub fn query_stats<A: ToSocketAddrs>(addr: A) -> Result<ServerInfo> {
+    let stream = TcpStream::connect(addr).context("Failed to connect to the sccache server")?;
+    let mut conn = ServerConnection::new(stream)?.authenticate_from_env()?;
+    let response = conn.request(Request::GetStats).context(
+        "Failed to send data to or receive data from server. Mismatch of client/server versions?",
+    )?;
+    match response {
+        Response::Stats(info) => Ok(*info),
+        _ => bail!("Unexpected server response!"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serial_test::serial;
+    use std::sync::{Arc, Mutex};
+
+    /// Returns its data a few bytes at a time, interrupted before each read.
+    struct InterruptedReader {
+        data: io::Cursor<Vec<u8>>,
+        interrupted: bool,
+    }
+
+    impl Read for InterruptedReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.interrupted = !self.interrupted;
+            if self.interrupted {
+                return Err(io::ErrorKind::Interrupted.into());
+            }
+            let len = buf.len().min(3);
+            self.data.read(&mut buf[..len])
+        }
+    }
+
+    /// Keeps what is written, interrupted before each write.
+    #[derive(Clone, Default)]
+    struct InterruptedWriter {
+        written: Arc<Mutex<Vec<u8>>>,
+        interrupted: bool,
+    }
+
+    impl Write for InterruptedWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.interrupted = !self.interrupted;
+            if self.interrupted {
+                return Err(io::ErrorKind::Interrupted.into());
+            }
+            self.written.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl ServerSocket for InterruptedWriter {
+        fn shutdown_write(&self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_interrupted_io() {
+        let mut data = vec![];
+        util::write_length_prefixed_bincode(&mut data, Response::ZeroStats).unwrap();
+        // What follows the response, until the server closes the connection.
+        data.extend_from_slice(b"trailing");
+        let writer = InterruptedWriter::default();
+        let reader = InterruptedReader {
+            data: io::Cursor::new(data),
+            interrupted: false,
+        };
+        let mut conn = ServerConnection::from_halves(reader, writer.clone());
+        assert!(matches!(
+            conn.request(Request::ZeroStats).unwrap(),
+            Response::ZeroStats
+        ));
+        let mut request = vec![];
+        util::write_length_prefixed_bincode(&mut request, Request::ZeroStats).unwrap();
+        assert_eq!(*writer.written.lock().unwrap(), request);
+        conn.wait_closed().unwrap();
+
+        // Other errors still fail the request.
+        struct FailingReader;
+        impl Read for FailingReader {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::ConnectionReset.into())
+            }
+        }
+        let mut conn = ServerConnection::from_halves(FailingReader, writer);
+        assert!(conn.request(Request::ZeroStats).is_err());
+        assert!(conn.wait_closed().is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn test_connect_to_discovered_server() {
+        let dir = tempfile::tempdir().unwrap();
+        env::set_var("XDG_RUNTIME_DIR", dir.path());
+        env::set_var("SCCACHE_SERVER_PORT", "0");
+        let refused = |res: io::Result<ServerConnection>| {
+            res.err().map(|e| e.kind()) == Some(io::ErrorKind::ConnectionRefused)
+        };
+        assert!(refused(connect_to_server(0)));
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        discovery::publish(addr).unwrap();
+        assert!(connect_to_server(0).is_ok());
+        assert_eq!(discovery::read(), Some(addr));
+
+        // The file of a server which is gone is removed.
+        drop(listener);
+        assert!(refused(connect_to_server(0)));
+        assert_eq!(discovery::read(), None);
+
+        env::remove_var("XDG_RUNTIME_DIR");
+        env::remove_var("SCCACHE_SERVER_PORT");
+    }
+}
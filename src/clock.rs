@@ -0,0 +1,263 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The source of time of the code waiting for it, e.g. the idle shutdown of
+//! the server, the refresh of the credentials before they expire and the
+//! backoff between retries.
+//!
+//! It is the system clock, except in the tests, which use a `FakeClock` to
+//! move the time forward at once instead of sleeping. The requests signed
+//! with the time, e.g. to S3, take it from a `SkewedClock`, set to the time of
+//! the server when the one of the machine is off.
+
+use futures::future::BoxFuture;
+#[cfg(feature = "s3")]
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+#[cfg(feature = "s3")]
+/// The smallest difference with the time of a server taken for a skew of the
+/// clock, well above the resolution of the `Date` headers and the latency.
+const SIGNIFICANT_SKEW: Duration = Duration::from_secs(60);
+
+/// A source of time.
+pub trait Clock: Send + Sync {
+    /// The current time, to measure durations with.
+    fn now(&self) -> Instant;
+    /// The current time of the wall clock, to compare timestamps with.
+    fn system_now(&self) -> SystemTime;
+    /// Wait for `duration`, from now rather than from the first poll.
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// A clock shared by the code using it.
+pub type SharedClock = Arc<dyn Clock>;
+
+/// The clock of the system, waiting with the timers of tokio.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// The clock of the system.
+pub fn system() -> SharedClock {
+    Arc::new(SystemClock)
+}
+
+#[cfg(feature = "s3")]
+/// A clock whose wall clock is moved to the time of a remote server, once
+/// measured, if it differs significantly from the one of `clock`. The
+/// durations are still measured with `clock`.
+pub struct SkewedClock {
+    clock: SharedClock,
+    /// The server, for the warnings.
+    remote: String,
+    /// How far ahead of `clock` the server is, in milliseconds.
+    offset: AtomicI64,
+    measured: AtomicBool,
+}
+
+#[cfg(feature = "s3")]
+impl SkewedClock {
+    pub fn new(clock: SharedClock, remote: &str) -> Arc<SkewedClock> {
+        Arc::new(SkewedClock {
+            clock,
+            remote: remote.to_owned(),
+            offset: AtomicI64::new(0),
+            measured: AtomicBool::new(false),
+        })
+    }
+
+    /// Whether the time of the server was measured yet.
+    pub fn measured(&self) -> bool {
+        self.measured.load(Ordering::Acquire)
+    }
+
+    /// Measure the skew from `remote`, the time of the server, e.g. in the
+    /// `Date` header of a response. Returns whether the time of the clock
+    /// changed.
+    pub fn observe(&self, remote: SystemTime) -> bool {
+        self.measured.store(true, Ordering::Release);
+        let skew = match remote.duration_since(self.clock.system_now()) {
+            Ok(ahead) => ahead.as_millis() as i64,
+            Err(e) => -(e.duration().as_millis() as i64),
+        };
+        let offset = if skew.unsigned_abs() < SIGNIFICANT_SKEW.as_millis() as u64 {
+            0
+        } else {
+            skew
+        };
+        let previous = self.offset.swap(offset, Ordering::AcqRel);
+        if offset != previous && offset != 0 {
+            warn!(
+                "The clock is {:?} {} the one of {}, using the time of the latter",
+                Duration::from_millis(offset.unsigned_abs()),
+                if offset > 0 { "behind" } else { "ahead of" },
+                self.remote
+            );
+        }
+        offset != previous
+    }
+}
+
+#[cfg(feature = "s3")]
+impl Clock for SkewedClock {
+    fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    fn system_now(&self) -> SystemTime {
+        let offset = self.offset.load(Ordering::Acquire);
+        let now = self.clock.system_now();
+        let shift = Duration::from_millis(offset.unsigned_abs());
+        if offset >= 0 {
+            now + shift
+        } else {
+            now - shift
+        }
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        self.clock.sleep(duration)
+    }
+}
+
+#[cfg(test)]
+pub use self::fake::FakeClock;
+
+#[cfg(test)]
+mod fake {
+    use super::*;
+    use std::sync::Mutex;
+    use tokio::sync::watch;
+
+    /// A clock standing still until `advance` moves it forward, waking the
+    /// sleeps which are then over.
+    pub struct FakeClock {
+        start: Instant,
+        start_system: SystemTime,
+        elapsed: watch::Sender<Duration>,
+        sleeps: Mutex<Vec<Duration>>,
+    }
+
+    impl FakeClock {
+        pub fn new() -> Arc<FakeClock> {
+            Arc::new(FakeClock {
+                start: Instant::now(),
+                start_system: SystemTime::now(),
+                elapsed: watch::channel(Duration::ZERO).0,
+                sleeps: Mutex::new(vec![]),
+            })
+        }
+
+        /// Move the time forward by `duration`.
+        pub fn advance(&self, duration: Duration) {
+            self.elapsed.send_modify(|elapsed| *elapsed += duration);
+        }
+
+        /// The durations of all the sleeps so far, in order.
+        pub fn sleeps(&self) -> Vec<Duration> {
+            self.sleeps.lock().unwrap().clone()
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            self.start + *self.elapsed.borrow()
+        }
+
+        fn system_now(&self) -> SystemTime {
+            self.start_system + *self.elapsed.borrow()
+        }
+
+        fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+            self.sleeps.lock().unwrap().push(duration);
+            let mut elapsed = self.elapsed.subscribe();
+            let end = *elapsed.borrow() + duration;
+            Box::pin(async move {
+                // The clock outlives the sleeps in the tests.
+                let _ = elapsed.wait_for(|elapsed| *elapsed >= end).await;
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::FutureExt;
+
+    #[test]
+    fn test_fake_clock() {
+        let clock = FakeClock::new();
+        let (start, start_system) = (clock.now(), clock.system_now());
+        let mut short = clock.sleep(Duration::from_secs(1));
+        let mut long = clock.sleep(Duration::from_secs(10));
+        assert!((&mut short).now_or_never().is_none());
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(clock.now() - start, Duration::from_secs(5));
+        assert_eq!(
+            clock.system_now().duration_since(start_system).unwrap(),
+            Duration::from_secs(5)
+        );
+        assert!(short.now_or_never().is_some());
+        assert!((&mut long).now_or_never().is_none());
+        // A sleep starts when it is made, not when it is polled.
+        let later = clock.sleep(Duration::from_secs(5));
+        clock.advance(Duration::from_secs(5));
+        assert!(long.now_or_never().is_some());
+        assert!(later.now_or_never().is_some());
+        assert_eq!(clock.sleeps(), [1, 10, 5].map(Duration::from_secs).to_vec());
+    }
+
+    #[test]
+    #[cfg(feature = "s3")]
+    fn test_skewed_clock() {
+        let fake = FakeClock::new();
+        let clock = SkewedClock::new(fake.clone(), "the server");
+        assert!(!clock.measured());
+        assert_eq!(clock.system_now(), fake.system_now());
+
+        // A difference within the latency isn't a skew.
+        assert!(!clock.observe(fake.system_now() + Duration::from_secs(2)));
+        assert!(clock.measured());
+        assert_eq!(clock.system_now(), fake.system_now());
+
+        // The local clock is an hour behind, then ahead.
+        let hour = Duration::from_secs(60 * 60);
+        assert!(clock.observe(fake.system_now() + hour));
+        assert_eq!(clock.system_now(), fake.system_now() + hour);
+        fake.advance(Duration::from_secs(5));
+        assert_eq!(clock.system_now(), fake.system_now() + hour);
+        assert!(clock.observe(fake.system_now() - hour));
+        assert_eq!(clock.system_now(), fake.system_now() - hour);
+        assert!(!clock.observe(fake.system_now() - hour));
+
+        // The durations aren't affected.
+        assert_eq!(clock.now(), fake.now());
+        assert!(clock.observe(fake.system_now()));
+        assert_eq!(clock.system_now(), fake.system_now());
+    }
+}
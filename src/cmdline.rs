@@ -12,11 +12,12 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::ccache;
 use crate::errors::*;
 use clap::{error::ErrorKind, Arg, ArgAction, ArgGroup, ValueEnum};
 use std::env;
 use std::ffi::OsString;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use which::which_in;
 
@@ -26,6 +27,10 @@ const ENV_VAR_INTERNAL_START_SERVER: &str = "SCCACHE_START_SERVER";
 pub enum StatsFormat {
     Text,
     Json,
+    /// The `ServerInfo` encoded with `ServerInfo::to_bincode`.
+    Bincode,
+    /// The metrics of `ServerInfo::to_prometheus`.
+    Prometheus,
 }
 
 impl StatsFormat {
@@ -33,6 +38,8 @@ impl StatsFormat {
         match self {
             Self::Text => "text",
             Self::Json => "json",
+            Self::Bincode => "bincode",
+            Self::Prometheus => "prometheus",
         }
     }
 }
@@ -44,6 +51,8 @@ impl FromStr for StatsFormat {
         match s {
             "text" => Ok(Self::Text),
             "json" => Ok(Self::Json),
+            "bincode" => Ok(Self::Bincode),
+            "prometheus" => Ok(Self::Prometheus),
             _ => bail!("Unrecognized stats format: {:?}", s),
         }
     }
@@ -63,14 +72,64 @@ pub enum Command {
     InternalStartServer,
     /// Start background server as a subprocess.
     StartServer,
+    /// Run the server in the foreground until it is signaled to stop, for a
+    /// service manager.
+    ForegroundServer,
+    /// Install the server as a Windows service.
+    InstallService,
+    /// Stop and remove the Windows service.
+    UninstallService,
+    /// Run the server as the Windows service, as the service control manager
+    /// starts it.
+    RunService,
     /// Stop background server.
     StopServer,
+    /// Wait for the pending cache writes of the server.
+    Flush,
+    /// Compile small programs twice and check the second compilations are
+    /// cache hits.
+    SelfTest,
     /// Zero cache statistics and exit.
     ZeroStats,
-    /// Show the status of the distributed client.
-    DistStatus,
+    /// Show the status of the distributed compilation scheduler.
+    DistStatus {
+        /// Whether to print the result as JSON.
+        #[cfg_attr(not(feature = "dist-client"), allow(dead_code))]
+        json: bool,
+    },
     /// Perform a login to authenticate for distributed compilation.
     DistAuth,
+    /// Copy the cache entries listed in a file from the remote cache to the
+    /// local disk cache.
+    Prefetch(PathBuf),
+    /// Write all the entries of the cache to an archive.
+    Export(PathBuf),
+    /// Add the entries of an archive to the cache.
+    Import(PathBuf),
+    /// List the entries of the cache whose key starts with a prefix, with
+    /// their size and age.
+    CacheList(String),
+    /// Remove the entry of the cache with a key.
+    CacheRemove(String),
+    /// Train a zstd dictionary from the entries of the cache.
+    TrainDict(PathBuf),
+    /// Check the configuration and that the configured services can be
+    /// reached.
+    CheckConfig,
+    /// Remove the entries of the local disk cache, as `ccache -C`.
+    ClearCache,
+    /// Set the size of the local disk cache in the configuration file, as
+    /// `ccache -M`.
+    SetMaxCacheSize(u64),
+    /// Show the version, as `ccache --version`.
+    Version,
+    /// Run the commands of the options of `ccache`, in turn.
+    Ccache(Vec<Command>),
+    /// Show where the cache is and the configuration in effect.
+    Info {
+        /// Whether to print the result as JSON.
+        json: bool,
+    },
     /// Package a toolchain for distributed compilation (executable, out)
     PackageToolchain(PathBuf, PathBuf),
     /// Run a compiler command.
@@ -83,6 +142,25 @@ pub enum Command {
         cwd: PathBuf,
         /// The environment variables to use for execution.
         env_vars: Vec<(OsString, OsString)>,
+        /// Where to add the stats of the compilation, without a server.
+        stats_on_exit: Option<PathBuf>,
+    },
+    /// Show the hash key of a compiler command, without running it.
+    Explain {
+        /// The binary to execute.
+        exe: OsString,
+        /// The commandline arguments to pass to `exe`.
+        cmdline: Vec<OsString>,
+        /// The directory in which to execute the command.
+        cwd: PathBuf,
+        /// The environment variables to use for execution.
+        env_vars: Vec<(OsString, OsString)>,
+        /// Whether to show the inputs of the hash key, with `--explain`.
+        inputs: bool,
+        /// Where to write the outputs on a cache hit, with `--extract-to`.
+        extract_to: Option<PathBuf>,
+        /// Whether to print the result as JSON.
+        json: bool,
     },
     DebugPreprocessorCacheEntries,
 }
@@ -131,12 +209,30 @@ fn get_clap_command() -> clap::Command {
             flag_infer_long("start-server")
                 .help("start background server")
                 .action(ArgAction::SetTrue),
+            flag_infer_long("foreground")
+                .help("run the server in the foreground until it receives SIGTERM or Ctrl-C, e.g. as a systemd service")
+                .action(ArgAction::SetTrue),
+            flag_infer_long("install-service")
+                .help("install the server as a Windows service, started at boot and restarted when it crashes")
+                .action(ArgAction::SetTrue),
+            flag_infer_long("uninstall-service")
+                .help("stop and remove the Windows service")
+                .action(ArgAction::SetTrue),
+            flag_infer_long("run-service")
+                .hide(true)
+                .action(ArgAction::SetTrue),
             flag_infer_long("debug-preprocessor-cache")
                 .help("show all preprocessor cache entries")
                 .action(ArgAction::SetTrue),
             flag_infer_long("stop-server")
                 .help("stop background server")
                 .action(ArgAction::SetTrue),
+            flag_infer_long("flush")
+                .help("wait for the cache writes of the server to finish, e.g. before shutting down a CI container")
+                .action(ArgAction::SetTrue),
+            flag_infer_long("selftest")
+                .help("compile small C, C++ and Rust programs twice and check the second compilations are cache hits")
+                .action(ArgAction::SetTrue),
             flag_infer_long_and_short("zero-stats")
                 .help("zero statistics counters")
                 .action(ArgAction::SetTrue),
@@ -144,15 +240,66 @@ fn get_clap_command() -> clap::Command {
                 .help("authenticate for distributed compilation")
                 .action(ArgAction::SetTrue),
             flag_infer_long("dist-status")
-                .help("show status of the distributed client")
+                .help("show the status of the distributed compilation scheduler and its servers")
                 .action(ArgAction::SetTrue),
             flag_infer_long("package-toolchain")
                 .help("package toolchain for distributed compilation")
                 .value_parser(clap::value_parser!(PathBuf))
                 .num_args(2)
                 .value_names(["EXE", "OUT"]),
+            flag_infer_long("prefetch")
+                .help(
+                    "copy the cache entries listed in FILE from the remote cache to the local one",
+                )
+                .value_name("FILE")
+                .value_parser(clap::value_parser!(PathBuf)),
+            flag_infer_long("export")
+                .help("write all the entries of the cache to the archive FILE")
+                .value_name("FILE")
+                .value_parser(clap::value_parser!(PathBuf)),
+            flag_infer_long("import")
+                .help("add the entries of the archive FILE to the cache")
+                .value_name("FILE")
+                .value_parser(clap::value_parser!(PathBuf)),
+            flag_infer_long("cache-list")
+                .help("list the entries of the cache, those whose key starts with PREFIX if given, with their size and age")
+                .value_name("PREFIX")
+                .num_args(0..=1)
+                .default_missing_value(""),
+            flag_infer_long("cache-remove")
+                .help("remove the entry KEY from the cache")
+                .value_name("KEY"),
+            flag_infer_long("train-dict")
+                .help("train a zstd dictionary for SCCACHE_ZSTD_DICT from the entries of the cache, and write it to FILE")
+                .value_name("FILE")
+                .value_parser(clap::value_parser!(PathBuf)),
+            flag_infer_long("check-config")
+                .help("check the configuration and that the cache can be reached, without writing to it")
+                .action(ArgAction::SetTrue),
+            flag_infer_long("info")
+                .help("show where the cache is and the configuration in effect")
+                .action(ArgAction::SetTrue),
+            flag_infer_long("explain")
+                .help("show the cache key of a compiler command without running it")
+                .requires("CMD")
+                .action(ArgAction::SetTrue),
+            flag_infer_long("dump-stats-on-exit")
+                .help("add the stats of the compilation to FILE, when compiling without a server")
+                .value_name("FILE")
+                .value_parser(clap::value_parser!(PathBuf))
+                .requires("CMD")
+                .conflicts_with_all(["explain", "extract-to"]),
+            flag_infer_long("extract-to")
+                .help("on a cache hit, write the outputs of a compiler command into DIR instead of running it, and show its cache key")
+                .value_name("DIR")
+                .value_parser(clap::value_parser!(PathBuf))
+                .requires("CMD"),
+            flag_infer_long("json")
+                .help("print the output of --explain, --extract-to, --dist-status or --info as JSON")
+                .requires("json_output")
+                .action(ArgAction::SetTrue),
             flag_infer_long("stats-format")
-                .help("set output format of statistics")
+                .help("set output format of statistics: text, json, bincode or prometheus")
                 .value_name("FMT")
                 .value_parser(clap::value_parser!(StatsFormat))
                 .default_value(StatsFormat::default().as_str()),
@@ -170,13 +317,28 @@ fn get_clap_command() -> clap::Command {
                     "show-stats",
                     "show-adv-stats",
                     "start-server",
+                    "foreground",
+                    "install-service",
+                    "uninstall-service",
+                    "run-service",
                     "stop-server",
+                    "flush",
+                    "selftest",
                     "zero-stats",
                     "package-toolchain",
+                    "prefetch",
+                    "export",
+                    "import",
+                    "cache-list",
+                    "cache-remove",
+                    "train-dict",
+                    "check-config",
+                    "info",
                     "CMD",
                 ])
                 .required(true),
         )
+        .group(ArgGroup::new("json_output").args(["explain", "extract-to", "dist-status", "info"]).multiple(true))
 }
 
 /// Parse the commandline args into a `Result<Command>` to execute.
@@ -190,6 +352,26 @@ pub fn try_parse() -> Result<Command> {
     let internal_start_server = env::var(ENV_VAR_INTERNAL_START_SERVER).as_deref() == Ok("1");
     let mut args: Vec<_> = env::args_os().collect();
 
+    // Invoked as `ccache`, or as `sccache --ccache-compat`, the ccache options
+    // are understood. A symlink named `ccache` is only seen in the arguments,
+    // `current_exe` resolves it.
+    if !internal_start_server {
+        let symlinked = args.first().is_some_and(|arg| {
+            Path::new(arg)
+                .file_stem()
+                .is_some_and(|stem| stem.eq_ignore_ascii_case("ccache"))
+        });
+        let compat = args.get(1).is_some_and(|arg| arg == "--ccache-compat");
+        if compat {
+            args.remove(1);
+        }
+        if symlinked || compat {
+            if let Some(commands) = ccache::parse_options(&args[1..])? {
+                return Ok(Command::Ccache(commands));
+            }
+        }
+    }
+
     if !internal_start_server {
         if let Ok(exe) = env::current_exe() {
             match exe
@@ -199,6 +381,8 @@ pub fn try_parse() -> Result<Command> {
             {
                 // If the executable has its standard name, do nothing.
                 Some(ref e) if e == env!("CARGO_PKG_NAME") => {}
+                // As `ccache`, the arguments are a compiler command, see above.
+                Some(ref e) if e == "ccache" => {}
                 // Otherwise, if it was copied/hardlinked under a different $name, act
                 // as if it were invoked with `sccache $name`, but avoid $name resolving
                 // to ourselves again if it's in the PATH.
@@ -264,16 +448,48 @@ pub fn try_parse() -> Result<Command> {
                 Ok(Command::ShowStats(fmt, true))
             } else if matches.get_flag("start-server") {
                 Ok(Command::StartServer)
+            } else if matches.get_flag("foreground") {
+                Ok(Command::ForegroundServer)
+            } else if matches.get_flag("install-service") {
+                Ok(Command::InstallService)
+            } else if matches.get_flag("uninstall-service") {
+                Ok(Command::UninstallService)
+            } else if matches.get_flag("run-service") {
+                Ok(Command::RunService)
             } else if matches.get_flag("debug-preprocessor-cache") {
                 Ok(Command::DebugPreprocessorCacheEntries)
             } else if matches.get_flag("stop-server") {
                 Ok(Command::StopServer)
+            } else if matches.get_flag("flush") {
+                Ok(Command::Flush)
+            } else if matches.get_flag("selftest") {
+                Ok(Command::SelfTest)
             } else if matches.get_flag("zero-stats") {
                 Ok(Command::ZeroStats)
             } else if matches.get_flag("dist-auth") {
                 Ok(Command::DistAuth)
             } else if matches.get_flag("dist-status") {
-                Ok(Command::DistStatus)
+                Ok(Command::DistStatus {
+                    json: matches.get_flag("json"),
+                })
+            } else if let Some(keys) = matches.get_one::<PathBuf>("prefetch") {
+                Ok(Command::Prefetch(keys.clone()))
+            } else if let Some(archive) = matches.get_one::<PathBuf>("export") {
+                Ok(Command::Export(archive.clone()))
+            } else if let Some(archive) = matches.get_one::<PathBuf>("import") {
+                Ok(Command::Import(archive.clone()))
+            } else if let Some(prefix) = matches.get_one::<String>("cache-list") {
+                Ok(Command::CacheList(prefix.clone()))
+            } else if let Some(key) = matches.get_one::<String>("cache-remove") {
+                Ok(Command::CacheRemove(key.clone()))
+            } else if let Some(dictionary) = matches.get_one::<PathBuf>("train-dict") {
+                Ok(Command::TrainDict(dictionary.clone()))
+            } else if matches.get_flag("check-config") {
+                Ok(Command::CheckConfig)
+            } else if matches.get_flag("info") {
+                Ok(Command::Info {
+                    json: matches.get_flag("json"),
+                })
             } else if matches.contains_id("package-toolchain") {
                 let mut toolchain_values = matches
                     .get_many("package-toolchain")
@@ -299,7 +515,6 @@ pub fn try_parse() -> Result<Command> {
                         && k != "PWD"
                         && k != "HOST"
                         && k != "RPM_BUILD_ROOT"
-                        && k != "SOURCE_DATE_EPOCH"
                         && k != "RPM_PACKAGE_RELEASE"
                         && k != "MINICOM"
                         && k != "DESTDIR"
@@ -312,11 +527,25 @@ pub fn try_parse() -> Result<Command> {
                     .cloned()
                     .collect::<Vec<OsString>>();
                 match cmd.as_slice() {
+                    [exe, cmdline @ ..]
+                        if matches.get_flag("explain") || matches.contains_id("extract-to") =>
+                    {
+                        Ok(Command::Explain {
+                            exe: exe.to_owned(),
+                            cmdline: cmdline.to_owned(),
+                            cwd,
+                            env_vars,
+                            inputs: matches.get_flag("explain"),
+                            extract_to: matches.get_one::<PathBuf>("extract-to").cloned(),
+                            json: matches.get_flag("json"),
+                        })
+                    }
                     [exe, cmdline @ ..] => Ok(Command::Compile {
                         exe: exe.to_owned(),
                         cmdline: cmdline.to_owned(),
                         cwd,
                         env_vars,
+                        stats_on_exit: matches.get_one::<PathBuf>("dump-stats-on-exit").cloned(),
                     }),
                     _ => unreachable!("clap should enforce at least one value in cmd"),
                 }
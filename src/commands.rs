@@ -12,29 +12,47 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::cache::storage_from_config;
-use crate::client::{connect_to_server, connect_with_retry, ServerConnection};
+use crate::admin;
+use crate::cache::archive;
+use crate::cache::dictionary::{self, Training};
+use crate::cache::prefetch::{self, PrefetchReport};
+use crate::cache::{disk_cache_from_config, storage_from_config, EntryInfo};
+use crate::client::{connect_to_admin, connect_to_server, connect_with_retry, ServerConnection};
 use crate::cmdline::{Command, StatsFormat};
-use crate::compiler::ColorMode;
-use crate::config::{default_disk_cache_dir, Config};
+use crate::compile;
+use crate::compiler::{forwarded_env, get_cache_salt, ColorMode, COMPILER_DIGEST_ENV_VAR};
+use crate::config::{
+    self, default_disk_cache_dir, CacheModeConfig, Config, ConfigSource, ConfigSources,
+};
+use crate::deadline;
 use crate::jobserver::Client;
-use crate::mock_command::{CommandChild, CommandCreatorSync, ProcessCommandCreator, RunCommand};
-use crate::protocol::{Compile, CompileFinished, CompileResponse, Request, Response};
-use crate::server::{self, DistInfo, ServerInfo, ServerStartup, ServerStats};
-use crate::util::daemonize;
+use crate::mock_command::{
+    CommandChild, CommandCreatorSync, ProcessCommandCreator, RunCommand, SPAWNED_PROGRAM_ENV_VAR,
+};
+use crate::protocol::{
+    Compile, CompileError, CompileFinished, CompileResponse, ExplainResponse, FlushSummary,
+    HashKeyExplanation, Request, Response,
+};
+use crate::selftest;
+use crate::server::{self, ServerInfo, ServerStartup, ServerStats};
+use crate::service;
+use crate::umask;
+use crate::util::{daemonize, Digest};
 use byteorder::{BigEndian, ByteOrder};
 use fs::{File, OpenOptions};
 use fs_err as fs;
 use is_terminal::IsTerminal;
 use log::Level::Trace;
+use number_prefix::NumberPrefix;
+use serde::Serialize;
 use std::env;
 use std::ffi::{OsStr, OsString};
-use std::io::{self, Write};
+use std::io::{self, Read, Seek, Write};
 #[cfg(unix)]
 use std::os::unix::process::ExitStatusExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use strip_ansi_escapes::Writer;
 use tokio::io::AsyncReadExt;
 use tokio::runtime::Runtime;
@@ -51,12 +69,29 @@ const SERVER_STARTUP_TIMEOUT: Duration = Duration::from_millis(10000);
 
 /// Get the port on which the server should listen.
 fn get_port() -> u16 {
+    if let Some(addr) = server::get_server_addr() {
+        return addr.port();
+    }
     env::var("SCCACHE_SERVER_PORT")
         .ok()
         .and_then(|s| s.parse().ok())
         .unwrap_or(DEFAULT_PORT)
 }
 
+/// Warn that other hosts may reach the server listening on `port`, unless it
+/// listens on loopback or requires a token.
+fn warn_if_exposed(port: u16) {
+    let addr = server::listen_addr(port);
+    if !addr.ip().is_loopback() && server::get_server_token().is_none() {
+        eprintln!(
+            "sccache: warning: The server listens on {}, which other hosts may reach, \
+             without a SCCACHE_SERVER_TOKEN: anyone who connects can run \
+             compilations as this user and read and write the cache.",
+            addr
+        );
+    }
+}
+
 /// Check if ignoring all response errors
 fn ignore_all_server_io_errors() -> bool {
     match env::var("SCCACHE_IGNORE_SERVER_IO_ERROR") {
@@ -295,6 +330,15 @@ fn run_server_process(startup_timeout: Option<Duration>) -> Result<ServerStartup
     })
 }
 
+/// Run the server of the Windows service, until `shutdown` resolves.
+#[cfg(windows)]
+pub(crate) fn run_service_server(shutdown: impl std::future::Future) -> Result<()> {
+    if env::var("SCCACHE_ERROR_LOG").is_ok() {
+        redirect_error_log(create_error_log()?)?;
+    }
+    server::start_foreground_server(&Config::load()?, get_port(), shutdown)
+}
+
 /// Attempt to connect to an sccache server listening on `port`, or start one if no server is running.
 fn connect_or_start_server(
     port: u16,
@@ -311,7 +355,10 @@ fn connect_or_start_server(
             // the server.
             match run_server_process(startup_timeout)? {
                 ServerStartup::Ok { port: actualport } => {
-                    if port != actualport {
+                    warn_if_exposed(actualport);
+                    // With port 0, the OS picks the port, which the client
+                    // finds in the discovery file.
+                    if port != 0 && port != actualport {
                         // bail as the next connect_with_retry will fail
                         bail!(
                             "sccache: Listening on port {} instead of {}",
@@ -346,6 +393,52 @@ pub fn request_zero_stats(mut conn: ServerConnection) -> Result<()> {
     }
 }
 
+/// Send a `ListEntries` request to the server, print the entries of the
+/// cache whose key starts with `prefix` to `out`, one per line, as the server
+/// lists them, and return how many there are.
+pub fn request_list_entries(
+    mut conn: ServerConnection,
+    out: &mut dyn Write,
+    prefix: &str,
+) -> Result<u64> {
+    debug!("request_list_entries");
+    let response = conn
+        .request(Request::ListEntries(prefix.to_owned()))
+        .context("Failed to send data to or receive data from server")?;
+    if !matches!(response, Response::ListingEntries) {
+        bail!("Unexpected server response!")
+    }
+    let now = SystemTime::now();
+    let mut count = 0;
+    loop {
+        match conn.read_one_response()? {
+            Response::Entries(entries) => {
+                for entry in &entries {
+                    print_cache_entry(out, entry, now)?;
+                }
+                count += entries.len() as u64;
+            }
+            Response::EntriesListed => return Ok(count),
+            Response::CacheFailed(reason) => bail!("{}", reason),
+            _ => bail!("Unexpected server response!"),
+        }
+    }
+}
+
+/// Send a `RemoveEntry` request to the server, and return whether the cache
+/// had an entry for `key`.
+pub fn request_remove_entry(mut conn: ServerConnection, key: &str) -> Result<bool> {
+    debug!("request_remove_entry");
+    let response = conn
+        .request(Request::RemoveEntry(key.to_owned()))
+        .context("Failed to send data to or receive data from server")?;
+    match response {
+        Response::EntryRemoved(removed) => Ok(removed),
+        Response::CacheFailed(reason) => bail!("{}", reason),
+        _ => bail!("Unexpected server response!"),
+    }
+}
+
 /// Send a `GetStats` request to the server, and return the `ServerInfo` request if successful.
 pub fn request_stats(mut conn: ServerConnection) -> Result<ServerInfo> {
     debug!("request_stats");
@@ -359,40 +452,62 @@ pub fn request_stats(mut conn: ServerConnection) -> Result<ServerInfo> {
     }
 }
 
-/// Send a `DistStatus` request to the server, and return `DistStatus` if successful.
-pub fn request_dist_status(mut conn: ServerConnection) -> Result<DistInfo> {
-    debug!("request_dist_status");
+/// Send a `Flush` request to the server, and return what it waited for once
+/// the pending cache writes finished.
+pub fn request_flush(mut conn: ServerConnection) -> Result<FlushSummary> {
+    debug!("request_flush");
     let response = conn
-        .request(Request::DistStatus)
+        .request(Request::Flush)
         .context("Failed to send data to or receive data from server")?;
-    if let Response::DistStatus(info) = response {
-        Ok(info)
+    if let Response::Flushed(summary) = response {
+        Ok(summary)
     } else {
         bail!("Unexpected server response!")
     }
 }
 
-/// Send a `Shutdown` request to the server, and return the `ServerInfo` contained within the response if successful.
-pub fn request_shutdown(mut conn: ServerConnection) -> Result<ServerInfo> {
+/// Send a `Shutdown` request to the server and wait for it to close the
+/// connection, returning the final `ServerInfo` and the number of abandoned
+/// compilations contained within the response if successful.
+pub fn request_shutdown(mut conn: ServerConnection) -> Result<(ServerInfo, usize)> {
     debug!("request_shutdown");
     //TODO: better error mapping
     let response = conn
         .request(Request::Shutdown)
         .context("Failed to send data to or receive data from server")?;
-    if let Response::ShuttingDown(stats) = response {
-        Ok(*stats)
+    if let Response::ShuttingDown(stats, abandoned) = response {
+        conn.wait_closed()
+            .context("Failed to wait for the server to shut down")?;
+        Ok((*stats, abandoned))
     } else {
         bail!("Unexpected server response!")
     }
 }
 
+/// The digest of the compiler `exe` sent to the server with
+/// `SCCACHE_COMPILER_DIGEST=1` in `env_vars`, the environment of the client.
+fn compiler_digest(exe: &Path, env_vars: &[(OsString, OsString)]) -> Result<Option<String>> {
+    let enabled = env_vars
+        .iter()
+        .any(|(k, v)| k == COMPILER_DIGEST_ENV_VAR && v == "1");
+    if !enabled {
+        return Ok(None);
+    }
+    let file = File::open(exe).with_context(|| format!("failed to open the compiler {:?}", exe))?;
+    Digest::reader_sync(file)
+        .with_context(|| format!("failed to hash the compiler {:?}", exe))
+        .map(Some)
+}
+
 /// Send a `Compile` request to the server, and return the server response if successful.
-fn request_compile<W, X, Y>(
+pub(crate) fn request_compile<W, X, Y>(
     conn: &mut ServerConnection,
     exe: W,
     args: &[X],
     cwd: Y,
     env_vars: Vec<(OsString, OsString)>,
+    deadline: Option<SystemTime>,
+    stdin: Option<&mut dyn Read>,
 ) -> Result<CompileResponse>
 where
     W: AsRef<Path>,
@@ -401,15 +516,21 @@ where
 {
     let req = Request::Compile(Compile {
         exe: exe.as_ref().to_owned().into(),
+        exe_digest: compiler_digest(exe.as_ref(), &env_vars)?,
         cwd: cwd.as_ref().to_owned().into(),
         args: args.iter().map(|a| a.as_ref().to_owned()).collect(),
-        env_vars,
+        env_vars: forwarded_env(args, env_vars),
+        stdin: stdin.as_ref().map(|_| vec![]),
+        deadline,
+        umask: umask::current(),
     });
     trace!("request_compile: {:?}", req);
     //TODO: better error mapping?
-    let response = conn
-        .request(req)
-        .context("Failed to send data to or receive data from server")?;
+    let response = match stdin {
+        Some(stdin) => conn.request_with_stdin(req, stdin),
+        None => conn.request(req),
+    }
+    .context("Failed to send data to or receive data from server")?;
     if let Response::Compile(response) = response {
         Ok(response)
     } else {
@@ -417,6 +538,165 @@ where
     }
 }
 
+/// Drop the leading invocations of `sccache` from the compiler command `exe`
+/// `cmdline`. Cargo runs `sccache sccache rustc ...` for the workspace members
+/// when sccache is both its `RUSTC_WRAPPER` and its `RUSTC_WORKSPACE_WRAPPER`,
+/// and the compilation should go through the server only once.
+fn strip_nested_sccache(
+    sccache: &Path,
+    mut exe: OsString,
+    mut cmdline: Vec<OsString>,
+    cwd: &Path,
+) -> (OsString, Vec<OsString>) {
+    let is_sccache = |exe: &OsStr| {
+        which_in(exe, env::var_os("PATH"), cwd)
+            .ok()
+            .and_then(|path| path.canonicalize().ok())
+            .is_some_and(|path| path == sccache)
+    };
+    while !cmdline.is_empty() && is_sccache(&exe) {
+        trace!("Dropping the nested sccache {:?}", exe);
+        exe = cmdline.remove(0);
+    }
+    (exe, cmdline)
+}
+
+/// Fail if sccache spawned this process as the compiler `spawned`, and either
+/// it is sccache or this process would spawn it again with `compiler`, i.e.
+/// the compiler resolves back to sccache, rather than recursing until
+/// something else gives up.
+fn check_recursion(sccache: &Path, compiler: &Path, spawned: Option<&OsStr>) -> Result<()> {
+    let Some(spawned) = spawned.and_then(|spawned| Path::new(spawned).canonicalize().ok()) else {
+        return Ok(());
+    };
+    if spawned == sccache || compiler.canonicalize().is_ok_and(|path| path == spawned) {
+        bail!(
+            "sccache invoked itself; check your CC/compiler path: {} resolves to sccache",
+            compiler.display()
+        );
+    }
+    Ok(())
+}
+
+/// Add the stats of `info` to those in the file at `path`, which the
+/// compilations without a server share.
+fn add_stats(path: &Path, mut info: ServerInfo) -> Result<()> {
+    let mut lock_path = path.as_os_str().to_owned();
+    lock_path.push(".lock");
+    let _lock = lock_file(Path::new(&lock_path))?;
+    match fs::read(path) {
+        Ok(previous) => match serde_json::from_slice::<ServerInfo>(&previous) {
+            Ok(previous) => info.stats.merge(previous.stats),
+            Err(e) => warn!("Replacing the invalid stats in {}: {}", path.display(), e),
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    server::write_stats(path, &info)
+}
+
+/// Open `path`, holding an exclusive lock on it until the file is closed.
+#[cfg(unix)]
+fn lock_file(path: &Path) -> Result<File> {
+    use std::os::unix::io::AsRawFd;
+
+    let file = OpenOptions::new().create(true).write(true).open(path)?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(io::Error::last_os_error()).context("failed to lock the stats");
+    }
+    Ok(file)
+}
+
+/// Concurrent compilations may lose some of the counts without the lock.
+#[cfg(not(unix))]
+fn lock_file(path: &Path) -> Result<File> {
+    Ok(OpenOptions::new().create(true).write(true).open(path)?)
+}
+
+/// Send an `Explain` request to the server, and return the server response if successful.
+#[allow(clippy::too_many_arguments)]
+fn request_explain(
+    conn: &mut ServerConnection,
+    exe: &Path,
+    args: Vec<OsString>,
+    cwd: &Path,
+    env_vars: Vec<(OsString, OsString)>,
+    extract_to: Option<&Path>,
+    stdin: Option<&mut dyn Read>,
+) -> Result<ExplainResponse> {
+    let req = Request::Explain(
+        Compile {
+            exe: exe.to_owned().into(),
+            exe_digest: compiler_digest(exe, &env_vars)?,
+            cwd: cwd.to_owned().into(),
+            env_vars: forwarded_env(&args, env_vars),
+            args,
+            stdin: stdin.as_ref().map(|_| vec![]),
+            deadline: None,
+            umask: None,
+        },
+        extract_to.map(|dir| cwd.join(dir).into()),
+    );
+    trace!("request_explain: {:?}", req);
+    let response = match stdin {
+        Some(stdin) => conn.request_with_stdin(req, stdin),
+        None => conn.request(req),
+    }
+    .context("Failed to send data to or receive data from server")?;
+    if let Response::Explain(response) = response {
+        Ok(response)
+    } else {
+        bail!("Unexpected response from server")
+    }
+}
+
+/// Print the hash key of `explanation`, whether it is cached and the outputs
+/// which were extracted, and the inputs of the key if `inputs`.
+fn print_explanation(
+    out: &mut dyn Write,
+    explanation: &HashKeyExplanation,
+    inputs: bool,
+) -> Result<()> {
+    writeln!(out, "Hash key: {}", explanation.key)?;
+    writeln!(
+        out,
+        "Cached: {}",
+        if explanation.cached { "yes" } else { "no" }
+    )?;
+    if !explanation.extracted.is_empty() {
+        writeln!(out, "Extracted:")?;
+        for path in &explanation.extracted {
+            writeln!(out, "    {}", path)?;
+        }
+    }
+    if !inputs {
+        return Ok(());
+    }
+    writeln!(out, "Inputs:")?;
+    let width = explanation
+        .inputs
+        .iter()
+        .map(|input| input.kind.len())
+        .max()
+        .unwrap_or_default();
+    for input in &explanation.inputs {
+        writeln!(out, "    {:<width$}  {}", input.kind, input.value)?;
+    }
+    Ok(())
+}
+
+/// Exit code when sccache itself failed to run the compilation.
+const EXIT_INTERNAL_ERROR: i32 = -2;
+/// Exit code when storing or restoring the cache entry failed.
+const EXIT_CACHE_ERROR: i32 = -4;
+/// Exit code when the compiler could not be executed, like shells use.
+const EXIT_COMPILER_NOT_FOUND: i32 = 127;
+/// Exit code when the compilation was killed past its deadline, like
+/// `timeout` uses.
+const EXIT_DEADLINE_EXCEEDED: i32 = 124;
+/// Exit code when the distributed compilation failed with an HTTP error.
+const EXIT_DIST_ERROR: i32 = 1;
+
 /// Return the signal that caused a process to exit from `status`.
 #[cfg(unix)]
 #[allow(dead_code)]
@@ -431,10 +711,64 @@ fn status_signal(_status: process::ExitStatus) -> Option<i32> {
     None
 }
 
+/// Whether to keep the colors of compiler output written to a stream, given
+/// whether it's a terminal and the variables of the environment.
+fn keep_colors(
+    color_mode: ColorMode,
+    is_terminal: bool,
+    env_var: impl Fn(&str) -> Option<OsString>,
+) -> bool {
+    // https://no-color.org/ and https://bixense.com/clicolors/
+    let no_color = env_var("NO_COLOR").is_some_and(|v| !v.is_empty());
+    let force_color = env_var("CLICOLOR_FORCE").is_some_and(|v| !v.is_empty() && v != "0");
+    // rustc uses the `termcolor` crate which explicitly checks for TERM=="dumb", so
+    // match that behavior here.
+    let dumb_term = env_var("TERM").is_some_and(|v| v == "dumb");
+    match color_mode {
+        // The compiler options explicitly requested color output, or not.
+        ColorMode::On => true,
+        ColorMode::Off => false,
+        ColorMode::Auto => !no_color && (force_color || (is_terminal && !dumb_term)),
+    }
+}
+
+/// Whether the compiler reads its input from stdin, like with `cc -c -`.
+fn reads_stdin(cmdline: &[OsString]) -> bool {
+    cmdline
+        .iter()
+        .enumerate()
+        .any(|(i, arg)| arg == "-" && (i == 0 || cmdline[i - 1] != "-o"))
+}
+
+/// Whether the compiler writes its output to stdout, like with `cc -o -`.
+fn writes_stdout(cmdline: &[OsString]) -> bool {
+    cmdline
+        .windows(2)
+        .any(|args| args[0] == "-o" && args[1] == "-")
+}
+
+/// A reader copying what it reads from `reader` to `copy`.
+struct TeeReader<'a> {
+    reader: &'a mut dyn Read,
+    copy: &'a mut std::fs::File,
+}
+
+impl Read for TeeReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.copy.write_all(&buf[..n])?;
+        Ok(n)
+    }
+}
+
 /// Handle `response`, the output from running a compile on the server.
 /// Return the compiler exit status.
+///
+/// When `output_to_stdout`, stdout is the output of the compiler, which is
+/// written as is.
 fn handle_compile_finished(
     response: CompileFinished,
+    output_to_stdout: bool,
     stdout: &mut dyn Write,
     stderr: &mut dyn Write,
 ) -> Result<i32> {
@@ -445,15 +779,7 @@ fn handle_compile_finished(
         data: &[u8],
         color_mode: ColorMode,
     ) -> Result<()> {
-        // rustc uses the `termcolor` crate which explicitly checks for TERM=="dumb", so
-        // match that behavior here.
-        let dumb_term = env::var("TERM").map(|v| v == "dumb").unwrap_or(false);
-        // If the compiler options explicitly requested color output, or if this output stream
-        // is a terminal and the compiler options didn't explicitly request non-color output,
-        // then write the compiler output directly.
-        if color_mode == ColorMode::On
-            || (!dumb_term && stream.is_terminal() && color_mode != ColorMode::Off)
-        {
+        if keep_colors(color_mode, stream.is_terminal(), |var| env::var_os(var)) {
             writer.write_all(data)?;
         } else {
             // Remove escape codes (and thus colors) while writing.
@@ -462,15 +788,18 @@ fn handle_compile_finished(
         }
         Ok(())
     }
-    // It might be nice if the server sent stdout/stderr as the process
-    // ran, but then it would have to also save them in the cache as
-    // interleaved streams to really make it work.
-    write_output(
-        std::io::stdout(),
-        stdout,
-        &response.stdout,
-        response.color_mode,
-    )?;
+    // The output the server streamed was written already, see
+    // `read_compile_finished`.
+    if output_to_stdout {
+        stdout.write_all(&response.stdout)?;
+    } else {
+        write_output(
+            std::io::stdout(),
+            stdout,
+            &response.stdout,
+            response.color_mode,
+        )?;
+    }
     write_output(
         std::io::stderr(),
         stderr,
@@ -478,6 +807,43 @@ fn handle_compile_finished(
         response.color_mode,
     )?;
 
+    match response.error {
+        Some(CompileError::CompilerNotFound(e)) => {
+            writeln!(
+                stderr,
+                "sccache: error: failed to execute the compiler: {}",
+                e
+            )?;
+            return Ok(EXIT_COMPILER_NOT_FOUND);
+        }
+        Some(CompileError::Cache(e)) => {
+            writeln!(stderr, "sccache: error: cache failure: {}", e)?;
+            return Ok(EXIT_CACHE_ERROR);
+        }
+        Some(CompileError::Internal(e)) => {
+            writeln!(stderr, "sccache: encountered fatal error")?;
+            writeln!(stderr, "sccache: error: {}", e)?;
+            return Ok(EXIT_INTERNAL_ERROR);
+        }
+        Some(CompileError::DeadlineExceeded) => {
+            writeln!(
+                stderr,
+                "sccache: error: the compilation didn't finish before its deadline"
+            )?;
+            return Ok(EXIT_DEADLINE_EXCEEDED);
+        }
+        Some(CompileError::Dist(e)) => {
+            writeln!(
+                stderr,
+                "sccache: error: distributed compilation failed: {}",
+                e
+            )?;
+            return Ok(EXIT_DIST_ERROR);
+        }
+        Some(CompileError::Preprocessing) => trace!("preprocessing failed"),
+        Some(CompileError::CompilerFailed) | None => {}
+    }
+
     if let Some(ret) = response.retcode {
         trace!("compiler exited with status {}", ret);
         Ok(ret)
@@ -490,13 +856,58 @@ fn handle_compile_finished(
     }
 }
 
+/// Wait for the response finishing the compilation started on `conn`,
+/// writing the output of the compiler the server streams meanwhile to
+/// `stdout` and `stderr`, as it comes, see `handle_compile_finished` for
+/// `output_to_stdout`. Return it.
+fn read_compile_finished(
+    conn: &mut ServerConnection,
+    output_to_stdout: bool,
+    stdout: &mut dyn Write,
+    stderr: &mut dyn Write,
+) -> Result<Response> {
+    let mut response = conn.read_one_response()?;
+    let color_mode = match response {
+        Response::CompileStdout(_, color_mode) | Response::CompileStderr(_, color_mode) => {
+            color_mode
+        }
+        response => return Ok(response),
+    };
+    // An escape code may be split between two chunks, the colors are removed
+    // from all of them at once.
+    fn live<'a>(writer: &'a mut dyn Write, keep_colors: bool) -> Box<dyn Write + 'a> {
+        if keep_colors {
+            Box::new(writer)
+        } else {
+            Box::new(Writer::new(writer))
+        }
+    }
+    let keep = |is_terminal| keep_colors(color_mode, is_terminal, |var| env::var_os(var));
+    let mut stdout = live(stdout, output_to_stdout || keep(io::stdout().is_terminal()));
+    let mut stderr = live(stderr, keep(io::stderr().is_terminal()));
+    loop {
+        let (live, chunk) = match &response {
+            Response::CompileStdout(chunk, _) => (&mut stdout, chunk),
+            Response::CompileStderr(chunk, _) => (&mut stderr, chunk),
+            _ => return Ok(response),
+        };
+        live.write_all(chunk)?;
+        live.flush()?;
+        response = conn.read_one_response()?;
+    }
+}
+
 /// Handle `response`, the response from sending a `Compile` request to the server. Return the compiler exit status.
 ///
 /// If the server returned `CompileStarted`, wait for a `CompileFinished` and
 /// print the results.
 ///
+/// If the server returned `SplitInputs`, send the compilation of each source
+/// file in turn, and return the first failing status.
+///
 /// If the server returned `UnhandledCompile`, run the compilation command
-/// locally using `creator` and return the result.
+/// locally using `creator` and return the result. The compiler is given
+/// `stdin` if it was read already.
 #[allow(clippy::too_many_arguments)]
 fn handle_compile_response<T>(
     mut creator: T,
@@ -506,6 +917,9 @@ fn handle_compile_response<T>(
     exe: &Path,
     cmdline: Vec<OsString>,
     cwd: &Path,
+    env_vars: &[(OsString, OsString)],
+    deadline: Option<SystemTime>,
+    stdin: Option<std::fs::File>,
     stdout: &mut dyn Write,
     stderr: &mut dyn Write,
 ) -> Result<i32>
@@ -516,9 +930,10 @@ where
         CompileResponse::CompileStarted => {
             debug!("Server sent CompileStarted");
             // Wait for CompileFinished.
-            match conn.read_one_response() {
+            let output_to_stdout = writes_stdout(&cmdline);
+            match read_compile_finished(conn, output_to_stdout, stdout, stderr) {
                 Ok(Response::CompileFinished(result)) => {
-                    return handle_compile_finished(result, stdout, stderr)
+                    return handle_compile_finished(result, output_to_stdout, stdout, stderr)
                 }
                 Ok(_) => bail!("unexpected response from server"),
                 Err(e) => {
@@ -549,6 +964,33 @@ where
             debug!("Server sent UnsupportedCompiler: {:?}", s);
             bail!("Compiler not supported: {:?}", s);
         }
+        CompileResponse::SplitInputs(compilations) => {
+            debug!("Server sent SplitInputs: {:?}", compilations);
+            let mut status = 0;
+            for cmdline in compilations {
+                let res =
+                    request_compile(conn, exe, &cmdline, cwd, env_vars.to_vec(), deadline, None)?;
+                let code = handle_compile_response(
+                    creator.clone(),
+                    runtime,
+                    conn,
+                    res,
+                    exe,
+                    cmdline,
+                    cwd,
+                    env_vars,
+                    deadline,
+                    None,
+                    stdout,
+                    stderr,
+                )?;
+                // Like the compiler, carry on with the other source files.
+                if status == 0 {
+                    status = code;
+                }
+            }
+            return Ok(status);
+        }
         CompileResponse::UnhandledCompile => {
             debug!("Server sent UnhandledCompile");
         }
@@ -556,6 +998,9 @@ where
 
     let mut cmd = creator.new_command_sync(exe);
     cmd.args(&cmdline).current_dir(cwd);
+    if let Some(stdin) = stdin {
+        cmd.stdin(process::Stdio::from(stdin));
+    }
     if log_enabled!(Trace) {
         trace!("running command: {:?}", cmd);
     }
@@ -592,6 +1037,7 @@ pub fn do_compile<T>(
     cwd: &Path,
     path: Option<OsString>,
     env_vars: Vec<(OsString, OsString)>,
+    stdin: &mut dyn Read,
     stdout: &mut dyn Write,
     stderr: &mut dyn Write,
 ) -> Result<i32>
@@ -600,14 +1046,463 @@ where
 {
     trace!("do_compile");
     let exe_path = which_in(exe, path, cwd)?;
-    let res = request_compile(&mut conn, &exe_path, &cmdline, cwd, env_vars)?;
+    let deadline = deadline::from_env(SystemTime::now(), &env_vars)?;
+    // Stdin is sent to the server as it is read, and copied to a file for the
+    // compiler to read, should it run here.
+    let mut stdin_copy = if reads_stdin(&cmdline) {
+        Some(tempfile::tempfile().context("failed to create a copy of stdin")?)
+    } else {
+        None
+    };
+    let mut tee = stdin_copy.as_mut().map(|copy| TeeReader {
+        reader: stdin,
+        copy,
+    });
+    let res = match request_compile(
+        &mut conn,
+        &exe_path,
+        &cmdline,
+        cwd,
+        env_vars.clone(),
+        deadline,
+        tee.as_mut().map(|tee| tee as &mut dyn Read),
+    ) {
+        Ok(res) => res,
+        Err(e) if e.root_cause().is::<RequestTooLarge>() => {
+            warn!("Running the compiler without caching: {:#}", e);
+            // Stdin wasn't read, and the compiler reads it as is.
+            stdin_copy = None;
+            CompileResponse::UnhandledCompile
+        }
+        Err(e) => return Err(e),
+    };
+    if let Some(copy) = stdin_copy.as_mut() {
+        copy.rewind()?;
+    }
     handle_compile_response(
-        creator, runtime, &mut conn, res, &exe_path, cmdline, cwd, stdout, stderr,
+        creator, runtime, &mut conn, res, &exe_path, cmdline, cwd, &env_vars, deadline, stdin_copy,
+        stdout, stderr,
     )
 }
 
+/// Run the compilation `exe cmdline` in this process, caching it without a
+/// server, and print its output. Return the compiler exit status.
+fn compile_without_server(
+    exe: OsString,
+    cmdline: Vec<OsString>,
+    cwd: &Path,
+    env_vars: Vec<(OsString, OsString)>,
+    stats_on_exit: Option<&Path>,
+    stdout: &mut dyn Write,
+    stderr: &mut dyn Write,
+) -> Result<i32> {
+    trace!("compile_without_server");
+    let storage = compile::StorageBuilder::from_env()?.build()?;
+    if server::get_error_if_cache_unreachable() {
+        storage.probe()?;
+    }
+    let command: Vec<_> = std::iter::once(exe).chain(cmdline).collect();
+    let result = compile::run_cached(&command, &env_vars, cwd, &storage);
+    if let Some(path) = stats_on_exit {
+        // The stats are still worth having when the compilation failed.
+        if let Err(e) = add_stats(path, storage.info()?) {
+            warn!("Failed to write the stats to {}: {:#}", path.display(), e);
+        }
+    }
+    let result = result?;
+    debug!("compiled without a server: {:?}", result.outcome);
+    stdout.write_all(&result.output.stdout)?;
+    stderr.write_all(&result.output.stderr)?;
+    match result.output.status.code() {
+        Some(ret) => Ok(ret),
+        None => {
+            println!("sccache: Compiler killed by signal");
+            Ok(-2)
+        }
+    }
+}
+
+/// Send an `Explain` request to the sccache server `conn`, and print the
+/// hash key it computed to `stdout`, along with its inputs if `inputs`. On a
+/// cache hit, the outputs are written into `extract_to`, if any, instead of
+/// their paths.
+///
+/// The first entry in `cmdline` will be looked up in `path` if it is not
+/// an absolute path.
+#[allow(clippy::too_many_arguments)]
+pub fn do_explain(
+    mut conn: ServerConnection,
+    exe: &Path,
+    cmdline: Vec<OsString>,
+    cwd: &Path,
+    path: Option<OsString>,
+    env_vars: Vec<(OsString, OsString)>,
+    inputs: bool,
+    extract_to: Option<&Path>,
+    json: bool,
+    stdout: &mut dyn Write,
+) -> Result<()> {
+    trace!("do_explain");
+    let exe_path = which_in(exe, path, cwd)?;
+    let mut stdin = io::stdin();
+    let stdin = reads_stdin(&cmdline).then_some(&mut stdin as &mut dyn Read);
+    match request_explain(
+        &mut conn, &exe_path, cmdline, cwd, env_vars, extract_to, stdin,
+    )? {
+        ExplainResponse::Explained(explanation) => {
+            if json {
+                serde_json::to_writer(&mut *stdout, &explanation)?;
+                writeln!(stdout)?;
+            } else {
+                print_explanation(stdout, &explanation, inputs)?;
+            }
+            Ok(())
+        }
+        ExplainResponse::NotCacheable(why) => bail!("Compilation is not cacheable: {}", why),
+        ExplainResponse::NotCompilation => bail!("Command is not a compilation"),
+        ExplainResponse::UnsupportedCompiler(msg) => {
+            bail!("Compiler not supported: {}", msg.to_string_lossy())
+        }
+        ExplainResponse::Failed(e) => bail!("Failed to compute the hash key: {}", e),
+    }
+}
+
+fn print_prefetch_report(out: &mut dyn Write, report: &PrefetchReport) -> Result<()> {
+    writeln!(
+        out,
+        "Prefetched {} entries, {} already cached locally, {} missing from the remote cache",
+        report.fetched,
+        report.already_cached,
+        report.missing.len()
+    )?;
+    for key in &report.missing {
+        writeln!(out, "missing: {}", key)?;
+    }
+    for (key, e) in &report.failed {
+        writeln!(out, "failed: {}: {}", key, e)?;
+    }
+    Ok(())
+}
+
+fn print_training(out: &mut dyn Write, training: &Training, path: &Path) -> Result<()> {
+    writeln!(
+        out,
+        "Trained the zstd dictionary {} of {} bytes from {} objects of {} entries, written to {}",
+        training.id,
+        training.dictionary.len(),
+        training.samples,
+        training.entries,
+        path.display()
+    )?;
+    writeln!(
+        out,
+        "The {} bytes of the objects left out of the training compress to {} bytes with it, \
+         {} bytes without it: {:.2}x smaller",
+        training.measured_size,
+        training.compressed_size_with_dictionary,
+        training.compressed_size,
+        training.improvement()
+    )?;
+    Ok(())
+}
+
+fn print_cache_entry(out: &mut dyn Write, entry: &EntryInfo, now: SystemTime) -> Result<()> {
+    let size = match NumberPrefix::binary(entry.size as f64) {
+        NumberPrefix::Standalone(bytes) => format!("{} bytes", bytes),
+        NumberPrefix::Prefixed(prefix, n) => format!("{:.1} {}B", n, prefix),
+    };
+    // An entry written after `now`, by a clock ahead of ours, is new.
+    let age = match entry.modified {
+        Some(modified) => fmt_age(now.duration_since(modified).unwrap_or_default()),
+        None => "-".to_owned(),
+    };
+    write!(out, "{}  {:>10}  {:>4}", entry.key, size, age)?;
+    if let Some(build_id) = &entry.build_id {
+        write!(out, "  {}", build_id)?;
+    }
+    writeln!(out)?;
+    Ok(())
+}
+
+/// Format `age` in its largest whole unit, e.g. `3d` or `42s`.
+fn fmt_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m", secs / 60),
+        3600..=86399 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
+#[cfg(feature = "dist-client")]
+fn print_dist_status(
+    out: &mut dyn Write,
+    scheduler_url: &crate::config::HTTPUrl,
+    status: &crate::dist::SchedulerStatusResult,
+) -> Result<()> {
+    writeln!(out, "Scheduler: {}", scheduler_url.to_url())?;
+    writeln!(
+        out,
+        "Servers: {} ({} CPUs)",
+        status.num_servers, status.num_cpus
+    )?;
+    writeln!(
+        out,
+        "Jobs: {} running, {} queued",
+        status.running, status.queued
+    )?;
+    for server in &status.servers {
+        let toolchain_jobs = server.toolchain_hits + server.toolchain_misses;
+        let toolchain_hit_rate = if toolchain_jobs == 0 {
+            "-".to_owned()
+        } else {
+            format!(
+                "{:.0}%",
+                server.toolchain_hits as f64 * 100.0 / toolchain_jobs as f64
+            )
+        };
+        writeln!(
+            out,
+            "  {:<24}{:>4} CPUs{:>6} jobs  load {:.2}  toolchain hits {}",
+            server.server_id.addr(),
+            server.num_cpus,
+            server.jobs_assigned,
+            server.jobs_assigned as f64 / server.num_cpus as f64,
+            toolchain_hit_rate
+        )?;
+    }
+    if status.recent_jobs.is_empty() {
+        writeln!(out, "Recent jobs: none")?;
+    } else {
+        writeln!(out, "Recent jobs:")?;
+        for job in &status.recent_jobs {
+            writeln!(
+                out,
+                "  {:<8}{:<24}{}",
+                job.job_id,
+                job.server_id.addr(),
+                job.outcome
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// How long to wait for the scheduler when checking the configuration.
+#[cfg(feature = "dist-client")]
+const CHECK_CONFIG_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Write one line of the `--check-config` report to `out`, and return whether
+/// the check passed.
+pub(crate) fn report_check(
+    out: &mut dyn Write,
+    subsystem: &str,
+    res: Result<String>,
+) -> Result<bool> {
+    match &res {
+        Ok(detail) => writeln!(out, "{:<8}{:<8}{}", subsystem, "ok", detail)?,
+        Err(e) => writeln!(out, "{:<8}{:<8}{:#}", subsystem, "FAILED", e)?,
+    }
+    Ok(res.is_ok())
+}
+
+/// Check the scheduler of distributed compilation can be reached.
+#[cfg(feature = "dist-client")]
+fn check_scheduler(config: &Config) -> Option<Result<String>> {
+    let url = config.dist.scheduler_url.as_ref()?.to_url();
+    let check = || {
+        let addrs = url
+            .socket_addrs(|| None)
+            .with_context(|| format!("failed to resolve the scheduler address {}", url))?;
+        let addr = addrs
+            .first()
+            .with_context(|| format!("the scheduler address {} doesn't resolve", url))?;
+        std::net::TcpStream::connect_timeout(addr, CHECK_CONFIG_TIMEOUT).with_context(|| {
+            format!(
+                "failed to connect to the scheduler at {}, check that it is running \
+                 and reachable from this machine",
+                url
+            )
+        })?;
+        Ok(format!("scheduler at {}", url))
+    };
+    Some(check())
+}
+
+#[cfg(not(feature = "dist-client"))]
+fn check_scheduler(config: &Config) -> Option<Result<String>> {
+    config.dist.scheduler_url.as_ref().map(|_| {
+        Err(anyhow!(
+            "a scheduler is configured, but this sccache was built without \
+             distributed compilation support"
+        ))
+    })
+}
+
+/// Check the configuration loaded in `config` and that the services it
+/// configures can be reached, writing a report to `out`. Nothing is written
+/// to the cache.
+///
+/// Returns 1 if any check failed.
+fn check_config(config: Result<Config>, out: &mut dyn Write) -> Result<i32> {
+    let config = match config {
+        Ok(config) => {
+            report_check(out, "config", Ok("loaded".to_owned()))?;
+            config
+        }
+        Err(e) => {
+            report_check(out, "config", Err(e))?;
+            return Ok(1);
+        }
+    };
+
+    let runtime = Runtime::new()?;
+    let cache = storage_from_config(&config, runtime.handle()).and_then(|storage| {
+        runtime
+            .block_on(storage.probe())
+            .with_context(|| storage.location())?;
+        Ok(storage.location())
+    });
+    let mut ok = report_check(out, "cache", cache)?;
+
+    match check_scheduler(&config) {
+        Some(res) => ok &= report_check(out, "dist", res)?,
+        None => writeln!(out, "dist    skipped not configured")?,
+    }
+    Ok(if ok { 0 } else { 1 })
+}
+
+/// Where the cache is and how it is configured, as shown by `--info`.
+#[derive(Debug, Serialize)]
+struct CacheInfo {
+    config_file: PathBuf,
+    config_file_found: bool,
+    remote: Option<RemoteCacheInfo>,
+    local: LocalCacheInfo,
+    /// e.g. `zstd level 3`
+    compression: String,
+    /// The salt of the cache keys, `SCCACHE_CACHE_SALT`, if any.
+    salt: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct RemoteCacheInfo {
+    backend: &'static str,
+    source: ConfigSource,
+    /// The configuration of the backend, with its secrets redacted.
+    config: serde_json::Value,
+}
+
+#[derive(Debug, Serialize)]
+struct LocalCacheInfo {
+    dir: PathBuf,
+    max_size: u64,
+    read_only: bool,
+    source: ConfigSource,
+}
+
+fn cache_info(config: &Config, sources: &ConfigSources) -> CacheInfo {
+    CacheInfo {
+        config_file: sources.file.clone(),
+        config_file_found: sources.file_found,
+        remote: config.cache.as_ref().map(|cache| RemoteCacheInfo {
+            backend: cache.name(),
+            source: sources.cache.unwrap_or(ConfigSource::File),
+            config: cache.to_redacted_json(),
+        }),
+        local: LocalCacheInfo {
+            dir: config.fallback_cache.dir.clone(),
+            max_size: config.fallback_cache.size,
+            read_only: config.fallback_cache.rw_mode == CacheModeConfig::ReadOnly,
+            source: sources.disk,
+        },
+        compression: config.entries.compression.to_string(),
+        salt: get_cache_salt(),
+    }
+}
+
+fn print_cache_info(out: &mut dyn Write, info: &CacheInfo) -> Result<()> {
+    writeln!(
+        out,
+        "Config file: {}{}",
+        info.config_file.display(),
+        if info.config_file_found {
+            ""
+        } else {
+            " (not found)"
+        }
+    )?;
+    match &info.remote {
+        Some(remote) => {
+            writeln!(
+                out,
+                "Remote cache: {} (from {})",
+                remote.backend, remote.source
+            )?;
+            if let serde_json::Value::Object(fields) = &remote.config {
+                for (name, value) in fields {
+                    if !value.is_null() {
+                        writeln!(out, "  {}: {}", name, value)?;
+                    }
+                }
+            }
+        }
+        None => writeln!(out, "Remote cache: none")?,
+    }
+    writeln!(
+        out,
+        "Local cache: {} (from {})",
+        info.local.dir.display(),
+        info.local.source
+    )?;
+    let size = match NumberPrefix::binary(info.local.max_size as f64) {
+        NumberPrefix::Standalone(bytes) => format!("{} bytes", bytes),
+        NumberPrefix::Prefixed(prefix, n) => format!("{:.0} {}B", n, prefix),
+    };
+    writeln!(out, "  max size: {}", size)?;
+    if info.local.read_only {
+        writeln!(out, "  read only")?;
+    }
+    writeln!(out, "Compression: {}", info.compression)?;
+    if let Some(salt) = &info.salt {
+        writeln!(out, "Cache key salt: {}", salt)?;
+    }
+    Ok(())
+}
+
 /// Run `cmd` and return the process exit status.
 pub fn run_command(cmd: Command) -> Result<i32> {
+    // The options of `ccache` are run in turn, until one fails.
+    if let Command::Ccache(cmds) = cmd {
+        trace!("Command::Ccache");
+        for cmd in cmds {
+            let status = run_command(cmd)?;
+            if status != 0 {
+                return Ok(status);
+            }
+        }
+        return Ok(0);
+    }
+
+    // Checking the configuration reports a broken config instead of failing.
+    if let Command::CheckConfig = cmd {
+        trace!("Command::CheckConfig");
+        return check_config(Config::load(), &mut io::stdout());
+    }
+
+    if let Command::Info { json } = cmd {
+        trace!("Command::Info");
+        let (config, sources) = Config::load_with_sources()?;
+        let info = cache_info(&config, &sources);
+        if json {
+            serde_json::to_writer(&mut io::stdout(), &info)?;
+        } else {
+            print_cache_info(&mut io::stdout(), &info)?;
+        }
+        return Ok(0);
+    }
+
     // Config isn't required for all commands, but if it's broken then we should flag
     // it early and loudly.
     let config = &Config::load()?;
@@ -616,7 +1511,7 @@ pub fn run_command(cmd: Command) -> Result<i32> {
     match cmd {
         Command::ShowStats(fmt, advanced) => {
             trace!("Command::ShowStats({:?})", fmt);
-            let stats = match connect_to_server(get_port()) {
+            let stats = match connect_to_admin(get_port()) {
                 Ok(srv) => request_stats(srv).context("failed to get stats from server")?,
                 // If there is no server, spawning a new server would start with zero stats
                 // anyways, so we can just return (mostly) empty stats directly.
@@ -629,6 +1524,8 @@ pub fn run_command(cmd: Command) -> Result<i32> {
             match fmt {
                 StatsFormat::Text => stats.print(advanced),
                 StatsFormat::Json => serde_json::to_writer(&mut io::stdout(), &stats)?,
+                StatsFormat::Bincode => io::stdout().write_all(&stats.to_bincode()?)?,
+                StatsFormat::Prometheus => print!("{}", stats.to_prometheus()),
             }
         }
         Command::DebugPreprocessorCacheEntries => {
@@ -662,6 +1559,35 @@ pub fn run_command(cmd: Command) -> Result<i32> {
             }
             server::start_server(config, get_port())?;
         }
+        Command::ForegroundServer => {
+            trace!("Command::ForegroundServer");
+            if env::var("SCCACHE_ERROR_LOG").is_ok() {
+                redirect_error_log(create_error_log()?)?;
+            }
+            warn_if_exposed(get_port());
+            server::start_foreground_server(config, get_port(), service::shutdown_signal())?;
+        }
+        #[cfg(windows)]
+        Command::InstallService => {
+            trace!("Command::InstallService");
+            service::install().context("failed to install the service")?;
+            println!("sccache: Installed and started the sccache service");
+        }
+        #[cfg(windows)]
+        Command::UninstallService => {
+            trace!("Command::UninstallService");
+            service::uninstall().context("failed to uninstall the service")?;
+            println!("sccache: Uninstalled the sccache service");
+        }
+        #[cfg(windows)]
+        Command::RunService => {
+            trace!("Command::RunService");
+            service::run()?;
+        }
+        #[cfg(not(windows))]
+        Command::InstallService | Command::UninstallService | Command::RunService => {
+            bail!("Services are only supported on Windows, run `sccache --foreground` from the service manager instead")
+        }
         Command::StartServer => {
             trace!("Command::StartServer");
             println!("sccache: Starting the server...");
@@ -672,6 +1598,7 @@ pub fn run_command(cmd: Command) -> Result<i32> {
                     if port != DEFAULT_PORT {
                         println!("sccache: Listening on port {}", port);
                     }
+                    warn_if_exposed(port);
                 }
                 ServerStartup::TimedOut => bail!("Timed out waiting for server startup"),
                 ServerStartup::AddrInUse => bail!("Server startup failed: Address in use"),
@@ -681,13 +1608,91 @@ pub fn run_command(cmd: Command) -> Result<i32> {
         Command::StopServer => {
             trace!("Command::StopServer");
             println!("Stopping sccache server...");
-            let server = connect_to_server(get_port()).context("couldn't connect to server")?;
-            let stats = request_shutdown(server)?;
+            let server = connect_to_admin(get_port()).context("couldn't connect to server")?;
+            let (stats, abandoned) = request_shutdown(server)?;
             stats.print(false);
+            if abandoned > 0 {
+                eprintln!(
+                    "sccache: {} compilations didn't finish in time and were abandoned",
+                    abandoned
+                );
+            }
         }
+        Command::Flush => {
+            trace!("Command::Flush");
+            let conn = connect_to_admin(get_port()).context("couldn't connect to server")?;
+            let summary = request_flush(conn).context("couldn't flush the cache writes")?;
+            println!(
+                "Flushed {} pending cache writes: {} written, {} failed, {} timed out",
+                summary.pending, summary.written, summary.failed, summary.timed_out
+            );
+        }
+        Command::SelfTest => {
+            trace!("Command::SelfTest");
+            connect_or_start_server(get_port(), startup_timeout)?;
+            let dir = tempfile::Builder::new()
+                .prefix("sccache-selftest")
+                .tempdir()?;
+            let env_vars: Vec<_> = env::vars_os().collect();
+            return selftest::run(
+                get_port(),
+                selftest::PROGRAMS,
+                dir.path(),
+                env::var_os("PATH"),
+                &env_vars,
+                &mut io::stdout(),
+            );
+        }
+        Command::ClearCache => {
+            trace!("Command::ClearCache");
+            // The server has the cache open, and its size in memory.
+            if let Ok(server) = connect_to_admin(get_port()) {
+                println!("Stopping sccache server...");
+                request_shutdown(server)?;
+            }
+            let dir = &config.fallback_cache.dir;
+            if dir.exists() {
+                for entry in fs::read_dir(dir)? {
+                    let path = entry?.path();
+                    if path.is_dir() {
+                        fs::remove_dir_all(&path)?;
+                    } else {
+                        fs::remove_file(&path)?;
+                    }
+                }
+            }
+            println!("Cleared the local disk cache in {}", dir.display());
+            if config.cache.is_some() {
+                println!("The remote cache isn't cleared");
+            }
+        }
+        Command::SetMaxCacheSize(size) => {
+            trace!("Command::SetMaxCacheSize({})", size);
+            let path = config::set_disk_cache_size(size)?;
+            let size = match NumberPrefix::binary(size as f64) {
+                NumberPrefix::Standalone(bytes) => format!("{} bytes", bytes),
+                NumberPrefix::Prefixed(prefix, n) => format!("{:.0} {}B", n, prefix),
+            };
+            println!("Set the cache size to {} in {}", size, path.display());
+            // The local cache of the environment replaces the one of the file.
+            for var in ["SCCACHE_DIR", "SCCACHE_CACHE_SIZE"] {
+                if env::var_os(var).is_some() {
+                    eprintln!("sccache: warning: {} is set, and overrides it", var);
+                }
+            }
+            println!("It takes effect when the server is restarted");
+        }
+        Command::Version => {
+            trace!("Command::Version");
+            println!("sccache {}", env!("CARGO_PKG_VERSION"));
+        }
+        Command::Ccache(_) => unreachable!("ccache options are run first"),
         Command::ZeroStats => {
             trace!("Command::ZeroStats");
-            let conn = connect_or_start_server(get_port(), startup_timeout)?;
+            let mut conn = connect_or_start_server(get_port(), startup_timeout)?;
+            if admin::addr().is_some() {
+                conn = connect_to_admin(get_port()).context("couldn't connect to server")?;
+            }
             request_zero_stats(conn).context("couldn't zero stats on server")?;
             eprintln!("Statistics zeroed.");
         }
@@ -747,12 +1752,136 @@ pub fn run_command(cmd: Command) -> Result<i32> {
         Command::DistAuth => bail!(
             "Distributed compilation not compiled in, please rebuild with the dist-client feature"
         ),
-        Command::DistStatus => {
+        #[cfg(feature = "dist-client")]
+        Command::DistStatus { json } => {
             trace!("Command::DistStatus");
-            let srv = connect_or_start_server(get_port(), startup_timeout)?;
-            let status =
-                request_dist_status(srv).context("failed to get dist-status from server")?;
-            serde_json::to_writer(&mut io::stdout(), &status)?;
+            // Talk to the scheduler directly, so this works without a server.
+            let scheduler_url = config
+                .dist
+                .scheduler_url
+                .as_ref()
+                .context("No scheduler is configured for distributed compilation")?;
+            let auth_token = config
+                .dist
+                .auth
+                .token()
+                .context("could not load client auth token, run |sccache --dist-auth|")?;
+            let runtime = Runtime::new()?;
+            let status = runtime
+                .block_on(crate::dist::http::get_scheduler_status(
+                    &scheduler_url.to_url(),
+                    &auth_token,
+                ))
+                .with_context(|| {
+                    format!(
+                        "Failed to get the status of the scheduler at {}",
+                        scheduler_url.to_url()
+                    )
+                })?;
+            if json {
+                let mut value = serde_json::to_value(&status)?;
+                value["scheduler_url"] = serde_json::to_value(scheduler_url)?;
+                serde_json::to_writer(&mut io::stdout(), &value)?;
+                println!();
+            } else {
+                print_dist_status(&mut io::stdout(), scheduler_url, &status)?;
+            }
+        }
+        #[cfg(not(feature = "dist-client"))]
+        Command::DistStatus { .. } => bail!(
+            "Distributed compilation not compiled in, please rebuild with the dist-client feature"
+        ),
+        Command::CheckConfig | Command::Info { .. } => {
+            unreachable!("handled before loading the config")
+        }
+        Command::Prefetch(keys_file) => {
+            trace!("Command::Prefetch({})", keys_file.display());
+            if config.cache.is_none() {
+                bail!("No remote cache is configured, there is nothing to prefetch from");
+            }
+            // The server only reads the local disk cache as a tier of the
+            // remote one.
+            if !config.fallback_cache.local_tier {
+                bail!(
+                    "SCCACHE_LOCAL_TIER isn't set, the server wouldn't read the prefetched \
+                     entries from the local disk cache"
+                );
+            }
+            // The server keeps an index of the local disk cache, which the
+            // entries copied behind its back would be missing from.
+            if connect_to_server(get_port()).is_ok() {
+                bail!(
+                    "The sccache server is running, stop it with `sccache --stop-server` \
+                     before prefetching into its local disk cache"
+                );
+            }
+            let keys = prefetch::parse_keys(&fs::read_to_string(&keys_file)?);
+            let runtime = Runtime::new()?;
+            let remote = storage_from_config(config, runtime.handle())?;
+            let local = disk_cache_from_config(config, runtime.handle())?;
+            let report = runtime.block_on(prefetch::prefetch(&*remote, &local, keys));
+            print_prefetch_report(&mut io::stdout(), &report)?;
+            if !report.failed.is_empty() {
+                return Ok(1);
+            }
+        }
+        Command::Export(path) => {
+            trace!("Command::Export({})", path.display());
+            let runtime = Runtime::new()?;
+            let storage = storage_from_config(config, runtime.handle())?;
+            let out = io::BufWriter::new(File::create(&path)?);
+            let count = runtime.block_on(archive::export(&*storage, out))?;
+            println!("Exported {} entries from {}", count, storage.location());
+        }
+        Command::Import(path) => {
+            trace!("Command::Import({})", path.display());
+            let runtime = Runtime::new()?;
+            let storage = storage_from_config(config, runtime.handle())?;
+            let input = io::BufReader::new(File::open(&path)?);
+            let report = runtime.block_on(archive::import(&*storage, input))?;
+            println!(
+                "Imported {} entries to {}, {} already present",
+                report.imported,
+                storage.location(),
+                report.skipped
+            );
+        }
+        Command::CacheList(prefix) => {
+            trace!("Command::CacheList({})", prefix);
+            // Through the server, which lists the cache it runs with.
+            let mut conn = connect_or_start_server(get_port(), startup_timeout)?;
+            if admin::addr().is_some() {
+                conn = connect_to_admin(get_port()).context("couldn't connect to server")?;
+            }
+            let mut out = io::BufWriter::new(io::stdout());
+            let count = request_list_entries(conn, &mut out, &prefix)
+                .context("couldn't list the cache entries")?;
+            out.flush()?;
+            debug!("Listed {} entries", count);
+        }
+        Command::CacheRemove(key) => {
+            trace!("Command::CacheRemove({})", key);
+            // Through the server, so that its index of the local disk cache
+            // and its memory cache forget the entry as well.
+            let mut conn = connect_or_start_server(get_port(), startup_timeout)?;
+            if admin::addr().is_some() {
+                conn = connect_to_admin(get_port()).context("couldn't connect to server")?;
+            }
+            if request_remove_entry(conn, &key).context("couldn't remove the cache entry")? {
+                println!("Removed {} from the cache", key);
+            } else {
+                println!("No entry {} in the cache", key);
+                return Ok(1);
+            }
+        }
+        Command::TrainDict(path) => {
+            trace!("Command::TrainDict({})", path.display());
+            let runtime = Runtime::new()?;
+            let storage = storage_from_config(config, runtime.handle())?;
+            let training =
+                runtime.block_on(dictionary::train(&*storage, dictionary::DICTIONARY_SIZE))?;
+            fs::write(&path, &training.dictionary)?;
+            print_training(&mut io::stdout(), &training, &path)?;
         }
         #[cfg(feature = "dist-client")]
         Command::PackageToolchain(executable, out) => {
@@ -779,13 +1908,66 @@ pub fn run_command(cmd: Command) -> Result<i32> {
         Command::PackageToolchain(_executable, _out) => bail!(
             "Toolchain packaging not compiled in, please rebuild with the dist-client feature"
         ),
+        Command::Explain {
+            exe,
+            cmdline,
+            cwd,
+            env_vars,
+            inputs,
+            extract_to,
+            json,
+        } => {
+            trace!("Command::Explain {{ {:?}, {:?}, {:?} }}", exe, cmdline, cwd);
+            let conn = connect_or_start_server(get_port(), startup_timeout)?;
+            do_explain(
+                conn,
+                exe.as_ref(),
+                cmdline,
+                &cwd,
+                env::var_os("PATH"),
+                env_vars,
+                inputs,
+                extract_to.as_deref(),
+                json,
+                &mut io::stdout(),
+            )?;
+        }
         Command::Compile {
             exe,
             cmdline,
             cwd,
             env_vars,
+            stats_on_exit,
         } => {
             trace!("Command::Compile {{ {:?}, {:?}, {:?} }}", exe, cmdline, cwd);
+            let (exe, cmdline) = match env::current_exe().and_then(|exe| exe.canonicalize()) {
+                Ok(sccache) => {
+                    let (exe, cmdline) = strip_nested_sccache(&sccache, exe, cmdline, &cwd);
+                    if let Ok(compiler) = which_in(&exe, env::var_os("PATH"), &cwd) {
+                        let spawned = env::var_os(SPAWNED_PROGRAM_ENV_VAR);
+                        check_recursion(&sccache, &compiler, spawned.as_deref())?;
+                    }
+                    (exe, cmdline)
+                }
+                Err(_) => (exe, cmdline),
+            };
+            if env::var("SCCACHE_NO_SERVER").as_deref() == Ok("1") {
+                return compile_without_server(
+                    exe,
+                    cmdline,
+                    &cwd,
+                    env_vars,
+                    stats_on_exit.as_deref(),
+                    &mut io::stdout(),
+                    &mut io::stderr(),
+                )
+                .context("failed to execute compile");
+            }
+            if stats_on_exit.is_some() {
+                warn!(
+                    "--dump-stats-on-exit only applies without a server, see SCCACHE_STATS_ON_EXIT"
+                );
+            }
             let jobserver = unsafe { Client::new() };
             let conn = connect_or_start_server(get_port(), startup_timeout)?;
             let mut runtime = Runtime::new()?;
@@ -798,6 +1980,7 @@ pub fn run_command(cmd: Command) -> Result<i32> {
                 &cwd,
                 env::var_os("PATH"),
                 env_vars,
+                &mut io::stdin(),
                 &mut io::stdout(),
                 &mut io::stderr(),
             );
@@ -807,3 +1990,270 @@ pub fn run_command(cmd: Command) -> Result<i32> {
 
     Ok(0)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::HashKeyInput;
+
+    #[test]
+    #[cfg(unix)]
+    fn test_strip_nested_sccache() {
+        let f = crate::test::utils::TestFixture::new();
+        let sccache = f.mk_bin("sccache").unwrap().canonicalize().unwrap();
+        let rustc = f.mk_bin("rustc").unwrap();
+        let args = |args: &[&OsStr]| args.iter().map(|&a| a.to_owned()).collect::<Vec<_>>();
+        let cwd = f.tempdir.path();
+
+        // As RUSTC_WRAPPER, or as RUSTC_WORKSPACE_WRAPPER alone.
+        let cmdline = args(&["--crate-name".as_ref(), "foo".as_ref()]);
+        assert_eq!(
+            strip_nested_sccache(&sccache, rustc.clone().into(), cmdline.clone(), cwd),
+            (rustc.clone().into(), cmdline.clone())
+        );
+        // As both.
+        let nested = args(&[rustc.as_os_str(), "--crate-name".as_ref(), "foo".as_ref()]);
+        assert_eq!(
+            strip_nested_sccache(&sccache, sccache.clone().into(), nested, cwd),
+            (rustc.clone().into(), cmdline)
+        );
+        // sccache alone is left to fail as a compiler.
+        assert_eq!(
+            strip_nested_sccache(&sccache, sccache.clone().into(), vec![], cwd),
+            (sccache.clone().into(), vec![])
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_check_recursion() {
+        let f = crate::test::utils::TestFixture::new();
+        let sccache = f.mk_bin("sccache").unwrap().canonicalize().unwrap();
+        let gcc = f.mk_bin("gcc").unwrap();
+        let ld = f.mk_bin("ld").unwrap();
+        assert!(check_recursion(&sccache, &gcc, None).is_ok());
+        // sccache spawned a real compiler, which runs sccache for another
+        // program.
+        assert!(check_recursion(&sccache, &ld, Some(gcc.as_os_str())).is_ok());
+        // sccache spawned the compiler again, e.g. a wrapper script running
+        // `sccache gcc` found first in the PATH as gcc.
+        let err = check_recursion(&sccache, &gcc, Some(gcc.as_os_str()))
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            format!(
+                "sccache invoked itself; check your CC/compiler path: {} resolves to sccache",
+                gcc.display()
+            )
+        );
+        // sccache spawned itself, e.g. through a symlink.
+        let cc = f.tempdir.path().join("cc");
+        std::os::unix::fs::symlink(&sccache, &cc).unwrap();
+        assert!(check_recursion(&sccache, &ld, Some(cc.as_os_str())).is_err());
+    }
+
+    #[test]
+    fn test_print_cache_entry() {
+        let entry = EntryInfo {
+            key: "0123abcd".to_owned(),
+            size: 3 * 1024 * 1024 / 2,
+            modified: Some(SystemTime::UNIX_EPOCH),
+            build_id: Some("1c26a3a".to_owned()),
+        };
+        let mut out = vec![];
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(2 * 86400 + 5);
+        print_cache_entry(&mut out, &entry, now).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "0123abcd     1.5 MiB    2d  1c26a3a\n"
+        );
+        assert_eq!(fmt_age(Duration::from_secs(42)), "42s");
+        assert_eq!(fmt_age(Duration::from_secs(7200)), "2h");
+    }
+
+    #[test]
+    fn test_print_cache_info() {
+        let info = CacheInfo {
+            config_file: "/home/user/.config/sccache/config".into(),
+            config_file_found: false,
+            remote: Some(RemoteCacheInfo {
+                backend: "s3",
+                source: ConfigSource::Environment,
+                config: serde_json::json!({"bucket": "artifacts", "region": null}),
+            }),
+            local: LocalCacheInfo {
+                dir: "/home/user/.cache/sccache".into(),
+                max_size: 10 * 1024 * 1024 * 1024,
+                read_only: true,
+                source: ConfigSource::Default,
+            },
+            compression: "zstd level 3".to_owned(),
+            salt: Some("2026-10".to_owned()),
+        };
+        let mut out = vec![];
+        print_cache_info(&mut out, &info).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Config file: /home/user/.config/sccache/config (not found)\n\
+             Remote cache: s3 (from environment)\n\
+             \x20 bucket: \"artifacts\"\n\
+             Local cache: /home/user/.cache/sccache (from default)\n\
+             \x20 max size: 10 GiB\n\
+             \x20 read only\n\
+             Compression: zstd level 3\n\
+             Cache key salt: 2026-10\n"
+        );
+        let json = serde_json::to_value(&info).unwrap();
+        assert_eq!(json["remote"]["source"], "environment");
+        assert_eq!(json["local"]["max_size"], 10u64 * 1024 * 1024 * 1024);
+    }
+
+    #[test]
+    fn test_print_explanation() {
+        let explanation = HashKeyExplanation {
+            key: "0123abcd".to_owned(),
+            cached: true,
+            inputs: vec![
+                HashKeyInput::new("language", "c"),
+                HashKeyInput::new("argument", "-O2"),
+            ],
+            extracted: vec!["/tmp/out/foo.o".to_owned(), "/tmp/out/foo.d".to_owned()],
+        };
+        let print = |inputs| {
+            let mut out = vec![];
+            print_explanation(&mut out, &explanation, inputs).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(
+            print(true),
+            "Hash key: 0123abcd\n\
+             Cached: yes\n\
+             Extracted:\n\
+             \x20   /tmp/out/foo.o\n\
+             \x20   /tmp/out/foo.d\n\
+             Inputs:\n\
+             \x20   language  c\n\
+             \x20   argument  -O2\n"
+        );
+        // With --extract-to alone, the inputs aren't shown.
+        assert_eq!(
+            print(false),
+            "Hash key: 0123abcd\n\
+             Cached: yes\n\
+             Extracted:\n\
+             \x20   /tmp/out/foo.o\n\
+             \x20   /tmp/out/foo.d\n"
+        );
+        // Nothing is extracted on a miss.
+        let miss = HashKeyExplanation {
+            key: "0123abcd".to_owned(),
+            cached: false,
+            inputs: vec![],
+            extracted: vec![],
+        };
+        let mut out = vec![];
+        print_explanation(&mut out, &miss, false).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Hash key: 0123abcd\nCached: no\n"
+        );
+    }
+
+    #[test]
+    fn test_keep_colors() {
+        let keep = |color_mode, is_terminal, vars: &[(&str, &str)]| {
+            keep_colors(color_mode, is_terminal, |var| {
+                vars.iter()
+                    .find(|(k, _)| *k == var)
+                    .map(|(_, v)| OsString::from(v))
+            })
+        };
+        // Colors on a terminal only.
+        assert!(keep(ColorMode::Auto, true, &[]));
+        assert!(!keep(ColorMode::Auto, false, &[]));
+        assert!(!keep(ColorMode::Auto, true, &[("TERM", "dumb")]));
+        // NO_COLOR disables them, unless empty.
+        assert!(!keep(ColorMode::Auto, true, &[("NO_COLOR", "1")]));
+        assert!(keep(ColorMode::Auto, true, &[("NO_COLOR", "")]));
+        // CLICOLOR_FORCE forces them, unless empty or 0.
+        assert!(keep(ColorMode::Auto, false, &[("CLICOLOR_FORCE", "1")]));
+        assert!(keep(
+            ColorMode::Auto,
+            true,
+            &[("CLICOLOR_FORCE", "1"), ("TERM", "dumb")]
+        ));
+        assert!(!keep(ColorMode::Auto, false, &[("CLICOLOR_FORCE", "0")]));
+        assert!(!keep(ColorMode::Auto, false, &[("CLICOLOR_FORCE", "")]));
+        // NO_COLOR wins over CLICOLOR_FORCE.
+        assert!(!keep(
+            ColorMode::Auto,
+            true,
+            &[("NO_COLOR", "1"), ("CLICOLOR_FORCE", "1")]
+        ));
+        // The compiler options win over the environment.
+        assert!(keep(ColorMode::On, false, &[("NO_COLOR", "1")]));
+        assert!(!keep(ColorMode::Off, true, &[("CLICOLOR_FORCE", "1")]));
+    }
+
+    #[test]
+    fn test_stdio_args() {
+        let args = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<_>>();
+        assert!(reads_stdin(&args(&["-x", "c", "-c", "-", "-o", "foo.o"])));
+        assert!(!writes_stdout(&args(&[
+            "-x", "c", "-c", "-", "-o", "foo.o"
+        ])));
+        assert!(!reads_stdin(&args(&["-c", "foo.c", "-o", "-"])));
+        assert!(writes_stdout(&args(&["-c", "foo.c", "-o", "-"])));
+        assert!(reads_stdin(&args(&["-x", "c", "-c", "-o", "-", "-"])));
+        assert!(writes_stdout(&args(&["-x", "c", "-c", "-o", "-", "-"])));
+        assert!(!reads_stdin(&args(&["-c", "foo.c"])));
+    }
+
+    #[test]
+    fn test_handle_compile_finished_stdout() {
+        let finished = || CompileFinished {
+            retcode: Some(0),
+            stdout: b"\x1b[1mobject".to_vec(),
+            color_mode: ColorMode::Off,
+            ..Default::default()
+        };
+        // Messages lose their colors, but an object written to stdout is
+        // binary.
+        let mut stdout = vec![];
+        let mut stderr = vec![];
+        handle_compile_finished(finished(), false, &mut stdout, &mut stderr).unwrap();
+        assert_eq!(stdout, b"object");
+        let mut stdout = vec![];
+        handle_compile_finished(finished(), true, &mut stdout, &mut stderr).unwrap();
+        assert_eq!(stdout, b"\x1b[1mobject");
+    }
+
+    #[test]
+    fn test_handle_compile_finished_errors() {
+        let exit_code = |error| {
+            let finished = CompileFinished {
+                error: Some(error),
+                ..Default::default()
+            };
+            let mut stderr = vec![];
+            let code = handle_compile_finished(finished, false, &mut vec![], &mut stderr).unwrap();
+            (code, String::from_utf8(stderr).unwrap())
+        };
+        assert_eq!(
+            exit_code(CompileError::Dist("http error status: 503".to_owned())),
+            (
+                1,
+                "sccache: error: distributed compilation failed: http error status: 503\n"
+                    .to_owned()
+            )
+        );
+        assert_eq!(exit_code(CompileError::Internal("oops".to_owned())).0, -2);
+        assert_eq!(exit_code(CompileError::Cache("oops".to_owned())).0, -4);
+        assert_eq!(
+            exit_code(CompileError::CompilerNotFound("cc".to_owned())).0,
+            127
+        );
+        assert_eq!(exit_code(CompileError::DeadlineExceeded).0, 124);
+    }
+}
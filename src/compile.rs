@@ -0,0 +1,540 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Cached compilations in the caller's process, without an sccache server.
+//!
+//! The cache is configured as the sccache server's is, or in code with
+//! [`Config::builder`], see [`StorageBuilder::from_config`].
+//!
+//! ```no_run
+//! use sccache::compile::{run_cached, StorageBuilder};
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let storage = StorageBuilder::from_env()?.build()?;
+//! let command = ["cc", "-c", "foo.c", "-o", "foo.o"].map(Into::into);
+//! let env: Vec<_> = std::env::vars_os().collect();
+//! let result = run_cached(&command, &env, &std::env::current_dir()?, &storage)?;
+//! println!("{:?}", result.outcome);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::cache::{self, storage_from_config};
+use crate::compiler::{self, get_compiler_info, CacheControl, CompilerArguments};
+use crate::config::Config;
+use crate::jobserver::Client;
+use crate::mock_command::{CommandChild, CommandCreatorSync, ProcessCommandCreator, RunCommand};
+use crate::server::{LanguageFilter, ServerInfo, ServerStats};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+use tokio::runtime::Runtime;
+use which::which_in;
+
+use crate::errors::*;
+
+/// Configures a [`Storage`].
+pub struct StorageBuilder {
+    config: Config,
+}
+
+impl StorageBuilder {
+    /// Use the same configuration as the sccache server would, from the
+    /// `SCCACHE_*` environment variables and the configuration file.
+    pub fn from_env() -> Result<StorageBuilder> {
+        Ok(StorageBuilder {
+            config: Config::load()?,
+        })
+    }
+
+    /// Use a configuration loaded separately, or built with
+    /// `Config::builder()` without reading the environment.
+    pub fn from_config(config: Config) -> StorageBuilder {
+        StorageBuilder { config }
+    }
+
+    /// Use a local disk cache in `dir`, holding at most `size` bytes, instead
+    /// of the configured cache.
+    pub fn disk(mut self, dir: impl Into<PathBuf>, size: u64) -> StorageBuilder {
+        self.config.cache = None;
+        self.config.fallback_cache.dir = dir.into();
+        self.config.fallback_cache.size = size;
+        self
+    }
+
+    /// Create the storage, along with the runtime its operations and the
+    /// compilations run on.
+    pub fn build(self) -> Result<Storage> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .context("Failed to create the runtime")?;
+        let storage = storage_from_config(&self.config, runtime.handle())?;
+        Ok(Storage {
+            storage,
+            runtime: Arc::new(runtime),
+            jobserver: Client::new_num(num_cpus::get()),
+            stats: Default::default(),
+            languages: Arc::new(LanguageFilter::from_env()),
+        })
+    }
+}
+
+/// A handle to the cache used by [`run_cached`].
+///
+/// The handle is cheap to clone, and is `Send` and `Sync`: clones share the
+/// same cache, runtime and job slots, so [`run_cached`] can be called from
+/// several threads at the same time. Concurrent compilations with the same
+/// hash key are not deduplicated, they are all run and the last one to finish
+/// is stored.
+///
+/// The runtime is shut down when the last clone is dropped, which must not
+/// happen from within an asynchronous context.
+#[derive(Clone)]
+pub struct Storage {
+    storage: Arc<dyn cache::Storage>,
+    runtime: Arc<Runtime>,
+    jobserver: Client,
+    stats: Arc<Mutex<ServerStats>>,
+    languages: Arc<LanguageFilter>,
+}
+
+impl Storage {
+    /// A description of where the cache is, e.g. its local directory.
+    pub fn location(&self) -> String {
+        self.storage.location()
+    }
+
+    /// Check that the cache can be reached, as `sccache --check-config` does.
+    pub fn probe(&self) -> Result<()> {
+        self.runtime
+            .block_on(crate::server::probe_storage(&*self.storage))
+    }
+
+    /// Info about the cache, and the stats of the compilations run with this
+    /// storage, counted like the server counts its own.
+    pub fn info(&self) -> Result<ServerInfo> {
+        let stats = self.stats.lock().unwrap().clone();
+        self.runtime
+            .block_on(ServerInfo::new(stats, Some(&*self.storage)))
+    }
+
+    fn count(&self, f: impl FnOnce(&mut ServerStats)) {
+        f(&mut self.stats.lock().unwrap())
+    }
+}
+
+/// How a compilation was handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The outputs were restored from the cache.
+    Hit,
+    /// The compiler was run and the outputs were stored in the cache.
+    Miss,
+    /// The compiler was run, but the command can't be cached.
+    NotCacheable,
+    /// The preprocessor or the compiler failed.
+    Failed,
+}
+
+/// The result of [`run_cached`].
+#[derive(Debug)]
+pub struct CompileResult {
+    pub outcome: Outcome,
+    /// The status and output of the compiler, or the one replayed from the
+    /// cache.
+    pub output: process::Output,
+}
+
+/// Run the compiler `command`, its first entry being the compiler itself,
+/// using the outputs from `storage` if they are cached and storing them
+/// otherwise.
+///
+/// `env` is the complete environment of the compiler, and is also where the
+/// `SCCACHE_RECACHE`, `SCCACHE_NOCACHE` and similar variables are read from.
+/// The compiler is looked up in its `PATH` when `command` doesn't give a path.
+///
+/// This blocks until the outputs are written and stored, and must not be
+/// called from within an asynchronous context.
+pub fn run_cached(
+    command: &[OsString],
+    env: &[(OsString, OsString)],
+    cwd: &Path,
+    storage: &Storage,
+) -> Result<CompileResult> {
+    let (exe, args) = command.split_first().context("Empty compiler command")?;
+    let path = env
+        .iter()
+        .find(|(k, _)| k == "PATH")
+        .map(|(_, v)| v.clone());
+    let exe = which_in(exe, path, cwd)
+        .with_context(|| format!("Failed to find the compiler {:?}", exe))?;
+    let creator = ProcessCommandCreator::new(&storage.jobserver);
+    storage
+        .runtime
+        .block_on(run_cached_with(creator, &exe, args, env, cwd, storage))
+}
+
+async fn run_cached_with<T>(
+    creator: T,
+    exe: &Path,
+    args: &[OsString],
+    env: &[(OsString, OsString)],
+    cwd: &Path,
+    storage: &Storage,
+) -> Result<CompileResult>
+where
+    T: CommandCreatorSync,
+{
+    let pool = storage.runtime.handle().clone();
+    storage.count(|stats| stats.compile_requests += 1);
+    let compiler = match get_compiler_info(creator.clone(), exe, cwd, args, env, &pool, None).await
+    {
+        Ok((compiler, _)) => compiler,
+        Err(e) => {
+            storage.count(|stats| stats.requests_unsupported_compiler += 1);
+            return Err(e);
+        }
+    };
+    let no_cache = env.iter().any(|(k, _)| k == "SCCACHE_NOCACHE");
+    let hasher = match compiler.parse_arguments(args, cwd, env) {
+        // The compiler would read the caller's stdin.
+        CompilerArguments::Ok(hasher) if hasher.reads_stdin() => {
+            debug!("run_cached: not caching, the input is stdin");
+            storage.count(|stats| stats.requests_not_cacheable += 1);
+            return run_uncached(creator, exe, args, env, cwd).await;
+        }
+        CompilerArguments::Ok(_) if no_cache => {
+            debug!("run_cached: caching disabled by SCCACHE_NOCACHE");
+            storage.count(|stats| stats.requests_not_cacheable += 1);
+            return run_uncached(creator, exe, args, env, cwd).await;
+        }
+        CompilerArguments::Ok(hasher) if !storage.languages.allows(hasher.language()) => {
+            debug!(
+                "run_cached: caching {} disabled by config",
+                hasher.language().as_str()
+            );
+            storage.count(|stats| {
+                stats.requests_not_cacheable += 1;
+                *stats
+                    .not_cached
+                    .entry("disabled by config".to_owned())
+                    .or_insert(0) += 1;
+            });
+            return run_uncached(creator, exe, args, env, cwd).await;
+        }
+        CompilerArguments::Ok(hasher) => hasher,
+        CompilerArguments::CannotCache(why, _) => {
+            debug!("run_cached: CannotCache({})", why);
+            storage.count(|stats| {
+                stats.requests_not_cacheable += 1;
+                *stats.not_cached.entry(why.to_owned()).or_insert(0) += 1;
+            });
+            return run_uncached(creator, exe, args, env, cwd).await;
+        }
+        CompilerArguments::NotCompilation => {
+            debug!("run_cached: NotCompilation");
+            storage.count(|stats| stats.requests_not_compile += 1);
+            return run_uncached(creator, exe, args, env, cwd).await;
+        }
+    };
+    let kind = compiler.kind();
+    let lang = hasher.language();
+    storage.count(|stats| stats.requests_executed += 1);
+
+    let cache_control = CacheControl::from_env(env, &hasher.output_pretty());
+    let result = hasher
+        .get_cached_or_compile(
+            None,
+            creator,
+            storage.storage.clone(),
+            args.to_vec(),
+            cwd.to_path_buf(),
+            env.to_vec(),
+            cache_control,
+            pool,
+        )
+        .await;
+    let (result, output) = match result.map_err(|e| e.downcast::<OutputTooLarge>()) {
+        Ok(result) => result,
+        // There is no response for the output to fit in, it is read back.
+        Err(Ok(too_large)) => {
+            debug!("run_cached: not caching, the compiler output is too large");
+            let output = too_large.into_output()?;
+            let outcome = if output.status.success() {
+                storage.count(|stats| {
+                    stats.cache_misses.increment(&kind, &lang);
+                    stats.too_large_compilations += 1;
+                });
+                Outcome::NotCacheable
+            } else {
+                storage.count(|stats| stats.compile_fails += 1);
+                Outcome::Failed
+            };
+            return Ok(CompileResult { outcome, output });
+        }
+        Err(Err(e)) => {
+            storage.count(|stats| stats.cache_errors.increment(&kind, &lang));
+            return Err(e);
+        }
+    };
+    let (outcome, cache_write) = {
+        let mut stats = storage.stats.lock().unwrap();
+        match result {
+            compiler::CompileResult::CacheHit(duration) => {
+                stats.cache_hits.increment(&kind, &lang);
+                stats.cache_read_hit_duration += duration;
+                (Outcome::Hit, None)
+            }
+            compiler::CompileResult::CacheMiss(_, _, duration, future) => {
+                stats.cache_misses.increment(&kind, &lang);
+                stats.compiler_write_duration += duration;
+                (Outcome::Miss, Some(future))
+            }
+            compiler::CompileResult::NotCacheable => {
+                stats.cache_misses.increment(&kind, &lang);
+                stats.non_cacheable_compilations += 1;
+                (Outcome::NotCacheable, None)
+            }
+            compiler::CompileResult::TooLarge => {
+                stats.cache_misses.increment(&kind, &lang);
+                stats.too_large_compilations += 1;
+                (Outcome::NotCacheable, None)
+            }
+            compiler::CompileResult::CompileFailed(future) => {
+                stats.compile_fails += 1;
+                (Outcome::Failed, future)
+            }
+            compiler::CompileResult::Error => {
+                stats.preprocessor_errors += 1;
+                (Outcome::Failed, None)
+            }
+        }
+    };
+    if let Some(cache_write) = cache_write {
+        // Like in the server, failing to store an entry doesn't fail the
+        // compilation.
+        match cache_write.await {
+            Ok(info) => storage.count(|stats| {
+                stats.cache_writes += 1;
+                stats.cache_write_duration += info.duration;
+            }),
+            Err(e) => {
+                warn!("run_cached: failed to store the cache entry: {:#}", e);
+                storage.count(|stats| stats.cache_write_errors += 1);
+            }
+        }
+    }
+    Ok(CompileResult { outcome, output })
+}
+
+async fn run_uncached<T>(
+    mut creator: T,
+    exe: &Path,
+    args: &[OsString],
+    env: &[(OsString, OsString)],
+    cwd: &Path,
+) -> Result<CompileResult>
+where
+    T: CommandCreatorSync,
+{
+    let mut cmd = creator.new_command_sync(exe);
+    cmd.args(args)
+        .env_clear()
+        .envs(env.iter().map(|(k, v)| (k, v)))
+        .current_dir(cwd);
+    let output = cmd.spawn().await?.wait_with_output().await?;
+    Ok(CompileResult {
+        outcome: Outcome::NotCacheable,
+        output,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::disk::DiskCache;
+    use crate::cache::{CacheMode, PreprocessorCacheModeConfig};
+    use crate::mock_command::*;
+    use crate::test::utils::*;
+    use std::fs;
+
+    fn disk_storage(dir: &Path) -> Storage {
+        let runtime = Runtime::new().unwrap();
+        let storage = DiskCache::new(
+            dir,
+            u64::MAX,
+            runtime.handle(),
+            PreprocessorCacheModeConfig::default(),
+            CacheMode::ReadWrite,
+        );
+        Storage {
+            storage: Arc::new(storage),
+            runtime: Arc::new(runtime),
+            jobserver: Client::new_num(1),
+            stats: Default::default(),
+            languages: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_storage_is_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Storage>();
+    }
+
+    #[test]
+    fn test_run_cached() {
+        drop(env_logger::try_init());
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let gcc = f.mk_bin("gcc").unwrap();
+        let storage = disk_storage(&f.tempdir.path().join("cache"));
+        fs::write(f.tempdir.path().join("foo.c"), "whatever").unwrap();
+        let args = ovec!["-c", "foo.c", "-o", "foo.o"];
+        let obj = f.tempdir.path().join("foo.o");
+
+        // The compiler detection, the preprocessor and the compiler.
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")),
+        );
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "preprocessor output", "")),
+        );
+        let o = obj.clone();
+        next_command_calls(&creator, move |_| {
+            fs::write(&o, b"file contents")?;
+            Ok(MockChild::new(exit_status(0), "", "compiler stderr"))
+        });
+        let result = storage
+            .runtime
+            .block_on(run_cached_with(
+                creator.clone(),
+                &gcc,
+                &args,
+                &[],
+                f.tempdir.path(),
+                &storage,
+            ))
+            .unwrap();
+        assert_eq!(Outcome::Miss, result.outcome);
+        assert_eq!(b"compiler stderr", result.output.stderr.as_slice());
+
+        // Only the compiler detection and the preprocessor run on a hit.
+        fs::remove_file(&obj).unwrap();
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")),
+        );
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "preprocessor output", "")),
+        );
+        let result = storage
+            .runtime
+            .block_on(run_cached_with(
+                creator.clone(),
+                &gcc,
+                &args,
+                &[],
+                f.tempdir.path(),
+                &storage,
+            ))
+            .unwrap();
+        assert_eq!(Outcome::Hit, result.outcome);
+        assert_eq!(b"compiler stderr", result.output.stderr.as_slice());
+        assert_eq!(b"file contents", fs::read(&obj).unwrap().as_slice());
+        assert_eq!(0, creator.lock().unwrap().children.len());
+
+        let stats = storage.info().unwrap().stats;
+        assert_eq!(2, stats.compile_requests);
+        assert_eq!(1, stats.cache_hits.all());
+        assert_eq!(1, stats.cache_misses.all());
+        assert_eq!(1, stats.cache_writes);
+    }
+
+    #[test]
+    fn test_run_cached_not_compilation() {
+        drop(env_logger::try_init());
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let gcc = f.mk_bin("gcc").unwrap();
+        let storage = disk_storage(&f.tempdir.path().join("cache"));
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")),
+        );
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "gcc version 1.2.3", "")),
+        );
+        let result = storage
+            .runtime
+            .block_on(run_cached_with(
+                creator.clone(),
+                &gcc,
+                &[OsString::from("--version")],
+                &[],
+                f.tempdir.path(),
+                &storage,
+            ))
+            .unwrap();
+        assert_eq!(Outcome::NotCacheable, result.outcome);
+        assert_eq!(b"gcc version 1.2.3", result.output.stdout.as_slice());
+    }
+
+    #[test]
+    fn test_run_cached_preprocessor_error() {
+        drop(env_logger::try_init());
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let gcc = f.mk_bin("gcc").unwrap();
+        let storage = disk_storage(&f.tempdir.path().join("cache"));
+        fs::write(f.tempdir.path().join("foo.c"), "whatever").unwrap();
+        let args = ovec!["-c", "foo.c", "-o", "foo.o"];
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")),
+        );
+        next_command(
+            &creator,
+            Ok(MockChild::new(
+                exit_status(1),
+                "",
+                "missing.h: No such file",
+            )),
+        );
+        let result = storage
+            .runtime
+            .block_on(run_cached_with(
+                creator.clone(),
+                &gcc,
+                &args,
+                &[],
+                f.tempdir.path(),
+                &storage,
+            ))
+            .unwrap();
+        assert_eq!(Outcome::Failed, result.outcome);
+        assert_eq!(b"missing.h: No such file", result.output.stderr.as_slice());
+
+        // The preprocessor failure is neither a compilation failure nor a
+        // cache error.
+        let stats = storage.info().unwrap().stats;
+        assert_eq!(1, stats.preprocessor_errors);
+        assert_eq!(0, stats.compile_fails);
+        assert_eq!(0, stats.cache_errors.all());
+    }
+}
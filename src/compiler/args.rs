@@ -638,6 +638,85 @@ where
     }
 }
 
+/// The default of `SCCACHE_RESPONSE_FILE_MAX_DEPTH`.
+const DEFAULT_RESPONSE_FILE_MAX_DEPTH: usize = 16;
+
+/// The default of `SCCACHE_RESPONSE_FILE_MAX_ARGS`.
+const DEFAULT_RESPONSE_FILE_MAX_ARGS: usize = 1_000_000;
+
+/// Get a limit of the expansion of the response files from `var`, or
+/// `default`.
+fn response_file_limit(var: &str, default: usize) -> usize {
+    match std::env::var(var) {
+        Ok(s) => s.parse().unwrap_or_else(|_| {
+            warn!("Invalid {} {:?}, using {}", var, s, default);
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+/// The limits of the expansion of the response files of a command line,
+/// which would go on forever with a file including itself, directly or not.
+/// The files may only be nested `SCCACHE_RESPONSE_FILE_MAX_DEPTH` levels deep
+/// and expand to `SCCACHE_RESPONSE_FILE_MAX_ARGS` arguments in total.
+#[derive(Debug)]
+pub struct ResponseFileLimits {
+    pub max_depth: usize,
+    pub max_args: usize,
+    /// The arguments read from the response files so far.
+    expanded: usize,
+    error: Option<String>,
+}
+
+impl ResponseFileLimits {
+    pub fn new() -> Self {
+        ResponseFileLimits {
+            max_depth: response_file_limit(
+                "SCCACHE_RESPONSE_FILE_MAX_DEPTH",
+                DEFAULT_RESPONSE_FILE_MAX_DEPTH,
+            ),
+            max_args: response_file_limit(
+                "SCCACHE_RESPONSE_FILE_MAX_ARGS",
+                DEFAULT_RESPONSE_FILE_MAX_ARGS,
+            ),
+            expanded: 0,
+            error: None,
+        }
+    }
+
+    /// Check that the `args` arguments of `file`, found `depth` files deep,
+    /// may be expanded. Once they may not, the expansion stops, and `error`
+    /// tells why.
+    pub fn check(&mut self, file: &Path, depth: usize, args: usize) -> bool {
+        if self.error.is_some() {
+            return false;
+        }
+        if depth >= self.max_depth {
+            self.error = Some(format!(
+                "response file `{}` is nested more than {} levels deep, see SCCACHE_RESPONSE_FILE_MAX_DEPTH",
+                file.display(),
+                self.max_depth
+            ));
+            return false;
+        }
+        self.expanded += args;
+        if self.expanded > self.max_args {
+            self.error = Some(format!(
+                "response files expand to more than {} arguments, see SCCACHE_RESPONSE_FILE_MAX_ARGS",
+                self.max_args
+            ));
+            return false;
+        }
+        true
+    }
+
+    /// Why the expansion stopped before the end of the arguments, if it did.
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+}
+
 /// Helper macro used to define ArgInfo::Flag's.
 /// Variant is an enum variant, e.g. enum ArgType { Variant }
 ///     flag!("-foo", Variant)
@@ -0,0 +1,278 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Path normalization relative to `SCCACHE_BASEDIR`, so that checkouts of a
+//! project in different directories share cache entries, like ccache's
+//! `base_dir`.
+//!
+//! Absolute paths under the base directory are rewritten relative to it in
+//! what is hashed, i.e. the compiler arguments and the preprocessor output.
+//! The compilation itself still sees the real paths. The compiler messages
+//! are stored with the base directory replaced by a placeholder, which is
+//! replaced by the base directory of the compilation hitting the cache.
+
+use crate::compiler::nondeterministic::split_prefix_map;
+use crate::compiler::Language;
+use memchr::memmem;
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
+use std::path::{is_separator, Path};
+
+/// The variable of the client environment holding the base directory.
+pub const BASEDIR_ENV_VAR: &str = "SCCACHE_BASEDIR";
+
+/// Stands for the base directory in the compiler messages in the cache.
+const PLACEHOLDER: &[u8] = b"\0SCCACHE_BASEDIR\0";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BaseDir(String);
+
+impl BaseDir {
+    /// Get the base directory of a compilation of `language` with
+    /// `env_vars`, if any. Only C-like languages are normalized.
+    pub fn from_env(env_vars: &[(OsString, OsString)], language: Language) -> Option<BaseDir> {
+        if language == Language::Rust {
+            return None;
+        }
+        let (_, value) = env_vars.iter().find(|(k, _)| k == BASEDIR_ENV_VAR)?;
+        let dir = match value.to_str() {
+            Some(dir) if Path::new(dir).is_absolute() => dir,
+            _ => {
+                warn!(
+                    "Ignoring {}={:?}: not an absolute UTF-8 path",
+                    BASEDIR_ENV_VAR, value
+                );
+                return None;
+            }
+        };
+        let dir = dir.trim_end_matches(is_separator);
+        // The root is the base directory of everything, which would make
+        // every absolute path relative.
+        if dir.is_empty() || Path::new(dir).parent().is_none() {
+            warn!("Ignoring {}: the root directory", BASEDIR_ENV_VAR);
+            return None;
+        }
+        Some(BaseDir(dir.to_owned()))
+    }
+
+    /// Rewrite a path under the base directory in `arg` relative to it, if
+    /// `arg` is such a path or an option taking it, e.g. `-I<path>` or
+    /// `--sysroot=<path>`. Of a prefix map, e.g. `-ffile-prefix-map=old=new`,
+    /// only the old prefix is, as the new one is in the outputs.
+    pub fn relativize_arg(&self, arg: &OsStr) -> OsString {
+        let Some(s) = arg.to_str() else {
+            return arg.to_owned();
+        };
+        if let Some((option, old, new)) = split_prefix_map(s) {
+            let old = self.relativize_arg(OsStr::new(old));
+            return format!("{}{}={}", option, old.to_string_lossy(), new).into();
+        }
+        let Some(start) = s.find(&self.0) else {
+            return arg.to_owned();
+        };
+        // Only the path an option starts with, not one in the middle of
+        // another.
+        if s[..start].contains(is_separator) {
+            return arg.to_owned();
+        }
+        match relative_end(&s.as_bytes()[start + self.0.len()..], false) {
+            Some(skip) => {
+                let rest = &s[start + self.0.len() + skip..];
+                let rest = if rest.is_empty() { "." } else { rest };
+                format!("{}{}", &s[..start], rest).into()
+            }
+            None => arg.to_owned(),
+        }
+    }
+
+    /// Rewrite the paths under the base directory in `text`, e.g. the
+    /// preprocessor output, relative to it.
+    pub fn relativize<'a>(&self, text: &'a [u8]) -> Cow<'a, [u8]> {
+        self.replace(text, |rest, out| {
+            let skip = relative_end(rest, true)?;
+            if rest.get(skip).map_or(true, |&b| !is_path_byte(b)) {
+                out.push(b'.');
+            }
+            Some(skip)
+        })
+    }
+
+    /// Replace the base directory in the compiler messages `text` with a
+    /// placeholder, before they are stored in the cache.
+    pub fn replace_with_placeholder<'a>(&self, text: &'a [u8]) -> Cow<'a, [u8]> {
+        self.replace(text, |rest, out| {
+            relative_end(rest, true)?;
+            out.extend_from_slice(PLACEHOLDER);
+            Some(0)
+        })
+    }
+
+    /// Replace the placeholder in compiler messages read from the cache with
+    /// the base directory.
+    pub fn replace_placeholder<'a>(&self, text: &'a [u8]) -> Cow<'a, [u8]> {
+        replace_all(text, PLACEHOLDER, |_, _, out| {
+            out.extend_from_slice(self.0.as_bytes());
+            Some(0)
+        })
+    }
+
+    /// Replace the occurrences of the base directory at the start of a path
+    /// in `text`. `f` is given the text following the occurrence, writes the
+    /// replacement, and returns how many bytes of the following text it
+    /// replaces too, unless the occurrence is kept.
+    fn replace<'a, F>(&self, text: &'a [u8], mut f: F) -> Cow<'a, [u8]>
+    where
+        F: FnMut(&[u8], &mut Vec<u8>) -> Option<usize>,
+    {
+        replace_all(text, self.0.as_bytes(), |before, rest, out| {
+            // Not part of a longer path, e.g. `/src/a` in `/other/src/a`.
+            if before.last().copied().is_some_and(is_path_byte) {
+                return None;
+            }
+            f(rest, out)
+        })
+    }
+}
+
+/// Get how many bytes following the base directory in `rest` go away in a
+/// relative path: the separators, if any. `None` if the occurrence is in a
+/// longer name, e.g. `/src/ab` for `/src/a`. In text, a path ends at a
+/// byte that can't be in a path; in an argument, it goes until the end.
+fn relative_end(rest: &[u8], text: bool) -> Option<usize> {
+    match rest.first() {
+        None => Some(0),
+        Some(&b) if is_separator(b as char) => Some(
+            rest.iter()
+                .take_while(|&&b| is_separator(b as char))
+                .count(),
+        ),
+        Some(&b) if text && !is_path_byte(b) => Some(0),
+        Some(_) => None,
+    }
+}
+
+fn is_path_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || is_separator(b as char) || b"._-+~".contains(&b) || b >= 0x80
+}
+
+/// Replace the occurrences of `needle` in `text` for which `f`, given the
+/// text before and after the occurrence, writes a replacement and returns
+/// how many bytes following the occurrence it replaces too.
+fn replace_all<'a, F>(text: &'a [u8], needle: &[u8], mut f: F) -> Cow<'a, [u8]>
+where
+    F: FnMut(&[u8], &[u8], &mut Vec<u8>) -> Option<usize>,
+{
+    let mut out = Vec::new();
+    // The end of the text already in `out`.
+    let mut copied = 0;
+    let mut from = 0;
+    while let Some(found) = memmem::find(&text[from..], needle) {
+        let start = from + found;
+        let end = start + needle.len();
+        let mut replacement = Vec::new();
+        match f(&text[..start], &text[end..], &mut replacement) {
+            Some(skip) => {
+                out.extend_from_slice(&text[copied..start]);
+                out.extend_from_slice(&replacement);
+                copied = end + skip;
+                from = copied;
+            }
+            None => from = start + 1,
+        }
+    }
+    if copied == 0 && out.is_empty() {
+        return Cow::Borrowed(text);
+    }
+    out.extend_from_slice(&text[copied..]);
+    Cow::Owned(out)
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+
+    fn base_dir(dir: &str) -> Option<BaseDir> {
+        BaseDir::from_env(&[(BASEDIR_ENV_VAR.into(), dir.into())], Language::C)
+    }
+
+    #[test]
+    fn test_from_env() {
+        assert_eq!(base_dir("/src/"), Some(BaseDir("/src".into())));
+        assert_eq!(base_dir("src"), None);
+        assert_eq!(base_dir("/"), None);
+        assert_eq!(
+            BaseDir::from_env(&[(BASEDIR_ENV_VAR.into(), "/src".into())], Language::Rust),
+            None
+        );
+        assert_eq!(BaseDir::from_env(&[], Language::C), None);
+    }
+
+    #[test]
+    fn test_relativize_arg() {
+        let base_dir = base_dir("/home/a/proj").unwrap();
+        let relativize = |arg: &str| base_dir.relativize_arg(arg.as_ref());
+        assert_eq!(relativize("/home/a/proj/foo.c"), "foo.c");
+        assert_eq!(relativize("-I/home/a/proj/include"), "-Iinclude");
+        assert_eq!(relativize("-I/home/a/proj"), "-I.");
+        assert_eq!(relativize("--sysroot=/home/a/proj/sys"), "--sysroot=sys");
+        assert_eq!(relativize("-I/home/a/project"), "-I/home/a/project");
+        assert_eq!(relativize("-I/x/home/a/proj/inc"), "-I/x/home/a/proj/inc");
+        assert_eq!(relativize("-O2"), "-O2");
+        assert_eq!(
+            relativize("-ffile-prefix-map=/home/a/proj=."),
+            "-ffile-prefix-map=.=."
+        );
+        assert_eq!(
+            relativize("-fdebug-prefix-map=/home/a/proj/src=/home/a/proj"),
+            "-fdebug-prefix-map=src=/home/a/proj"
+        );
+        assert_eq!(
+            relativize("-fmacro-prefix-map=/usr=/home/a/proj"),
+            "-fmacro-prefix-map=/usr=/home/a/proj"
+        );
+    }
+
+    #[test]
+    fn test_relativize() {
+        let base_dir = base_dir("/home/a/proj").unwrap();
+        let text: &[u8] = b"# 1 \"/home/a/proj/foo.c\"\n\
+            # 1 \"/home/a/proj//\"\n\
+            # 1 \"/home/a/proj\"\n\
+            # 1 \"/home/a/project/bar.h\"\n\
+            # 1 \"/x/home/a/proj/baz.h\"\n";
+        assert_eq!(
+            &*base_dir.relativize(text),
+            b"# 1 \"foo.c\"\n\
+            # 1 \".\"\n\
+            # 1 \".\"\n\
+            # 1 \"/home/a/project/bar.h\"\n\
+            # 1 \"/x/home/a/proj/baz.h\"\n"
+        );
+        assert!(matches!(
+            base_dir.relativize(b"int main() {}"),
+            Cow::Borrowed(_)
+        ));
+    }
+
+    #[test]
+    fn test_placeholder() {
+        let alice = base_dir("/home/alice/proj").unwrap();
+        let bob = base_dir("/home/bob/proj").unwrap();
+        let stderr: &[u8] = b"/home/alice/proj/foo.c:1:1: warning: /home/alice/project\n";
+        let cached = alice.replace_with_placeholder(stderr);
+        assert_eq!(alice.replace_placeholder(&cached), stderr);
+        assert_eq!(
+            &*bob.replace_placeholder(&cached),
+            b"/home/bob/proj/foo.c:1:1: warning: /home/alice/project\n"
+        );
+    }
+}
@@ -12,11 +12,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::cache::metadata::EntryMetadata;
 use crate::cache::{FileObjectSource, PreprocessorCacheModeConfig, Storage};
+use crate::compiler::basedir::{BaseDir, BASEDIR_ENV_VAR};
+use crate::compiler::comments::{ignores_comments, strip_line_info, IGNORE_COMMENTS_ENV_VAR};
+use crate::compiler::nondeterministic;
 use crate::compiler::preprocessor_cache::preprocessor_cache_entry_hash_key;
 use crate::compiler::{
-    Cacheable, ColorMode, Compilation, CompileCommand, Compiler, CompilerArguments, CompilerHasher,
-    CompilerKind, HashResult, Language,
+    get_cache_salt, get_extra_files, host_target, Cacheable, ColorMode, Compilation,
+    CompileCommand, Compiler, CompilerArguments, CompilerHasher, CompilerKind, HashKeyInput,
+    HashResult, Language, DEPFILE_OUTPUT,
 };
 #[cfg(feature = "dist-client")]
 use crate::compiler::{DistPackagers, NoopOutputsRewriter};
@@ -26,9 +31,10 @@ use crate::dist::pkg;
 use crate::mock_command::CommandCreatorSync;
 use crate::util::{
     decode_path, encode_path, hash_all, Digest, HashToDigest, MetadataCtimeExt, TimeMacroFinder,
-    Timestamp,
+    Timestamp, HASH_BUFFER_SIZE,
 };
 use async_trait::async_trait;
+use filetime::FileTime;
 use fs_err as fs;
 use once_cell::sync::Lazy;
 use std::borrow::Cow;
@@ -117,16 +123,30 @@ pub struct ParsedArguments {
     pub suppress_rewrite_includes_only: bool,
     /// Arguments are incompatible with preprocessor cache mode
     pub too_hard_for_preprocessor_cache_mode: Option<OsString>,
+    /// The contents of stdin, when the input is `-`.
+    pub stdin: Option<Vec<u8>>,
+    /// Whether the object is written to stdout with `-o -`.
+    pub output_to_stdout: bool,
+    /// The sysroot of a cross compilation, given with `--sysroot`.
+    pub sysroot: Option<PathBuf>,
 }
 
 impl ParsedArguments {
     pub fn output_pretty(&self) -> Cow<'_, str> {
+        if self.output_to_stdout {
+            return Cow::Borrowed("stdout");
+        }
         self.outputs
             .get("obj")
             .and_then(|o| o.path.file_name())
             .map(|s| s.to_string_lossy())
             .unwrap_or(Cow::Borrowed("Unknown filename"))
     }
+
+    /// Whether the input is read from stdin.
+    pub fn reads_stdin(&self) -> bool {
+        self.input == Path::new("-")
+    }
 }
 
 /// A generic implementation of the `Compilation` trait for C/C++ compilers.
@@ -174,6 +194,11 @@ pub trait CCompilerImpl: Clone + fmt::Debug + Send + Sync + 'static {
         arguments: &[OsString],
         cwd: &Path,
     ) -> CompilerArguments<ParsedArguments>;
+    /// Split the compilation of several source files with `arguments`, see
+    /// `Compiler::split_inputs`.
+    fn split_inputs(&self, _arguments: &[OsString]) -> Option<Vec<Vec<OsString>>> {
+        None
+    }
     /// Run the C preprocessor with the specified set of arguments.
     #[allow(clippy::too_many_arguments)]
     async fn preprocess<T>(
@@ -189,6 +214,41 @@ pub trait CCompilerImpl: Clone + fmt::Debug + Send + Sync + 'static {
     ) -> Result<process::Output>
     where
         T: CommandCreatorSync;
+    /// Like `preprocess`, but also passing the preprocessor output to
+    /// `on_output` as it is produced, so that it can be hashed while the
+    /// preprocessor runs. By default, it is passed at once when the
+    /// preprocessor is done.
+    #[allow(clippy::too_many_arguments)]
+    async fn preprocess_streaming<T>(
+        &self,
+        creator: &T,
+        executable: &Path,
+        parsed_args: &ParsedArguments,
+        cwd: &Path,
+        env_vars: &[(OsString, OsString)],
+        may_dist: bool,
+        rewrite_includes_only: bool,
+        preprocessor_cache_mode: bool,
+        on_output: &mut (dyn for<'a> FnMut(&'a [u8]) + Send),
+    ) -> Result<process::Output>
+    where
+        T: CommandCreatorSync,
+    {
+        let output = self
+            .preprocess(
+                creator,
+                executable,
+                parsed_args,
+                cwd,
+                env_vars,
+                may_dist,
+                rewrite_includes_only,
+                preprocessor_cache_mode,
+            )
+            .await?;
+        on_output(&output.stdout);
+        Ok(output)
+    }
     /// Generate a command that can be used to invoke the C compiler to perform
     /// the compilation.
     fn generate_compile_commands(
@@ -298,12 +358,15 @@ impl<T: CommandCreatorSync, I: CCompilerImpl> Compiler<T> for CCompiler<I> {
     ) -> CompilerArguments<Box<dyn CompilerHasher<T> + 'static>> {
         match self.compiler.parse_arguments(arguments, cwd) {
             CompilerArguments::Ok(mut args) => {
-                // Handle SCCACHE_EXTRAFILES
-                for (k, v) in env_vars.iter() {
-                    if k.as_os_str() == OsStr::new("SCCACHE_EXTRAFILES") {
-                        args.extra_hash_files.extend(std::env::split_paths(&v))
-                    }
+                if !args.reads_stdin() && has_no_cache_directive(&cwd.join(&args.input)) {
+                    debug!("{:?} asks not to be cached", args.input);
+                    return CompilerArguments::CannotCache("explicit", None);
                 }
+                if let Some(flag) = nondeterministic::check(arguments, env_vars, args.language) {
+                    return CompilerArguments::CannotCache("nondeterministic", Some(flag));
+                }
+
+                args.extra_hash_files.extend(get_extra_files(env_vars, cwd));
 
                 // Handle cache invalidation for the ROCm device bitcode libraries. Every HIP
                 // object links in some LLVM bitcode libraries (.bc files), so in some sense
@@ -335,6 +398,10 @@ impl<T: CommandCreatorSync, I: CCompilerImpl> Compiler<T> for CCompiler<I> {
         }
     }
 
+    fn split_inputs(&self, arguments: &[OsString]) -> Option<Vec<Vec<OsString>>> {
+        self.compiler.split_inputs(arguments)
+    }
+
     fn box_clone(&self) -> Box<dyn Compiler<T>> {
         Box::new((*self).clone())
     }
@@ -365,7 +432,11 @@ where
             compiler,
         } = *self;
 
-        let extra_hashes = hash_all(&parsed_args.extra_hash_files, &pool.clone()).await?;
+        let mut extra_hashes = hash_all(&parsed_args.extra_hash_files, &pool.clone()).await?;
+        let sysroot_fingerprint = parsed_args.sysroot.as_deref().map(sysroot_fingerprint);
+        if let Some(fingerprint) = &sysroot_fingerprint {
+            extra_hashes.push(fingerprint.clone());
+        }
         // Create an argument vector containing both preprocessor and arch args, to
         // use in creating a hash key
         let mut preprocessor_and_arch_args = parsed_args.preprocessor_args.clone();
@@ -373,6 +444,42 @@ where
         // common_args is used in preprocessing too
         preprocessor_and_arch_args.extend(parsed_args.common_args.to_vec());
 
+        let mut common_and_arch_args = parsed_args.common_args.clone();
+        common_and_arch_args.extend(parsed_args.arch_args.to_vec());
+        // The output isn't hashed, but an entry of a compilation to stdout
+        // holds the object as stdout instead of as a file.
+        if parsed_args.output_to_stdout {
+            common_and_arch_args.extend(["-o".into(), "-".into()]);
+        }
+        // The dependency file is cached too, and names its target.
+        if parsed_args.outputs.contains_key(DEPFILE_OUTPUT) {
+            preprocessor_and_arch_args.extend(parsed_args.dependency_args.iter().cloned());
+            common_and_arch_args.extend(parsed_args.dependency_args.iter().cloned());
+        }
+        // Paths under the base directory are hashed relative to it.
+        let base_dir = BaseDir::from_env(&env_vars, parsed_args.language);
+        if let Some(base_dir) = &base_dir {
+            common_and_arch_args = common_and_arch_args
+                .iter()
+                .map(|arg| base_dir.relativize_arg(arg))
+                .collect();
+        }
+        let mut inputs = hash_key_inputs(
+            &executable_digest,
+            parsed_args.language,
+            &common_and_arch_args,
+            &parsed_args.extra_hash_files,
+            &extra_hashes,
+            &env_vars,
+            compiler.plusplus(),
+        );
+        if let (Some(sysroot), Some(fingerprint)) = (&parsed_args.sysroot, sysroot_fingerprint) {
+            inputs.push(HashKeyInput::new(
+                "sysroot",
+                format!("{} {}", sysroot.display(), fingerprint),
+            ));
+        }
+
         let absolute_input_path: Cow<'_, _> = if parsed_args.input.is_absolute() {
             Cow::Borrowed(&parsed_args.input)
         } else {
@@ -390,10 +497,12 @@ where
                 arg
             );
         }
-        // Disable preprocessor cache when doing distributed compilation
+        // Disable preprocessor cache when doing distributed compilation, and
+        // for the sources of the assembler, which are hashed as they are.
         let mut preprocessor_key = if !may_dist
             && preprocessor_cache_mode_config.use_preprocessor_cache_mode
             && !too_hard_for_preprocessor_cache_mode
+            && parsed_args.language != Language::Assembler
         {
             preprocessor_cache_entry_hash_key(
                 &executable_digest,
@@ -444,6 +553,10 @@ where
                     if !update_failed {
                         if let Some(key) = hit {
                             debug!("Preprocessor cache hit: {preprocessor_key}");
+                            inputs.push(HashKeyInput::new(
+                                "preprocessor cache entry",
+                                preprocessor_key,
+                            ));
                             // A compiler binary may be a symlink to another and
                             // so has the same digest, but that means
                             // the toolchain will not contain the correct path
@@ -464,6 +577,7 @@ where
                                     env_vars: env_vars.to_owned(),
                                 }),
                                 weak_toolchain_key,
+                                inputs,
                             });
                         } else {
                             debug!("Preprocessor cache miss: {preprocessor_key}");
@@ -473,18 +587,60 @@ where
             }
         }
 
-        let result = compiler
-            .preprocess(
-                creator,
-                &executable,
-                &parsed_args,
-                &cwd,
-                &env_vars,
-                may_dist,
-                rewrite_includes_only,
-                preprocessor_cache_mode_config.use_preprocessor_cache_mode,
-            )
-            .await;
+        // Hash the preprocessor output while the preprocessor runs. In
+        // preprocessor cache mode, relative to a base directory, or without
+        // its line numbers, the output is rewritten before it is hashed, so it
+        // is only hashed once the preprocessor is done.
+        let ignore_comments = ignores_comments(&env_vars, parsed_args.language);
+        let hash_while_preprocessing =
+            preprocessor_key.is_none() && base_dir.is_none() && !ignore_comments;
+        let mut key_digest = hash_key_digest(
+            &executable_digest,
+            parsed_args.language,
+            &common_and_arch_args,
+            &extra_hashes,
+            &env_vars,
+            compiler.plusplus(),
+        );
+        let mut output_digest = Digest::new();
+        let result = if parsed_args.language == Language::Assembler {
+            // Nothing preprocesses the sources of the assembler: the source
+            // stands for the preprocessor output.
+            let source = match &parsed_args.stdin {
+                Some(stdin) if parsed_args.reads_stdin() => Ok(stdin.clone()),
+                _ => fs::read(&*absolute_input_path).map_err(Error::from),
+            };
+            source.map(|source| {
+                if hash_while_preprocessing {
+                    key_digest.update(&source);
+                    output_digest.update(&source);
+                }
+                process::Output {
+                    status: process::ExitStatus::default(),
+                    stdout: source,
+                    stderr: vec![],
+                }
+            })
+        } else {
+            compiler
+                .preprocess_streaming(
+                    creator,
+                    &executable,
+                    &parsed_args,
+                    &cwd,
+                    &env_vars,
+                    may_dist,
+                    rewrite_includes_only,
+                    preprocessor_cache_mode_config.use_preprocessor_cache_mode,
+                    &mut |output| {
+                        if hash_while_preprocessing {
+                            key_digest.update(output);
+                            output_digest.update(output);
+                        }
+                    },
+                )
+                .await
+        };
         let out_pretty = parsed_args.output_pretty().into_owned();
         let result = result.map_err(|e| {
             debug!("[{}]: preprocessor failed: {:?}", out_pretty, e);
@@ -545,6 +701,17 @@ where
             )? {
                 debug!("Disabling preprocessor cache mode");
                 preprocessor_key = None;
+            } else if !remember_listed_headers(
+                &preprocessor_result.stderr,
+                &absolute_input_path,
+                &cwd,
+                &mut include_files,
+                preprocessor_cache_mode_config,
+                start_of_compilation,
+                &StandardFsAbstraction,
+            )? {
+                debug!("Disabling preprocessor cache mode");
+                preprocessor_key = None;
             }
         }
 
@@ -554,24 +721,42 @@ where
             preprocessor_result.stdout.len()
         );
 
-        // Create an argument vector containing both common and arch args, to
-        // use in creating a hash key
-        let mut common_and_arch_args = parsed_args.common_args.clone();
-        common_and_arch_args.extend(parsed_args.arch_args.to_vec());
-
-        let key = {
+        let mut included: Vec<_> = include_files.iter().collect();
+        included.sort();
+        for (path, digest) in included {
+            inputs.push(HashKeyInput::new(
+                "include file",
+                format!("{} {}", path.display(), digest),
+            ));
+        }
+        let key = if hash_while_preprocessing {
+            key_digest.finish()
+        } else {
+            let mut output = match &base_dir {
+                Some(base_dir) => base_dir.relativize(&preprocessor_result.stdout),
+                None => Cow::Borrowed(&preprocessor_result.stdout[..]),
+            };
+            if ignore_comments {
+                output = Cow::Owned(strip_line_info(&output));
+            }
+            output_digest.update(&output);
             hash_key(
                 &executable_digest,
                 parsed_args.language,
                 &common_and_arch_args,
                 &extra_hashes,
                 &env_vars,
-                &preprocessor_result.stdout,
+                &output,
                 compiler.plusplus(),
             )
         };
+        inputs.push(HashKeyInput::new(
+            "preprocessor output",
+            output_digest.finish(),
+        ));
 
-        // Cache the preprocessing step
+        // Cache the preprocessing step in the background, so that looking the
+        // key up doesn't wait for it.
         if let Some(preprocessor_key) = preprocessor_key {
             if !include_files.is_empty() {
                 let mut preprocessor_cache_entry = PreprocessorCacheEntry::new();
@@ -582,12 +767,14 @@ where
                 files.sort_unstable_by(|a, b| a.1.cmp(&b.1));
                 preprocessor_cache_entry.add_result(start_of_compilation, &key, files);
 
-                if let Err(e) = storage
-                    .put_preprocessor_cache_entry(&preprocessor_key, preprocessor_cache_entry)
-                    .await
-                {
-                    debug!("Failed to update preprocessor cache: {}", e);
-                }
+                pool.spawn(async move {
+                    if let Err(e) = storage
+                        .put_preprocessor_cache_entry(&preprocessor_key, preprocessor_cache_entry)
+                        .await
+                    {
+                        debug!("Failed to update preprocessor cache: {}", e);
+                    }
+                });
             }
         }
 
@@ -607,6 +794,7 @@ where
                 env_vars,
             }),
             weak_toolchain_key,
+            inputs,
         })
     }
 
@@ -625,12 +813,103 @@ where
     fn language(&self) -> Language {
         self.parsed_args.language
     }
+
+    fn entry_metadata(&self) -> EntryMetadata {
+        EntryMetadata {
+            compiler: CompilerKind::C(self.compiler.kind()).name(),
+            compiler_version: self.compiler.version(),
+            key_version: String::from_utf8_lossy(CACHE_VERSION).into_owned(),
+            created: std::time::SystemTime::now(),
+            build_id: None,
+        }
+    }
+
+    fn reads_stdin(&self) -> bool {
+        self.parsed_args.reads_stdin()
+    }
+
+    fn set_stdin(&mut self, stdin: Vec<u8>) {
+        self.parsed_args.stdin = Some(stdin);
+    }
+
+    fn writes_stdout(&self) -> bool {
+        self.parsed_args.output_to_stdout
+    }
+}
+
+/// Files of a sysroot, relative to it, which an update of it is expected to
+/// touch.
+const SYSROOT_KEY_FILES: &[&str] = &[
+    "usr/include",
+    "usr/include/features.h",
+    "usr/include/stdlib.h",
+    "usr/include/linux/version.h",
+    "usr/include/c++",
+    "usr/lib",
+    "lib",
+];
+
+/// Fingerprint the sysroot `dir` from the size and modification time of its
+/// key files, so that updating it invalidates the objects built against it.
+///
+/// Hashing all of its contents would be too slow, so this misses an update
+/// which touches none of the key files. The headers a compilation includes
+/// are part of its preprocessor output and hashed anyway.
+fn sysroot_fingerprint(dir: &Path) -> String {
+    let mut digest = Digest::new();
+    for file in SYSROOT_KEY_FILES {
+        digest.update(file.as_bytes());
+        match fs::metadata(dir.join(file)) {
+            Ok(metadata) => {
+                let mtime = FileTime::from_last_modification_time(&metadata);
+                digest.update(
+                    format!(
+                        " {} {}.{}\n",
+                        metadata.len(),
+                        mtime.unix_seconds(),
+                        mtime.nanoseconds()
+                    )
+                    .as_bytes(),
+                );
+            }
+            Err(_) => digest.update(b" missing\n"),
+        }
+    }
+    digest.finish()
 }
 
 const PRAGMA_GCC_PCH_PREPROCESS: &[u8] = b"pragma GCC pch_preprocess";
 const HASH_31_COMMAND_LINE_NEWLINE: &[u8] = b"# 31 \"<command-line>\"\n";
 const HASH_32_COMMAND_LINE_2_NEWLINE: &[u8] = b"# 32 \"<command-line>\" 2\n";
 const INCBIN_DIRECTIVE: &[u8] = b".incbin";
+const NO_CACHE_DIRECTIVE: &[u8] = b"sccache: no-cache";
+
+/// Whether the source file at `path` asks not to be cached, usually with a
+/// `// sccache: no-cache` comment. The headers it includes aren't checked:
+/// they are only known once it is preprocessed, which the comment is there
+/// to skip.
+fn has_no_cache_directive(path: &Path) -> bool {
+    let Ok(mut file) = fs::File::open(path) else {
+        return false;
+    };
+    let finder = memchr::memmem::Finder::new(NO_CACHE_DIRECTIVE);
+    let mut buf = vec![0; HASH_BUFFER_SIZE];
+    let mut len = 0;
+    loop {
+        match io::Read::read(&mut file, &mut buf[len..]) {
+            Ok(0) | Err(_) => return false,
+            Ok(n) => len += n,
+        }
+        if finder.find(&buf[..len]).is_some() {
+            return true;
+        }
+        // Keep the end of the buffer, which could be the start of the
+        // directive.
+        let keep_from = len.saturating_sub(NO_CACHE_DIRECTIVE.len() - 1);
+        buf.copy_within(keep_from..len, 0);
+        len -= keep_from;
+    }
+}
 
 /// Remember the include files in the preprocessor output if it can be cached.
 /// Returns `false` if preprocessor cache mode should be disabled.
@@ -1031,7 +1310,7 @@ fn remember_include_file(
     }
 
     // TODO add an option to ignore some header files?
-    if include_is_too_new(&path, &meta, time_of_compilation) {
+    if include_is_too_new(&path, &meta, config, time_of_compilation) {
         return Ok(false);
     }
 
@@ -1072,6 +1351,69 @@ fn remember_include_file(
     Ok(true)
 }
 
+/// Split the output of `-H` out of the stderr of the preprocessor: the
+/// headers it opened, one per line after as many dots as they are deep and a
+/// space, the precompiled headers it used, and with GCC, the list of headers
+/// which may use include guards which ends it.
+pub(crate) fn split_header_list(stderr: &[u8]) -> (Vec<&[u8]>, Vec<u8>) {
+    let mut headers = vec![];
+    let mut rest = vec![];
+    let mut guards = false;
+    for line in stderr.split_inclusive(|&b| b == b'\n') {
+        let trimmed = line.strip_suffix(b"\n").unwrap_or(line);
+        let trimmed = trimmed.strip_suffix(b"\r").unwrap_or(trimmed);
+        if guards || trimmed == b"Multiple include guards may be useful for:" {
+            guards = true;
+            continue;
+        }
+        let dots = trimmed.iter().take_while(|&&b| b == b'.').count();
+        match trimmed[dots..].strip_prefix(b" ") {
+            Some(header) if dots > 0 && !header.is_empty() => headers.push(header),
+            _ if trimmed.starts_with(b"! ") => {}
+            _ => rest.extend_from_slice(line),
+        }
+    }
+    (headers, rest)
+}
+
+/// Remember the headers the preprocessor listed as opened in `stderr`, see
+/// `split_header_list`, along with those of the line markers of its output,
+/// so that a header which left none is hashed too. Return false like
+/// `remember_include_file` to disable the preprocessor cache mode.
+fn remember_listed_headers(
+    stderr: &[u8],
+    input_file: &Path,
+    cwd: &Path,
+    included_files: &mut HashMap<PathBuf, String>,
+    config: PreprocessorCacheModeConfig,
+    time_of_compilation: std::time::SystemTime,
+    fs_impl: &impl PreprocessorFSAbstraction,
+) -> Result<bool> {
+    // The system headers can't be told apart in the list.
+    if config.skip_system_headers {
+        return Ok(true);
+    }
+    for header in split_header_list(stderr).0 {
+        let path = normalize_path(&cwd.join(decode_path(header)?));
+        let mut encoded = vec![];
+        encode_path(&mut encoded, &path)?;
+        if !remember_include_file(
+            &encoded,
+            input_file,
+            cwd,
+            included_files,
+            &mut Digest::new(),
+            false,
+            config,
+            time_of_compilation,
+            fs_impl,
+        )? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
 /// Opt out of preprocessor cache mode because of a race condition.
 ///
 /// The race condition consists of these events:
@@ -1085,11 +1427,12 @@ fn remember_include_file(
 fn include_is_too_new(
     path: &Path,
     meta: &PreprocessorFileMetadata,
+    config: PreprocessorCacheModeConfig,
     time_of_compilation: std::time::SystemTime,
 ) -> bool {
     // The comparison using >= is intentional, due to a possible race between
     // starting compilation and writing the include file.
-    if let Some(mtime) = meta.modified {
+    if let Some(mtime) = meta.modified.filter(|_| !config.ignore_include_file_mtime) {
         if mtime >= time_of_compilation.into() {
             debug!("Include file {} is too new", path.display());
             return true;
@@ -1097,7 +1440,10 @@ fn include_is_too_new(
     }
 
     // The same >= logic as above applies to the change time of the file.
-    if let Some(ctime) = meta.ctime_or_creation {
+    if let Some(ctime) = meta
+        .ctime_or_creation
+        .filter(|_| !config.ignore_include_file_ctime)
+    {
         if ctime >= time_of_compilation.into() {
             debug!("Include file {} is too new", path.display());
             return true;
@@ -1364,16 +1710,19 @@ impl pkg::ToolchainPackager for CToolchainPackager {
 }
 
 /// The cache is versioned by the inputs to `hash_key`.
-pub const CACHE_VERSION: &[u8] = b"11";
+pub const CACHE_VERSION: &[u8] = b"12";
 
 /// Environment variables that are factored into the cache key.
-static CACHED_ENV_VARS: Lazy<HashSet<&'static OsStr>> = Lazy::new(|| {
+pub(crate) static CACHED_ENV_VARS: Lazy<HashSet<&'static OsStr>> = Lazy::new(|| {
     [
         // SCCACHE_C_CUSTOM_CACHE_BUSTER has no particular meaning behind it,
         // serving as a way for the user to factor custom data into the hash.
         // One can set it to different values for different invocations
         // to prevent cache reuse between them.
         "SCCACHE_C_CUSTOM_CACHE_BUSTER",
+        // gcc (>= 7) and clang (>= 16) use it for the expansion of `__DATE__`,
+        // `__TIME__` and `__TIMESTAMP__`, so it can change the object file.
+        "SOURCE_DATE_EPOCH",
         "MACOSX_DEPLOYMENT_TARGET",
         "IPHONEOS_DEPLOYMENT_TARGET",
         "TVOS_DEPLOYMENT_TARGET",
@@ -1396,6 +1745,28 @@ pub fn hash_key(
     preprocessor_output: &[u8],
     plusplus: bool,
 ) -> String {
+    let mut m = hash_key_digest(
+        compiler_digest,
+        language,
+        arguments,
+        extra_hashes,
+        env_vars,
+        plusplus,
+    );
+    m.update(preprocessor_output);
+    m.finish()
+}
+
+/// Hash the inputs of `hash_key` except for the preprocessor output, which is
+/// hashed last.
+fn hash_key_digest(
+    compiler_digest: &str,
+    language: Language,
+    arguments: &[OsString],
+    extra_hashes: &[String],
+    env_vars: &[(OsString, OsString)],
+    plusplus: bool,
+) -> Digest {
     // If you change any of the inputs to the hash, you should change `CACHE_VERSION`.
     let mut m = Digest::new();
     m.update(compiler_digest.as_bytes());
@@ -1403,6 +1774,10 @@ pub fn hash_key(
     // we have to incorporate that into the hash as well.
     m.update(&[plusplus as u8]);
     m.update(CACHE_VERSION);
+    if let Some(salt) = get_cache_salt() {
+        m.update(salt.as_bytes());
+    }
+    m.update(host_target().as_bytes());
     m.update(language.as_str().as_bytes());
     for arg in arguments {
         arg.hash(&mut HashToDigest { digest: &mut m });
@@ -1416,10 +1791,61 @@ pub fn hash_key(
             var.hash(&mut HashToDigest { digest: &mut m });
             m.update(&b"="[..]);
             val.hash(&mut HashToDigest { digest: &mut m });
+        } else if var == BASEDIR_ENV_VAR {
+            // Paths are hashed relative to the base directory, so only
+            // whether there is one matters.
+            var.hash(&mut HashToDigest { digest: &mut m });
+        } else if var == IGNORE_COMMENTS_ENV_VAR && val == "1" {
+            // The entries without line numbers in their key aren't shared
+            // with the others.
+            var.hash(&mut HashToDigest { digest: &mut m });
         }
     }
-    m.update(preprocessor_output);
-    m.finish()
+    m
+}
+
+/// Describe the inputs of `hash_key`, except for the preprocessor output.
+fn hash_key_inputs(
+    compiler_digest: &str,
+    language: Language,
+    arguments: &[OsString],
+    extra_hash_files: &[PathBuf],
+    extra_hashes: &[String],
+    env_vars: &[(OsString, OsString)],
+    plusplus: bool,
+) -> Vec<HashKeyInput> {
+    let mut inputs = vec![
+        HashKeyInput::new("compiler", compiler_digest),
+        HashKeyInput::new("plusplus", plusplus.to_string()),
+        HashKeyInput::new("cache version", String::from_utf8_lossy(CACHE_VERSION)),
+    ];
+    if let Some(salt) = get_cache_salt() {
+        inputs.push(HashKeyInput::new("cache salt", salt));
+    }
+    inputs.push(HashKeyInput::new("target", host_target()));
+    inputs.push(HashKeyInput::new("language", language.as_str()));
+    for arg in arguments {
+        inputs.push(HashKeyInput::new("argument", arg.to_string_lossy()));
+    }
+    for (path, hash) in extra_hash_files.iter().zip(extra_hashes) {
+        inputs.push(HashKeyInput::new(
+            "extra file",
+            format!("{} {}", path.display(), hash),
+        ));
+    }
+    for (var, val) in env_vars.iter() {
+        if CACHED_ENV_VARS.contains(var.as_os_str()) {
+            inputs.push(HashKeyInput::new(
+                "env",
+                format!("{}={}", var.to_string_lossy(), val.to_string_lossy()),
+            ));
+        } else if var == BASEDIR_ENV_VAR {
+            inputs.push(HashKeyInput::new("env", BASEDIR_ENV_VAR));
+        } else if var == IGNORE_COMMENTS_ENV_VAR && val == "1" {
+            inputs.push(HashKeyInput::new("env", IGNORE_COMMENTS_ENV_VAR));
+        }
+    }
+    inputs
 }
 
 #[cfg(test)]
@@ -1427,6 +1853,39 @@ mod test {
     use std::{collections::VecDeque, sync::Mutex};
 
     use super::*;
+    use serial_test::serial;
+
+    #[test]
+    fn test_sysroot_fingerprint() {
+        let dir = tempfile::tempdir().unwrap();
+        let include = dir.path().join("usr/include");
+        std::fs::create_dir_all(&include).unwrap();
+        std::fs::write(include.join("features.h"), "#define VERSION 1\n").unwrap();
+        let fingerprint = sysroot_fingerprint(dir.path());
+        assert_eq!(fingerprint, sysroot_fingerprint(dir.path()));
+        // Other files aren't looked at.
+        std::fs::write(dir.path().join("README"), "").unwrap();
+        assert_eq!(fingerprint, sysroot_fingerprint(dir.path()));
+        // Updating a key file changes the fingerprint.
+        std::fs::write(include.join("features.h"), "#define VERSION 2\n").unwrap();
+        filetime::set_file_mtime(include.join("features.h"), FileTime::from_unix_time(1, 0))
+            .unwrap();
+        assert_neq!(fingerprint, sysroot_fingerprint(dir.path()));
+    }
+
+    #[test]
+    fn test_no_cache_directive_across_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("foo.c");
+        // The directive straddles the end of the first chunk.
+        let mut source = vec![b' '; HASH_BUFFER_SIZE - 5];
+        source.extend_from_slice(b"// sccache: no-cache\nint x;\n");
+        std::fs::write(&path, &source).unwrap();
+        assert!(has_no_cache_directive(&path));
+        std::fs::write(&path, vec![b' '; HASH_BUFFER_SIZE * 2 + 3]).unwrap();
+        assert!(!has_no_cache_directive(&path));
+        assert!(!has_no_cache_directive(&dir.path().join("missing.c")));
+    }
 
     #[test]
     fn test_same_content() {
@@ -1515,6 +1974,110 @@ mod test {
         }
     }
 
+    #[test]
+    #[serial]
+    fn test_hash_key_cache_salt() {
+        let args = ovec!["a", "b", "c"];
+        const PREPROCESSED: &[u8] = b"hello world";
+        let key = || hash_key("abcd", Language::C, &args, &[], &[], PREPROCESSED, false);
+        let unsalted = temp_env::with_var_unset("SCCACHE_CACHE_SALT", key);
+        let salted = temp_env::with_var("SCCACHE_CACHE_SALT", Some("1"), key);
+        assert_neq!(unsalted, salted);
+        assert_neq!(
+            salted,
+            temp_env::with_var("SCCACHE_CACHE_SALT", Some("2"), key)
+        );
+        // An empty salt is none.
+        assert_eq!(
+            unsalted,
+            temp_env::with_var("SCCACHE_CACHE_SALT", Some(""), key)
+        );
+        let inputs = temp_env::with_var("SCCACHE_CACHE_SALT", Some("1"), || {
+            hash_key_inputs("abcd", Language::C, &args, &[], &[], &[], false)
+        });
+        assert!(inputs
+            .iter()
+            .any(|i| i.kind == "cache salt" && i.value == "1"));
+    }
+
+    #[test]
+    #[serial]
+    fn test_hash_key_inputs() {
+        let args = ovec!["-O2"];
+        let vars = vec![
+            (OsString::from("SOURCE_DATE_EPOCH"), OsString::from("1")),
+            (OsString::from("NOT_HASHED"), OsString::from("2")),
+        ];
+        let files = vec![PathBuf::from("profile.data")];
+        let inputs = hash_key_inputs(
+            "abcd",
+            Language::Cxx,
+            &args,
+            &files,
+            &["1234".to_owned()],
+            &vars,
+            true,
+        );
+        let inputs: Vec<_> = inputs
+            .iter()
+            .map(|i| (i.kind.as_str(), i.value.as_str()))
+            .collect();
+        assert_eq!(
+            inputs,
+            vec![
+                ("compiler", "abcd"),
+                ("plusplus", "true"),
+                ("cache version", std::str::from_utf8(CACHE_VERSION).unwrap()),
+                ("target", host_target()),
+                ("language", "c++"),
+                ("argument", "-O2"),
+                ("extra file", "profile.data 1234"),
+                ("env", "SOURCE_DATE_EPOCH=1"),
+            ]
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_hash_key_inputs_env() {
+        let args = ovec!["-O2"];
+        const PREPROCESSED: &[u8] = b"hello world";
+        let env = |vars: &[(&str, &str)]| -> Vec<(OsString, OsString)> {
+            vars.iter()
+                .map(|(k, v)| (OsString::from(k), OsString::from(v)))
+                .collect()
+        };
+        let envs = [
+            env(&[]),
+            env(&[("NOT_HASHED", "1")]),
+            env(&[(IGNORE_COMMENTS_ENV_VAR, "1")]),
+            env(&[(IGNORE_COMMENTS_ENV_VAR, "0")]),
+            env(&[(BASEDIR_ENV_VAR, "/base")]),
+            env(&[(BASEDIR_ENV_VAR, "/other")]),
+            env(&[("SOURCE_DATE_EPOCH", "1")]),
+        ];
+        let key = |vars| hash_key("abcd", Language::C, &args, &[], vars, PREPROCESSED, false);
+        let inputs = |vars| hash_key_inputs("abcd", Language::C, &args, &[], &[], vars, false);
+        // The environments hashed to the same key are explained the same,
+        // and the others differently.
+        for a in &envs {
+            for b in &envs {
+                assert_eq!(
+                    key(a) == key(b),
+                    inputs(a) == inputs(b),
+                    "{:?} and {:?}",
+                    a,
+                    b
+                );
+            }
+        }
+        let inputs = inputs(&envs[2]);
+        assert_eq!(
+            inputs.last(),
+            Some(&HashKeyInput::new("env", IGNORE_COMMENTS_ENV_VAR))
+        );
+    }
+
     #[test]
     fn test_extra_hash_data() {
         let args = ovec!["a", "b", "c"];
@@ -1573,6 +2136,10 @@ mod test {
 
         t("cu", Language::Cuda);
         t("hip", Language::Hip);
+
+        t("s", Language::Assembler);
+        t("S", Language::AssemblerToPreprocess);
+        t("sx", Language::AssemblerToPreprocess);
     }
 
     #[test]
@@ -1592,6 +2159,7 @@ mod test {
         t("Hpp");
         t("Mm");
         t("Cu");
+        t("Sx");
     }
 
     #[test]
@@ -1841,6 +2409,31 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_include_is_too_new() {
+        let now = std::time::SystemTime::now();
+        let path = Path::new("foo.h");
+        let old = Timestamp::new(1_000_000_000, 0);
+        let new = Timestamp::from(now + std::time::Duration::from_secs(60));
+        let meta = |modified, ctime| PreprocessorFileMetadata {
+            is_dir: false,
+            is_file: true,
+            modified: Some(modified),
+            ctime_or_creation: Some(ctime),
+        };
+        let strict = PreprocessorCacheModeConfig::activated();
+        assert!(!include_is_too_new(path, &meta(old, old), strict, now));
+        assert!(include_is_too_new(path, &meta(new, old), strict, now));
+        assert!(include_is_too_new(path, &meta(old, new), strict, now));
+
+        let mut sloppy = strict;
+        sloppy.apply_sloppiness("include_file_mtime").unwrap();
+        assert!(!include_is_too_new(path, &meta(new, old), sloppy, now));
+        assert!(include_is_too_new(path, &meta(old, new), sloppy, now));
+        sloppy.apply_sloppiness("include_file_ctime").unwrap();
+        assert!(!include_is_too_new(path, &meta(new, new), sloppy, now));
+    }
+
     /// Test cases where we test filesystem access
     #[test]
     fn test_process_preprocessor_line_fs_access() {
@@ -1954,4 +2547,77 @@ mod test {
             "a93900c371d997927c5bc568ea538bed59ae5c960021dcfe7b0b369da5267528",
         );
     }
+
+    #[test]
+    fn test_split_header_list() {
+        let stderr = b"! pch.h.gch\n\
+            . inc/a.h\n\
+            .. /usr/include/stdio.h\r\n\
+            t.c:2:1: warning: no newline at end of file\n\
+            Multiple include guards may be useful for:\n\
+            inc/a.h\n";
+        let (headers, rest) = split_header_list(stderr);
+        assert_eq!(headers, [&b"inc/a.h"[..], b"/usr/include/stdio.h"]);
+        assert_eq!(rest, b"t.c:2:1: warning: no newline at end of file\n");
+    }
+
+    #[test]
+    fn test_remember_listed_headers() {
+        let config = PreprocessorCacheModeConfig::activated();
+        let metadata = PreprocessorFileMetadata {
+            is_dir: false,
+            is_file: true,
+            modified: Some(Timestamp::new(12341234, 0)),
+            ctime_or_creation: None,
+        };
+        let remember = |contents: &'static [u8]| {
+            // inc/a.h is known from the line markers, inc/b.h isn't.
+            let mut include_files =
+                HashMap::from([(PathBuf::from("/src/inc/a.h"), "digest".to_owned())]);
+            let fs_impl = TestFs {
+                metadata_results: Mutex::new(VecDeque::from([(
+                    PathBuf::from("/src/inc/b.h"),
+                    metadata.clone(),
+                )])),
+                open_results: Mutex::new(VecDeque::from([(
+                    PathBuf::from("/src/inc/b.h"),
+                    Box::new(contents) as Box<dyn std::io::Read>,
+                )])),
+            };
+            assert!(remember_listed_headers(
+                b". inc/a.h\n.. ./inc/../inc/b.h\n",
+                Path::new("/src/t.c"),
+                Path::new("/src"),
+                &mut include_files,
+                config,
+                std::time::SystemTime::now(),
+                &fs_impl,
+            )
+            .unwrap());
+            assert_eq!(include_files.len(), 2);
+            include_files
+        };
+        // A header modified in place changes what the entry is looked up
+        // with.
+        assert_ne!(
+            remember(b"#define B 1\n")[Path::new("/src/inc/b.h")],
+            remember(b"#define B 2\n")[Path::new("/src/inc/b.h")]
+        );
+
+        // Unless the system headers are skipped, as they can't be told apart.
+        let config = PreprocessorCacheModeConfig {
+            skip_system_headers: true,
+            ..config
+        };
+        assert!(remember_listed_headers(
+            b". /usr/include/stdio.h\n",
+            Path::new("/src/t.c"),
+            Path::new("/src"),
+            &mut HashMap::new(),
+            config,
+            std::time::SystemTime::now(),
+            &PanicFs,
+        )
+        .unwrap());
+    }
 }
@@ -104,6 +104,10 @@ impl CCompilerImpl for Clang {
         )
     }
 
+    fn split_inputs(&self, arguments: &[OsString]) -> Option<Vec<Vec<OsString>>> {
+        gcc::split_inputs(arguments, (&gcc::ARGS[..], &ARGS[..]))
+    }
+
     #[allow(clippy::too_many_arguments)]
     async fn preprocess<T>(
         &self,
@@ -116,6 +120,36 @@ impl CCompilerImpl for Clang {
         rewrite_includes_only: bool,
         preprocessor_cache_mode: bool,
     ) -> Result<process::Output>
+    where
+        T: CommandCreatorSync,
+    {
+        self.preprocess_streaming(
+            creator,
+            executable,
+            parsed_args,
+            cwd,
+            env_vars,
+            may_dist,
+            rewrite_includes_only,
+            preprocessor_cache_mode,
+            &mut |_| {},
+        )
+        .await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn preprocess_streaming<T>(
+        &self,
+        creator: &T,
+        executable: &Path,
+        parsed_args: &ParsedArguments,
+        cwd: &Path,
+        env_vars: &[(OsString, OsString)],
+        may_dist: bool,
+        rewrite_includes_only: bool,
+        preprocessor_cache_mode: bool,
+        on_output: &mut (dyn for<'a> FnMut(&'a [u8]) + Send),
+    ) -> Result<process::Output>
     where
         T: CommandCreatorSync,
     {
@@ -140,6 +174,8 @@ impl CCompilerImpl for Clang {
             self.kind(),
             rewrite_includes_only,
             ignorable_whitespace_flags,
+            preprocessor_cache_mode,
+            on_output,
         )
         .await
     }
@@ -190,18 +226,21 @@ counted_array!(pub static ARGS: [ArgInfo<gcc::ArgData>; _] = [
     flag!("-fno-pch-timestamp", PassThroughFlag),
     flag!("-fno-profile-instr-generate", TooHardFlag),
     flag!("-fno-profile-instr-use", TooHardFlag),
-    take_arg!("-fplugin", PathBuf, CanBeConcatenated('='), ExtraHashFile),
+    take_arg!("-foptimization-record-file", OsString, Concatenated('='), TooHard),
+    take_arg!("-fpass-plugin", PathBuf, Concatenated('='), Plugin),
+    take_arg!("-fplugin", PathBuf, CanBeConcatenated('='), Plugin),
     flag!("-fprofile-instr-generate", ProfileGenerate),
     // Note: the PathBuf argument is optional
     take_arg!("-fprofile-instr-use", PathBuf, Concatenated('='), ClangProfileUse),
     // Note: this overrides the -fprofile-use option in gcc.rs.
     take_arg!("-fprofile-use", PathBuf, Concatenated('='), ClangProfileUse),
     take_arg!("-fsanitize-blacklist", PathBuf, Concatenated('='), ExtraHashFile),
+    flag!("-fsave-optimization-record", ExtraOutput),
     flag!("-fuse-ctor-homing", PassThroughFlag),
     take_arg!("-gcc-toolchain", OsString, Separated, PassThrough),
     flag!("-gcodeview", PassThroughFlag),
     take_arg!("-include-pch", PathBuf, CanBeSeparated, PreprocessorArgumentPath),
-    take_arg!("-load", PathBuf, Separated, ExtraHashFile),
+    take_arg!("-load", PathBuf, Separated, Plugin),
     take_arg!("-mllvm", OsString, Separated, PassThrough),
     flag!("-no-opaque-pointers", PreprocessorArgumentFlag),
     take_arg!("-plugin-arg", OsString, Concatenated('-'), PassThrough),
@@ -663,6 +702,29 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_parse_arguments_optimization_record() {
+        let a = parses!("-c", "foo.c", "-fsave-optimization-record", "-o", "foo.o");
+        assert_eq!(
+            a.outputs.get("opt-record"),
+            Some(&ArtifactDescriptor {
+                path: "foo.opt.yaml".into(),
+                optional: false
+            })
+        );
+        assert_eq!(
+            CompilerArguments::CannotCache("-foptimization-record-file", None),
+            parse_arguments_(stringvec![
+                "-c",
+                "foo.c",
+                "-fsave-optimization-record",
+                "-foptimization-record-file=foo.yaml",
+                "-o",
+                "foo.o"
+            ])
+        );
+    }
+
     #[test]
     fn test_parse_xclang_invalid() {
         assert_eq!(
@@ -694,25 +756,13 @@ mod test {
 
     #[test]
     fn test_parse_xclang_load() {
-        let a = parses!(
-            "-c",
-            "foo.c",
-            "-o",
-            "foo.o",
-            "-Xclang",
-            "-load",
-            "-Xclang",
-            "plugin.so"
-        );
+        let f = TestFixture::new();
+        let plugin = f.touch("plugin.so").unwrap();
+        let plugin = plugin.to_str().unwrap();
+        let a = parses!("-c", "foo.c", "-o", "foo.o", "-Xclang", "-load", "-Xclang", plugin);
         println!("A {:#?}", a);
-        assert_eq!(
-            ovec!["-Xclang", "-load", "-Xclang", "plugin.so"],
-            a.common_args
-        );
-        assert_eq!(
-            ovec![std::env::current_dir().unwrap().join("plugin.so")],
-            a.extra_hash_files
-        );
+        assert_eq!(ovec!["-Xclang", "-load", "-Xclang", plugin], a.common_args);
+        assert_eq!(ovec![plugin], a.extra_hash_files);
     }
 
     #[test]
@@ -862,12 +912,37 @@ mod test {
 
     #[test]
     fn test_parse_fplugin() {
-        let a = parses!("-c", "foo.c", "-o", "foo.o", "-fplugin", "plugin.so");
+        let f = TestFixture::new();
+        let plugin = f.touch("plugin.so").unwrap();
+        let pass_plugin = f.touch("pass.so").unwrap();
+        let plugin = plugin.to_str().unwrap();
+        let pass_plugin = pass_plugin.to_str().unwrap();
+        let a = parses!(
+            "-c",
+            "foo.c",
+            "-o",
+            "foo.o",
+            "-fplugin",
+            plugin,
+            format!("-fpass-plugin={}", pass_plugin)
+        );
         println!("A {:#?}", a);
-        assert_eq!(ovec!["-fplugin", "plugin.so"], a.common_args);
         assert_eq!(
-            ovec![std::env::current_dir().unwrap().join("plugin.so")],
-            a.extra_hash_files
+            ovec!["-fplugin", plugin, format!("-fpass-plugin={}", pass_plugin)],
+            a.common_args
+        );
+        assert_eq!(ovec![plugin, pass_plugin], a.extra_hash_files);
+    }
+
+    #[test]
+    fn test_parse_fplugin_missing() {
+        // A plugin which can't be hashed can't be cached.
+        let f = TestFixture::new();
+        let plugin = f.tempdir.path().join("missing.so");
+        let plugin = plugin.to_str().unwrap();
+        assert_eq!(
+            CompilerArguments::CannotCache("-fplugin", Some(plugin.to_owned())),
+            parse_arguments_(stringvec!["-c", "foo.c", "-fplugin", plugin])
         );
     }
 
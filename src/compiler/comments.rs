@@ -0,0 +1,153 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Hashing C-like sources regardless of their comments, with
+//! `SCCACHE_IGNORE_COMMENTS=1`, so that editing only comments, e.g. license
+//! headers, hits the cache.
+//!
+//! The preprocessor output has no comments, but the line numbers of its line
+//! markers, and its blank lines, still move with them. Those are left out of
+//! the hash, so that an entry compiled from a source with other line numbers
+//! may be used: its debug info and the lines of its warnings are those of
+//! the source it was compiled from. `__LINE__` is expanded before hashing,
+//! and still changes the key.
+
+use crate::compiler::Language;
+use std::ffi::{OsStr, OsString};
+use std::sync::Once;
+
+/// The variable of the client environment enabling it, set to `1`.
+pub const IGNORE_COMMENTS_ENV_VAR: &str = "SCCACHE_IGNORE_COMMENTS";
+
+/// Whether a compilation of `language` with `env_vars` ignores comments.
+pub fn ignores_comments(env_vars: &[(OsString, OsString)], language: Language) -> bool {
+    // The sources of the assembler are hashed as they are.
+    if language == Language::Rust || language == Language::Assembler {
+        return false;
+    }
+    let enabled = env_vars
+        .iter()
+        .any(|(k, v)| k == IGNORE_COMMENTS_ENV_VAR && v == OsStr::new("1"));
+    if enabled {
+        static WARNING: Once = Once::new();
+        WARNING.call_once(|| {
+            warn!(
+                "{}=1: the cache entries of sources differing only in comments and line \
+                 numbers are shared, with the debug info and the warnings of the first one",
+                IGNORE_COMMENTS_ENV_VAR
+            )
+        });
+    }
+    enabled
+}
+
+/// Remove the line numbers of the line markers of `output`, e.g.
+/// `# 12 "foo.h" 2` or `#line 12 "foo.h"`, and its blank lines, keeping the
+/// files the line markers name.
+pub fn strip_line_info(output: &[u8]) -> Vec<u8> {
+    let mut stripped = Vec::with_capacity(output.len());
+    for line in output.split(|&b| b == b'\n') {
+        let line = trim_end(line);
+        if line.is_empty() {
+            continue;
+        }
+        match line_marker(line) {
+            Some((directive, rest)) => {
+                stripped.extend_from_slice(directive);
+                stripped.extend_from_slice(rest);
+            }
+            None => stripped.extend_from_slice(line),
+        }
+        stripped.push(b'\n');
+    }
+    stripped
+}
+
+fn trim_end(line: &[u8]) -> &[u8] {
+    let end = line
+        .iter()
+        .rposition(|b| !b.is_ascii_whitespace())
+        .map_or(0, |i| i + 1);
+    &line[..end]
+}
+
+fn trim_start(line: &[u8]) -> &[u8] {
+    let start = line
+        .iter()
+        .position(|b| !matches!(b, b' ' | b'\t'))
+        .unwrap_or(line.len());
+    &line[start..]
+}
+
+/// Split the line marker `line` into its directive, e.g. `#` or `#line`, and
+/// what follows its line number.
+fn line_marker(line: &[u8]) -> Option<(&[u8], &[u8])> {
+    let rest = trim_start(line.strip_prefix(b"#")?);
+    let (directive, rest) = match rest.strip_prefix(b"line") {
+        Some(rest) => (&b"#line"[..], rest),
+        None => (&b"#"[..], rest),
+    };
+    let rest = trim_start(rest);
+    let digits = rest.iter().take_while(|b| b.is_ascii_digit()).count();
+    if digits == 0 || rest.get(digits).is_some_and(|b| !matches!(b, b' ' | b'\t')) {
+        return None;
+    }
+    Some((directive, &rest[digits..]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_strip_line_info() {
+        let before = b"# 1 \"foo.c\"\n# 1 \"<built-in>\"\n# 1 \"foo.c\"\n\n\n\n\n# 1 \"foo.h\" 1\nint f(void);\n# 5 \"foo.c\" 2\n\nint g = 1;   \n";
+        // Ten more lines of license header.
+        let after = b"# 1 \"foo.c\"\n# 1 \"<built-in>\"\n# 1 \"foo.c\"\n\n\n# 1 \"foo.h\" 1\nint f(void);\n# 15 \"foo.c\" 2\nint g = 1;\n";
+        assert_eq!(strip_line_info(before), strip_line_info(after));
+        assert_eq!(
+            strip_line_info(before),
+            b"# \"foo.c\"\n# \"<built-in>\"\n# \"foo.c\"\n# \"foo.h\" 1\nint f(void);\n# \"foo.c\" 2\nint g = 1;\n"
+        );
+        // The code and the files still count.
+        let code = b"# 1 \"foo.c\"\nint g = 2;\n";
+        assert_ne!(
+            strip_line_info(code),
+            strip_line_info(b"# 1 \"foo.c\"\nint g = 1;\n")
+        );
+        assert_ne!(
+            strip_line_info(code),
+            strip_line_info(b"# 1 \"bar.c\"\nint g = 2;\n")
+        );
+
+        // MSVC's line directives.
+        assert_eq!(
+            strip_line_info(b"#line 1 \"foo.c\"\r\n\r\n#line 3 \"foo.c\"\r\nint g;\r\n"),
+            strip_line_info(b"#line 1 \"foo.c\"\r\n#line 13 \"foo.c\"\r\nint g;\r\n"),
+        );
+        // Other directives are kept as is.
+        assert_eq!(
+            strip_line_info(b"#pragma once\n#define X 1\n#ident \"v1\"\n"),
+            b"#pragma once\n#define X 1\n#ident \"v1\"\n"
+        );
+    }
+
+    #[test]
+    fn test_ignores_comments() {
+        let env = |v: &str| vec![(OsString::from(IGNORE_COMMENTS_ENV_VAR), OsString::from(v))];
+        assert!(ignores_comments(&env("1"), Language::C));
+        assert!(ignores_comments(&env("1"), Language::Cxx));
+        assert!(!ignores_comments(&env("0"), Language::C));
+        assert!(!ignores_comments(&[], Language::C));
+        assert!(!ignores_comments(&env("1"), Language::Rust));
+    }
+}
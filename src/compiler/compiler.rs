@@ -13,25 +13,37 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::cache::{Cache, CacheWrite, DecompressionFailure, FileObjectSource, Storage};
+use crate::cache::metadata::EntryMetadata;
+use crate::cache::{Cache, CacheRead, CacheWrite, DecompressionFailure, FileObjectSource, Storage};
 use crate::compiler::args::*;
+use crate::compiler::basedir::BaseDir;
 use crate::compiler::c::{CCompiler, CCompilerKind};
 use crate::compiler::clang::Clang;
 use crate::compiler::diab::Diab;
 use crate::compiler::gcc::Gcc;
+use crate::compiler::in_flight::Flight;
 use crate::compiler::msvc;
 use crate::compiler::msvc::Msvc;
 use crate::compiler::nvcc::Nvcc;
 use crate::compiler::nvcc::NvccHostCompiler;
 use crate::compiler::nvhpc::Nvhpc;
+use crate::compiler::probe_cache::{CompilerProbe, PROBE_CACHE};
 use crate::compiler::rust::{Rust, RustupProxy};
 use crate::compiler::tasking_vx::TaskingVX;
+use crate::config;
+use crate::deadline;
 #[cfg(feature = "dist-client")]
 use crate::dist::pkg;
+use crate::jobs;
 #[cfg(feature = "dist-client")]
 use crate::lru_disk_cache;
-use crate::mock_command::{exit_status, CommandChild, CommandCreatorSync, RunCommand};
-use crate::util::{fmt_duration_as_secs, ref_env, run_input_output};
+use crate::mock_command::{CommandChild, CommandCreatorSync, RunCommand};
+use crate::result_log::{self, update_record};
+use crate::stderr_stream;
+use crate::util::{
+    exit_status_from_code, fmt_duration_as_secs, ref_env, run_input_output, run_input_output_live,
+    write_atomically, OutputLimits,
+};
 use crate::{counted_array, dist};
 use async_trait::async_trait;
 use filetime::FileTime;
@@ -52,6 +64,7 @@ use std::time::{Duration, Instant};
 use tempfile::TempDir;
 
 use crate::errors::*;
+use once_cell::sync::Lazy;
 
 /// Can dylibs (shared libraries or proc macros) be distributed on this platform?
 #[cfg(all(
@@ -77,10 +90,34 @@ pub struct CompileCommand {
     pub arguments: Vec<OsString>,
     pub env_vars: Vec<(OsString, OsString)>,
     pub cwd: PathBuf,
+    /// What to feed the compiler on stdin, when it reads the source from there.
+    pub stdin: Option<Vec<u8>>,
 }
 
 impl CompileCommand {
     pub async fn execute<T>(self, creator: &T) -> Result<process::Output>
+    where
+        T: CommandCreatorSync,
+    {
+        self.run(creator, None).await
+    }
+
+    /// Like `execute`, but also streaming the stderr of the compiler to the
+    /// client of the current task, if any, see `stderr_stream`, once it has a
+    /// job to run the compiler, see `jobs`.
+    pub async fn execute_live<T>(self, creator: &T) -> Result<process::Output>
+    where
+        T: CommandCreatorSync,
+    {
+        let _job = jobs::start().await;
+        self.run(creator, stderr_stream::of_task()).await
+    }
+
+    async fn run<T>(
+        self,
+        creator: &T,
+        live_stderr: Option<stderr_stream::Sender>,
+    ) -> Result<process::Output>
     where
         T: CommandCreatorSync,
     {
@@ -89,7 +126,7 @@ impl CompileCommand {
             .env_clear()
             .envs(self.env_vars)
             .current_dir(self.cwd);
-        run_input_output(cmd, None).await
+        run_input_output_live(cmd, self.stdin, OutputLimits::from_env(), live_stderr).await
     }
 }
 
@@ -114,6 +151,11 @@ pub enum Language {
     Cuda,
     Rust,
     Hip,
+    /// The sources of the assembler, e.g. `.s`, which aren't preprocessed.
+    Assembler,
+    /// The sources of the assembler going through the C preprocessor first,
+    /// e.g. `.S`.
+    AssemblerToPreprocess,
 }
 
 impl Language {
@@ -137,6 +179,8 @@ impl Language {
             // TODO cy
             Some("rs") => Some(Language::Rust),
             Some("hip") => Some(Language::Hip),
+            Some("s") => Some(Language::Assembler),
+            Some("S") | Some("sx") => Some(Language::AssemblerToPreprocess),
             e => {
                 trace!("Unknown source extension: {}", e.unwrap_or("(None)"));
                 None
@@ -154,11 +198,27 @@ impl Language {
             Language::Cuda => "cuda",
             Language::Rust => "rust",
             Language::Hip => "hip",
+            Language::Assembler => "assembler",
+            Language::AssemblerToPreprocess => "assembler-cpp",
         }
     }
 }
 
 impl CompilerKind {
+    /// The name of the kind of compiler, e.g. `gcc`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            CompilerKind::C(CCompilerKind::Clang) => "clang",
+            CompilerKind::C(CCompilerKind::Diab) => "diab",
+            CompilerKind::C(CCompilerKind::Gcc) => "gcc",
+            CompilerKind::C(CCompilerKind::Msvc) => "msvc",
+            CompilerKind::C(CCompilerKind::Nvhpc) => "nvhpc",
+            CompilerKind::C(CCompilerKind::Nvcc) => "nvcc",
+            CompilerKind::C(CCompilerKind::TaskingVX) => "taskingvx",
+            CompilerKind::Rust => "rustc",
+        }
+    }
+
     pub fn lang_kind(&self, lang: &Language) -> String {
         match lang {
             Language::C
@@ -171,6 +231,7 @@ impl CompilerKind {
             Language::Cuda => "CUDA",
             Language::Rust => "Rust",
             Language::Hip => "HIP",
+            Language::Assembler | Language::AssemblerToPreprocess => "Assembler",
         }
         .to_string()
     }
@@ -218,6 +279,12 @@ where
         cwd: &Path,
         env_vars: &[(OsString, OsString)],
     ) -> CompilerArguments<Box<dyn CompilerHasher<T> + 'static>>;
+    /// Split the compilation of several source files with `arguments` into
+    /// one compilation per source file, if this compiler supports it and
+    /// the flags allow it, so that each is cached on its own.
+    fn split_inputs(&self, _arguments: &[OsString]) -> Option<Vec<Vec<OsString>>> {
+        None
+    }
     fn box_clone(&self) -> Box<dyn Compiler<T>>;
 }
 
@@ -253,6 +320,156 @@ impl<T: CommandCreatorSync> Clone for Box<dyn CompilerProxy<T>> {
     }
 }
 
+/// Get the salt all the cache keys are hashed with, `SCCACHE_CACHE_SALT`,
+/// from the environment of the server. Changing it invalidates the whole
+/// cache at once, the entries of the old salt aging out as usual.
+pub fn get_cache_salt() -> Option<String> {
+    std::env::var("SCCACHE_CACHE_SALT")
+        .ok()
+        .filter(|salt| !salt.is_empty())
+}
+
+/// Get the target sccache runs on, its architecture, OS, pointer width and
+/// byte order, e.g. `x86_64-linux-64-little`. It is hashed into all the cache
+/// keys and stored in the entries, so that the machines of different targets
+/// sharing a cache don't get the entries of each other, even if their keys
+/// were to collide.
+pub fn host_target() -> &'static str {
+    static HOST_TARGET: Lazy<String> = Lazy::new(|| {
+        format!(
+            "{}-{}-{}-{}",
+            std::env::consts::ARCH,
+            std::env::consts::OS,
+            usize::BITS,
+            if cfg!(target_endian = "big") {
+                "big"
+            } else {
+                "little"
+            }
+        )
+    });
+    &HOST_TARGET
+}
+
+/// Get the files whose contents are hashed into the key of a compilation,
+/// `SCCACHE_EXTRA_FILES`, or `SCCACHE_EXTRAFILES` as it was first named, from
+/// its environment, relative to `cwd`. They are the inputs sccache can't
+/// find, e.g. a file a compiler plugin reads, which must exist: a missing one
+/// fails the compilation rather than leaving it out of the key.
+pub fn get_extra_files(env_vars: &[(OsString, OsString)], cwd: &Path) -> Vec<PathBuf> {
+    env_vars
+        .iter()
+        .filter(|(k, _)| k == "SCCACHE_EXTRA_FILES" || k == "SCCACHE_EXTRAFILES")
+        .flat_map(|(_, v)| std::env::split_paths(v))
+        .filter(|path| !path.as_os_str().is_empty())
+        .map(|path| cwd.join(path))
+        .collect()
+}
+
+/// Get the build making the entries, e.g. the commit it builds, from the
+/// `SCCACHE_BUILD_ID` of the client. It is recorded in the entries without
+/// being hashed, the entries being shared by all the builds.
+fn get_build_id(env_vars: &[(OsString, OsString)]) -> Option<String> {
+    let (_, build_id) = env_vars.iter().find(|(k, _)| k == "SCCACHE_BUILD_ID")?;
+    match build_id.to_str() {
+        Some("") => None,
+        Some(build_id) => Some(build_id.to_owned()),
+        None => {
+            warn!("Invalid SCCACHE_BUILD_ID {:?}, not recording it", build_id);
+            None
+        }
+    }
+}
+
+/// Get the fraction of the cache hits compiled again to check that their
+/// outputs are the cached ones, `SCCACHE_VERIFY_HITS`, from the environment
+/// of a compilation.
+fn get_verify_hits(env_vars: &[(OsString, OsString)]) -> f64 {
+    let Some((_, rate)) = env_vars.iter().find(|(k, _)| k == "SCCACHE_VERIFY_HITS") else {
+        return 0.0;
+    };
+    match rate.to_str().and_then(|rate| rate.parse::<f64>().ok()) {
+        Some(rate) if (0.0..=1.0).contains(&rate) => rate,
+        _ => {
+            warn!(
+                "Invalid SCCACHE_VERIFY_HITS {:?}, not verifying the cache hits",
+                rate
+            );
+            0.0
+        }
+    }
+}
+
+/// Run `compile_cmd` again and compare its outputs with the ones of the cache
+/// hit `entry` for `key`, logging an error if they differ, which means that
+/// the hash key misses an input of the compilation. The outputs of the
+/// compilation are left for the cached ones to overwrite.
+async fn verify_hit<T>(
+    creator: &T,
+    compile_cmd: CompileCommand,
+    entry: &mut CacheRead,
+    outputs: &[FileObjectSource],
+    key: &str,
+    out_pretty: &str,
+) where
+    T: CommandCreatorSync,
+{
+    let command = format!("{:?} {:?}", compile_cmd.executable, compile_cmd.arguments);
+    debug!("[{}]: Verifying the cache hit {}", out_pretty, key);
+    match compile_cmd.execute(creator).await {
+        Ok(output) if output.status.success() => {}
+        Ok(output) => {
+            warn!(
+                "[{}]: Not verifying the cache hit, compiling again failed with {}",
+                out_pretty, output.status
+            );
+            return;
+        }
+        Err(e) => {
+            warn!(
+                "[{}]: Not verifying the cache hit, compiling again failed: {:#}",
+                out_pretty, e
+            );
+            return;
+        }
+    }
+    let differ: Vec<_> = outputs
+        .iter()
+        .filter(|output| {
+            let mut cached = vec![];
+            let cached = entry
+                .get_object(&output.key, &mut cached)
+                .ok()
+                .map(|_| cached);
+            cached != fs::read(&output.path).ok()
+        })
+        .map(|output| &output.path)
+        .collect();
+    if differ.is_empty() {
+        debug!("[{}]: The cache hit {} is verified", out_pretty, key);
+    } else {
+        error!(
+            "[{}]: The outputs {:?} of the cache hit {} differ from the ones of \
+             compiling again with {}, the hash key misses an input",
+            out_pretty, differ, key, command
+        );
+    }
+}
+
+/// Whether the failed compilation with `output` would fail the same way if it
+/// was run again, i.e. it wasn't killed by a signal and didn't complain about
+/// files it couldn't read.
+fn is_deterministic_failure(output: &process::Output) -> bool {
+    const UNREADABLE_FILE_ERRORS: &[&str] = &[
+        "no such file or directory",
+        "file not found",
+        "cannot open",
+        "permission denied",
+    ];
+    let stderr = String::from_utf8_lossy(&output.stderr).to_lowercase();
+    output.status.code().is_some() && !UNREADABLE_FILE_ERRORS.iter().any(|e| stderr.contains(e))
+}
+
 /// An interface to a compiler for hash key generation, the result of
 /// argument parsing.
 #[async_trait]
@@ -295,7 +512,25 @@ where
     ) -> Result<(CompileResult, process::Output)> {
         let out_pretty = self.output_pretty().into_owned();
         debug!("[{}]: get_cached_or_compile: {:?}", out_pretty, arguments);
+        let mut metadata = self.entry_metadata();
+        metadata.build_id = get_build_id(&env_vars);
+        let cache_failures = env_vars
+            .iter()
+            .any(|(k, _v)| k.as_os_str() == OsStr::new("SCCACHE_CACHE_FAILURES"));
+        let verify_hits = get_verify_hits(&env_vars);
+        let share_pending_writes = env_vars
+            .iter()
+            .any(|(k, v)| k == "SCCACHE_SHARE_PENDING_WRITES" && v == "1");
+        let base_dir = BaseDir::from_env(&env_vars, self.language());
+        // The output isn't rewritten like the messages are.
+        let stdout_is_output = self.writes_stdout();
         let start = Instant::now();
+        // The build servers only deal with files.
+        let dist_client = if self.reads_stdin() || stdout_is_output {
+            None
+        } else {
+            dist_client
+        };
         let may_dist = dist_client.is_some();
         let rewrite_includes_only = match dist_client {
             Some(ref client) => client.rewrite_includes_only(),
@@ -329,14 +564,60 @@ where
                 key,
                 compilation,
                 weak_toolchain_key,
+                ..
             }) => (key, compilation, weak_toolchain_key),
         };
         debug!("[{}]: Hash key: {}", out_pretty, key);
+        update_record(|r| {
+            r.key = Some(key.clone());
+            r.hash_secs = Some(start.elapsed().as_secs_f64());
+        });
+        // An output which isn't a regular file, e.g. a FIFO read by another
+        // process, can't be read back to be stored, nor replaced on a hit:
+        // the compiler writes to it as it would without sccache.
+        let special_output = compilation
+            .outputs_in(&cwd)
+            .map(|output| output.path)
+            .find(|path| is_special_file(path));
+        if let Some(path) = special_output {
+            debug!(
+                "[{}]: Not caching, the output {} isn't a regular file",
+                out_pretty,
+                path.display()
+            );
+            let start = Instant::now();
+            let (_, _, output) = dist_or_local_compile(
+                None,
+                creator,
+                cwd,
+                compilation,
+                weak_toolchain_key,
+                out_pretty,
+            )
+            .await?;
+            update_record(|r| r.compile_secs = Some(start.elapsed().as_secs_f64()));
+            if !output.status.success() {
+                return Ok((CompileResult::CompileFailed(None), output));
+            }
+            return Ok((CompileResult::NotCacheable, output));
+        }
+        // Wait for an identical compilation of the server in flight to store
+        // its result, unless the cache isn't checked anyway, or can't store
+        // it. The flight is left once the result of this compilation is stored.
+        let mut flight = if cache_control == CacheControl::ForceRecache || storage.is_read_only() {
+            None
+        } else {
+            Flight::join(&key).await
+        };
+        let pending = flight.as_mut().and_then(Flight::take_pending);
         // If `ForceRecache` is enabled, we won't check the cache.
         let start = Instant::now();
         let cache_status = async {
             if cache_control == CacheControl::ForceRecache {
                 Ok(Cache::Recache)
+            } else if let Some(pending) = pending {
+                // The entry an identical compilation is storing.
+                CacheRead::from(std::io::Cursor::new(pending)).map(Cache::Hit)
             } else {
                 storage.get(&key).await
             }
@@ -348,41 +629,134 @@ where
         let cache_status = async {
             let res = tokio::time::timeout(timeout, cache_status).await;
             let duration = start.elapsed();
+            update_record(|r| r.lookup_secs = Some(duration.as_secs_f64()));
             (res, duration)
         };
 
         // Check the result of the cache lookup.
-        let outputs = compilation
-            .outputs()
-            .map(|output| FileObjectSource {
-                path: cwd.join(output.path),
-                ..output
-            })
-            .collect::<Vec<_>>();
+        let outputs = compilation.outputs_in(&cwd).collect::<Vec<_>>();
+        deadline::record_outputs(outputs.iter().map(|output| output.path.clone()));
+        let mut state = compilation.state();
 
         let lookup = match cache_status.await {
-            (Ok(Ok(Cache::Hit(mut entry))), duration) => {
-                debug!(
-                    "[{}]: Cache hit in {}",
+            (Ok(Ok(Cache::Hit(entry))), _) if !entry.format().is_for_host() => {
+                warn!(
+                    "[{}]: Ignoring the cache entry {} of another target, {}: the cache is shared \
+                     by several targets and their keys collided",
                     out_pretty,
-                    fmt_duration_as_secs(&duration)
+                    key,
+                    entry.format().target.as_deref().unwrap_or_default()
                 );
-                let stdout = entry.get_stdout();
-                let stderr = entry.get_stderr();
-                let output = process::Output {
-                    status: exit_status(0),
-                    stdout,
-                    stderr,
-                };
-                let hit = CompileResult::CacheHit(duration);
-                match entry.extract_objects(outputs.clone(), &pool).await {
-                    Ok(()) => Ok(CacheLookupResult::Success(hit, output)),
-                    Err(e) => {
-                        if e.downcast_ref::<DecompressionFailure>().is_some() {
-                            debug!("[{}]: Failed to decompress object", out_pretty);
-                            Ok(CacheLookupResult::Miss(MissType::CacheReadError))
-                        } else {
-                            Err(e)
+                Ok(CacheLookupResult::Miss(MissType::Normal))
+            }
+            (Ok(Ok(Cache::Hit(mut entry))), duration) => {
+                update_record(|r| r.bytes_read = Some(entry.size()));
+                let exit_code = entry.get_exit_code();
+                if exit_code != 0 && !cache_failures {
+                    debug!(
+                        "[{}]: Cache hit for a failed compilation in {}, ignoring it",
+                        out_pretty,
+                        fmt_duration_as_secs(&duration)
+                    );
+                    Ok(CacheLookupResult::Miss(MissType::Normal))
+                } else {
+                    debug!(
+                        "[{}]: Cache hit in {}",
+                        out_pretty,
+                        fmt_duration_as_secs(&duration)
+                    );
+                    let mut stdout = entry.get_stdout();
+                    let mut stderr = entry.get_stderr();
+                    if let Some(base_dir) = &base_dir {
+                        if !stdout_is_output {
+                            stdout = base_dir.replace_placeholder(&stdout).into_owned();
+                        }
+                        stderr = base_dir.replace_placeholder(&stderr).into_owned();
+                    }
+                    let output = process::Output {
+                        status: exit_status_from_code(exit_code),
+                        stdout,
+                        stderr,
+                    };
+                    let hit = CompileResult::CacheHit(duration);
+                    // Failed compilations are stored without their outputs.
+                    let outputs = if exit_code == 0 {
+                        outputs.clone()
+                    } else {
+                        vec![]
+                    };
+                    let (depfile, outputs) = split_depfile(outputs, base_dir.as_ref());
+                    let depfile = depfile.and_then(|depfile| {
+                        let mut deps = vec![];
+                        entry.get_object(DEPFILE_OUTPUT, &mut deps).ok()?;
+                        Some((depfile.path, deps))
+                    });
+                    // The output on stdout can't be compared, and the failed
+                    // compilations have no outputs to compare.
+                    if exit_code == 0
+                        && !stdout_is_output
+                        && verify_hits > 0.0
+                        && rand::random::<f64>() < verify_hits
+                    {
+                        let mut path_transformer = dist::PathTransformer::new();
+                        match compilation.generate_compile_commands(&mut path_transformer, true) {
+                            Ok((compile_cmd, _, _)) => {
+                                verify_hit(
+                                    &creator,
+                                    compile_cmd,
+                                    &mut entry,
+                                    &outputs,
+                                    &key,
+                                    &out_pretty,
+                                )
+                                .await
+                            }
+                            Err(e) => {
+                                warn!("[{}]: Not verifying the cache hit: {:#}", out_pretty, e)
+                            }
+                        }
+                    }
+                    // Entries stored without the state still restore the
+                    // outputs, the compiler then starts over.
+                    let packed_state = state.as_ref().filter(|_| exit_code == 0).and_then(|_| {
+                        let mut packed = vec![];
+                        entry.get_object(STATE_OBJECT, &mut packed).ok()?;
+                        Some(packed)
+                    });
+                    let extracted = match entry.extract_objects(outputs, &pool).await {
+                        Ok(()) => match (&base_dir, depfile) {
+                            (Some(base_dir), Some((path, deps))) => {
+                                let deps = base_dir.replace_placeholder(&deps);
+                                write_atomically(&path, |file| Ok(file.write_all(&deps)?))
+                            }
+                            _ => Ok(()),
+                        },
+                        Err(e) => Err(e),
+                    };
+                    match extracted {
+                        Ok(()) => {
+                            if let (Some(state), Some(packed)) = (state.take(), packed_state) {
+                                let restored = pool
+                                    .spawn_blocking(move || state.restore(&packed))
+                                    .await
+                                    .map_err(anyhow::Error::from)
+                                    .and_then(|res| res);
+                                if let Err(e) = restored {
+                                    warn!(
+                                        "[{}]: Failed to restore the compiler state: {:#}",
+                                        out_pretty, e
+                                    );
+                                }
+                            }
+                            Ok(CacheLookupResult::Success(hit, output))
+                        }
+                        Err(e) => {
+                            if e.downcast_ref::<DecompressionFailure>().is_some() {
+                                debug!("[{}]: Failed to decompress object", out_pretty);
+                                Ok(CacheLookupResult::Miss(MissType::CacheReadError))
+                            } else {
+                                Err(e.context(CacheError("failed to extract the cache entry")))
+                            }
                         }
                     }
                 }
@@ -403,6 +777,15 @@ where
                 );
                 Ok(CacheLookupResult::Miss(MissType::ForcedRecache))
             }
+            (Ok(Err(err)), duration) if err.downcast_ref::<CacheTimeout>().is_some() => {
+                warn!(
+                    "[{}]: Cache read cancelled: {} in {}",
+                    out_pretty,
+                    err,
+                    fmt_duration_as_secs(&duration)
+                );
+                Ok(CacheLookupResult::Miss(MissType::TimedOut))
+            }
             (Ok(Err(err)), duration) => {
                 error!(
                     "[{}]: Cache read error: {:?} in {}",
@@ -440,13 +823,19 @@ where
                 )
                 .await?;
                 let duration_compilation = start.elapsed();
-                if !compiler_result.status.success() {
+                update_record(|r| r.compile_secs = Some(duration_compilation.as_secs_f64()));
+                let failed = !compiler_result.status.success();
+                if failed
+                    && !(cache_failures
+                        && cacheable == Cacheable::Yes
+                        && is_deterministic_failure(&compiler_result))
+                {
                     debug!(
                         "[{}]: Compiled in {}, but failed, not storing in cache",
                         out_pretty,
                         fmt_duration_as_secs(&duration_compilation)
                     );
-                    return Ok((CompileResult::CompileFailed, compiler_result));
+                    return Ok((CompileResult::CompileFailed(None), compiler_result));
                 }
                 if cacheable != Cacheable::Yes {
                     // Not cacheable
@@ -457,32 +846,149 @@ where
                     );
                     return Ok((CompileResult::NotCacheable, compiler_result));
                 }
+                // The state is cached as well when it can be packed.
+                let packed_state = match state {
+                    Some(state) if !failed => {
+                        match pool.spawn_blocking(move || state.pack()).await? {
+                            Ok(packed) => Some(packed),
+                            Err(e) => {
+                                warn!(
+                                    "[{}]: Not caching the compiler state: {:#}",
+                                    out_pretty, e
+                                );
+                                None
+                            }
+                        }
+                    }
+                    _ => None,
+                };
+                if !failed {
+                    if let Some(max_size) = max_entry_size(&*storage).await {
+                        let size: u64 = outputs
+                            .iter()
+                            .filter_map(|output| fs::metadata(&output.path).ok())
+                            .map(|metadata| metadata.len())
+                            .chain(packed_state.as_ref().map(|packed| packed.len() as u64))
+                            .sum();
+                        if size > max_size {
+                            debug!(
+                                "[{}]: Compiled in {}, but the outputs are too large to cache: {} bytes, more than {}",
+                                out_pretty,
+                                fmt_duration_as_secs(&duration_compilation),
+                                size,
+                                max_size
+                            );
+                            return Ok((CompileResult::TooLarge, compiler_result));
+                        }
+                    }
+                }
                 debug!(
-                    "[{}]: Compiled in {}, storing in cache",
+                    "[{}]: Compiled in {}{}, storing in cache",
                     out_pretty,
-                    fmt_duration_as_secs(&duration_compilation)
+                    fmt_duration_as_secs(&duration_compilation),
+                    if failed { ", but failed" } else { "" }
                 );
                 let start_create_artifact = Instant::now();
-                let mut entry = CacheWrite::from_objects(outputs, &pool)
-                    .await
-                    .context("failed to zip up compiler outputs")?;
+                // A failed compilation may leave stale outputs behind, so only
+                // its messages and exit code are stored.
+                let (depfile, outputs) = split_depfile(outputs, base_dir.as_ref());
+                let compression = storage.entry_config().compression;
+                let mut entry = if failed {
+                    CacheWrite::compressed(compression)
+                } else {
+                    CacheWrite::from_objects(outputs, compression, &pool)
+                        .await
+                        .context(CacheError("failed to zip up compiler outputs"))?
+                };
+                // The dependency file lists the paths of this compilation.
+                if let (Some(base_dir), Some(depfile)) = (&base_dir, depfile.filter(|_| !failed)) {
+                    let deps = fs::read(&depfile.path)
+                        .context(CacheError("failed to read the dependency file"))?;
+                    entry
+                        .put_object(
+                            DEPFILE_OUTPUT,
+                            &mut &base_dir.replace_with_placeholder(&deps)[..],
+                            None,
+                        )
+                        .context(CacheError("failed to store the dependency file"))?;
+                }
+                if let Some(packed) = packed_state {
+                    entry
+                        .put_object(STATE_OBJECT, &mut &packed[..], None)
+                        .context(CacheError("failed to store the compiler state"))?;
+                }
 
-                entry.put_stdout(&compiler_result.stdout)?;
-                entry.put_stderr(&compiler_result.stderr)?;
+                // The messages mention the base directory of this compilation,
+                // which may not be the one of the compilation hitting the cache.
+                let (stdout, stderr) = match &base_dir {
+                    Some(base_dir) if stdout_is_output => (
+                        Cow::Borrowed(&compiler_result.stdout[..]),
+                        base_dir.replace_with_placeholder(&compiler_result.stderr),
+                    ),
+                    Some(base_dir) => (
+                        base_dir.replace_with_placeholder(&compiler_result.stdout),
+                        base_dir.replace_with_placeholder(&compiler_result.stderr),
+                    ),
+                    None => (
+                        Cow::Borrowed(&compiler_result.stdout[..]),
+                        Cow::Borrowed(&compiler_result.stderr[..]),
+                    ),
+                };
+                entry
+                    .put_stdout(&stdout)
+                    .context(CacheError("failed to store the compiler stdout"))?;
+                entry
+                    .put_stderr(&stderr)
+                    .context(CacheError("failed to store the compiler stderr"))?;
+                entry
+                    .put_exit_code(compiler_result.status.code().unwrap_or_default())
+                    .context(CacheError("failed to store the compiler exit code"))?;
+                if let Some(build_id) = &metadata.build_id {
+                    entry
+                        .put_build_id(build_id)
+                        .context(CacheError("failed to store the build ID"))?;
+                }
                 debug!(
                     "[{}]: Created cache artifact in {}",
                     out_pretty,
                     fmt_duration_as_secs(&start_create_artifact.elapsed())
                 );
 
+                // The compilations waiting for this one read the entry while
+                // it's being stored.
+                let entry = match &flight {
+                    Some(flight) if share_pending_writes => {
+                        let bytes: Arc<[u8]> = entry
+                            .finish()
+                            .context(CacheError("failed to finish the cache entry"))?
+                            .into();
+                        flight.share(bytes.clone());
+                        CacheWrite::from_bytes(bytes.to_vec())?
+                    }
+                    _ => entry,
+                };
+
                 let out_pretty2 = out_pretty.clone();
                 // Try to finish storing the newly-written cache
                 // entry. We'll get the result back elsewhere.
                 let future = async move {
+                    let mut flight = flight;
                     let start = Instant::now();
+                    // The storages only see the size of the entry once it's finished.
+                    let mut entry = if result_log::is_recording() {
+                        let bytes = entry.finish()?;
+                        update_record(|r| r.bytes_written = Some(bytes.len() as u64));
+                        CacheWrite::from_bytes(bytes)?
+                    } else {
+                        entry
+                    };
+                    entry.set_metadata(metadata);
                     match storage.put(&key, entry).await {
                         Ok(_) => {
                             debug!("[{}]: Stored in cache successfully!", out_pretty2);
+                            if let Some(flight) = flight.as_mut() {
+                                flight.stored();
+                            }
                             Ok(CacheWriteInfo {
                                 object_file_pretty: out_pretty2,
                                 duration: start.elapsed(),
@@ -492,10 +998,12 @@ where
                     }
                 };
                 let future = Box::pin(future);
-                Ok((
-                    CompileResult::CacheMiss(miss_type, dist_type, duration_compilation, future),
-                    compiler_result,
-                ))
+                let result = if failed {
+                    CompileResult::CompileFailed(Some(future))
+                } else {
+                    CompileResult::CacheMiss(miss_type, dist_type, duration_compilation, future)
+                };
+                Ok((result, compiler_result))
             }
         }
         .with_context(|| format!("failed to store `{}` to cache", out_pretty))
@@ -510,6 +1018,39 @@ where
     fn box_clone(&self) -> Box<dyn CompilerHasher<T>>;
 
     fn language(&self) -> Language;
+
+    /// What the cache entry of this compilation is made with, as of now.
+    fn entry_metadata(&self) -> EntryMetadata;
+
+    /// Whether the compiler reads its input from stdin, which the client has
+    /// to capture and pass along with `set_stdin`.
+    fn reads_stdin(&self) -> bool {
+        false
+    }
+
+    /// Give the compiler the contents of stdin.
+    fn set_stdin(&mut self, _stdin: Vec<u8>) {}
+
+    /// Whether the compiler writes its output to stdout, so that stdout is
+    /// the output itself rather than messages.
+    fn writes_stdout(&self) -> bool {
+        false
+    }
+}
+
+/// Turn the error of a failed local compilation back into its output, so that
+/// failures are checked the same way as distributed compilations are.
+fn failure_as_output(res: Result<process::Output>) -> Result<process::Output> {
+    res.or_else(|e| match e.downcast::<ProcessError>() {
+        Ok(ProcessError(output)) => Ok(output),
+        Err(e) => Err(e),
+    })
+}
+
+/// Whether `path` exists and isn't a regular file, e.g. it's a FIFO or a
+/// device.
+fn is_special_file(path: &Path) -> bool {
+    std::fs::metadata(path).is_ok_and(|metadata| !metadata.is_file())
 }
 
 #[cfg(not(feature = "dist-client"))]
@@ -530,9 +1071,7 @@ where
         .context("Failed to generate compile commands")?;
 
     debug!("[{}]: Compiling locally", out_pretty);
-    compile_cmd
-        .execute(&creator)
-        .await
+    failure_as_output(compile_cmd.execute_live(&creator).await)
         .map(move |o| (cacheable, DistType::NoDist, o))
 }
 
@@ -563,9 +1102,7 @@ where
         Some(dc) => dc,
         None => {
             debug!("[{}]: Compiling locally", out_pretty);
-            return compile_cmd
-                .execute(&creator)
-                .await
+            return failure_as_output(compile_cmd.execute_live(&creator).await)
                 .map(move |o| (cacheable, DistType::NoDist, o));
         }
     };
@@ -581,8 +1118,8 @@ where
             dist_compile_cmd.context("Could not create distributed compile command")?;
         debug!("[{}]: Creating distributed compile request", out_pretty);
         let dist_output_paths = compilation
-            .outputs()
-            .map(|output| path_transformer.as_dist_abs(&cwd.join(output.path)))
+            .outputs_in(&cwd)
+            .map(|output| path_transformer.as_dist_abs(&output.path))
             .collect::<Option<_>>()
             .context("Failed to adapt an output path for distributed compile")?;
         let (inputs_packager, toolchain_packager, outputs_rewriter) =
@@ -696,10 +1233,11 @@ where
             // Do this first so cleanup works correctly
             let local_path = output_paths.last().expect("nothing in vec after push");
 
-            let mut file = try_or_cleanup!(File::create(local_path)
-                .with_context(|| format!("Failed to create output file {}", local_path.display())));
-            let count = try_or_cleanup!(io::copy(&mut output_data.into_reader(), &mut file)
-                .with_context(|| format!("Failed to write output to {}", local_path.display())));
+            // Written at once, as the outputs restored from the cache are.
+            let count = try_or_cleanup!(write_atomically(local_path, |file| {
+                io::copy(&mut output_data.into_reader(), file)
+                    .with_context(|| format!("Failed to write output to {}", local_path.display()))
+            }));
 
             assert!(count == len);
         }
@@ -734,9 +1272,7 @@ where
                     out_pretty2, errmsg
                 );
 
-                compile_cmd
-                    .execute(&creator)
-                    .await
+                failure_as_output(compile_cmd.execute_live(&creator).await)
                     .map(|o| (DistType::Error, o))
             }
         })
@@ -772,6 +1308,57 @@ pub trait Compilation: Send {
     /// Each item is a descriptive (and unique) name of the output paired with
     /// the path where it'll show up.
     fn outputs<'a>(&'a self) -> Box<dyn Iterator<Item = FileObjectSource> + 'a>;
+
+    /// The outputs of this compilation, with their paths resolved against
+    /// `cwd`, the working directory of the compile request. Relative paths
+    /// must never be resolved against the working directory of the server.
+    fn outputs_in<'a>(&'a self, cwd: &'a Path) -> Box<dyn Iterator<Item = FileObjectSource> + 'a> {
+        Box::new(self.outputs().map(move |output| FileObjectSource {
+            path: cwd.join(output.path),
+            ..output
+        }))
+    }
+
+    /// The state the compiler keeps between compilations which is cached
+    /// along with the outputs, if any.
+    fn state(&self) -> Option<Box<dyn CompilerState>> {
+        None
+    }
+}
+
+/// The name of the object of a cache entry holding the `CompilerState`.
+const STATE_OBJECT: &str = "compiler-state";
+
+/// The key of the output of a C-like compilation listing the dependencies of
+/// the object, e.g. with `-MD`. Under a base directory, it is stored with the
+/// base directory replaced by a placeholder, like the compiler messages.
+pub const DEPFILE_OUTPUT: &str = "d";
+
+/// Split the dependency file out of `outputs`, when it is rewritten for the
+/// base directory.
+fn split_depfile(
+    outputs: Vec<FileObjectSource>,
+    base_dir: Option<&BaseDir>,
+) -> (Option<FileObjectSource>, Vec<FileObjectSource>) {
+    if base_dir.is_none() {
+        return (None, outputs);
+    }
+    let (mut depfile, outputs): (Vec<_>, Vec<_>) = outputs
+        .into_iter()
+        .partition(|output| output.key == DEPFILE_OUTPUT);
+    (depfile.pop(), outputs)
+}
+
+/// State a compiler keeps between compilations, e.g. the incremental state
+/// of rustc, which is stored in the cache entry of a compilation so that a
+/// cache hit restores it too.
+pub trait CompilerState: Send {
+    /// Pack the state left by the compilation.
+    fn pack(&self) -> Result<Vec<u8>>;
+
+    /// Replace the state with `packed`, from a cache hit. The state must be
+    /// left as it was if this fails.
+    fn restore(&self, packed: &[u8]) -> Result<()>;
 }
 
 #[cfg(feature = "dist-client")]
@@ -807,6 +1394,26 @@ pub struct HashResult {
     pub compilation: Box<dyn Compilation + 'static>,
     /// A weak key that may be used to identify the toolchain
     pub weak_toolchain_key: String,
+    /// The inputs that went into `key`, as reported by `sccache --explain`.
+    pub inputs: Vec<HashKeyInput>,
+}
+
+/// A single input of a hash key.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HashKeyInput {
+    /// What kind of input this is, e.g. `argument` or `env`.
+    pub kind: String,
+    /// The hashed value, or the digest of the contents for files.
+    pub value: String,
+}
+
+impl HashKeyInput {
+    pub fn new(kind: &str, value: impl Into<String>) -> HashKeyInput {
+        HashKeyInput {
+            kind: kind.to_owned(),
+            value: value.into(),
+        }
+    }
 }
 
 /// Possible results of parsing compiler arguments.
@@ -870,7 +1477,8 @@ pub struct CacheWriteInfo {
 
 /// The result of a compilation or cache retrieval.
 pub enum CompileResult {
-    /// An error made the compilation not possible.
+    /// Preprocessing the input, or whatever else computing the hash key
+    /// runs the compiler for, failed.
     Error,
     /// Result was found in cache.
     CacheHit(Duration),
@@ -886,8 +1494,14 @@ pub enum CompileResult {
     ),
     /// Not in cache, but the compilation result was determined to be not cacheable.
     NotCacheable,
+    /// Not in cache, and the outputs of the compilation are too large to be
+    /// cached, as set by `SCCACHE_MAX_ENTRY_SIZE`.
+    TooLarge,
     /// Not in cache, but compilation failed.
-    CompileFailed,
+    ///
+    /// With `SCCACHE_CACHE_FAILURES`, the `CacheWriteFuture` will resolve when
+    /// the failure is finished being stored in the cache.
+    CompileFailed(Option<Pin<Box<dyn Future<Output = Result<CacheWriteInfo>> + Send>>>),
 }
 
 /// The state of `--color` options passed to a compiler.
@@ -909,7 +1523,8 @@ impl fmt::Debug for CompileResult {
                 write!(f, "CompileResult::CacheMiss({:?}, {:?}, {:?}, _)", d, m, dt)
             }
             CompileResult::NotCacheable => write!(f, "CompileResult::NotCacheable"),
-            CompileResult::CompileFailed => write!(f, "CompileResult::CompileFailed"),
+            CompileResult::TooLarge => write!(f, "CompileResult::TooLarge"),
+            CompileResult::CompileFailed(_) => write!(f, "CompileResult::CompileFailed"),
         }
     }
 }
@@ -924,12 +1539,26 @@ impl PartialEq<CompileResult> for CompileResult {
                 m == n && dt == dt2
             }
             (&CompileResult::NotCacheable, &CompileResult::NotCacheable) => true,
-            (&CompileResult::CompileFailed, &CompileResult::CompileFailed) => true,
+            (&CompileResult::TooLarge, &CompileResult::TooLarge) => true,
+            (&CompileResult::CompileFailed(_), &CompileResult::CompileFailed(_)) => true,
             _ => false,
         }
     }
 }
 
+/// Get the largest total size of the outputs of a compilation that is stored
+/// in `storage`, from its `EntryConfig`, e.g. `SCCACHE_MAX_ENTRY_SIZE=512M`, or
+/// else a tenth of the size of the cache. A size of 0 disables the limit.
+async fn max_entry_size(storage: &dyn Storage) -> Option<u64> {
+    match storage.entry_config().max_size {
+        Some(0) => return None,
+        Some(size) => return Some(size),
+        None => {}
+    }
+    let max_size = storage.max_size().await.ok().flatten()?;
+    Some(max_size / 10)
+}
+
 /// Can this result be stored in cache?
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Cacheable {
@@ -946,6 +1575,28 @@ pub enum CacheControl {
     ForceRecache,
 }
 
+impl CacheControl {
+    /// The caching behavior for the compilation producing `output`.
+    ///
+    /// `SCCACHE_RECACHE` forces recompilation of everything, while
+    /// `SCCACHE_RECACHE_ONLY` holds a comma separated list of the outputs it
+    /// applies to: object file names for C and C++, crate names for Rust.
+    pub fn from_env(env_vars: &[(OsString, OsString)], output: &str) -> CacheControl {
+        let force_recache = env_vars.iter().any(|(k, v)| {
+            k == "SCCACHE_RECACHE"
+                || (k == "SCCACHE_RECACHE_ONLY"
+                    && v.to_string_lossy()
+                        .split(',')
+                        .any(|only| only.trim() == output))
+        });
+        if force_recache {
+            CacheControl::ForceRecache
+        } else {
+            CacheControl::Default
+        }
+    }
+}
+
 /// Creates a future that will write `contents` to `path` inside of a temporary
 /// directory.
 ///
@@ -962,7 +1613,9 @@ pub async fn write_temp_file(
 ) -> Result<(TempDir, PathBuf)> {
     let path = path.to_owned();
     pool.spawn_blocking(move || {
-        let dir = tempfile::Builder::new().prefix("sccache").tempdir()?;
+        let dir = tempfile::Builder::new()
+            .prefix("sccache")
+            .tempdir_in(config::scratch_dir())?;
         let src = dir.path().join(path);
         let mut file = File::create(&src)?;
         file.write_all(&contents)?;
@@ -1008,6 +1661,75 @@ fn is_known_c_compiler<P: AsRef<Path>>(p: P) -> bool {
     )
 }
 
+/// The variable of the client environment forcing the kind of the compiler,
+/// skipping the detection of C compilers.
+pub const COMPILER_KIND_ENV_VAR: &str = "SCCACHE_COMPILER_KIND";
+
+/// Get the compiler kind forced by `SCCACHE_COMPILER_KIND` in `env`: `rust`
+/// or one of the C compiler ids.
+fn compiler_kind_override(env: &[(OsString, OsString)]) -> Option<&str> {
+    let (_, kind) = env.iter().find(|(k, _)| k == COMPILER_KIND_ENV_VAR)?;
+    match kind.to_str() {
+        Some(kind) if kind == "rust" || C_COMPILER_IDS.contains(&kind) => Some(kind),
+        _ => {
+            warn!(
+                "Ignoring {}={:?}: not a known compiler kind",
+                COMPILER_KIND_ENV_VAR, kind
+            );
+            None
+        }
+    }
+}
+
+/// Get the family of compilers the file name of `executable` looks like,
+/// if any, e.g. `gcc` for `x86_64-linux-gnu-g++-12`.
+fn kind_family_from_name(executable: &Path) -> Option<&'static str> {
+    let stem = executable.file_stem()?.to_string_lossy().to_lowercase();
+    if is_rustc_like(executable) {
+        return Some("rust");
+    }
+    if stem == "clang-cl" {
+        return Some("clang-cl");
+    }
+    // Drop the version and the target, if any.
+    let name = stem.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.' || c == '-');
+    match name.rsplit('-').next()? {
+        "gcc" | "g++" => Some("gcc"),
+        "clang" | "clang++" => Some("clang"),
+        "cl" => Some("msvc"),
+        "nvcc" => Some("nvcc"),
+        "nvc" | "nvc++" => Some("nvhpc"),
+        _ => None,
+    }
+}
+
+/// Get the family of compilers of the compiler kind `kind`.
+fn kind_family(kind: &str) -> &str {
+    match kind {
+        "g++" => "gcc",
+        "clang++" | "apple-clang" | "apple-clang++" => "clang",
+        "msvc-clang" => "clang-cl",
+        "nvcc-msvc" | "nvcc-nvhpc" => "nvcc",
+        kind => kind,
+    }
+}
+
+/// Warn if the compiler kind `kind` forced for `executable` is obviously not
+/// the one of its name.
+fn warn_on_kind_mismatch(executable: &Path, kind: &str) {
+    if let Some(family) = kind_family_from_name(executable) {
+        if family != kind_family(kind) {
+            warn!(
+                "{} is forced to be {} by {}, but looks like {}",
+                executable.display(),
+                kind,
+                COMPILER_KIND_ENV_VAR,
+                family
+            );
+        }
+    }
+}
+
 /// If `executable` is a known compiler, return `Some(Box<Compiler>)`.
 async fn detect_compiler<T>(
     creator: T,
@@ -1022,6 +1744,26 @@ where
     T: CommandCreatorSync,
 {
     trace!("detect_compiler: {}", executable.display());
+    if let Some(kind) = compiler_kind_override(env) {
+        debug!("{} is forced to be {}", executable.display(), kind);
+        warn_on_kind_mismatch(executable, kind);
+        if kind == "rust" {
+            return resolve_rust_compiler(
+                creator,
+                executable,
+                executable.to_path_buf(),
+                env,
+                cwd.to_path_buf(),
+                dist_archive,
+                pool.clone(),
+            )
+            .await;
+        }
+        let probe = complete_probe(&creator, kind, None, executable, env.to_vec(), pool).await?;
+        return c_compiler_from_probe(probe, executable, pool)
+            .await
+            .map(|c| (c, None));
+    }
     // First, see if this looks like rustc.
 
     let maybe_rustc_executable = if is_rustc_like(executable) {
@@ -1096,7 +1838,8 @@ where
     let mut child = creator.clone().new_command_sync(executable);
     // We're wrapping rustc if the executable doesn't match the detected rustc_executable. In this case the wrapper
     // expects rustc as the first argument.
-    if rustc_executable != executable {
+    let workspace_wrapper = (rustc_executable != executable).then(|| executable.to_owned());
+    if workspace_wrapper.is_some() {
         child.arg(&rustc_executable);
     }
 
@@ -1179,7 +1922,7 @@ where
             .await
             .map(|c| {
                 (
-                    Box::new(c) as Box<dyn Compiler<T>>,
+                    Box::new(c.with_workspace_wrapper(workspace_wrapper)) as Box<dyn Compiler<T>>,
                     proxy as Option<Box<dyn CompilerProxy<T>>>,
                 )
             })
@@ -1270,22 +2013,29 @@ compiler_version=__VERSION__
     let (tempdir, src) = write_temp_file(&pool, "testfile.c".as_ref(), test).await?;
 
     let executable = executable.as_ref();
-    let mut cmd = creator.clone().new_command_sync(executable);
-    cmd.stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .envs(env.iter().map(|s| (&s.0, &s.1)));
 
     // Iterate over all the arguments for compilation and extract
     // any that are required for any valid execution of the compiler.
     // Allowing our compiler vendor detection to always properly execute
+    let mut required_args = vec![];
     for arg in ArgsIter::new(arguments.iter().cloned(), &ARGS[..]) {
         let arg = arg.unwrap_or_else(|_| Argument::Raw(OsString::from("")));
         if let Some(Detect_PassThrough(_)) = arg.get_data() {
             let required_arg = arg.normalize(NormalizedDisposition::Concatenated);
-            cmd.args(&Vec::from_iter(required_arg.iter_os_strings()));
+            required_args.extend(required_arg.iter_os_strings());
         }
     }
 
+    if let Some(probe) = PROBE_CACHE.get(executable, &required_args, &env) {
+        debug!("Using the cached probe of {}", executable.display());
+        return c_compiler_from_probe(probe, executable, &pool).await;
+    }
+
+    let mut cmd = creator.clone().new_command_sync(executable);
+    cmd.stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .envs(env.iter().map(|s| (&s.0, &s.1)))
+        .args(&required_args);
     cmd.arg("-E").arg(src);
     trace!("compiler {:?}", cmd);
     let child = cmd.spawn().await?;
@@ -1311,115 +2061,16 @@ compiler_version=__VERSION__
         }
     });
     if let Some(kind) = lines.next() {
-        let executable = executable.to_owned();
         let version = lines
             .next()
             // In case the compiler didn't expand the macro.
             .filter(|&line| line != "__VERSION__")
             .map(str::to_owned);
-        match kind {
-            "clang" | "clang++" | "apple-clang" | "apple-clang++" => {
-                debug!("Found {}", kind);
-                return CCompiler::new(
-                    Clang {
-                        clangplusplus: kind.ends_with("++"),
-                        is_appleclang: kind.starts_with("apple-"),
-                        version: version.clone(),
-                    },
-                    executable,
-                    &pool,
-                )
-                .await
-                .map(|c| Box::new(c) as Box<dyn Compiler<T>>);
-            }
-            "diab" => {
-                debug!("Found diab");
-                return CCompiler::new(
-                    Diab {
-                        version: version.clone(),
-                    },
-                    executable,
-                    &pool,
-                )
-                .await
-                .map(|c| Box::new(c) as Box<dyn Compiler<T>>);
-            }
-            "gcc" | "g++" => {
-                debug!("Found {}", kind);
-                return CCompiler::new(
-                    Gcc {
-                        gplusplus: kind == "g++",
-                        version: version.clone(),
-                    },
-                    executable,
-                    &pool,
-                )
-                .await
-                .map(|c| Box::new(c) as Box<dyn Compiler<T>>);
-            }
-            "msvc" | "msvc-clang" => {
-                let is_clang = kind == "msvc-clang";
-                debug!("Found MSVC (is clang: {})", is_clang);
-                let prefix = msvc::detect_showincludes_prefix(
-                    &creator,
-                    executable.as_ref(),
-                    is_clang,
-                    env,
-                    &pool,
-                )
-                .await?;
-                trace!("showIncludes prefix: '{}'", prefix);
-
-                return CCompiler::new(
-                    Msvc {
-                        includes_prefix: prefix,
-                        is_clang,
-                        version: version.clone(),
-                    },
-                    executable,
-                    &pool,
-                )
-                .await
-                .map(|c| Box::new(c) as Box<dyn Compiler<T>>);
-            }
-            "nvcc" | "nvcc-msvc" | "nvcc-nvhpc" => {
-                let host_compiler = match kind {
-                    "nvcc-nvhpc" => NvccHostCompiler::Nvhpc,
-                    "nvcc-msvc" => NvccHostCompiler::Msvc,
-                    "nvcc" => NvccHostCompiler::Gcc,
-                    &_ => NvccHostCompiler::Gcc,
-                };
-                return CCompiler::new(
-                    Nvcc {
-                        host_compiler,
-                        version: version.clone(),
-                    },
-                    executable,
-                    &pool,
-                )
-                .await
-                .map(|c| Box::new(c) as Box<dyn Compiler<T>>);
-            }
-            "nvhpc" => {
-                debug!("Found NVHPC");
-                return CCompiler::new(
-                    Nvhpc {
-                        nvcplusplus: kind == "nvc++",
-                        version: version.clone(),
-                    },
-                    executable,
-                    &pool,
-                )
-                .await
-                .map(|c| Box::new(c) as Box<dyn Compiler<T>>);
-            }
-            "tasking_vx" => {
-                debug!("Found Tasking VX");
-                return CCompiler::new(TaskingVX, executable, &pool)
-                    .await
-                    .map(|c| Box::new(c) as Box<dyn Compiler<T>>);
-            }
-            _ => (),
+        if C_COMPILER_IDS.contains(&kind) {
+            let probe =
+                complete_probe(&creator, kind, version, executable, env.clone(), &pool).await?;
+            PROBE_CACHE.insert(executable, &required_args, &env, probe.clone());
+            return c_compiler_from_probe(probe, executable, &pool).await;
         }
     }
 
@@ -1431,32 +2082,195 @@ compiler_version=__VERSION__
     bail!(stderr.into_owned())
 }
 
-/// If `executable` is a known compiler, return a `Box<Compiler>` containing information about it.
-pub async fn get_compiler_info<T>(
-    creator: T,
+/// The ids of the C compilers told apart by `detect_c_compiler`.
+const C_COMPILER_IDS: &[&str] = &[
+    "gcc",
+    "g++",
+    "clang",
+    "clang++",
+    "apple-clang",
+    "apple-clang++",
+    "msvc",
+    "msvc-clang",
+    "nvcc",
+    "nvcc-msvc",
+    "nvcc-nvhpc",
+    "nvhpc",
+    "diab",
+    "tasking_vx",
+];
+
+/// Find out what else than its id and version is needed to create the C
+/// compiler `executable`.
+async fn complete_probe<T>(
+    creator: &T,
+    id: &str,
+    version: Option<String>,
     executable: &Path,
-    cwd: &Path,
-    args: &[OsString],
-    env: &[(OsString, OsString)],
+    env: Vec<(OsString, OsString)>,
     pool: &tokio::runtime::Handle,
-    dist_archive: Option<PathBuf>,
-) -> Result<(Box<dyn Compiler<T>>, Option<Box<dyn CompilerProxy<T>>>)>
+) -> Result<CompilerProbe>
 where
     T: CommandCreatorSync,
 {
-    let pool = pool.clone();
-    detect_compiler(creator, executable, cwd, args, env, &pool, dist_archive).await
-}
+    let includes_prefix = match id {
+        "msvc" | "msvc-clang" => {
+            let prefix = msvc::detect_showincludes_prefix(
+                creator,
+                executable.as_ref(),
+                id == "msvc-clang",
+                env,
+                pool,
+            )
+            .await?;
+            trace!("showIncludes prefix: '{}'", prefix);
+            Some(prefix)
+        }
+        _ => None,
+    };
+    Ok(CompilerProbe {
+        id: id.to_owned(),
+        version,
+        includes_prefix,
+    })
+}
+
+/// Create the C compiler `executable` is, given its probe.
+async fn c_compiler_from_probe<T>(
+    probe: CompilerProbe,
+    executable: &Path,
+    pool: &tokio::runtime::Handle,
+) -> Result<Box<dyn Compiler<T>>>
+where
+    T: CommandCreatorSync,
+{
+    let executable = executable.to_owned();
+    let CompilerProbe {
+        id,
+        version,
+        includes_prefix,
+    } = probe;
+    let kind = id.as_str();
+    match kind {
+        "clang" | "clang++" | "apple-clang" | "apple-clang++" => {
+            debug!("Found {}", kind);
+            CCompiler::new(
+                Clang {
+                    clangplusplus: kind.ends_with("++"),
+                    is_appleclang: kind.starts_with("apple-"),
+                    version,
+                },
+                executable,
+                pool,
+            )
+            .await
+            .map(|c| Box::new(c) as Box<dyn Compiler<T>>)
+        }
+        "diab" => {
+            debug!("Found diab");
+            CCompiler::new(Diab { version }, executable, pool)
+                .await
+                .map(|c| Box::new(c) as Box<dyn Compiler<T>>)
+        }
+        "gcc" | "g++" => {
+            debug!("Found {}", kind);
+            CCompiler::new(
+                Gcc {
+                    gplusplus: kind == "g++",
+                    version,
+                },
+                executable,
+                pool,
+            )
+            .await
+            .map(|c| Box::new(c) as Box<dyn Compiler<T>>)
+        }
+        "msvc" | "msvc-clang" => {
+            let is_clang = kind == "msvc-clang";
+            debug!("Found MSVC (is clang: {})", is_clang);
+            let prefix = includes_prefix.context("the showIncludes prefix is unknown")?;
+
+            CCompiler::new(
+                Msvc {
+                    includes_prefix: prefix,
+                    is_clang,
+                    version,
+                },
+                executable,
+                pool,
+            )
+            .await
+            .map(|c| Box::new(c) as Box<dyn Compiler<T>>)
+        }
+        "nvcc" | "nvcc-msvc" | "nvcc-nvhpc" => {
+            let host_compiler = match kind {
+                "nvcc-nvhpc" => NvccHostCompiler::Nvhpc,
+                "nvcc-msvc" => NvccHostCompiler::Msvc,
+                "nvcc" => NvccHostCompiler::Gcc,
+                &_ => NvccHostCompiler::Gcc,
+            };
+            CCompiler::new(
+                Nvcc {
+                    host_compiler,
+                    version,
+                },
+                executable,
+                pool,
+            )
+            .await
+            .map(|c| Box::new(c) as Box<dyn Compiler<T>>)
+        }
+        "nvhpc" => {
+            debug!("Found NVHPC");
+            CCompiler::new(
+                Nvhpc {
+                    nvcplusplus: kind == "nvc++",
+                    version,
+                },
+                executable,
+                pool,
+            )
+            .await
+            .map(|c| Box::new(c) as Box<dyn Compiler<T>>)
+        }
+        "tasking_vx" => {
+            debug!("Found Tasking VX");
+            CCompiler::new(TaskingVX, executable, pool)
+                .await
+                .map(|c| Box::new(c) as Box<dyn Compiler<T>>)
+        }
+        _ => bail!("Unknown compiler id {}", kind),
+    }
+}
+
+/// If `executable` is a known compiler, return a `Box<Compiler>` containing information about it.
+pub async fn get_compiler_info<T>(
+    creator: T,
+    executable: &Path,
+    cwd: &Path,
+    args: &[OsString],
+    env: &[(OsString, OsString)],
+    pool: &tokio::runtime::Handle,
+    dist_archive: Option<PathBuf>,
+) -> Result<(Box<dyn Compiler<T>>, Option<Box<dyn CompilerProxy<T>>>)>
+where
+    T: CommandCreatorSync,
+{
+    let pool = pool.clone();
+    detect_compiler(creator, executable, cwd, args, env, &pool, dist_archive).await
+}
 
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::cache::disk::DiskCache;
     use crate::cache::{CacheMode, CacheRead, PreprocessorCacheModeConfig};
+    use crate::compiler::in_flight::{self, Flights};
     use crate::mock_command::*;
     use crate::test::mock_storage::MockStorage;
     use crate::test::utils::*;
     use fs::File;
+    use futures::TryStreamExt;
     use std::io::{Cursor, Write};
     use std::sync::Arc;
     use std::time::Duration;
@@ -1489,6 +2303,61 @@ mod test {
         assert_eq!(CompilerKind::C(CCompilerKind::Gcc), c.kind());
     }
 
+    #[test]
+    fn test_detect_compiler_kind_override() {
+        let f = TestFixture::new();
+        let creator = new_creator();
+        let runtime = single_threaded_runtime();
+        let pool = runtime.handle();
+        let gcc = f.mk_bin("gcc").unwrap();
+        // No command runs, the kind is taken as is.
+        let env = vec![(OsString::from(COMPILER_KIND_ENV_VAR), "clang".into())];
+        let c = detect_compiler(
+            creator.clone(),
+            &gcc,
+            f.tempdir.path(),
+            &[],
+            &env,
+            pool,
+            None,
+        )
+        .wait()
+        .unwrap()
+        .0;
+        assert_eq!(CompilerKind::C(CCompilerKind::Clang), c.kind());
+
+        // An unknown kind is ignored.
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")),
+        );
+        let env = vec![(OsString::from(COMPILER_KIND_ENV_VAR), "fortran".into())];
+        let c = detect_compiler(creator, &gcc, f.tempdir.path(), &[], &env, pool, None)
+            .wait()
+            .unwrap()
+            .0;
+        assert_eq!(CompilerKind::C(CCompilerKind::Gcc), c.kind());
+    }
+
+    #[test]
+    fn test_kind_family_from_name() {
+        assert_eq!(kind_family_from_name("/usr/bin/gcc".as_ref()), Some("gcc"));
+        assert_eq!(
+            kind_family_from_name("x86_64-linux-gnu-g++-12".as_ref()),
+            Some("gcc")
+        );
+        assert_eq!(kind_family_from_name("clang-17".as_ref()), Some("clang"));
+        assert_eq!(
+            kind_family_from_name("clang-cl.exe".as_ref()),
+            Some("clang-cl")
+        );
+        assert_eq!(kind_family_from_name("cl.exe".as_ref()), Some("msvc"));
+        assert_eq!(kind_family_from_name("rustc".as_ref()), Some("rust"));
+        assert_eq!(kind_family_from_name("cc".as_ref()), None);
+        assert_eq!(kind_family("g++"), "gcc");
+        assert_eq!(kind_family("msvc-clang"), "clang-cl");
+    }
+
     #[test]
     fn test_detect_compiler_kind_clang() {
         let f = TestFixture::new();
@@ -1747,6 +2616,82 @@ LLVM version: 6.0",
         .is_ok());
     }
 
+    #[test]
+    fn test_rustc_workspace_wrapper_not_cached() {
+        let f = TestFixture::new();
+        // Windows uses bin, everything else uses lib. Just create both.
+        fs::create_dir(f.tempdir.path().join("lib")).unwrap();
+        fs::create_dir(f.tempdir.path().join("bin")).unwrap();
+        let rustc = f.mk_bin("rustc.exe").unwrap();
+        let wrapper = f.mk_bin("rustc-workspace-wrapper").unwrap();
+        let runtime = single_threaded_runtime();
+        let pool = runtime.handle();
+        let cargo_env = [(OsString::from("CARGO"), OsString::from("CARGO"))];
+        let args = ovec![
+            "--emit=link",
+            "foo.rs",
+            "--out-dir",
+            "out",
+            "--crate-name=foo",
+            "--crate-type=lib"
+        ];
+
+        // `sccache rustc ...`, as the RUSTC_WRAPPER or as the only
+        // RUSTC_WORKSPACE_WRAPPER.
+        let creator = new_creator();
+        populate_rustc_command_mock(&creator, &f);
+        let c = detect_compiler(
+            creator,
+            &rustc,
+            f.tempdir.path(),
+            &args,
+            &cargo_env,
+            pool,
+            None,
+        )
+        .wait()
+        .unwrap()
+        .0;
+        assert!(matches!(
+            c.parse_arguments(&args, f.tempdir.path(), &cargo_env),
+            CompilerArguments::Ok(_)
+        ));
+
+        // `sccache $RUSTC_WORKSPACE_WRAPPER rustc ...`, for a workspace member.
+        let mut wrapped_args = vec![rustc.clone().into_os_string()];
+        wrapped_args.extend(args.iter().cloned());
+        let creator = new_creator();
+        populate_rustc_command_mock(&creator, &f);
+        let c = detect_compiler(
+            creator,
+            &wrapper,
+            f.tempdir.path(),
+            &wrapped_args,
+            &cargo_env,
+            pool,
+            None,
+        )
+        .wait()
+        .unwrap()
+        .0;
+        assert_eq!(CompilerKind::Rust, c.kind());
+        match c.parse_arguments(&wrapped_args, f.tempdir.path(), &cargo_env) {
+            CompilerArguments::CannotCache(why, Some(extra)) => {
+                assert_eq!(why, "RUSTC_WORKSPACE_WRAPPER");
+                assert_eq!(extra, wrapper.display().to_string());
+            }
+            _ => panic!("Workspace wrapper compilation was cacheable"),
+        }
+        assert!(matches!(
+            c.parse_arguments(
+                &[rustc.into_os_string(), "--version".into()],
+                f.tempdir.path(),
+                &cargo_env
+            ),
+            CompilerArguments::NotCompilation
+        ));
+    }
+
     #[test]
     fn test_detect_compiler_kind_diab() {
         let f = TestFixture::new();
@@ -1938,6 +2883,95 @@ LLVM version: 6.0",
         assert_ne!(results[0].key, results[2].key);
     }
 
+    fn generate_gcc_hash_key(
+        f: &TestFixture,
+        preprocessor: MockChild,
+        preprocessor_cache_mode: bool,
+    ) -> Result<HashResult> {
+        let creator = new_creator();
+        let runtime = single_threaded_runtime();
+        let pool = runtime.handle();
+        let gcc = f.mk_bin("gcc").unwrap();
+        std::fs::write(f.tempdir.path().join("foo.c"), "whatever").unwrap();
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")),
+        );
+        let c = detect_compiler(
+            creator.clone(),
+            &gcc,
+            f.tempdir.path(),
+            &[],
+            &[],
+            pool,
+            None,
+        )
+        .wait()
+        .unwrap()
+        .0;
+        next_command(&creator, Ok(preprocessor));
+        let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
+        let hasher = match c.parse_arguments(&arguments, ".".as_ref(), &[]) {
+            CompilerArguments::Ok(h) => h,
+            o => panic!("Bad result from parse_arguments: {:?}", o),
+        };
+        hasher
+            .generate_hash_key(
+                &creator,
+                f.tempdir.path().to_path_buf(),
+                vec![],
+                false,
+                pool,
+                false,
+                Arc::new(MockStorage::new(None, preprocessor_cache_mode)),
+                CacheControl::Default,
+            )
+            .wait()
+    }
+
+    #[test]
+    fn test_hash_while_preprocessing() {
+        let f = TestFixture::new();
+        // Large enough to be read in several chunks.
+        let output = "int x;\n".repeat(100_000);
+        // The output is hashed while it is read without the preprocessor cache,
+        // and once it is complete with it.
+        let streamed =
+            generate_gcc_hash_key(&f, MockChild::new(exit_status(0), &output, ""), false).unwrap();
+        let at_once =
+            generate_gcc_hash_key(&f, MockChild::new(exit_status(0), &output, ""), true).unwrap();
+        assert_eq!(streamed.key, at_once.key);
+        let output_input = |r: &HashResult| {
+            r.inputs
+                .iter()
+                .find(|i| i.kind == "preprocessor output")
+                .map(|i| i.value.clone())
+        };
+        assert_eq!(output_input(&streamed), output_input(&at_once));
+    }
+
+    #[test]
+    fn test_preprocessor_fails_while_hashing() {
+        let f = TestFixture::new();
+        // The preprocessor fails after some of its output was already hashed.
+        let output = "int x;\n".repeat(100_000);
+        let err = generate_gcc_hash_key(
+            &f,
+            MockChild::new(exit_status(1 << 8), &output, "error: bad"),
+            false,
+        )
+        .map(|_| ())
+        .unwrap_err();
+        match err.downcast::<ProcessError>() {
+            Ok(ProcessError(output)) => {
+                assert_eq!(output.status.code(), Some(1));
+                assert!(output.stdout.is_empty());
+                assert_eq!(output.stderr, b"error: bad");
+            }
+            Err(e) => panic!("Unexpected error: {:?}", e),
+        }
+    }
+
     #[test]
     fn test_get_compiler_info() {
         let creator = new_creator();
@@ -2084,10 +3118,8 @@ LLVM version: 6.0",
         assert_eq!(COMPILER_STDERR, res.stderr.as_slice());
     }
 
-    #[test_case(true ; "with preprocessor cache")]
-    #[test_case(false ; "without preprocessor cache")]
-    #[cfg(feature = "dist-client")]
-    fn test_compiler_get_cached_or_compile_dist(preprocessor_cache_mode: bool) {
+    #[test]
+    fn test_compiler_get_cached_or_compile_relative_output() {
         drop(env_logger::try_init());
         let creator = new_creator();
         let f = TestFixture::new();
@@ -2098,76 +3130,892 @@ LLVM version: 6.0",
             f.tempdir.path().join("cache"),
             u64::MAX,
             &pool,
-            PreprocessorCacheModeConfig {
-                use_preprocessor_cache_mode: preprocessor_cache_mode,
-                ..Default::default()
-            },
+            PreprocessorCacheModeConfig::default(),
             CacheMode::ReadWrite,
         );
-        // Write a dummy input file so the preprocessor cache mode can work
-        std::fs::write(f.tempdir.path().join("foo.c"), "whatever").unwrap();
         let storage = Arc::new(storage);
-        // Pretend to be GCC.
+        // The client runs in another directory than the server.
+        let cwd = f.tempdir.path().join("project");
+        fs::create_dir_all(cwd.join("build")).unwrap();
+        assert_ne!(std::env::current_dir().unwrap(), cwd);
+        std::fs::write(cwd.join("foo.c"), "whatever").unwrap();
         next_command(
             &creator,
             Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")),
         );
-        let c = get_compiler_info(
-            creator.clone(),
-            &gcc,
-            f.tempdir.path(),
-            &[],
-            &[],
-            &pool,
-            None,
-        )
-        .wait()
-        .unwrap()
-        .0;
-        // The preprocessor invocation.
-        next_command(
-            &creator,
-            Ok(MockChild::new(exit_status(0), "preprocessor output", "")),
-        );
-        // The compiler invocation.
-        const COMPILER_STDOUT: &[u8] = b"compiler stdout";
-        const COMPILER_STDERR: &[u8] = b"compiler stderr";
-        let obj = f.tempdir.path().join("foo.o");
-        // Dist client will do the compilation
-        let dist_client = Some(test_dist::OneshotClient::new(
-            0,
-            COMPILER_STDOUT.to_owned(),
-            COMPILER_STDERR.to_owned(),
-        ));
-        let cwd = f.tempdir.path();
-        let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
-        let hasher = match c.parse_arguments(&arguments, ".".as_ref(), &[]) {
+        let c = get_compiler_info(creator.clone(), &gcc, &cwd, &[], &[], &pool, None)
+            .wait()
+            .unwrap()
+            .0;
+        let obj = cwd.join("build").join("foo.o");
+        let arguments = ovec!["-c", "foo.c", "-o", "build/foo.o"];
+        let hasher = match c.parse_arguments(&arguments, &cwd, &[]) {
             CompilerArguments::Ok(h) => h,
             o => panic!("Bad result from parse_arguments: {:?}", o),
         };
         let hasher2 = hasher.clone();
-        let (cached, res) = runtime
-            .block_on(async {
-                hasher
-                    .get_cached_or_compile(
-                        dist_client.clone(),
-                        creator.clone(),
-                        storage.clone(),
-                        arguments.clone(),
-                        cwd.to_path_buf(),
-                        vec![],
-                        CacheControl::Default,
-                        pool.clone(),
-                    )
-                    .await
-            })
-            .unwrap();
-        // Ensure that the object file was created.
-        assert!(fs::metadata(&obj).map(|m| m.len() > 0).unwrap());
-        match cached {
-            CompileResult::CacheMiss(MissType::Normal, DistType::Ok(_), _, f) => {
-                // wait on cache write future so we don't race with it!
-                f.wait().unwrap();
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "preprocessor output", "")),
+        );
+        let o = obj.clone();
+        next_command_calls(&creator, move |_| {
+            let mut f = File::create(&o)?;
+            f.write_all(b"file contents")?;
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+        let (cached, _) = runtime
+            .block_on(hasher.get_cached_or_compile(
+                None,
+                creator.clone(),
+                storage.clone(),
+                arguments.clone(),
+                cwd.clone(),
+                vec![],
+                CacheControl::Default,
+                pool.clone(),
+            ))
+            .unwrap();
+        match cached {
+            CompileResult::CacheMiss(MissType::Normal, DistType::NoDist, _, f) => {
+                f.wait().unwrap();
+            }
+            _ => panic!("Unexpected compile result: {:?}", cached),
+        }
+
+        // The hit restores the object relative to the cwd of the request.
+        fs::remove_file(&obj).unwrap();
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "preprocessor output", "")),
+        );
+        let (cached, _) = runtime
+            .block_on(hasher2.get_cached_or_compile(
+                None,
+                creator,
+                storage,
+                arguments,
+                cwd.clone(),
+                vec![],
+                CacheControl::Default,
+                pool,
+            ))
+            .unwrap();
+        assert_eq!(CompileResult::CacheHit(Duration::new(0, 0)), cached);
+        assert_eq!(fs::read(&obj).unwrap(), b"file contents");
+        assert!(!std::env::current_dir()
+            .unwrap()
+            .join("build")
+            .join("foo.o")
+            .exists());
+    }
+
+    #[test]
+    fn test_compiler_get_cached_or_compile_too_large() {
+        drop(env_logger::try_init());
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let gcc = f.mk_bin("gcc").unwrap();
+        let runtime = Runtime::new().unwrap();
+        let pool = runtime.handle().clone();
+        // The entries are limited to a tenth of the cache, 10 bytes.
+        let storage = DiskCache::new(
+            f.tempdir.path().join("cache"),
+            100,
+            &pool,
+            PreprocessorCacheModeConfig::default(),
+            CacheMode::ReadWrite,
+        );
+        let storage: Arc<dyn Storage> = Arc::new(storage);
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")),
+        );
+        let c = get_compiler_info(
+            creator.clone(),
+            &gcc,
+            f.tempdir.path(),
+            &[],
+            &[],
+            &pool,
+            None,
+        )
+        .wait()
+        .unwrap()
+        .0;
+        std::fs::write(f.tempdir.path().join("foo.c"), "whatever").unwrap();
+        let obj = f.tempdir.path().join("foo.o");
+        let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
+        let hasher = match c.parse_arguments(&arguments, f.tempdir.path(), &[]) {
+            CompilerArguments::Ok(h) => h,
+            o => panic!("Bad result from parse_arguments: {:?}", o),
+        };
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "preprocessor output", "")),
+        );
+        let o = obj.clone();
+        next_command_calls(&creator, move |_| {
+            std::fs::write(&o, b"file contents")?;
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+        let (cached, res) = runtime
+            .block_on(hasher.get_cached_or_compile(
+                None,
+                creator,
+                storage.clone(),
+                arguments,
+                f.tempdir.path().to_owned(),
+                vec![],
+                CacheControl::Default,
+                pool,
+            ))
+            .unwrap();
+        // Compiled, but not stored.
+        assert_eq!(CompileResult::TooLarge, cached);
+        assert_eq!(exit_status(0), res.status);
+        assert_eq!(fs::read(&obj).unwrap(), b"file contents");
+        assert_eq!(runtime.block_on(storage.current_size()).unwrap(), Some(0));
+    }
+
+    #[test]
+    fn test_compiler_get_cached_or_compile_build_id() {
+        drop(env_logger::try_init());
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let gcc = f.mk_bin("gcc").unwrap();
+        let runtime = Runtime::new().unwrap();
+        let pool = runtime.handle().clone();
+        let storage = DiskCache::new(
+            f.tempdir.path().join("cache"),
+            u64::MAX,
+            &pool,
+            PreprocessorCacheModeConfig::default(),
+            CacheMode::ReadWrite,
+        );
+        let storage: Arc<dyn Storage> = Arc::new(storage);
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")),
+        );
+        let c = get_compiler_info(
+            creator.clone(),
+            &gcc,
+            f.tempdir.path(),
+            &[],
+            &[],
+            &pool,
+            None,
+        )
+        .wait()
+        .unwrap()
+        .0;
+        std::fs::write(f.tempdir.path().join("foo.c"), "whatever").unwrap();
+        let obj = f.tempdir.path().join("foo.o");
+        let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
+        // The entry written last, by the same compilation, keeps its build.
+        for (build_id, cache_control) in [
+            ("1c26a3a", CacheControl::Default),
+            ("402e545", CacheControl::ForceRecache),
+        ] {
+            let hasher = match c.parse_arguments(&arguments, f.tempdir.path(), &[]) {
+                CompilerArguments::Ok(h) => h,
+                o => panic!("Bad result from parse_arguments: {:?}", o),
+            };
+            next_command(
+                &creator,
+                Ok(MockChild::new(exit_status(0), "preprocessor output", "")),
+            );
+            let o = obj.clone();
+            next_command_calls(&creator, move |_| {
+                std::fs::write(&o, b"file contents")?;
+                Ok(MockChild::new(exit_status(0), "", ""))
+            });
+            let (cached, _) = runtime
+                .block_on(hasher.get_cached_or_compile(
+                    None,
+                    creator.clone(),
+                    storage.clone(),
+                    arguments.clone(),
+                    f.tempdir.path().to_owned(),
+                    vec![("SCCACHE_BUILD_ID".into(), build_id.into())],
+                    cache_control,
+                    pool.clone(),
+                ))
+                .unwrap();
+            match cached {
+                CompileResult::CacheMiss(_, DistType::NoDist, _, f) => f.wait().unwrap(),
+                _ => panic!("Unexpected compile result: {:?}", cached),
+            };
+            let entries: Vec<_> = runtime
+                .block_on(async { storage.list_entries("").await?.try_collect().await })
+                .unwrap();
+            let build_ids: Vec<_> = entries.iter().map(|e| e.build_id.as_deref()).collect();
+            assert_eq!(build_ids, [Some(build_id)]);
+        }
+    }
+
+    #[test]
+    fn test_compiler_get_cached_or_compile_base_dir() {
+        drop(env_logger::try_init());
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let gcc = f.mk_bin("gcc").unwrap();
+        let runtime = Runtime::new().unwrap();
+        let pool = runtime.handle().clone();
+        let storage = DiskCache::new(
+            f.tempdir.path().join("cache"),
+            u64::MAX,
+            &pool,
+            PreprocessorCacheModeConfig::default(),
+            CacheMode::ReadWrite,
+        );
+        let storage: Arc<dyn Storage> = Arc::new(storage);
+        // Pretend to be GCC.
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")),
+        );
+        let c = get_compiler_info(
+            creator.clone(),
+            &gcc,
+            f.tempdir.path(),
+            &[],
+            &[],
+            &pool,
+            None,
+        )
+        .wait()
+        .unwrap()
+        .0;
+        // The same project, checked out in two directories.
+        let mut results = vec![];
+        for checkout in ["alice", "bob"] {
+            let dir = f.tempdir.path().join(checkout);
+            fs::create_dir(&dir).unwrap();
+            let input = dir.join("foo.c");
+            let obj = dir.join("foo.o");
+            let preprocessed = format!("# 1 \"{}\"\n", input.display());
+            let stderr = format!("{}:1:1: warning\n", input.display());
+            next_command(
+                &creator,
+                Ok(MockChild::new(exit_status(0), preprocessed, "")),
+            );
+            let o = obj.clone();
+            next_command_calls(&creator, move |_| {
+                let mut f = File::create(&o)?;
+                f.write_all(b"file contents")?;
+                Ok(MockChild::new(exit_status(0), "", &stderr))
+            });
+            let arguments = vec![
+                OsString::from("-c"),
+                input.into(),
+                "-o".into(),
+                obj.clone().into(),
+                format!("-I{}", dir.join("include").display()).into(),
+            ];
+            let env_vars = vec![(OsString::from("SCCACHE_BASEDIR"), dir.clone().into())];
+            let hasher = match c.parse_arguments(&arguments, &dir, &env_vars) {
+                CompilerArguments::Ok(h) => h,
+                o => panic!("Bad result from parse_arguments: {:?}", o),
+            };
+            let (cached, res) = runtime
+                .block_on(hasher.get_cached_or_compile(
+                    None,
+                    creator.clone(),
+                    storage.clone(),
+                    arguments,
+                    dir.clone(),
+                    env_vars,
+                    CacheControl::Default,
+                    pool.clone(),
+                ))
+                .unwrap();
+            if let CompileResult::CacheMiss(_, _, _, f) = cached {
+                f.wait().unwrap();
+                results.push("miss");
+            } else {
+                assert_eq!(CompileResult::CacheHit(Duration::new(0, 0)), cached);
+                results.push("hit");
+            }
+            assert!(fs::metadata(&obj).map(|m| m.len() > 0).unwrap());
+            // The messages name the files of this checkout.
+            assert_eq!(
+                String::from_utf8(res.stderr).unwrap(),
+                format!("{}:1:1: warning\n", dir.join("foo.c").display())
+            );
+        }
+        assert_eq!(results, ["miss", "hit"]);
+    }
+
+    #[test]
+    fn test_compiler_get_cached_or_compile_depfile() {
+        drop(env_logger::try_init());
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let gcc = f.mk_bin("gcc").unwrap();
+        let runtime = Runtime::new().unwrap();
+        let pool = runtime.handle().clone();
+        let storage = DiskCache::new(
+            f.tempdir.path().join("cache"),
+            u64::MAX,
+            &pool,
+            PreprocessorCacheModeConfig::default(),
+            CacheMode::ReadWrite,
+        );
+        let storage: Arc<dyn Storage> = Arc::new(storage);
+        // Pretend to be GCC.
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")),
+        );
+        let c = get_compiler_info(
+            creator.clone(),
+            &gcc,
+            f.tempdir.path(),
+            &[],
+            &[],
+            &pool,
+            None,
+        )
+        .wait()
+        .unwrap()
+        .0;
+        let deps_of = |dir: &Path| {
+            format!(
+                "foo.o: {} {}\n",
+                dir.join("foo.c").display(),
+                dir.join("foo.h").display()
+            )
+        };
+        // The same project, checked out in two directories.
+        let mut results = vec![];
+        for checkout in ["alice", "bob"] {
+            let dir = f.tempdir.path().join(checkout);
+            fs::create_dir(&dir).unwrap();
+            let depfile = dir.join("foo.d");
+            let preprocessed = format!("# 1 \"{}\"\n", dir.join("foo.c").display());
+            // The preprocessor writes the dependency file, only when it runs
+            // for the first checkout, like when a hit skips it.
+            let deps = (checkout == "alice").then(|| (depfile.clone(), deps_of(&dir)));
+            next_command_calls(&creator, move |args| {
+                assert!(args.contains(&OsString::from("-MD")));
+                if let Some((depfile, deps)) = &deps {
+                    fs::write(depfile, deps)?;
+                }
+                Ok(MockChild::new(exit_status(0), &preprocessed, ""))
+            });
+            let obj = dir.join("foo.o");
+            next_command_calls(&creator, move |_| {
+                fs::write(&obj, b"file contents")?;
+                Ok(MockChild::new(exit_status(0), "", ""))
+            });
+            let arguments = ovec!["-c", "foo.c", "-o", "foo.o", "-MD"];
+            let env_vars = vec![(OsString::from("SCCACHE_BASEDIR"), dir.clone().into())];
+            let hasher = match c.parse_arguments(&arguments, &dir, &env_vars) {
+                CompilerArguments::Ok(h) => h,
+                o => panic!("Bad result from parse_arguments: {:?}", o),
+            };
+            let (cached, _) = runtime
+                .block_on(hasher.get_cached_or_compile(
+                    None,
+                    creator.clone(),
+                    storage.clone(),
+                    arguments,
+                    dir.clone(),
+                    env_vars,
+                    CacheControl::Default,
+                    pool.clone(),
+                ))
+                .unwrap();
+            if let CompileResult::CacheMiss(_, _, _, f) = cached {
+                f.wait().unwrap();
+                results.push("miss");
+            } else {
+                assert_eq!(CompileResult::CacheHit(Duration::new(0, 0)), cached);
+                results.push("hit");
+            }
+            // The dependency file lists the files of this checkout.
+            assert_eq!(fs::read_to_string(&depfile).unwrap(), deps_of(&dir));
+        }
+        assert_eq!(results, ["miss", "hit"]);
+    }
+
+    #[test]
+    fn test_compiler_get_cached_or_compile_stdio() {
+        drop(env_logger::try_init());
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let gcc = f.mk_bin("gcc").unwrap();
+        let runtime = Runtime::new().unwrap();
+        let pool = runtime.handle().clone();
+        let storage = DiskCache::new(
+            f.tempdir.path().join("cache"),
+            u64::MAX,
+            &pool,
+            PreprocessorCacheModeConfig::default(),
+            CacheMode::ReadWrite,
+        );
+        let storage: Arc<dyn Storage> = Arc::new(storage);
+        // Pretend to be GCC.
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")),
+        );
+        let c = get_compiler_info(
+            creator.clone(),
+            &gcc,
+            f.tempdir.path(),
+            &[],
+            &[],
+            &pool,
+            None,
+        )
+        .wait()
+        .unwrap()
+        .0;
+        // The object is written to stdout, and mentions the base directory,
+        // which must be left alone.
+        let base_dir = f.tempdir.path().to_owned();
+        let object = format!("object of {}", base_dir.display());
+        let env_vars = vec![(OsString::from("SCCACHE_BASEDIR"), base_dir.into())];
+        let arguments = ovec!["-x", "c", "-c", "-", "-o", "-"];
+        let mut results = vec![];
+        for _ in 0..2 {
+            next_command(
+                &creator,
+                Ok(MockChild::new(exit_status(0), "preprocessed stdin", "")),
+            );
+            let o = object.clone();
+            next_command_calls(&creator, move |args| {
+                assert_eq!(&args[args.len() - 3..], ovec!["-o", "-", "-"]);
+                Ok(MockChild::new(exit_status(0), &o, ""))
+            });
+            let mut hasher = match c.parse_arguments(&arguments, f.tempdir.path(), &env_vars) {
+                CompilerArguments::Ok(h) => h,
+                o => panic!("Bad result from parse_arguments: {:?}", o),
+            };
+            assert!(hasher.reads_stdin());
+            assert!(hasher.writes_stdout());
+            hasher.set_stdin(b"int main() {}".to_vec());
+            let (cached, res) = runtime
+                .block_on(hasher.get_cached_or_compile(
+                    None,
+                    creator.clone(),
+                    storage.clone(),
+                    arguments.clone(),
+                    f.tempdir.path().to_owned(),
+                    env_vars.clone(),
+                    CacheControl::Default,
+                    pool.clone(),
+                ))
+                .unwrap();
+            if let CompileResult::CacheMiss(_, _, _, f) = cached {
+                f.wait().unwrap();
+                results.push("miss");
+            } else {
+                assert_eq!(CompileResult::CacheHit(Duration::new(0, 0)), cached);
+                results.push("hit");
+            }
+            assert_eq!(String::from_utf8(res.stdout).unwrap(), object);
+        }
+        assert_eq!(results, ["miss", "hit"]);
+        // The compiler didn't run on the hit.
+        assert_eq!(creator.lock().unwrap().children.len(), 1);
+    }
+
+    #[test_case(false ; "leader succeeds")]
+    #[test_case(true ; "leader fails")]
+    fn test_compiler_get_cached_or_compile_coalesced(leader_fails: bool) {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        drop(env_logger::try_init());
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let gcc = f.mk_bin("gcc").unwrap();
+        let runtime = Runtime::new().unwrap();
+        let pool = runtime.handle().clone();
+        let storage = DiskCache::new(
+            f.tempdir.path().join("cache"),
+            u64::MAX,
+            &pool,
+            PreprocessorCacheModeConfig::default(),
+            CacheMode::ReadWrite,
+        );
+        let storage = Arc::new(storage);
+        // Pretend to be GCC.
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")),
+        );
+        let c = get_compiler_info(
+            creator.clone(),
+            &gcc,
+            f.tempdir.path(),
+            &[],
+            &[],
+            &pool,
+            None,
+        )
+        .wait()
+        .unwrap()
+        .0;
+        // Both compilations run the preprocessor, in whichever order, but a
+        // single one runs the compiler, unless the first compiler run fails.
+        let compiles = Arc::new(AtomicUsize::new(0));
+        let obj = f.tempdir.path().join("foo.o");
+        for _ in 0..if leader_fails { 4 } else { 3 } {
+            let o = obj.clone();
+            let compiles = compiles.clone();
+            next_command_calls(&creator, move |args| {
+                if args.iter().any(|a| a == "-E") {
+                    return Ok(MockChild::new(exit_status(0), "preprocessor output", ""));
+                }
+                if compiles.fetch_add(1, Ordering::SeqCst) == 0 && leader_fails {
+                    return Ok(MockChild::new(exit_status(1), "", "error"));
+                }
+                let mut f = File::create(&o)?;
+                f.write_all(b"file contents")?;
+                Ok(MockChild::new(exit_status(0), "", ""))
+            });
+        }
+        let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
+        let hasher = match c.parse_arguments(&arguments, ".".as_ref(), &[]) {
+            CompilerArguments::Ok(h) => h,
+            o => panic!("Bad result from parse_arguments: {:?}", o),
+        };
+        // The compilations of a server.
+        let flights = Arc::new(Flights::default());
+        let compilations = (0..2)
+            .map(|_| {
+                let hasher = hasher.clone();
+                let creator = creator.clone();
+                let storage = storage.clone();
+                let arguments = arguments.clone();
+                let cwd = f.tempdir.path().to_path_buf();
+                let pool = pool.clone();
+                let flights = flights.clone();
+                runtime.spawn(in_flight::scope(flights, async move {
+                    let (cached, _) = hasher
+                        .get_cached_or_compile(
+                            None,
+                            creator,
+                            storage,
+                            arguments,
+                            cwd,
+                            vec![],
+                            CacheControl::Default,
+                            pool,
+                        )
+                        .await
+                        .unwrap();
+                    match cached {
+                        CompileResult::CacheMiss(_, _, _, f) => {
+                            f.await.unwrap();
+                            "miss"
+                        }
+                        CompileResult::CacheHit(_) => "hit",
+                        CompileResult::CompileFailed(None) => "failed",
+                        o => panic!("Unexpected compile result: {:?}", o),
+                    }
+                }))
+            })
+            .collect::<Vec<_>>();
+        let mut results = runtime
+            .block_on(futures::future::try_join_all(compilations))
+            .unwrap();
+        results.sort_unstable();
+        if leader_fails {
+            assert_eq!(results, ["failed", "miss"]);
+            assert_eq!(compiles.load(Ordering::SeqCst), 2);
+        } else {
+            assert_eq!(results, ["hit", "miss"]);
+            assert_eq!(compiles.load(Ordering::SeqCst), 1);
+        }
+        assert!(fs::metadata(&obj).map(|m| m.len() > 0).unwrap());
+    }
+
+    #[test]
+    fn test_compiler_get_cached_or_compile_shared_pending_write() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        drop(env_logger::try_init());
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let gcc = f.mk_bin("gcc").unwrap();
+        let runtime = Runtime::new().unwrap();
+        let pool = runtime.handle().clone();
+        // The storage is looked up once: the other compilation reads the
+        // entry being stored.
+        let storage = MockStorage::new(None, false);
+        storage.next_get(Ok(Cache::Miss));
+        let storage: Arc<dyn Storage> = Arc::new(storage);
+        // Pretend to be GCC.
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")),
+        );
+        let c = get_compiler_info(
+            creator.clone(),
+            &gcc,
+            f.tempdir.path(),
+            &[],
+            &[],
+            &pool,
+            None,
+        )
+        .wait()
+        .unwrap()
+        .0;
+        let compiles = Arc::new(AtomicUsize::new(0));
+        let obj = f.tempdir.path().join("foo.o");
+        for _ in 0..3 {
+            let o = obj.clone();
+            let compiles = compiles.clone();
+            next_command_calls(&creator, move |args| {
+                if args.iter().any(|a| a == "-E") {
+                    return Ok(MockChild::new(exit_status(0), "preprocessor output", ""));
+                }
+                compiles.fetch_add(1, Ordering::SeqCst);
+                fs::write(&o, b"file contents")?;
+                Ok(MockChild::new(exit_status(0), "", ""))
+            });
+        }
+        let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
+        let hasher = match c.parse_arguments(&arguments, ".".as_ref(), &[]) {
+            CompilerArguments::Ok(h) => h,
+            o => panic!("Bad result from parse_arguments: {:?}", o),
+        };
+        let env_vars = vec![("SCCACHE_SHARE_PENDING_WRITES".into(), "1".into())];
+        // The compilations of a server.
+        let flights = Arc::new(Flights::default());
+        let compilations = (0..2)
+            .map(|_| {
+                let hasher = hasher.clone();
+                let creator = creator.clone();
+                let storage = storage.clone();
+                let arguments = arguments.clone();
+                let cwd = f.tempdir.path().to_path_buf();
+                let env_vars = env_vars.clone();
+                let pool = pool.clone();
+                let flights = flights.clone();
+                runtime.spawn(in_flight::scope(flights, async move {
+                    let (cached, _) = hasher
+                        .get_cached_or_compile(
+                            None,
+                            creator,
+                            storage,
+                            arguments,
+                            cwd,
+                            env_vars,
+                            CacheControl::Default,
+                            pool,
+                        )
+                        .await
+                        .unwrap();
+                    // The write isn't waited for yet.
+                    match cached {
+                        CompileResult::CacheMiss(_, _, _, f) => ("miss", Some(f)),
+                        CompileResult::CacheHit(_) => ("hit", None),
+                        o => panic!("Unexpected compile result: {:?}", o),
+                    }
+                }))
+            })
+            .collect::<Vec<_>>();
+        let mut results = runtime
+            .block_on(async {
+                tokio::time::timeout(
+                    Duration::from_secs(10),
+                    futures::future::try_join_all(compilations),
+                )
+                .await
+            })
+            .expect("the compilations waited for the write")
+            .unwrap();
+        results.sort_unstable_by_key(|(result, _)| *result);
+        assert_eq!(results[0].0, "hit");
+        assert_eq!(results[1].0, "miss");
+        assert_eq!(compiles.load(Ordering::SeqCst), 1);
+        let write = results.pop().unwrap().1.unwrap();
+        runtime.block_on(write).unwrap();
+        assert_eq!(fs::read(&obj).unwrap(), b"file contents");
+    }
+
+    #[test]
+    fn test_compiler_get_cached_or_compile_identical_not_stored() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        drop(env_logger::try_init());
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let gcc = f.mk_bin("gcc").unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let pool = runtime.handle().clone();
+        // All the compilations look the key up: the first one doesn't store
+        // a result, the compilation failing.
+        let storage = MockStorage::new(None, false);
+        for _ in 0..3 {
+            storage.next_get(Ok(Cache::Miss));
+        }
+        let storage: Arc<dyn Storage> = Arc::new(storage);
+        // Pretend to be GCC.
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")),
+        );
+        let c = get_compiler_info(
+            creator.clone(),
+            &gcc,
+            f.tempdir.path(),
+            &[],
+            &[],
+            &pool,
+            None,
+        )
+        .wait()
+        .unwrap()
+        .0;
+        // The compilations waiting for the first one then compile as well.
+        let compiles = Arc::new(AtomicUsize::new(0));
+        for _ in 0..6 {
+            let compiles = compiles.clone();
+            next_command_calls(&creator, move |args| {
+                if args.iter().any(|a| a == "-E") {
+                    return Ok(MockChild::new(exit_status(0), "preprocessor output", ""));
+                }
+                compiles.fetch_add(1, Ordering::SeqCst);
+                Ok(MockChild::new(exit_status(1), "", "error"))
+            });
+        }
+        let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
+        let hasher = match c.parse_arguments(&arguments, ".".as_ref(), &[]) {
+            CompilerArguments::Ok(h) => h,
+            o => panic!("Bad result from parse_arguments: {:?}", o),
+        };
+        // The compilations of a server.
+        let flights = Arc::new(Flights::default());
+        let compilations = (0..3)
+            .map(|_| {
+                let hasher = hasher.clone();
+                let creator = creator.clone();
+                let storage = storage.clone();
+                let arguments = arguments.clone();
+                let cwd = f.tempdir.path().to_path_buf();
+                let pool = pool.clone();
+                let flights = flights.clone();
+                runtime.spawn(in_flight::scope(flights, async move {
+                    let (cached, output) = hasher
+                        .get_cached_or_compile(
+                            None,
+                            creator,
+                            storage,
+                            arguments,
+                            cwd,
+                            vec![],
+                            CacheControl::Default,
+                            pool,
+                        )
+                        .await
+                        .unwrap();
+                    assert!(matches!(cached, CompileResult::CompileFailed(None)));
+                    output.status.success()
+                }))
+            })
+            .collect::<Vec<_>>();
+        let succeeded = runtime
+            .block_on(futures::future::try_join_all(compilations))
+            .unwrap();
+        assert_eq!(succeeded, [false, false, false]);
+        assert_eq!(compiles.load(Ordering::SeqCst), 3);
+    }
+
+    #[test_case(true ; "with preprocessor cache")]
+    #[test_case(false ; "without preprocessor cache")]
+    #[cfg(feature = "dist-client")]
+    fn test_compiler_get_cached_or_compile_dist(preprocessor_cache_mode: bool) {
+        drop(env_logger::try_init());
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let gcc = f.mk_bin("gcc").unwrap();
+        let runtime = Runtime::new().unwrap();
+        let pool = runtime.handle().clone();
+        let storage = DiskCache::new(
+            f.tempdir.path().join("cache"),
+            u64::MAX,
+            &pool,
+            PreprocessorCacheModeConfig {
+                use_preprocessor_cache_mode: preprocessor_cache_mode,
+                ..Default::default()
+            },
+            CacheMode::ReadWrite,
+        );
+        // Write a dummy input file so the preprocessor cache mode can work
+        std::fs::write(f.tempdir.path().join("foo.c"), "whatever").unwrap();
+        let storage = Arc::new(storage);
+        // Pretend to be GCC.
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")),
+        );
+        let c = get_compiler_info(
+            creator.clone(),
+            &gcc,
+            f.tempdir.path(),
+            &[],
+            &[],
+            &pool,
+            None,
+        )
+        .wait()
+        .unwrap()
+        .0;
+        // The preprocessor invocation.
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "preprocessor output", "")),
+        );
+        // The compiler invocation.
+        const COMPILER_STDOUT: &[u8] = b"compiler stdout";
+        const COMPILER_STDERR: &[u8] = b"compiler stderr";
+        let obj = f.tempdir.path().join("foo.o");
+        // Dist client will do the compilation
+        let dist_client = Some(test_dist::OneshotClient::new(
+            0,
+            COMPILER_STDOUT.to_owned(),
+            COMPILER_STDERR.to_owned(),
+        ));
+        let cwd = f.tempdir.path();
+        let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
+        let hasher = match c.parse_arguments(&arguments, ".".as_ref(), &[]) {
+            CompilerArguments::Ok(h) => h,
+            o => panic!("Bad result from parse_arguments: {:?}", o),
+        };
+        let hasher2 = hasher.clone();
+        let (cached, res) = runtime
+            .block_on(async {
+                hasher
+                    .get_cached_or_compile(
+                        dist_client.clone(),
+                        creator.clone(),
+                        storage.clone(),
+                        arguments.clone(),
+                        cwd.to_path_buf(),
+                        vec![],
+                        CacheControl::Default,
+                        pool.clone(),
+                    )
+                    .await
+            })
+            .unwrap();
+        // Ensure that the object file was created.
+        assert!(fs::metadata(&obj).map(|m| m.len() > 0).unwrap());
+        match cached {
+            CompileResult::CacheMiss(MissType::Normal, DistType::Ok(_), _, f) => {
+                // wait on cache write future so we don't race with it!
+                f.wait().unwrap();
             }
             _ => panic!("Unexpected compile result: {:?}", cached),
         }
@@ -2206,21 +4054,278 @@ LLVM version: 6.0",
         assert_eq!(COMPILER_STDERR, res.stderr.as_slice());
     }
 
-    #[test_case(true ; "with preprocessor cache")]
-    #[test_case(false ; "without preprocessor cache")]
-    /// Test that a cache read that results in an error is treated as a cache
-    /// miss.
-    fn test_compiler_get_cached_or_compile_cache_error(preprocessor_cache_mode: bool) {
+    #[test_case(true ; "with preprocessor cache")]
+    #[test_case(false ; "without preprocessor cache")]
+    /// Test that a cache read that results in an error is treated as a cache
+    /// miss.
+    fn test_compiler_get_cached_or_compile_cache_error(preprocessor_cache_mode: bool) {
+        drop(env_logger::try_init());
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let gcc = f.mk_bin("gcc").unwrap();
+        let runtime = Runtime::new().unwrap();
+        let pool = runtime.handle().clone();
+        let storage = MockStorage::new(None, preprocessor_cache_mode);
+        let storage: Arc<MockStorage> = Arc::new(storage);
+        // Write a dummy input file so the preprocessor cache mode can work
+        std::fs::write(f.tempdir.path().join("foo.c"), "whatever").unwrap();
+        // Pretend to be GCC.
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")),
+        );
+        let c = get_compiler_info(
+            creator.clone(),
+            &gcc,
+            f.tempdir.path(),
+            &[],
+            &[],
+            &pool,
+            None,
+        )
+        .wait()
+        .unwrap()
+        .0;
+        // The preprocessor invocation.
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "preprocessor output", "")),
+        );
+        // The compiler invocation.
+        const COMPILER_STDOUT: &[u8] = b"compiler stdout";
+        const COMPILER_STDERR: &[u8] = b"compiler stderr";
+        let obj = f.tempdir.path().join("foo.o");
+        let o = obj.clone();
+        next_command_calls(&creator, move |_| {
+            // Pretend to compile something.
+            let mut f = File::create(&o)?;
+            f.write_all(b"file contents")?;
+            Ok(MockChild::new(
+                exit_status(0),
+                COMPILER_STDOUT,
+                COMPILER_STDERR,
+            ))
+        });
+        let cwd = f.tempdir.path();
+        let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
+        let hasher = match c.parse_arguments(&arguments, ".".as_ref(), &[]) {
+            CompilerArguments::Ok(h) => h,
+            o => panic!("Bad result from parse_arguments: {:?}", o),
+        };
+        // The cache will return an error.
+        storage.next_get(Err(anyhow!("Some Error")));
+        let (cached, res) = runtime
+            .block_on(hasher.get_cached_or_compile(
+                None,
+                creator,
+                storage,
+                arguments.clone(),
+                cwd.to_path_buf(),
+                vec![],
+                CacheControl::Default,
+                pool,
+            ))
+            .unwrap();
+        // Ensure that the object file was created.
+        assert!(fs::metadata(&obj).map(|m| m.len() > 0).unwrap());
+        match cached {
+            CompileResult::CacheMiss(MissType::CacheReadError, DistType::NoDist, _, f) => {
+                // wait on cache write future so we don't race with it!
+                let _ = f.wait();
+            }
+            _ => panic!("Unexpected compile result: {:?}", cached),
+        }
+
+        assert_eq!(exit_status(0), res.status);
+        assert_eq!(COMPILER_STDOUT, res.stdout.as_slice());
+        assert_eq!(COMPILER_STDERR, res.stderr.as_slice());
+    }
+
+    #[test_case(true ; "with preprocessor cache")]
+    #[test_case(false ; "without preprocessor cache")]
+    /// Test that cache read timing is recorded.
+    fn test_compiler_get_cached_or_compile_cache_get_timing(preprocessor_cache_mode: bool) {
+        drop(env_logger::try_init());
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let gcc = f.mk_bin("gcc").unwrap();
+        let runtime = Runtime::new().unwrap();
+        let pool = runtime.handle().clone();
+        // Write a dummy input file so the preprocessor cache mode can work
+        std::fs::write(f.tempdir.path().join("foo.c"), "whatever").unwrap();
+        // Make our storage wait 2ms for each get/put operation.
+        let storage_delay = Duration::from_millis(2);
+        let storage = MockStorage::new(Some(storage_delay), preprocessor_cache_mode);
+        let storage: Arc<MockStorage> = Arc::new(storage);
+        // Pretend to be GCC.
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")),
+        );
+        let c = get_compiler_info(
+            creator.clone(),
+            &gcc,
+            f.tempdir.path(),
+            &[],
+            &[],
+            &pool,
+            None,
+        )
+        .wait()
+        .unwrap()
+        .0;
+        // The preprocessor invocation.
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "preprocessor output", "")),
+        );
+        // The compiler invocation.
+        const COMPILER_STDOUT: &[u8] = b"compiler stdout";
+        const COMPILER_STDERR: &[u8] = b"compiler stderr";
+        let obj_file: &[u8] = &[1, 2, 3, 4];
+        // A cache entry to hand out
+        let mut cachewrite = CacheWrite::new();
+        cachewrite
+            .put_stdout(COMPILER_STDOUT)
+            .expect("Failed to store stdout");
+        cachewrite
+            .put_stderr(COMPILER_STDERR)
+            .expect("Failed to store stderr");
+        cachewrite
+            .put_object("obj", &mut Cursor::new(obj_file), None)
+            .expect("Failed to store cache object");
+        let entry = cachewrite.finish().expect("Failed to finish cache entry");
+        let entry = CacheRead::from(Cursor::new(entry)).expect("Failed to re-read cache entry");
+
+        let cwd = f.tempdir.path();
+        let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
+        let hasher = match c.parse_arguments(&arguments, ".".as_ref(), &[]) {
+            CompilerArguments::Ok(h) => h,
+            o => panic!("Bad result from parse_arguments: {:?}", o),
+        };
+        storage.next_get(Ok(Cache::Hit(entry)));
+        let (cached, _res) = runtime
+            .block_on(hasher.get_cached_or_compile(
+                None,
+                creator,
+                storage,
+                arguments.clone(),
+                cwd.to_path_buf(),
+                vec![],
+                CacheControl::Default,
+                pool,
+            ))
+            .unwrap();
+        match cached {
+            CompileResult::CacheHit(duration) => {
+                assert!(duration >= storage_delay);
+            }
+            _ => panic!("Unexpected compile result: {:?}", cached),
+        }
+    }
+
+    /// Test that the entry of another target sharing the cache is a miss,
+    /// should the keys of both collide.
+    #[test]
+    fn test_compiler_get_cached_or_compile_other_target() {
+        drop(env_logger::try_init());
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let gcc = f.mk_bin("gcc").unwrap();
+        let runtime = Runtime::new().unwrap();
+        let pool = runtime.handle().clone();
+        std::fs::write(f.tempdir.path().join("foo.c"), "whatever").unwrap();
+        let storage = Arc::new(MockStorage::new(None, false));
+        // Pretend to be GCC.
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")),
+        );
+        let c = get_compiler_info(
+            creator.clone(),
+            &gcc,
+            f.tempdir.path(),
+            &[],
+            &[],
+            &pool,
+            None,
+        )
+        .wait()
+        .unwrap()
+        .0;
+        // The preprocessor invocation.
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "preprocessor output", "")),
+        );
+        // The compiler invocation.
+        let obj = f.tempdir.path().join("foo.o");
+        let o = obj.clone();
+        next_command_calls(&creator, move |_| {
+            fs::write(&o, b"file contents")?;
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+        // The entry another target stored under the same key.
+        let mut cachewrite = CacheWrite::new();
+        cachewrite
+            .put_object("obj", &mut Cursor::new(b"other target"), None)
+            .expect("Failed to store cache object");
+        let entry = cachewrite.finish().expect("Failed to finish cache entry");
+        let mut zip = zip::ZipWriter::new_append(Cursor::new(entry)).unwrap();
+        zip.set_comment(format!(
+            "compression=zstd; hash=blake3; target=not-{}",
+            host_target()
+        ));
+        let entry = zip.finish().unwrap().into_inner();
+        let entry = CacheRead::from(Cursor::new(entry)).expect("Failed to re-read cache entry");
+        assert!(!entry.format().is_for_host());
+
+        let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
+        let hasher = match c.parse_arguments(&arguments, ".".as_ref(), &[]) {
+            CompilerArguments::Ok(h) => h,
+            o => panic!("Bad result from parse_arguments: {:?}", o),
+        };
+        storage.next_get(Ok(Cache::Hit(entry)));
+        let (cached, _) = runtime
+            .block_on(hasher.get_cached_or_compile(
+                None,
+                creator,
+                storage,
+                arguments,
+                f.tempdir.path().to_path_buf(),
+                vec![],
+                CacheControl::Default,
+                pool,
+            ))
+            .unwrap();
+        match cached {
+            CompileResult::CacheMiss(MissType::Normal, DistType::NoDist, _, f) => {
+                let _ = f.wait();
+            }
+            _ => panic!("Unexpected compile result: {:?}", cached),
+        }
+        assert_eq!(fs::read(&obj).unwrap(), b"file contents");
+    }
+
+    /// An output which is a FIFO is written by the compiler, without the
+    /// cache being looked up nor written, which would block on the FIFO.
+    #[cfg(unix)]
+    #[test]
+    fn test_compiler_get_cached_or_compile_fifo_output() {
+        use std::os::unix::fs::FileTypeExt;
+
         drop(env_logger::try_init());
         let creator = new_creator();
         let f = TestFixture::new();
         let gcc = f.mk_bin("gcc").unwrap();
         let runtime = Runtime::new().unwrap();
         let pool = runtime.handle().clone();
-        let storage = MockStorage::new(None, preprocessor_cache_mode);
-        let storage: Arc<MockStorage> = Arc::new(storage);
-        // Write a dummy input file so the preprocessor cache mode can work
         std::fs::write(f.tempdir.path().join("foo.c"), "whatever").unwrap();
+        let fifo = f.tempdir.path().join("foo.o");
+        let path = std::ffi::CString::new(fifo.as_os_str().as_encoded_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o600) }, 0);
+        // No lookup is queued, the storage panics if it's looked up.
+        let storage = Arc::new(MockStorage::new(None, false));
         // Pretend to be GCC.
         next_command(
             &creator,
@@ -2243,72 +4348,100 @@ LLVM version: 6.0",
             &creator,
             Ok(MockChild::new(exit_status(0), "preprocessor output", "")),
         );
-        // The compiler invocation.
-        const COMPILER_STDOUT: &[u8] = b"compiler stdout";
-        const COMPILER_STDERR: &[u8] = b"compiler stderr";
-        let obj = f.tempdir.path().join("foo.o");
-        let o = obj.clone();
-        next_command_calls(&creator, move |_| {
-            // Pretend to compile something.
-            let mut f = File::create(&o)?;
-            f.write_all(b"file contents")?;
-            Ok(MockChild::new(
-                exit_status(0),
-                COMPILER_STDOUT,
-                COMPILER_STDERR,
-            ))
-        });
-        let cwd = f.tempdir.path();
+        // The compiler invocation, which would write to the FIFO.
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "compiler stdout", "")),
+        );
         let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
         let hasher = match c.parse_arguments(&arguments, ".".as_ref(), &[]) {
             CompilerArguments::Ok(h) => h,
             o => panic!("Bad result from parse_arguments: {:?}", o),
         };
-        // The cache will return an error.
-        storage.next_get(Err(anyhow!("Some Error")));
         let (cached, res) = runtime
             .block_on(hasher.get_cached_or_compile(
                 None,
                 creator,
                 storage,
-                arguments.clone(),
-                cwd.to_path_buf(),
+                arguments,
+                f.tempdir.path().to_path_buf(),
                 vec![],
                 CacheControl::Default,
                 pool,
             ))
             .unwrap();
-        // Ensure that the object file was created.
-        assert!(fs::metadata(&obj).map(|m| m.len() > 0).unwrap());
-        match cached {
-            CompileResult::CacheMiss(MissType::CacheReadError, DistType::NoDist, _, f) => {
-                // wait on cache write future so we don't race with it!
-                let _ = f.wait();
-            }
-            _ => panic!("Unexpected compile result: {:?}", cached),
-        }
+        assert!(matches!(cached, CompileResult::NotCacheable));
+        assert_eq!(res.stdout, b"compiler stdout");
+        // The FIFO is left as it is.
+        assert!(fs::metadata(&fifo).unwrap().file_type().is_fifo());
+    }
 
-        assert_eq!(exit_status(0), res.status);
-        assert_eq!(COMPILER_STDOUT, res.stdout.as_slice());
-        assert_eq!(COMPILER_STDERR, res.stderr.as_slice());
+    #[test]
+    fn test_compiler_get_cached_or_compile_missing_extra_file() {
+        drop(env_logger::try_init());
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let gcc = f.mk_bin("gcc").unwrap();
+        let runtime = Runtime::new().unwrap();
+        let pool = runtime.handle().clone();
+        std::fs::write(f.tempdir.path().join("foo.c"), "whatever").unwrap();
+        // The storage panics if it's looked up.
+        let storage = Arc::new(MockStorage::new(None, false));
+        // Pretend to be GCC.
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")),
+        );
+        let c = get_compiler_info(
+            creator.clone(),
+            &gcc,
+            f.tempdir.path(),
+            &[],
+            &[],
+            &pool,
+            None,
+        )
+        .wait()
+        .unwrap()
+        .0;
+        let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
+        let env_vars = vec![(
+            OsString::from("SCCACHE_EXTRA_FILES"),
+            OsString::from("gen.cfg"),
+        )];
+        let hasher = match c.parse_arguments(&arguments, f.tempdir.path(), &env_vars) {
+            CompilerArguments::Ok(h) => h,
+            o => panic!("Bad result from parse_arguments: {:?}", o),
+        };
+        // The compilation fails rather than leaving the file out of the key.
+        let err = runtime
+            .block_on(hasher.get_cached_or_compile(
+                None,
+                creator,
+                storage,
+                arguments,
+                f.tempdir.path().to_path_buf(),
+                env_vars,
+                CacheControl::Default,
+                pool,
+            ))
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("gen.cfg"), "{:#}", err);
     }
 
-    #[test_case(true ; "with preprocessor cache")]
-    #[test_case(false ; "without preprocessor cache")]
-    /// Test that cache read timing is recorded.
-    fn test_compiler_get_cached_or_compile_cache_get_timing(preprocessor_cache_mode: bool) {
+    #[test_case(0 ; "exit code 0")]
+    #[test_case(1 ; "exit code 1")]
+    #[test_case(2 ; "exit code 2")]
+    /// Test that the exit code stored in a cache entry is replayed on a hit.
+    fn test_compiler_get_cached_or_compile_replays_exit_code(code: i32) {
         drop(env_logger::try_init());
         let creator = new_creator();
         let f = TestFixture::new();
         let gcc = f.mk_bin("gcc").unwrap();
         let runtime = Runtime::new().unwrap();
         let pool = runtime.handle().clone();
-        // Write a dummy input file so the preprocessor cache mode can work
         std::fs::write(f.tempdir.path().join("foo.c"), "whatever").unwrap();
-        // Make our storage wait 2ms for each get/put operation.
-        let storage_delay = Duration::from_millis(2);
-        let storage = MockStorage::new(Some(storage_delay), preprocessor_cache_mode);
-        let storage: Arc<MockStorage> = Arc::new(storage);
+        let storage = Arc::new(MockStorage::new(None, false));
         // Pretend to be GCC.
         next_command(
             &creator,
@@ -2326,54 +4459,259 @@ LLVM version: 6.0",
         .wait()
         .unwrap()
         .0;
-        // The preprocessor invocation.
+        // The preprocessor invocation, the compiler must not be invoked.
         next_command(
             &creator,
             Ok(MockChild::new(exit_status(0), "preprocessor output", "")),
         );
-        // The compiler invocation.
-        const COMPILER_STDOUT: &[u8] = b"compiler stdout";
-        const COMPILER_STDERR: &[u8] = b"compiler stderr";
-        let obj_file: &[u8] = &[1, 2, 3, 4];
-        // A cache entry to hand out
         let mut cachewrite = CacheWrite::new();
         cachewrite
-            .put_stdout(COMPILER_STDOUT)
-            .expect("Failed to store stdout");
-        cachewrite
-            .put_stderr(COMPILER_STDERR)
+            .put_stderr(b"compiler stderr")
             .expect("Failed to store stderr");
         cachewrite
-            .put_object("obj", &mut Cursor::new(obj_file), None)
+            .put_exit_code(code)
+            .expect("Failed to store exit code");
+        cachewrite
+            .put_object("obj", &mut Cursor::new(&[1, 2, 3, 4]), None)
             .expect("Failed to store cache object");
         let entry = cachewrite.finish().expect("Failed to finish cache entry");
         let entry = CacheRead::from(Cursor::new(entry)).expect("Failed to re-read cache entry");
 
-        let cwd = f.tempdir.path();
         let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
         let hasher = match c.parse_arguments(&arguments, ".".as_ref(), &[]) {
             CompilerArguments::Ok(h) => h,
             o => panic!("Bad result from parse_arguments: {:?}", o),
         };
         storage.next_get(Ok(Cache::Hit(entry)));
-        let (cached, _res) = runtime
+        let (cached, res) = runtime
             .block_on(hasher.get_cached_or_compile(
                 None,
-                creator,
+                creator.clone(),
                 storage,
-                arguments.clone(),
-                cwd.to_path_buf(),
-                vec![],
+                arguments,
+                f.tempdir.path().to_path_buf(),
+                vec![("SCCACHE_CACHE_FAILURES".into(), "1".into())],
                 CacheControl::Default,
                 pool,
             ))
             .unwrap();
+        assert!(matches!(cached, CompileResult::CacheHit(_)));
+        assert_eq!(Some(code), res.status.code());
+        assert_eq!(b"compiler stderr", res.stderr.as_slice());
+        assert_eq!(0, creator.lock().unwrap().children.len());
+    }
+
+    #[test_case(true ; "with SCCACHE_CACHE_FAILURES")]
+    #[test_case(false ; "without SCCACHE_CACHE_FAILURES")]
+    fn test_compiler_get_cached_or_compile_cache_failures(cache_failures: bool) {
+        drop(env_logger::try_init());
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let gcc = f.mk_bin("gcc").unwrap();
+        let runtime = Runtime::new().unwrap();
+        let pool = runtime.handle().clone();
+        let storage = DiskCache::new(
+            f.tempdir.path().join("cache"),
+            u64::MAX,
+            &pool,
+            PreprocessorCacheModeConfig::default(),
+            CacheMode::ReadWrite,
+        );
+        let storage = Arc::new(storage);
+        std::fs::write(f.tempdir.path().join("foo.c"), "whatever").unwrap();
+        // Pretend to be GCC.
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")),
+        );
+        let c = get_compiler_info(
+            creator.clone(),
+            &gcc,
+            f.tempdir.path(),
+            &[],
+            &[],
+            &pool,
+            None,
+        )
+        .wait()
+        .unwrap()
+        .0;
+        const COMPILER_STDERR: &[u8] = b"foo.c:1:1: error: unknown type name 'whatever'";
+        let env_vars: Vec<(OsString, OsString)> = if cache_failures {
+            vec![("SCCACHE_CACHE_FAILURES".into(), "1".into())]
+        } else {
+            vec![]
+        };
+        let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
+        let hasher = match c.parse_arguments(&arguments, ".".as_ref(), &[]) {
+            CompilerArguments::Ok(h) => h,
+            o => panic!("Bad result from parse_arguments: {:?}", o),
+        };
+        let hasher2 = hasher.clone();
+        // The preprocessor invocation and the failing compiler invocation.
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "preprocessor output", "")),
+        );
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(1 << 8), "", COMPILER_STDERR)),
+        );
+        let (cached, res) = runtime
+            .block_on(hasher.get_cached_or_compile(
+                None,
+                creator.clone(),
+                storage.clone(),
+                arguments.clone(),
+                f.tempdir.path().to_path_buf(),
+                env_vars.clone(),
+                CacheControl::Default,
+                pool.clone(),
+            ))
+            .unwrap();
+        assert_eq!(Some(1), res.status.code());
         match cached {
-            CompileResult::CacheHit(duration) => {
-                assert!(duration >= storage_delay);
+            CompileResult::CompileFailed(Some(f)) if cache_failures => {
+                f.wait().unwrap();
             }
+            CompileResult::CompileFailed(None) if !cache_failures => {}
             _ => panic!("Unexpected compile result: {:?}", cached),
         }
+
+        // Compile again, the stored failure is replayed if it was stored.
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "preprocessor output", "")),
+        );
+        if !cache_failures {
+            next_command(
+                &creator,
+                Ok(MockChild::new(exit_status(1 << 8), "", COMPILER_STDERR)),
+            );
+        }
+        let (cached, res) = runtime
+            .block_on(hasher2.get_cached_or_compile(
+                None,
+                creator.clone(),
+                storage,
+                arguments,
+                f.tempdir.path().to_path_buf(),
+                env_vars,
+                CacheControl::Default,
+                pool,
+            ))
+            .unwrap();
+        assert_eq!(
+            cache_failures,
+            matches!(cached, CompileResult::CacheHit(_)),
+            "Unexpected compile result: {:?}",
+            cached
+        );
+        assert_eq!(Some(1), res.status.code());
+        assert_eq!(COMPILER_STDERR, res.stderr.as_slice());
+        assert_eq!(0, creator.lock().unwrap().children.len());
+    }
+
+    #[test]
+    fn test_compiler_get_cached_or_compile_verify_hits() {
+        drop(env_logger::try_init());
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let gcc = f.mk_bin("gcc").unwrap();
+        let runtime = Runtime::new().unwrap();
+        let pool = runtime.handle().clone();
+        let storage = DiskCache::new(
+            f.tempdir.path().join("cache"),
+            u64::MAX,
+            &pool,
+            PreprocessorCacheModeConfig::default(),
+            CacheMode::ReadWrite,
+        );
+        let storage = Arc::new(storage);
+        std::fs::write(f.tempdir.path().join("foo.c"), "whatever").unwrap();
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")),
+        );
+        let c = get_compiler_info(
+            creator.clone(),
+            &gcc,
+            f.tempdir.path(),
+            &[],
+            &[],
+            &pool,
+            None,
+        )
+        .wait()
+        .unwrap()
+        .0;
+        let env_vars: Vec<(OsString, OsString)> = vec![("SCCACHE_VERIFY_HITS".into(), "1".into())];
+        assert_eq!(get_verify_hits(&env_vars), 1.0);
+        assert_eq!(get_verify_hits(&[]), 0.0);
+        assert_eq!(
+            get_verify_hits(&[("SCCACHE_VERIFY_HITS".into(), "2".into())]),
+            0.0
+        );
+        let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
+        let hasher = match c.parse_arguments(&arguments, ".".as_ref(), &[]) {
+            CompilerArguments::Ok(h) => h,
+            o => panic!("Bad result from parse_arguments: {:?}", o),
+        };
+        let obj = f.tempdir.path().join("foo.o");
+        // Compile twice, the second compilation, a verification, giving
+        // another object as if the hash key missed an input.
+        for (contents, stdout) in [("cached", "cached stdout"), ("differs", "other stdout")] {
+            let hasher = hasher.clone();
+            next_command(
+                &creator,
+                Ok(MockChild::new(exit_status(0), "preprocessor output", "")),
+            );
+            let o = obj.clone();
+            next_command_calls(&creator, move |_| {
+                File::create(&o)?.write_all(contents.as_bytes())?;
+                Ok(MockChild::new(exit_status(0), stdout, ""))
+            });
+            let (cached, res) = runtime
+                .block_on(hasher.get_cached_or_compile(
+                    None,
+                    creator.clone(),
+                    storage.clone(),
+                    arguments.clone(),
+                    f.tempdir.path().to_path_buf(),
+                    env_vars.clone(),
+                    CacheControl::Default,
+                    pool.clone(),
+                ))
+                .unwrap();
+            if let CompileResult::CacheMiss(_, _, _, f) = cached {
+                f.wait().unwrap();
+            } else {
+                assert!(matches!(cached, CompileResult::CacheHit(_)));
+            }
+            // The hit is what the cache has, whatever the verification finds.
+            assert_eq!(b"cached stdout", res.stdout.as_slice());
+            assert_eq!(b"cached", fs::read(&obj).unwrap().as_slice());
+        }
+        assert_eq!(0, creator.lock().unwrap().children.len());
+    }
+
+    #[test]
+    fn test_is_deterministic_failure() {
+        let output = |status, stderr: &str| process::Output {
+            status: exit_status(status),
+            stdout: vec![],
+            stderr: stderr.as_bytes().to_vec(),
+        };
+        assert!(is_deterministic_failure(&output(
+            1 << 8,
+            "foo.c:1:1: error: expected ';'"
+        )));
+        assert!(!is_deterministic_failure(&output(
+            1 << 8,
+            "foo.c:1:10: fatal error: bar.h: No such file or directory"
+        )));
+        #[cfg(unix)]
+        assert!(!is_deterministic_failure(&output(9, "")));
     }
 
     #[test_case(true ; "with preprocessor cache")]
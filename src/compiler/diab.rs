@@ -15,7 +15,7 @@
 
 use crate::compiler::args::{
     ArgDisposition, ArgInfo, ArgToStringResult, ArgsIter, Argument, FromArg, IntoArg,
-    NormalizedDisposition, PathTransformerFn, SearchableArgInfo,
+    NormalizedDisposition, PathTransformerFn, ResponseFileLimits, SearchableArgInfo,
 };
 use crate::compiler::c::{ArtifactDescriptor, CCompilerImpl, CCompilerKind, ParsedArguments};
 use crate::compiler::{Cacheable, ColorMode, CompileCommand, CompilerArguments, Language};
@@ -175,9 +175,9 @@ where
 
     // Custom iterator to expand `@` arguments which stand for reading a file
     // and interpreting it as a list of more arguments.
-    let it = ExpandAtArgs::new(cwd, arguments);
+    let mut it = ExpandAtArgs::new(cwd, arguments);
 
-    for arg in ArgsIter::new(it, arg_info) {
+    for arg in ArgsIter::new(&mut it, arg_info) {
         let arg = try_or_cannot_cache!(arg, "argument parse");
         // Check if the value part of this argument begins with '@'. If so, we either
         // failed to expand it, or it was a concatenated argument - either way, bail.
@@ -251,6 +251,9 @@ where
         };
         args.extend(arg.normalize(norm).iter_os_strings());
     }
+    if let Some(error) = it.error() {
+        cannot_cache!("@ expansion", error.to_owned());
+    }
 
     // We only support compilation.
     if !compilation {
@@ -266,8 +269,11 @@ where
         None => cannot_cache!("no input file"),
     };
     let language = match Language::from_file_name(Path::new(&input)) {
+        // Only the compilers like gcc assemble the sources here.
+        Some(Language::Assembler | Language::AssemblerToPreprocess) | None => {
+            cannot_cache!("unknown source language")
+        }
         Some(l) => l,
-        None => cannot_cache!("unknown source language"),
     };
 
     let output = output_arg
@@ -302,6 +308,9 @@ where
         color_mode: ColorMode::Auto,
         suppress_rewrite_includes_only: false,
         too_hard_for_preprocessor_cache_mode: None,
+        stdin: None,
+        output_to_stdout: false,
+        sysroot: None,
     })
 }
 
@@ -360,23 +369,34 @@ pub fn generate_compile_commands(
         arguments,
         env_vars: env_vars.to_owned(),
         cwd: cwd.to_owned(),
+        stdin: None,
     };
 
     Ok((command, None, Cacheable::Yes))
 }
 
+/// Iterator that expands `-@file` arguments in place, recursively, within
+/// the `limits`.
 pub struct ExpandAtArgs<'a> {
     cwd: &'a Path,
-    stack: Vec<OsString>,
+    /// The arguments left, with how deep in the response files they are.
+    stack: Vec<(OsString, usize)>,
+    limits: ResponseFileLimits,
 }
 
 impl<'a> ExpandAtArgs<'a> {
     pub fn new(cwd: &'a Path, args: &[OsString]) -> Self {
         ExpandAtArgs {
-            stack: args.iter().rev().map(|a| a.to_owned()).collect(),
+            stack: args.iter().rev().map(|a| (a.to_owned(), 0)).collect(),
             cwd,
+            limits: ResponseFileLimits::new(),
         }
     }
+
+    /// Why the expansion stopped before the end of the arguments, if it did.
+    pub fn error(&self) -> Option<&str> {
+        self.limits.error()
+    }
 }
 
 impl<'a> Iterator for ExpandAtArgs<'a> {
@@ -384,7 +404,10 @@ impl<'a> Iterator for ExpandAtArgs<'a> {
 
     fn next(&mut self) -> Option<OsString> {
         loop {
-            let arg = self.stack.pop()?;
+            if self.error().is_some() {
+                return None;
+            }
+            let (arg, depth) = self.stack.pop()?;
 
             // Just return non @ arguments
             if !arg.starts_with("-@") {
@@ -420,7 +443,7 @@ impl<'a> Iterator for ExpandAtArgs<'a> {
 
             let mut contents = String::new();
             let file = self.cwd.join(&value);
-            let res = File::open(file).and_then(|mut f| f.read_to_string(&mut contents));
+            let res = File::open(&file).and_then(|mut f| f.read_to_string(&mut contents));
             if res.is_err() {
                 // Failed to read the file, so return the argument as it is.
                 // This will result in a CannotCache.
@@ -430,7 +453,11 @@ impl<'a> Iterator for ExpandAtArgs<'a> {
                 return Some(arg);
             }
             let new_args = contents.split_whitespace().collect::<Vec<_>>();
-            self.stack.extend(new_args.iter().rev().map(|s| s.into()));
+            if !self.limits.check(&file, depth, new_args.len()) {
+                return None;
+            }
+            self.stack
+                .extend(new_args.iter().rev().map(|s| (s.into(), depth + 1)));
         }
     }
 }
@@ -685,6 +712,23 @@ mod test {
         assert_eq!(parse_arguments_(vec!["-@O+foo".into()]), cannot_cache);
     }
 
+    #[test]
+    fn test_at_signs_file_recursive() {
+        let td = tempfile::Builder::new()
+            .prefix("sccache")
+            .tempdir()
+            .unwrap();
+        let file = td.path().join("self");
+        let arg = format!("-@{}", file.display());
+        fs::write(&file, format!("-c foo.c {}", arg)).unwrap();
+        match parse_arguments_(vec![arg]) {
+            CompilerArguments::CannotCache("@ expansion", Some(error)) => {
+                assert!(error.contains("nested more than 16 levels"), "{}", error)
+            }
+            o => panic!("Got unexpected parse result: {:?}", o),
+        }
+    }
+
     #[test]
     fn test_at_signs_file_not_readable() {
         let td = tempfile::Builder::new()
@@ -769,6 +813,9 @@ mod test {
             color_mode: ColorMode::Auto,
             suppress_rewrite_includes_only: false,
             too_hard_for_preprocessor_cache_mode: None,
+            stdin: None,
+            output_to_stdout: false,
+            sysroot: None,
         };
         let compiler = &f.bins[0];
         // Compiler invocation.
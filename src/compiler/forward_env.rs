@@ -0,0 +1,175 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The environment variables the client sends to the server with a compile
+//! request: those the compilers and sccache read, and the ones factored into
+//! the hash keys, rather than the whole environment of the build, which is
+//! large and may have unrelated secrets.
+//!
+//! `SCCACHE_FORWARD_ENV` adds comma-separated names, or prefixes ending with
+//! `*`, to them, and `*` forwards the whole environment. The environment of a
+//! Rust compilation is always forwarded whole, since `env!` may read any
+//! variable.
+
+use crate::compiler::{c, preprocessor_cache};
+use std::ffi::{OsStr, OsString};
+
+/// The variable adding names to those forwarded.
+pub const FORWARD_ENV_VAR: &str = "SCCACHE_FORWARD_ENV";
+
+/// The names, or prefixes ending with `*`, of the variables forwarded, in any
+/// case since Windows ignores it.
+const FORWARDED: &[&str] = &[
+    // sccache itself, on the server.
+    "SCCACHE_*",
+    // Finding and running the compiler and its tools.
+    "PATH",
+    "PATHEXT",
+    "HOME",
+    "USERPROFILE",
+    "SYSTEMROOT",
+    "WINDIR",
+    "COMSPEC",
+    "TMPDIR",
+    "TMP",
+    "TEMP",
+    "LD_LIBRARY_PATH",
+    "DYLD_*",
+    "LIBRARY_PATH",
+    "COMPILER_PATH",
+    "GCC_*",
+    "DEVELOPER_DIR",
+    "MAKEFLAGS",
+    "MFLAGS",
+    // The language and colors of the diagnostics.
+    "LANG",
+    "LANGUAGE",
+    "LC_*",
+    "TERM",
+    "NO_COLOR",
+    "CLICOLOR*",
+    // Read by the compilers, beyond those in the hash keys.
+    "DEPENDENCIES_OUTPUT",
+    "SUNPRO_DEPENDENCIES",
+    "CCC_*",
+    "INCLUDE",
+    "LIB",
+    "LIBPATH",
+    "CL",
+    "_CL_",
+    "VSLANG",
+    "VS_UNICODE_OUTPUT",
+    "NVCC_*",
+    "CUDA_*",
+    "HIP_*",
+    "ROCM_PATH",
+];
+
+/// Whether `name` matches `pattern`, a name or a prefix ending with `*`.
+fn matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name
+            .get(..prefix.len())
+            .is_some_and(|start| start.eq_ignore_ascii_case(prefix)),
+        None => name.eq_ignore_ascii_case(pattern),
+    }
+}
+
+/// Keep the variables of `env_vars` to forward to the server for compiling
+/// `args`.
+pub fn forwarded_env(
+    args: &[impl AsRef<OsStr>],
+    env_vars: Vec<(OsString, OsString)>,
+) -> Vec<(OsString, OsString)> {
+    if args.iter().any(|arg| arg.as_ref() == "--crate-name") {
+        return env_vars;
+    }
+    let extra = env_vars
+        .iter()
+        .find(|(k, _)| k == FORWARD_ENV_VAR)
+        .map(|(_, v)| v.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let extra: Vec<&str> = extra
+        .split(',')
+        .map(str::trim)
+        .filter(|pattern| !pattern.is_empty())
+        .collect();
+    if extra.contains(&"*") {
+        return env_vars;
+    }
+    env_vars
+        .into_iter()
+        .filter(|(k, _)| {
+            c::CACHED_ENV_VARS.contains(k.as_os_str())
+                || preprocessor_cache::CACHED_ENV_VARS.contains(k.as_os_str())
+                || k.to_str().is_some_and(|name| {
+                    FORWARDED
+                        .iter()
+                        .chain(&extra)
+                        .any(|pattern| matches(pattern, name))
+                })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(OsString, OsString)> {
+        vars.iter()
+            .map(|(k, v)| (OsString::from(k), OsString::from(v)))
+            .collect()
+    }
+
+    #[test]
+    fn test_forwarded_env() {
+        let vars = env(&[
+            ("PATH", "/usr/bin"),
+            ("Path", "C:\\Windows"),
+            ("AWS_SECRET_ACCESS_KEY", "hunter2"),
+            ("SCCACHE_RECACHE", "1"),
+            ("SOURCE_DATE_EPOCH", "0"),
+            ("CPATH", "/opt/include"),
+            ("LC_ALL", "C"),
+            ("LCD", "1"),
+            ("MY_TOOL_HOME", "/opt/tool"),
+        ]);
+        let args = ["-c", "a.c"];
+        assert_eq!(
+            forwarded_env(&args, vars.clone()),
+            env(&[
+                ("PATH", "/usr/bin"),
+                ("Path", "C:\\Windows"),
+                ("SCCACHE_RECACHE", "1"),
+                ("SOURCE_DATE_EPOCH", "0"),
+                ("CPATH", "/opt/include"),
+                ("LC_ALL", "C"),
+            ])
+        );
+
+        let mut extended = vars.clone();
+        extended.push(("SCCACHE_FORWARD_ENV".into(), "MY_*, LCD".into()));
+        let forwarded = forwarded_env(&args, extended);
+        assert!(forwarded.iter().any(|(k, _)| k == "MY_TOOL_HOME"));
+        assert!(forwarded.iter().any(|(k, _)| k == "LCD"));
+        assert!(!forwarded.iter().any(|(k, _)| k == "AWS_SECRET_ACCESS_KEY"));
+
+        let mut all = vars.clone();
+        all.push(("SCCACHE_FORWARD_ENV".into(), "*".into()));
+        assert_eq!(forwarded_env(&args, all.clone()), all);
+
+        // Rust compilations may read any variable.
+        let args = ["--crate-name", "foo", "src/lib.rs"];
+        assert_eq!(forwarded_env(&args, vars.clone()), vars);
+    }
+}
@@ -13,10 +13,14 @@
 // limitations under the License.
 
 use crate::compiler::args::*;
-use crate::compiler::c::{ArtifactDescriptor, CCompilerImpl, CCompilerKind, ParsedArguments};
-use crate::compiler::{clang, Cacheable, ColorMode, CompileCommand, CompilerArguments, Language};
+use crate::compiler::c::{
+    split_header_list, ArtifactDescriptor, CCompilerImpl, CCompilerKind, ParsedArguments,
+};
+use crate::compiler::{
+    clang, Cacheable, ColorMode, CompileCommand, CompilerArguments, Language, DEPFILE_OUTPUT,
+};
 use crate::mock_command::{CommandCreatorSync, RunCommand};
-use crate::util::{run_input_output, OsStrExt};
+use crate::util::{run_input_output_streaming, OsStrExt};
 use crate::{counted_array, dist};
 use async_trait::async_trait;
 use fs::File;
@@ -57,6 +61,10 @@ impl CCompilerImpl for Gcc {
         parse_arguments(arguments, cwd, &ARGS[..], self.gplusplus, self.kind())
     }
 
+    fn split_inputs(&self, arguments: &[OsString]) -> Option<Vec<Vec<OsString>>> {
+        split_inputs(arguments, &ARGS[..])
+    }
+
     #[allow(clippy::too_many_arguments)]
     async fn preprocess<T>(
         &self,
@@ -69,6 +77,36 @@ impl CCompilerImpl for Gcc {
         rewrite_includes_only: bool,
         preprocessor_cache_mode: bool,
     ) -> Result<process::Output>
+    where
+        T: CommandCreatorSync,
+    {
+        self.preprocess_streaming(
+            creator,
+            executable,
+            parsed_args,
+            cwd,
+            env_vars,
+            may_dist,
+            rewrite_includes_only,
+            preprocessor_cache_mode,
+            &mut |_| {},
+        )
+        .await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn preprocess_streaming<T>(
+        &self,
+        creator: &T,
+        executable: &Path,
+        parsed_args: &ParsedArguments,
+        cwd: &Path,
+        env_vars: &[(OsString, OsString)],
+        may_dist: bool,
+        rewrite_includes_only: bool,
+        preprocessor_cache_mode: bool,
+        on_output: &mut (dyn for<'a> FnMut(&'a [u8]) + Send),
+    ) -> Result<process::Output>
     where
         T: CommandCreatorSync,
     {
@@ -87,6 +125,8 @@ impl CCompilerImpl for Gcc {
             self.kind(),
             rewrite_includes_only,
             ignorable_whitespace_flags,
+            preprocessor_cache_mode,
+            on_output,
         )
         .await
     }
@@ -129,6 +169,7 @@ ArgData! { pub
     // Used for arguments that shouldn't affect the computed hash
     Unhashed(OsString),
     DoCompilation,
+    Stdin,
     Output(PathBuf),
     NeedDepTarget,
     // Though you might think this should be a path as it's a Makefile target,
@@ -137,14 +178,20 @@ ArgData! { pub
     DepTarget(OsString),
     DepArgumentPath(PathBuf),
     Language(OsString),
-    SplitDwarf,
+    // Makes the compiler write a file next to the object, see `EXTRA_OUTPUTS`.
+    ExtraOutput,
     ProfileGenerate,
     ClangProfileUse(PathBuf),
     TestCoverage,
     Coverage,
     ExtraHashFile(PathBuf),
+    // A plugin of the compiler, hashed as an input, see `resolve_plugin`.
+    Plugin(PathBuf),
+    Sysroot(PathBuf),
     // Only valid for clang, but this needs to be here since clang shares gcc's arg parsing.
     XClang(OsString),
+    // The arguments given to the preprocessor, see `parse_arguments`.
+    XPreprocessor(OsString),
     Arch(OsString),
     PedanticFlag,
     Standard(OsString),
@@ -154,14 +201,41 @@ use self::ArgData::*;
 
 const ARCH_FLAG: &str = "-arch";
 
+/// Get the plugin which the compiler loads for `path` from `cwd`, to hash it,
+/// if it can be found. GCC looks up the short names, without a directory nor
+/// an extension, in its own plugin directory.
+pub(crate) fn resolve_plugin(path: &Path, cwd: &Path) -> Option<PathBuf> {
+    if path.parent() == Some(Path::new("")) && path.extension().is_none() {
+        return None;
+    }
+    let path = cwd.join(path);
+    path.is_file().then_some(path)
+}
+
+/// The files which the `ExtraOutput` flags make the compiler write next to
+/// the object: the flag, the name of the file in the cache entry, its
+/// extension, replacing the one of the object, and whether the compiler may
+/// not write it.
+const EXTRA_OUTPUTS: &[(&str, &str, &str, bool)] = &[
+    (
+        "-fsave-optimization-record",
+        "opt-record",
+        "opt.yaml",
+        false,
+    ),
+    ("-fstack-usage", "su", "su", false),
+    // -gsplit-dwarf doesn't guarantee .dwo file if no -g is specified
+    ("-gsplit-dwarf", "dwo", "dwo", true),
+];
+
 // Mostly taken from https://github.com/ccache/ccache/blob/master/src/compopt.cpp#L52-L172
 counted_array!(pub static ARGS: [ArgInfo<ArgData>; _] = [
-    flag!("-", TooHardFlag),
+    flag!("-", Stdin),
     flag!("--coverage", Coverage),
     take_arg!("--param", OsString, Separated, PassThrough),
     flag!("--save-temps", TooHardFlag),
     take_arg!("--serialize-diagnostics", PathBuf, Separated, PassThroughPath),
-    take_arg!("--sysroot", PathBuf, Separated, PassThroughPath),
+    take_arg!("--sysroot", PathBuf, CanBeSeparated('='), Sysroot),
     take_arg!("-A", OsString, Separated, PassThrough),
     take_arg!("-B", PathBuf, CanBeSeparated, PassThroughPath),
     take_arg!("-D", OsString, CanBeSeparated, PassThrough),
@@ -182,11 +256,11 @@ counted_array!(pub static ARGS: [ArgInfo<ArgData>; _] = [
     take_arg!("-U", OsString, CanBeSeparated, PassThrough),
     take_arg!("-V", OsString, Separated, PassThrough),
     flag!("-Werror=pedantic", PedanticFlag),
-    take_arg!("-Wp", OsString, Concatenated(','), PreprocessorArgument),
+    take_arg!("-Wp", OsString, Concatenated(','), XPreprocessor),
     flag!("-Wpedantic", PedanticFlag),
     take_arg!("-Xassembler", OsString, Separated, PassThrough),
     take_arg!("-Xlinker", OsString, Separated, PassThrough),
-    take_arg!("-Xpreprocessor", OsString, Separated, PreprocessorArgument),
+    take_arg!("-Xpreprocessor", OsString, Separated, XPreprocessor),
     take_arg!(ARCH_FLAG, OsString, Separated, Arch),
     take_arg!("-aux-info", OsString, Separated, PassThrough),
     take_arg!("-b", OsString, Separated, PassThrough),
@@ -196,15 +270,17 @@ counted_array!(pub static ARGS: [ArgInfo<ArgData>; _] = [
     flag!("-fno-profile-generate", TooHardFlag),
     flag!("-fno-profile-use", TooHardFlag),
     flag!("-fno-working-directory", PreprocessorArgumentFlag),
+    take_arg!("-fplugin", PathBuf, Concatenated('='), Plugin),
     flag!("-fplugin=libcc1plugin", TooHardFlag),
     flag!("-fprofile-arcs", ProfileGenerate),
     flag!("-fprofile-generate", ProfileGenerate),
     take_arg!("-fprofile-use", OsString, Concatenated, TooHard),
     flag!("-frepo", TooHardFlag),
+    flag!("-fstack-usage", ExtraOutput),
     flag!("-fsyntax-only", TooHardFlag),
     flag!("-ftest-coverage", TestCoverage),
     flag!("-fworking-directory", PreprocessorArgumentFlag),
-    flag!("-gsplit-dwarf", SplitDwarf),
+    flag!("-gsplit-dwarf", ExtraOutput),
     take_arg!("-idirafter", PathBuf, CanBeSeparated, PreprocessorArgumentPath),
     take_arg!("-iframework", PathBuf, CanBeSeparated, PreprocessorArgumentPath),
     take_arg!("-imacros", PathBuf, CanBeSeparated, PreprocessorArgumentPath),
@@ -257,6 +333,10 @@ where
     let mut double_dash_input = false;
     let mut dep_target = None;
     let mut dep_flag = OsString::from("-MT");
+    let mut dep_path = None;
+    // Whether the preprocessor writes a dependency file, with `-MD` or
+    // `-MMD`, unlike `-MP` alone.
+    let mut writes_depfile = false;
     let mut common_args = vec![];
     let mut arch_args = vec![];
     let mut unhashed_args = vec![];
@@ -268,7 +348,7 @@ where
     let mut multiple_input_files = Vec::new();
     let mut pedantic_flag = false;
     let mut language_extensions = true; // by default, GCC allows extensions
-    let mut split_dwarf = false;
+    let mut extra_outputs = vec![];
     let mut need_explicit_dep_target = false;
     enum DepArgumentRequirePath {
         NotNeeded,
@@ -281,17 +361,19 @@ where
     let mut profile_generate = false;
     let mut outputs_gcno = false;
     let mut xclangs: Vec<OsString> = vec![];
+    let mut xpreprocessors: Vec<OsString> = vec![];
     let mut color_mode = ColorMode::Auto;
     let mut seen_arch = None;
+    let mut sysroot = None;
     let dont_cache_multiarch = env::var("SCCACHE_CACHE_MULTIARCH").is_err();
 
     // Custom iterator to expand `@` arguments which stand for reading a file
     // and interpreting it as a list of more arguments.
-    let it = ExpandIncludeFile::new(cwd, arguments);
+    let mut it = ExpandIncludeFile::new(cwd, arguments);
 
     let mut too_hard_for_preprocessor_cache_mode = None;
 
-    let mut args_iter = ArgsIter::new(it, arg_info);
+    let mut args_iter = ArgsIter::new(&mut it, arg_info);
     if kind == CCompilerKind::Clang {
         args_iter = args_iter.with_double_dashes();
     }
@@ -324,7 +406,9 @@ where
             Some(PedanticFlag) => pedantic_flag = true,
             // standard values vary, but extension values all start with "gnu"
             Some(Standard(version)) => language_extensions = version.starts_with("gnu"),
-            Some(SplitDwarf) => split_dwarf = true,
+            Some(ExtraOutput) => {
+                extra_outputs.push(arg.flag_str().expect("Extra output flag expected"))
+            }
             Some(DoCompilation) => {
                 compilation = true;
                 compilation_flag =
@@ -350,7 +434,9 @@ where
             }
             Some(Output(p)) => output_arg = Some(p.clone()),
             Some(NeedDepTarget) => {
-                too_hard_for_preprocessor_cache_mode = Some(arg.to_os_string());
+                if matches!(arg.flag_str(), Some("-MD") | Some("-MMD")) {
+                    writes_depfile = true;
+                }
                 need_explicit_dep_target = true;
                 if let DepArgumentRequirePath::NotNeeded = need_explicit_dep_argument_path {
                     need_explicit_dep_argument_path = DepArgumentRequirePath::Missing;
@@ -360,10 +446,12 @@ where
                 dep_flag = OsString::from(arg.flag_str().expect("Dep target flag expected"));
                 dep_target = Some(s.clone());
             }
-            Some(DepArgumentPath(_)) => {
+            Some(DepArgumentPath(path)) => {
+                dep_path = Some(path.clone());
                 need_explicit_dep_argument_path = DepArgumentRequirePath::Provided
             }
             Some(ExtraHashFile(_))
+            | Some(Plugin(_))
             | Some(PassThroughFlag)
             | Some(PreprocessorArgumentFlag)
             | Some(PreprocessorArgument(_))
@@ -383,6 +471,8 @@ where
                     "rs" => Some(Language::Rust),
                     "cuda" => Some(Language::Cuda),
                     "hip" => Some(Language::Hip),
+                    "assembler" => Some(Language::Assembler),
+                    "assembler-with-cpp" => Some(Language::AssemblerToPreprocess),
                     _ => cannot_cache!("-x"),
                 };
             }
@@ -398,6 +488,24 @@ where
                 seen_arch = Some(arch.clone());
             }
             Some(XClang(s)) => xclangs.push(s.clone()),
+            Some(XPreprocessor(s)) => {
+                if arg.flag_str() == Some("-Wp") {
+                    match s.to_str() {
+                        Some(s) => xpreprocessors.extend(s.split(',').map(OsString::from)),
+                        None => cannot_cache!("-Wp", s.to_string_lossy().into_owned()),
+                    }
+                } else {
+                    xpreprocessors.push(s.clone());
+                }
+            }
+            Some(Sysroot(path)) => sysroot = Some(cwd.join(path)),
+            Some(Stdin) => {
+                if input_arg.is_some() {
+                    multiple_input = true;
+                    multiple_input_files.push("-".into());
+                }
+                input_arg = Some("-".into());
+            }
             None => match arg {
                 Argument::Raw(ref val) if val == "--" => {
                     if input_arg.is_none() {
@@ -416,7 +524,7 @@ where
             },
         }
         let args = match arg.get_data() {
-            Some(SplitDwarf)
+            Some(ExtraOutput)
             | Some(PedanticFlag)
             | Some(Standard(_))
             | Some(ProfileGenerate)
@@ -428,26 +536,35 @@ where
             | Some(NoDiagnosticsColorFlag)
             | Some(PassThroughFlag)
             | Some(PassThrough(_))
-            | Some(PassThroughPath(_)) => &mut common_args,
+            | Some(PassThroughPath(_))
+            | Some(Sysroot(_)) => &mut common_args,
             Some(Unhashed(_)) => &mut unhashed_args,
             Some(Arch(_)) => &mut arch_args,
             Some(ExtraHashFile(path)) => {
                 extra_hash_files.push(cwd.join(path));
                 &mut common_args
             }
-            Some(PreprocessorArgument(_)) => {
-                too_hard_for_preprocessor_cache_mode = match arg.flag_str() {
-                    Some(s) if s == "-Xpreprocessor" || s == "-Wp" => Some(arg.to_os_string()),
-                    _ => None,
-                };
-                &mut preprocessor_args
-            }
-            Some(PreprocessorArgumentFlag) | Some(PreprocessorArgumentPath(_)) => {
-                &mut preprocessor_args
+            Some(Plugin(path)) => {
+                match resolve_plugin(path, cwd) {
+                    Some(path) => extra_hash_files.push(path),
+                    None => cannot_cache!(
+                        arg.flag_str().expect("Plugin flag expected"),
+                        path.display().to_string()
+                    ),
+                }
+                &mut common_args
             }
+            Some(PreprocessorArgumentFlag)
+            | Some(PreprocessorArgument(_))
+            | Some(PreprocessorArgumentPath(_)) => &mut preprocessor_args,
             Some(DepArgumentPath(_)) | Some(NeedDepTarget) => &mut dependency_args,
-            Some(DoCompilation) | Some(Language(_)) | Some(Output(_)) | Some(XClang(_))
-            | Some(DepTarget(_)) => continue,
+            Some(DoCompilation)
+            | Some(Language(_))
+            | Some(Output(_))
+            | Some(XClang(_))
+            | Some(XPreprocessor(_))
+            | Some(DepTarget(_))
+            | Some(Stdin) => continue,
             Some(TooHardFlag) | Some(TooHard(_)) => unreachable!(),
             None => match arg {
                 Argument::Raw(_) => continue,
@@ -465,13 +582,63 @@ where
         };
         args.extend(arg.normalize(norm).iter_os_strings());
     }
+    if let Some(error) = it.error() {
+        cannot_cache!("@ expansion", error.to_owned());
+    }
+
+    // The arguments of -Wp, and -Xpreprocessor are the flags of the
+    // preprocessor, so that `-Wp,-D,FOO` is hashed and preprocessed as
+    // `-DFOO`. The flags which aren't known to only affect the preprocessing
+    // are kept as given, and those making it write files can't be cached.
+    for arg in ArgsIter::new(xpreprocessors.into_iter(), &ARGS[..]) {
+        let arg = try_or_cannot_cache!(arg, "argument parse");
+        let args = match arg.get_data() {
+            Some(PreprocessorArgumentFlag)
+            | Some(PreprocessorArgument(_))
+            | Some(PreprocessorArgumentPath(_)) => &mut preprocessor_args,
+            Some(PassThrough(_)) if matches!(arg.flag_str(), Some("-D" | "-U" | "-A")) => {
+                &mut common_args
+            }
+            Some(TooHardFlag)
+            | Some(TooHard(_))
+            | Some(DoCompilation)
+            | Some(Output(_))
+            | Some(NeedDepTarget)
+            | Some(DepTarget(_))
+            | Some(DepArgumentPath(_))
+            | Some(ExtraOutput)
+            | Some(Language(_))
+            | Some(Stdin)
+            | Some(XPreprocessor(_)) => cannot_cache!(
+                "-Xpreprocessor",
+                arg.flag_str().unwrap_or_default().to_owned()
+            ),
+            None if matches!(arg, Argument::Raw(_)) => cannot_cache!(
+                "-Xpreprocessor",
+                arg.to_os_string().to_string_lossy().into_owned()
+            ),
+            _ => {
+                too_hard_for_preprocessor_cache_mode = Some("-Xpreprocessor".into());
+                for arg in arg.iter_os_strings() {
+                    preprocessor_args.push("-Xpreprocessor".into());
+                    preprocessor_args.push(arg);
+                }
+                continue;
+            }
+        };
+        let norm = match arg.flag_str() {
+            Some(s) if s.len() == 2 => NormalizedDisposition::Concatenated,
+            _ => NormalizedDisposition::Separated,
+        };
+        args.extend(arg.normalize(norm).iter_os_strings());
+    }
 
-    let xclang_it = ExpandIncludeFile::new(cwd, &xclangs);
+    let mut xclang_it = ExpandIncludeFile::new(cwd, &xclangs);
     let mut follows_plugin_arg = false;
-    for arg in ArgsIter::new(xclang_it, (&ARGS[..], &clang::ARGS[..])) {
+    for arg in ArgsIter::new(&mut xclang_it, (&ARGS[..], &clang::ARGS[..])) {
         let arg = try_or_cannot_cache!(arg, "argument parse");
         let args = match arg.get_data() {
-            Some(SplitDwarf)
+            Some(ExtraOutput)
             | Some(PedanticFlag)
             | Some(Standard(_))
             | Some(ProfileGenerate)
@@ -481,8 +648,10 @@ where
             | Some(DoCompilation)
             | Some(Language(_))
             | Some(Output(_))
+            | Some(Stdin)
             | Some(TooHardFlag)
             | Some(XClang(_))
+            | Some(XPreprocessor(_))
             | Some(TooHard(_)) => cannot_cache!(arg
                 .flag_str()
                 .unwrap_or("Can't handle complex arguments through clang",)),
@@ -506,12 +675,23 @@ where
             | Some(Arch(_))
             | Some(PassThrough(_))
             | Some(PassThroughFlag)
-            | Some(PassThroughPath(_)) => &mut common_args,
+            | Some(PassThroughPath(_))
+            | Some(Sysroot(_)) => &mut common_args,
             Some(Unhashed(_)) => &mut unhashed_args,
             Some(ExtraHashFile(path)) => {
                 extra_hash_files.push(cwd.join(path));
                 &mut common_args
             }
+            Some(Plugin(path)) => {
+                match resolve_plugin(path, cwd) {
+                    Some(path) => extra_hash_files.push(path),
+                    None => cannot_cache!(
+                        arg.flag_str().expect("Plugin flag expected"),
+                        path.display().to_string()
+                    ),
+                }
+                &mut common_args
+            }
             Some(PreprocessorArgumentFlag)
             | Some(PreprocessorArgument(_))
             | Some(PreprocessorArgumentPath(_)) => &mut preprocessor_args,
@@ -536,6 +716,9 @@ where
             args.push(arg)
         }
     }
+    if let Some(error) = xclang_it.error() {
+        cannot_cache!("@ expansion", error.to_owned());
+    }
 
     // We only support compilation.
     if !compilation {
@@ -567,20 +750,54 @@ where
         Some(l) => l,
         None => cannot_cache!("unknown source language"),
     };
+    let stdio_supported = matches!(kind, CCompilerKind::Gcc | CCompilerKind::Clang);
+    let stdin = input == "-";
+    if stdin {
+        if !stdio_supported {
+            cannot_cache!("-");
+        }
+        // The object would be named after the input otherwise.
+        if output_arg.is_none() {
+            cannot_cache!("- without -o");
+        }
+        // There is no source file to check the includes of.
+        too_hard_for_preprocessor_cache_mode = Some("-".into());
+    }
     let mut outputs = HashMap::new();
     let output = match output_arg {
         // We can't cache compilation that doesn't go to a file
         None => PathBuf::from(Path::new(&input).with_extension("o").file_name().unwrap()),
         Some(o) => o,
     };
-    if split_dwarf {
-        let dwo = output.with_extension("dwo");
-        // -gsplit-dwarf doesn't guarantee .dwo file if no -g is specified
+    let output_to_stdout = output == Path::new("-");
+    if output_to_stdout {
+        // Only the object goes to stdout, the other outputs would be named
+        // after it.
+        if !stdio_supported
+            || !extra_outputs.is_empty()
+            || outputs_gcno
+            || (need_explicit_dep_target && dep_target.is_none())
+            || matches!(
+                need_explicit_dep_argument_path,
+                DepArgumentRequirePath::Missing
+            )
+        {
+            cannot_cache!("-o -");
+        }
+        // Preprocessor cache entries don't tell results written to stdout
+        // apart from the others.
+        too_hard_for_preprocessor_cache_mode = Some("-o -".into());
+    }
+    for flag in extra_outputs {
+        let &(_, name, extension, optional) = EXTRA_OUTPUTS
+            .iter()
+            .find(|(f, ..)| *f == flag)
+            .expect("Extra output flags have an extra output");
         outputs.insert(
-            "dwo",
+            name,
             ArtifactDescriptor {
-                path: dwo,
-                optional: true,
+                path: output.with_extension(extension),
+                optional,
             },
         );
     }
@@ -607,13 +824,26 @@ where
         dependency_args.push(OsString::from("-MF"));
         dependency_args.push(Path::new(&output).with_extension("d").into_os_string());
     }
-    outputs.insert(
-        "obj",
-        ArtifactDescriptor {
-            path: output,
-            optional: false,
-        },
-    );
+    // The dependency file is cached along with the object, so that it is
+    // there after a hit which skipped the preprocessor.
+    if writes_depfile {
+        outputs.insert(
+            DEPFILE_OUTPUT,
+            ArtifactDescriptor {
+                path: dep_path.unwrap_or_else(|| output.with_extension("d")),
+                optional: false,
+            },
+        );
+    }
+    if !output_to_stdout {
+        outputs.insert(
+            "obj",
+            ArtifactDescriptor {
+                path: output,
+                optional: false,
+            },
+        );
+    }
 
     CompilerArguments::Ok(ParsedArguments {
         input: input.into(),
@@ -633,9 +863,76 @@ where
         color_mode,
         suppress_rewrite_includes_only,
         too_hard_for_preprocessor_cache_mode,
+        stdin: None,
+        output_to_stdout,
+        sysroot,
     })
 }
 
+/// Split the compilation of several source files with `arguments` into one
+/// compilation per source file, keeping the other arguments in place. Each
+/// writes the object the compiler names after its source file, as it does for
+/// them all at once.
+///
+/// Return `None` unless `arguments` compile several source files with known
+/// extensions, and when they are given an output, a dependency file or
+/// target, stdin, or a language which would apply to some of them only, as
+/// these can't be split.
+pub fn split_inputs<S>(arguments: &[OsString], arg_info: S) -> Option<Vec<Vec<OsString>>>
+where
+    S: SearchableArgInfo<ArgData>,
+{
+    // The sources may be in the files of `@` arguments.
+    if arguments.iter().any(|arg| arg.starts_with("@")) {
+        return None;
+    }
+    let mut compilation = false;
+    let mut language = false;
+    let mut inputs = vec![];
+    let mut position = 0;
+    for arg in ArgsIter::new(arguments.iter().cloned(), arg_info) {
+        let arg = arg.ok()?;
+        match arg.get_data() {
+            Some(DoCompilation) => compilation = true,
+            Some(Output(_)) | Some(DepTarget(_)) | Some(DepArgumentPath(_)) | Some(Stdin) => {
+                return None
+            }
+            Some(Language(_)) if !inputs.is_empty() => return None,
+            Some(Language(_)) => language = true,
+            Some(_) => {}
+            None => match arg {
+                Argument::Raw(ref val) if val == "--" => return None,
+                // What looks like a source may be the value of an unknown
+                // flag, so that the sources must be known ones.
+                Argument::Raw(ref val)
+                    if !language && Language::from_file_name(Path::new(val)).is_none() =>
+                {
+                    return None
+                }
+                Argument::Raw(_) => inputs.push(position),
+                _ => {}
+            },
+        }
+        position += arg.iter_os_strings().count();
+    }
+    if !compilation || inputs.len() < 2 {
+        return None;
+    }
+    Some(
+        inputs
+            .iter()
+            .map(|input| {
+                arguments
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, _)| i == input || !inputs.contains(i))
+                    .map(|(_, arg)| arg.clone())
+                    .collect()
+            })
+            .collect(),
+    )
+}
+
 fn language_to_gcc_arg(lang: Language) -> Option<&'static str> {
     match lang {
         Language::C => Some("c"),
@@ -648,6 +945,8 @@ fn language_to_gcc_arg(lang: Language) -> Option<&'static str> {
         Language::Rust => None, // Let the compiler decide
         Language::Hip => Some("hip"),
         Language::GenericHeader => None, // Let the compiler decide
+        Language::Assembler => Some("assembler"),
+        Language::AssemblerToPreprocess => Some("assembler-with-cpp"),
     }
 }
 
@@ -742,6 +1041,8 @@ pub async fn preprocess<T>(
     kind: CCompilerKind,
     rewrite_includes_only: bool,
     ignorable_whitespace_flags: Vec<String>,
+    list_headers: bool,
+    on_output: &mut (dyn FnMut(&[u8]) + Send),
 ) -> Result<process::Output>
 where
     T: CommandCreatorSync,
@@ -758,10 +1059,27 @@ where
         rewrite_includes_only,
         ignorable_whitespace_flags,
     );
+    if list_headers {
+        // The headers hashed in preprocessor cache mode are those of the line
+        // markers of the output, and those the preprocessor lists on stderr.
+        cmd.arg("-H");
+    }
     if log_enabled!(Trace) {
         trace!("preprocess: {:?}", cmd);
     }
-    run_input_output(cmd, None).await
+    let output = run_input_output_streaming(cmd, parsed_args.stdin.clone(), on_output).await;
+    if !list_headers {
+        return output;
+    }
+    // Only the diagnostics are shown when the preprocessor fails.
+    output.map_err(|e| match e.downcast::<ProcessError>() {
+        Ok(ProcessError(output)) => ProcessError(process::Output {
+            stderr: split_header_list(&output.stderr).1,
+            ..output
+        })
+        .into(),
+        Err(e) => e,
+    })
 }
 
 pub fn generate_compile_commands(
@@ -785,6 +1103,7 @@ pub fn generate_compile_commands(
 
     let out_file = match parsed_args.outputs.get("obj") {
         Some(obj) => &obj.path,
+        None if parsed_args.output_to_stdout => Path::new("-"),
         None => return Err(anyhow!("Missing object file output")),
     };
 
@@ -813,12 +1132,20 @@ pub fn generate_compile_commands(
         arguments,
         env_vars: env_vars.to_owned(),
         cwd: cwd.to_owned(),
+        stdin: parsed_args.stdin.clone(),
     };
 
     #[cfg(not(feature = "dist-client"))]
     let dist_command = None;
     #[cfg(feature = "dist-client")]
     let dist_command = (|| {
+        if parsed_args.reads_stdin() || parsed_args.output_to_stdout {
+            return None;
+        }
+        // The sources of the assembler are assembled locally, which is quick.
+        if let Language::Assembler | Language::AssemblerToPreprocess = parsed_args.language {
+            return None;
+        }
         // https://gcc.gnu.org/onlinedocs/gcc-4.9.0/gcc/Overall-Options.html
         let mut language: Option<String> =
             language_to_gcc_arg(parsed_args.language).map(|lang| lang.into());
@@ -872,18 +1199,28 @@ pub fn generate_compile_commands(
     Ok((command, dist_command, Cacheable::Yes))
 }
 
+/// Iterator that expands `@file` arguments in place, recursively, within
+/// the `limits`.
 pub struct ExpandIncludeFile<'a> {
     cwd: &'a Path,
-    stack: Vec<OsString>,
+    /// The arguments left, with how deep in the response files they are.
+    stack: Vec<(OsString, usize)>,
+    limits: ResponseFileLimits,
 }
 
 impl<'a> ExpandIncludeFile<'a> {
     pub fn new(cwd: &'a Path, args: &[OsString]) -> Self {
         ExpandIncludeFile {
-            stack: args.iter().rev().map(|a| a.to_owned()).collect(),
+            stack: args.iter().rev().map(|a| (a.to_owned(), 0)).collect(),
             cwd,
+            limits: ResponseFileLimits::new(),
         }
     }
+
+    /// Why the expansion stopped before the end of the arguments, if it did.
+    pub fn error(&self) -> Option<&str> {
+        self.limits.error()
+    }
 }
 
 impl<'a> Iterator for ExpandIncludeFile<'a> {
@@ -891,10 +1228,10 @@ impl<'a> Iterator for ExpandIncludeFile<'a> {
 
     fn next(&mut self) -> Option<OsString> {
         loop {
-            let arg = match self.stack.pop() {
-                Some(arg) => arg,
-                None => return None,
-            };
+            if self.error().is_some() {
+                return None;
+            }
+            let (arg, depth) = self.stack.pop()?;
             let file = match arg.split_prefix("@") {
                 Some(arg) => self.cwd.join(arg),
                 None => return Some(arg),
@@ -936,7 +1273,11 @@ impl<'a> Iterator for ExpandIncludeFile<'a> {
                 return Some(arg);
             }
             let new_args = contents.split_whitespace().collect::<Vec<_>>();
-            self.stack.extend(new_args.iter().rev().map(|s| s.into()));
+            if !self.limits.check(&file, depth, new_args.len()) {
+                return None;
+            }
+            self.stack
+                .extend(new_args.iter().rev().map(|s| (s.into(), depth + 1)));
         }
     }
 }
@@ -1060,6 +1401,120 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_parse_arguments_assembler() {
+        for (args, expected) in [
+            (
+                stringvec!["-c", "foo.s", "-o", "foo.o"],
+                Language::Assembler,
+            ),
+            (
+                stringvec!["-c", "foo.S", "-o", "foo.o"],
+                Language::AssemblerToPreprocess,
+            ),
+            (
+                stringvec!["-x", "assembler", "-c", "foo.asm", "-o", "foo.o"],
+                Language::Assembler,
+            ),
+            (
+                stringvec!["-x", "assembler-with-cpp", "-c", "foo.asm", "-o", "foo.o"],
+                Language::AssemblerToPreprocess,
+            ),
+        ] {
+            match parse_arguments_(args, false) {
+                CompilerArguments::Ok(args) => assert_eq!(expected, args.language),
+                o => panic!("Got unexpected parse result: {:?}", o),
+            }
+        }
+        assert_eq!(
+            Some("assembler-with-cpp"),
+            language_to_gcc_arg(Language::AssemblerToPreprocess)
+        );
+    }
+
+    #[test]
+    fn test_parse_arguments_sysroot() {
+        for args in [
+            stringvec!["--sysroot=/opt/cross", "-c", "foo.c", "-o", "foo.o"],
+            stringvec!["--sysroot", "/opt/cross", "-c", "foo.c", "-o", "foo.o"],
+        ] {
+            let parsed_args = match parse_arguments_(args, false) {
+                CompilerArguments::Ok(args) => args,
+                o => panic!("Got unexpected parse result: {:?}", o),
+            };
+            assert_eq!(Some(PathBuf::from("/opt/cross")), parsed_args.sysroot);
+            assert_eq!(ovec!["--sysroot", "/opt/cross"], parsed_args.common_args);
+        }
+        let args = stringvec!["-c", "foo.c", "-o", "foo.o"];
+        match parse_arguments_(args, false) {
+            CompilerArguments::Ok(args) => assert_eq!(None, args.sysroot),
+            o => panic!("Got unexpected parse result: {:?}", o),
+        }
+    }
+
+    #[test]
+    fn test_parse_arguments_stdin() {
+        let args = stringvec!["-x", "c", "-c", "-", "-o", "foo.o"];
+        let parsed_args = match parse_arguments_(args, false) {
+            CompilerArguments::Ok(args) => args,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert!(parsed_args.reads_stdin());
+        assert_eq!(Language::C, parsed_args.language);
+        assert!(parsed_args.too_hard_for_preprocessor_cache_mode.is_some());
+        assert_map_contains!(
+            parsed_args.outputs,
+            (
+                "obj",
+                ArtifactDescriptor {
+                    path: "foo.o".into(),
+                    optional: false
+                }
+            )
+        );
+
+        // The language is only known from -x, and the object would be named
+        // after the input.
+        assert_eq!(
+            CompilerArguments::CannotCache("unknown source language", None),
+            parse_arguments_(stringvec!["-c", "-", "-o", "foo.o"], false)
+        );
+        assert_eq!(
+            CompilerArguments::CannotCache("- without -o", None),
+            parse_arguments_(stringvec!["-x", "c", "-c", "-"], false)
+        );
+        assert_eq!(
+            CompilerArguments::CannotCache("multiple input files", Some("[\"-\"]".to_string())),
+            parse_arguments_(
+                stringvec!["-x", "c", "-c", "foo.c", "-", "-o", "foo.o"],
+                false
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_arguments_stdout() {
+        let args = stringvec!["-x", "c", "-c", "-", "-o", "-"];
+        let parsed_args = match parse_arguments_clang(args, false) {
+            CompilerArguments::Ok(args) => args,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert!(parsed_args.reads_stdin());
+        assert!(parsed_args.output_to_stdout);
+        assert!(parsed_args.outputs.is_empty());
+        assert_eq!("stdout", parsed_args.output_pretty());
+
+        // The other outputs would be named after the object.
+        assert_eq!(
+            CompilerArguments::CannotCache("-o -", None),
+            parse_arguments_(stringvec!["-gsplit-dwarf", "-c", "foo.c", "-o", "-"], false)
+        );
+        assert_eq!(
+            CompilerArguments::CannotCache("-o -", None),
+            parse_arguments_(stringvec!["-MD", "-c", "foo.c", "-o", "-"], false)
+        );
+    }
+
     #[test]
     fn test_parse_arguments_split_dwarf() {
         let args = stringvec!["-gsplit-dwarf", "-c", "foo.cpp", "-o", "foo.o"];
@@ -1225,6 +1680,195 @@ mod test {
         assert!(profile_generate);
     }
 
+    #[test]
+    fn test_parse_arguments_extra_outputs() {
+        let args = stringvec![
+            "-fstack-usage",
+            "-ftest-coverage",
+            "-c",
+            "foo.cpp",
+            "-o",
+            "out/foo.o"
+        ];
+        let ParsedArguments {
+            outputs,
+            common_args,
+            ..
+        } = match parse_arguments_(args, false) {
+            CompilerArguments::Ok(args) => args,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(outputs.len(), 3);
+        assert_map_contains!(
+            outputs,
+            (
+                "obj",
+                ArtifactDescriptor {
+                    path: "out/foo.o".into(),
+                    optional: false
+                }
+            ),
+            (
+                "su",
+                ArtifactDescriptor {
+                    path: "out/foo.su".into(),
+                    optional: false
+                }
+            ),
+            (
+                "gcno",
+                ArtifactDescriptor {
+                    path: "out/foo.gcno".into(),
+                    optional: false
+                }
+            )
+        );
+        // The flags are hashed, so that the entries have the outputs asked.
+        assert_eq!(ovec!["-fstack-usage", "-ftest-coverage"], common_args);
+        assert_eq!(
+            CompilerArguments::CannotCache("-o -", None),
+            parse_arguments_(stringvec!["-fstack-usage", "-c", "foo.c", "-o", "-"], false)
+        );
+    }
+
+    #[test]
+    fn test_parse_arguments_plugins() {
+        let f = TestFixture::new();
+        let plugins = [f.touch("a.so").unwrap(), f.touch("b.so").unwrap()];
+        let args = stringvec![
+            "-c",
+            "foo.c",
+            format!("-fplugin={}", plugins[0].display()),
+            format!("-fplugin={}", plugins[1].display())
+        ];
+        let ParsedArguments {
+            common_args,
+            extra_hash_files,
+            ..
+        } = match parse_arguments_(args, false) {
+            CompilerArguments::Ok(args) => args,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(plugins.to_vec(), extra_hash_files);
+        assert_eq!(common_args.len(), 2);
+
+        // GCC looks up the short names in its plugin directory.
+        assert_eq!(
+            CompilerArguments::CannotCache("-fplugin", Some("annobin".to_owned())),
+            parse_arguments_(stringvec!["-c", "foo.c", "-fplugin=annobin"], false)
+        );
+        assert_eq!(
+            CompilerArguments::CannotCache("-fplugin=libcc1plugin", None),
+            parse_arguments_(stringvec!["-c", "foo.c", "-fplugin=libcc1plugin"], false)
+        );
+    }
+
+    #[test]
+    fn test_split_inputs() {
+        let split = |args: &[&str]| {
+            let args = args.iter().map(OsString::from).collect::<Vec<_>>();
+            split_inputs(&args, &ARGS[..])
+        };
+        assert_eq!(
+            Some(vec![
+                ovec!["-c", "a.c", "-Iinclude", "-Wall"],
+                ovec!["-c", "-Iinclude", "b.cpp", "-Wall"],
+            ]),
+            split(&["-c", "a.c", "-Iinclude", "b.cpp", "-Wall"])
+        );
+        assert_eq!(
+            Some(vec![
+                ovec!["-x", "c", "-c", "a"],
+                ovec!["-x", "c", "-c", "b"]
+            ]),
+            split(&["-x", "c", "-c", "a", "b"])
+        );
+        // Not compilations of several source files.
+        assert_eq!(None, split(&["-c", "a.c"]));
+        assert_eq!(None, split(&["a.c", "b.c"]));
+        // These can't be split.
+        assert_eq!(None, split(&["-c", "a.c", "b.c", "-o", "a.o"]));
+        assert_eq!(None, split(&["-c", "-MD", "-MF", "deps.d", "a.c", "b.c"]));
+        assert_eq!(None, split(&["-c", "-MD", "-MT", "target", "a.c", "b.c"]));
+        assert_eq!(None, split(&["-c", "a.c", "-"]));
+        assert_eq!(None, split(&["-c", "a.c", "-x", "c++", "b.c"]));
+        assert_eq!(None, split(&["-c", "a.c", "@args"]));
+        assert_eq!(None, split(&["-c", "-unknown", "value", "a.c"]));
+    }
+
+    #[test]
+    fn test_parse_arguments_xpreprocessor() {
+        let parse = |extra: &[&str]| {
+            let mut args = stringvec!["-c", "foo.c", "-o", "foo.o"];
+            args.extend(extra.iter().map(|arg| arg.to_string()));
+            match parse_arguments_(args, false) {
+                CompilerArguments::Ok(args) => args,
+                o => panic!("Got unexpected parse result: {:?}", o),
+            }
+        };
+        let direct = parse(&["-DFOO=1", "-UBAR", "-Iinclude", "-include", "config.h"]);
+        for forms in [
+            // Comma-packed.
+            &["-Wp,-DFOO=1,-UBAR", "-Wp,-Iinclude,-include,config.h"][..],
+            &["-Wp,-D,FOO=1,-U,BAR,-I,include", "-Wp,-include,config.h"],
+            // Space-separated.
+            &[
+                "-Xpreprocessor",
+                "-DFOO=1",
+                "-Xpreprocessor",
+                "-U",
+                "-Xpreprocessor",
+                "BAR",
+                "-Xpreprocessor",
+                "-Iinclude",
+                "-Xpreprocessor",
+                "-include",
+                "-Xpreprocessor",
+                "config.h",
+            ],
+        ] {
+            let parsed = parse(forms);
+            assert_eq!(direct.common_args, parsed.common_args, "{:?}", forms);
+            assert_eq!(
+                direct.preprocessor_args, parsed.preprocessor_args,
+                "{:?}",
+                forms
+            );
+            assert!(parsed.too_hard_for_preprocessor_cache_mode.is_none());
+        }
+
+        // The other flags are kept as given, and both forms are the same.
+        let parsed = parse(&["-Wp,-fdirectives-only"]);
+        assert_eq!(
+            ovec!["-Xpreprocessor", "-fdirectives-only"],
+            parsed.preprocessor_args
+        );
+        assert_eq!(
+            parsed.preprocessor_args,
+            parse(&["-Xpreprocessor", "-fdirectives-only"]).preprocessor_args
+        );
+        assert!(parsed.too_hard_for_preprocessor_cache_mode.is_some());
+
+        // Unlike the flags making the preprocessor write files.
+        for forms in [
+            &["-Wp,-MD,foo.d"][..],
+            &["-Xpreprocessor", "-MMD", "-Xpreprocessor", "foo.d"],
+            &["-Wp,-o,foo.i"],
+            &["-Wp,bar.c"],
+        ] {
+            let mut args = stringvec!["-c", "foo.c", "-o", "foo.o"];
+            args.extend(forms.iter().map(|arg| arg.to_string()));
+            assert!(
+                matches!(
+                    parse_arguments_(args, false),
+                    CompilerArguments::CannotCache("-Xpreprocessor", _)
+                ),
+                "{:?}",
+                forms
+            );
+        }
+    }
+
     #[test]
     fn test_parse_arguments_profile_generate() {
         let args = stringvec!["-fprofile-generate", "-c", "foo.cpp", "-o", "foo.o"];
@@ -1490,6 +2134,13 @@ mod test {
                     path: "foo.o".into(),
                     optional: false
                 }
+            ),
+            (
+                "d",
+                ArtifactDescriptor {
+                    path: "file".into(),
+                    optional: false
+                }
             )
         );
         assert_eq!(
@@ -1529,6 +2180,13 @@ mod test {
                     path: "foo.o".into(),
                     optional: false
                 }
+            ),
+            (
+                "d",
+                ArtifactDescriptor {
+                    path: "file".into(),
+                    optional: false
+                }
             )
         );
         assert_eq!(
@@ -1763,6 +2421,13 @@ mod test {
                     path: "foo.o".into(),
                     optional: false
                 }
+            ),
+            (
+                "d",
+                ArtifactDescriptor {
+                    path: "file".into(),
+                    optional: false
+                }
             )
         );
         assert_eq!(ovec!["-MF", "file", "-MD", "-MT", "foo.o"], dependency_args);
@@ -1795,6 +2460,13 @@ mod test {
                     path: PathBuf::from("foo/bar.o"),
                     optional: false
                 }
+            ),
+            (
+                "d",
+                ArtifactDescriptor {
+                    path: "foo/bar.d".into(),
+                    optional: false
+                }
             )
         );
         assert_eq!(
@@ -2002,6 +2674,35 @@ mod test {
         assert!(!msvc_show_includes);
     }
 
+    #[test]
+    fn at_signs_recursive() {
+        let td = tempfile::Builder::new()
+            .prefix("sccache")
+            .tempdir()
+            .unwrap();
+        let file = td.path().join("self");
+        let arg = format!("@{}", file.display());
+        fs::write(&file, format!("-c foo.c {}", arg)).unwrap();
+        match parse_arguments_(vec![arg.clone()], false) {
+            CompilerArguments::CannotCache("@ expansion", Some(error)) => {
+                assert!(error.contains("nested more than 16 levels"), "{}", error)
+            }
+            o => panic!("Got unexpected parse result: {:?}", o),
+        }
+
+        // The arguments are counted across all the files.
+        fs::write(&file, "-c foo.c -o foo.o").unwrap();
+        let args = vec![OsString::from(&arg), OsString::from(&arg)];
+        let mut it = ExpandIncludeFile::new(td.path(), &args);
+        it.limits.max_args = 8;
+        assert_eq!(it.by_ref().count(), 8);
+        assert_eq!(it.error(), None);
+        let mut it = ExpandIncludeFile::new(td.path(), &args);
+        it.limits.max_args = 7;
+        assert_eq!(it.by_ref().count(), 4);
+        assert!(it.error().unwrap().contains("more than 7 arguments"));
+    }
+
     #[test]
     fn test_compile_simple() {
         let creator = new_creator();
@@ -2032,6 +2733,9 @@ mod test {
             color_mode: ColorMode::Auto,
             suppress_rewrite_includes_only: false,
             too_hard_for_preprocessor_cache_mode: None,
+            stdin: None,
+            output_to_stdout: false,
+            sysroot: None,
         };
         let compiler = &f.bins[0];
         // Compiler invocation.
@@ -2196,7 +2900,7 @@ mod test {
         };
         assert!(parsed_args.too_hard_for_preprocessor_cache_mode.is_none());
 
-        let args = stringvec!["-c", "foo.c", "-o", "foo.o", "-Xpreprocessor", "-M"];
+        let args = stringvec!["-c", "foo.c", "-o", "foo.o", "-Xpreprocessor", "-C"];
         let parsed_args = match parse_arguments_(args, false) {
             CompilerArguments::Ok(args) => args,
             o => panic!("Got unexpected parse result: {:?}", o),
@@ -2206,14 +2910,12 @@ mod test {
             Some("-Xpreprocessor".into())
         );
 
+        // The macros given to the preprocessor are as good as given directly.
         let args = stringvec!["-c", "foo.c", "-o", "foo.o", r#"-Wp,-DFOO="something""#];
         let parsed_args = match parse_arguments_(args, false) {
             CompilerArguments::Ok(args) => args,
             o => panic!("Got unexpected parse result: {:?}", o),
         };
-        assert_eq!(
-            parsed_args.too_hard_for_preprocessor_cache_mode,
-            Some("-Wp".into())
-        );
+        assert!(parsed_args.too_hard_for_preprocessor_cache_mode.is_none());
     }
 }
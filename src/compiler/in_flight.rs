@@ -0,0 +1,300 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Coalescing of identical compilations running at the same time.
+//!
+//! A compilation joins the flight of its cache key before looking it up in
+//! the cache, and leaves it once it's done, including storing its result in
+//! the cache. Only one compilation per key is in flight at a time, so the
+//! others wait and then find its result in the cache. If it didn't store a
+//! result, e.g. because the compilation failed or wasn't cacheable, or the
+//! write failed, all the others look the key up and compile it themselves,
+//! at once rather than one after the other. The compilations don't join
+//! flights when the cache is read-only, as none would store a result.
+//!
+//! The compilation in flight may also share its entry while it's being
+//! stored, and the others read it then rather than wait for the write to the
+//! cache. The entry is only shared once it is complete, as reading it needs
+//! its index at the end, so the write failing doesn't affect them.
+//!
+//! The flights are those of a server, which its compilations join: see
+//! `scope`.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{watch, Mutex as AsyncMutex, OwnedMutexGuard};
+
+tokio::task_local! {
+    static FLIGHTS: Arc<Flights>;
+}
+
+/// What the compilation in flight left to the others waiting for it.
+#[derive(Clone, Default)]
+enum Landing {
+    /// Nothing yet, it's still in flight.
+    #[default]
+    InFlight,
+    /// The entry it is storing, once it is shared.
+    Shared(Arc<[u8]>),
+    /// Nothing: it finished without storing a result, so the others compile
+    /// the key themselves.
+    Abandoned,
+}
+
+/// The flight of a cache key.
+struct FlightState {
+    /// Held by the compilation in flight.
+    lock: Arc<AsyncMutex<()>>,
+    landing: watch::Sender<Landing>,
+}
+
+impl Default for FlightState {
+    fn default() -> FlightState {
+        FlightState {
+            lock: Arc::default(),
+            landing: watch::channel(Landing::InFlight).0,
+        }
+    }
+}
+
+/// The flights of the compilations of a server, by cache key.
+#[derive(Default)]
+pub struct Flights(Mutex<HashMap<String, Arc<FlightState>>>);
+
+/// Run `task`, whose compilations join the flights of `flights`.
+pub async fn scope<F: Future>(flights: Arc<Flights>, task: F) -> F::Output {
+    FLIGHTS.scope(flights, task).await
+}
+
+/// A compilation in flight for a cache key. Other compilations of the key
+/// wait until it is dropped, or until it shares its entry.
+pub struct Flight {
+    flights: Arc<Flights>,
+    key: String,
+    state: Arc<FlightState>,
+    guard: Option<OwnedMutexGuard<()>>,
+    pending: Option<Arc<[u8]>>,
+    /// Whether the compilation in flight stored its result.
+    stored: bool,
+}
+
+impl Flight {
+    /// Join the flight of `key` among the flights of the current task, if it
+    /// runs in the scope of a server, see `Flights::join`.
+    pub async fn join(key: &str) -> Option<Flight> {
+        let flights = FLIGHTS.try_with(Clone::clone).ok()?;
+        Some(flights.join(key).await)
+    }
+
+    /// Share `entry`, the complete cache entry of the key being stored, with
+    /// the compilations waiting for this one.
+    pub fn share(&self, entry: Arc<[u8]>) {
+        if self.guard.is_some() {
+            self.state.landing.send_replace(Landing::Shared(entry));
+        }
+    }
+
+    /// Tell that the result of this compilation was stored, so that the
+    /// others find it in the cache rather than compile the key themselves.
+    pub fn stored(&mut self) {
+        self.stored = true;
+    }
+
+    /// Take the entry shared by the compilation in flight, if this one got it
+    /// instead of waiting for the compilation to finish.
+    pub fn take_pending(&mut self) -> Option<Arc<[u8]>> {
+        self.pending.take()
+    }
+}
+
+impl Flights {
+    /// Join the flight of `key`, waiting for the compilation in flight for
+    /// it, if any, to finish or to share its entry.
+    pub async fn join(self: &Arc<Self>, key: &str) -> Flight {
+        let state = self
+            .0
+            .lock()
+            .unwrap()
+            .entry(key.to_owned())
+            .or_default()
+            .clone();
+        // Leave the flight even if this is dropped while waiting.
+        let mut flight = Flight {
+            flights: self.clone(),
+            key: key.to_owned(),
+            state,
+            guard: None,
+            pending: None,
+            stored: false,
+        };
+        let guard = match flight.state.lock.clone().try_lock_owned() {
+            Ok(guard) => guard,
+            Err(_) => {
+                debug!("Waiting for an identical compilation of {}", key);
+                let mut landing = flight.state.landing.subscribe();
+                tokio::select! {
+                    biased;
+                    Ok(landing) = landing.wait_for(|l| !matches!(l, Landing::InFlight)) => {
+                        match &*landing {
+                            Landing::Shared(entry) => {
+                                debug!("Reading the entry of an identical compilation of {}", key);
+                                flight.pending = Some(entry.clone());
+                            }
+                            _ => debug!("An identical compilation of {} stored nothing", key),
+                        }
+                        return flight;
+                    }
+                    guard = flight.state.lock.clone().lock_owned() => guard,
+                }
+            }
+        };
+        flight.guard = Some(guard);
+        flight
+    }
+}
+
+impl Drop for Flight {
+    fn drop(&mut self) {
+        let mut flights = self.flights.0.lock().unwrap();
+        let current = flights
+            .get(&self.key)
+            .is_some_and(|state| Arc::ptr_eq(state, &self.state));
+        if self.guard.take().is_some() && !self.stored {
+            // Release all the compilations waiting, and let the next ones
+            // start a new flight.
+            self.state.landing.send_replace(Landing::Abandoned);
+            if current {
+                flights.remove(&self.key);
+            }
+            return;
+        }
+        // The map holds the other reference, unless other compilations joined
+        // the flight.
+        if current && Arc::strong_count(&self.state) == 2 {
+            flights.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::FutureExt;
+
+    fn in_flight(flights: &Flights, key: &str) -> bool {
+        flights.0.lock().unwrap().contains_key(key)
+    }
+
+    #[test]
+    fn test_flight() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let flights = Arc::new(Flights::default());
+        runtime.block_on(async {
+            let mut first = flights.join("test_flight").await;
+            // Other keys don't wait.
+            let other = flights.join("test_flight_other").await;
+            drop(other);
+            assert!(!in_flight(&flights, "test_flight_other"));
+            // Nor do the compilations of other servers, and those outside of
+            // any server don't join flights.
+            drop(Arc::new(Flights::default()).join("test_flight").await);
+            assert!(Flight::join("test_flight").await.is_none());
+            let joined = scope(flights.clone(), Flight::join("test_flight"));
+            assert!(joined.now_or_never().is_none());
+
+            let mut second = Box::pin(flights.join("test_flight"));
+            assert!((&mut second).now_or_never().is_none());
+            first.stored();
+            drop(first);
+            assert!(in_flight(&flights, "test_flight"));
+            let second = second.await;
+            drop(second);
+            assert!(!in_flight(&flights, "test_flight"));
+        });
+    }
+
+    #[test]
+    fn test_flight_abandoned() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let flights = Arc::new(Flights::default());
+        runtime.block_on(async {
+            let first = flights.join("test_flight_abandoned").await;
+            // A compilation giving up on waiting leaves the flight.
+            let waiting = flights.join("test_flight_abandoned").now_or_never();
+            assert!(waiting.is_none());
+            drop(first);
+            assert!(!in_flight(&flights, "test_flight_abandoned"));
+        });
+    }
+
+    #[test]
+    fn test_flight_stored_nothing() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let flights = Arc::new(Flights::default());
+        runtime.block_on(async {
+            let first = flights.join("test_flight_nothing").await;
+            let mut second = Box::pin(flights.join("test_flight_nothing"));
+            let mut third = Box::pin(flights.join("test_flight_nothing"));
+            assert!((&mut second).now_or_never().is_none());
+            assert!((&mut third).now_or_never().is_none());
+            // Without a result stored, the waiting compilations all go ahead
+            // at once, none of them waiting for another.
+            drop(first);
+            assert!(!in_flight(&flights, "test_flight_nothing"));
+            let (mut second, mut third) = futures::join!(second, third);
+            assert_eq!(second.take_pending(), None);
+            assert_eq!(third.take_pending(), None);
+            // The next ones start a new flight.
+            let fourth = flights.join("test_flight_nothing").await;
+            let mut fifth = Box::pin(flights.join("test_flight_nothing"));
+            assert!((&mut fifth).now_or_never().is_none());
+            drop((second, third, fourth));
+        });
+    }
+
+    #[test]
+    fn test_flight_shared_entry() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let flights = Arc::new(Flights::default());
+        runtime.block_on(async {
+            let first = flights.join("test_flight_shared").await;
+            let mut second = Box::pin(flights.join("test_flight_shared"));
+            assert!((&mut second).now_or_never().is_none());
+            // The waiting compilation gets the entry while the first one is
+            // still in flight.
+            first.share(Arc::from(&b"entry"[..]));
+            let mut second = second.await;
+            assert_eq!(second.take_pending().as_deref(), Some(&b"entry"[..]));
+            // And so do the ones joining later.
+            let mut third = flights.join("test_flight_shared").await;
+            assert_eq!(third.take_pending().as_deref(), Some(&b"entry"[..]));
+            // They don't share entries themselves.
+            third.share(Arc::from(&b"other"[..]));
+            drop((first, second, third));
+            assert!(!in_flight(&flights, "test_flight_shared"));
+
+            // A new flight starts over.
+            let mut fourth = flights.join("test_flight_shared").await;
+            assert_eq!(fourth.take_pending(), None);
+        });
+    }
+}
@@ -14,21 +14,32 @@
 
 #[macro_use]
 mod args;
+mod basedir;
 mod c;
 mod clang;
+mod comments;
 #[macro_use]
 #[allow(clippy::module_inception)]
 mod compiler;
 mod diab;
+mod forward_env;
 mod gcc;
+pub mod in_flight;
 mod msvc;
+mod nondeterministic;
 mod nvcc;
 mod nvhpc;
 mod preprocessor_cache;
+mod probe_cache;
+mod remap;
 mod rust;
 mod tasking_vx;
+mod wrapper;
 #[macro_use]
 mod counted_array;
 
 pub use crate::compiler::compiler::*;
+pub use crate::compiler::forward_env::forwarded_env;
 pub use crate::compiler::preprocessor_cache::PreprocessorCacheEntry;
+pub use crate::compiler::remap::{CompilerPathMap, COMPILER_DIGEST_ENV_VAR};
+pub use crate::compiler::wrapper::CompilerWrapper;
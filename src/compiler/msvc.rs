@@ -641,14 +641,22 @@ pub fn parse_arguments(
         [xclangs, clangs].iter(),
         &[xclang_append, dash_clang_append],
     ) {
-        let it = gcc::ExpandIncludeFile::new(cwd, args);
-        for arg in ArgsIter::new(it, (&gcc::ARGS[..], &clang::ARGS[..])) {
+        let mut it = gcc::ExpandIncludeFile::new(cwd, args);
+        for arg in ArgsIter::new(&mut it, (&gcc::ARGS[..], &clang::ARGS[..])) {
             let arg = try_or_cannot_cache!(arg, "argument parse");
             // Eagerly bail if it looks like we need to do more complicated work
             use crate::compiler::gcc::ArgData::*;
             let args = match arg.get_data() {
-                Some(SplitDwarf) | Some(TestCoverage) | Some(Coverage) | Some(DoCompilation)
-                | Some(Language(_)) | Some(Output(_)) | Some(TooHardFlag) | Some(XClang(_))
+                Some(ExtraOutput)
+                | Some(TestCoverage)
+                | Some(Coverage)
+                | Some(DoCompilation)
+                | Some(Language(_))
+                | Some(Output(_))
+                | Some(TooHardFlag)
+                | Some(XClang(_))
+                | Some(XPreprocessor(_))
+                | Some(Stdin)
                 | Some(TooHard(_)) => cannot_cache!(arg
                     .flag_str()
                     .unwrap_or("Can't handle complex arguments through clang",)),
@@ -663,6 +671,7 @@ pub fn parse_arguments(
                 | Some(PassThroughFlag)
                 | Some(PassThrough(_))
                 | Some(PassThroughPath(_))
+                | Some(Sysroot(_))
                 | Some(PedanticFlag)
                 | Some(Standard(_)) => &mut common_args,
                 Some(Unhashed(_)) => &mut unhashed_args,
@@ -681,6 +690,16 @@ pub fn parse_arguments(
                     extra_hash_files.push(cwd.join(path));
                     &mut common_args
                 }
+                Some(Plugin(path)) => {
+                    match gcc::resolve_plugin(path, cwd) {
+                        Some(path) => extra_hash_files.push(path),
+                        None => cannot_cache!(
+                            arg.flag_str().expect("Plugin flag expected"),
+                            path.display().to_string()
+                        ),
+                    }
+                    &mut common_args
+                }
                 Some(PreprocessorArgumentFlag)
                 | Some(PreprocessorArgument(_))
                 | Some(PreprocessorArgumentPath(_)) => &mut preprocessor_args,
@@ -699,6 +718,9 @@ pub fn parse_arguments(
                 append_fn(arg, args);
             }
         }
+        if let Some(error) = it.error() {
+            cannot_cache!("@ expansion", error.to_owned());
+        }
     }
 
     // We only support compilation.
@@ -714,8 +736,11 @@ pub fn parse_arguments(
     }
     let (input, language) = match input_arg {
         Some(i) => match Language::from_file_name(Path::new(&i)) {
+            // Only the compilers like gcc assemble the sources here.
+            Some(Language::Assembler | Language::AssemblerToPreprocess) | None => {
+                cannot_cache!("unknown source language")
+            }
             Some(l) => (i.to_owned(), l),
-            None => cannot_cache!("unknown source language"),
         },
         // We can't cache compilation without an input.
         None => cannot_cache!("no input file"),
@@ -824,6 +849,9 @@ pub fn parse_arguments(
         color_mode: ColorMode::Auto,
         suppress_rewrite_includes_only: false,
         too_hard_for_preprocessor_cache_mode: None,
+        stdin: None,
+        output_to_stdout: false,
+        sysroot: None,
     })
 }
 
@@ -1064,6 +1092,7 @@ fn generate_compile_commands(
         arguments,
         env_vars: env_vars.to_owned(),
         cwd: cwd.to_owned(),
+        stdin: None,
     };
 
     #[cfg(not(feature = "dist-client"))]
@@ -2446,6 +2475,9 @@ mod test {
             color_mode: ColorMode::Auto,
             suppress_rewrite_includes_only: false,
             too_hard_for_preprocessor_cache_mode: None,
+            stdin: None,
+            output_to_stdout: false,
+            sysroot: None,
         };
         let compiler = &f.bins[0];
         // Compiler invocation.
@@ -2531,6 +2563,9 @@ mod test {
             color_mode: ColorMode::Auto,
             suppress_rewrite_includes_only: false,
             too_hard_for_preprocessor_cache_mode: None,
+            stdin: None,
+            output_to_stdout: false,
+            sysroot: None,
         };
         let compiler = &f.bins[0];
         // Compiler invocation.
@@ -0,0 +1,233 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The C-like compiler flags which make the outputs of the compilations, or
+//! their hash keys, depend on the machine, hurting the hits across machines.
+//!
+//! The flags of `SCCACHE_NONDETERMINISTIC_FLAGS`, `-grecord-gcc-switches`
+//! and `-frecord-gcc-switches` by default, record the command line in the
+//! object, with the paths of the machine it was compiled on. The compilations
+//! using them are cached as usual, unless `SCCACHE_NONDETERMINISTIC=skip`.
+//!
+//! The prefix maps, e.g. `-ffile-prefix-map=/home/a/proj=.`, make the outputs
+//! the same across checkouts, but their absolute paths are in the hash keys.
+//! They are normalized under `SCCACHE_BASEDIR`, like the other paths.
+//!
+//! With `SCCACHE_WARN_NONDETERMINISTIC=1`, the server warns once about each
+//! of those flags.
+
+use crate::compiler::basedir::BaseDir;
+use crate::compiler::Language;
+use once_cell::sync::Lazy;
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// The variable of the client environment listing the flags.
+pub const FLAGS_ENV_VAR: &str = "SCCACHE_NONDETERMINISTIC_FLAGS";
+/// The variable of the client environment set to `skip` for the compilations
+/// using them not to be cached.
+pub const MODE_ENV_VAR: &str = "SCCACHE_NONDETERMINISTIC";
+/// The variable of the client environment set to `1` to warn about them.
+pub const WARN_ENV_VAR: &str = "SCCACHE_WARN_NONDETERMINISTIC";
+
+const DEFAULT_FLAGS: &[&str] = &["-grecord-gcc-switches", "-frecord-gcc-switches"];
+
+/// The options mapping path prefixes in the outputs.
+const PREFIX_MAPS: &[&str] = &[
+    "-fdebug-prefix-map=",
+    "-ffile-prefix-map=",
+    "-fmacro-prefix-map=",
+    "-fprofile-prefix-map=",
+];
+
+/// The flags already warned about.
+static WARNED: Lazy<Mutex<HashSet<String>>> = Lazy::new(Default::default);
+
+fn warn_once(flag: &str, why: &str) {
+    if WARNED.lock().unwrap().insert(flag.to_owned()) {
+        warn!("{}: {}", flag, why);
+    }
+}
+
+/// Split `arg` into a prefix map option, e.g. `-ffile-prefix-map=`, and the
+/// old and new prefixes it maps, if it is one.
+pub fn split_prefix_map(arg: &str) -> Option<(&str, &str, &str)> {
+    let option = PREFIX_MAPS.iter().find(|option| arg.starts_with(*option))?;
+    let (old, new) = arg[option.len()..].split_once('=')?;
+    Some((&arg[..option.len()], old, new))
+}
+
+/// Check `arguments`, those of a compilation of `language` with `env_vars`,
+/// for the flags making it nondeterministic. Returns the flag the
+/// compilation isn't cached because of, if any.
+pub fn check(
+    arguments: &[OsString],
+    env_vars: &[(OsString, OsString)],
+    language: Language,
+) -> Option<String> {
+    if language == Language::Rust {
+        return None;
+    }
+    let var = |name: &str| {
+        env_vars
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.to_string_lossy())
+    };
+    let warn = var(WARN_ENV_VAR).as_deref() == Some("1");
+    let skip = match var(MODE_ENV_VAR).as_deref() {
+        None | Some("cache") => false,
+        Some("skip") => true,
+        Some(mode) => {
+            warn_once(
+                MODE_ENV_VAR,
+                &format!("ignoring {:?}, expected cache or skip", mode),
+            );
+            false
+        }
+    };
+    if !warn && !skip {
+        return None;
+    }
+    let flags = var(FLAGS_ENV_VAR);
+    let flags: Vec<&str> = match &flags {
+        Some(flags) => flags
+            .split(',')
+            .map(str::trim)
+            .filter(|flag| !flag.is_empty())
+            .collect(),
+        None => DEFAULT_FLAGS.to_vec(),
+    };
+    let base_dir = BaseDir::from_env(env_vars, language);
+    for arg in arguments.iter().filter_map(|arg| arg.to_str()) {
+        let is_flag = |flag: &&str| {
+            arg.strip_prefix(*flag)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('='))
+        };
+        if let Some(flag) = flags.iter().copied().find(is_flag) {
+            if warn {
+                let consequence = if skip {
+                    "not caching the compilations using it"
+                } else {
+                    "set SCCACHE_NONDETERMINISTIC=skip not to cache them"
+                };
+                warn_once(
+                    flag,
+                    &format!(
+                        "the outputs of the compilations depend on their command lines, \
+                         and the paths of the machines compiling them; {}",
+                        consequence
+                    ),
+                );
+            }
+            if skip {
+                return Some(flag.to_string());
+            }
+        }
+        if !warn {
+            continue;
+        }
+        let Some((option, old, _)) = split_prefix_map(arg) else {
+            continue;
+        };
+        let normalized = base_dir
+            .as_ref()
+            .is_some_and(|base_dir| base_dir.relativize_arg(OsStr::new(old)) != old);
+        if Path::new(old).is_absolute() && !normalized {
+            warn_once(
+                arg,
+                &format!(
+                    "the hash keys of the compilations hold the absolute path {:?} of \
+                     {}; set SCCACHE_BASEDIR to a directory it is under to share them \
+                     across machines",
+                    old,
+                    option.trim_end_matches('=')
+                ),
+            );
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::basedir::BASEDIR_ENV_VAR;
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(OsString, OsString)> {
+        vars.iter().map(|(k, v)| (k.into(), v.into())).collect()
+    }
+
+    #[test]
+    fn test_check() {
+        let recording = args(&["-c", "foo.c", "-g", "-grecord-gcc-switches"]);
+        // Cached as usual by default.
+        assert_eq!(check(&recording, &[], Language::C), None);
+        assert_eq!(
+            check(&recording, &env(&[(WARN_ENV_VAR, "1")]), Language::C),
+            None
+        );
+        let skip = env(&[(MODE_ENV_VAR, "skip")]);
+        assert_eq!(
+            check(&recording, &skip, Language::Cxx).as_deref(),
+            Some("-grecord-gcc-switches")
+        );
+        assert_eq!(check(&recording, &skip, Language::Rust), None);
+        assert_eq!(
+            check(&args(&["-c", "foo.c", "-g"]), &skip, Language::C),
+            None
+        );
+
+        // A configured set of flags.
+        let skip = env(&[(MODE_ENV_VAR, "skip"), (FLAGS_ENV_VAR, "-frandom-seed,-pg")]);
+        assert_eq!(check(&recording, &skip, Language::C), None);
+        assert_eq!(
+            check(
+                &args(&["-c", "foo.c", "-frandom-seed=42"]),
+                &skip,
+                Language::C
+            )
+            .as_deref(),
+            Some("-frandom-seed")
+        );
+        assert_eq!(check(&args(&["-pgo"]), &skip, Language::C), None);
+
+        // The prefix maps are only warned about.
+        let maps = args(&["-c", "foo.c", "-ffile-prefix-map=/home/a/proj=."]);
+        let warn = env(&[
+            (WARN_ENV_VAR, "1"),
+            (MODE_ENV_VAR, "skip"),
+            (BASEDIR_ENV_VAR, "/home/a"),
+        ]);
+        assert_eq!(check(&maps, &warn, Language::C), None);
+    }
+
+    #[test]
+    fn test_split_prefix_map() {
+        assert_eq!(
+            split_prefix_map("-ffile-prefix-map=/home/a/proj=."),
+            Some(("-ffile-prefix-map=", "/home/a/proj", "."))
+        );
+        assert_eq!(
+            split_prefix_map("-fdebug-prefix-map=/a=/b=c"),
+            Some(("-fdebug-prefix-map=", "/a", "/b=c"))
+        );
+        assert_eq!(split_prefix_map("-ffile-prefix-map=/a"), None);
+        assert_eq!(split_prefix_map("-I/a=b"), None);
+    }
+}
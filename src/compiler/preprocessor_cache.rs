@@ -37,7 +37,7 @@ use crate::{
     util::{encode_path, Digest, HashToDigest, MetadataCtimeExt, Timestamp},
 };
 
-use super::Language;
+use super::{get_cache_salt, host_target, Language};
 
 /// The current format is 1 header byte for the version + bincode encoding
 /// of the [`PreprocessorCacheEntry`] struct.
@@ -353,13 +353,16 @@ impl PreprocessorCacheEntry {
 }
 
 /// Environment variables that are factored into the preprocessor cache entry cached key.
-static CACHED_ENV_VARS: Lazy<HashSet<&'static OsStr>> = Lazy::new(|| {
+pub(crate) static CACHED_ENV_VARS: Lazy<HashSet<&'static OsStr>> = Lazy::new(|| {
     [
         // SCCACHE_C_CUSTOM_CACHE_BUSTER has no particular meaning behind it,
         // serving as a way for the user to factor custom data into the hash.
         // One can set it to different values for different invocations
         // to prevent cache reuse between them.
         "SCCACHE_C_CUSTOM_CACHE_BUSTER",
+        // Must match the main hash key, otherwise a preprocessor cache hit
+        // could point to a result compiled with a different epoch.
+        "SOURCE_DATE_EPOCH",
         "CPATH",
         "C_INCLUDE_PATH",
         "CPLUS_INCLUDE_PATH",
@@ -390,6 +393,10 @@ pub fn preprocessor_cache_entry_hash_key(
     // we have to incorporate that into the hash as well.
     m.update(&[plusplus as u8]);
     m.update(&[FORMAT_VERSION]);
+    if let Some(salt) = get_cache_salt() {
+        m.update(salt.as_bytes());
+    }
+    m.update(host_target().as_bytes());
     m.update(language.as_str().as_bytes());
     for arg in arguments {
         arg.hash(&mut HashToDigest { digest: &mut m });
@@ -635,4 +642,32 @@ mod test {
         assert!(!finder.found_timestamp());
         assert!(!finder.found_date());
     }
+
+    #[test]
+    fn test_preprocessor_cache_entry_hash_key_source_date_epoch() {
+        let tempdir = tempfile::Builder::new()
+            .prefix("sccache_test")
+            .tempdir()
+            .unwrap();
+        let input = tempdir.path().join("foo.c");
+        std::fs::write(&input, b"int main() { return 0; }").unwrap();
+        let key = |env_vars: &[(OsString, OsString)]| {
+            preprocessor_cache_entry_hash_key(
+                "abcd",
+                Language::C,
+                &[],
+                &[],
+                env_vars,
+                &input,
+                false,
+                PreprocessorCacheModeConfig::activated(),
+            )
+            .unwrap()
+            .unwrap()
+        };
+        let epoch = |v: &str| vec![(OsString::from("SOURCE_DATE_EPOCH"), OsString::from(v))];
+        assert_ne!(key(&[]), key(&epoch("1700000000")));
+        assert_ne!(key(&epoch("1700000000")), key(&epoch("1700000001")));
+        assert_eq!(key(&epoch("1700000000")), key(&epoch("1700000000")));
+    }
 }
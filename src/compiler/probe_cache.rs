@@ -0,0 +1,259 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An on-disk cache of what detecting C compilers found out by running them,
+//! so that a restarted server doesn't run them again. The results of a
+//! compiler are kept as long as its binary has the same modification time
+//! and size, and it is run with the same arguments and language settings.
+
+use crate::config;
+use filetime::FileTime;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::errors::*;
+
+/// The probe cache of the server, which tests don't persist.
+pub static PROBE_CACHE: Lazy<ProbeCache> = Lazy::new(|| {
+    ProbeCache::new(if cfg!(test) {
+        None
+    } else {
+        Some(config::compiler_probe_cache_path())
+    })
+});
+
+/// The environment variables what detecting a compiler finds out depends on:
+/// the language of the `-showIncludes` prefix of MSVC follows them.
+const PROBE_ENV_VARS: &[&str] = &["VSLANG", "LANG", "LC_ALL", "LC_MESSAGES"];
+
+/// The variables of `env` among `PROBE_ENV_VARS`.
+fn probe_env(env: &[(OsString, OsString)]) -> Vec<(OsString, OsString)> {
+    PROBE_ENV_VARS
+        .iter()
+        .filter_map(|&var| env.iter().find(|(k, _)| k == var).cloned())
+        .collect()
+}
+
+/// What detecting a C compiler found out.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompilerProbe {
+    /// The id of the compiler, e.g. `gcc` or `msvc-clang`.
+    pub id: String,
+    pub version: Option<String>,
+    /// The prefix of the `-showIncludes` output of MSVC compilers.
+    pub includes_prefix: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Entry {
+    /// The modification time of the binary, as seconds and nanoseconds.
+    mtime: (i64, u32),
+    size: u64,
+    /// The arguments the compiler was run with during detection.
+    args: Vec<OsString>,
+    /// The environment the compiler was run with during detection, as far
+    /// as the probe depends on it.
+    env: Vec<(OsString, OsString)>,
+    probe: CompilerProbe,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ProbeFile {
+    /// The version of sccache which wrote the entries, since they depend on
+    /// how it detects compilers.
+    version: String,
+    entries: HashMap<PathBuf, Entry>,
+}
+
+/// The cached probes of the compilers by path.
+pub struct ProbeCache {
+    /// Where the probes persist, unless they are not cached.
+    path: Option<PathBuf>,
+    /// The probes, once loaded.
+    entries: Mutex<Option<HashMap<PathBuf, Entry>>>,
+}
+
+impl ProbeCache {
+    pub fn new(path: Option<PathBuf>) -> ProbeCache {
+        ProbeCache {
+            path,
+            entries: Mutex::new(None),
+        }
+    }
+
+    /// Get the probe of `executable` run with `args` and `env`, unless its
+    /// binary changed since.
+    pub fn get(
+        &self,
+        executable: &Path,
+        args: &[OsString],
+        env: &[(OsString, OsString)],
+    ) -> Option<CompilerProbe> {
+        // The server keeps the compilers it detected in memory anyway.
+        self.path.as_ref()?;
+        let (mtime, size) = binary_info(executable)?;
+        let mut entries = self.entries.lock().unwrap();
+        let entries = entries.get_or_insert_with(|| self.load());
+        let entry = entries.get(executable)?;
+        if entry.mtime == mtime
+            && entry.size == size
+            && entry.args == args
+            && entry.env == probe_env(env)
+        {
+            Some(entry.probe.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Remember the probe of `executable` run with `args` and `env`.
+    pub fn insert(
+        &self,
+        executable: &Path,
+        args: &[OsString],
+        env: &[(OsString, OsString)],
+        probe: CompilerProbe,
+    ) {
+        let (Some(_), Some((mtime, size))) = (&self.path, binary_info(executable)) else {
+            return;
+        };
+        let mut entries = self.entries.lock().unwrap();
+        let entries = entries.get_or_insert_with(|| self.load());
+        entries.insert(
+            executable.to_owned(),
+            Entry {
+                mtime,
+                size,
+                args: args.to_vec(),
+                env: probe_env(env),
+                probe,
+            },
+        );
+        // Forget the compilers which are gone.
+        entries.retain(|path, _| path.exists());
+        if let Err(e) = self.save(entries) {
+            warn!("Failed to save the compiler probes: {:?}", e);
+        }
+    }
+
+    fn load(&self) -> HashMap<PathBuf, Entry> {
+        let path = self.path.as_ref().unwrap();
+        let file: ProbeFile = match fs::read(path) {
+            Ok(data) => match serde_json::from_slice(&data) {
+                Ok(file) => file,
+                Err(e) => {
+                    warn!("Ignoring the compiler probes in {}: {}", path.display(), e);
+                    return HashMap::new();
+                }
+            },
+            Err(_) => return HashMap::new(),
+        };
+        if file.version != env!("CARGO_PKG_VERSION") {
+            debug!("Ignoring the compiler probes of sccache {}", file.version);
+            return HashMap::new();
+        }
+        file.entries
+    }
+
+    fn save(&self, entries: &HashMap<PathBuf, Entry>) -> Result<()> {
+        let path = self.path.as_ref().unwrap();
+        let dir = path.parent().context("probe cache path has no parent")?;
+        fs::create_dir_all(dir)?;
+        let file = ProbeFile {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            entries: entries.clone(),
+        };
+        // Other servers may read it meanwhile.
+        let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+        tmp.write_all(&serde_json::to_vec(&file)?)?;
+        tmp.persist(path).map_err(|e| e.error)?;
+        Ok(())
+    }
+}
+
+fn binary_info(executable: &Path) -> Option<((i64, u32), u64)> {
+    let metadata = fs::metadata(executable).ok()?;
+    let mtime = FileTime::from_last_modification_time(&metadata);
+    Some(((mtime.unix_seconds(), mtime.nanoseconds()), metadata.len()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::utils::TestFixture;
+
+    fn probe(version: &str) -> CompilerProbe {
+        CompilerProbe {
+            id: "gcc".into(),
+            version: Some(version.into()),
+            includes_prefix: None,
+        }
+    }
+
+    #[test]
+    fn test_probe_cache() {
+        let f = TestFixture::new();
+        let gcc = f.mk_bin("gcc").unwrap();
+        let path = f.tempdir.path().join("probes").join("compiler-probes.json");
+        let cache = ProbeCache::new(Some(path.clone()));
+        assert_eq!(cache.get(&gcc, &[], &[]), None);
+        cache.insert(&gcc, &[], &[], probe("12"));
+        assert_eq!(cache.get(&gcc, &[], &[]), Some(probe("12")));
+        // Detecting with other arguments may find something else.
+        assert_eq!(cache.get(&gcc, &["-ccbin=gcc".into()], &[]), None);
+        // And so may detecting in another language, but for the variables
+        // the probe doesn't depend on.
+        let env = |var: &str, val: &str| vec![(OsString::from(var), OsString::from(val))];
+        assert_eq!(cache.get(&gcc, &[], &env("VSLANG", "1031")), None);
+        assert_eq!(cache.get(&gcc, &[], &env("LC_ALL", "de_DE")), None);
+        assert_eq!(
+            cache.get(&gcc, &[], &env("PATH", "/bin")),
+            Some(probe("12"))
+        );
+        cache.insert(&gcc, &[], &env("VSLANG", "1031"), probe("12"));
+        assert_eq!(cache.get(&gcc, &[], &[]), None);
+        assert_eq!(
+            cache.get(&gcc, &[], &env("VSLANG", "1031")),
+            Some(probe("12"))
+        );
+        cache.insert(&gcc, &[], &[], probe("12"));
+
+        // The probes survive a restart.
+        let cache = ProbeCache::new(Some(path.clone()));
+        assert_eq!(cache.get(&gcc, &[], &[]), Some(probe("12")));
+
+        // Upgrading the compiler invalidates them.
+        fs::write(&gcc, "a newer gcc").unwrap();
+        filetime::set_file_mtime(&gcc, FileTime::from_unix_time(1, 0)).unwrap();
+        assert_eq!(cache.get(&gcc, &[], &[]), None);
+        let cache = ProbeCache::new(Some(path));
+        assert_eq!(cache.get(&gcc, &[], &[]), None);
+    }
+
+    #[test]
+    fn test_probe_cache_other_version() {
+        let f = TestFixture::new();
+        let gcc = f.mk_bin("gcc").unwrap();
+        let path = f.tempdir.path().join("compiler-probes.json");
+        ProbeCache::new(Some(path.clone())).insert(&gcc, &[], &[], probe("12"));
+        let mut file: ProbeFile = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        file.version = "0.0.0".into();
+        fs::write(&path, serde_json::to_vec(&file).unwrap()).unwrap();
+        assert_eq!(ProbeCache::new(Some(path)).get(&gcc, &[], &[]), None);
+    }
+}
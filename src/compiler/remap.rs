@@ -0,0 +1,140 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The compilers the server runs for those of the clients, from
+//! `SCCACHE_COMPILER_MAP`, for a server which doesn't see the compilers at
+//! the paths of its clients, e.g. in another container.
+//!
+//! `SCCACHE_COMPILER_MAP` is a comma-separated list of
+//! `client_path=server_path`, e.g. `/usr/bin/gcc=/toolchains/gcc/bin/gcc`. A
+//! client path may be a directory, e.g. `/usr/bin=/sysroot/usr/bin`, to map
+//! the compilers under it, and the longest one a compiler is under wins.
+//! Once it is set, it is an allowlist: the compilers it doesn't map are
+//! rejected, and the clients run them themselves, without caching.
+
+use std::env;
+use std::path::{Component, PathBuf};
+
+use crate::errors::*;
+
+/// The variable of the client environment making it send the digest of its
+/// compiler, set to `1`.
+pub const COMPILER_DIGEST_ENV_VAR: &str = "SCCACHE_COMPILER_DIGEST";
+
+/// The compilers of the clients, mapped to those of the server.
+#[derive(Debug, Default)]
+pub struct CompilerPathMap {
+    /// The client paths and the server paths they map to. None maps all the
+    /// compilers to themselves.
+    entries: Vec<(PathBuf, PathBuf)>,
+}
+
+impl CompilerPathMap {
+    /// The map of `SCCACHE_COMPILER_MAP`, if set.
+    pub fn from_env() -> Result<CompilerPathMap> {
+        let Ok(map) = env::var("SCCACHE_COMPILER_MAP") else {
+            return Ok(CompilerPathMap::default());
+        };
+        CompilerPathMap::parse(&map).context("invalid SCCACHE_COMPILER_MAP")
+    }
+
+    /// Parse a list of `client_path=server_path`.
+    pub fn parse(map: &str) -> Result<CompilerPathMap> {
+        let entries = map
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let Some((client, server)) = entry.split_once('=') else {
+                    bail!("{:?} is not a client_path=server_path", entry);
+                };
+                let (client, server) = (PathBuf::from(client), PathBuf::from(server));
+                if !client.is_absolute() || !server.is_absolute() {
+                    bail!("the paths of {:?} must be absolute", entry);
+                }
+                Ok((client, server))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if entries.is_empty() {
+            bail!("no compiler is mapped");
+        }
+        Ok(CompilerPathMap { entries })
+    }
+
+    /// The compiler the server runs for the compiler `exe` of a client.
+    pub fn resolve(&self, exe: PathBuf) -> Result<PathBuf> {
+        if self.entries.is_empty() {
+            return Ok(exe);
+        }
+        self.entries
+            .iter()
+            .filter_map(|(client, server)| {
+                let rest = exe.strip_prefix(client).ok()?;
+                // Nothing out of the directory of `server`.
+                if !rest.components().all(|c| matches!(c, Component::Normal(_))) {
+                    return None;
+                }
+                Some((client.as_os_str().len(), server.join(rest)))
+            })
+            .max_by_key(|(len, _)| *len)
+            .map(|(_, server)| server)
+            .with_context(|| format!("the compiler {:?} is not in SCCACHE_COMPILER_MAP", exe))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::Path;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_compiler_map() {
+        let map = CompilerPathMap::parse(
+            "/usr/bin/gcc=/toolchains/gcc/bin/gcc, /usr/bin=/sysroot/usr/bin",
+        )
+        .unwrap();
+        for (client, server) in [
+            ("/usr/bin/gcc", "/toolchains/gcc/bin/gcc"),
+            ("/usr/bin/clang", "/sysroot/usr/bin/clang"),
+            ("/usr/bin/x86_64/cc", "/sysroot/usr/bin/x86_64/cc"),
+        ] {
+            assert_eq!(
+                map.resolve(client.into()).unwrap(),
+                Path::new(server),
+                "{}",
+                client
+            );
+        }
+        // The compilers it doesn't map are rejected.
+        for client in ["/usr/local/bin/gcc", "/usr/bin/../../etc/passwd", "/usr"] {
+            let err = map.resolve(client.into()).unwrap_err();
+            assert!(
+                err.to_string().contains("is not in SCCACHE_COMPILER_MAP"),
+                "{}",
+                err
+            );
+        }
+
+        // Without a map, the compilers are run as is.
+        assert_eq!(
+            CompilerPathMap::default()
+                .resolve("/usr/local/bin/gcc".into())
+                .unwrap(),
+            Path::new("/usr/local/bin/gcc")
+        );
+
+        assert!(CompilerPathMap::parse("/usr/bin/gcc").is_err());
+        assert!(CompilerPathMap::parse("gcc=/usr/bin/gcc").is_err());
+        assert!(CompilerPathMap::parse(" , ").is_err());
+    }
+}
@@ -12,14 +12,17 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::cache::metadata::EntryMetadata;
 use crate::cache::{FileObjectSource, Storage};
 use crate::compiler::args::*;
 use crate::compiler::{
-    c::ArtifactDescriptor, Cacheable, ColorMode, Compilation, CompileCommand, Compiler,
-    CompilerArguments, CompilerHasher, CompilerKind, CompilerProxy, HashResult, Language,
+    c::ArtifactDescriptor, get_cache_salt, get_extra_files, host_target, Cacheable, ColorMode,
+    Compilation, CompileCommand, Compiler, CompilerArguments, CompilerHasher, CompilerKind,
+    CompilerProxy, CompilerState, HashKeyInput, HashResult, Language,
 };
 #[cfg(feature = "dist-client")]
 use crate::compiler::{DistPackagers, OutputsRewriter};
+use crate::config;
 #[cfg(feature = "dist-client")]
 use crate::dist::pkg;
 #[cfg(feature = "dist-client")]
@@ -47,7 +50,6 @@ use std::ffi::OsString;
 use std::fmt;
 use std::future::Future;
 use std::hash::Hash;
-#[cfg(feature = "dist-client")]
 use std::io;
 use std::io::Read;
 use std::iter;
@@ -113,6 +115,9 @@ pub struct Rust {
     /// A shared, caching reader for rlib dependencies
     #[cfg(feature = "dist-client")]
     rlib_dep_reader: Option<Arc<RlibDepReader>>,
+    /// The `RUSTC_WORKSPACE_WRAPPER` cargo runs rustc through, if it invokes
+    /// sccache as `sccache $RUSTC_WORKSPACE_WRAPPER $RUSTC`.
+    workspace_wrapper: Option<PathBuf>,
 }
 
 /// A struct on which to hang a `CompilerHasher` impl.
@@ -161,6 +166,8 @@ pub struct ParsedArguments {
     dep_info: Option<PathBuf>,
     /// If gcno info is being emitted, the name of the gcno file.
     gcno: Option<PathBuf>,
+    /// If an object file is being emitted, the name of the object file.
+    obj: Option<PathBuf>,
     /// rustc says that emits .rlib for --emit=metadata
     /// https://github.com/rust-lang/rust/issues/54852
     emit: HashSet<String>,
@@ -168,6 +175,8 @@ pub struct ParsedArguments {
     color_mode: ColorMode,
     /// Whether `--json` was passed to this invocation.
     has_json: bool,
+    /// The directory of `-C incremental`, if the incremental state is cached.
+    incremental: Option<PathBuf>,
 }
 
 /// A struct on which to hang a `Compilation` impl.
@@ -200,6 +209,8 @@ pub struct RustCompilation {
     cwd: PathBuf,
     /// The environment variables
     env_vars: Vec<(OsString, OsString)>,
+    /// The directory of `-C incremental`, if the incremental state is cached.
+    incremental: Option<PathBuf>,
 }
 
 // The selection of crate types for this compilation
@@ -210,11 +221,15 @@ pub struct CrateTypes {
 }
 
 /// Emit types that we will cache.
-static ALLOWED_EMIT: Lazy<HashSet<&'static str>> =
-    Lazy::new(|| ["link", "metadata", "dep-info"].iter().copied().collect());
+static ALLOWED_EMIT: Lazy<HashSet<&'static str>> = Lazy::new(|| {
+    ["link", "metadata", "dep-info", "obj"]
+        .iter()
+        .copied()
+        .collect()
+});
 
 /// Version number for cache key.
-const CACHE_VERSION: &[u8] = b"6";
+const CACHE_VERSION: &[u8] = b"7";
 
 /// Get absolute paths for all source files and env-deps listed in rustc's dep-info output.
 async fn get_source_files_and_env_deps<T>(
@@ -233,7 +248,7 @@ where
     // Get the full list of source files from rustc's dep-info.
     let temp_dir = tempfile::Builder::new()
         .prefix("sccache")
-        .tempdir()
+        .tempdir_in(config::scratch_dir())
         .context("Failed to create temp dir")?;
     let dep_file = temp_dir.path().join("deps.d");
     let mut cmd = creator.clone().new_command_sync(executable);
@@ -465,6 +480,7 @@ impl Rust {
                 sysroot,
                 compiler_shlibs_digests: digests,
                 rlib_dep_reader,
+                workspace_wrapper: None,
             })
         }
 
@@ -477,9 +493,17 @@ impl Rust {
                 version: rustc_verbose_version.to_string(),
                 sysroot,
                 compiler_shlibs_digests: digests,
+                workspace_wrapper: None,
             })
         }
     }
+
+    /// Compile through `wrapper`, the `RUSTC_WORKSPACE_WRAPPER` of cargo,
+    /// which it runs for the workspace members only.
+    pub fn with_workspace_wrapper(mut self, wrapper: Option<PathBuf>) -> Rust {
+        self.workspace_wrapper = wrapper;
+        self
+    }
 }
 
 impl<T> Compiler<T> for Rust
@@ -510,9 +534,25 @@ where
         &self,
         arguments: &[OsString],
         cwd: &Path,
-        _env_vars: &[(OsString, OsString)],
+        env_vars: &[(OsString, OsString)],
     ) -> CompilerArguments<Box<dyn CompilerHasher<T> + 'static>> {
-        match parse_arguments(arguments, cwd) {
+        if let Some(wrapper) = &self.workspace_wrapper {
+            // The wrapper may do anything with the compilation, e.g.
+            // clippy-driver adds its lints, so the workspace members are
+            // compiled as they are. The dependencies cargo runs without the
+            // wrapper are cached.
+            return match parse_arguments(arguments.get(1..).unwrap_or(&[]), cwd, false) {
+                CompilerArguments::NotCompilation => CompilerArguments::NotCompilation,
+                _ => CompilerArguments::CannotCache(
+                    "RUSTC_WORKSPACE_WRAPPER",
+                    Some(wrapper.display().to_string()),
+                ),
+            };
+        }
+        let cache_incremental = env_vars
+            .iter()
+            .any(|(k, _)| k == "SCCACHE_RUST_INCREMENTAL");
+        match parse_arguments(arguments, cwd, cache_incremental) {
             CompilerArguments::Ok(args) => CompilerArguments::Ok(Box::new(RustHasher {
                 executable: self.executable.clone(), // if rustup exists, this must already contain the true resolved compiler path
                 host: self.host.clone(),
@@ -1041,7 +1081,11 @@ counted_array!(static ARGS: [ArgInfo<ArgData>; _] = [
     take_arg!("-o", PathBuf, CanBeSeparated, TooHardPath),
 ]);
 
-fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<ParsedArguments> {
+fn parse_arguments(
+    arguments: &[OsString],
+    cwd: &Path,
+    cache_incremental: bool,
+) -> CompilerArguments<ParsedArguments> {
     let mut args = vec![];
 
     let mut emit: Option<HashSet<String>> = None;
@@ -1060,6 +1104,8 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
     let mut color_mode = ColorMode::Auto;
     let mut has_json = false;
     let mut profile = false;
+    let mut incremental = None;
+    let mut codegen_units = None;
 
     for arg in ArgsIter::new(arguments.iter().cloned(), &ARGS[..]) {
         let arg = try_or_cannot_cache!(arg, "argument parse");
@@ -1114,14 +1160,17 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
                 match (opt.as_ref(), value) {
                     ("extra-filename", Some(value)) => extra_filename = Some(value.to_owned()),
                     ("extra-filename", None) => cannot_cache!("extra-filename"),
+                    ("codegen-units", value) => codegen_units = value.clone(),
                     // Incremental compilation makes a mess of sccache's entire world
-                    // view. It produces additional compiler outputs that we don't cache,
-                    // and just letting rustc do its work in incremental mode is likely
-                    // to be faster than trying to fetch a result from cache anyway, so
-                    // don't bother caching compiles where it's enabled currently.
-                    // Longer-term we would like to figure out better integration between
-                    // sccache and rustc in the incremental scenario:
+                    // view. It produces additional compiler outputs that we don't cache
+                    // unless SCCACHE_RUST_INCREMENTAL is set, and just letting rustc do
+                    // its work in incremental mode is likely to be faster than trying
+                    // to fetch a result from cache anyway, so don't bother caching
+                    // compiles where it's enabled by default:
                     // https://github.com/mozilla/sccache/issues/236
+                    ("incremental", Some(dir)) if cache_incremental => {
+                        incremental = Some(cwd.join(dir))
+                    }
                     ("incremental", _) => cannot_cache!("incremental"),
                     (_, _) => (),
                 }
@@ -1188,8 +1237,8 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
     req!(emit);
     req!(crate_name);
     // We won't cache invocations that are not producing
-    // binary output.
-    if !emit.is_empty() && !emit.contains("link") && !emit.contains("metadata") {
+    // binary output or dep-info.
+    if !emit.is_empty() && !emit.iter().any(|e| ALLOWED_EMIT.contains(e.as_str())) {
         return CompilerArguments::NotCompilation;
     }
     // If it's not an rlib and not a staticlib then crate-type wasn't passed,
@@ -1207,6 +1256,12 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
     if emit.iter().any(|e| !ALLOWED_EMIT.contains(e.as_str())) {
         cannot_cache!("unsupported --emit");
     }
+    // rustc only writes a single object file, whose name we know, with a
+    // single codegen unit. It keeps one object file per codegen unit
+    // otherwise.
+    if emit.contains("obj") && codegen_units.as_deref() != Some("1") {
+        cannot_cache!("--emit=obj", "without -C codegen-units=1".to_owned())
+    }
 
     // Figure out the dep-info filename, if emitting dep-info.
     let dep_info = if emit.contains("dep-info") {
@@ -1220,6 +1275,18 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
         None
     };
 
+    // Figure out the object filename, if emitting an object file.
+    let obj = if emit.contains("obj") {
+        let mut obj = crate_name.clone();
+        if let Some(extra_filename) = extra_filename.clone() {
+            obj.push_str(&extra_filename[..]);
+        }
+        obj.push_str(".o");
+        Some(obj)
+    } else {
+        None
+    };
+
     // Figure out the gcno filename, if producing gcno files with `-Zprofile`.
     let gcno = if profile && emit.contains("link") {
         let mut gcno = crate_name.clone();
@@ -1268,9 +1335,11 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
         crate_name,
         dep_info: dep_info.map(|s| s.into()),
         gcno: gcno.map(|s| s.into()),
+        obj: obj.map(|s| s.into()),
         emit,
         color_mode,
         has_json,
+        incremental,
     })
 }
 
@@ -1312,6 +1381,8 @@ where
                     emit,
                     has_json,
                     gcno,
+                    obj,
+                    incremental,
                     ..
                 },
         } = *self;
@@ -1366,22 +1437,44 @@ where
         trace!("[{}]: hashing {} staticlibs", crate_name, staticlibs.len());
         let abs_staticlibs = staticlibs.iter().map(|s| cwd.join(s)).collect::<Vec<_>>();
         let staticlib_hashes = hash_all_archives(&abs_staticlibs, pool);
+        // Hash the contents of the extra files of the compilation.
+        let extra_files = get_extra_files(&env_vars, &cwd);
+        let extra_hashes = hash_all(&extra_files, pool);
 
-        let ((source_files, source_hashes, mut env_deps), extern_hashes, staticlib_hashes) = futures::try_join!(
+        let (
+            (source_files, source_hashes, mut env_deps),
+            extern_hashes,
+            staticlib_hashes,
+            extra_hashes,
+        ) = futures::try_join!(
             source_files_and_hashes_and_env_deps,
             extern_hashes,
-            staticlib_hashes
+            staticlib_hashes,
+            extra_hashes
         )?;
         // If you change any of the inputs to the hash, you should change `CACHE_VERSION`.
         let mut m = Digest::new();
+        let mut hash_inputs = vec![];
         // Hash inputs:
         // 1. A version
         m.update(CACHE_VERSION);
+        hash_inputs.push(HashKeyInput::new(
+            "cache version",
+            String::from_utf8_lossy(CACHE_VERSION),
+        ));
         // 2. compiler_shlibs_digests
         for d in compiler_shlibs_digests {
             m.update(d.as_bytes());
+            hash_inputs.push(HashKeyInput::new("compiler", d));
         }
         let weak_toolchain_key = m.clone().finish();
+        // The salt of all the keys, which the toolchain doesn't need.
+        if let Some(salt) = get_cache_salt() {
+            m.update(salt.as_bytes());
+            hash_inputs.push(HashKeyInput::new("cache salt", salt));
+        }
+        m.update(host_target().as_bytes());
+        hash_inputs.push(HashKeyInput::new("target", host_target()));
         // 3. The full commandline (self.arguments)
         // TODO: there will be full paths here, it would be nice to
         // normalize them so we can get cross-machine cache hits.
@@ -1411,15 +1504,36 @@ where
                 })
         };
         args.hash(&mut HashToDigest { digest: &mut m });
+        hash_inputs.push(HashKeyInput::new("arguments", args.to_string_lossy()));
         // 4. The digest of all source files (this includes src file from cmdline).
         // 5. The digest of all files listed on the commandline (self.externs).
         // 6. The digest of all static libraries listed on the commandline (self.staticlibs).
-        for h in source_hashes
-            .into_iter()
-            .chain(extern_hashes)
-            .chain(staticlib_hashes)
-        {
+        // 6b. The digest of the extra files of SCCACHE_EXTRA_FILES, if any.
+        let hashed_files = source_files
+            .iter()
+            .zip(&source_hashes)
+            .map(|f| ("source file", f))
+            .chain(
+                abs_externs
+                    .iter()
+                    .zip(&extern_hashes)
+                    .map(|f| ("extern", f)),
+            )
+            .chain(
+                abs_staticlibs
+                    .iter()
+                    .zip(&staticlib_hashes)
+                    .map(|f| ("staticlib", f)),
+            )
+            .chain(
+                extra_files
+                    .iter()
+                    .zip(&extra_hashes)
+                    .map(|f| ("extra file", f)),
+            );
+        for (kind, (path, h)) in hashed_files {
             m.update(h.as_bytes());
+            hash_inputs.push(HashKeyInput::new(kind, format!("{} {}", path.display(), h)));
         }
         // 7. Environment variables: Hash all environment variables listed in the rustc dep-info
         //    output. Additionally also has all environment variables starting with `CARGO_`,
@@ -1429,6 +1543,10 @@ where
             var.hash(&mut HashToDigest { digest: &mut m });
             m.update(b"=");
             val.hash(&mut HashToDigest { digest: &mut m });
+            hash_inputs.push(HashKeyInput::new(
+                "env",
+                format!("{}={}", var.to_string_lossy(), val.to_string_lossy()),
+            ));
         }
         let mut env_vars: Vec<_> = env_vars
             .iter()
@@ -1444,12 +1562,18 @@ where
                 var.hash(&mut HashToDigest { digest: &mut m });
                 m.update(b"=");
                 val.hash(&mut HashToDigest { digest: &mut m });
+                hash_inputs.push(HashKeyInput::new(
+                    "env",
+                    format!("{}={}", var.to_string_lossy(), val.to_string_lossy()),
+                ));
             }
         }
         // 8. The cwd of the compile. This will wind up in the rlib.
         cwd.hash(&mut HashToDigest { digest: &mut m });
+        hash_inputs.push(HashKeyInput::new("cwd", cwd.to_string_lossy()));
         // 9. The version of the compiler.
         version.hash(&mut HashToDigest { digest: &mut m });
+        hash_inputs.push(HashKeyInput::new("compiler version", version.clone()));
 
         // Turn arguments into a simple Vec<OsString> to calculate outputs.
         let flat_os_string_arguments: Vec<OsString> = os_string_arguments
@@ -1477,8 +1601,7 @@ where
         // rmeta.
         //
         // This can go away once the above rustc PR makes it in.
-        let emit_generates_only_metadata =
-            !emit.is_empty() && emit.iter().all(|e| e == "metadata" || e == "dep-info");
+        let emit_generates_only_metadata = !emit.is_empty() && !emit.contains("link");
 
         if emit_generates_only_metadata {
             outputs.retain(|o| o.ends_with(".rlib") || o.ends_with(".rmeta"));
@@ -1504,6 +1627,9 @@ where
                     outputs.retain(|p| *p != lib);
                 }
             }
+        } else if emit_generates_only_metadata {
+            // Only dep-info and object files, which are added below.
+            outputs.clear();
         }
 
         // Convert output files into a map of basename -> full
@@ -1535,6 +1661,16 @@ where
         } else {
             None
         };
+        if let Some(obj) = obj {
+            let p = output_dir.join(&obj);
+            outputs.insert(
+                obj.to_string_lossy().into_owned(),
+                ArtifactDescriptor {
+                    path: p,
+                    optional: false,
+                },
+            );
+        }
         if let Some(gcno) = gcno {
             let p = output_dir.join(&gcno);
             outputs.insert(
@@ -1576,10 +1712,12 @@ where
                 dep_info,
                 cwd,
                 env_vars,
+                incremental,
                 #[cfg(feature = "dist-client")]
                 rlib_dep_reader,
             }),
             weak_toolchain_key,
+            inputs: hash_inputs,
         })
     }
 
@@ -1598,6 +1736,16 @@ where
     fn language(&self) -> Language {
         Language::Rust
     }
+
+    fn entry_metadata(&self) -> EntryMetadata {
+        EntryMetadata {
+            compiler: CompilerKind::Rust.name(),
+            compiler_version: self.version.lines().next().map(ToOwned::to_owned),
+            key_version: String::from_utf8_lossy(CACHE_VERSION).into_owned(),
+            created: std::time::SystemTime::now(),
+            build_id: None,
+        }
+    }
 }
 
 impl Compilation for RustCompilation {
@@ -1635,12 +1783,17 @@ impl Compilation for RustCompilation {
                 .collect(),
             env_vars: env_vars.to_owned(),
             cwd: cwd.to_owned(),
+            stdin: None,
         };
 
         #[cfg(not(feature = "dist-client"))]
         let dist_command = None;
         #[cfg(feature = "dist-client")]
         let dist_command = (|| {
+            // The incremental state has to be local.
+            if self.incremental.is_some() {
+                return None;
+            }
             macro_rules! try_string_arg {
                 ($e:expr) => {
                     match $e {
@@ -1758,6 +1911,7 @@ impl Compilation for RustCompilation {
             dep_info,
             rlib_dep_reader,
             env_vars,
+            cwd,
             ..
         } = *{ self };
         trace!(
@@ -1775,7 +1929,11 @@ impl Compilation for RustCompilation {
             rlib_dep_reader,
         });
         let toolchain_packager = Box::new(RustToolchainPackager { sysroot });
-        let outputs_rewriter = Box::new(RustOutputsRewriter { dep_info });
+        // The outputs it is matched against are relative to the cwd of the
+        // compile request, like the `--out-dir` it is in.
+        let outputs_rewriter = Box::new(RustOutputsRewriter {
+            dep_info: dep_info.map(|dep_info| cwd.join(dep_info)),
+        });
 
         Ok((inputs_packager, toolchain_packager, outputs_rewriter))
     }
@@ -1787,6 +1945,133 @@ impl Compilation for RustCompilation {
             optional: v.optional,
         }))
     }
+
+    fn state(&self) -> Option<Box<dyn CompilerState>> {
+        let dir = self.incremental.clone()?;
+        Some(Box::new(IncrementalState {
+            dir,
+            crate_name: self.crate_name.clone(),
+        }))
+    }
+}
+
+/// The incremental state of rustc for a crate, which rustc keeps in
+/// `<dir>/<crate name>-<crate id>/`, one directory per session, where `dir`
+/// is the directory of `-C incremental`. Only the latest finished session is
+/// cached.
+struct IncrementalState {
+    dir: PathBuf,
+    crate_name: String,
+}
+
+impl IncrementalState {
+    /// Whether `name` is the name of the directory of a crate id of this crate.
+    fn is_crate_dir(&self, name: &str) -> bool {
+        name.strip_prefix(&self.crate_name)
+            .and_then(|id| id.strip_prefix('-'))
+            .is_some_and(|id| !id.is_empty() && !id.contains('-'))
+    }
+
+    /// Find the latest finished session, as the name of its crate directory
+    /// and the name of the session directory.
+    fn latest_session(&self) -> Result<(String, String)> {
+        let mut latest = None;
+        for crate_dir in fs::read_dir(&self.dir)? {
+            let crate_dir = crate_dir?;
+            let crate_dir_name = crate_dir.file_name().to_string_lossy().into_owned();
+            if !self.is_crate_dir(&crate_dir_name) || !crate_dir.file_type()?.is_dir() {
+                continue;
+            }
+            for session in fs::read_dir(crate_dir.path())? {
+                let session = session?;
+                let name = session.file_name().to_string_lossy().into_owned();
+                // Sessions in progress end with `-working`, and have not been
+                // validated by rustc yet.
+                if !name.starts_with("s-") || name.ends_with("-working") {
+                    continue;
+                }
+                let metadata = session.metadata()?;
+                if !metadata.is_dir() {
+                    continue;
+                }
+                let modified = metadata.modified()?;
+                if latest.as_ref().map_or(true, |(m, _, _)| modified > *m) {
+                    latest = Some((modified, crate_dir_name.clone(), name));
+                }
+            }
+        }
+        match latest {
+            Some((_, crate_dir, session)) => Ok((crate_dir, session)),
+            None => bail!(
+                "no incremental session of {} in {:?}",
+                self.crate_name,
+                self.dir
+            ),
+        }
+    }
+}
+
+/// The lock file of the session directory `s-<timestamp>-<random>-<svh>`,
+/// which rustc deletes the session without.
+fn session_lock_file(session: &str) -> Option<String> {
+    let mut parts = session.splitn(4, '-');
+    let (s, timestamp, random) = (parts.next()?, parts.next()?, parts.next()?);
+    Some(format!("{s}-{timestamp}-{random}.lock"))
+}
+
+impl CompilerState for IncrementalState {
+    fn pack(&self) -> Result<Vec<u8>> {
+        let (crate_dir, session) = self.latest_session()?;
+        let crate_path = self.dir.join(&crate_dir);
+        // The cache entry is compressed already.
+        let mut builder = tar::Builder::new(vec![]);
+        builder.follow_symlinks(false);
+        builder.append_dir_all(
+            Path::new(&crate_dir).join(&session),
+            crate_path.join(&session),
+        )?;
+        if let Some(lock) = session_lock_file(&session) {
+            let lock_path = crate_path.join(&lock);
+            if lock_path.is_file() {
+                builder.append_path_with_name(lock_path, Path::new(&crate_dir).join(&lock))?;
+            }
+        }
+        Ok(builder.into_inner()?)
+    }
+
+    fn restore(&self, packed: &[u8]) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        // Unpack next to the state, so that it can be moved in place at once.
+        let tmp = tempfile::Builder::new()
+            .prefix(".sccache-")
+            .tempdir_in(&self.dir)?;
+        let unpacked = tmp.path().join("unpacked");
+        tar::Archive::new(packed).unpack(&unpacked)?;
+        let mut entries = fs::read_dir(&unpacked)?.collect::<io::Result<Vec<_>>>()?;
+        let crate_dir = match entries.pop() {
+            Some(entry) if entries.is_empty() => entry.file_name(),
+            _ => bail!("invalid incremental state"),
+        };
+        if !self.is_crate_dir(&crate_dir.to_string_lossy()) {
+            bail!("incremental state of another crate: {:?}", crate_dir);
+        }
+        // The sessions of the crate are replaced, rather than mixed with
+        // those matching other sources.
+        let target = self.dir.join(&crate_dir);
+        let old = tmp.path().join("old");
+        let had_old = match fs::rename(&target, &old) {
+            Ok(()) => true,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+            Err(e) => return Err(e.into()),
+        };
+        if let Err(e) = fs::rename(unpacked.join(&crate_dir), &target) {
+            if had_old {
+                fs::rename(&old, &target)?;
+            }
+            return Err(e.into());
+        }
+        Ok(())
+    }
 }
 
 // TODO: we do end up with slashes facing the wrong way, but Windows is agnostic so it's
@@ -2511,7 +2796,7 @@ mod test {
 
     fn _parse_arguments(arguments: &[String]) -> CompilerArguments<ParsedArguments> {
         let arguments = arguments.iter().map(OsString::from).collect::<Vec<_>>();
-        parse_arguments(&arguments, ".".as_ref())
+        parse_arguments(&arguments, ".".as_ref(), false)
     }
 
     macro_rules! parses {
@@ -2739,7 +3024,78 @@ LLVM version: 15.0.2
             "-C",
             "incremental=/foo"
         );
-        assert_eq!(r, CompilerArguments::CannotCache("incremental", None))
+        assert_eq!(r, CompilerArguments::CannotCache("incremental", None));
+
+        let args = ovec![
+            "--emit",
+            "link",
+            "foo.rs",
+            "--out-dir",
+            "out",
+            "--crate-name",
+            "foo",
+            "--crate-type",
+            "lib",
+            "-C",
+            "incremental=target/incremental"
+        ];
+        match parse_arguments(&args, "/work".as_ref(), true) {
+            CompilerArguments::Ok(h) => {
+                assert_eq!(h.incremental, Some("/work/target/incremental".into()))
+            }
+            o => panic!("Got unexpected parse result: {:?}", o),
+        }
+    }
+
+    #[test]
+    fn test_incremental_state() {
+        let f = TestFixture::new();
+        let dir = f.tempdir.path().join("incremental");
+        let state = IncrementalState {
+            dir: dir.clone(),
+            crate_name: "foo".into(),
+        };
+        let crate_dir = dir.join("foo-1abc");
+        let old_session = crate_dir.join("s-1-aaa-svh1");
+        let session = crate_dir.join("s-2-bbb-svh2");
+        fs::create_dir_all(&old_session).unwrap();
+        fs::create_dir_all(&session).unwrap();
+        fs::create_dir_all(crate_dir.join("s-3-ccc-working")).unwrap();
+        fs::create_dir_all(dir.join("foobar-2def/s-4-ddd-svh4")).unwrap();
+        File::create(old_session.join("dep-graph.bin")).unwrap();
+        filetime::set_file_mtime(&old_session, FileTime::from_unix_time(1, 0)).unwrap();
+        fs::write(session.join("dep-graph.bin"), "graph").unwrap();
+        File::create(crate_dir.join("s-2-bbb.lock")).unwrap();
+        assert_eq!(
+            session_lock_file("s-2-bbb-svh2"),
+            Some("s-2-bbb.lock".into())
+        );
+
+        let packed = state.pack().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        fs::create_dir_all(crate_dir.join("s-5-eee-svh5")).unwrap();
+        state.restore(&packed).unwrap();
+        // Only the latest finished session is restored, in place of the
+        // others.
+        let mut sessions = fs::read_dir(&crate_dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect::<Vec<_>>();
+        sessions.sort();
+        assert_eq!(sessions, ["s-2-bbb-svh2", "s-2-bbb.lock"]);
+        assert_eq!(fs::read(session.join("dep-graph.bin")).unwrap(), b"graph");
+        // A broken state leaves the state alone.
+        assert!(state.restore(b"garbage").is_err());
+        assert!(session.join("dep-graph.bin").exists());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        let other = IncrementalState {
+            dir: dir.clone(),
+            crate_name: "bar".into(),
+        };
+        assert!(other.pack().is_err());
+        assert!(other.restore(&packed).is_err());
+        assert!(session.join("dep-graph.bin").exists());
     }
 
     #[test]
@@ -3214,6 +3570,8 @@ proc_macro false
                 color_mode: ColorMode::Auto,
                 has_json: false,
                 gcno: None,
+                obj: None,
+                incremental: None,
             },
         });
         let creator = new_creator();
@@ -3247,6 +3605,8 @@ proc_macro false
         m.update(CACHE_VERSION);
         // sysroot shlibs digests.
         m.update(FAKE_DIGEST.as_bytes());
+        // The target.
+        m.update(host_target().as_bytes());
         // Arguments, with cfgs sorted at the end.
         OsStr::new("ab--cfgabc--cfgxyz").hash(&mut HashToDigest { digest: &mut m });
         // bar.rs (source file, from dep-info)
@@ -3285,7 +3645,7 @@ proc_macro false
         F: Fn(&Path) -> Result<()>,
     {
         let oargs = args.iter().map(OsString::from).collect::<Vec<OsString>>();
-        let parsed_args = match parse_arguments(&oargs, f.tempdir.path()) {
+        let parsed_args = match parse_arguments(&oargs, f.tempdir.path(), false) {
             CompilerArguments::Ok(parsed_args) => parsed_args,
             o => panic!("Got unexpected parse result: {:?}", o),
         };
@@ -3334,6 +3694,162 @@ proc_macro false
             .key
     }
 
+    /// The outputs of the compilation of `args`, with the `--print file-names`
+    /// output `file_names`.
+    fn outputs(f: &TestFixture, args: &[&str], file_names: &[&str]) -> Vec<(String, PathBuf)> {
+        let oargs = args.iter().map(OsString::from).collect::<Vec<OsString>>();
+        let parsed_args = match parse_arguments(&oargs, f.tempdir.path(), false) {
+            CompilerArguments::Ok(parsed_args) => parsed_args,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        f.touch("foo.rs").unwrap();
+        let hasher = Box::new(RustHasher {
+            executable: "rustc".into(),
+            host: "x86-64-unknown-unknown-unknown".to_owned(),
+            version: TEST_RUSTC_VERSION.to_string(),
+            sysroot: f.tempdir.path().join("sysroot"),
+            compiler_shlibs_digests: vec![],
+            #[cfg(feature = "dist-client")]
+            rlib_dep_reader: None,
+            parsed_args,
+        });
+        let creator = new_creator();
+        let runtime = single_threaded_runtime();
+        let pool = runtime.handle().clone();
+        mock_dep_info(&creator, &["foo.rs"]);
+        mock_file_names(&creator, file_names);
+        let compilation = hasher
+            .generate_hash_key(
+                &creator,
+                f.tempdir.path().to_owned(),
+                vec![],
+                false,
+                &pool,
+                false,
+                Arc::new(MockStorage::new(None, false)),
+                CacheControl::Default,
+            )
+            .wait()
+            .unwrap()
+            .compilation;
+        let mut outputs = compilation
+            .outputs()
+            .map(|o| {
+                assert!(!o.optional);
+                (o.key, o.path)
+            })
+            .collect::<Vec<_>>();
+        outputs.sort();
+        outputs
+    }
+
+    #[test]
+    fn test_emit_outputs() {
+        let f = TestFixture::new();
+        let args = |emit| {
+            [
+                "--crate-name",
+                "foo",
+                "--crate-type",
+                "lib",
+                "foo.rs",
+                emit,
+                "--out-dir",
+                "out",
+                "-C",
+                "extra-filename=-abc",
+            ]
+        };
+        let file_names = ["libfoo-abc.rlib"];
+        let output = |name: &str| (name.to_owned(), Path::new("out").join(name));
+
+        assert_eq!(
+            outputs(&f, &args("--emit=link"), &file_names),
+            [output("libfoo-abc.rlib")]
+        );
+        assert_eq!(
+            outputs(&f, &args("--emit=metadata,link"), &file_names),
+            [output("libfoo-abc.rlib"), output("libfoo-abc.rmeta")]
+        );
+        assert_eq!(
+            outputs(&f, &args("--emit=dep-info,metadata"), &file_names),
+            [output("foo-abc.d"), output("libfoo-abc.rmeta")]
+        );
+        assert_eq!(
+            outputs(&f, &args("--emit=dep-info"), &file_names),
+            [output("foo-abc.d")]
+        );
+        let mut obj_args = args("--emit=obj,dep-info,link").to_vec();
+        obj_args.extend(["-C", "codegen-units=1"]);
+        assert_eq!(
+            outputs(&f, &obj_args, &file_names),
+            [
+                output("foo-abc.d"),
+                output("foo-abc.o"),
+                output("libfoo-abc.rlib")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_arguments_emit() {
+        let h = parses!(
+            "--crate-name",
+            "foo",
+            "--crate-type",
+            "lib",
+            "foo.rs",
+            "--emit=dep-info",
+            "--out-dir",
+            "out"
+        );
+        assert_eq!(h.dep_info, Some("foo.d".into()));
+        assert_eq!(h.obj, None);
+        let h = parses!(
+            "--crate-name",
+            "foo",
+            "--crate-type",
+            "lib",
+            "foo.rs",
+            "--emit=obj",
+            "--out-dir",
+            "out",
+            "-C",
+            "codegen-units=1"
+        );
+        assert_eq!(h.obj, Some("foo.o".into()));
+        // rustc names the object files of the codegen units.
+        assert_eq!(
+            fails!(
+                "--crate-name",
+                "foo",
+                "--crate-type",
+                "lib",
+                "foo.rs",
+                "--emit=obj",
+                "--out-dir",
+                "out"
+            ),
+            CompilerArguments::CannotCache(
+                "--emit=obj",
+                Some("without -C codegen-units=1".to_owned())
+            )
+        );
+        assert_eq!(
+            fails!(
+                "--crate-name",
+                "foo",
+                "--crate-type",
+                "lib",
+                "foo.rs",
+                "--emit=asm",
+                "--out-dir",
+                "out"
+            ),
+            CompilerArguments::NotCompilation
+        );
+    }
+
     #[allow(clippy::unnecessary_unwrap)]
     fn nothing(_path: &Path) -> Result<()> {
         Ok(())
@@ -3396,6 +3912,43 @@ proc_macro false
         );
     }
 
+    #[test]
+    fn test_extra_files_hashes() {
+        let f = TestFixture::new();
+        let args = &[
+            "--emit",
+            "link",
+            "foo.rs",
+            "--out-dir",
+            "out",
+            "--crate-name",
+            "foo",
+            "--crate-type",
+            "lib",
+        ];
+        let env_vars = [(
+            OsString::from("SCCACHE_EXTRA_FILES"),
+            OsString::from("gen.cfg"),
+        )];
+        let with_config = |contents: &'static [u8]| {
+            hash_key(
+                &f,
+                args,
+                &env_vars,
+                |tempdir| {
+                    create_file(tempdir, "gen.cfg", |mut f| f.write_all(contents))?;
+                    Ok(())
+                },
+                false,
+            )
+        };
+        let key = with_config(b"plugin=a");
+        assert_ne!(key, hash_key(&f, args, &[], nothing, false));
+        assert_eq!(key, with_config(b"plugin=a"));
+        // Changing the file changes the key.
+        assert_ne!(key, with_config(b"plugin=b"));
+    }
+
     #[test_case(true ; "with preprocessor cache")]
     #[test_case(false ; "without preprocessor cache")]
     fn test_equal_hashes_link_paths(preprocessor_cache_mode: bool) {
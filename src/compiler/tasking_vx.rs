@@ -237,8 +237,11 @@ where
         None => cannot_cache!("no input file"),
     };
     let language = match Language::from_file_name(Path::new(&input)) {
+        // Only the compilers like gcc assemble the sources here.
+        Some(Language::Assembler | Language::AssemblerToPreprocess) | None => {
+            cannot_cache!("unknown source language")
+        }
         Some(l) => l,
-        None => cannot_cache!("unknown source language"),
     };
 
     // --dep-file without any argument is valid too and uses the source file name
@@ -282,6 +285,9 @@ where
         color_mode: ColorMode::Auto,
         suppress_rewrite_includes_only: false,
         too_hard_for_preprocessor_cache_mode: None,
+        stdin: None,
+        output_to_stdout: false,
+        sysroot: None,
     })
 }
 
@@ -375,6 +381,7 @@ fn generate_compile_commands(
         arguments,
         env_vars: env_vars.to_owned(),
         cwd: cwd.to_owned(),
+        stdin: None,
     };
 
     Ok((command, None, Cacheable::Yes))
@@ -708,6 +715,9 @@ mod test {
             color_mode: ColorMode::Auto,
             suppress_rewrite_includes_only: false,
             too_hard_for_preprocessor_cache_mode: None,
+            stdin: None,
+            output_to_stdout: false,
+            sysroot: None,
         };
         let compiler = &f.bins[0];
         // Compiler invocation.
@@ -757,6 +767,9 @@ mod test {
             color_mode: ColorMode::Auto,
             suppress_rewrite_includes_only: false,
             too_hard_for_preprocessor_cache_mode: None,
+            stdin: None,
+            output_to_stdout: false,
+            sysroot: None,
         };
         let compiler = &f.bins[0];
         // Compiler invocation.
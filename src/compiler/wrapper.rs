@@ -0,0 +1,270 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Compilers invoked through a wrapper declared in the configuration, e.g. a
+//! `distcc`-style script or `ccache gcc`. The compiler the wrapper runs is
+//! detected and hashed in its place, and the wrapper is only put back in
+//! front of the command running the compilation.
+
+use crate::cache::metadata::EntryMetadata;
+use crate::cache::{FileObjectSource, Storage};
+#[cfg(feature = "dist-client")]
+use crate::compiler::DistPackagers;
+use crate::compiler::{
+    CacheControl, Cacheable, ColorMode, Compilation, CompileCommand, Compiler, CompilerArguments,
+    CompilerHasher, CompilerKind, HashResult, Language,
+};
+use crate::config::CompilerWrapperConfig;
+use crate::dist;
+#[cfg(feature = "dist-client")]
+use crate::dist::pkg;
+use crate::mock_command::CommandCreatorSync;
+use async_trait::async_trait;
+use std::borrow::Cow;
+use std::ffi::OsString;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::errors::*;
+
+/// A wrapper sccache is invoked with instead of the compiler it runs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompilerWrapper {
+    /// The wrapper, as invoked.
+    pub path: PathBuf,
+    /// The arguments of the wrapper preceding the compiler arguments.
+    pub args: Vec<OsString>,
+    /// The compiler the wrapper runs.
+    pub compiler: PathBuf,
+}
+
+impl CompilerWrapper {
+    pub fn from_config(path: &Path, config: &CompilerWrapperConfig) -> CompilerWrapper {
+        CompilerWrapper {
+            path: path.to_owned(),
+            args: config.args.iter().map(OsString::from).collect(),
+            compiler: config.compiler.clone(),
+        }
+    }
+
+    /// Get the compiler arguments of an invocation of the wrapper with
+    /// `arguments`, unless they don't start with the arguments of the
+    /// wrapper.
+    pub fn strip<'a>(&self, arguments: &'a [OsString]) -> Option<&'a [OsString]> {
+        arguments.strip_prefix(self.args.as_slice())
+    }
+
+    /// Run the compilations of `compiler` through the wrapper.
+    pub fn wrap<T: CommandCreatorSync>(
+        self: Arc<Self>,
+        compiler: Box<dyn Compiler<T>>,
+    ) -> Box<dyn Compiler<T>> {
+        Box::new(WrappedCompiler {
+            compiler,
+            wrapper: self,
+        })
+    }
+
+    fn wrap_command(&self, command: &mut CompileCommand) {
+        command.executable = self.path.clone();
+        command.arguments.splice(0..0, self.args.iter().cloned());
+    }
+}
+
+struct WrappedCompiler<T: CommandCreatorSync> {
+    compiler: Box<dyn Compiler<T>>,
+    wrapper: Arc<CompilerWrapper>,
+}
+
+impl<T: CommandCreatorSync> Compiler<T> for WrappedCompiler<T> {
+    fn kind(&self) -> CompilerKind {
+        self.compiler.kind()
+    }
+    #[cfg(feature = "dist-client")]
+    fn get_toolchain_packager(&self) -> Box<dyn pkg::ToolchainPackager> {
+        self.compiler.get_toolchain_packager()
+    }
+    fn parse_arguments(
+        &self,
+        arguments: &[OsString],
+        cwd: &Path,
+        env_vars: &[(OsString, OsString)],
+    ) -> CompilerArguments<Box<dyn CompilerHasher<T> + 'static>> {
+        match self.compiler.parse_arguments(arguments, cwd, env_vars) {
+            CompilerArguments::Ok(hasher) => CompilerArguments::Ok(Box::new(WrappedHasher {
+                hasher,
+                wrapper: self.wrapper.clone(),
+            })),
+            CompilerArguments::CannotCache(why, extra_info) => {
+                CompilerArguments::CannotCache(why, extra_info)
+            }
+            CompilerArguments::NotCompilation => CompilerArguments::NotCompilation,
+        }
+    }
+    fn split_inputs(&self, arguments: &[OsString]) -> Option<Vec<Vec<OsString>>> {
+        let mut compilations = self.compiler.split_inputs(arguments)?;
+        for arguments in &mut compilations {
+            arguments.splice(0..0, self.wrapper.args.iter().cloned());
+        }
+        Some(compilations)
+    }
+    fn box_clone(&self) -> Box<dyn Compiler<T>> {
+        Box::new(WrappedCompiler {
+            compiler: self.compiler.clone(),
+            wrapper: self.wrapper.clone(),
+        })
+    }
+}
+
+struct WrappedHasher<T: CommandCreatorSync> {
+    hasher: Box<dyn CompilerHasher<T>>,
+    wrapper: Arc<CompilerWrapper>,
+}
+
+impl<T: CommandCreatorSync> fmt::Debug for WrappedHasher<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WrappedHasher")
+            .field("hasher", &self.hasher)
+            .field("wrapper", &self.wrapper)
+            .finish()
+    }
+}
+
+#[async_trait]
+impl<T: CommandCreatorSync> CompilerHasher<T> for WrappedHasher<T> {
+    async fn generate_hash_key(
+        self: Box<Self>,
+        creator: &T,
+        cwd: PathBuf,
+        env_vars: Vec<(OsString, OsString)>,
+        may_dist: bool,
+        pool: &tokio::runtime::Handle,
+        rewrite_includes_only: bool,
+        storage: Arc<dyn Storage>,
+        cache_control: CacheControl,
+    ) -> Result<HashResult> {
+        let WrappedHasher { hasher, wrapper } = *self;
+        let mut result = hasher
+            .generate_hash_key(
+                creator,
+                cwd,
+                env_vars,
+                may_dist,
+                pool,
+                rewrite_includes_only,
+                storage,
+                cache_control,
+            )
+            .await?;
+        result.compilation = Box::new(WrappedCompilation {
+            compilation: result.compilation,
+            wrapper,
+        });
+        Ok(result)
+    }
+
+    fn color_mode(&self) -> ColorMode {
+        self.hasher.color_mode()
+    }
+
+    fn output_pretty(&self) -> Cow<'_, str> {
+        self.hasher.output_pretty()
+    }
+
+    fn box_clone(&self) -> Box<dyn CompilerHasher<T>> {
+        Box::new(WrappedHasher {
+            hasher: self.hasher.clone(),
+            wrapper: self.wrapper.clone(),
+        })
+    }
+
+    fn language(&self) -> Language {
+        self.hasher.language()
+    }
+
+    fn entry_metadata(&self) -> EntryMetadata {
+        self.hasher.entry_metadata()
+    }
+
+    fn reads_stdin(&self) -> bool {
+        self.hasher.reads_stdin()
+    }
+
+    fn set_stdin(&mut self, stdin: Vec<u8>) {
+        self.hasher.set_stdin(stdin)
+    }
+
+    fn writes_stdout(&self) -> bool {
+        self.hasher.writes_stdout()
+    }
+}
+
+struct WrappedCompilation {
+    compilation: Box<dyn Compilation>,
+    wrapper: Arc<CompilerWrapper>,
+}
+
+impl Compilation for WrappedCompilation {
+    fn generate_compile_commands(
+        &self,
+        path_transformer: &mut dist::PathTransformer,
+        rewrite_includes_only: bool,
+    ) -> Result<(CompileCommand, Option<dist::CompileCommand>, Cacheable)> {
+        // Distributed compilations run the compiler itself, as packaged.
+        let (mut command, dist_command, cacheable) = self
+            .compilation
+            .generate_compile_commands(path_transformer, rewrite_includes_only)?;
+        self.wrapper.wrap_command(&mut command);
+        Ok((command, dist_command, cacheable))
+    }
+
+    #[cfg(feature = "dist-client")]
+    fn into_dist_packagers(
+        self: Box<Self>,
+        path_transformer: dist::PathTransformer,
+    ) -> Result<DistPackagers> {
+        self.compilation.into_dist_packagers(path_transformer)
+    }
+
+    fn outputs<'a>(&'a self) -> Box<dyn Iterator<Item = FileObjectSource> + 'a> {
+        self.compilation.outputs()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_strip() {
+        let wrapper = CompilerWrapper {
+            path: "/usr/bin/ccache".into(),
+            args: vec!["gcc".into()],
+            compiler: "/usr/bin/gcc".into(),
+        };
+        let args: Vec<OsString> = vec!["gcc".into(), "-c".into(), "foo.c".into()];
+        assert_eq!(wrapper.strip(&args), Some(&args[1..]));
+        assert_eq!(wrapper.strip(&args[1..]), None);
+
+        let mut command = CompileCommand {
+            executable: "/usr/bin/gcc".into(),
+            arguments: args[1..].to_vec(),
+            env_vars: vec![],
+            cwd: "/".into(),
+            stdin: None,
+        };
+        wrapper.wrap_command(&mut command);
+        assert_eq!(command.executable, Path::new("/usr/bin/ccache"));
+        assert_eq!(command.arguments, args);
+    }
+}
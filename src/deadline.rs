@@ -0,0 +1,148 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The deadlines of the compilations, which the client passes along with
+//! them from `SCCACHE_COMPILE_TIMEOUT` or `SCCACHE_COMPILE_DEADLINE`, for the
+//! build tools giving each action a time budget.
+//!
+//! Past its deadline, a compilation is dropped, which kills the compiler, and
+//! the outputs it may have partly written are removed.
+
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::errors::*;
+
+tokio::task_local! {
+    static OUTPUTS: Arc<Mutex<Vec<PathBuf>>>;
+}
+
+/// Get the deadline of a compilation starting `now` from `env_vars`, the
+/// environment of the client: `SCCACHE_COMPILE_TIMEOUT`, in seconds from now,
+/// and `SCCACHE_COMPILE_DEADLINE`, in seconds since the Unix epoch. With
+/// both, the earliest is the deadline.
+pub fn from_env(now: SystemTime, env_vars: &[(OsString, OsString)]) -> Result<Option<SystemTime>> {
+    let var = |name: &str| {
+        env_vars
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_os_str())
+    };
+    parse(
+        now,
+        var("SCCACHE_COMPILE_TIMEOUT"),
+        var("SCCACHE_COMPILE_DEADLINE"),
+    )
+}
+
+fn parse(
+    now: SystemTime,
+    timeout: Option<&OsStr>,
+    deadline: Option<&OsStr>,
+) -> Result<Option<SystemTime>> {
+    let secs = |name: &str, value: &OsStr| {
+        value
+            .to_str()
+            .and_then(|value| value.trim().parse::<f64>().ok())
+            .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+            .with_context(|| format!("{} is not a number of seconds: {:?}", name, value))
+    };
+    let timeout = timeout
+        .map(|timeout| secs("SCCACHE_COMPILE_TIMEOUT", timeout).map(|timeout| now + timeout))
+        .transpose()?;
+    let deadline = deadline
+        .map(|deadline| secs("SCCACHE_COMPILE_DEADLINE", deadline).map(|secs| UNIX_EPOCH + secs))
+        .transpose()?;
+    Ok(timeout.into_iter().chain(deadline).min())
+}
+
+/// Record that the compilation the current task runs writes `paths`, to
+/// remove them if it is dropped past its deadline.
+pub fn record_outputs(paths: impl IntoIterator<Item = PathBuf>) {
+    let _ = OUTPUTS.try_with(|outputs| outputs.lock().unwrap().extend(paths));
+}
+
+/// Run `task` until `deadline`, if any. Past it, `task` is dropped, the
+/// outputs it recorded are removed, and `None` is returned.
+pub async fn run<F: Future>(deadline: Option<SystemTime>, task: F) -> Option<F::Output> {
+    let Some(deadline) = deadline else {
+        return Some(task.await);
+    };
+    let left = deadline
+        .duration_since(SystemTime::now())
+        .unwrap_or_default();
+    let outputs = Arc::new(Mutex::new(vec![]));
+    let task = OUTPUTS.scope(outputs.clone(), task);
+    if let Ok(output) = tokio::time::timeout(left, task).await {
+        return Some(output);
+    }
+    for path in outputs.lock().unwrap().drain(..) {
+        match fs::remove_file(&path) {
+            Ok(()) => debug!("Removed {:?}, past the deadline", path),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => warn!("Failed to remove {:?}: {}", path, e),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_deadline() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let parse = |timeout: Option<&str>, deadline: Option<&str>| {
+            parse(now, timeout.map(OsStr::new), deadline.map(OsStr::new))
+        };
+        assert_eq!(parse(None, None).unwrap(), None);
+        assert_eq!(
+            parse(Some("2.5"), None).unwrap(),
+            Some(now + Duration::from_millis(2500))
+        );
+        assert_eq!(
+            parse(None, Some("1700000100")).unwrap(),
+            Some(now + Duration::from_secs(100))
+        );
+        // The earliest wins.
+        assert_eq!(
+            parse(Some("300"), Some("1700000100")).unwrap(),
+            Some(now + Duration::from_secs(100))
+        );
+        assert!(parse(Some("soon"), None).is_err());
+        assert!(parse(Some("-1"), None).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_run_past_deadline() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let output = tempdir.path().join("foo.o");
+        assert_eq!(run(None, async { 1 }).await, Some(1));
+        let deadline = Some(SystemTime::now() + Duration::from_secs(60));
+        assert_eq!(run(deadline, async { 1 }).await, Some(1));
+
+        let deadline = Some(SystemTime::now() + Duration::from_millis(50));
+        let task = async {
+            record_outputs([output.clone()]);
+            fs::write(&output, b"partial").unwrap();
+            std::future::pending::<()>().await
+        };
+        assert_eq!(run(deadline, task).await, None);
+        assert!(!output.exists());
+    }
+}
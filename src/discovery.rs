@@ -0,0 +1,124 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The discovery file, `$XDG_RUNTIME_DIR/sccache.addr`, where the server
+//! writes the address it listens on for the clients of the same user to find
+//! it, e.g. at a port the OS picked with `SCCACHE_SERVER_PORT=0`, so that
+//! several users or versions of sccache on a host don't collide on a port.
+//!
+//! It is only used when the clients aren't told where the server is, with
+//! `SCCACHE_SERVER_ADDR`, `SCCACHE_SERVER_UDS` or a `SCCACHE_SERVER_PORT`
+//! other than 0. A file left by a server which is gone is removed by the
+//! client finding it unreachable, and replaced by the next server.
+
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+/// The name of the discovery file, in `$XDG_RUNTIME_DIR`.
+const FILE_NAME: &str = "sccache.addr";
+
+/// Get the path of the discovery file, unless the clients are told where the
+/// server is or `XDG_RUNTIME_DIR` isn't set.
+pub fn path() -> Option<PathBuf> {
+    if env::var_os("SCCACHE_SERVER_ADDR").is_some() || env::var_os("SCCACHE_SERVER_UDS").is_some() {
+        return None;
+    }
+    let port = env::var("SCCACHE_SERVER_PORT").ok();
+    if port
+        .and_then(|port| port.parse::<u16>().ok())
+        .is_some_and(|port| port != 0)
+    {
+        return None;
+    }
+    let dir = env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty())?;
+    Some(PathBuf::from(dir).join(FILE_NAME))
+}
+
+/// Read the address of the server from the discovery file, if any.
+pub fn read() -> Option<SocketAddr> {
+    read_from(&path()?)
+}
+
+/// Write `addr`, the address the server listens on, to the discovery file,
+/// if any, replacing the one of a previous server.
+pub fn publish(addr: SocketAddr) -> io::Result<()> {
+    match path() {
+        Some(path) => publish_to(&path, addr),
+        None => Ok(()),
+    }
+}
+
+/// Remove the discovery file if it still has `addr`, when the server at
+/// `addr` shuts down or is unreachable.
+pub fn withdraw(addr: SocketAddr) {
+    if let Some(path) = path() {
+        withdraw_from(&path, addr);
+    }
+}
+
+fn read_from(path: &Path) -> Option<SocketAddr> {
+    let contents = fs::read_to_string(path).ok()?;
+    match contents.trim().parse() {
+        Ok(addr) => Some(addr),
+        Err(_) => {
+            warn!("Ignoring the invalid discovery file {}", path.display());
+            None
+        }
+    }
+}
+
+fn publish_to(path: &Path, addr: SocketAddr) -> io::Result<()> {
+    // The file is replaced at once, so that the clients never read half of
+    // it.
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+    writeln!(tmp, "{}", addr)?;
+    tmp.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
+
+fn withdraw_from(path: &Path, addr: SocketAddr) {
+    if read_from(path) == Some(addr) {
+        debug!("Removing the discovery file {}", path.display());
+        let _ = fs::remove_file(path);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_publish_and_withdraw() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(FILE_NAME);
+        assert_eq!(read_from(&path), None);
+
+        let addr = "127.0.0.1:4226".parse().unwrap();
+        publish_to(&path, addr).unwrap();
+        assert_eq!(read_from(&path), Some(addr));
+
+        // A newer server replaces the file, which the previous one leaves.
+        let newer = "127.0.0.1:4227".parse().unwrap();
+        publish_to(&path, newer).unwrap();
+        withdraw_from(&path, addr);
+        assert_eq!(read_from(&path), Some(newer));
+        withdraw_from(&path, newer);
+        assert!(!path.exists());
+
+        fs::write(&path, "not an address").unwrap();
+        assert_eq!(read_from(&path), None);
+    }
+}
@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 #[cfg(feature = "dist-client")]
-pub use self::client::Client;
+pub use self::client::{get_scheduler_status, Client};
 #[cfg(feature = "dist-server")]
 pub use self::server::Server;
 #[cfg(feature = "dist-server")]
@@ -714,6 +714,22 @@ mod server {
                 }};
             }
 
+            macro_rules! check_client_auth_or_err {
+                ($request:ident) => {{
+                    let bearer_auth = match bearer_http_auth($request) {
+                        Some(s) => s,
+                        None => return make_401("no_bearer_auth"),
+                    };
+                    match check_client_auth.check(bearer_auth) {
+                        Ok(()) => (),
+                        Err(client_msg) => {
+                            warn!("Bearer auth failed: {:?}", client_msg);
+                            return make_401_with_body("bearer_auth_failed", client_msg);
+                        }
+                    }
+                }};
+            }
+
             fn maybe_update_certs(
                 client: &mut reqwest::blocking::Client,
                 certs: &mut HashMap<ServerId, (Vec<u8>, Vec<u8>)>,
@@ -765,21 +781,11 @@ mod server {
                 trace!("Req {} ({}): {:?}", req_id, request.remote_addr(), request);
                 let response = (|| router!(request,
                     (POST) (/api/v1/scheduler/alloc_job) => {
-                        let bearer_auth = match bearer_http_auth(request) {
-                            Some(s) => s,
-                            None => return make_401("no_bearer_auth"),
-                        };
-                        match check_client_auth.check(bearer_auth) {
-                            Ok(()) => (),
-                            Err(client_msg) => {
-                                warn!("Bearer auth failed: {:?}", client_msg);
-                                return make_401_with_body("bearer_auth_failed", client_msg)
-                            },
-                        }
+                        check_client_auth_or_err!(request);
                         let toolchain = try_or_400_log!(req_id, bincode_input(request));
                         trace!("Req {}: alloc_job: {:?}", req_id, toolchain);
 
-                        let alloc_job_res: AllocJobResult = try_or_500_log!(req_id, handler.handle_alloc_job(&requester, toolchain));
+                        let alloc_job_res: AllocJobResult = try_or_500_log!(req_id, handler.handle_alloc_job(&requester, request.remote_addr().ip(), toolchain));
                         let certs = server_certificates.lock().unwrap();
                         let res = AllocJobHttpResponse::from_alloc_job_result(alloc_job_res, &certs);
                         prepare_response(request, &res)
@@ -828,7 +834,9 @@ mod server {
                         prepare_response(request, &res)
                     },
                     (GET) (/api/v1/scheduler/status) => {
-                        let res: SchedulerStatusResult = try_or_500_log!(req_id, handler.handle_status());
+                        // The status tells the addresses of the servers.
+                        check_client_auth_or_err!(request);
+                        let res: SchedulerStatusResult = try_or_500_log!(req_id, handler.handle_status(request.remote_addr().ip()));
                         prepare_response(request, &res)
                     },
                     _ => {
@@ -1080,6 +1088,25 @@ mod client {
 
     const REQUEST_TIMEOUT_SECS: u64 = 600;
     const CONNECT_TIMEOUT_SECS: u64 = 5;
+    const STATUS_TIMEOUT_SECS: u64 = 10;
+
+    /// Ask the scheduler for its status, authenticated with the token of the
+    /// client. Unlike `Client::do_get_status`, this doesn't need the
+    /// toolchain cache of a full client.
+    pub async fn get_scheduler_status(
+        scheduler_url: &reqwest::Url,
+        auth_token: &str,
+    ) -> Result<SchedulerStatusResult> {
+        let client = reqwest::ClientBuilder::new()
+            .timeout(Duration::new(STATUS_TIMEOUT_SECS, 0))
+            .connect_timeout(Duration::new(CONNECT_TIMEOUT_SECS, 0))
+            .build()
+            .context("failed to create an async HTTP client")?;
+        let req = client
+            .get(urls::scheduler_status(scheduler_url))
+            .bearer_auth(auth_token);
+        bincode_req_fut(req).await
+    }
 
     pub struct Client {
         auth_token: String,
@@ -1223,7 +1250,12 @@ mod client {
         async fn do_get_status(&self) -> Result<SchedulerStatusResult> {
             let scheduler_url = self.scheduler_url.clone();
             let url = urls::scheduler_status(&scheduler_url);
-            let req = self.client.lock().unwrap().get(url);
+            let req = self
+                .client
+                .lock()
+                .unwrap()
+                .get(url)
+                .bearer_auth(self.auth_token.clone());
             bincode_req_fut(req).await
         }
 
@@ -19,6 +19,8 @@ use serde::{Deserialize, Serialize};
 use std::ffi::OsString;
 use std::fmt;
 use std::io::{self, Read};
+#[cfg(feature = "dist-server")]
+use std::net::IpAddr;
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 use std::process;
@@ -316,7 +318,12 @@ pub fn try_compile_command_to_dist(command: compiler::CompileCommand) -> Option<
         arguments,
         env_vars,
         cwd,
+        stdin,
     } = command;
+    // The build servers don't feed the compiler anything on stdin.
+    if stdin.is_some() {
+        return None;
+    }
     Some(CompileCommand {
         executable: executable.into_os_string().into_string().ok()?,
         arguments: arguments
@@ -581,7 +588,53 @@ pub struct JobComplete {
 pub struct SchedulerStatusResult {
     pub num_servers: usize,
     pub num_cpus: usize,
-    pub in_progress: usize,
+    /// Jobs running on a server.
+    pub running: usize,
+    /// Jobs assigned to a server that haven't started yet.
+    pub queued: usize,
+    pub servers: Vec<ServerStatusResult>,
+    /// The most recent jobs of the client asking for the status, oldest first.
+    pub recent_jobs: Vec<JobStatusResult>,
+}
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerStatusResult {
+    pub server_id: ServerId,
+    pub num_cpus: usize,
+    pub jobs_assigned: usize,
+    /// The jobs assigned to the server which it had the toolchain of.
+    pub toolchain_hits: u64,
+    /// The jobs assigned to the server which it needed the toolchain of.
+    pub toolchain_misses: u64,
+}
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JobStatusResult {
+    pub job_id: JobId,
+    pub server_id: ServerId,
+    pub outcome: JobOutcome,
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub enum JobOutcome {
+    /// The server ran the job.
+    Complete,
+    /// The job couldn't be assigned to the server it was allocated to.
+    AssignFailed,
+    /// The job was dropped before finishing, e.g. because its server went
+    /// away.
+    Lost,
+}
+impl fmt::Display for JobOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use self::JobOutcome::*;
+        match *self {
+            Complete => "complete",
+            AssignFailed => "assign failed",
+            Lost => "lost",
+        }
+        .fmt(f)
+    }
 }
 
 // SubmitToolchain
@@ -658,6 +711,7 @@ pub trait SchedulerIncoming: Send + Sync {
     fn handle_alloc_job(
         &self,
         requester: &dyn SchedulerOutgoing,
+        client: IpAddr,
         tc: Toolchain,
     ) -> ExtResult<AllocJobResult, Error>;
     // From Server
@@ -675,8 +729,8 @@ pub trait SchedulerIncoming: Send + Sync {
         server_id: ServerId,
         job_state: JobState,
     ) -> ExtResult<UpdateJobStateResult, Error>;
-    // From anyone
-    fn handle_status(&self) -> ExtResult<SchedulerStatusResult, Error>;
+    // From anyone, `client` being who the recent jobs are for
+    fn handle_status(&self, client: IpAddr) -> ExtResult<SchedulerStatusResult, Error>;
 }
 
 #[cfg(feature = "dist-server")]
@@ -32,6 +32,47 @@ pub struct HttpClientError(pub String);
 #[derive(Debug)]
 pub struct ProcessError(pub process::Output);
 
+/// A program, usually the compiler, could not be started.
+#[derive(Debug)]
+pub struct SpawnError(pub String);
+
+/// Storing or restoring a cache entry failed.
+#[derive(Debug)]
+pub struct CacheError(pub &'static str);
+
+/// The output of a process was larger than `max_size`, and was left in
+/// temporary files to be streamed from.
+#[derive(Debug)]
+pub struct OutputTooLarge {
+    pub max_size: u64,
+    pub status: process::ExitStatus,
+    pub stdout: crate::util::Captured,
+    pub stderr: crate::util::Captured,
+}
+
+impl OutputTooLarge {
+    /// All of the output, read back from its files.
+    pub fn into_output(self) -> Result<process::Output> {
+        Ok(process::Output {
+            status: self.status,
+            stdout: self.stdout.into_bytes()?,
+            stderr: self.stderr.into_bytes()?,
+        })
+    }
+}
+
+/// A request to the server was larger than the given limit, and was not sent.
+#[derive(Debug)]
+pub struct RequestTooLarge(pub u64, pub usize);
+
+/// A cache entry couldn't be stored because the disk is full.
+#[derive(Debug)]
+pub struct DiskFull;
+
+/// A request to the cache took longer than its timeout, and was cancelled.
+#[derive(Debug)]
+pub struct CacheTimeout(pub std::time::Duration);
+
 #[cfg(feature = "hyper")]
 impl std::error::Error for BadHttpStatusError {}
 
@@ -39,6 +80,18 @@ impl std::error::Error for HttpClientError {}
 
 impl std::error::Error for ProcessError {}
 
+impl std::error::Error for SpawnError {}
+
+impl std::error::Error for CacheError {}
+
+impl std::error::Error for OutputTooLarge {}
+
+impl std::error::Error for RequestTooLarge {}
+
+impl std::error::Error for DiskFull {}
+
+impl std::error::Error for CacheTimeout {}
+
 #[cfg(feature = "hyper")]
 impl std::fmt::Display for BadHttpStatusError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -58,4 +111,44 @@ impl std::fmt::Display for ProcessError {
     }
 }
 
+impl std::fmt::Display for SpawnError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "failed to spawn {}", self.0)
+    }
+}
+
+impl std::fmt::Display for CacheError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::fmt::Display for OutputTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the output is larger than {} bytes", self.max_size)
+    }
+}
+
+impl std::fmt::Display for RequestTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "the request is {} bytes, more than SCCACHE_MAX_FRAME_LENGTH, {} bytes",
+            self.0, self.1
+        )
+    }
+}
+
+impl std::fmt::Display for DiskFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the disk is full")
+    }
+}
+
+impl std::fmt::Display for CacheTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the cache request timed out after {:?}", self.0)
+    }
+}
+
 pub type Result<T> = anyhow::Result<T>;
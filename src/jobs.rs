@@ -0,0 +1,110 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The jobs of the server, which bound the compilers it runs at once, see
+//! `SCCACHE_SERVER_MAX_JOBS`. A compilation only takes a job to run the
+//! compiler locally: the cache lookups and the distributed compilations
+//! don't take any.
+
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+tokio::task_local! {
+    static QUEUE: Arc<CompileQueue>;
+}
+
+/// Bounds the compilations of the server: at most `max_jobs` compilers run
+/// at once, and at most `max_queued` more compilations are in progress,
+/// looking up the cache, compiling remotely or waiting for a job. Compile
+/// requests beyond that wait for room in the queue before being answered,
+/// instead of piling up tasks.
+pub struct CompileQueue {
+    /// One permit per job.
+    jobs: Arc<Semaphore>,
+    /// One permit per compilation in progress.
+    slots: Arc<Semaphore>,
+    /// How many compilations are waiting for a job.
+    queued: AtomicUsize,
+}
+
+impl CompileQueue {
+    pub fn new(max_jobs: usize, max_queued: usize) -> CompileQueue {
+        CompileQueue {
+            jobs: Arc::new(Semaphore::new(max_jobs)),
+            slots: Arc::new(Semaphore::new(max_jobs + max_queued)),
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    /// Wait for room in the queue.
+    pub async fn enter(&self) -> OwnedSemaphorePermit {
+        self.slots
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("The compile queue is never closed")
+    }
+
+    /// Wait for a job, once in the queue.
+    async fn start(&self) -> OwnedSemaphorePermit {
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        let job = self
+            .jobs
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("The compile queue is never closed");
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+        job
+    }
+
+    /// The number of compilations waiting for a job.
+    pub fn depth(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+}
+
+/// Run `task`, whose compilers wait for a job of `queue`.
+pub async fn scope<F: Future>(queue: Arc<CompileQueue>, task: F) -> F::Output {
+    QUEUE.scope(queue, task).await
+}
+
+/// Wait for a job to run a compiler in the current task, if it runs in the
+/// queue of a server. The job is held until the permit is dropped.
+pub async fn start() -> Option<OwnedSemaphorePermit> {
+    let queue = QUEUE.try_with(Clone::clone).ok()?;
+    Some(queue.start().await)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_start() {
+        assert!(start().await.is_none());
+        let queue = Arc::new(CompileQueue::new(1, 0));
+        let job = scope(queue.clone(), start()).await;
+        assert!(job.is_some());
+        // The second job waits for the first one.
+        let second = tokio::spawn(scope(queue.clone(), start()));
+        while queue.depth() == 0 {
+            tokio::task::yield_now().await;
+        }
+        drop(job);
+        assert!(second.await.unwrap().is_some());
+        assert_eq!(queue.depth(), 0);
+    }
+}
@@ -33,18 +33,35 @@ mod test;
 #[macro_use]
 pub mod errors;
 
-mod cache;
-mod client;
+mod access_log;
+mod admin;
+mod affinity;
+pub mod cache;
+mod ccache;
+pub mod client;
+mod clock;
 mod cmdline;
 mod commands;
+pub mod compile;
 mod compiler;
 pub mod config;
+mod deadline;
+mod discovery;
 pub mod dist;
+mod jobs;
 mod jobserver;
 pub mod lru_disk_cache;
+mod memory_accounting;
 mod mock_command;
 mod protocol;
+mod result_log;
+mod retry;
+mod selftest;
 pub mod server;
+mod service;
+mod statsd;
+mod stderr_stream;
+mod umask;
 #[doc(hidden)]
 pub mod util;
 
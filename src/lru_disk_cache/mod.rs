@@ -1,9 +1,11 @@
 pub mod lru_cache;
+mod sharded;
 
 use fs::File;
 use fs_err as fs;
 use std::borrow::Borrow;
 use std::boxed::Box;
+use std::cell::Cell;
 use std::collections::hash_map::RandomState;
 use std::error::Error as StdError;
 use std::ffi::{OsStr, OsString};
@@ -12,9 +14,12 @@ use std::hash::BuildHasher;
 use std::io;
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
 use filetime::{set_file_times, FileTime};
 pub use lru_cache::{LruCache, Meter};
+pub use sharded::{ShardedLruDiskCache, DEFAULT_SHARDS};
 use tempfile::NamedTempFile;
 use walkdir::WalkDir;
 
@@ -22,16 +27,24 @@ use crate::util::OsStrExt;
 
 const TEMPFILE_PREFIX: &str = ".sccachetmp";
 
+/// A file in the cache.
+struct Entry {
+    size: u64,
+    /// When the file was last used, as read from `LruDiskCache::clock`. A `Cell`
+    /// since `LruCache` only hands out shared references to metered values.
+    tick: Cell<u64>,
+}
+
 struct FileSize;
 
-/// Given a tuple of (path, filesize), use the filesize for measurement.
-impl<K> Meter<K, u64> for FileSize {
+/// Given a tuple of (path, entry), use the file size for measurement.
+impl<K> Meter<K, Entry> for FileSize {
     type Measure = usize;
-    fn measure<Q: ?Sized>(&self, _: &Q, v: &u64) -> usize
+    fn measure<Q: ?Sized>(&self, _: &Q, v: &Entry) -> usize
     where
         K: Borrow<Q>,
     {
-        *v as usize
+        v.size as usize
     }
 }
 
@@ -61,12 +74,71 @@ fn get_all_files<P: AsRef<Path>>(path: P) -> Box<dyn Iterator<Item = (PathBuf, u
     Box::new(files.into_iter().map(|(_mtime, path, size)| (path, size)))
 }
 
+/// Remove `file` if it is a temporary file left behind by an insertion,
+/// returning whether it was one.
+fn is_tempfile(file: &Path) -> bool {
+    if !file
+        .file_name()
+        .expect("Bad path?")
+        .starts_with(TEMPFILE_PREFIX)
+    {
+        return false;
+    }
+    fs::remove_file(file)
+        .unwrap_or_else(|e| error!("Error removing temporary file `{}`: {}", file.display(), e));
+    true
+}
+
+/// Remove the temporary files left behind by insertions in `dir`.
+fn remove_tempfiles(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            is_tempfile(&entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// Move the temporary `file` to `path`, copying it if they are on different
+/// filesystems.
+fn persist(file: NamedTempFile, path: &Path) -> io::Result<()> {
+    let e = match file.persist(path) {
+        Ok(_) => return Ok(()),
+        Err(e) => e,
+    };
+    // `ERROR_NOT_SAME_DEVICE`
+    let cross_device = if cfg!(windows) { 17 } else { libc::EXDEV };
+    if e.error.raw_os_error() != Some(cross_device) {
+        return Err(e.error);
+    }
+    // Copy it next to `path` first, so that it is moved there at once.
+    let mut file = e.file;
+    file.rewind()?;
+    let mut copy = tempfile::Builder::new()
+        .prefix(TEMPFILE_PREFIX)
+        .tempfile_in(path.parent().expect("Bad path?"))?;
+    io::copy(&mut file, &mut copy)?;
+    copy.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
+
 /// An LRU cache of files on disk.
 pub struct LruDiskCache<S: BuildHasher = RandomState> {
-    lru: LruCache<OsString, u64, S, FileSize>,
+    lru: LruCache<OsString, Entry, S, FileSize>,
     root: PathBuf,
+    capacity: u64,
     pending: Vec<OsString>,
     pending_size: u64,
+    /// Counts the uses of the files, ordering them across the shards of a
+    /// `ShardedLruDiskCache`.
+    clock: Arc<AtomicU64>,
+    /// Whether inserting files lets the cache grow past its capacity, the
+    /// files being evicted by the sweeps of a `ShardedLruDiskCache` instead.
+    deferred_eviction: bool,
+    /// Where the files being inserted are written, the root of the cache if
+    /// `None`.
+    temp_dir: Option<PathBuf>,
 }
 
 /// Errors returned by this crate.
@@ -144,13 +216,27 @@ impl LruDiskCache {
     where
         PathBuf: From<T>,
     {
+        Self::empty(PathBuf::from(path), size, Arc::default()).init()
+    }
+
+    /// Create an `LruDiskCache` without looking for the existing files.
+    fn empty(root: PathBuf, capacity: u64, clock: Arc<AtomicU64>) -> Self {
         LruDiskCache {
-            lru: LruCache::with_meter(size, FileSize),
-            root: PathBuf::from(path),
+            // The entries are only evicted by `make_space` and `evict_lru`,
+            // which remove their files too.
+            lru: LruCache::with_meter(u64::MAX, FileSize),
+            root,
+            capacity,
             pending: vec![],
             pending_size: 0,
+            clock,
+            deferred_eviction: false,
+            temp_dir: None,
         }
-        .init()
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed)
     }
 
     /// Return the current size of all the files in the cache.
@@ -167,9 +253,14 @@ impl LruDiskCache {
         self.lru.len() == 0
     }
 
+    /// Return the keys of all the entries in the cache.
+    pub fn keys(&self) -> impl Iterator<Item = &OsStr> {
+        self.lru.iter().map(|(k, _)| k.as_os_str())
+    }
+
     /// Return the maximum size of the cache.
     pub fn capacity(&self) -> u64 {
-        self.lru.capacity()
+        self.capacity
     }
 
     /// Return the path in which the cache is stored.
@@ -186,38 +277,41 @@ impl LruDiskCache {
     fn init(mut self) -> Result<Self> {
         fs::create_dir_all(&self.root)?;
         for (file, size) in get_all_files(&self.root) {
-            if file
-                .file_name()
-                .expect("Bad path?")
-                .starts_with(TEMPFILE_PREFIX)
-            {
-                fs::remove_file(&file).unwrap_or_else(|e| {
-                    error!("Error removing temporary file `{}`: {}", file.display(), e)
-                });
-            } else if !self.can_store(size) {
-                fs::remove_file(file).unwrap_or_else(|e| {
-                    error!(
-                        "Error removing file `{}` which is too large for the cache ({} bytes)",
-                        e, size
-                    )
-                });
-            } else {
-                self.add_file(AddFile::AbsPath(file), size)
-                    .unwrap_or_else(|e| error!("Error adding file: {}", e));
+            if !is_tempfile(&file) {
+                self.adopt(file, size);
             }
         }
         Ok(self)
     }
 
+    /// Store the existing file `file` of `size` bytes, or remove it if it is
+    /// too large for the cache.
+    fn adopt(&mut self, file: PathBuf, size: u64) {
+        if !self.can_store(size) {
+            fs::remove_file(file).unwrap_or_else(|e| {
+                error!(
+                    "Error removing file `{}` which is too large for the cache ({} bytes)",
+                    e, size
+                )
+            });
+        } else {
+            self.add_file(AddFile::AbsPath(file), size)
+                .unwrap_or_else(|e| error!("Error adding file: {}", e));
+        }
+    }
+
     /// Returns `true` if the disk cache can store a file of `size` bytes.
     pub fn can_store(&self, size: u64) -> bool {
-        size <= self.lru.capacity()
+        size <= self.capacity
     }
 
     fn make_space(&mut self, size: u64) -> Result<()> {
         if !self.can_store(size) {
             return Err(Error::FileTooLarge);
         }
+        if self.deferred_eviction {
+            return Ok(());
+        }
         //TODO: ideally LRUCache::insert would give us back the entries it had to remove.
         while self.size() + size > self.capacity() {
             let (rel_path, _) = self.lru.remove_lru().expect("Unexpectedly empty cache!");
@@ -238,7 +332,14 @@ impl LruDiskCache {
             AddFile::RelPath(p) => p,
         };
         self.make_space(size)?;
-        self.lru.insert(rel_path.to_owned(), size);
+        let tick = self.tick();
+        self.lru.insert(
+            rel_path.to_owned(),
+            Entry {
+                size,
+                tick: Cell::new(tick),
+            },
+        );
         Ok(())
     }
 
@@ -321,7 +422,7 @@ impl LruDiskCache {
         self.pending_size += size;
         tempfile::Builder::new()
             .prefix(TEMPFILE_PREFIX)
-            .tempfile_in(&self.root)
+            .tempfile_in(self.temp_dir.as_ref().unwrap_or(&self.root))
             .map(|file| LruDiskCacheAddEntry { file, key, size })
             .map_err(Into::into)
     }
@@ -346,11 +447,27 @@ impl LruDiskCache {
         self.pending_size -= size;
         let path = self.rel_to_abs_path(&key);
         fs::create_dir_all(path.parent().unwrap())?;
-        file.persist(path).map_err(|e| e.error)?;
-        self.lru.insert(key, real_size);
+        persist(file, &path)?;
+        let tick = self.tick();
+        self.lru.insert(
+            key,
+            Entry {
+                size: real_size,
+                tick: Cell::new(tick),
+            },
+        );
         Ok(())
     }
 
+    /// Drop an entry coming from `LruDiskCache::prepare_add` without adding it
+    /// to the cache, e.g. because writing it failed.
+    pub fn discard(&mut self, entry: LruDiskCacheAddEntry) {
+        if let Some(i) = self.pending.iter().position(|k| k == &entry.key) {
+            self.pending.remove(i);
+            self.pending_size -= entry.size;
+        }
+    }
+
     /// Return `true` if a file with path `key` is in the cache. Entries created
     /// by `LruDiskCache::prepare_add` but not yet committed return `false`.
     pub fn contains_key<K: AsRef<OsStr>>(&self, key: K) -> bool {
@@ -364,10 +481,12 @@ impl LruDiskCache {
     pub fn get_file<K: AsRef<OsStr>>(&mut self, key: K) -> Result<File> {
         let rel_path = key.as_ref();
         let path = self.rel_to_abs_path(rel_path);
+        let tick = self.tick();
         self.lru
             .get(rel_path)
             .ok_or(Error::FileNotInCache)
-            .and_then(|_| {
+            .and_then(|entry| {
+                entry.tick.set(tick);
                 let t = FileTime::now();
                 set_file_times(&path, t, t)?;
                 File::open(path).map_err(Into::into)
@@ -382,6 +501,29 @@ impl LruDiskCache {
         self.get_file(key).map(|f| Box::new(f) as Box<dyn ReadSeek>)
     }
 
+    /// Return when the least recently used entry was last used.
+    fn lru_tick(&self) -> Option<u64> {
+        self.lru.iter().next().map(|(_, entry)| entry.tick.get())
+    }
+
+    /// Drop the least recently used entry, leaving its file to be removed with
+    /// `LruDiskCache::remove_evicted`, and return its key.
+    fn evict_lru(&mut self) -> Option<OsString> {
+        self.lru.remove_lru().map(|(key, _)| key)
+    }
+
+    /// Remove the file of the entry `key` evicted by `evict_lru`, unless it
+    /// was added back since.
+    fn remove_evicted(&mut self, key: &OsStr) -> Result<()> {
+        if self.lru.contains_key(key) || self.pending.iter().any(|k| k == key) {
+            return Ok(());
+        }
+        match fs::remove_file(self.rel_to_abs_path(key)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
     /// Remove the given key from the cache.
     pub fn remove<K: AsRef<OsStr>>(&mut self, key: K) -> Result<()> {
         match self.lru.remove(key.as_ref()) {
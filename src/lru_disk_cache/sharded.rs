@@ -0,0 +1,469 @@
+//! An LRU cache of files on disk split into shards, each with its own lock, so
+//! that the operations on different keys don't wait for each other.
+
+use std::collections::hash_map::DefaultHasher;
+use std::ffi::{OsStr, OsString};
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use fs_err as fs;
+
+use super::{
+    get_all_files, is_tempfile, remove_tempfiles, LruDiskCache, LruDiskCacheAddEntry, ReadSeek,
+    Result,
+};
+
+/// The default number of shards.
+pub const DEFAULT_SHARDS: usize = 16;
+
+/// An LRU cache of files on disk, split into shards of the keys.
+///
+/// The files are evicted in sweeps rather than on insertion, see
+/// `ShardedLruDiskCache::start_sweep`, across the shards, the least recently
+/// used first.
+pub struct ShardedLruDiskCache {
+    shards: Vec<Mutex<LruDiskCache>>,
+    /// The size of the files in each shard, updated under the lock of the
+    /// shard, so that the size of the cache can be read without taking them.
+    sizes: Vec<AtomicU64>,
+    root: PathBuf,
+    capacity: u64,
+    /// The sizes from which a sweep evicts entries and down to which it does.
+    watermarks: (u64, u64),
+    sweeping: AtomicBool,
+    /// How many entries were evicted, and their size.
+    evictions: AtomicU64,
+    evicted_bytes: AtomicU64,
+}
+
+impl ShardedLruDiskCache {
+    /// Create a `ShardedLruDiskCache` of `shards` shards that stores files in
+    /// `path`, limited to `size` bytes, like `LruDiskCache::new`.
+    ///
+    /// The files are evicted once the cache is larger than `size`, until it is
+    /// down to 90% of it, see `ShardedLruDiskCache::with_watermarks`.
+    pub fn new<T>(path: T, size: u64, shards: usize) -> Result<Self>
+    where
+        PathBuf: From<T>,
+    {
+        let root = PathBuf::from(path);
+        let clock = Arc::<AtomicU64>::default();
+        let mut cache = ShardedLruDiskCache {
+            shards: (0..shards.max(1))
+                .map(|_| {
+                    let mut shard = LruDiskCache::empty(root.clone(), size, clock.clone());
+                    shard.deferred_eviction = true;
+                    Mutex::new(shard)
+                })
+                .collect(),
+            sizes: (0..shards.max(1)).map(|_| AtomicU64::new(0)).collect(),
+            root,
+            capacity: size,
+            watermarks: (0, 0),
+            sweeping: AtomicBool::new(false),
+            evictions: AtomicU64::new(0),
+            evicted_bytes: AtomicU64::new(0),
+        }
+        .with_watermarks(100, 90);
+        // Scan the files once, from the oldest, so that the shards agree on
+        // which ones were used last.
+        fs::create_dir_all(&cache.root)?;
+        for (file, size) in get_all_files(&cache.root) {
+            if is_tempfile(&file) {
+                continue;
+            }
+            let key = file
+                .strip_prefix(&cache.root)
+                .expect("Bad path?")
+                .to_owned();
+            let shard = cache.shard_of(key.as_os_str());
+            cache.shards[shard].get_mut().unwrap().adopt(file, size);
+        }
+        for (shard, size) in cache.shards.iter_mut().zip(&cache.sizes) {
+            size.store(shard.get_mut().unwrap().size(), Ordering::Relaxed);
+        }
+        Ok(cache)
+    }
+
+    /// Evict files once the cache is larger than `high` percent of its
+    /// capacity, until it is down to `low` percent of it.
+    pub fn with_watermarks(mut self, high: u8, low: u8) -> Self {
+        let percent = |p: u8| (self.capacity as u128 * p as u128 / 100) as u64;
+        self.watermarks = (percent(high), percent(low));
+        self
+    }
+
+    /// Write the files being inserted in `dir` rather than in the cache, e.g.
+    /// because it is on a faster disk, removing the ones left behind in it.
+    pub fn with_temp_dir(mut self, dir: PathBuf) -> Result<Self> {
+        fs::create_dir_all(&dir)?;
+        remove_tempfiles(&dir)?;
+        for shard in &mut self.shards {
+            shard.get_mut().unwrap().temp_dir = Some(dir.clone());
+        }
+        Ok(self)
+    }
+
+    fn shard_of(&self, key: &OsStr) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() % self.shards.len() as u64) as usize
+    }
+
+    /// Run `f` on the shard of `key`.
+    fn with_shard<T>(&self, key: &OsStr, f: impl FnOnce(&mut LruDiskCache) -> T) -> T {
+        let i = self.shard_of(key);
+        let mut shard = self.shards[i].lock().unwrap();
+        let res = f(&mut shard);
+        self.sizes[i].store(shard.size(), Ordering::Relaxed);
+        res
+    }
+
+    /// Return the current size of all the files in the cache.
+    pub fn size(&self) -> u64 {
+        self.sizes.iter().map(|s| s.load(Ordering::Relaxed)).sum()
+    }
+
+    /// Return the count of entries in the cache.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.lock().unwrap().len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Return the keys of all the entries in the cache.
+    pub fn keys(&self) -> Vec<OsString> {
+        self.shards
+            .iter()
+            .flat_map(|s| {
+                let shard = s.lock().unwrap();
+                shard.keys().map(ToOwned::to_owned).collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Return the maximum size of the cache.
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// Return the path in which the cache is stored.
+    pub fn path(&self) -> &Path {
+        self.root.as_path()
+    }
+
+    /// Return the sizes from which a sweep evicts entries and down to which
+    /// it does.
+    pub fn watermarks(&self) -> (u64, u64) {
+        self.watermarks
+    }
+
+    /// Return whether a sweep is running.
+    pub fn is_sweeping(&self) -> bool {
+        self.sweeping.load(Ordering::Relaxed)
+    }
+
+    /// Return how many entries were evicted since the cache was opened, and
+    /// their total size.
+    pub fn evictions(&self) -> (u64, u64) {
+        (
+            self.evictions.load(Ordering::Relaxed),
+            self.evicted_bytes.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Prepare the insertion of a file at path `key`, see
+    /// `LruDiskCache::prepare_add`.
+    pub fn prepare_add<K: AsRef<OsStr>>(&self, key: K, size: u64) -> Result<LruDiskCacheAddEntry> {
+        self.with_shard(key.as_ref(), |shard| shard.prepare_add(key.as_ref(), size))
+    }
+
+    /// Commit an entry coming from `ShardedLruDiskCache::prepare_add`.
+    pub fn commit(&self, entry: LruDiskCacheAddEntry) -> Result<()> {
+        let key = entry.key.clone();
+        self.with_shard(&key, |shard| shard.commit(entry))
+    }
+
+    /// Drop an entry coming from `ShardedLruDiskCache::prepare_add`, see
+    /// `LruDiskCache::discard`.
+    pub fn discard(&self, entry: LruDiskCacheAddEntry) {
+        let key = entry.key.clone();
+        self.with_shard(&key, |shard| shard.discard(entry))
+    }
+
+    /// Get an opened `File` for `key`, see `LruDiskCache::get_file`.
+    pub fn get_file<K: AsRef<OsStr>>(&self, key: K) -> Result<fs::File> {
+        self.with_shard(key.as_ref(), |shard| shard.get_file(key.as_ref()))
+    }
+
+    /// Get an opened readable and seekable handle to the file at `key`, see
+    /// `LruDiskCache::get`.
+    pub fn get<K: AsRef<OsStr>>(&self, key: K) -> Result<Box<dyn ReadSeek>> {
+        self.with_shard(key.as_ref(), |shard| shard.get(key.as_ref()))
+    }
+
+    /// Remove the entry `key` and its file, see `LruDiskCache::remove`.
+    pub fn remove<K: AsRef<OsStr>>(&self, key: K) -> Result<()> {
+        self.with_shard(key.as_ref(), |shard| shard.remove(key.as_ref()))
+    }
+
+    /// Start a sweep if the cache grew past its high watermark and no sweep is
+    /// running already, evicting the least recently used entries across the
+    /// shards until the cache is down to its low watermark.
+    ///
+    /// The evicted entries are dropped from the cache right away, and their
+    /// keys returned so that their files can be removed one at a time with
+    /// `ShardedLruDiskCache::remove_evicted`, without blocking the other
+    /// operations for the whole sweep. `ShardedLruDiskCache::finish_sweep`
+    /// must be called once they are.
+    pub fn start_sweep(&self) -> Option<Vec<OsString>> {
+        let (high_water, low_water) = self.watermarks;
+        if self.size() <= high_water
+            || self
+                .sweeping
+                .compare_exchange(false, true, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+        {
+            return None;
+        }
+        Some(self.evict_down_to(low_water))
+    }
+
+    /// Evict the least recently used entries until `bytes` bytes are freed,
+    /// e.g. because the disk is full, whether the cache grew past its high
+    /// watermark or not.
+    ///
+    /// Like with `ShardedLruDiskCache::start_sweep`, the files of the
+    /// returned keys must then be removed with
+    /// `ShardedLruDiskCache::remove_evicted`.
+    pub fn evict(&self, bytes: u64) -> Vec<OsString> {
+        self.evict_down_to(self.size().saturating_sub(bytes))
+    }
+
+    /// Evict the least recently used entries across the shards until the
+    /// cache is down to `target` bytes, and return their keys.
+    fn evict_down_to(&self, target: u64) -> Vec<OsString> {
+        // Take all the locks, in order, for the evicted entries to be the
+        // least recently used ones of the whole cache.
+        let mut shards: Vec<MutexGuard<'_, LruDiskCache>> =
+            self.shards.iter().map(|s| s.lock().unwrap()).collect();
+        let start: u64 = shards.iter().map(|s| s.size()).sum();
+        let mut size = start;
+        let mut evicted = vec![];
+        while size > target {
+            let Some((oldest, _)) = shards
+                .iter()
+                .enumerate()
+                .filter_map(|(i, s)| Some((i, s.lru_tick()?)))
+                .min_by_key(|&(_, tick)| tick)
+            else {
+                break;
+            };
+            let before = shards[oldest].size();
+            evicted.extend(shards[oldest].evict_lru());
+            size -= before - shards[oldest].size();
+        }
+        for (shard, size) in shards.iter().zip(&self.sizes) {
+            size.store(shard.size(), Ordering::Relaxed);
+        }
+        self.evictions
+            .fetch_add(evicted.len() as u64, Ordering::Relaxed);
+        self.evicted_bytes
+            .fetch_add(start - size, Ordering::Relaxed);
+        evicted
+    }
+
+    /// Remove the file of the entry `key` evicted by `start_sweep`, unless it
+    /// was added back since.
+    pub fn remove_evicted(&self, key: &OsStr) -> Result<()> {
+        self.with_shard(key, |shard| shard.remove_evicted(key))
+    }
+
+    /// Mark the sweep started by `start_sweep` as done.
+    pub fn finish_sweep(&self) {
+        self.sweeping.store(false, Ordering::Relaxed);
+    }
+
+    /// Returns `true` if the disk cache can store a file of `size` bytes.
+    pub fn can_store(&self, size: u64) -> bool {
+        size <= self.capacity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ShardedLruDiskCache;
+
+    use std::ffi::OsString;
+    use std::io::Write;
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    fn insert(c: &ShardedLruDiskCache, key: &str, size: usize) {
+        let mut entry = c.prepare_add(key, size as u64).unwrap();
+        entry.as_file_mut().write_all(&vec![0; size]).unwrap();
+        c.commit(entry).unwrap();
+    }
+
+    fn keys(keys: &[&str]) -> Vec<OsString> {
+        keys.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn test_sweep_across_shards() {
+        let dir = TempDir::new().unwrap();
+        let c = ShardedLruDiskCache::new(dir.path(), 30, 4).unwrap();
+        for name in ["file1", "file2", "file3"] {
+            insert(&c, name, 10);
+        }
+        assert!(c.start_sweep().is_none());
+        // The cache grows past its capacity until it is swept.
+        insert(&c, "file4", 10);
+        assert_eq!(c.size(), 40);
+        assert!(dir.path().join("file1").exists());
+
+        // Using a file makes it the most recently used one.
+        c.get("file1").unwrap();
+        let evicted = c.start_sweep().unwrap();
+        assert_eq!(evicted, keys(&["file2", "file3"]));
+        assert_eq!(c.size(), 20);
+        assert!(c.is_sweeping());
+        // A single sweep runs at a time.
+        insert(&c, "file5", 20);
+        assert!(c.start_sweep().is_none());
+        // An entry added back during the sweep is kept.
+        insert(&c, "file3", 10);
+        for key in &evicted {
+            c.remove_evicted(key).unwrap();
+        }
+        c.finish_sweep();
+        assert!(!c.is_sweeping());
+        assert!(!dir.path().join("file2").exists());
+        assert!(dir.path().join("file3").exists());
+        assert_eq!(c.len(), 4);
+        assert_eq!(c.size(), 50);
+        assert_eq!(c.start_sweep().unwrap(), keys(&["file4", "file1", "file5"]));
+    }
+
+    #[test]
+    fn test_watermarks() {
+        let dir = TempDir::new().unwrap();
+        let c = ShardedLruDiskCache::new(dir.path(), 100, 4)
+            .unwrap()
+            .with_watermarks(80, 50);
+        assert_eq!(c.watermarks(), (80, 50));
+        for i in 0..8 {
+            insert(&c, &format!("file{}", i), 10);
+        }
+        assert!(c.start_sweep().is_none());
+        insert(&c, "file8", 10);
+        // The sweep evicts a batch of entries, not just one.
+        assert_eq!(
+            c.start_sweep().unwrap(),
+            keys(&["file0", "file1", "file2", "file3"])
+        );
+        assert_eq!(c.size(), 50);
+        // The sweeps count as evictions too.
+        assert_eq!(c.evictions(), (4, 40));
+        c.finish_sweep();
+        // No sweep until the high watermark is crossed again.
+        for i in 9..12 {
+            insert(&c, &format!("file{}", i), 10);
+        }
+        assert!(c.start_sweep().is_none());
+    }
+
+    #[test]
+    fn test_evict() {
+        let dir = TempDir::new().unwrap();
+        let c = ShardedLruDiskCache::new(dir.path(), 100, 4).unwrap();
+        for i in 0..5 {
+            insert(&c, &format!("file{}", i), 10);
+        }
+        // Unlike sweeps, evicting doesn't wait for the cache to be full.
+        assert!(c.start_sweep().is_none());
+        assert_eq!(c.evictions(), (0, 0));
+        assert_eq!(c.evict(15), keys(&["file0", "file1"]));
+        assert_eq!(c.size(), 30);
+        assert_eq!(c.evictions(), (2, 20));
+        assert_eq!(c.evict(100), keys(&["file2", "file3", "file4"]));
+        assert!(c.is_empty());
+        assert_eq!(c.evictions(), (5, 50));
+    }
+
+    #[test]
+    fn test_discard() {
+        let dir = TempDir::new().unwrap();
+        let c = ShardedLruDiskCache::new(dir.path(), 100, 4).unwrap();
+        let entry = c.prepare_add("file", 10).unwrap();
+        assert_eq!(c.size(), 10);
+        c.discard(entry);
+        assert_eq!(c.size(), 0);
+        assert!(c.is_empty());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_temp_dir() {
+        let dir = TempDir::new().unwrap();
+        let (root, temp) = (dir.path().join("cache"), dir.path().join("tmp"));
+        std::fs::create_dir(&temp).unwrap();
+        std::fs::write(temp.join(".sccachetmp-stale"), "").unwrap();
+        let c = ShardedLruDiskCache::new(&root, 100, 4)
+            .unwrap()
+            .with_temp_dir(temp.clone())
+            .unwrap();
+        // The leftovers of a previous server are removed.
+        assert!(!temp.join(".sccachetmp-stale").exists());
+
+        let mut entry = c.prepare_add("a/file", 10).unwrap();
+        entry.as_file_mut().write_all(&[0; 10]).unwrap();
+        assert_eq!(std::fs::read_dir(&temp).unwrap().count(), 1);
+        assert!(!root.join("a").exists());
+        c.commit(entry).unwrap();
+        assert_eq!(std::fs::read_dir(&temp).unwrap().count(), 0);
+        assert_eq!(std::fs::read(root.join("a/file")).unwrap(), [0; 10]);
+    }
+
+    #[test]
+    fn test_existing_files() {
+        let dir = TempDir::new().unwrap();
+        {
+            let c = ShardedLruDiskCache::new(dir.path(), 100, 4).unwrap();
+            for i in 0..5 {
+                insert(&c, &format!("a/file{}", i), 10);
+            }
+        }
+        let c = ShardedLruDiskCache::new(dir.path(), 100, 8).unwrap();
+        assert_eq!(c.len(), 5);
+        assert_eq!(c.size(), 50);
+        for i in 0..5 {
+            c.get(format!("a/file{}", i)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_concurrent_inserts() {
+        let dir = TempDir::new().unwrap();
+        let c = Arc::new(ShardedLruDiskCache::new(dir.path(), u64::MAX, 4).unwrap());
+        let threads: Vec<_> = (0..8)
+            .map(|t| {
+                let c = c.clone();
+                std::thread::spawn(move || {
+                    for i in 0..50 {
+                        insert(&c, &format!("{}/{}", t, i), 3);
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(c.len(), 400);
+        assert_eq!(c.size(), 1200);
+    }
+}
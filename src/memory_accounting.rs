@@ -0,0 +1,90 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The accounting of the memory the compilers take, enabled with
+//! `SCCACHE_MEMORY_ACCOUNTING=1`.
+//!
+//! The peak resident set size of a compilation is the largest of those of
+//! the processes it spawned, e.g. the preprocessor and the compiler, each
+//! including the processes it waited for, e.g. `cc1` for `gcc`. It is read
+//! when a process exits, on Linux from the resources the kernel reports for
+//! it, and on Windows from its job object, which is the peak memory committed
+//! rather than resident. It isn't measured elsewhere.
+//!
+//! When disabled, the processes are waited for as usual, and nothing is
+//! measured.
+
+use once_cell::sync::Lazy;
+use std::env;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Whether `SCCACHE_MEMORY_ACCOUNTING` is set to `1`.
+static ENABLED: Lazy<bool> =
+    Lazy::new(|| env::var("SCCACHE_MEMORY_ACCOUNTING").is_ok_and(|v| v == "1"));
+
+tokio::task_local! {
+    static PEAK_RSS: Arc<AtomicU64>;
+}
+
+/// Whether the processes spawned by the current task are measured.
+pub fn is_accounting() -> bool {
+    PEAK_RSS.try_with(|_| ()).is_ok()
+}
+
+/// Record that a process spawned by the current task peaked at `bytes`.
+pub fn record_peak_rss(bytes: u64) {
+    let _ = PEAK_RSS.try_with(|peak| peak.fetch_max(bytes, Ordering::Relaxed));
+}
+
+/// Run `task`, measuring the processes it spawns if the accounting is
+/// enabled. Along with its output comes the largest peak RSS of those
+/// processes in bytes, if any was measured.
+pub async fn account<F: Future>(task: F) -> (F::Output, Option<u64>) {
+    if !*ENABLED {
+        return (task.await, None);
+    }
+    measure(task).await
+}
+
+/// Run `task`, measuring the processes it spawns whether or not the
+/// accounting is enabled, see `account`.
+pub async fn measure<F: Future>(task: F) -> (F::Output, Option<u64>) {
+    let peak = Arc::new(AtomicU64::new(0));
+    let output = PEAK_RSS.scope(peak.clone(), task).await;
+    let peak = peak.load(Ordering::Relaxed);
+    (output, Some(peak).filter(|&peak| peak > 0))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_account() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        assert!(!is_accounting());
+        // Nothing is recorded outside of an accounted task.
+        record_peak_rss(1);
+        let ((), peak) = runtime.block_on(measure(async {
+            assert!(is_accounting());
+            record_peak_rss(3 << 20);
+            record_peak_rss(1 << 20);
+        }));
+        assert_eq!(peak, Some(3 << 20));
+        let ((), peak) = runtime.block_on(measure(async {}));
+        assert_eq!(peak, None);
+    }
+}
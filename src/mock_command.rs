@@ -45,8 +45,10 @@
 //! then create an `Arc<Mutex<MockCommandCreator>>` and safely provide
 //! `MockChild` outputs.
 
+use crate::affinity;
 use crate::errors::*;
 use crate::jobserver::{Acquired, Client};
+use crate::memory_accounting;
 use async_trait::async_trait;
 use std::boxed::Box;
 use std::ffi::{OsStr, OsString};
@@ -55,9 +57,16 @@ use std::io;
 use std::path::Path;
 use std::process::{Command, ExitStatus, Output, Stdio};
 use std::sync::{Arc, Mutex};
-use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(unix)]
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
 use tokio::process::{ChildStderr, ChildStdin, ChildStdout};
 
+/// The variable set to their program in the environment of the processes
+/// sccache spawns, so that sccache can tell when it was spawned as a compiler
+/// by itself.
+pub const SPAWNED_PROGRAM_ENV_VAR: &str = "SCCACHE_SPAWNED_PROGRAM";
+
 /// A trait that provides a subset of the methods of `std::process::Child`.
 #[async_trait]
 pub trait CommandChild {
@@ -141,8 +150,213 @@ pub trait CommandCreatorSync: Clone + Send + Sync + 'static {
 }
 
 pub struct Child {
-    inner: tokio::process::Child,
-    token: Acquired,
+    /// `None` once handed to its group to be killed, when it is dropped.
+    inner: Option<tokio::process::Child>,
+    /// The token of the jobserver, released once the child is dropped.
+    _token: Acquired,
+    group: Option<ProcessGroup>,
+    /// Whether its peak RSS is recorded when it exits, see
+    /// `memory_accounting`.
+    accounted: bool,
+}
+
+/// How long the processes of an interrupted compiler have to exit after
+/// `SIGTERM` before they are killed.
+#[cfg(unix)]
+const KILL_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The processes of a compiler, so that they can be killed along with it if
+/// it is dropped before it exits, e.g. when the client waiting for it
+/// disconnects. On Unix this is the process group of the compiler, on
+/// Windows a job object.
+struct ProcessGroup {
+    #[cfg(unix)]
+    pgid: libc::pid_t,
+    #[cfg(windows)]
+    job: winapi::um::winnt::HANDLE,
+    #[cfg(windows)]
+    exited: bool,
+}
+
+// The handle of a job object can be used from any thread.
+#[cfg(windows)]
+unsafe impl Send for ProcessGroup {}
+#[cfg(windows)]
+unsafe impl Sync for ProcessGroup {}
+
+impl ProcessGroup {
+    /// The group of `child`, spawned as the leader of its own process group.
+    #[cfg(unix)]
+    fn new(child: &tokio::process::Child) -> Option<ProcessGroup> {
+        Some(ProcessGroup {
+            pgid: child.id()? as libc::pid_t,
+        })
+    }
+
+    /// A new job object holding `child`, and the processes it starts.
+    #[cfg(windows)]
+    fn new(child: &tokio::process::Child) -> Option<ProcessGroup> {
+        use winapi::um::handleapi::CloseHandle;
+        use winapi::um::jobapi2::{AssignProcessToJobObject, CreateJobObjectW};
+
+        let handle = child.raw_handle()?;
+        unsafe {
+            let job = CreateJobObjectW(std::ptr::null_mut(), std::ptr::null());
+            if job.is_null() {
+                return None;
+            }
+            if AssignProcessToJobObject(job, handle as _) == 0 {
+                CloseHandle(job);
+                return None;
+            }
+            Some(ProcessGroup { job, exited: false })
+        }
+    }
+
+    /// The compiler exited, leave alone the processes it left running, e.g.
+    /// the PDB server of MSVC.
+    #[cfg(unix)]
+    fn exited(self) {}
+
+    #[cfg(windows)]
+    fn exited(mut self) {
+        self.exited = true;
+    }
+
+    /// The compiler `leader` is dropped before it exited, interrupt the
+    /// processes of the group and kill those still running after
+    /// `KILL_TIMEOUT`. The leader is only reaped once they are killed: until
+    /// then its pid, which is the id of the group, can't be reused.
+    #[cfg(unix)]
+    fn interrupt(self, mut leader: tokio::process::Child) {
+        // Give the compiler a chance to delete its partial outputs.
+        let pgid = self.pgid;
+        unsafe { libc::killpg(pgid, libc::SIGTERM) };
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            handle.spawn(async move {
+                tokio::time::sleep(KILL_TIMEOUT).await;
+                unsafe { libc::killpg(pgid, libc::SIGKILL) };
+                let _ = leader.wait().await;
+            });
+        }
+    }
+
+    /// Dropping the job terminates its processes.
+    #[cfg(windows)]
+    fn interrupt(self, _leader: tokio::process::Child) {}
+
+    /// The peak memory committed by the processes of the job.
+    #[cfg(windows)]
+    fn peak_memory(&self) -> Option<u64> {
+        use winapi::um::jobapi2::QueryInformationJobObject;
+        use winapi::um::winnt::{
+            JobObjectExtendedLimitInformation, JOBOBJECT_EXTENDED_LIMIT_INFORMATION,
+        };
+
+        unsafe {
+            let mut info: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = std::mem::zeroed();
+            let ok = QueryInformationJobObject(
+                self.job,
+                JobObjectExtendedLimitInformation,
+                &mut info as *mut _ as *mut _,
+                std::mem::size_of_val(&info) as u32,
+                std::ptr::null_mut(),
+            );
+            (ok != 0).then_some(info.PeakJobMemoryUsed as u64)
+        }
+    }
+}
+
+/// Wait for the process `pid` to exit, leaving it to be reaped, and get the
+/// peak RSS of it and of the processes it waited for, in bytes.
+#[cfg(target_os = "linux")]
+fn peak_rss_at_exit(pid: u32) -> io::Result<u64> {
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    loop {
+        // Unlike `waitid` of libc, the system call reports the resources
+        // used, and with `WNOWAIT` tokio still reaps the process.
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_waitid,
+                libc::P_PID,
+                pid,
+                &mut info as *mut libc::siginfo_t,
+                libc::WEXITED | libc::WNOWAIT,
+                &mut usage as *mut libc::rusage,
+            )
+        };
+        if ret == 0 {
+            // In KiB.
+            return Ok(usage.ru_maxrss as u64 * 1024);
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+/// Wait for `inner` to exit, recording its peak RSS.
+async fn wait_accounted(
+    inner: &mut tokio::process::Child,
+    group: Option<&ProcessGroup>,
+) -> io::Result<ExitStatus> {
+    #[cfg(target_os = "linux")]
+    if let Some(pid) = inner.id() {
+        match tokio::task::spawn_blocking(move || peak_rss_at_exit(pid)).await {
+            Ok(Ok(bytes)) => memory_accounting::record_peak_rss(bytes),
+            Ok(Err(e)) => debug!("Failed to measure the process {}: {}", pid, e),
+            Err(e) => debug!("Failed to measure the process {}: {}", pid, e),
+        }
+    }
+    let status = inner.wait().await?;
+    #[cfg(windows)]
+    if let Some(bytes) = group.and_then(ProcessGroup::peak_memory) {
+        memory_accounting::record_peak_rss(bytes);
+    }
+    #[cfg(not(windows))]
+    let _ = group;
+    Ok(status)
+}
+
+async fn read_to_end(pipe: Option<impl AsyncRead + Unpin>) -> io::Result<Vec<u8>> {
+    let mut buf = vec![];
+    if let Some(mut pipe) = pipe {
+        pipe.read_to_end(&mut buf).await?;
+    }
+    Ok(buf)
+}
+
+#[cfg(windows)]
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        use winapi::um::handleapi::CloseHandle;
+        use winapi::um::jobapi2::TerminateJobObject;
+
+        unsafe {
+            if !self.exited {
+                TerminateJobObject(self.job, 1);
+            }
+            CloseHandle(self.job);
+        }
+    }
+}
+
+impl Child {
+    fn inner(&mut self) -> &mut tokio::process::Child {
+        self.inner
+            .as_mut()
+            .expect("the child is only taken when dropped")
+    }
+}
+
+impl Drop for Child {
+    fn drop(&mut self) {
+        if let (Some(group), Some(inner)) = (self.group.take(), self.inner.take()) {
+            group.interrupt(inner);
+        }
+    }
 }
 
 /// Trivial implementation of `CommandChild` for `std::process::Child`.
@@ -153,27 +367,63 @@ impl CommandChild for Child {
     type E = ChildStderr;
 
     fn take_stdin(&mut self) -> Option<ChildStdin> {
-        self.inner.stdin.take()
+        self.inner().stdin.take()
     }
     fn take_stdout(&mut self) -> Option<ChildStdout> {
-        self.inner.stdout.take()
+        self.inner().stdout.take()
     }
     fn take_stderr(&mut self) -> Option<ChildStderr> {
-        self.inner.stderr.take()
+        self.inner().stderr.take()
     }
 
-    async fn wait(self) -> io::Result<ExitStatus> {
-        let Child { mut inner, token } = self;
-        inner.wait().await.map(|ret| {
-            drop(token);
+    async fn wait(mut self) -> io::Result<ExitStatus> {
+        // The child is kept in `self` while waiting, so that it is killed if
+        // the wait is dropped.
+        let inner = self
+            .inner
+            .as_mut()
+            .expect("the child is only taken when dropped");
+        let status = if self.accounted {
+            wait_accounted(inner, self.group.as_ref()).await
+        } else {
+            inner.wait().await
+        };
+        status.map(|ret| {
+            if let Some(group) = self.group.take() {
+                group.exited();
+            }
             ret
         })
     }
 
-    async fn wait_with_output(self) -> io::Result<Output> {
-        let Child { inner, token } = self;
-        inner.wait_with_output().await.map(|ret| {
-            drop(token);
+    async fn wait_with_output(mut self) -> io::Result<Output> {
+        let inner = self
+            .inner
+            .as_mut()
+            .expect("the child is only taken when dropped");
+        // As `wait_with_output` does, reading the outputs while waiting so
+        // that the process doesn't block writing them.
+        drop(inner.stdin.take());
+        let (stdout, stderr) = (inner.stdout.take(), inner.stderr.take());
+        let group = self.group.as_ref();
+        let status = async {
+            if self.accounted {
+                wait_accounted(inner, group).await
+            } else {
+                inner.wait().await
+            }
+        };
+        let output = futures::try_join!(status, read_to_end(stdout), read_to_end(stderr)).map(
+            |(status, stdout, stderr)| Output {
+                status,
+                stdout,
+                stderr,
+            },
+        );
+        output.map(|ret| {
+            if let Some(group) = self.group.take() {
+                group.exited();
+            }
             ret
         })
     }
@@ -182,6 +432,7 @@ impl CommandChild for Child {
 pub struct AsyncCommand {
     inner: Option<Command>,
     jobserver: Client,
+    interruptible: bool,
 }
 
 impl AsyncCommand {
@@ -189,9 +440,17 @@ impl AsyncCommand {
         AsyncCommand {
             inner: Some(Command::new(program)),
             jobserver,
+            interruptible: false,
         }
     }
 
+    /// Kill the process, and those it starts, if its `Child` is dropped
+    /// before it exits.
+    pub fn interruptible(&mut self) -> &mut AsyncCommand {
+        self.interruptible = true;
+        self
+    }
+
     fn inner(&mut self) -> &mut Command {
         self.inner.as_mut().expect("can't reuse commands")
     }
@@ -253,17 +512,34 @@ impl RunCommand for AsyncCommand {
         inner.env_remove("MAKEFLAGS");
         inner.env_remove("MFLAGS");
         inner.env_remove("CARGO_MAKEFLAGS");
+        let program = inner.get_program().to_owned();
+        inner.env(SPAWNED_PROGRAM_ENV_VAR, program);
         self.jobserver.configure(&mut inner);
+        #[cfg(unix)]
+        if self.interruptible {
+            use std::os::unix::process::CommandExt;
+            inner.process_group(0);
+        }
+
+        affinity::configure_compiler(&mut inner);
 
         let token = self.jobserver.acquire().await?;
         let mut inner = tokio::process::Command::from(inner);
         let child = inner
             .spawn()
-            .with_context(|| format!("failed to spawn {:?}", inner))?;
+            .with_context(|| SpawnError(format!("{:?}", inner)))?;
+        affinity::pin_compiler(&child);
+        let group = if self.interruptible {
+            ProcessGroup::new(&child)
+        } else {
+            None
+        };
 
         Ok(Child {
-            inner: child,
-            token,
+            inner: Some(child),
+            _token: token,
+            group,
+            accounted: memory_accounting::is_accounting(),
         })
     }
 }
@@ -278,6 +554,16 @@ impl fmt::Debug for AsyncCommand {
 #[derive(Clone)]
 pub struct ProcessCommandCreator {
     jobserver: Client,
+    interruptible: bool,
+}
+
+impl ProcessCommandCreator {
+    /// Make the commands created `interruptible`, as the server does so that
+    /// a compiler doesn't outlive the client it compiles for.
+    pub fn interruptible(mut self) -> ProcessCommandCreator {
+        self.interruptible = true;
+        self
+    }
 }
 
 /// Trivial implementation of `CommandCreator` for `ProcessCommandCreator`.
@@ -287,11 +573,16 @@ impl CommandCreator for ProcessCommandCreator {
     fn new(client: &Client) -> ProcessCommandCreator {
         ProcessCommandCreator {
             jobserver: client.clone(),
+            interruptible: false,
         }
     }
 
     fn new_command<S: AsRef<OsStr>>(&mut self, program: S) -> AsyncCommand {
-        AsyncCommand::new(program, self.jobserver.clone())
+        let mut command = AsyncCommand::new(program, self.jobserver.clone());
+        if self.interruptible {
+            command.interruptible();
+        }
+        command
     }
 }
 
@@ -335,8 +626,11 @@ pub struct MockChild {
     pub stdout: Option<io::Cursor<Vec<u8>>>,
     /// A `Cursor` to hand out as stderr.
     pub stderr: Option<io::Cursor<Vec<u8>>>,
-    /// The `Result` to be handed out when `wait` is called.
+    /// The `Result` to be handed out when `wait` is called, or `None` to
+    /// never exit.
     pub wait_result: Option<io::Result<ExitStatus>>,
+    /// Dropped along with the child, to tell when it is killed.
+    pub alive: Option<std::sync::mpsc::Sender<()>>,
 }
 
 /// A mocked child process that simply returns stored values for its status and output.
@@ -353,6 +647,7 @@ impl MockChild {
             stdout: Some(io::Cursor::new(stdout.as_ref().to_vec())),
             stderr: Some(io::Cursor::new(stderr.as_ref().to_vec())),
             wait_result: Some(Ok(status)),
+            alive: None,
         }
     }
 
@@ -364,6 +659,20 @@ impl MockChild {
             stdout: None,
             stderr: None,
             wait_result: Some(Err(err)),
+            alive: None,
+        }
+    }
+
+    /// Create a `MockChild` that never exits, dropping `alive` once it is
+    /// killed.
+    #[allow(dead_code)]
+    pub fn running(alive: std::sync::mpsc::Sender<()>) -> MockChild {
+        MockChild {
+            stdin: Some(io::Cursor::new(vec![])),
+            stdout: Some(io::Cursor::new(vec![])),
+            stderr: Some(io::Cursor::new(vec![])),
+            wait_result: None,
+            alive: Some(alive),
         }
     }
 }
@@ -385,7 +694,10 @@ impl CommandChild for MockChild {
     }
 
     async fn wait(mut self) -> io::Result<ExitStatus> {
-        self.wait_result.take().unwrap()
+        match self.wait_result.take() {
+            Some(result) => result,
+            None => futures::future::pending().await,
+        }
     }
 
     async fn wait_with_output(self) -> io::Result<Output> {
@@ -393,10 +705,15 @@ impl CommandChild for MockChild {
             stdout,
             stderr,
             wait_result,
+            alive,
             ..
         } = self;
 
-        wait_result.unwrap().map(|status| Output {
+        let Some(wait_result) = wait_result else {
+            let _alive = alive;
+            return futures::future::pending().await;
+        };
+        wait_result.map(|status| Output {
             status,
             stdout: stdout.map(|c| c.into_inner()).unwrap_or_else(Vec::new),
             stderr: stderr.map(|c| c.into_inner()).unwrap_or_else(Vec::new),
@@ -664,4 +981,90 @@ mod test {
         );
         assert_eq!(exit_status(0), spawn_on_thread(creator, true));
     }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_interruptible_command() {
+        use tokio::io::AsyncBufReadExt;
+
+        // Whether `pid` runs, as a zombie doesn't.
+        fn is_running(pid: &str) -> bool {
+            std::fs::read_to_string(format!("/proc/{}/stat", pid))
+                .map(|stat| !stat.contains(") Z "))
+                .unwrap_or(false)
+        }
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let client = Client::new_num(1);
+        let mut creator =
+            <ProcessCommandCreator as CommandCreatorSync>::new(&client).interruptible();
+        runtime.block_on(async {
+            let mut child = creator
+                .new_command_sync("sh")
+                .args(&["-c", "sleep 60 & echo $!; wait"])
+                .stdout(Stdio::piped())
+                .spawn()
+                .await
+                .unwrap();
+            let mut stdout = tokio::io::BufReader::new(child.take_stdout().unwrap());
+            let mut pid = String::new();
+            stdout.read_line(&mut pid).await.unwrap();
+            let pid = pid.trim().to_owned();
+            let leader = child.inner().id().unwrap().to_string();
+            assert!(is_running(&pid));
+            // Dropping the child kills the processes it started too.
+            drop(child);
+            for _ in 0..100 {
+                if !is_running(&pid) && !is_running(&leader) {
+                    // Its pid isn't released before the group is killed.
+                    assert!(Path::new(&format!("/proc/{}", leader)).exists());
+                    return;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            }
+            panic!("{} or {} still runs", leader, pid);
+        });
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_peak_rss() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let client = Client::new_num(1);
+        let mut creator = <ProcessCommandCreator as CommandCreatorSync>::new(&client);
+        let (output, peak) = runtime.block_on(memory_accounting::measure(async {
+            // The shell holds the 32 MiB it reads from its child.
+            creator
+                .new_command_sync("sh")
+                .args(&[
+                    "-c",
+                    "x=$(head -c 33554432 /dev/zero | tr '\\0' a); echo ${#x}",
+                ])
+                .stdout(Stdio::piped())
+                .spawn()
+                .await
+                .unwrap()
+                .wait_with_output()
+                .await
+                .unwrap()
+        }));
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"33554432\n");
+        let peak = peak.unwrap();
+        assert!(peak > 32 << 20, "{}", peak);
+        assert!(peak < 1 << 30, "{}", peak);
+
+        // Nothing is measured outside of an accounted task.
+        let (_, peak) = runtime.block_on(memory_accounting::account(async {
+            creator
+                .new_command_sync("true")
+                .spawn()
+                .await
+                .unwrap()
+                .wait()
+                .await
+                .unwrap()
+        }));
+        assert_eq!(peak, None);
+    }
 }
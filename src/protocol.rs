@@ -1,21 +1,47 @@
-use crate::compiler::ColorMode;
+use crate::cache::EntryInfo;
+use crate::compiler::{ColorMode, HashKeyInput};
 use crate::server::{DistInfo, ServerInfo};
 use serde::{Deserialize, Serialize};
 use std::ffi::OsString;
+use std::time::SystemTime;
+
+/// The version of the protocol between the client and the server.
+///
+/// It must be bumped whenever a request or a response changes, including the
+/// `ServerInfo` and `ServerStats` returned for `GetStats`, so that programs
+/// reading the statistics can tell which fields to expect.
+pub const PROTOCOL_VERSION: u32 = 31;
 
 /// A client request.
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Request {
+    /// Authenticate the connection with a token, which must be the first
+    /// request when the server has a `SCCACHE_SERVER_TOKEN`.
+    Authenticate(String),
     /// Zero the server's statistics.
     ZeroStats,
     /// Get server statistics.
     GetStats,
     /// Get dist status.
     DistStatus,
+    /// Wait for the cache writes still running after their compilations were
+    /// answered.
+    Flush,
     /// Shut the server down gracefully.
     Shutdown,
+    /// List the entries of the cache whose keys start with the prefix.
+    ListEntries(String),
+    /// Remove the entry of the key from the cache.
+    RemoveEntry(String),
     /// Execute a compile or fetch a cached compilation result.
     Compile(Compile),
+    /// Compute the hash key of a compilation without running it, and on a
+    /// cache hit write its outputs into the directory, if any.
+    Explain(Compile, Option<OsString>),
+    /// A chunk of the stdin of the `Compile` or `Explain` request sent just
+    /// before, which isn't answered. An empty chunk marks the end of stdin,
+    /// and the request is answered then.
+    Stdin(Vec<u8>),
 }
 
 /// A server response.
@@ -29,10 +55,64 @@ pub enum Response {
     Stats(Box<ServerInfo>),
     /// Response for `Request::DistStatus`, containing client info.
     DistStatus(DistInfo),
-    /// Response for `Request::Shutdown`, containing server statistics.
-    ShuttingDown(Box<ServerInfo>),
+    /// Response for `Request::Shutdown`, sent once the running compilations
+    /// finished, containing the final server statistics and how many
+    /// compilations were abandoned because they didn't finish in time.
+    ShuttingDown(Box<ServerInfo>, usize),
+    /// Response for `Request::Flush`, sent once the pending cache writes
+    /// finished.
+    Flushed(FlushSummary),
+    /// Response for `Request::ListEntries`, followed by the entries in
+    /// `Entries` chunks as they are listed, and then by `EntriesListed` or
+    /// `CacheFailed`.
+    ListingEntries,
+    /// A chunk of the entries listed for `Request::ListEntries`.
+    Entries(Vec<EntryInfo>),
+    /// The last response for `Request::ListEntries`, once all the entries
+    /// were listed.
+    EntriesListed,
+    /// Response for `Request::RemoveEntry`, with whether there was an entry.
+    EntryRemoved(bool),
+    /// Response for `Request::ListEntries` or `Request::RemoveEntry` when
+    /// the cache failed, with the reason.
+    CacheFailed(String),
+    /// A chunk of the stderr of the compiler for `Request::Compile`, sent
+    /// as the compiler writes it, between `CompileStarted` and
+    /// `CompileFinished`, with the color mode to print it with. The stderr of
+    /// `CompileFinished` is then only the rest of it, if any, or it is sent in
+    /// more chunks once the compiler exits when larger than
+    /// `SCCACHE_MAX_OUTPUT_SIZE`. The cache hits aren't streamed.
+    CompileStderr(Vec<u8>, ColorMode),
+    /// A chunk of the stdout of the compiler for `Request::Compile`, when
+    /// it is larger than `SCCACHE_MAX_OUTPUT_SIZE`, sent once the compiler
+    /// exits and before `CompileFinished`, whose stdout is then empty.
+    CompileStdout(Vec<u8>, ColorMode),
     /// Second response for `Request::Compile`, containing the results of the compilation.
     CompileFinished(CompileFinished),
+    /// Response for `Request::Explain`.
+    Explain(ExplainResponse),
+    /// Response for `Request::Authenticate` with the right token.
+    Authenticated,
+    /// Response for a request on a connection which was not authenticated
+    /// with the right token. The server closes the connection after it.
+    Unauthorized,
+    /// Response for a request which the socket it was sent on doesn't take,
+    /// e.g. an admin request besides `SCCACHE_ADMIN_ADDR`, with the reason.
+    /// The server closes the connection after it.
+    Refused(String),
+}
+
+/// The cache writes a `Flush` request waited for.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct FlushSummary {
+    /// How many cache writes were pending when the flush began.
+    pub pending: usize,
+    /// How many cache writes were written while flushing.
+    pub written: u64,
+    /// How many failed while flushing.
+    pub failed: u64,
+    /// How many timed out while flushing.
+    pub timed_out: u64,
 }
 
 /// Possible responses from the server for a `Compile` request.
@@ -44,6 +124,37 @@ pub enum CompileResponse {
     UnhandledCompile,
     /// The compiler was not supported.
     UnsupportedCompiler(OsString),
+    /// The commandline compiles several source files, and should be sent
+    /// again as these commandlines, one per source file.
+    SplitInputs(Vec<Vec<OsString>>),
+}
+
+/// Possible responses from the server for an `Explain` request.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ExplainResponse {
+    /// The hash key of the compilation.
+    Explained(HashKeyExplanation),
+    /// The compilation can't be cached, for the given reason.
+    NotCacheable(String),
+    /// The commandline is not a compilation.
+    NotCompilation,
+    /// The compiler was not supported.
+    UnsupportedCompiler(OsString),
+    /// Computing the hash key failed.
+    Failed(String),
+}
+
+/// The hash key of a compilation and what went into it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HashKeyExplanation {
+    /// The hash key.
+    pub key: String,
+    /// Whether the cache has an entry for `key`.
+    pub cached: bool,
+    /// The inputs of the hash key, in the order they were hashed.
+    pub inputs: Vec<HashKeyInput>,
+    /// The outputs written into the directory of the request, on a hit.
+    pub extracted: Vec<String>,
 }
 
 /// Information about a finished compile, either from cache or executed locally.
@@ -59,6 +170,27 @@ pub struct CompileFinished {
     pub stderr: Vec<u8>,
     /// The state of any compiler options passed to control color output.
     pub color_mode: ColorMode,
+    /// Why the compilation failed, if it did.
+    pub error: Option<CompileError>,
+}
+
+/// The reason a compilation failed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum CompileError {
+    /// The compiler exited with a non-zero status, which is in `retcode`.
+    CompilerFailed,
+    /// Preprocessing the input failed, the exit status is in `retcode`.
+    Preprocessing,
+    /// The compiler could not be executed.
+    CompilerNotFound(String),
+    /// Storing or restoring the cache entry failed.
+    Cache(String),
+    /// Anything else that went wrong in sccache.
+    Internal(String),
+    /// The compilation didn't finish before the deadline of the request.
+    DeadlineExceeded,
+    /// The distributed compilation failed with an HTTP error.
+    Dist(String),
 }
 
 /// The contents of a compile request from a client.
@@ -66,10 +198,24 @@ pub struct CompileFinished {
 pub struct Compile {
     /// The full path to the compiler executable.
     pub exe: OsString,
+    /// The digest of the compiler executable, sent with
+    /// `SCCACHE_COMPILER_DIGEST=1` for the server to check that it runs the
+    /// same compiler.
+    pub exe_digest: Option<String>,
     /// The current working directory in which to execute the compile.
     pub cwd: OsString,
     /// The commandline arguments passed to the compiler.
     pub args: Vec<OsString>,
     /// The environment variables present when the compiler was executed, as (var, val).
     pub env_vars: Vec<(OsString, OsString)>,
+    /// The contents of stdin, when the compiler reads its input from there.
+    /// The client sends it empty, followed by `Request::Stdin` chunks as it
+    /// reads stdin, which the server appends to it.
+    pub stdin: Option<Vec<u8>>,
+    /// When the compilation must have finished, past which the server kills
+    /// the compiler and fails it.
+    pub deadline: Option<SystemTime>,
+    /// The umask of the client, which the outputs restored from the cache are
+    /// created with, on Unix.
+    pub umask: Option<u32>,
 }
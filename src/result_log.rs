@@ -0,0 +1,253 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A log of one JSON record per compilation, `SCCACHE_RESULT_LOG`, for
+//! analyzing what each translation unit costs, unlike the aggregate stats.
+//!
+//! The server builds the record of a compilation while running it, the phases
+//! filling in what they find out with `update_record`. The same record makes
+//! the metrics sent to StatsD, see `statsd`.
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::env;
+use std::ffi::OsStr;
+use std::fs::OpenOptions;
+use std::future::Future;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::access_log::{AccessLog, AccessRequest};
+use crate::errors::*;
+use crate::statsd::Statsd;
+
+/// The result log of the server, if `SCCACHE_RESULT_LOG` is set.
+pub static RESULT_LOG: Lazy<Option<ResultLog>> = Lazy::new(|| {
+    let path = env::var_os("SCCACHE_RESULT_LOG")?;
+    match ResultLog::open(&path) {
+        Ok(log) => Some(log),
+        Err(e) => {
+            warn!("Not logging the compile results: {:#}", e);
+            None
+        }
+    }
+});
+
+tokio::task_local! {
+    static RECORD: Arc<Mutex<CompileRecord>>;
+}
+
+/// What a compilation did and cost. It has neither the environment nor the
+/// stdout and stderr of the compiler, which may hold secrets.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct CompileRecord {
+    /// When the compile request was received, as RFC 3339.
+    pub time: String,
+    /// The compiler and language, e.g. `c++ [clang]`.
+    pub compiler: String,
+    pub language: String,
+    /// The output of the compilation, as in the server logs.
+    pub output: String,
+    pub arguments: Vec<String>,
+    pub cwd: String,
+    /// One of `hit`, `miss`, `recache`, `timeout`, `read_error`,
+    /// `not_cacheable`, `too_large`, `failed`, `preprocessor_error`,
+    /// `cancelled`, `deadline_exceeded` and `error`.
+    pub result: String,
+    pub key: Option<String>,
+    /// The location of the cache, e.g. the bucket of S3.
+    pub backend: String,
+    /// The build server which compiled it, if distributed.
+    pub dist_server: Option<String>,
+    /// How long each phase took, in seconds.
+    pub hash_secs: Option<f64>,
+    pub lookup_secs: Option<f64>,
+    pub compile_secs: Option<f64>,
+    pub write_secs: Option<f64>,
+    /// The size of the cache entry read on a hit, or written after a miss.
+    pub bytes_read: Option<u64>,
+    pub bytes_written: Option<u64>,
+    /// Why storing the result failed, if it did.
+    pub write_error: Option<String>,
+    /// The peak RSS of the compiler in bytes, with
+    /// `SCCACHE_MEMORY_ACCOUNTING`.
+    pub peak_rss: Option<u64>,
+    /// The deadline the client gave, as RFC 3339.
+    pub deadline: Option<String>,
+}
+
+impl CompileRecord {
+    pub fn new(
+        compiler: String,
+        language: &str,
+        output: String,
+        arguments: &[impl AsRef<OsStr>],
+        cwd: &std::path::Path,
+        backend: String,
+    ) -> CompileRecord {
+        CompileRecord {
+            time: chrono::Utc::now().to_rfc3339(),
+            compiler,
+            language: language.to_owned(),
+            output,
+            arguments: arguments
+                .iter()
+                .map(|arg| arg.as_ref().to_string_lossy().into_owned())
+                .collect(),
+            cwd: cwd.to_string_lossy().into_owned(),
+            backend,
+            ..Default::default()
+        }
+    }
+}
+
+/// Whether the compilations are recorded, so that finding out what only the
+/// record needs is worth it.
+pub fn is_recording() -> bool {
+    RECORD.try_with(|_| ()).is_ok()
+}
+
+/// Update the record of the compilation the current task runs, if it is
+/// recorded.
+pub fn update_record(f: impl FnOnce(&mut CompileRecord)) {
+    let _ = RECORD.try_with(|record| f(&mut record.lock().unwrap()));
+}
+
+/// Run `task`, recording into `record`. Once `task` completes, the record
+/// is logged to `log`, its metrics sent to `statsd` and the request which
+/// started it logged to the access log, if any.
+pub async fn record_compile<F: Future>(
+    log: Option<&'static ResultLog>,
+    statsd: Option<&'static Statsd>,
+    access: Option<(&'static AccessLog, AccessRequest)>,
+    record: CompileRecord,
+    task: F,
+) -> F::Output {
+    let start = Instant::now();
+    let record = Arc::new(Mutex::new(record));
+    let output = RECORD.scope(record.clone(), task).await;
+    let record = record.lock().unwrap();
+    if let Some(statsd) = statsd {
+        statsd.emit(&record, start.elapsed());
+    }
+    if let Some(Err(e)) = log.map(|log| log.write(&record)) {
+        warn!("Failed to log the compile result: {:#}", e);
+    }
+    if let Some((access_log, request)) = access {
+        let result = if record.result.is_empty() {
+            "unknown"
+        } else {
+            &record.result
+        };
+        if let Err(e) = access_log.write(&request, result, record.key.as_deref()) {
+            warn!("Failed to log the request: {:#}", e);
+        }
+    }
+    output
+}
+
+/// Where the records are appended, one JSON object per line.
+pub struct ResultLog {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl ResultLog {
+    /// Append to the file at `path`, or write to stdout if it is `-`.
+    pub fn open(path: &OsStr) -> Result<ResultLog> {
+        let out: Box<dyn Write + Send> = if path == "-" {
+            Box::new(io::stdout())
+        } else {
+            // Appending keeps the lines of several servers whole.
+            Box::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("failed to open {:?}", path))?,
+            )
+        };
+        Ok(ResultLog::new(out))
+    }
+
+    pub fn new(out: Box<dyn Write + Send>) -> ResultLog {
+        ResultLog {
+            out: Mutex::new(out),
+        }
+    }
+
+    /// Append `record`, written at once so that concurrent compilations
+    /// don't interleave.
+    pub fn write(&self, record: &CompileRecord) -> Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        let mut out = self.out.lock().unwrap();
+        out.write_all(&line)?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::utils::TestFixture;
+    use std::fs;
+    use std::time::Duration;
+
+    #[test]
+    fn test_result_log() {
+        let f = TestFixture::new();
+        let path = f.tempdir.path().join("results.jsonl");
+        let log: &'static ResultLog =
+            Box::leak(Box::new(ResultLog::open(path.as_os_str()).unwrap()));
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        assert!(!is_recording());
+        for output in ["a.o", "b.o"] {
+            let record = CompileRecord::new(
+                "c [gcc]".into(),
+                "c",
+                output.into(),
+                &["-c", "a.c"],
+                f.tempdir.path(),
+                "Local disk".into(),
+            );
+            runtime.block_on(record_compile(Some(log), None, None, record, async {
+                assert!(is_recording());
+                update_record(|r| {
+                    r.result = "miss".into();
+                    r.key = Some("abcd".into());
+                    r.compile_secs = Some(Duration::from_millis(1500).as_secs_f64());
+                })
+            }));
+        }
+        // Nothing is recorded outside of a compilation.
+        update_record(|_| panic!());
+
+        let records = fs::read_to_string(&path).unwrap();
+        let records: Vec<serde_json::Value> = records
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["output"], "a.o");
+        assert_eq!(records[1]["output"], "b.o");
+        assert_eq!(records[0]["result"], "miss");
+        assert_eq!(records[0]["key"], "abcd");
+        assert_eq!(records[0]["arguments"][1], "a.c");
+        assert_eq!(records[0]["compile_secs"], 1.5);
+        assert_eq!(records[0]["backend"], "Local disk");
+        assert!(records[0]["bytes_read"].is_null());
+    }
+}
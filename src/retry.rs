@@ -0,0 +1,679 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Retries of what fails temporarily, backing off as a `Backoff` says: the
+//! connections of the client to a server which is starting, the requests to
+//! the remote caches, and the loading of their credentials.
+//!
+//! The remote caches tell which errors are temporary: timeouts, connection
+//! errors and 5xx responses are, while e.g. 403 and 404 responses are not and
+//! fail right away. They all back off as `SCCACHE_REMOTE_BACKOFF` says.
+
+// Without a remote cache, only the client retries, with a fixed backoff.
+#![cfg_attr(
+    not(any(
+        feature = "azure",
+        feature = "gcs",
+        feature = "gha",
+        feature = "memcached",
+        feature = "redis",
+        feature = "s3",
+        feature = "webdav",
+        feature = "oss",
+        feature = "http-cache",
+    )),
+    allow(dead_code)
+)]
+
+use std::env;
+use std::fmt;
+#[cfg(any(feature = "gcs", feature = "http-cache", feature = "s3"))]
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+#[cfg(any(
+    feature = "azure",
+    feature = "gcs",
+    feature = "gha",
+    feature = "memcached",
+    feature = "redis",
+    feature = "webdav",
+    feature = "oss",
+))]
+use crate::cache::{Cache, CacheMode, CacheWrite, EntryStream, Storage, StorageCounts};
+#[cfg(any(feature = "gcs", feature = "http-cache", feature = "s3"))]
+use crate::clock::SharedClock;
+#[cfg(any(
+    feature = "azure",
+    feature = "gcs",
+    feature = "gha",
+    feature = "memcached",
+    feature = "redis",
+    feature = "s3",
+    feature = "webdav",
+    feature = "oss",
+    feature = "http-cache",
+))]
+use crate::errors::*;
+#[cfg(any(
+    feature = "azure",
+    feature = "gcs",
+    feature = "gha",
+    feature = "memcached",
+    feature = "redis",
+    feature = "webdav",
+    feature = "oss",
+))]
+use async_trait::async_trait;
+use rand::Rng;
+
+/// The default number of times a failed request to a remote cache is
+/// retried.
+pub const DEFAULT_REMOTE_RETRIES: usize = 3;
+
+/// The default backoff of the requests to the remote caches: 100ms before
+/// the first retry, doubled for each of the next ones up to 10s, with jitter.
+pub const DEFAULT_REMOTE_BACKOFF: Backoff =
+    Backoff::exponential(Duration::from_millis(100), DEFAULT_REMOTE_RETRIES)
+        .with_jitter()
+        .capped(Duration::from_secs(10));
+
+/// How the delays between the retries grow.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strategy {
+    /// The same delay before each retry.
+    Fixed,
+    /// Twice the previous delay before each retry.
+    Exponential,
+}
+
+/// How many times to retry, and how long to wait before each retry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Backoff {
+    strategy: Strategy,
+    /// The delay before the first retry.
+    delay: Duration,
+    /// The delay which the delays of the next retries don't grow past.
+    max_delay: Duration,
+    /// Whether to wait for up to half as long again as the delay, at random,
+    /// so that the retries of a burst of failures don't all come at once.
+    jitter: bool,
+    retries: usize,
+}
+
+impl Backoff {
+    /// Retry at most `retries` times, waiting for `delay` before each retry.
+    pub const fn fixed(delay: Duration, retries: usize) -> Self {
+        Backoff {
+            strategy: Strategy::Fixed,
+            delay,
+            max_delay: delay,
+            jitter: false,
+            retries,
+        }
+    }
+
+    /// Retry at most `retries` times, waiting for `delay` before the first
+    /// retry and for twice as long as before the previous one before the next
+    /// ones.
+    pub const fn exponential(delay: Duration, retries: usize) -> Self {
+        Backoff {
+            strategy: Strategy::Exponential,
+            delay,
+            max_delay: Duration::MAX,
+            jitter: false,
+            retries,
+        }
+    }
+
+    /// Wait for up to half as long again as each delay, at random.
+    pub const fn with_jitter(self) -> Self {
+        Backoff {
+            jitter: true,
+            ..self
+        }
+    }
+
+    /// Wait for at most `max_delay` before each retry, jitter aside.
+    pub const fn capped(self, max_delay: Duration) -> Self {
+        Backoff { max_delay, ..self }
+    }
+
+    /// Retry at most `retries` times, waiting as this backoff does.
+    pub const fn with_retries(self, retries: usize) -> Self {
+        Backoff { retries, ..self }
+    }
+
+    /// Get the backoff of the requests to the remote caches, from
+    /// `SCCACHE_REMOTE_BACKOFF`, `SCCACHE_REMOTE_BACKOFF_DELAY`,
+    /// `SCCACHE_REMOTE_BACKOFF_MAX_DELAY` and `SCCACHE_REMOTE_RETRIES`.
+    pub fn remote() -> Self {
+        let default = DEFAULT_REMOTE_BACKOFF;
+        let backoff = match env::var("SCCACHE_REMOTE_BACKOFF").as_deref() {
+            Ok("fixed") => Backoff::fixed(default.delay, 0),
+            Ok("exponential") => Backoff::exponential(default.delay, 0).capped(default.max_delay),
+            Ok("exponential-jitter") | Err(_) => default,
+            Ok(s) => {
+                warn!(
+                    "Invalid SCCACHE_REMOTE_BACKOFF {:?}, using exponential-jitter",
+                    s
+                );
+                default
+            }
+        };
+        let delay = get_millis("SCCACHE_REMOTE_BACKOFF_DELAY", backoff.delay);
+        let max_delay = match backoff.strategy {
+            Strategy::Fixed => delay,
+            Strategy::Exponential => {
+                get_millis("SCCACHE_REMOTE_BACKOFF_MAX_DELAY", backoff.max_delay)
+            }
+        };
+        Backoff {
+            delay,
+            max_delay,
+            ..backoff
+        }
+        .with_retries(get_remote_retries())
+    }
+
+    /// The number of times to retry.
+    #[cfg(test)]
+    pub fn retries(&self) -> usize {
+        self.retries
+    }
+
+    /// The delays to wait for before each retry, in order.
+    pub fn delays(&self) -> impl Iterator<Item = Duration> {
+        let backoff = *self;
+        let mut delay = backoff.delay.min(backoff.max_delay);
+        (0..backoff.retries).map(move |_| {
+            let current = delay;
+            if backoff.strategy == Strategy::Exponential {
+                delay = delay
+                    .checked_mul(2)
+                    .unwrap_or(Duration::MAX)
+                    .min(backoff.max_delay);
+            }
+            if backoff.jitter {
+                let jitter = rand::thread_rng().gen_range(Duration::ZERO..=current / 2);
+                current.saturating_add(jitter)
+            } else {
+                current
+            }
+        })
+    }
+}
+
+/// Get a duration in milliseconds from the environment variable `var`, or
+/// `default`.
+fn get_millis(var: &str, default: Duration) -> Duration {
+    match env::var(var) {
+        Ok(s) => s.parse().map(Duration::from_millis).unwrap_or_else(|_| {
+            warn!("Invalid {} {:?}, using {}", var, s, default.as_millis());
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+/// Get the number of times to retry a request from `SCCACHE_REMOTE_RETRIES`.
+fn get_remote_retries() -> usize {
+    match env::var("SCCACHE_REMOTE_RETRIES") {
+        Ok(s) => s.parse().unwrap_or_else(|_| {
+            warn!(
+                "Invalid SCCACHE_REMOTE_RETRIES {:?}, using {}",
+                s, DEFAULT_REMOTE_RETRIES
+            );
+            DEFAULT_REMOTE_RETRIES
+        }),
+        Err(_) => DEFAULT_REMOTE_RETRIES,
+    }
+}
+
+/// Call `f` until it succeeds, at most `backoff.retries()` more times,
+/// sleeping on `clock` between the calls.
+#[cfg(any(feature = "gcs", feature = "http-cache", feature = "s3"))]
+pub async fn retry_async<T, E, F, Fut>(
+    backoff: &Backoff,
+    clock: &SharedClock,
+    mut f: F,
+) -> std::result::Result<T, E>
+where
+    E: fmt::Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = std::result::Result<T, E>>,
+{
+    let mut delays = backoff.delays();
+    loop {
+        let err = match f().await {
+            Ok(value) => return Ok(value),
+            Err(e) => e,
+        };
+        let Some(delay) = delays.next() else {
+            return Err(err);
+        };
+        debug!("Retrying in {:?} after: {:#}", delay, err);
+        clock.sleep(delay).await;
+    }
+}
+
+/// Call `f` until it succeeds, at most `backoff.retries()` more times,
+/// blocking the thread between the calls.
+pub fn retry_sync<T, E, F>(backoff: &Backoff, mut f: F) -> std::result::Result<T, E>
+where
+    E: fmt::Display,
+    F: FnMut() -> std::result::Result<T, E>,
+{
+    let mut delays = backoff.delays();
+    loop {
+        let err = match f() {
+            Ok(value) => return Ok(value),
+            Err(e) => e,
+        };
+        let Some(delay) = delays.next() else {
+            return Err(err);
+        };
+        trace!("Retrying in {:?} after: {:#}", delay, err);
+        thread::sleep(delay);
+    }
+}
+
+/// The retries of the requests to a cache since they were last taken, see
+/// `Storage::take_counts`.
+#[derive(Clone, Debug, Default)]
+pub struct RetryCount(Arc<AtomicU64>);
+
+impl RetryCount {
+    fn add(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Get the number of retries since the last call, and reset it.
+    pub fn take(&self) -> u64 {
+        self.0.swap(0, Ordering::Relaxed)
+    }
+}
+
+/// Send the requests `send` makes until the connection doesn't fail and the
+/// cache doesn't answer with a `5xx`, retrying as `backoff` says on `clock`
+/// and counting the retries in `retries`.
+#[cfg(any(feature = "gcs", feature = "http-cache", feature = "s3"))]
+pub async fn send_with_retries<F, Fut>(
+    backoff: &Backoff,
+    clock: &SharedClock,
+    retries: &RetryCount,
+    mut send: F,
+) -> Result<reqwest::Response>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<reqwest::Response>>,
+{
+    let mut sent = false;
+    retry_async(backoff, clock, || {
+        if sent {
+            retries.add();
+        }
+        sent = true;
+        let response = send();
+        async move {
+            match response.await? {
+                response if response.status().is_server_error() => {
+                    Err(anyhow!("the cache answered with {}", response.status()))
+                }
+                response => Ok(response),
+            }
+        }
+    })
+    .await
+}
+
+#[cfg(any(
+    feature = "azure",
+    feature = "gcs",
+    feature = "gha",
+    feature = "memcached",
+    feature = "redis",
+    feature = "webdav",
+    feature = "oss",
+))]
+struct CountRetries(RetryCount);
+
+#[cfg(any(
+    feature = "azure",
+    feature = "gcs",
+    feature = "gha",
+    feature = "memcached",
+    feature = "redis",
+    feature = "webdav",
+    feature = "oss",
+))]
+impl opendal::layers::RetryInterceptor for CountRetries {
+    fn intercept(&self, err: &opendal::Error, dur: Duration, _ctx: &[(&str, &str)]) {
+        self.0.add();
+        debug!("Retrying a cache request in {:?} after: {}", dur, err);
+    }
+}
+
+/// Retry the requests to `operator` that fail temporarily as `backoff` says.
+///
+/// opendal's jitter waits for up to the first delay more, rather than for up
+/// to half of each delay.
+#[cfg(any(
+    feature = "azure",
+    feature = "gcs",
+    feature = "gha",
+    feature = "memcached",
+    feature = "redis",
+    feature = "webdav",
+    feature = "oss",
+))]
+pub fn with_retries(
+    operator: opendal::Operator,
+    backoff: &Backoff,
+    retries: &RetryCount,
+) -> opendal::Operator {
+    if backoff.retries == 0 {
+        return operator;
+    }
+    let mut layer = opendal::layers::RetryLayer::new()
+        .with_max_times(backoff.retries)
+        .with_factor(match backoff.strategy {
+            Strategy::Fixed => 1.0,
+            Strategy::Exponential => 2.0,
+        })
+        .with_min_delay(backoff.delay)
+        .with_max_delay(backoff.max_delay);
+    if backoff.jitter {
+        layer = layer.with_jitter();
+    }
+    operator.layer(layer.with_notify(CountRetries(retries.clone())))
+}
+
+/// A storage going through `operator`, retrying the requests that fail
+/// temporarily as `backoff` says, and counting the retries.
+#[cfg(any(
+    feature = "azure",
+    feature = "gcs",
+    feature = "gha",
+    feature = "memcached",
+    feature = "redis",
+    feature = "webdav",
+    feature = "oss",
+))]
+pub struct RetriedOperator {
+    operator: opendal::Operator,
+    retries: RetryCount,
+}
+
+#[cfg(any(
+    feature = "azure",
+    feature = "gcs",
+    feature = "gha",
+    feature = "memcached",
+    feature = "redis",
+    feature = "webdav",
+    feature = "oss",
+))]
+impl RetriedOperator {
+    pub fn new(operator: opendal::Operator, backoff: &Backoff) -> RetriedOperator {
+        let retries = RetryCount::default();
+        RetriedOperator {
+            operator: with_retries(operator, backoff, &retries),
+            retries,
+        }
+    }
+}
+
+#[cfg(any(
+    feature = "azure",
+    feature = "gcs",
+    feature = "gha",
+    feature = "memcached",
+    feature = "redis",
+    feature = "webdav",
+    feature = "oss",
+))]
+#[async_trait]
+impl Storage for RetriedOperator {
+    async fn get(&self, key: &str) -> Result<Cache> {
+        Storage::get(&self.operator, key).await
+    }
+
+    async fn put(&self, key: &str, entry: CacheWrite) -> Result<Duration> {
+        Storage::put(&self.operator, key, entry).await
+    }
+
+    async fn check(&self) -> Result<CacheMode> {
+        Storage::check(&self.operator).await
+    }
+
+    async fn probe(&self) -> Result<()> {
+        Storage::probe(&self.operator).await
+    }
+
+    fn location(&self) -> String {
+        Storage::location(&self.operator)
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        Storage::list(&self.operator).await
+    }
+
+    async fn list_entries(&self, prefix: &str) -> Result<EntryStream> {
+        Storage::list_entries(&self.operator, prefix).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool> {
+        Storage::delete(&self.operator, key).await
+    }
+
+    async fn current_size(&self) -> Result<Option<u64>> {
+        Storage::current_size(&self.operator).await
+    }
+
+    async fn max_size(&self) -> Result<Option<u64>> {
+        Storage::max_size(&self.operator).await
+    }
+
+    fn take_counts(&self) -> StorageCounts {
+        StorageCounts {
+            retries: self.retries.take(),
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use serial_test::serial;
+
+    /// Make backoffs of all kinds, at random but the same in each run.
+    fn backoffs() -> impl Iterator<Item = Backoff> {
+        let mut rng = StdRng::seed_from_u64(0x5cca_c4e5);
+        (0..1000).map(move |_| {
+            let delay = Duration::from_millis(rng.gen_range(0..10_000));
+            let retries = rng.gen_range(0..40);
+            let mut backoff = if rng.gen() {
+                Backoff::fixed(delay, retries)
+            } else {
+                Backoff::exponential(delay, retries)
+            };
+            if rng.gen() {
+                backoff = backoff.with_jitter();
+            }
+            if rng.gen() {
+                backoff = backoff.capped(Duration::from_millis(rng.gen_range(0..100_000)));
+            }
+            backoff
+        })
+    }
+
+    #[test]
+    fn test_backoff_sequences() {
+        for backoff in backoffs() {
+            let delays: Vec<_> = backoff.delays().collect();
+            assert_eq!(delays.len(), backoff.retries(), "{:?}", backoff);
+            let mut base = backoff.delay.min(backoff.max_delay);
+            for delay in delays {
+                // Each delay is the base one, or up to half as long again
+                // with jitter.
+                if backoff.jitter {
+                    assert!(delay >= base && delay <= base + base / 2, "{:?}", backoff);
+                } else {
+                    assert_eq!(delay, base, "{:?}", backoff);
+                }
+                // The base delay stays the same, or doubles up to the cap.
+                assert!(base <= backoff.max_delay, "{:?}", backoff);
+                if backoff.strategy == Strategy::Exponential {
+                    base = (base * 2).min(backoff.max_delay);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_backoff_no_overflow() {
+        let backoff = Backoff::exponential(Duration::from_secs(1), 200).with_jitter();
+        let last = backoff.delays().last().unwrap();
+        assert!(last >= Duration::MAX / 2, "{:?}", last);
+    }
+
+    #[test]
+    fn test_retry_sync() {
+        for backoff in backoffs() {
+            // Don't sleep for real.
+            let backoff = Backoff {
+                delay: Duration::ZERO,
+                max_delay: Duration::ZERO,
+                ..backoff
+            };
+            let mut calls = 0;
+            let res: std::result::Result<(), _> = retry_sync(&backoff, || {
+                calls += 1;
+                Err("down")
+            });
+            assert_eq!(res, Err("down"));
+            assert_eq!(calls, backoff.retries() + 1);
+
+            let mut calls = 0;
+            let res = retry_sync(&backoff, || {
+                calls += 1;
+                if calls > 1 {
+                    Ok(calls)
+                } else {
+                    Err("down")
+                }
+            });
+            let expected = if backoff.retries() == 0 {
+                Err("down")
+            } else {
+                Ok(2)
+            };
+            assert_eq!(res, expected);
+        }
+    }
+
+    #[tokio::test]
+    #[cfg(any(feature = "gcs", feature = "http-cache", feature = "s3"))]
+    async fn test_retry_async() {
+        use crate::clock::FakeClock;
+
+        let clock = FakeClock::new();
+        let shared: SharedClock = clock.clone();
+        let backoff =
+            Backoff::exponential(Duration::from_secs(1), 5).capped(Duration::from_secs(4));
+        let mut calls = 0;
+        let retried = tokio::spawn(async move {
+            retry_async(&backoff, &shared, || {
+                calls += 1;
+                let calls = calls;
+                async move {
+                    if calls < 5 {
+                        Err(anyhow!("down"))
+                    } else {
+                        Ok(calls)
+                    }
+                }
+            })
+            .await
+        });
+        for n in 1..=4 {
+            while clock.sleeps().len() < n {
+                tokio::task::yield_now().await;
+            }
+            clock.advance(Duration::from_secs(10));
+        }
+        assert_eq!(retried.await.unwrap().unwrap(), 5);
+        assert_eq!(
+            clock.sleeps(),
+            [1, 2, 4, 4].map(Duration::from_secs).to_vec()
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_get_remote_retries() {
+        env::remove_var("SCCACHE_REMOTE_RETRIES");
+        assert_eq!(get_remote_retries(), DEFAULT_REMOTE_RETRIES);
+        env::set_var("SCCACHE_REMOTE_RETRIES", "7");
+        assert_eq!(get_remote_retries(), 7);
+        env::set_var("SCCACHE_REMOTE_RETRIES", "often");
+        assert_eq!(get_remote_retries(), DEFAULT_REMOTE_RETRIES);
+        env::remove_var("SCCACHE_REMOTE_RETRIES");
+    }
+
+    #[test]
+    #[serial]
+    fn test_remote_backoff() {
+        assert_eq!(Backoff::remote(), DEFAULT_REMOTE_BACKOFF);
+        env::set_var("SCCACHE_REMOTE_BACKOFF", "fixed");
+        env::set_var("SCCACHE_REMOTE_BACKOFF_DELAY", "250");
+        env::set_var("SCCACHE_REMOTE_RETRIES", "2");
+        assert_eq!(
+            Backoff::remote(),
+            Backoff::fixed(Duration::from_millis(250), 2)
+        );
+        env::set_var("SCCACHE_REMOTE_BACKOFF", "exponential");
+        env::set_var("SCCACHE_REMOTE_BACKOFF_MAX_DELAY", "1000");
+        assert_eq!(
+            Backoff::remote(),
+            Backoff::exponential(Duration::from_millis(250), 2).capped(Duration::from_secs(1))
+        );
+        env::set_var("SCCACHE_REMOTE_BACKOFF", "sometimes");
+        env::set_var("SCCACHE_REMOTE_BACKOFF_DELAY", "soon");
+        env::remove_var("SCCACHE_REMOTE_BACKOFF_MAX_DELAY");
+        env::remove_var("SCCACHE_REMOTE_RETRIES");
+        assert_eq!(Backoff::remote(), DEFAULT_REMOTE_BACKOFF);
+        env::remove_var("SCCACHE_REMOTE_BACKOFF");
+        env::remove_var("SCCACHE_REMOTE_BACKOFF_DELAY");
+    }
+
+    #[test]
+    #[cfg(feature = "gcs")]
+    fn test_retries_are_counted() {
+        use opendal::layers::RetryInterceptor;
+
+        let err = opendal::Error::new(opendal::ErrorKind::Unexpected, "reset").set_temporary();
+        let retries = RetryCount::default();
+        let count = CountRetries(retries.clone());
+        count.intercept(&err, Duration::ZERO, &[]);
+        count.intercept(&err, Duration::ZERO, &[]);
+        assert_eq!(retries.take(), 2);
+        assert_eq!(retries.take(), 0);
+    }
+}
@@ -0,0 +1,238 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Check that compilations are cached end to end, see `sccache --selftest`.
+//!
+//! A tiny program of every language is compiled twice through the server,
+//! and the second compilation must be a cache hit. The programs are unique to
+//! the run, so the first compilation is always a miss.
+
+use crate::client::{connect_to_admin, connect_to_server};
+use crate::commands::{report_check, request_compile, request_flush, request_stats};
+use crate::protocol::{CompileResponse, Response};
+use std::ffi::{OsStr, OsString};
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use which::which_in;
+
+use crate::errors::*;
+
+/// A program compiled by the self test.
+pub struct Program {
+    /// The language, as reported.
+    pub language: &'static str,
+    /// The variable naming the compiler, e.g. `CC`.
+    pub compiler_var: &'static str,
+    /// The compiler when the variable isn't set.
+    pub default_compiler: &'static str,
+    /// The name of the source file.
+    pub file: &'static str,
+    /// The source, where `{id}` is replaced by an identifier of the run.
+    pub source: &'static str,
+    /// The arguments of the compiler, after which the source file is passed.
+    pub args: &'static [&'static str],
+}
+
+/// The programs compiled by `sccache --selftest`.
+pub const PROGRAMS: &[Program] = &[
+    Program {
+        language: "C",
+        compiler_var: "CC",
+        default_compiler: "cc",
+        file: "selftest.c",
+        source: "const char *sccache_selftest = \"{id}\";\n",
+        args: &["-c", "-o", "selftest-c.o"],
+    },
+    Program {
+        language: "C++",
+        compiler_var: "CXX",
+        default_compiler: "c++",
+        file: "selftest.cpp",
+        source: "extern const char *const sccache_selftest = \"{id}\";\n",
+        args: &["-c", "-o", "selftest-cpp.o"],
+    },
+    Program {
+        language: "Rust",
+        compiler_var: "RUSTC",
+        default_compiler: "rustc",
+        file: "selftest.rs",
+        source: "pub const SCCACHE_SELFTEST: &str = \"{id}\";\n",
+        args: &[
+            "--crate-name",
+            "sccache_selftest",
+            "--crate-type",
+            "lib",
+            "--emit=link",
+            "--out-dir",
+            ".",
+        ],
+    },
+];
+
+/// How a compilation went through the cache.
+#[derive(Debug, PartialEq, Eq)]
+enum Outcome {
+    Hit,
+    Miss,
+    NotCached,
+}
+
+/// Compile `file` with `compiler` through the server listening on `port`,
+/// and return how it went through the cache and how long it took.
+fn compile(
+    port: u16,
+    compiler: &Path,
+    args: &[OsString],
+    dir: &Path,
+    env_vars: &[(OsString, OsString)],
+) -> Result<(Outcome, Duration)> {
+    let before = request_stats(connect_to_admin(port)?)?.stats;
+    let start = Instant::now();
+    let mut conn = connect_to_server(port)?;
+    let response = request_compile(
+        &mut conn,
+        compiler,
+        args,
+        dir,
+        env_vars.to_vec(),
+        None,
+        None,
+    )?;
+    if let CompileResponse::UnsupportedCompiler(s) = response {
+        bail!(
+            "sccache doesn't support the compiler: {}",
+            s.to_string_lossy()
+        );
+    } else if !matches!(response, CompileResponse::CompileStarted) {
+        bail!("sccache doesn't cache the compilation: {:?}", response);
+    }
+    let mut stderr = vec![];
+    let finished = loop {
+        match conn.read_one_response()? {
+            Response::CompileStderr(chunk, _) => stderr.extend(chunk),
+            Response::CompileStdout(..) => {}
+            Response::CompileFinished(finished) => break finished,
+            _ => bail!("Unexpected server response!"),
+        }
+    };
+    let elapsed = start.elapsed();
+    if finished.retcode != Some(0) {
+        stderr.extend(&finished.stderr);
+        bail!(
+            "the compilation failed: {}",
+            String::from_utf8_lossy(&stderr).trim()
+        );
+    }
+    let after = request_stats(connect_to_admin(port)?)?.stats;
+    let outcome = if after.cache_hits.all() > before.cache_hits.all() {
+        Outcome::Hit
+    } else if after.cache_misses.all() > before.cache_misses.all() {
+        Outcome::Miss
+    } else {
+        Outcome::NotCached
+    };
+    Ok((outcome, elapsed))
+}
+
+/// Compile `program` twice in `dir` and check the second compilation is a
+/// cache hit. Return `None` if the compiler isn't installed, and wasn't asked
+/// for explicitly.
+fn check_program(
+    port: u16,
+    program: &Program,
+    dir: &Path,
+    path: Option<OsString>,
+    env_vars: &[(OsString, OsString)],
+    id: &str,
+) -> Option<Result<String>> {
+    let configured = env_vars
+        .iter()
+        .find(|(k, _)| k == program.compiler_var)
+        .map(|(_, v)| v.clone());
+    let compiler = match which_in(
+        configured
+            .as_deref()
+            .unwrap_or(OsStr::new(program.default_compiler)),
+        path,
+        dir,
+    ) {
+        Ok(compiler) => compiler,
+        Err(_) if configured.is_none() => return None,
+        Err(_) => {
+            return Some(Err(anyhow!(
+                "the compiler {:?} of {} isn't found",
+                configured.unwrap(),
+                program.compiler_var
+            )))
+        }
+    };
+    let check = || {
+        std::fs::write(dir.join(program.file), program.source.replace("{id}", id))?;
+        let mut args: Vec<OsString> = program.args.iter().map(Into::into).collect();
+        args.push(program.file.into());
+
+        let (first, miss) = compile(port, &compiler, &args, dir, env_vars)
+            .with_context(|| format!("failed to compile with {}", compiler.display()))?;
+        if first == Outcome::NotCached {
+            bail!("the compilation wasn't cached, see `sccache --show-adv-stats`");
+        }
+        let flushed = request_flush(connect_to_admin(port)?)?;
+        if flushed.failed > 0 || flushed.timed_out > 0 {
+            bail!("failed to write the cache entry, see the server log");
+        }
+        let (second, hit) = compile(port, &compiler, &args, dir, env_vars)
+            .with_context(|| format!("failed to compile with {}", compiler.display()))?;
+        if second != Outcome::Hit {
+            bail!("the second compilation missed the cache, is it read only?");
+        }
+        Ok(format!(
+            "{}: miss {:.3} s, hit {:.3} s",
+            compiler.display(),
+            miss.as_secs_f64(),
+            hit.as_secs_f64()
+        ))
+    };
+    Some(check())
+}
+
+/// Check that compilations of `programs` in `dir` are cached by the server
+/// listening on `port`, writing a report to `out`. `path` and `env_vars`
+/// are those of the client, to find the compilers.
+///
+/// Returns 1 if any check failed.
+pub fn run(
+    port: u16,
+    programs: &[Program],
+    dir: &Path,
+    path: Option<OsString>,
+    env_vars: &[(OsString, OsString)],
+    out: &mut dyn Write,
+) -> Result<i32> {
+    let info = request_stats(connect_to_admin(port)?)?;
+    report_check(out, "cache", Ok(info.cache_location))?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let id = format!("{}-{}", std::process::id(), now.as_nanos());
+
+    let mut ok = true;
+    for program in programs {
+        match check_program(port, program, dir, path.clone(), env_vars, &id) {
+            Some(res) => ok &= report_check(out, program.language, res)?,
+            None => writeln!(
+                out,
+                "{:<8}{:<8}{} not found",
+                program.language, "skipped", program.default_compiler
+            )?,
+        }
+    }
+    Ok(if ok { 0 } else { 1 })
+}
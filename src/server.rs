@@ -12,28 +12,49 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.SCCACHE_MAX_FRAME_LENGTH
 
+use crate::access_log::{self, Peer, ACCESS_LOG};
+use crate::admin::{self, AdminAddr, Role};
+use crate::affinity;
+use crate::cache::overrides::CacheOverrides;
 use crate::cache::readonly::ReadOnlyStorage;
-use crate::cache::{storage_from_config, CacheMode, Storage};
+use crate::cache::reloading::ReloadingStorage;
+use crate::cache::{
+    storage_from_config, Cache, CacheMode, CacheOccupancy, CacheRead, EvictionState,
+    FileObjectSource, Storage,
+};
+use crate::clock::{self, SharedClock};
+use crate::compiler::in_flight::{self, Flights};
 use crate::compiler::{
     get_compiler_info, CacheControl, CompileResult, Compiler, CompilerArguments, CompilerHasher,
-    CompilerKind, CompilerProxy, DistType, Language, MissType,
+    CompilerKind, CompilerPathMap, CompilerProxy, CompilerWrapper, DistType, HashResult, Language,
+    MissType, COMPILER_KIND_ENV_VAR,
 };
-#[cfg(feature = "dist-client")]
 use crate::config;
 use crate::config::Config;
+use crate::deadline;
+use crate::discovery;
 use crate::dist;
+use crate::jobs::{self, CompileQueue};
 use crate::jobserver::Client;
+use crate::memory_accounting;
 use crate::mock_command::{CommandCreatorSync, ProcessCommandCreator};
-use crate::protocol::{Compile, CompileFinished, CompileResponse, Request, Response};
-use crate::util;
-#[cfg(feature = "dist-client")]
+use crate::protocol::{
+    Compile, CompileError, CompileFinished, CompileResponse, ExplainResponse, FlushSummary,
+    HashKeyExplanation, Request, Response, PROTOCOL_VERSION,
+};
+use crate::result_log::{record_compile, update_record, CompileRecord, RESULT_LOG};
+use crate::service;
+use crate::statsd::STATSD;
+use crate::stderr_stream;
+use crate::umask;
+use crate::util::{self, Captured};
 use anyhow::Context as _;
 use bytes::{buf::BufMut, Bytes, BytesMut};
 use filetime::FileTime;
 use fs::metadata;
 use fs_err as fs;
 use futures::channel::mpsc;
-use futures::future::FutureExt;
+use futures::future::{BoxFuture, FutureExt};
 use futures::{future, stream, Sink, SinkExt, Stream, StreamExt, TryFutureExt};
 use number_prefix::NumberPrefix;
 use serde::{Deserialize, Serialize};
@@ -46,33 +67,61 @@ use std::io::{self, Write};
 use std::marker::Unpin;
 #[cfg(feature = "dist-client")]
 use std::mem;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
-use std::path::PathBuf;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::process::{ExitStatus, Output};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::task::{Context, Poll, Waker};
-use std::time::Duration;
 #[cfg(feature = "dist-client")]
 use std::time::Instant;
+use std::time::{Duration, SystemTime};
 use std::u64;
+use tokio::sync::watch;
 use tokio::sync::Mutex;
 use tokio::sync::RwLock;
 use tokio::{
     io::{AsyncRead, AsyncWrite},
-    net::TcpListener,
+    net::{TcpListener, TcpSocket},
     runtime::Runtime,
-    time::{self, sleep, Sleep},
+    time,
 };
 use tokio_serde::Framed;
-use tokio_util::codec::{length_delimited, LengthDelimitedCodec};
-use tower::Service;
+use tokio_util::codec::{length_delimited, Decoder, Encoder, LengthDelimitedCodec};
+use tower::timeout::TimeoutLayer;
+use tower::util::BoxCloneService;
+use tower::{Layer, Service, ServiceBuilder, ServiceExt};
 
 use crate::errors::*;
 
 /// If the server is idle for this many seconds, shut down.
 const DEFAULT_IDLE_TIMEOUT: u64 = 600;
 
+/// How many seconds a shutting down server waits for running compilations.
+const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 10;
+
+/// How many connections the OS queues until the server accepts them, unless
+/// `SCCACHE_LISTEN_BACKLOG` is set. It is capped by the OS, e.g. by
+/// `net.core.somaxconn` on Linux.
+const DEFAULT_LISTEN_BACKLOG: u32 = 4096;
+
+/// How many compilations may be in progress besides those running a
+/// compiler, before new compile requests wait for room in the queue. The
+/// cache hits and the distributed compilations don't take a job, so this is
+/// far above the number of jobs, for builds running hundreds of them at once.
+const DEFAULT_MAX_QUEUED_COMPILES: usize = 1024;
+
+/// The largest message between the client and the server, in bytes, unless
+/// `SCCACHE_MAX_FRAME_LENGTH` is set. It leaves room for the command lines of
+/// generated builds with thousands of flags, and for the output of their
+/// compilers.
+const DEFAULT_MAX_FRAME_LENGTH: usize = 32 * 1024 * 1024;
+
+/// How many cache entries are sent to the client at once when listing them,
+/// far below the largest message.
+const LIST_CHUNK_SIZE: usize = 1000;
+
 /// If the dist client couldn't be created, retry creation at this number
 /// of seconds from now (or later)
 #[cfg(feature = "dist-client")]
@@ -100,6 +149,344 @@ fn get_idle_timeout() -> u64 {
         .unwrap_or(DEFAULT_IDLE_TIMEOUT)
 }
 
+/// Get the address the server should listen on, `SCCACHE_SERVER_ADDR` e.g.
+/// `0.0.0.0:4226`, if it is set.
+pub(crate) fn get_server_addr() -> Option<SocketAddr> {
+    let addr = env::var("SCCACHE_SERVER_ADDR").ok()?;
+    match addr.parse() {
+        Ok(addr) => Some(addr),
+        Err(_) => {
+            warn!("Invalid SCCACHE_SERVER_ADDR {:?}, using loopback", addr);
+            None
+        }
+    }
+}
+
+/// Get the token the clients must authenticate with, `SCCACHE_SERVER_TOKEN`,
+/// if it is set.
+pub(crate) fn get_server_token() -> Option<String> {
+    env::var("SCCACHE_SERVER_TOKEN")
+        .ok()
+        .filter(|token| !token.is_empty())
+}
+
+/// Whether the connections which fail before their first request are taken
+/// for the health probes of a load balancer, `SCCACHE_ALLOW_PROBES`, and
+/// closed without logging an error.
+fn get_allow_probes() -> bool {
+    env::var("SCCACHE_ALLOW_PROBES").as_deref() == Ok("1")
+}
+
+/// Whether the server, or the compilations without one, fail to start when
+/// the cache can't be reached, `SCCACHE_ERROR_IF_CACHE_UNREACHABLE`, instead
+/// of running with every lookup missing.
+pub(crate) fn get_error_if_cache_unreachable() -> bool {
+    env::var("SCCACHE_ERROR_IF_CACHE_UNREACHABLE").as_deref() == Ok("1")
+}
+
+/// Check that `storage` can be reached, as `--check-config` does.
+pub(crate) async fn probe_storage(storage: &dyn Storage) -> Result<()> {
+    storage
+        .probe()
+        .await
+        .with_context(|| format!("the cache can't be reached: {}", storage.location()))
+}
+
+/// Get the compilers to detect when the server starts,
+/// `SCCACHE_PREWARM_COMPILERS`, a comma-separated list of paths. The paths
+/// which are not absolute are looked up in the `PATH` of the server.
+pub(crate) fn get_prewarm_compilers() -> Vec<PathBuf> {
+    let Ok(compilers) = env::var("SCCACHE_PREWARM_COMPILERS") else {
+        return vec![];
+    };
+    compilers
+        .split(',')
+        .map(str::trim)
+        .filter(|compiler| !compiler.is_empty())
+        .filter_map(|compiler| {
+            let path = Path::new(compiler);
+            if path.is_absolute() {
+                return Some(path.to_owned());
+            }
+            match which::which(path) {
+                Ok(path) => Some(path),
+                Err(e) => {
+                    warn!("Not prewarming the compiler {:?}: {}", compiler, e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Make `storage` read-only if its check found it to be.
+fn with_cache_mode(storage: Arc<dyn Storage>, mode: CacheMode) -> Arc<dyn Storage> {
+    match mode {
+        CacheMode::ReadOnly => Arc::new(ReadOnlyStorage(storage)),
+        _ => storage,
+    }
+}
+
+/// How often the files of `SCCACHE_CONFIG_DIR` are checked for changes.
+const CONFIG_DIR_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Build the storage of the server again whenever the variables in the
+/// configuration directory `dir` change, e.g. rotated credentials, and pass
+/// the next requests to it. A configuration which fails to load or whose
+/// cache fails its check is logged, and the previous storage kept.
+async fn reload_on_config_dir_change(
+    dir: PathBuf,
+    storage: Arc<ReloadingStorage>,
+    pool: tokio::runtime::Handle,
+) {
+    let fingerprint = |dir: &Path| {
+        config::config_dir_fingerprint(dir)
+            .map_err(|e| warn!("Failed to read SCCACHE_CONFIG_DIR {}: {}", dir.display(), e))
+            .ok()
+    };
+    let mut last = fingerprint(&dir);
+    let mut interval = time::interval(CONFIG_DIR_POLL_INTERVAL);
+    interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let current = fingerprint(&dir);
+        if current.is_none() || current == last {
+            continue;
+        }
+        last = current;
+        let reload = async {
+            let config = Config::load()?;
+            let new = storage_from_config(&config, &pool)?;
+            let mode = new.check().await?;
+            Ok::<_, Error>(with_cache_mode(new, mode))
+        };
+        match reload.await {
+            Ok(new) => {
+                info!("Reloaded the cache configuration after SCCACHE_CONFIG_DIR changed");
+                storage.replace(new);
+            }
+            Err(e) => warn!(
+                "Failed to reload the cache configuration after SCCACHE_CONFIG_DIR changed, \
+                 keeping the previous one: {:#}",
+                e
+            ),
+        }
+    }
+}
+
+/// Get the address the server listens on for `port`: loopback, unless
+/// `SCCACHE_SERVER_ADDR` gives another IP address.
+pub(crate) fn listen_addr(port: u16) -> SocketAddr {
+    let ip = get_server_addr().map_or(Ipv4Addr::LOCALHOST.into(), |addr| addr.ip());
+    SocketAddr::new(ip, port)
+}
+
+/// Get how many connections the OS queues until the server accepts them.
+fn get_listen_backlog() -> u32 {
+    env::var("SCCACHE_LISTEN_BACKLOG")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|&backlog| backlog > 0)
+        .unwrap_or(DEFAULT_LISTEN_BACKLOG)
+}
+
+/// Listen on `addr`, with room for `backlog` connections not accepted yet.
+pub(crate) async fn bind_listener(addr: SocketAddr, backlog: u32) -> io::Result<TcpListener> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    // As `TcpListener::bind` does, so that a restarted server can listen on
+    // the port of the previous one right away.
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    socket.listen(backlog)
+}
+
+/// Get the time the server waits for running compilations when shutting down,
+/// in seconds.
+pub(crate) fn get_shutdown_timeout() -> u64 {
+    env::var("SCCACHE_SHUTDOWN_TIMEOUT")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT)
+}
+
+/// Get the largest message the client and the server exchange, in bytes.
+pub(crate) fn get_max_frame_length() -> usize {
+    match env::var("SCCACHE_MAX_FRAME_LENGTH") {
+        Ok(length) => length.parse().unwrap_or_else(|_| {
+            warn!("Content of SCCACHE_MAX_FRAME_LENGTH is not a valid number, using default");
+            DEFAULT_MAX_FRAME_LENGTH
+        }),
+        Err(_) => DEFAULT_MAX_FRAME_LENGTH,
+    }
+}
+
+/// Hold the `Compile` and `Explain` requests with stdin back until the end of
+/// their stdin, which is appended to them, see `Request::Stdin`. Return the
+/// request to answer, if any.
+fn gather_stdin(
+    with_stdin: &mut Option<Request>,
+    input: SccacheRequest,
+) -> Result<Option<SccacheRequest>> {
+    let request = input.into_inner();
+    if let Request::Stdin(chunk) = request {
+        let compile = match with_stdin {
+            Some(Request::Compile(compile)) | Some(Request::Explain(compile, _)) => compile,
+            _ => bail!("Unexpected stdin"),
+        };
+        if !chunk.is_empty() {
+            compile.stdin.get_or_insert_with(Vec::new).extend(chunk);
+            return Ok(None);
+        }
+        return Ok(with_stdin.take().map(Message::WithoutBody));
+    }
+    if with_stdin.is_some() {
+        bail!("Missing the end of stdin");
+    }
+    match request {
+        Request::Compile(Compile { stdin: Some(_), .. })
+        | Request::Explain(Compile { stdin: Some(_), .. }, _) => {
+            *with_stdin = Some(request);
+            Ok(None)
+        }
+        request => Ok(Some(Message::WithoutBody(request))),
+    }
+}
+
+/// Whether `e` is the error of a message larger than the maximum frame length
+/// being read or written.
+fn is_frame_too_large(e: &Error) -> bool {
+    e.chain().any(|e| {
+        e.downcast_ref::<io::Error>()
+            .and_then(|e| e.get_ref())
+            .is_some_and(|e| e.is::<length_delimited::LengthDelimitedCodecError>())
+    })
+}
+
+/// Send the output of a compiler left in `file` to `tx` from `offset`, in
+/// chunks made into responses by `response`.
+async fn send_spilled(
+    file: fs::File,
+    offset: u64,
+    tx: &BodySender<Response>,
+    response: impl Fn(Vec<u8>) -> Response,
+) -> Result<()> {
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+    let mut file = tokio::fs::File::from_std(file.into_parts().0);
+    file.seek(io::SeekFrom::Start(offset)).await?;
+    loop {
+        let mut chunk = vec![0; 64 * 1024];
+        let n = file.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        chunk.truncate(n);
+        tx.send(Ok(response(chunk)))
+            .await
+            .map_err(|e| anyhow!("send on finish failed").context(e))?;
+    }
+}
+
+/// Get how long the server may take to answer a request, not counting the
+/// compilation it started, `SCCACHE_SERVER_REQUEST_TIMEOUT` in seconds, if it
+/// is set.
+fn get_request_timeout() -> Option<Duration> {
+    env::var("SCCACHE_SERVER_REQUEST_TIMEOUT")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|&secs| secs > 0)
+        .map(Duration::from_secs)
+}
+
+/// Get the number of compilers the server runs at once.
+fn get_max_jobs() -> usize {
+    env::var("SCCACHE_SERVER_MAX_JOBS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|&jobs| jobs > 0)
+        .unwrap_or_else(num_cpus::get)
+}
+
+/// Get the number of compilations that may be in progress besides those
+/// running a compiler, above which new compile requests aren't answered until
+/// there is room.
+fn get_max_queued_compiles() -> usize {
+    env::var("SCCACHE_SERVER_MAX_QUEUED_JOBS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_MAX_QUEUED_COMPILES)
+}
+
+/// The names of the languages, as `Language::as_str` gives them.
+const LANGUAGE_NAMES: &[&str] = &[
+    "c",
+    "c++",
+    "c/c++",
+    "objc",
+    "objc++",
+    "cuda",
+    "rust",
+    "hip",
+    "assembler",
+    "assembler-cpp",
+];
+
+/// Which languages are cached, from `SCCACHE_ENABLE_LANGS` and
+/// `SCCACHE_DISABLE_LANGS`, lists of comma-separated language names such as
+/// `c++` or `rust`. The compilations of the other languages are run without
+/// the cache.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct LanguageFilter {
+    /// If set, only these languages are cached.
+    enabled: Option<Vec<String>>,
+    /// These languages are never cached.
+    disabled: Vec<String>,
+}
+
+impl LanguageFilter {
+    pub(crate) fn from_env() -> LanguageFilter {
+        LanguageFilter::new(
+            env::var("SCCACHE_ENABLE_LANGS").ok().as_deref(),
+            env::var("SCCACHE_DISABLE_LANGS").ok().as_deref(),
+        )
+    }
+
+    pub(crate) fn new(enabled: Option<&str>, disabled: Option<&str>) -> LanguageFilter {
+        fn parse(list: &str) -> Vec<String> {
+            list.split(',')
+                .map(|name| name.trim().to_lowercase())
+                .filter(|name| !name.is_empty())
+                .inspect(|name| {
+                    if !LANGUAGE_NAMES.contains(&name.as_str()) {
+                        warn!(
+                            "Unknown language {:?}, expected one of {}",
+                            name,
+                            LANGUAGE_NAMES.join(", ")
+                        );
+                    }
+                })
+                .collect()
+        }
+        LanguageFilter {
+            enabled: enabled.map(parse),
+            disabled: disabled.map(parse).unwrap_or_default(),
+        }
+    }
+
+    /// Whether the compilations of `lang` are cached.
+    pub(crate) fn allows(&self, lang: Language) -> bool {
+        let name = lang.as_str();
+        self.enabled
+            .as_ref()
+            .map_or(true, |enabled| enabled.iter().any(|e| e == name))
+            && !self.disabled.iter().any(|d| d == name)
+    }
+}
+
 fn notify_server_startup_internal<W: Write>(mut w: W, status: ServerStartup) -> Result<()> {
     util::write_length_prefixed_bincode(&mut w, status)
 }
@@ -341,14 +728,9 @@ impl DistClientContainer {
             Some(ref addr) => {
                 let url = addr.to_url();
                 info!("Enabling distributed sccache to {}", url);
-                let auth_token = match &config.auth {
-                    config::DistAuth::Token { token } => Ok(token.to_owned()),
-                    config::DistAuth::Oauth2CodeGrantPKCE { auth_url, .. }
-                    | config::DistAuth::Oauth2Implicit { auth_url, .. } => {
-                        Self::get_cached_config_auth_token(auth_url)
-                    }
-                };
-                let auth_token = try_or_fail_with_message!(auth_token
+                let auth_token = try_or_fail_with_message!(config
+                    .auth
+                    .token()
                     .context("could not load client auth token, run |sccache --dist-auth|"));
                 let dist_client = dist::http::Client::new(
                     &config.pool,
@@ -385,13 +767,6 @@ impl DistClientContainer {
             }
         }
     }
-
-    fn get_cached_config_auth_token(auth_url: &str) -> Result<String> {
-        let cached_config = config::CachedConfig::reload()?;
-        cached_config
-            .with(|c| c.dist.auth_tokens.get(auth_url).map(String::to_owned))
-            .with_context(|| format!("token for url {} not present in cached config", auth_url))
-    }
 }
 
 thread_local! {
@@ -406,6 +781,21 @@ thread_local! {
 /// Spins an event loop handling client connections until a client
 /// requests a shutdown.
 pub fn start_server(config: &Config, port: u16) -> Result<()> {
+    run_server(config, port, future::pending::<()>(), false)
+}
+
+/// Start an sccache server in the foreground, as run by a service manager,
+/// listening on `port` until `shutdown` resolves or a client requests a
+/// shutdown.
+///
+/// Unlike a server spawned by the client, it doesn't shut down when idle
+/// unless `SCCACHE_IDLE_TIMEOUT` is set, as the service manager is in charge
+/// of its lifetime.
+pub fn start_foreground_server<F: Future>(config: &Config, port: u16, shutdown: F) -> Result<()> {
+    run_server(config, port, shutdown, true)
+}
+
+fn run_server<F: Future>(config: &Config, port: u16, shutdown: F, foreground: bool) -> Result<()> {
     info!("start_server: port: {}", port);
     let panic_hook = std::panic::take_hook();
     std::panic::set_hook(Box::new(move |info| {
@@ -418,15 +808,30 @@ pub fn start_server(config: &Config, port: u16) -> Result<()> {
         panic_hook(info)
     }));
     let client = unsafe { Client::new() };
+    affinity::pin_server_thread();
     let runtime = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .worker_threads(std::cmp::max(20, 2 * num_cpus::get()))
+        .on_thread_start(affinity::pin_server_thread)
         .build()?;
     let pool = runtime.handle().clone();
     let dist_client = DistClientContainer::new(config, &pool);
 
     let notify = env::var_os("SCCACHE_STARTUP_NOTIFY");
 
+    let compiler_map = match CompilerPathMap::from_env() {
+        Ok(compiler_map) => compiler_map,
+        Err(err) => {
+            notify_server_startup(
+                &notify,
+                ServerStartup::Err {
+                    reason: format!("{:#}", err),
+                },
+            )?;
+            return Err(err);
+        }
+    };
+
     let raw_storage = match storage_from_config(config, &pool) {
         Ok(storage) => storage,
         Err(err) => {
@@ -444,7 +849,13 @@ pub fn start_server(config: &Config, port: u16) -> Result<()> {
     };
 
     let cache_mode = runtime.block_on(async {
-        match raw_storage.check().await {
+        let check = async {
+            if get_error_if_cache_unreachable() {
+                probe_storage(&*raw_storage).await?;
+            }
+            raw_storage.check().await
+        };
+        match check.await {
             Ok(mode) => Ok(mode),
             Err(err) => {
                 error!("storage check failed for: {err:?}");
@@ -462,19 +873,80 @@ pub fn start_server(config: &Config, port: u16) -> Result<()> {
     })?;
     info!("server has setup with {cache_mode:?}");
 
-    let storage = match cache_mode {
-        CacheMode::ReadOnly => Arc::new(ReadOnlyStorage(raw_storage)),
-        _ => raw_storage,
-    };
+    let mut storage = with_cache_mode(raw_storage, cache_mode);
+    if let Some(dir) = config::config_dir() {
+        let reloading = Arc::new(ReloadingStorage::new(storage));
+        runtime.spawn(reload_on_config_dir_change(
+            dir,
+            reloading.clone(),
+            pool.clone(),
+        ));
+        storage = reloading;
+    }
 
-    let res =
-        SccacheServer::<ProcessCommandCreator>::new(port, runtime, client, dist_client, storage);
+    // The compilers are killed if the client they compile for goes away.
+    let creator = ProcessCommandCreator::new(&client).interruptible();
+    // The socket systemd passed, if any, is used instead of the port.
+    let res = match service::activated_listener(&runtime) {
+        Ok(Some(listener)) => Ok(SccacheServer::<ProcessCommandCreator>::with_listener(
+            listener,
+            runtime,
+            client,
+            dist_client,
+            storage,
+        )),
+        Ok(None) => {
+            SccacheServer::<ProcessCommandCreator>::new(port, runtime, client, dist_client, storage)
+        }
+        Err(e) => Err(e),
+    };
     match res {
-        Ok(srv) => {
+        Ok(mut srv) => {
+            srv.set_command_creator(creator);
+            srv.set_compiler_wrappers(
+                config
+                    .compiler_wrappers
+                    .iter()
+                    .map(|(path, wrapper)| CompilerWrapper::from_config(path, wrapper)),
+            );
+            srv.set_compiler_map(compiler_map);
+            if foreground && env::var_os("SCCACHE_IDLE_TIMEOUT").is_none() {
+                srv.set_idle_timeout(Duration::from_secs(0));
+            }
+            srv.set_cache_overrides(CacheOverrides::from_env(config, &pool));
+            srv.layer(
+                ServiceBuilder::new()
+                    .map_err(|e: tower::BoxError| anyhow!(e))
+                    .option_layer(get_request_timeout().map(TimeoutLayer::new)),
+            );
+            srv.prewarm(get_prewarm_compilers());
+            let admin = admin::addr();
+            if let Some(addr) = &admin {
+                if let Err(e) = srv.listen_admin(addr) {
+                    let reason = format!("failed to listen on SCCACHE_ADMIN_ADDR: {}", e);
+                    notify_server_startup(&notify, ServerStartup::Err { reason })?;
+                    return Err(e.into());
+                }
+                info!("listening for the admin requests on {:?}", addr);
+            }
             let port = srv.port();
             info!("server started, listening on port {}", port);
+            // The address of a Unix socket is only ever configured.
+            let discovered = (port != 0).then(|| listen_addr(port));
+            if let Some(addr) = discovered {
+                if let Err(e) = discovery::publish(addr) {
+                    warn!("Failed to write the discovery file: {}", e);
+                }
+            }
             notify_server_startup(&notify, ServerStartup::Ok { port })?;
-            srv.run(future::pending::<()>())?;
+            let res = srv.run(shutdown);
+            if let Some(addr) = discovered {
+                discovery::withdraw(addr);
+            }
+            if let Some(addr) = &admin {
+                admin::unbind(addr);
+            }
+            res?;
             Ok(())
         }
         Err(e) => {
@@ -501,12 +973,61 @@ pub fn start_server(config: &Config, port: u16) -> Result<()> {
     }
 }
 
+/// The socket the server accepts the connections of the clients on.
+pub enum Acceptor {
+    Tcp(TcpListener),
+    /// A Unix socket, inherited from systemd, or the admin socket.
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl From<TcpListener> for Acceptor {
+    fn from(listener: TcpListener) -> Acceptor {
+        Acceptor::Tcp(listener)
+    }
+}
+
+impl Acceptor {
+    /// Accept the next connection, and return the future serving it, taking
+    /// the requests of `role`.
+    async fn accept<C: CommandCreatorSync>(
+        &self,
+        service: &SccacheService<C>,
+        stack: &SccacheStack,
+        role: Role,
+    ) -> io::Result<future::BoxFuture<'static, Result<()>>> {
+        Ok(match self {
+            Acceptor::Tcp(listener) => {
+                let (socket, _) = listener.accept().await?;
+                let peer = Peer::tcp(&socket);
+                service
+                    .clone()
+                    .bind(socket, stack.clone(), peer, role)
+                    .boxed()
+            }
+            #[cfg(unix)]
+            Acceptor::Unix(listener) => {
+                let (socket, _) = listener.accept().await?;
+                let peer = Peer::unix(&socket);
+                service
+                    .clone()
+                    .bind(socket, stack.clone(), peer, role)
+                    .boxed()
+            }
+        })
+    }
+}
+
 pub struct SccacheServer<C: CommandCreatorSync> {
     runtime: Runtime,
-    listener: TcpListener,
+    listener: Acceptor,
+    /// The admin socket, if any, see `crate::admin`.
+    admin: Option<Acceptor>,
     rx: mpsc::Receiver<ServerMessage>,
     timeout: Duration,
+    clock: SharedClock,
     service: SccacheService<C>,
+    layers: Vec<Box<dyn FnOnce(SccacheStack) -> SccacheStack + Send>>,
     wait: WaitUntilZero,
 }
 
@@ -518,8 +1039,16 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
         dist_client: DistClientContainer,
         storage: Arc<dyn Storage>,
     ) -> Result<SccacheServer<C>> {
-        let addr = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), port);
-        let listener = runtime.block_on(TcpListener::bind(&SocketAddr::V4(addr)))?;
+        let addr = listen_addr(port);
+        if !addr.ip().is_loopback() && get_server_token().is_none() {
+            warn!(
+                "Listening on {}, which other hosts may reach, without a \
+                 SCCACHE_SERVER_TOKEN: anyone who connects can run compilations \
+                 as this user and read and write the cache",
+                addr
+            );
+        }
+        let listener = runtime.block_on(bind_listener(addr, get_listen_backlog()))?;
 
         Ok(Self::with_listener(
             listener,
@@ -531,7 +1060,7 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
     }
 
     pub fn with_listener(
-        listener: TcpListener,
+        listener: impl Into<Acceptor>,
         runtime: Runtime,
         client: Client,
         dist_client: DistClientContainer,
@@ -546,26 +1075,136 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
 
         SccacheServer {
             runtime,
-            listener,
+            listener: listener.into(),
+            admin: None,
             rx,
             service,
+            layers: vec![],
             timeout: Duration::from_secs(get_idle_timeout()),
+            clock: clock::system(),
             wait,
         }
     }
 
+    /// Listens for the admin requests on `addr`, which the socket of the
+    /// clients then refuses.
+    pub fn listen_admin(&mut self, addr: &AdminAddr) -> io::Result<()> {
+        if let AdminAddr::Tcp(addr) = addr {
+            if !addr.ip().is_loopback() && self.service.token.is_none() {
+                warn!(
+                    "The admin socket listens on {}, which other hosts may reach, \
+                     without a SCCACHE_SERVER_TOKEN: anyone who connects can \
+                     shut the server down",
+                    addr
+                );
+            }
+        }
+        self.admin = Some(self.runtime.block_on(admin::bind(addr))?);
+        Ok(())
+    }
+
     /// Configures how long this server will be idle before shutting down.
     #[allow(dead_code)]
     pub fn set_idle_timeout(&mut self, timeout: Duration) {
         self.timeout = timeout;
     }
 
+    /// Configures the clock timing the idle shutdown.
+    #[allow(dead_code)]
+    pub fn set_clock(&mut self, clock: SharedClock) {
+        self.clock = clock;
+    }
+
+    /// Configures how long this server waits for running compilations when
+    /// shutting down.
+    #[allow(dead_code)]
+    pub fn set_shutdown_timeout(&mut self, timeout: Duration) {
+        self.service.shutdown_timeout = timeout;
+    }
+
+    /// Configures how many compilers this server runs at once, and how many
+    /// more compilations may be in progress.
+    #[allow(dead_code)]
+    pub fn set_max_jobs(&mut self, max_jobs: usize, max_queued: usize) {
+        self.service.compile_queue = Arc::new(CompileQueue::new(max_jobs, max_queued));
+    }
+
+    /// Configures the token the connections must authenticate with, if any.
+    #[allow(dead_code)]
+    pub fn set_token(&mut self, token: Option<&str>) {
+        self.service.token = token.map(Into::into);
+    }
+
+    /// Configures whether the health probes are closed quietly.
+    #[allow(dead_code)]
+    pub fn set_allow_probes(&mut self, allow_probes: bool) {
+        self.service.allow_probes = allow_probes;
+    }
+
+    /// Configures the caches the compilations may choose with
+    /// `SCCACHE_CACHE_OVERRIDE`.
+    #[allow(dead_code)]
+    pub fn set_cache_overrides(&mut self, cache_overrides: CacheOverrides) {
+        self.service.cache_overrides = Arc::new(cache_overrides);
+    }
+
+    /// Configures which languages this server caches.
+    #[allow(dead_code)]
+    pub(crate) fn set_languages(&mut self, languages: LanguageFilter) {
+        self.service.languages = Arc::new(languages);
+    }
+
+    /// Set the compiler wrappers this server will see through.
+    pub fn set_compiler_wrappers<I>(&mut self, wrappers: I)
+    where
+        I: IntoIterator<Item = CompilerWrapper>,
+    {
+        self.service.compiler_wrappers = Arc::new(
+            wrappers
+                .into_iter()
+                .map(|wrapper| (wrapper.path.clone(), Arc::new(wrapper)))
+                .collect(),
+        );
+    }
+
+    /// Set the compilers this server runs for those of the clients.
+    pub fn set_compiler_map(&mut self, compiler_map: CompilerPathMap) {
+        self.service.compiler_map = Arc::new(compiler_map);
+    }
+
+    /// Detect `compilers` in the background, ahead of their first
+    /// compilations.
+    pub fn prewarm(&self, compilers: Vec<PathBuf>) {
+        self.service.prewarm(compilers);
+    }
+
+    /// Wrap the service answering the requests in `layer`, e.g. a middleware
+    /// of the `tower` ecosystem, outside of the layers added before.
+    pub fn layer<L>(&mut self, layer: L)
+    where
+        L: Layer<SccacheStack> + Send + 'static,
+        L::Service: Service<SccacheRequest, Response = SccacheResponse, Error = Error>
+            + Clone
+            + Send
+            + 'static,
+        <L::Service as Service<SccacheRequest>>::Future: Send + 'static,
+    {
+        self.layers.push(Box::new(move |stack| {
+            BoxCloneService::new(layer.layer(stack))
+        }));
+    }
+
     /// Set the storage this server will use.
     #[allow(dead_code)]
     pub fn set_storage(&mut self, storage: Arc<dyn Storage>) {
         self.service.storage = storage;
     }
 
+    /// Set the command creator this server will use.
+    pub fn set_command_creator(&mut self, creator: C) {
+        self.service.creator = creator;
+    }
+
     /// Returns a reference to a thread pool to run work on
     #[allow(dead_code)]
     pub fn pool(&self) -> &tokio::runtime::Handle {
@@ -578,10 +1217,15 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
         &self.service.creator
     }
 
-    /// Returns the port that this server is bound to
+    /// Returns the port that this server is bound to, or 0 if it listens on a
+    /// Unix socket.
     #[allow(dead_code)]
     pub fn port(&self) -> u16 {
-        self.listener.local_addr().unwrap().port()
+        match &self.listener {
+            Acceptor::Tcp(listener) => listener.local_addr().unwrap().port(),
+            #[cfg(unix)]
+            Acceptor::Unix(_) => 0,
+        }
     }
 
     /// Runs this server to completion.
@@ -597,19 +1241,36 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
         let SccacheServer {
             runtime,
             listener,
+            admin,
             rx,
             service,
+            layers,
             timeout,
+            clock,
             wait,
         } = self;
+        let stack = layers.into_iter().fold(
+            BoxCloneService::new(Arc::new(service.clone())),
+            |stack, layer| layer(stack),
+        );
+        let active_compiles = service.active_compiles.clone();
+        let shutdown_timeout = service.shutdown_timeout;
+        let stats_on_exit =
+            env::var_os("SCCACHE_STATS_ON_EXIT").map(|path| (path, service.clone()));
 
         // Create our "server future" which will simply handle all incoming
         // connections in separate tasks.
         let server = async move {
             loop {
-                let (socket, _) = listener.accept().await?;
+                let conn = match &admin {
+                    Some(admin) => tokio::select! {
+                        conn = listener.accept(&service, &stack, Role::Compile) => conn,
+                        conn = admin.accept(&service, &stack, Role::Admin) => conn,
+                    },
+                    None => listener.accept(&service, &stack, Role::All).await,
+                }?;
                 trace!("incoming connection");
-                let conn = service.clone().bind(socket).map_err(|res| {
+                let conn = conn.map_err(|res| {
                     error!("Failed to bind socket: {}", res);
                 });
 
@@ -640,11 +1301,12 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
             ShutdownOrInactive {
                 rx,
                 timeout: if timeout != Duration::new(0, 0) {
-                    Some(Box::pin(sleep(timeout)))
+                    Some(clock.sleep(timeout))
                 } else {
                     None
                 },
                 timeout_dur: timeout,
+                clock,
             }
             .await;
             info!("shutting down due to being idle or request");
@@ -658,21 +1320,48 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
             }
         })?;
 
-        const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
         info!(
             "moving into the shutdown phase now, waiting at most {} seconds \
-             for all client requests to complete",
-            SHUTDOWN_TIMEOUT.as_secs()
+             for running compilations to complete",
+            shutdown_timeout.as_secs()
         );
 
         // Once our server has shut down either due to inactivity or a manual
-        // request we still need to give a bit of time for all active
-        // connections to finish. This `wait` future will resolve once all
-        // instances of `SccacheService` have been dropped.
+        // request we still need to give time for the running compilations to
+        // finish, up to the shutdown timeout.
+        let abandoned = runtime.block_on(wait_for_compiles(&active_compiles, shutdown_timeout));
+        if abandoned > 0 {
+            warn!(
+                "shutdown timed out, abandoning {} running compilations",
+                abandoned
+            );
+        }
+
+        // The stats are final once the compilations are done, and the service
+        // must be dropped before waiting for the connections below.
+        if let Some((path, service)) = stats_on_exit {
+            let path = Path::new(&path);
+            match runtime
+                .block_on(service.get_info())
+                .and_then(|info| write_stats(path, &info))
+            {
+                Ok(()) => info!("wrote the final stats to {}", path.display()),
+                Err(e) => warn!(
+                    "failed to write the final stats to {}: {:#}",
+                    path.display(),
+                    e
+                ),
+            }
+        }
+
+        // Then give a bit of time for all active connections to finish, e.g. to
+        // send the results of the compilations. This `wait` future will
+        // resolve once all instances of `SccacheService` have been dropped.
         //
         // Note that we cap the amount of time this can take, however, as we
         // don't want to wait *too* long.
-        runtime.block_on(async { time::timeout(SHUTDOWN_TIMEOUT, wait).await })?;
+        const CONNECTIONS_TIMEOUT: Duration = Duration::from_secs(1);
+        let _ = runtime.block_on(async { time::timeout(CONNECTIONS_TIMEOUT, wait).await });
 
         info!("ok, fully shutting down now");
 
@@ -683,6 +1372,8 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
 /// Maps a compiler proxy path to a compiler proxy and it's last modification time
 type CompilerProxyMap<C> = HashMap<PathBuf, (Box<dyn CompilerProxy<C>>, FileTime)>;
 type CompilerMap<C> = HashMap<PathBuf, Option<CompilerCacheEntry<C>>>;
+/// Maps the path of a compiler being prewarmed to its detection
+type PrewarmMap = HashMap<PathBuf, future::Shared<future::BoxFuture<'static, ()>>>;
 
 /// entry of the compiler cache
 struct CompilerCacheEntry<C> {
@@ -692,6 +1383,8 @@ struct CompilerCacheEntry<C> {
     pub mtime: FileTime,
     /// distributed compilation extra info
     pub dist_info: Option<(PathBuf, FileTime)>,
+    /// the compiler kind forced by the client, if any
+    pub kind_override: Option<OsString>,
 }
 
 impl<C> CompilerCacheEntry<C> {
@@ -699,11 +1392,13 @@ impl<C> CompilerCacheEntry<C> {
         compiler: Box<dyn Compiler<C>>,
         mtime: FileTime,
         dist_info: Option<(PathBuf, FileTime)>,
+        kind_override: Option<OsString>,
     ) -> Self {
         Self {
             compiler,
             mtime,
             dist_info,
+            kind_override,
         }
     }
 }
@@ -753,10 +1448,54 @@ where
     /// This field causes [WaitUntilZero] to wait until this struct drops.
     #[allow(dead_code)]
     info: ActiveInfo,
+
+    /// The number of compilations running.
+    active_compiles: Arc<watch::Sender<usize>>,
+
+    /// How long a shutdown request waits for the running compilations.
+    shutdown_timeout: Duration,
+
+    /// The number of cache writes still running after their compilations
+    /// were answered.
+    pending_writes: Arc<watch::Sender<usize>>,
+
+    /// The compile queue, bounding how many compilations run at once and how
+    /// many wait for a job.
+    compile_queue: Arc<CompileQueue>,
+
+    /// The identical compilations in flight, by cache key.
+    flights: Arc<Flights>,
+
+    /// The compiler wrappers, by path.
+    compiler_wrappers: Arc<HashMap<PathBuf, Arc<CompilerWrapper>>>,
+
+    /// The compilers run for those of the clients.
+    compiler_map: Arc<CompilerPathMap>,
+
+    /// The token the connections must authenticate with, if any.
+    token: Option<Arc<str>>,
+
+    /// Whether the health probes are closed quietly.
+    allow_probes: bool,
+
+    /// The caches the compilations may choose instead of `storage`.
+    cache_overrides: Arc<CacheOverrides>,
+
+    /// Which languages are cached.
+    languages: Arc<LanguageFilter>,
+
+    /// The compilers being detected ahead of their first compilations, by
+    /// path.
+    prewarming: Arc<std::sync::Mutex<PrewarmMap>>,
 }
 
-type SccacheRequest = Message<Request, Body<()>>;
-type SccacheResponse = Message<Response, Body<Response>>;
+/// A request of a client, as the service answering the requests gets it.
+pub type SccacheRequest = Message<Request, Body<()>>;
+/// The answer to a request, with the results of a compilation it started, if
+/// any, as its body.
+pub type SccacheResponse = Message<Response, Body<Response>>;
+/// The service answering the requests, with the layers of the server.
+pub type SccacheStack = BoxCloneService<SccacheRequest, SccacheResponse, Error>;
 
 /// Messages sent from all services to the main event loop indicating activity.
 ///
@@ -789,11 +1528,19 @@ where
         let me = self.clone();
         Box::pin(async move {
             match req.into_inner() {
+                Request::Authenticate(_) => Ok(Message::WithoutBody(Response::Authenticated)),
                 Request::Compile(compile) => {
                     debug!("handle_client: compile");
                     me.stats.lock().await.compile_requests += 1;
                     me.handle_compile(compile).await
                 }
+                Request::Explain(compile, extract_to) => {
+                    debug!("handle_client: explain");
+                    Ok(Message::WithoutBody(Response::Explain(
+                        me.handle_explain(compile, extract_to.map(PathBuf::from))
+                            .await,
+                    )))
+                }
                 Request::GetStats => {
                     debug!("handle_client: get_stats");
                     me.get_info()
@@ -813,21 +1560,29 @@ where
                     me.zero_stats().await;
                     Ok(Message::WithoutBody(Response::ZeroStats))
                 }
+                Request::Flush => {
+                    debug!("handle_client: flush");
+                    Ok(Message::WithoutBody(Response::Flushed(me.flush().await)))
+                }
                 Request::Shutdown => {
                     debug!("handle_client: shutdown");
-                    let mut tx = me.tx.clone();
-                    future::try_join(
-                        async {
-                            let _ = tx.send(ServerMessage::Shutdown).await;
-                            Ok(())
-                        },
-                        me.get_info(),
-                    )
-                    .await
-                    .map(move |(_, info)| {
-                        Message::WithoutBody(Response::ShuttingDown(Box::new(info)))
+                    let _ = me.tx.clone().send(ServerMessage::Shutdown).await;
+                    let abandoned =
+                        wait_for_compiles(&me.active_compiles, me.shutdown_timeout).await;
+                    me.get_info().await.map(move |info| {
+                        Message::WithoutBody(Response::ShuttingDown(Box::new(info), abandoned))
                     })
                 }
+                Request::ListEntries(prefix) => {
+                    debug!("handle_client: list_entries");
+                    Ok(me.list_entries(prefix))
+                }
+                Request::RemoveEntry(key) => {
+                    debug!("handle_client: remove_entry");
+                    Ok(Message::WithoutBody(me.remove_entry(&key).await))
+                }
+                // The connection gathers stdin into the request it follows.
+                Request::Stdin(_) => bail!("Unexpected stdin"),
             }
         })
     }
@@ -862,50 +1617,235 @@ where
             creator: C::new(client),
             tx,
             info,
+            active_compiles: Arc::new(watch::channel(0).0),
+            shutdown_timeout: Duration::from_secs(get_shutdown_timeout()),
+            pending_writes: Arc::new(watch::channel(0).0),
+            compile_queue: Arc::new(CompileQueue::new(get_max_jobs(), get_max_queued_compiles())),
+            flights: Arc::default(),
+            compiler_wrappers: Arc::default(),
+            compiler_map: Arc::default(),
+            token: get_server_token().map(Into::into),
+            allow_probes: get_allow_probes(),
+            cache_overrides: Arc::default(),
+            languages: Arc::new(LanguageFilter::from_env()),
+            prewarming: Arc::default(),
         }
     }
 
-    fn bind<T>(self, socket: T) -> impl Future<Output = Result<()>> + Send + Sized + 'static
-    where
-        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
-    {
-        let mut builder = length_delimited::Builder::new();
-        if let Ok(max_frame_length_str) = env::var("SCCACHE_MAX_FRAME_LENGTH") {
-            if let Ok(max_frame_length) = max_frame_length_str.parse::<usize>() {
-                builder.max_frame_length(max_frame_length);
-            } else {
-                warn!("Content of SCCACHE_MAX_FRAME_LENGTH is not a valid number, using default");
+    /// Detect `compilers` in the background, as if compiling with them in
+    /// the working directory and environment of the server. Failing to
+    /// detect one is only logged.
+    fn prewarm(&self, compilers: Vec<PathBuf>) {
+        let cwd = env::current_dir().unwrap_or_default();
+        let env_vars = env::vars_os().collect::<Vec<_>>();
+        for compiler in compilers {
+            if !compiler.is_file() {
+                warn!(
+                    "Not prewarming the compiler {:?}, which doesn't exist",
+                    compiler
+                );
+                continue;
             }
+            let me = self.clone();
+            let (path, cwd, env_vars) = (compiler.clone(), cwd.clone(), env_vars.clone());
+            let detect = async move {
+                match me.compiler_info(path.clone(), cwd, &[], &env_vars).await {
+                    Ok(c) => info!("Prewarmed the compiler {:?}: {:?}", path, c.kind()),
+                    Err(e) => warn!("Failed to prewarm the compiler {:?}: {:#}", path, e),
+                }
+            }
+            .boxed()
+            .shared();
+            self.prewarming
+                .lock()
+                .unwrap()
+                .insert(compiler.clone(), detect.clone());
+            let prewarming = self.prewarming.clone();
+            self.rt.spawn(async move {
+                detect.await;
+                prewarming.lock().unwrap().remove(&compiler);
+            });
         }
-        let io = builder.new_framed(socket);
+    }
 
-        let (sink, stream) = SccacheTransport {
-            inner: Framed::new(io.sink_err_into().err_into(), BincodeCodec),
+    /// Wait for `exe` to be prewarmed, if it is being, rather than detecting
+    /// it twice.
+    async fn wait_for_prewarm(&self, exe: &Path) {
+        let detect = self.prewarming.lock().unwrap().get(exe).cloned();
+        if let Some(detect) = detect {
+            trace!("Waiting for the compiler {:?} to be prewarmed", exe);
+            detect.await;
+        }
+    }
+
+    /// Check that the connection is authenticated before `request`, or that
+    /// `request` authenticates it, and that `peer` may send `request` on a
+    /// socket of `role`. Returns the response if the request is not to be
+    /// handled further.
+    fn authorize(
+        &self,
+        authenticated: &mut bool,
+        role: Role,
+        peer: &Peer,
+        request: &Request,
+    ) -> Option<Response> {
+        if let Err(reason) = role.check(peer, request) {
+            warn!("Refusing a request on the {:?} socket: {}", role, reason);
+            return Some(Response::Refused(reason.to_owned()));
         }
-        .split();
+        match request {
+            Request::Authenticate(token) => {
+                // The token may be sent to a server which doesn't require one.
+                let valid = self.token.as_ref().map_or(true, |expected| {
+                    util::constant_time_eq(token.as_bytes(), expected.as_bytes())
+                });
+                if valid {
+                    *authenticated = true;
+                    Some(Response::Authenticated)
+                } else {
+                    warn!("Rejecting a connection with the wrong token");
+                    Some(Response::Unauthorized)
+                }
+            }
+            _ if *authenticated || self.token.is_none() => None,
+            _ => {
+                warn!("Rejecting a connection without a token");
+                Some(Response::Unauthorized)
+            }
+        }
+    }
+
+    fn bind<T>(
+        self,
+        socket: T,
+        stack: SccacheStack,
+        peer: Peer,
+        role: Role,
+    ) -> impl Future<Output = Result<()>> + Send + Sized + 'static
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let max_frame_length = get_max_frame_length();
+        let (sink, stream) = SccacheTransport::new(socket, max_frame_length).split();
         let sink = sink.sink_err_into::<Error>();
 
+        // Close the connection once a shutdown request was answered, so that
+        // the client can wait for it to be closed.
+        let (shutdown_tx, shutdown_rx) = futures::channel::oneshot::channel();
+        let mut shutdown_tx = Some(shutdown_tx);
+
+        // The connection is closed after a request was rejected for lack of
+        // the right token, leaving one guess per connection, or refused on
+        // this socket.
+        let mut authenticated = false;
+        let rejected = Arc::new(AtomicBool::new(false));
+        let mut with_stdin = None;
+        let rejected_ = rejected.clone();
+
+        // A health probe connects, and closes or sends a few bytes which are
+        // not a request.
+        let requested = Arc::new(AtomicBool::new(false));
+        let requested_ = requested.clone();
+        let allow_probes = self.allow_probes;
+        let peer = Arc::new(peer);
+
+        // The requests are read while the responses are written, so that the
+        // end of the requests, e.g. the client going away, is seen while a
+        // compilation runs, cutting short its body.
+        let (requests_tx, requests) = mpsc::channel(0);
+        let (disconnected_tx, disconnected_rx) = futures::channel::oneshot::channel::<()>();
+        let disconnected = disconnected_rx.shared();
+        let read = stream
+            .map(Ok)
+            .forward(requests_tx)
+            .map(move |_| drop(disconnected_tx));
+
         let me = Arc::new(self);
-        stream
+        let conn = requests
             .err_into::<Error>()
-            .and_then(move |input| me.clone().call(input))
-            .and_then(move |message| async move {
-                let fut = match message {
-                    Message::WithoutBody(message) => {
-                        let stream = stream::once(async move { Ok(Frame::Message { message }) });
-                        Either::Left(stream)
-                    }
-                    Message::WithBody(message, body) => {
-                        let stream = stream::once(async move { Ok(Frame::Message { message }) })
-                            .chain(body.map_ok(|chunk| Frame::Body { chunk: Some(chunk) }))
-                            .chain(stream::once(async move { Ok(Frame::Body { chunk: None }) }));
-                        Either::Right(stream)
+            .inspect_ok(move |input| {
+                requested_.store(true, Ordering::SeqCst);
+                if let Message::WithoutBody(Request::Shutdown) = input {
+                    if let Some(tx) = shutdown_tx.take() {
+                        let _ = tx.send(());
                     }
-                };
-                Ok(Box::pin(fut))
+                }
+            })
+            .take_until(shutdown_rx)
+            .try_take_while(move |_| future::ready(Ok(!rejected_.load(Ordering::SeqCst))))
+            .and_then(move |input| {
+                if let Some(response) =
+                    me.authorize(&mut authenticated, role, &peer, input.get_ref())
+                {
+                    if let Response::Unauthorized | Response::Refused(_) = response {
+                        rejected.store(true, Ordering::SeqCst);
+                    }
+                    let response = Ok(Message::WithoutBody(response));
+                    if let Some(log) = ACCESS_LOG.as_ref() {
+                        let request = log.request(&peer, authenticated, input.get_ref());
+                        log.write_response(&request, &response);
+                    }
+                    return Either::Right(future::ready(response.map(Some)));
+                }
+                match gather_stdin(&mut with_stdin, input) {
+                    Ok(Some(input)) => {
+                        let request = ACCESS_LOG
+                            .as_ref()
+                            .map(|log| log.request(&peer, authenticated, input.get_ref()));
+                        let answer = stack.clone().oneshot(input);
+                        Either::Left(access_log::log_request(request, answer).map_ok(Some))
+                    }
+                    Ok(None) => Either::Right(future::ok(None)),
+                    Err(e) => Either::Right(future::err(e)),
+                }
+            })
+            .and_then(move |message| {
+                let disconnected = disconnected.clone();
+                async move {
+                    let fut = message.map(|message| match message {
+                        Message::WithoutBody(message) => {
+                            let stream =
+                                stream::once(async move { Ok(Frame::Message { message }) });
+                            Either::Left(stream)
+                        }
+                        Message::WithBody(message, body) => {
+                            let stream =
+                                stream::once(async move { Ok(Frame::Message { message }) })
+                                    .chain(
+                                        body.take_until(disconnected)
+                                            .map_ok(|chunk| Frame::Body { chunk: Some(chunk) }),
+                                    )
+                                    .chain(stream::once(
+                                        async move { Ok(Frame::Body { chunk: None }) },
+                                    ));
+                            Either::Right(stream)
+                        }
+                    });
+                    // The chunks of stdin aren't answered.
+                    Ok(Box::pin(stream::iter(fut).flatten()))
+                }
             })
             .try_flatten()
-            .forward(sink)
+            .forward(sink);
+        let conn = async move {
+            futures::pin_mut!(conn);
+            match future::select(read, conn).await {
+                // The responses to the last requests are still written.
+                Either::Left((_, conn)) => conn.await,
+                Either::Right((res, _)) => res,
+            }
+        };
+        conn.map(move |res| match res {
+            Err(e) if allow_probes && !requested.load(Ordering::SeqCst) => {
+                debug!("Closed a health probe: {:#}", e);
+                Ok(())
+            }
+            Err(e) if is_frame_too_large(&e) => Err(e.context(format!(
+                "a message is larger than SCCACHE_MAX_FRAME_LENGTH, {} bytes",
+                max_frame_length
+            ))),
+            res => res,
+        })
     }
 
     /// Get dist status.
@@ -915,13 +1855,94 @@ where
 
     /// Get info and stats about the cache.
     async fn get_info(&self) -> Result<ServerInfo> {
-        let stats = self.stats.lock().await.clone();
-        ServerInfo::new(stats, Some(&*self.storage)).await
+        let stats = {
+            let mut stats = self.stats.lock().await;
+            let counts = self.storage.take_counts();
+            stats.cache_retries += counts.retries;
+            stats.cache_memory_hits += counts.memory_hits;
+            stats.cache_fallback_hits += counts.fallback_hits;
+            stats.remote_fetches_queued += counts.fetches_queued;
+            stats.remote_fetch_queue_duration += counts.fetch_queue_duration;
+            stats.clone()
+        };
+        let mut info = ServerInfo::new(stats, Some(&*self.storage)).await?;
+        info.queued_compiles = Some(self.compile_queue.depth());
+        Ok(info)
     }
 
     /// Zero stats about the cache.
+    /// Wait for the pending cache writes, those still running after their
+    /// compilations were answered, to finish.
+    async fn flush(&self) -> FlushSummary {
+        let finished = |stats: &ServerStats| {
+            (
+                stats.cache_writes,
+                stats.cache_write_errors,
+                stats.cache_write_timeouts,
+            )
+        };
+        let before = finished(&*self.stats.lock().await);
+        let mut pending = self.pending_writes.subscribe();
+        let waited_for = *pending.borrow();
+        let _ = pending.wait_for(|n| *n == 0).await;
+        let after = finished(&*self.stats.lock().await);
+        // The stats may have been zeroed meanwhile.
+        FlushSummary {
+            pending: waited_for,
+            written: after.0.saturating_sub(before.0),
+            failed: after.1.saturating_sub(before.1),
+            timed_out: after.2.saturating_sub(before.2),
+        }
+    }
+
+    /// List the entries of the cache whose keys start with `prefix`, sent in
+    /// chunks as they are listed, so that a large cache isn't held in memory.
+    fn list_entries(&self, prefix: String) -> SccacheResponse {
+        let (tx, rx) = Body::pair();
+        let storage = self.storage.clone();
+        self.rt.spawn(async move {
+            let listed = async {
+                let mut entries = storage
+                    .list_entries(&prefix)
+                    .await?
+                    .try_chunks(LIST_CHUNK_SIZE)
+                    .map_err(|e| e.1);
+                while let Some(chunk) = entries.try_next().await? {
+                    if tx.send(Ok(Response::Entries(chunk))).await.is_err() {
+                        debug!("The client stopped listing the entries");
+                        break;
+                    }
+                }
+                Ok::<_, Error>(())
+            };
+            let last = match listed.await {
+                Ok(()) => Response::EntriesListed,
+                Err(e) => {
+                    warn!("Failed to list the cache entries: {:#}", e);
+                    Response::CacheFailed(format!("{:#}", e))
+                }
+            };
+            let _ = tx.send(Ok(last)).await;
+        });
+        Message::WithBody(Response::ListingEntries, rx)
+    }
+
+    /// Remove the entry of `key` from the cache, including from the index of
+    /// the local disk cache and from the memory cache.
+    async fn remove_entry(&self, key: &str) -> Response {
+        match self.storage.delete(key).await {
+            Ok(removed) => Response::EntryRemoved(removed),
+            Err(e) => {
+                warn!("Failed to remove {} from the cache: {:#}", key, e);
+                Response::CacheFailed(format!("{:#}", e))
+            }
+        }
+    }
+
     async fn zero_stats(&self) {
-        *self.stats.lock().await = ServerStats::default();
+        let mut stats = self.stats.lock().await;
+        self.storage.take_counts();
+        *stats = ServerStats::default();
     }
 
     /// Handle a compile request from a client.
@@ -930,16 +1951,227 @@ where
     /// the initial information and an optional body which will eventually
     /// contain the results of the compilation.
     async fn handle_compile(&self, compile: Compile) -> Result<SccacheResponse> {
-        let exe = compile.exe;
-        let cmd = compile.args;
         let cwd: PathBuf = compile.cwd.into();
         let env_vars = compile.env_vars;
         let me = self.clone();
 
-        let info = self
-            .compiler_info(exe.into(), cwd.clone(), &cmd, &env_vars)
+        let exe = self
+            .server_compiler(compile.exe.into(), compile.exe_digest.as_deref())
             .await;
-        Ok(me.check_compiler(info, cmd, cwd, env_vars).await)
+        let (info, cmd) = match exe {
+            Ok(exe) => {
+                let (exe, cmd, wrapper) = self.unwrap_compiler(exe, compile.args);
+                self.wait_for_prewarm(&exe).await;
+                let info = self
+                    .compiler_info(exe, cwd.clone(), &cmd, &env_vars)
+                    .await
+                    .map(|c| match wrapper {
+                        Some(wrapper) => wrapper.wrap(c),
+                        None => c,
+                    });
+                (info, cmd)
+            }
+            Err(e) => {
+                warn!("Rejecting the compilation: {:#}", e);
+                (Err(e), compile.args)
+            }
+        };
+        Ok(me
+            .check_compiler(
+                info,
+                cmd,
+                cwd,
+                env_vars,
+                compile.stdin,
+                compile.deadline,
+                compile.umask,
+            )
+            .await)
+    }
+
+    /// Get the compiler the server runs for the compiler `exe` of a client,
+    /// as mapped by `SCCACHE_COMPILER_MAP`, checked against the `digest` the
+    /// client sent of its compiler, if any.
+    async fn server_compiler(&self, exe: PathBuf, digest: Option<&str>) -> Result<PathBuf> {
+        let server_exe = self.compiler_map.resolve(exe)?;
+        if let Some(digest) = digest {
+            let actual = util::Digest::file(&server_exe, &self.rt)
+                .await
+                .with_context(|| format!("failed to hash the compiler {:?}", server_exe))?;
+            if actual != digest {
+                bail!(
+                    "the compiler {:?} of the server differs from that of the client",
+                    server_exe
+                );
+            }
+        }
+        Ok(server_exe)
+    }
+
+    /// If `exe` is a compiler wrapper invoked with `args`, get the compiler
+    /// it runs with its arguments, and the wrapper. Otherwise, return `exe`
+    /// and `args` as is.
+    fn unwrap_compiler(
+        &self,
+        exe: PathBuf,
+        args: Vec<OsString>,
+    ) -> (PathBuf, Vec<OsString>, Option<Arc<CompilerWrapper>>) {
+        let Some(wrapper) = self.compiler_wrappers.get(&exe) else {
+            return (exe, args, None);
+        };
+        let Some(compiler_args) = wrapper.strip(&args) else {
+            trace!("{:?} is not invoked as a compiler wrapper: {:?}", exe, args);
+            return (exe, args, None);
+        };
+        if !wrapper.compiler.is_file() {
+            warn!(
+                "The compiler {:?} run by the wrapper {:?} doesn't exist",
+                wrapper.compiler, exe
+            );
+            return (exe, args, None);
+        }
+        trace!("The compiler wrapper {:?} runs {:?}", exe, wrapper.compiler);
+        let args = compiler_args.to_vec();
+        (wrapper.compiler.clone(), args, Some(wrapper.clone()))
+    }
+
+    /// Handle an explain request from a client.
+    ///
+    /// This computes the hash key of the compilation, but doesn't run the
+    /// compilation nor touch the statistics. On a cache hit, the outputs are
+    /// written into `extract_to`, if any, instead of their paths.
+    async fn handle_explain(
+        &self,
+        compile: Compile,
+        extract_to: Option<PathBuf>,
+    ) -> ExplainResponse {
+        let exe = match self
+            .server_compiler(compile.exe.into(), compile.exe_digest.as_deref())
+            .await
+        {
+            Ok(exe) => exe,
+            Err(e) => return ExplainResponse::UnsupportedCompiler(OsString::from(e.to_string())),
+        };
+        let (exe, cmd, _) = self.unwrap_compiler(exe, compile.args);
+        let cwd: PathBuf = compile.cwd.into();
+        let env_vars = compile.env_vars;
+
+        self.wait_for_prewarm(&exe).await;
+        let compiler = match self.compiler_info(exe, cwd.clone(), &cmd, &env_vars).await {
+            Ok(compiler) => compiler,
+            Err(e) => return ExplainResponse::UnsupportedCompiler(OsString::from(e.to_string())),
+        };
+        let mut hasher = match compiler.parse_arguments(&cmd, &cwd, &env_vars) {
+            CompilerArguments::Ok(hasher) => hasher,
+            CompilerArguments::CannotCache(why, Some(extra_info)) => {
+                return ExplainResponse::NotCacheable(format!("{why}: {extra_info}"))
+            }
+            CompilerArguments::CannotCache(why, None) => {
+                return ExplainResponse::NotCacheable(why.to_string())
+            }
+            CompilerArguments::NotCompilation => return ExplainResponse::NotCompilation,
+        };
+        if hasher.reads_stdin() {
+            match compile.stdin {
+                Some(stdin) => hasher.set_stdin(stdin),
+                None => return ExplainResponse::NotCacheable("stdin not captured".to_owned()),
+            }
+        }
+
+        let (
+            HashResult {
+                key,
+                inputs,
+                compilation,
+                ..
+            },
+            entry,
+        ) = match self.lookup_hash_key(hasher, cwd, env_vars).await {
+            Ok(found) => found,
+            Err(e) => return ExplainResponse::Failed(format!("{e:#}")),
+        };
+        let cached = entry.is_some();
+        let extracted = match (entry, extract_to) {
+            (Some(entry), Some(dir)) => {
+                let outputs = compilation.outputs().collect();
+                match self.extract_outputs(entry, outputs, dir).await {
+                    Ok(extracted) => extracted,
+                    Err(e) => {
+                        return ExplainResponse::Failed(format!(
+                            "failed to extract the outputs: {e:#}"
+                        ))
+                    }
+                }
+            }
+            _ => vec![],
+        };
+        ExplainResponse::Explained(HashKeyExplanation {
+            key,
+            cached,
+            inputs,
+            extracted,
+        })
+    }
+
+    /// Write the `outputs` found in `entry` into `dir`, each under the file
+    /// name of its path, and return where they were written.
+    async fn extract_outputs(
+        &self,
+        entry: CacheRead,
+        outputs: Vec<FileObjectSource>,
+        dir: PathBuf,
+    ) -> Result<Vec<String>> {
+        let outputs: Vec<_> = outputs
+            .into_iter()
+            .filter_map(|output| {
+                let path = dir.join(output.path.file_name()?);
+                Some(FileObjectSource { path, ..output })
+            })
+            .collect();
+        let paths: Vec<_> = outputs.iter().map(|output| output.path.clone()).collect();
+        fs::create_dir_all(&dir).with_context(|| format!("failed to create {}", dir.display()))?;
+        entry.extract_objects(outputs, &self.rt).await?;
+        // The optional outputs the entry doesn't have aren't written.
+        Ok(paths
+            .into_iter()
+            .filter(|path| path.exists())
+            .map(|path| path.display().to_string())
+            .collect())
+    }
+
+    /// Compute the hash key of a compilation the same way
+    /// `get_cached_or_compile` does, and get the entry of the cache for it,
+    /// if any. Nothing is written to the cache.
+    async fn lookup_hash_key(
+        &self,
+        hasher: Box<dyn CompilerHasher<C>>,
+        cwd: PathBuf,
+        env_vars: Vec<(OsString, OsString)>,
+    ) -> Result<(HashResult, Option<CacheRead>)> {
+        // Distributed compilation changes how the preprocessor is run, which
+        // can change the key.
+        let (may_dist, rewrite_includes_only) = match self.dist_client.get_client().await {
+            Ok(Some(client)) => (true, client.rewrite_includes_only()),
+            _ => (false, false),
+        };
+        let storage: Arc<dyn Storage> = Arc::new(ReadOnlyStorage(self.storage.clone()));
+        let result = hasher
+            .generate_hash_key(
+                &self.creator,
+                cwd,
+                env_vars,
+                may_dist,
+                &self.rt,
+                rewrite_includes_only,
+                storage.clone(),
+                CacheControl::Default,
+            )
+            .await?;
+        let entry = match storage.get(&result.key).await {
+            Ok(Cache::Hit(entry)) => Some(entry),
+            _ => None,
+        };
+        Ok((result, entry))
     }
 
     /// Look up compiler info from the cache for the compiler `path`.
@@ -1020,10 +2252,18 @@ where
             _ => None,
         };
 
+        let kind_override = env
+            .iter()
+            .find(|(k, _)| k == COMPILER_KIND_ENV_VAR)
+            .map(|(_, v)| v.clone());
         let opt = match me1.compilers.read().await.get(&resolved_compiler_path) {
-            // It's a hit only if the mtime and dist archive data matches.
+            // It's a hit only if the mtime, dist archive data and forced kind
+            // match.
             Some(Some(entry)) => {
-                if entry.mtime == mtime && entry.dist_info == dist_info {
+                if entry.mtime == mtime
+                    && entry.dist_info == dist_info
+                    && entry.kind_override == kind_override
+                {
                     Some(entry.compiler.box_clone())
                 } else {
                     None
@@ -1085,7 +2325,7 @@ where
                 // TODO the same as the resolved compiler binary
 
                 // cache
-                let map_info = CompilerCacheEntry::new(c.clone(), mtime, dist_info);
+                let map_info = CompilerCacheEntry::new(c.clone(), mtime, dist_info, kind_override);
                 trace!(
                     "Inserting POSSIBLY PROXIED cache map info for {:?}",
                     &resolved_compiler_path
@@ -1103,12 +2343,20 @@ where
 
     /// Check that we can handle and cache `cmd` when run with `compiler`.
     /// If so, run `start_compile_task` to execute it.
+    ///
+    /// `stdin` is what the client read from stdin, for compilers reading
+    /// their input from there, `deadline` when the compilation must have
+    /// finished, if the client gave one, and `umask` the umask of the client.
+    #[allow(clippy::too_many_arguments)]
     async fn check_compiler(
         &self,
         compiler: Result<Box<dyn Compiler<C>>>,
         cmd: Vec<OsString>,
         cwd: PathBuf,
         env_vars: Vec<(OsString, OsString)>,
+        stdin: Option<Vec<u8>>,
+        deadline: Option<SystemTime>,
+        umask: Option<u32>,
     ) -> SccacheResponse {
         match compiler {
             Err(e) => {
@@ -1120,14 +2368,74 @@ where
             }
             Ok(c) => {
                 debug!("check_compiler: Supported compiler");
+                // The client sends the compilation of each source file on its
+                // own, so that each is looked up and cached separately.
+                if let Some(compilations) = c.split_inputs(&cmd) {
+                    debug!(
+                        "check_compiler: split into {} compilations: {:?}",
+                        compilations.len(),
+                        cmd
+                    );
+                    self.stats.lock().await.requests_split += 1;
+                    return Message::WithoutBody(Response::Compile(CompileResponse::SplitInputs(
+                        compilations,
+                    )));
+                }
                 // Now check that we can handle this compiler with
                 // the provided commandline.
                 match c.parse_arguments(&cmd, &cwd, &env_vars) {
-                    CompilerArguments::Ok(hasher) => {
+                    CompilerArguments::Ok(_)
+                        if env_vars
+                            .iter()
+                            .any(|(k, _v)| k.as_os_str() == OsStr::new("SCCACHE_NOCACHE")) =>
+                    {
+                        debug!(
+                            "parse_arguments: caching disabled by SCCACHE_NOCACHE: {:?}",
+                            cmd
+                        );
+                        let mut stats = self.stats.lock().await;
+                        stats.requests_not_cacheable += 1;
+                        *stats.not_cached.entry("explicit".to_owned()).or_insert(0) += 1;
+                    }
+                    CompilerArguments::Ok(hasher) if !self.languages.allows(hasher.language()) => {
+                        debug!(
+                            "parse_arguments: caching {} disabled by config: {:?}",
+                            hasher.language().as_str(),
+                            cmd
+                        );
+                        let mut stats = self.stats.lock().await;
+                        stats.requests_not_cacheable += 1;
+                        *stats
+                            .not_cached
+                            .entry("disabled by config".to_owned())
+                            .or_insert(0) += 1;
+                    }
+                    // The client only captures stdin when it can tell the
+                    // compiler reads it.
+                    CompilerArguments::Ok(hasher) if hasher.reads_stdin() && stdin.is_none() => {
+                        debug!("parse_arguments: stdin not captured: {:?}", cmd);
+                        let mut stats = self.stats.lock().await;
+                        stats.requests_not_cacheable += 1;
+                        *stats.not_cached.entry("-".to_owned()).or_insert(0) += 1;
+                    }
+                    CompilerArguments::Ok(mut hasher) => {
                         debug!("parse_arguments: Ok: {:?}", cmd);
-                        self.stats.lock().await.requests_executed += 1;
+                        if let Some(stdin) = stdin.filter(|_| hasher.reads_stdin()) {
+                            hasher.set_stdin(stdin);
+                        }
                         let (tx, rx) = Body::pair();
-                        self.start_compile_task(c, hasher, cmd, cwd, env_vars, tx);
+                        let dry_run = env_vars
+                            .iter()
+                            .any(|(k, _v)| k.as_os_str() == OsStr::new("SCCACHE_DRYRUN"));
+                        if dry_run {
+                            self.start_dry_run_task(hasher, cwd, env_vars, tx);
+                        } else {
+                            self.stats.lock().await.requests_executed += 1;
+                            self.start_compile_task(
+                                c, hasher, cmd, cwd, env_vars, deadline, umask, tx,
+                            )
+                            .await;
+                        }
                         let res = CompileResponse::CompileStarted;
                         return Message::WithBody(Response::Compile(res), rx);
                     }
@@ -1156,70 +2464,265 @@ where
         Message::WithoutBody(Response::Compile(res))
     }
 
+    /// Look up a compile result in the cache and report whether it was found,
+    /// without compiling on a miss nor storing anything.
+    ///
+    /// If `SCCACHE_DRYRUN_TOUCH_OUTPUTS` is set, missing outputs are created
+    /// empty so that build tools can carry on with the next steps.
+    fn start_dry_run_task(
+        &self,
+        hasher: Box<dyn CompilerHasher<C>>,
+        cwd: PathBuf,
+        env_vars: Vec<(OsString, OsString)>,
+        tx: BodySender<Response>,
+    ) {
+        let touch_outputs = env_vars
+            .iter()
+            .any(|(k, _v)| k.as_os_str() == OsStr::new("SCCACHE_DRYRUN_TOUCH_OUTPUTS"));
+        let out_pretty = hasher.output_pretty().into_owned();
+        let color_mode = hasher.color_mode();
+        let me = self.clone();
+
+        let task = async move {
+            let mut res = CompileFinished {
+                color_mode,
+                ..Default::default()
+            };
+            match me.lookup_hash_key(hasher, cwd.clone(), env_vars).await {
+                Ok((HashResult { compilation, .. }, entry)) => {
+                    let cached = entry.is_some();
+                    let mut stats = me.stats.lock().await;
+                    if cached {
+                        debug!("[{}]: dry run: cache hit", out_pretty);
+                        stats.dry_run_hits += 1;
+                    } else {
+                        debug!("[{}]: dry run: cache miss", out_pretty);
+                        stats.dry_run_misses += 1;
+                    }
+                    drop(stats);
+
+                    if touch_outputs {
+                        for output in compilation.outputs_in(&cwd).filter(|o| !o.optional) {
+                            let path = output.path;
+                            if !path.exists() {
+                                if let Err(e) = fs::File::create(&path) {
+                                    warn!("[{}]: failed to touch output: {}", out_pretty, e);
+                                }
+                            }
+                        }
+                    }
+                    res.retcode = Some(0);
+                    res.stderr = format!(
+                        "sccache: dry run: cache {} for {}\n",
+                        if cached { "hit" } else { "miss" },
+                        out_pretty
+                    )
+                    .into_bytes();
+                }
+                Err(err) => match err.downcast::<ProcessError>() {
+                    Ok(ProcessError(output)) => {
+                        debug!("[{}]: dry run: preprocessing failed", out_pretty);
+                        update_record(|r| r.result = "preprocessor_error".into());
+                        me.stats.lock().await.preprocessor_errors += 1;
+                        match output.status.code() {
+                            Some(code) => res.retcode = Some(code),
+                            None => res.signal = Some(get_signal(output.status)),
+                        };
+                        res.stdout = output.stdout;
+                        res.stderr = output.stderr;
+                        res.error = Some(CompileError::Preprocessing);
+                    }
+                    Err(err) => {
+                        error!("[{:?}] dry run: fatal error: {}", out_pretty, err);
+                        res.error =
+                            Some(CompileError::Internal(format!("dry run failed: {:#}", err)));
+                    }
+                },
+            }
+            tx.send(Ok(Response::CompileFinished(res)))
+                .await
+                .map_err(|e| anyhow!("send on finish failed").context(e))
+        };
+
+        self.rt.spawn(async move {
+            task.await
+                .unwrap_or_else(|e| warn!("Failed to execute task: {:?}", e));
+        });
+    }
+
     /// Given compiler arguments `arguments`, look up
     /// a compile result in the cache or execute the compilation and store
     /// the result in the cache.
-    fn start_compile_task(
+    ///
+    /// This waits for room in the compile queue before starting the task.
+    /// Past `deadline`, the compilation is cancelled and fails. The outputs
+    /// restored from the cache are created with `umask`, if any.
+    #[allow(clippy::too_many_arguments)]
+    async fn start_compile_task(
         &self,
         compiler: Box<dyn Compiler<C>>,
         hasher: Box<dyn CompilerHasher<C>>,
         arguments: Vec<OsString>,
         cwd: PathBuf,
         env_vars: Vec<(OsString, OsString)>,
-        mut tx: mpsc::Sender<Result<Response>>,
+        deadline: Option<SystemTime>,
+        umask: Option<u32>,
+        tx: BodySender<Response>,
     ) {
-        let force_recache = env_vars
-            .iter()
-            .any(|(k, _v)| k.as_os_str() == OsStr::new("SCCACHE_RECACHE"));
-        let cache_control = if force_recache {
-            CacheControl::ForceRecache
-        } else {
-            CacheControl::Default
+        let kind = compiler.kind();
+        let lang = hasher.language();
+        let (cache_override, storage) = match self.cache_overrides.choose(&env_vars) {
+            Ok(Some((url, storage))) => (Some(url), storage),
+            Ok(None) => (None, self.storage.clone()),
+            Err(e) => {
+                warn!("Not compiling {:?}: {:#}", hasher.output_pretty(), e);
+                self.stats.lock().await.cache_errors.increment(&kind, &lang);
+                let res = CompileFinished {
+                    error: Some(CompileError::Cache(format!("{:#}", e))),
+                    ..Default::default()
+                };
+                self.rt.spawn(async move {
+                    if tx.send(Ok(Response::CompileFinished(res))).await.is_err() {
+                        warn!("Failed to send the cache override error");
+                    }
+                });
+                return;
+            }
         };
+        let slot = self.compile_queue.enter().await;
         let out_pretty = hasher.output_pretty().into_owned();
+        let cache_control = CacheControl::from_env(&env_vars, &out_pretty);
         let color_mode = hasher.color_mode();
         let me = self.clone();
-        let kind = compiler.kind();
-        let lang = hasher.language();
         let creator = self.creator.clone();
-        let storage = self.storage.clone();
         let pool = self.rt.clone();
+        let active = InFlight::new(self.active_compiles.clone());
+        let access = ACCESS_LOG.as_ref().zip(access_log::current());
+        let record = (RESULT_LOG.is_some() || STATSD.is_some() || access.is_some()).then(|| {
+            let mut record = CompileRecord::new(
+                kind.lang_comp_kind(&lang),
+                lang.as_str(),
+                out_pretty.clone(),
+                &arguments,
+                &cwd,
+                storage.location(),
+            );
+            record.deadline = deadline
+                .map(|deadline| chrono::DateTime::<chrono::Utc>::from(deadline).to_rfc3339());
+            record
+        });
 
         let task = async move {
-            let dist_client = me.dist_client.get_client().await;
-            let result = match dist_client {
-                Ok(client) => std::panic::AssertUnwindSafe(hasher.get_cached_or_compile(
-                    client,
-                    creator,
-                    storage,
-                    arguments,
-                    cwd,
-                    env_vars,
-                    cache_control,
-                    pool,
-                ))
-                .catch_unwind()
-                .await
-                .map_err(|e| {
-                    let panic = e
-                        .downcast_ref::<&str>()
-                        .map(|s| &**s)
-                        .or_else(|| e.downcast_ref::<String>().map(|s| &**s))
-                        .unwrap_or("An unknown panic was caught.");
-                    let thread = std::thread::current();
-                    let thread_name = thread.name().unwrap_or("unnamed");
-                    if let Some((file, line, column)) = PANIC_LOCATION.with(|l| l.take()) {
-                        anyhow!(
-                            "thread '{thread_name}' panicked at {file}:{line}:{column}: {panic}"
-                        )
-                    } else {
-                        anyhow!("thread '{thread_name}' panicked: {panic}")
+            let _active = active;
+            let _slot = slot;
+            let compile = async {
+                let dist_client = me.dist_client.get_client().await;
+                let result = match dist_client {
+                    Ok(client) => std::panic::AssertUnwindSafe(hasher.get_cached_or_compile(
+                        client,
+                        creator,
+                        storage,
+                        arguments,
+                        cwd,
+                        env_vars,
+                        cache_control,
+                        pool,
+                    ))
+                    .catch_unwind()
+                    .await
+                    .map_err(|e| {
+                        let panic = e
+                            .downcast_ref::<&str>()
+                            .map(|s| &**s)
+                            .or_else(|| e.downcast_ref::<String>().map(|s| &**s))
+                            .unwrap_or("An unknown panic was caught.");
+                        let thread = std::thread::current();
+                        let thread_name = thread.name().unwrap_or("unnamed");
+                        if let Some((file, line, column)) = PANIC_LOCATION.with(|l| l.take()) {
+                            anyhow!(
+                                "thread '{thread_name}' panicked at {file}:{line}:{column}: {panic}"
+                            )
+                        } else {
+                            anyhow!("thread '{thread_name}' panicked: {panic}")
+                        }
+                    })
+                    .and_then(std::convert::identity),
+                    Err(e) => Err(e),
+                };
+                result
+            };
+            // The stderr of the compiler is sent to the client as it comes, the
+            // compiler waiting for the client to take it. It ends before the
+            // compilation is answered.
+            let (stderr_tx, mut stderr_rx) = stderr_stream::channel();
+            let mut streamed = 0;
+            let stream_stderr = async {
+                while let Some(chunk) = stderr_rx.recv().await {
+                    streamed += chunk.len();
+                    let chunk = Response::CompileStderr(chunk, color_mode);
+                    if tx.send(Ok(chunk)).await.is_err() {
+                        break;
                     }
-                })
-                .and_then(std::convert::identity),
-                Err(e) => Err(e),
+                }
             };
+            // Boxed, or the large future of the compilation overflows the
+            // stack in debug builds.
+            let compile = Box::pin(stderr_stream::scope(
+                stderr_tx,
+                jobs::scope(
+                    me.compile_queue.clone(),
+                    in_flight::scope(me.flights.clone(), compile),
+                ),
+            ));
+            let compile = async {
+                future::join(
+                    deadline::run(
+                        deadline,
+                        memory_accounting::account(umask::scope(umask, compile)),
+                    ),
+                    stream_stderr,
+                )
+                .await
+                .0
+            };
+            // The compilation is abandoned, and nothing cached, if the client
+            // goes away or the deadline passes before it finishes. Dropping it
+            // kills the compiler.
+            let (result, peak_rss) = tokio::select! {
+                compiled = compile => {
+                    let Some(compiled) = compiled else {
+                        debug!("[{}]: the deadline passed, cancelling the compilation", out_pretty);
+                        update_record(|r| r.result = "deadline_exceeded".into());
+                        me.stats.lock().await.deadlines_exceeded += 1;
+                        let res = CompileFinished {
+                            error: Some(CompileError::DeadlineExceeded),
+                            color_mode,
+                            ..Default::default()
+                        };
+                        return tx
+                            .send(Ok(Response::CompileFinished(res)))
+                            .await
+                            .map_err(|e| anyhow!("send on finish failed").context(e));
+                    };
+                    compiled
+                }
+                _ = tx.closed() => {
+                    debug!("[{}]: the client went away, cancelling the compilation", out_pretty);
+                    update_record(|r| r.result = "cancelled".into());
+                    return Ok(());
+                }
+            };
+            if let Some(peak_rss) = peak_rss {
+                update_record(|r| r.peak_rss = Some(peak_rss));
+                me.stats
+                    .lock()
+                    .await
+                    .peak_rss
+                    .record(&kind, &lang, peak_rss);
+            }
             let mut cache_write = None;
+            // The output too large to be sent in `res`.
+            let mut spilled = None;
             let mut res = CompileFinished {
                 color_mode,
                 ..Default::default()
@@ -1229,15 +2732,21 @@ where
                     let mut stats = me.stats.lock().await;
                     match compiled {
                         CompileResult::Error => {
-                            debug!("compile result: cache error");
+                            debug!("compile result: preprocessor error");
+                            update_record(|r| r.result = "preprocessor_error".into());
 
-                            stats.cache_errors.increment(&kind, &lang);
+                            stats.preprocessor_errors += 1;
+                            res.error = Some(CompileError::Preprocessing);
                         }
                         CompileResult::CacheHit(duration) => {
                             debug!("compile result: cache hit");
+                            update_record(|r| r.result = "hit".into());
 
                             stats.cache_hits.increment(&kind, &lang);
                             stats.cache_read_hit_duration += duration;
+                            if let Some(url) = &cache_override {
+                                *stats.cache_override_hits.entry(url.clone()).or_insert(0) += 1;
+                            }
                         }
                         CompileResult::CacheMiss(miss_type, dist_type, duration, future) => {
                             debug!("compile result: cache miss");
@@ -1246,39 +2755,58 @@ where
                                 DistType::NoDist => {}
                                 DistType::Ok(id) => {
                                     let server = id.addr().to_string();
+                                    update_record(|r| r.dist_server = Some(server.clone()));
                                     let server_count =
                                         stats.dist_compiles.entry(server).or_insert(0);
                                     *server_count += 1;
                                 }
                                 DistType::Error => stats.dist_errors += 1,
                             }
-                            match miss_type {
-                                MissType::Normal => {}
+                            let result = match miss_type {
+                                MissType::Normal => "miss",
                                 MissType::ForcedRecache => {
                                     stats.forced_recaches += 1;
+                                    "recache"
                                 }
                                 MissType::TimedOut => {
                                     stats.cache_timeouts += 1;
+                                    "timeout"
                                 }
                                 MissType::CacheReadError => {
                                     stats.cache_errors.increment(&kind, &lang);
+                                    "read_error"
                                 }
-                            }
+                            };
+                            update_record(|r| r.result = result.into());
                             stats.cache_misses.increment(&kind, &lang);
                             stats.compiler_write_duration += duration;
+                            if let Some(url) = &cache_override {
+                                *stats.cache_override_misses.entry(url.clone()).or_insert(0) += 1;
+                            }
                             debug!("stats after compile result: {stats:?}");
                             cache_write = Some(future);
                         }
                         CompileResult::NotCacheable => {
                             debug!("compile result: not cacheable");
+                            update_record(|r| r.result = "not_cacheable".into());
 
                             stats.cache_misses.increment(&kind, &lang);
                             stats.non_cacheable_compilations += 1;
                         }
-                        CompileResult::CompileFailed => {
+                        CompileResult::TooLarge => {
+                            debug!("compile result: too large to cache");
+                            update_record(|r| r.result = "too_large".into());
+
+                            stats.cache_misses.increment(&kind, &lang);
+                            stats.too_large_compilations += 1;
+                        }
+                        CompileResult::CompileFailed(future) => {
                             debug!("compile result: compile failed");
+                            update_record(|r| r.result = "failed".into());
 
                             stats.compile_fails += 1;
+                            res.error = Some(CompileError::CompilerFailed);
+                            cache_write = future;
                         }
                     };
                     // Make sure the write guard has been dropped ASAP.
@@ -1299,6 +2827,7 @@ where
                 }
                 Err(err) => {
                     let mut stats = me.stats.lock().await;
+                    update_record(|r| r.result = "error".into());
                     match err.downcast::<ProcessError>() {
                         Ok(ProcessError(output)) => {
                             debug!("Compilation failed: {:?}", output);
@@ -1309,6 +2838,7 @@ where
                             };
                             res.stdout = output.stdout;
                             res.stderr = output.stderr;
+                            res.error = Some(CompileError::CompilerFailed);
                         }
                         Err(err) => match err.downcast::<HttpClientError>() {
                             Ok(HttpClientError(msg)) => {
@@ -1316,40 +2846,108 @@ where
                                 let errmsg =
                                     format!("[{:?}] http error status: {}", out_pretty, msg);
                                 error!("{}", errmsg);
-                                res.retcode = Some(1);
-                                res.stderr = errmsg.as_bytes().to_vec();
+                                res.error = Some(CompileError::Dist(errmsg));
                             }
-                            Err(err) => {
-                                use std::fmt::Write;
-
-                                error!("[{:?}] fatal error: {}", out_pretty, err);
-
-                                let mut error = "sccache: encountered fatal error\n".to_string();
-                                let _ = writeln!(error, "sccache: error: {}", err);
-                                for e in err.chain() {
-                                    error!("[{:?}] \t{}", out_pretty, e);
-                                    let _ = writeln!(error, "sccache: caused by: {}", e);
+                            Err(err) => match err.downcast::<OutputTooLarge>() {
+                                Ok(OutputTooLarge {
+                                    max_size,
+                                    status,
+                                    stdout,
+                                    stderr,
+                                }) => {
+                                    debug!(
+                                        "[{:?}] not caching, the compiler output is larger than {} bytes",
+                                        out_pretty, max_size
+                                    );
+                                    if status.success() {
+                                        update_record(|r| r.result = "too_large".into());
+                                        stats.cache_misses.increment(&kind, &lang);
+                                        stats.too_large_compilations += 1;
+                                    } else {
+                                        update_record(|r| r.result = "failed".into());
+                                        stats.compile_fails += 1;
+                                        res.error = Some(CompileError::CompilerFailed);
+                                    }
+                                    match status.code() {
+                                        Some(code) => res.retcode = Some(code),
+                                        None => res.signal = Some(get_signal(status)),
+                                    };
+                                    spilled = Some((stdout, stderr));
                                 }
-                                stats.cache_errors.increment(&kind, &lang);
-                                //TODO: figure out a better way to communicate this?
-                                res.retcode = Some(-2);
-                                res.stderr = error.into_bytes();
-                            }
+                                Err(err) => {
+                                    error!("[{:?}] fatal error: {}", out_pretty, err);
+                                    for e in err.chain() {
+                                        error!("[{:?}] \t{}", out_pretty, e);
+                                    }
+                                    let msg = format!("{:#}", err);
+                                    res.error =
+                                        Some(if err.downcast_ref::<SpawnError>().is_some() {
+                                            CompileError::CompilerNotFound(msg)
+                                        } else if err.downcast_ref::<CacheError>().is_some() {
+                                            stats.cache_errors.increment(&kind, &lang);
+                                            CompileError::Cache(msg)
+                                        } else {
+                                            stats.cache_errors.increment(&kind, &lang);
+                                            CompileError::Internal(msg)
+                                        });
+                                }
+                            },
                         },
                     }
                 }
             };
+            // The output too large for `res` is streamed from its files.
+            if let Some((stdout, stderr)) = spilled {
+                match stdout {
+                    Captured::Kept(stdout) => res.stdout = stdout,
+                    Captured::Spilled(file) => {
+                        send_spilled(file, 0, &tx, |chunk| {
+                            Response::CompileStdout(chunk, color_mode)
+                        })
+                        .await?
+                    }
+                }
+                match stderr {
+                    Captured::Kept(stderr) => res.stderr = stderr,
+                    Captured::Spilled(file) => {
+                        send_spilled(file, streamed as u64, &tx, |chunk| {
+                            Response::CompileStderr(chunk, color_mode)
+                        })
+                        .await?;
+                        streamed = 0;
+                    }
+                }
+            }
+            // The client printed the stderr streamed already.
+            res.stderr.drain(..streamed.min(res.stderr.len()));
+            // Pending from before the client is answered, for a flush it
+            // sends next to wait for it.
+            let pending = cache_write
+                .as_ref()
+                .map(|_| InFlight::new(me.pending_writes.clone()));
             let send = tx
                 .send(Ok(Response::CompileFinished(res)))
                 .map_err(|e| anyhow!("send on finish failed").context(e));
 
             let me = me.clone();
             let cache_write = async move {
+                let _pending = pending;
                 if let Some(cache_write) = cache_write {
                     match cache_write.await {
                         Err(e) => {
-                            debug!("Error executing cache write: {}", e);
-                            me.stats.lock().await.cache_write_errors += 1;
+                            // The compilation succeeded, just without being cached.
+                            update_record(|r| r.write_error = Some(format!("{:#}", e)));
+                            if e.downcast_ref::<CacheTimeout>().is_some() {
+                                warn!("Not caching the compilation: {:#}", e);
+                                me.stats.lock().await.cache_write_timeouts += 1;
+                            } else {
+                                if e.downcast_ref::<DiskFull>().is_some() {
+                                    warn!("Not caching the compilation: {:#}", e);
+                                } else {
+                                    debug!("Error executing cache write: {}", e);
+                                }
+                                me.stats.lock().await.cache_write_errors += 1;
+                            }
                         }
                         //TODO: save cache stats!
                         Ok(info) => {
@@ -1358,6 +2956,7 @@ where
                                 info.object_file_pretty,
                                 util::fmt_duration_as_secs(&info.duration)
                             );
+                            update_record(|r| r.write_secs = Some(info.duration.as_secs_f64()));
                             let mut stats = me.stats.lock().await;
                             stats.cache_writes += 1;
                             stats.cache_write_duration += info.duration;
@@ -1371,6 +2970,14 @@ where
 
             Ok::<_, Error>(())
         };
+        let task = async move {
+            match record {
+                Some(record) => {
+                    record_compile(RESULT_LOG.as_ref(), STATSD.as_ref(), access, record, task).await
+                }
+                None => task.await,
+            }
+        };
 
         self.rt.spawn(async move {
             task.await
@@ -1386,7 +2993,7 @@ pub struct PerLanguageCount {
 }
 
 impl PerLanguageCount {
-    fn increment(&mut self, kind: &CompilerKind, lang: &Language) {
+    pub(crate) fn increment(&mut self, kind: &CompilerKind, lang: &Language) {
         let lang_comp_key = kind.lang_comp_kind(lang);
         let adv_count = self.adv_counts.entry(lang_comp_key).or_insert(0);
         *adv_count += 1;
@@ -1396,6 +3003,15 @@ impl PerLanguageCount {
         *count += 1;
     }
 
+    fn merge(&mut self, other: PerLanguageCount) {
+        for (key, count) in other.counts {
+            *self.counts.entry(key).or_insert(0) += count;
+        }
+        for (key, count) in other.adv_counts {
+            *self.adv_counts.entry(key).or_insert(0) += count;
+        }
+    }
+
     pub fn all(&self) -> u64 {
         self.counts.values().sum()
     }
@@ -1413,6 +3029,60 @@ impl PerLanguageCount {
     }
 }
 
+/// The peak RSS of the compilers of a language, in bytes.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeakRss {
+    /// The count of compilations measured.
+    pub count: u64,
+    /// The sum and the largest of their peaks.
+    pub total: u64,
+    pub max: u64,
+}
+
+impl PeakRss {
+    pub fn avg(&self) -> u64 {
+        self.total.checked_div(self.count).unwrap_or(0)
+    }
+
+    fn merge(&mut self, other: PeakRss) {
+        self.count += other.count;
+        self.total += other.total;
+        self.max = self.max.max(other.max);
+    }
+}
+
+/// The peak RSS of the compilers, per language, with
+/// `SCCACHE_MEMORY_ACCOUNTING`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PerLanguagePeakRss {
+    peaks: HashMap<String, PeakRss>,
+}
+
+impl PerLanguagePeakRss {
+    pub(crate) fn record(&mut self, kind: &CompilerKind, lang: &Language, bytes: u64) {
+        let peak = self.peaks.entry(kind.lang_kind(lang)).or_default();
+        peak.merge(PeakRss {
+            count: 1,
+            total: bytes,
+            max: bytes,
+        });
+    }
+
+    fn merge(&mut self, other: PerLanguagePeakRss) {
+        for (key, peak) in other.peaks {
+            self.peaks.entry(key).or_default().merge(peak);
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&PeakRss> {
+        self.peaks.get(key)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peaks.is_empty()
+    }
+}
+
 /// Statistics about the server.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ServerStats {
@@ -1424,6 +3094,9 @@ pub struct ServerStats {
     pub requests_not_compile: u64,
     /// The count of client requests that were not cacheable.
     pub requests_not_cacheable: u64,
+    /// The count of client requests compiling several source files, which
+    /// the client sent again as one request per source file.
+    pub requests_split: u64,
     /// The count of client requests that were executed.
     pub requests_executed: u64,
     /// The count of errors handling compile requests (per language).
@@ -1432,16 +3105,39 @@ pub struct ServerStats {
     pub cache_hits: PerLanguageCount,
     /// The count of cache misses for handled compile requests (per language).
     pub cache_misses: PerLanguageCount,
+    /// The count of cache hits served from the entries kept in memory.
+    pub cache_memory_hits: u64,
+    /// The count of cache hits served from the fallback of the cache, while
+    /// the cache failed.
+    pub cache_fallback_hits: u64,
     /// The count of cache misses because the cache took too long to respond.
     pub cache_timeouts: u64,
+    /// The count of requests to the remote cache retried after failing
+    /// temporarily.
+    pub cache_retries: u64,
+    /// The count of lookups of the remote cache which waited for one of the
+    /// `SCCACHE_REMOTE_FETCH_CONCURRENCY` fetches to finish.
+    pub remote_fetches_queued: u64,
+    /// The total time the lookups of the remote cache waited for their turn.
+    pub remote_fetch_queue_duration: Duration,
     /// The count of errors reading cache entries.
     pub cache_read_errors: u64,
     /// The count of compilations which were successful but couldn't be cached.
     pub non_cacheable_compilations: u64,
+    /// The count of compilations which were successful but whose outputs were
+    /// too large to cache.
+    pub too_large_compilations: u64,
     /// The count of compilations which forcibly ignored the cache.
     pub forced_recaches: u64,
+    /// The count of dry run lookups which found an entry in the cache.
+    pub dry_run_hits: u64,
+    /// The count of dry run lookups which didn't find an entry in the cache.
+    pub dry_run_misses: u64,
     /// The count of errors writing to cache.
     pub cache_write_errors: u64,
+    /// The count of cache writes cancelled because the cache took too long
+    /// to respond.
+    pub cache_write_timeouts: u64,
     /// The number of successful cache writes.
     pub cache_writes: u64,
     /// The total time spent writing cache entries.
@@ -1452,6 +3148,11 @@ pub struct ServerStats {
     pub compiler_write_duration: Duration,
     /// The count of compilation failures.
     pub compile_fails: u64,
+    /// The count of compilations whose preprocessing failed, e.g. because of
+    /// a missing header.
+    pub preprocessor_errors: u64,
+    /// The count of compilations cancelled past the deadline of the client.
+    pub deadlines_exceeded: u64,
     /// Counts of reasons why compiles were not cached.
     pub not_cached: HashMap<String, usize>,
     /// The count of compilations that were successfully distributed indexed
@@ -1459,6 +3160,12 @@ pub struct ServerStats {
     pub dist_compiles: HashMap<String, usize>,
     /// The count of compilations that were distributed but failed and had to be re-run locally
     pub dist_errors: u64,
+    /// The counts of cache hits and misses of the compilations which chose
+    /// their cache with `SCCACHE_CACHE_OVERRIDE`, indexed by its URL.
+    pub cache_override_hits: HashMap<String, usize>,
+    pub cache_override_misses: HashMap<String, usize>,
+    /// The peak RSS of the compilers, per language.
+    pub peak_rss: PerLanguagePeakRss,
 }
 
 /// Info and stats about the server.
@@ -1468,8 +3175,20 @@ pub struct ServerInfo {
     pub cache_location: String,
     pub cache_size: Option<u64>,
     pub max_cache_size: Option<u64>,
+    /// How many requests to the cache are running, when their concurrency is
+    /// limited.
+    pub requests_in_flight: Option<usize>,
+    /// The state of the eviction, when the cache evicts entries in sweeps.
+    pub eviction: Option<EvictionState>,
+    /// How full the cache is and what it evicted, when it tracks its entries
+    /// locally, unlike the remote caches.
+    pub occupancy: Option<CacheOccupancy>,
+    /// How many compilations are waiting for a job, when there is a server.
+    pub queued_compiles: Option<usize>,
     pub use_preprocessor_cache_mode: bool,
     pub version: String,
+    /// The `PROTOCOL_VERSION` of the server.
+    pub protocol_version: u32,
 }
 
 /// Status of the dist client.
@@ -1489,28 +3208,129 @@ impl Default for ServerStats {
             requests_unsupported_compiler: u64::default(),
             requests_not_compile: u64::default(),
             requests_not_cacheable: u64::default(),
+            requests_split: u64::default(),
             requests_executed: u64::default(),
             cache_errors: PerLanguageCount::new(),
             cache_hits: PerLanguageCount::new(),
             cache_misses: PerLanguageCount::new(),
+            cache_memory_hits: u64::default(),
+            cache_fallback_hits: u64::default(),
             cache_timeouts: u64::default(),
+            cache_retries: u64::default(),
+            remote_fetches_queued: u64::default(),
+            remote_fetch_queue_duration: Duration::new(0, 0),
             cache_read_errors: u64::default(),
             non_cacheable_compilations: u64::default(),
+            too_large_compilations: u64::default(),
             forced_recaches: u64::default(),
+            dry_run_hits: u64::default(),
+            dry_run_misses: u64::default(),
             cache_write_errors: u64::default(),
+            cache_write_timeouts: u64::default(),
             cache_writes: u64::default(),
             cache_write_duration: Duration::new(0, 0),
             cache_read_hit_duration: Duration::new(0, 0),
             compiler_write_duration: Duration::new(0, 0),
             compile_fails: u64::default(),
+            preprocessor_errors: u64::default(),
+            deadlines_exceeded: u64::default(),
             not_cached: HashMap::new(),
             dist_compiles: HashMap::new(),
             dist_errors: u64::default(),
+            cache_override_hits: HashMap::new(),
+            cache_override_misses: HashMap::new(),
+            peak_rss: PerLanguagePeakRss::default(),
         }
     }
 }
 
 impl ServerStats {
+    /// Add the counts of `other`, e.g. of another process, to these.
+    pub(crate) fn merge(&mut self, other: ServerStats) {
+        let ServerStats {
+            compile_requests,
+            requests_unsupported_compiler,
+            requests_not_compile,
+            requests_not_cacheable,
+            requests_split,
+            requests_executed,
+            cache_errors,
+            cache_hits,
+            cache_misses,
+            cache_memory_hits,
+            cache_fallback_hits,
+            cache_timeouts,
+            cache_retries,
+            remote_fetches_queued,
+            remote_fetch_queue_duration,
+            cache_read_errors,
+            non_cacheable_compilations,
+            too_large_compilations,
+            forced_recaches,
+            dry_run_hits,
+            dry_run_misses,
+            cache_write_errors,
+            cache_write_timeouts,
+            cache_writes,
+            cache_write_duration,
+            cache_read_hit_duration,
+            compiler_write_duration,
+            compile_fails,
+            preprocessor_errors,
+            deadlines_exceeded,
+            not_cached,
+            dist_compiles,
+            dist_errors,
+            cache_override_hits,
+            cache_override_misses,
+            peak_rss,
+        } = other;
+        self.compile_requests += compile_requests;
+        self.requests_unsupported_compiler += requests_unsupported_compiler;
+        self.requests_not_compile += requests_not_compile;
+        self.requests_not_cacheable += requests_not_cacheable;
+        self.requests_split += requests_split;
+        self.requests_executed += requests_executed;
+        self.cache_errors.merge(cache_errors);
+        self.cache_hits.merge(cache_hits);
+        self.cache_misses.merge(cache_misses);
+        self.cache_memory_hits += cache_memory_hits;
+        self.cache_fallback_hits += cache_fallback_hits;
+        self.cache_timeouts += cache_timeouts;
+        self.cache_retries += cache_retries;
+        self.remote_fetches_queued += remote_fetches_queued;
+        self.remote_fetch_queue_duration += remote_fetch_queue_duration;
+        self.cache_read_errors += cache_read_errors;
+        self.non_cacheable_compilations += non_cacheable_compilations;
+        self.too_large_compilations += too_large_compilations;
+        self.forced_recaches += forced_recaches;
+        self.dry_run_hits += dry_run_hits;
+        self.dry_run_misses += dry_run_misses;
+        self.cache_write_errors += cache_write_errors;
+        self.cache_write_timeouts += cache_write_timeouts;
+        self.cache_writes += cache_writes;
+        self.cache_write_duration += cache_write_duration;
+        self.cache_read_hit_duration += cache_read_hit_duration;
+        self.compiler_write_duration += compiler_write_duration;
+        self.compile_fails += compile_fails;
+        self.preprocessor_errors += preprocessor_errors;
+        self.deadlines_exceeded += deadlines_exceeded;
+        for (reason, count) in not_cached {
+            *self.not_cached.entry(reason).or_insert(0) += count;
+        }
+        for (server, count) in dist_compiles {
+            *self.dist_compiles.entry(server).or_insert(0) += count;
+        }
+        self.dist_errors += dist_errors;
+        for (url, count) in cache_override_hits {
+            *self.cache_override_hits.entry(url).or_insert(0) += count;
+        }
+        for (url, count) in cache_override_misses {
+            *self.cache_override_misses.entry(url).or_insert(0) += count;
+        }
+        self.peak_rss.merge(peak_rss);
+    }
+
     /// Print stats to stdout in a human-readable format.
     ///
     /// Return the formatted width of each of the (name, value) columns.
@@ -1570,11 +3390,54 @@ impl ServerStats {
             set_lang_stat!(stats_vec, self.cache_hits, "Cache hits");
             set_lang_stat!(stats_vec, self.cache_misses, "Cache misses");
         }
+        if self.cache_memory_hits > 0 {
+            set_stat!(stats_vec, self.cache_memory_hits, "Cache hits from memory");
+            let hits = self.cache_hits.all().max(self.cache_memory_hits);
+            let rate = self.cache_memory_hits as f64 * 100.0 / hits as f64;
+            // name, value, suffix length
+            stats_vec.push((
+                "Cache hit rate from memory".to_string(),
+                format!("{:.2} %", rate),
+                2,
+            ));
+        }
+        if self.cache_fallback_hits > 0 {
+            set_stat!(
+                stats_vec,
+                self.cache_fallback_hits,
+                "Cache hits from the fallback"
+            );
+        }
         set_stat!(stats_vec, self.cache_timeouts, "Cache timeouts");
+        if self.cache_retries > 0 {
+            set_stat!(stats_vec, self.cache_retries, "Cache retries");
+        }
+        if self.remote_fetches_queued > 0 {
+            set_stat!(
+                stats_vec,
+                self.remote_fetches_queued,
+                "Remote fetches queued"
+            );
+            set_duration_stat!(
+                stats_vec,
+                self.remote_fetch_queue_duration,
+                self.remote_fetches_queued,
+                "Average remote fetch queue wait"
+            );
+        }
         set_stat!(stats_vec, self.cache_read_errors, "Cache read errors");
         set_stat!(stats_vec, self.forced_recaches, "Forced recaches");
+        if self.dry_run_hits + self.dry_run_misses > 0 {
+            set_stat!(stats_vec, self.dry_run_hits, "Dry run hits");
+            set_stat!(stats_vec, self.dry_run_misses, "Dry run misses");
+        }
         set_stat!(stats_vec, self.cache_write_errors, "Cache write errors");
+        set_stat!(stats_vec, self.cache_write_timeouts, "Cache write timeouts");
         set_stat!(stats_vec, self.compile_fails, "Compilation failures");
+        set_stat!(stats_vec, self.preprocessor_errors, "Preprocessor errors");
+        if self.deadlines_exceeded > 0 {
+            set_stat!(stats_vec, self.deadlines_exceeded, "Deadlines exceeded");
+        }
         if advanced {
             set_compiler_stat!(stats_vec, self.cache_errors, "Cache errors");
         } else {
@@ -1586,11 +3449,21 @@ impl ServerStats {
             self.non_cacheable_compilations,
             "Non-cacheable compilations"
         );
+        set_stat!(
+            stats_vec,
+            self.too_large_compilations,
+            "Compilations too large to cache"
+        );
         set_stat!(
             stats_vec,
             self.requests_not_cacheable,
             "Non-cacheable calls"
         );
+        set_stat!(
+            stats_vec,
+            self.requests_split,
+            "Multiple source file calls split"
+        );
         set_stat!(
             stats_vec,
             self.requests_not_compile,
@@ -1649,6 +3522,41 @@ impl ServerStats {
                 );
             }
         }
+        for (title, counts) in [
+            ("Cache override hits", &self.cache_override_hits),
+            ("Cache override misses", &self.cache_override_misses),
+        ] {
+            if counts.is_empty() {
+                continue;
+            }
+            println!("\n{}", title);
+            let mut counts: Vec<_> = counts.iter().collect();
+            counts.sort_by(|(_, c1), (_, c2)| c1.cmp(c2).reverse());
+            for (url, count) in counts {
+                println!(
+                    "  {:<name_width$} {:>stat_width$}",
+                    url,
+                    count,
+                    name_width = name_width - 2,
+                    stat_width = stat_width
+                );
+            }
+        }
+        if !self.peak_rss.is_empty() {
+            println!("\nCompiler peak RSS (max, avg)");
+            let mut peaks: Vec<_> = self.peak_rss.peaks.iter().collect();
+            peaks.sort_by_key(|(lang, _)| *lang);
+            for (lang, peak) in peaks {
+                println!(
+                    "  {:<name_width$} {:>stat_width$} {}",
+                    lang,
+                    fmt_bytes(peak.max),
+                    fmt_bytes(peak.avg()),
+                    name_width = name_width - 2,
+                    stat_width = stat_width
+                );
+            }
+        }
         if !self.not_cached.is_empty() {
             println!("\nNon-cacheable reasons:");
             let mut counts: Vec<_> = self.not_cached.iter().collect();
@@ -1668,14 +3576,44 @@ impl ServerStats {
     }
 }
 
+/// Format `bytes` as e.g. `512 MiB`.
+fn fmt_bytes(bytes: u64) -> String {
+    match NumberPrefix::binary(bytes as f64) {
+        NumberPrefix::Standalone(bytes) => format!("{} bytes", bytes),
+        NumberPrefix::Prefixed(prefix, n) => format!("{:.0} {}B", n, prefix),
+    }
+}
+
+/// Write `info` to `path` as JSON, like `--show-stats --stats-format=json`
+/// prints it. The file is replaced at once, so that a half-written one never
+/// appears.
+pub(crate) fn write_stats(path: &Path, info: &ServerInfo) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut tmp = tempfile::NamedTempFile::new_in(dir)
+        .with_context(|| format!("failed to create a file in {}", dir.display()))?;
+    serde_json::to_writer(&mut tmp, info)?;
+    tmp.as_file().sync_all()?;
+    tmp.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
+
 impl ServerInfo {
     pub async fn new(stats: ServerStats, storage: Option<&dyn Storage>) -> Result<Self> {
         let cache_location;
         let use_preprocessor_cache_mode;
         let cache_size;
         let max_cache_size;
+        let requests_in_flight;
+        let eviction;
+        let occupancy;
         if let Some(storage) = storage {
             cache_location = storage.location();
+            requests_in_flight = storage.requests_in_flight();
+            eviction = storage.eviction_state();
+            occupancy = storage.occupancy();
             use_preprocessor_cache_mode = storage
                 .preprocessor_cache_mode_config()
                 .use_preprocessor_cache_mode;
@@ -1683,6 +3621,9 @@ impl ServerInfo {
                 futures::try_join!(storage.current_size(), storage.max_size())?;
         } else {
             cache_location = String::new();
+            requests_in_flight = None;
+            eviction = None;
+            occupancy = None;
             use_preprocessor_cache_mode = false;
             cache_size = None;
             max_cache_size = None;
@@ -1693,11 +3634,43 @@ impl ServerInfo {
             cache_location,
             cache_size,
             max_cache_size,
+            requests_in_flight,
+            eviction,
+            occupancy,
+            queued_compiles: None,
             use_preprocessor_cache_mode,
             version,
+            protocol_version: PROTOCOL_VERSION,
         })
     }
 
+    /// Encode the info compactly, as `--stats-format=bincode` prints it: the
+    /// `PROTOCOL_VERSION` of the fields as a little-endian `u32`, followed by
+    /// the bincode encoding of the info, on which it depends.
+    pub fn to_bincode(&self) -> Result<Vec<u8>> {
+        let mut bytes = PROTOCOL_VERSION.to_le_bytes().to_vec();
+        bincode::serialize_into(&mut bytes, self)?;
+        Ok(bytes)
+    }
+
+    /// Decode the info encoded with `to_bincode`, which must be of the same
+    /// `PROTOCOL_VERSION`.
+    pub fn from_bincode(bytes: &[u8]) -> Result<ServerInfo> {
+        if bytes.len() < 4 {
+            bail!("The stats are truncated");
+        }
+        let (version, info) = bytes.split_at(4);
+        let version = u32::from_le_bytes(version.try_into().unwrap());
+        if version != PROTOCOL_VERSION {
+            bail!(
+                "The stats are of version {}, while this reads version {}",
+                version,
+                PROTOCOL_VERSION
+            );
+        }
+        Ok(bincode::deserialize(info)?)
+    }
+
     /// Print info to stdout in a human-readable format.
     pub fn print(&self, advanced: bool) {
         let (name_width, stat_width) = self.stats.print(advanced);
@@ -1719,15 +3692,50 @@ impl ServerInfo {
                 name_width = name_width
             );
         }
+        if let Some(requests) = self.requests_in_flight {
+            println!(
+                "{:<name_width$} {:>stat_width$}",
+                "Cache requests in flight",
+                requests,
+                name_width = name_width,
+                stat_width = stat_width
+            );
+        }
+        if let Some(queued) = self.queued_compiles {
+            println!(
+                "{:<name_width$} {:>stat_width$}",
+                "Compile requests queued",
+                queued,
+                name_width = name_width,
+                stat_width = stat_width
+            );
+        }
         println!(
             "{:<name_width$} {}",
             "Version (client)",
             self.version,
             name_width = name_width
         );
+        let (high_watermark, low_watermark) = match self.eviction {
+            Some(eviction) if advanced => {
+                (Some(eviction.high_watermark), Some(eviction.low_watermark))
+            }
+            _ => (None, None),
+        };
+        if self.cache_size.is_none() && !self.cache_location.is_empty() {
+            println!(
+                "{:<name_width$} {:>stat_width$}",
+                "Cache size",
+                "unknown",
+                name_width = name_width,
+                stat_width = stat_width
+            );
+        }
         for &(name, val) in &[
             ("Cache size", &self.cache_size),
             ("Max cache size", &self.max_cache_size),
+            ("Eviction high watermark", &high_watermark),
+            ("Eviction low watermark", &low_watermark),
         ] {
             if let Some(val) = *val {
                 let (val, suffix) = match NumberPrefix::binary(val as f64) {
@@ -1746,6 +3754,103 @@ impl ServerInfo {
                 );
             }
         }
+        if let Some(eviction) = self.eviction.filter(|_| advanced) {
+            println!(
+                "{:<name_width$} {}",
+                "Eviction running?",
+                if eviction.sweeping { "yes" } else { "no" },
+                name_width = name_width
+            );
+        }
+        if let Some(occupancy) = self.occupancy {
+            println!(
+                "{:<name_width$} {:>stat_width$}",
+                "Cache entries",
+                occupancy.entries,
+                name_width = name_width,
+                stat_width = stat_width
+            );
+            println!(
+                "{:<name_width$} {:>stat_width$}",
+                "Cache evictions",
+                occupancy.evictions,
+                name_width = name_width,
+                stat_width = stat_width
+            );
+            println!(
+                "{:<name_width$} {:>stat_width$}",
+                "Cache evicted",
+                fmt_bytes(occupancy.evicted_bytes),
+                name_width = name_width,
+                stat_width = stat_width
+            );
+        }
+    }
+
+    /// Format the cache occupancy and the main counters in the Prometheus
+    /// text exposition format, as `--stats-format=prometheus` prints them.
+    /// The size of a cache which doesn't know it, e.g. a remote one, is
+    /// `NaN`, and its entries and evictions are left out.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+            out.push_str(&format!(
+                "# HELP sccache_{name} {help}\n# TYPE sccache_{name} {kind}\n"
+            ));
+            out.push_str(&format!("sccache_{name} {value}\n"));
+        };
+        let known = |size: Option<u64>| size.map_or("NaN".to_owned(), |s| s.to_string());
+        metric(
+            "cache_bytes",
+            "gauge",
+            "The size of the cache, in bytes.",
+            known(self.cache_size),
+        );
+        metric(
+            "cache_max_bytes",
+            "gauge",
+            "The maximum size of the cache, in bytes.",
+            known(self.max_cache_size),
+        );
+        if let Some(occupancy) = self.occupancy {
+            metric(
+                "cache_entries",
+                "gauge",
+                "The number of files in the cache.",
+                occupancy.entries.to_string(),
+            );
+            metric(
+                "cache_evictions_total",
+                "counter",
+                "The files evicted from the cache.",
+                occupancy.evictions.to_string(),
+            );
+            metric(
+                "cache_evicted_bytes_total",
+                "counter",
+                "The size of the files evicted from the cache, in bytes.",
+                occupancy.evicted_bytes.to_string(),
+            );
+        }
+        metric(
+            "compile_requests_total",
+            "counter",
+            "The compile requests.",
+            self.stats.compile_requests.to_string(),
+        );
+        metric(
+            "cache_hits_total",
+            "counter",
+            "The cache hits.",
+            self.stats.cache_hits.all().to_string(),
+        );
+        metric(
+            "cache_misses_total",
+            "counter",
+            "The cache misses.",
+            self.stats.cache_misses.all().to_string(),
+        );
+        out
     }
 }
 
@@ -1754,13 +3859,19 @@ enum Frame<R, R1> {
     Message { message: R },
 }
 
-struct Body<R> {
-    receiver: mpsc::Receiver<Result<R>>,
+/// The responses following a response, e.g. the results of a compilation
+/// following `CompileResponse::CompileStarted`.
+pub struct Body<R> {
+    receiver: tokio::sync::mpsc::Receiver<Result<R>>,
 }
 
+/// The sending end of a `Body`, which can tell when the body is dropped, e.g.
+/// because the client disconnected.
+type BodySender<R> = tokio::sync::mpsc::Sender<Result<R>>;
+
 impl<R> Body<R> {
-    fn pair() -> (mpsc::Sender<Result<R>>, Self) {
-        let (tx, rx) = mpsc::channel(0);
+    fn pair() -> (BodySender<R>, Self) {
+        let (tx, rx) = tokio::sync::mpsc::channel(1);
         (tx, Body { receiver: rx })
     }
 }
@@ -1771,17 +3882,25 @@ impl<R> futures::Stream for Body<R> {
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
-        Pin::new(&mut self.receiver).poll_next(cx)
+        self.receiver.poll_recv(cx)
     }
 }
 
-enum Message<R, B> {
+/// A request or a response, and the body following it, if any.
+pub enum Message<R, B> {
     WithBody(R, B),
     WithoutBody(R),
 }
 
 impl<R, B> Message<R, B> {
-    fn into_inner(self) -> R {
+    pub fn into_inner(self) -> R {
+        match self {
+            Message::WithBody(r, _) => r,
+            Message::WithoutBody(r) => r,
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
         match self {
             Message::WithBody(r, _) => r,
             Message::WithoutBody(r) => r,
@@ -1815,14 +3934,65 @@ where
     }
 }
 
+/// The length-delimited framing of the messages, each prefixed with its
+/// length as a big-endian `u32`.
+///
+/// A message is buffered across as many reads as it takes to arrive, however
+/// the writes of the peer were split. The connection closing in the middle of
+/// a message is an `UnexpectedEof` error, rather than the generic one of
+/// `LengthDelimitedCodec`.
+struct FrameCodec(LengthDelimitedCodec);
+
+impl Decoder for FrameCodec {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<BytesMut>> {
+        // A message is only handed to `LengthDelimitedCodec` once it is whole,
+        // or to fail if it is too large, so that what was read of it stays in
+        // `src` until then.
+        let Some(head) = src.get(..4) else {
+            return Ok(None);
+        };
+        let len = u32::from_be_bytes(head.try_into().unwrap()) as usize;
+        if len <= self.0.max_frame_length() && src.len() < 4 + len {
+            src.reserve(4 + len - src.len());
+            return Ok(None);
+        }
+        self.0.decode(src)
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> io::Result<Option<BytesMut>> {
+        match self.decode(src)? {
+            Some(frame) => Ok(Some(frame)),
+            None if src.is_empty() => Ok(None),
+            None => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "the connection closed in the middle of a message, after {} bytes of it",
+                    src.len()
+                ),
+            )),
+        }
+    }
+}
+
+impl Encoder<Bytes> for FrameCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, frame: Bytes, dst: &mut BytesMut) -> io::Result<()> {
+        self.0.encode(frame, dst)
+    }
+}
+
 /// Implementation of `Stream + Sink` that tokio-proto is expecting
 ///
 /// This type is composed of a few layers:
 ///
 /// * First there's `I`, the I/O object implementing `AsyncRead` and
 ///   `AsyncWrite`
-/// * Next that's framed using the `length_delimited` module in tokio-io giving
-///   us a `Sink` and `Stream` of `BytesMut`.
+/// * Next that's framed by `FrameCodec`, over the `length_delimited` module
+///   of tokio-util, giving us a `Sink` and `Stream` of `BytesMut`.
 /// * Next that sink/stream is wrapped in `ReadBincode` which will cause the
 ///   `Stream` implementation to switch from `BytesMut` to `Request` by parsing
 ///   the bytes  bincode.
@@ -1833,11 +4003,7 @@ where
 struct SccacheTransport<I: AsyncRead + AsyncWrite + Unpin> {
     inner: Framed<
         futures::stream::ErrInto<
-            futures::sink::SinkErrInto<
-                tokio_util::codec::Framed<I, LengthDelimitedCodec>,
-                Bytes,
-                Error,
-            >,
+            futures::sink::SinkErrInto<tokio_util::codec::Framed<I, FrameCodec>, Bytes, Error>,
             Error,
         >,
         Request,
@@ -1846,6 +4012,18 @@ struct SccacheTransport<I: AsyncRead + AsyncWrite + Unpin> {
     >,
 }
 
+impl<I: AsyncRead + AsyncWrite + Unpin> SccacheTransport<I> {
+    fn new(io: I, max_frame_length: usize) -> SccacheTransport<I> {
+        let codec = length_delimited::Builder::new()
+            .max_frame_length(max_frame_length)
+            .new_codec();
+        let io = tokio_util::codec::Framed::new(io, FrameCodec(codec));
+        SccacheTransport {
+            inner: Framed::new(io.sink_err_into().err_into(), BincodeCodec),
+        }
+    }
+}
+
 impl<I: AsyncRead + AsyncWrite + Unpin> Stream for SccacheTransport<I> {
     type Item = Result<Message<Request, Body<()>>>;
 
@@ -1882,8 +4060,9 @@ impl<I: AsyncRead + AsyncWrite + Unpin> Sink<Frame<Response, Response>> for Scca
 
 struct ShutdownOrInactive {
     rx: mpsc::Receiver<ServerMessage>,
-    timeout: Option<Pin<Box<Sleep>>>,
+    timeout: Option<BoxFuture<'static, ()>>,
     timeout_dur: Duration,
+    clock: SharedClock,
 }
 
 impl Future for ShutdownOrInactive {
@@ -1897,7 +4076,7 @@ impl Future for ShutdownOrInactive {
                 Poll::Ready(Some(ServerMessage::Shutdown)) => return Poll::Ready(()),
                 Poll::Ready(Some(ServerMessage::Request)) => {
                     if self.timeout_dur != Duration::new(0, 0) {
-                        self.timeout = Some(Box::pin(sleep(self.timeout_dur)));
+                        self.timeout = Some(self.clock.sleep(self.timeout_dur));
                     }
                 }
                 // All services have shut down, in theory this isn't possible...
@@ -1911,6 +4090,31 @@ impl Future for ShutdownOrInactive {
     }
 }
 
+/// Wait for the running compilations counted by `active_compiles` to finish,
+/// at most for `timeout`, and return how many are still running.
+async fn wait_for_compiles(active_compiles: &watch::Sender<usize>, timeout: Duration) -> usize {
+    let mut active = active_compiles.subscribe();
+    let _ = time::timeout(timeout, active.wait_for(|n| *n == 0)).await;
+    let running = *active.borrow();
+    running
+}
+
+/// Counts a compilation, or a cache write, as running until it is dropped.
+struct InFlight(Arc<watch::Sender<usize>>);
+
+impl InFlight {
+    fn new(count: Arc<watch::Sender<usize>>) -> InFlight {
+        count.send_modify(|n| *n += 1);
+        InFlight(count)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.send_modify(|n| *n -= 1);
+    }
+}
+
 /// Helper future which tracks the `ActiveInfo` below. This future will resolve
 /// once all instances of `ActiveInfo` have been dropped.
 struct WaitUntilZero {
@@ -1978,3 +4182,110 @@ fn waits_until_zero() {
     drop(active2);
     assert_eq!(wait.now_or_never(), Some(()));
 }
+
+/// Write `chunks` to a transport one after the other, reading the requests
+/// which are whole after each, then close the connection. Returns the
+/// requests read, and the error the transport ended with, if any.
+#[cfg(test)]
+async fn read_requests(chunks: &[&[u8]]) -> (Vec<String>, Option<Error>) {
+    use tokio::io::AsyncWriteExt;
+
+    let size = chunks.iter().map(|chunk| chunk.len()).sum::<usize>().max(1);
+    let (mut client, server) = tokio::io::duplex(size);
+    let mut transport = SccacheTransport::new(server, 1 << 20);
+    let mut requests = vec![];
+    for chunk in chunks {
+        client.write_all(chunk).await.unwrap();
+        while let Some(Some(request)) = transport.next().now_or_never() {
+            requests.push(format!("{:?}", request.unwrap().into_inner()));
+        }
+    }
+    drop(client);
+    while let Some(request) = transport.next().await {
+        match request {
+            Ok(request) => requests.push(format!("{:?}", request.into_inner())),
+            Err(e) => return (requests, Some(e)),
+        }
+    }
+    (requests, None)
+}
+
+#[tokio::test]
+async fn test_transport_partial_reads() {
+    let messages = [
+        Request::Authenticate("token".repeat(8)),
+        Request::GetStats,
+        Request::Stdin(vec![1, 2, 3]),
+    ];
+    let mut bytes = vec![];
+    let mut ends = vec![];
+    for message in &messages {
+        let data = bincode::serialize(message).unwrap();
+        bytes.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&data);
+        ends.push(bytes.len());
+    }
+    let expected: Vec<_> = messages.iter().map(|m| format!("{:?}", m)).collect();
+
+    // However the messages are split across the reads, they are read whole.
+    for split in 0..=bytes.len() {
+        let (requests, err) = read_requests(&[&bytes[..split], &bytes[split..]]).await;
+        assert!(err.is_none(), "split at {}: {:?}", split, err);
+        assert_eq!(requests, expected, "split at {}", split);
+    }
+    let one_by_one: Vec<_> = bytes.chunks(1).collect();
+    let (requests, err) = read_requests(&one_by_one).await;
+    assert!(err.is_none(), "{:?}", err);
+    assert_eq!(requests, expected);
+
+    // A message cut short by the end of the connection is an error, after the
+    // messages before it.
+    for end in 1..bytes.len() {
+        let (requests, err) = read_requests(&[&bytes[..end]]).await;
+        let whole = ends.iter().filter(|&&e| e <= end).count();
+        assert_eq!(requests, expected[..whole], "truncated at {}", end);
+        if ends.contains(&end) {
+            assert!(err.is_none(), "truncated at {}: {:?}", end, err);
+            continue;
+        }
+        let err = err.unwrap_or_else(|| panic!("truncated at {}", end));
+        let kind = err.downcast_ref::<io::Error>().map(io::Error::kind);
+        assert_eq!(kind, Some(io::ErrorKind::UnexpectedEof), "{:#}", err);
+    }
+}
+
+#[tokio::test]
+async fn test_server_info_bincode() {
+    let stats = ServerStats {
+        compile_requests: 3,
+        cache_writes: 2,
+        not_cached: HashMap::from([("-E".to_owned(), 1)]),
+        ..Default::default()
+    };
+    let mut info = ServerInfo::new(stats, None).await.unwrap();
+    info.cache_location = "Local disk: \"/tmp/cache\"".to_owned();
+    info.queued_compiles = Some(1);
+    let mut bytes = info.to_bincode().unwrap();
+    assert_eq!(bytes[..4], PROTOCOL_VERSION.to_le_bytes());
+
+    // The stats are decoded whole.
+    let decoded = ServerInfo::from_bincode(&bytes).unwrap();
+    assert_eq!(
+        serde_json::to_value(&info).unwrap(),
+        serde_json::to_value(&decoded).unwrap()
+    );
+
+    // The stats of another version, or truncated, aren't misread.
+    assert!(ServerInfo::from_bincode(&bytes[..bytes.len() - 1]).is_err());
+    assert!(ServerInfo::from_bincode(&bytes[..2]).is_err());
+    bytes[..4].copy_from_slice(&(PROTOCOL_VERSION + 1).to_le_bytes());
+    let err = ServerInfo::from_bincode(&bytes).unwrap_err().to_string();
+    assert_eq!(
+        err,
+        format!(
+            "The stats are of version {}, while this reads version {}",
+            PROTOCOL_VERSION + 1,
+            PROTOCOL_VERSION
+        )
+    );
+}
@@ -0,0 +1,562 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Running the server under a service manager: in the foreground, e.g. as a
+//! systemd service of `Type=simple`, possibly socket activated, or as a
+//! Windows service.
+
+#[cfg(unix)]
+use std::{env, io, mem, process};
+
+use tokio::runtime::Runtime;
+
+use crate::errors::*;
+use crate::server::Acceptor;
+
+/// Wait until the server is asked to stop by a signal, SIGTERM or SIGINT on
+/// Unix and Ctrl-C on Windows.
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => tokio::select! {
+                _ = terminate.recv() => info!("received SIGTERM"),
+                Ok(()) = tokio::signal::ctrl_c() => info!("received SIGINT"),
+            },
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(windows)]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+/// The variable a supervisor sets to the file descriptor of a listening
+/// socket for the server to take over, see `activated_listener`.
+const LISTEN_FD_VAR: &str = "SCCACHE_LISTEN_FD";
+
+/// Take the listening socket passed to the server, instead of binding one:
+/// the one systemd passes with socket activation, as told by `LISTEN_PID` and
+/// `LISTEN_FDS`, or the inherited one of `SCCACHE_LISTEN_FD`, e.g. from a
+/// supervisor restarting the server without closing its socket. It may be a
+/// TCP or a Unix socket.
+#[cfg(unix)]
+pub fn activated_listener(runtime: &Runtime) -> Result<Option<Acceptor>> {
+    use std::os::unix::io::RawFd;
+
+    /// The first file descriptor systemd passes.
+    const SD_LISTEN_FDS_START: RawFd = 3;
+
+    if let Some(fd) = env::var_os(LISTEN_FD_VAR) {
+        // It is meant for the server, not for the compilers it runs.
+        env::remove_var(LISTEN_FD_VAR);
+        let fd = fd
+            .to_str()
+            .and_then(|fd| fd.parse::<RawFd>().ok())
+            .filter(|&fd| fd >= 0)
+            .with_context(|| format!("Invalid {} {:?}", LISTEN_FD_VAR, fd))?;
+        // The clients connect to a Unix socket when `SCCACHE_SERVER_UDS` is
+        // set, and to a TCP one otherwise.
+        let unix = env::var_os("SCCACHE_SERVER_UDS").is_some();
+        let from = format!("{}={}", LISTEN_FD_VAR, fd);
+        return listener_from_fd(runtime, fd, &from, Some(unix)).map(Some);
+    }
+
+    let (Some(pid), Some(fds)) = (env::var_os("LISTEN_PID"), env::var_os("LISTEN_FDS")) else {
+        return Ok(None);
+    };
+    // They are meant for the server, not for the compilers it runs.
+    for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(var);
+    }
+    if pid.to_str().and_then(|pid| pid.parse().ok()) != Some(process::id()) {
+        debug!("Ignoring the sockets passed to the process {:?}", pid);
+        return Ok(None);
+    }
+    let fds = fds
+        .to_str()
+        .and_then(|fds| fds.parse::<RawFd>().ok())
+        .filter(|&fds| fds > 0)
+        .with_context(|| format!("Invalid LISTEN_FDS {:?}", fds))?;
+    if fds > 1 {
+        warn!(
+            "Only listening on the first of the {} sockets passed by systemd",
+            fds
+        );
+    }
+    listener_from_fd(runtime, SD_LISTEN_FDS_START, "systemd", None).map(Some)
+}
+
+/// Take the listening stream socket `fd`, passed by `from`, which must be a
+/// Unix socket if `unix` is `Some(true)`, an IPv4 or IPv6 one if it is
+/// `Some(false)`, and either otherwise.
+#[cfg(unix)]
+fn listener_from_fd(
+    runtime: &Runtime,
+    fd: std::os::unix::io::RawFd,
+    from: &str,
+    unix: Option<bool>,
+) -> Result<Acceptor> {
+    use std::os::unix::io::FromRawFd;
+
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of_val(&addr) as libc::socklen_t;
+    if unsafe { libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) } != 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("The file descriptor passed by {} is not a socket", from));
+    }
+    let get_option = |option| -> io::Result<libc::c_int> {
+        let mut value: libc::c_int = 0;
+        let mut len = mem::size_of_val(&value) as libc::socklen_t;
+        let res = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                option,
+                &mut value as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if res != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(value)
+    };
+    if get_option(libc::SO_TYPE)? != libc::SOCK_STREAM {
+        bail!("The socket passed by {} is not a stream socket", from);
+    }
+    if get_option(libc::SO_ACCEPTCONN)? == 0 {
+        bail!("The socket passed by {} is not listening", from);
+    }
+    let family = addr.ss_family as libc::c_int;
+    match (family, unix) {
+        (libc::AF_UNIX, Some(false)) => bail!(
+            "The socket passed by {} is a Unix socket, but SCCACHE_SERVER_UDS isn't set for the clients to connect to it",
+            from
+        ),
+        (libc::AF_INET | libc::AF_INET6, Some(true)) => bail!(
+            "The socket passed by {} is a TCP socket, but SCCACHE_SERVER_UDS is set for the clients to connect to a Unix socket",
+            from
+        ),
+        _ => {}
+    }
+    // Nor is the socket, which was passed inheritable.
+    unsafe {
+        libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+    }
+    let _guard = runtime.enter();
+    let listener = match family {
+        libc::AF_UNIX => {
+            let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+            listener.set_nonblocking(true)?;
+            info!(
+                "Listening on the Unix socket {:?} passed by {}",
+                listener.local_addr()?,
+                from
+            );
+            Acceptor::Unix(tokio::net::UnixListener::from_std(listener)?)
+        }
+        libc::AF_INET | libc::AF_INET6 => {
+            let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            listener.set_nonblocking(true)?;
+            info!("Listening on {} passed by {}", listener.local_addr()?, from);
+            Acceptor::Tcp(tokio::net::TcpListener::from_std(listener)?)
+        }
+        family => bail!(
+            "The socket passed by {} is of the unsupported family {}",
+            from,
+            family
+        ),
+    };
+    Ok(listener)
+}
+
+/// Take the inherited listening TCP socket whose handle is
+/// `SCCACHE_LISTEN_FD`, if set, instead of binding one. There is no socket
+/// activation on Windows.
+#[cfg(windows)]
+pub fn activated_listener(runtime: &Runtime) -> Result<Option<Acceptor>> {
+    use std::os::windows::io::{FromRawSocket, RawSocket};
+    use std::{env, io, mem};
+    use winapi::shared::ws2def::{AF_INET, AF_INET6, SOCKADDR, SOCKADDR_STORAGE};
+    use winapi::um::handleapi::SetHandleInformation;
+    use winapi::um::winbase::HANDLE_FLAG_INHERIT;
+    use winapi::um::winsock2::{
+        getsockname, getsockopt, WSAGetLastError, WSAStartup, SOCKET, SOCK_STREAM, SOL_SOCKET,
+        SO_ACCEPTCONN, SO_TYPE, WSADATA,
+    };
+
+    let Some(socket) = env::var_os(LISTEN_FD_VAR) else {
+        return Ok(None);
+    };
+    // It is meant for the server, not for the compilers it runs.
+    env::remove_var(LISTEN_FD_VAR);
+    let socket = socket
+        .to_str()
+        .and_then(|socket| socket.parse::<RawSocket>().ok())
+        .with_context(|| format!("Invalid {} {:?}", LISTEN_FD_VAR, socket))?;
+    let from = format!("{}={}", LISTEN_FD_VAR, socket);
+    let last_error = || io::Error::from_raw_os_error(unsafe { WSAGetLastError() });
+
+    // The standard library only sets Winsock up once it creates a socket.
+    let mut data: WSADATA = unsafe { mem::zeroed() };
+    let res = unsafe { WSAStartup(0x202, &mut data) };
+    if res != 0 {
+        return Err(io::Error::from_raw_os_error(res)).context("Failed to set up Winsock");
+    }
+    let mut addr: SOCKADDR_STORAGE = unsafe { mem::zeroed() };
+    let mut len = mem::size_of_val(&addr) as i32;
+    if unsafe {
+        getsockname(
+            socket as SOCKET,
+            &mut addr as *mut _ as *mut SOCKADDR,
+            &mut len,
+        )
+    } != 0
+    {
+        return Err(last_error())
+            .with_context(|| format!("The handle passed by {} is not a socket", from));
+    }
+    let get_option = |option| -> io::Result<i32> {
+        let mut value: i32 = 0;
+        let mut len = mem::size_of_val(&value) as i32;
+        let res = unsafe {
+            getsockopt(
+                socket as SOCKET,
+                SOL_SOCKET,
+                option,
+                &mut value as *mut _ as *mut _,
+                &mut len,
+            )
+        };
+        if res != 0 {
+            return Err(last_error());
+        }
+        Ok(value)
+    };
+    if get_option(SO_TYPE)? != SOCK_STREAM {
+        bail!("The socket passed by {} is not a stream socket", from);
+    }
+    if get_option(SO_ACCEPTCONN)? == 0 {
+        bail!("The socket passed by {} is not listening", from);
+    }
+    let family = addr.ss_family as i32;
+    if family != AF_INET && family != AF_INET6 {
+        bail!(
+            "The socket passed by {} is of the unsupported family {}",
+            from,
+            family
+        );
+    }
+    // Nor is the socket, which was passed inheritable.
+    unsafe {
+        SetHandleInformation(socket as _, HANDLE_FLAG_INHERIT, 0);
+    }
+    let _guard = runtime.enter();
+    let listener = unsafe { std::net::TcpListener::from_raw_socket(socket) };
+    listener.set_nonblocking(true)?;
+    info!("Listening on {} passed by {}", listener.local_addr()?, from);
+    Ok(Some(Acceptor::Tcp(tokio::net::TcpListener::from_std(
+        listener,
+    )?)))
+}
+
+#[cfg(windows)]
+pub use self::windows::{install, run, uninstall};
+
+/// The services of the service control manager, which starts the server at
+/// boot and restarts it when it crashes.
+#[cfg(windows)]
+mod windows {
+    use std::ffi::OsStr;
+    use std::os::windows::ffi::OsStrExt;
+    use std::ptr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use std::{env, io, mem};
+
+    use futures::channel::oneshot;
+    use winapi::shared::minwindef::{DWORD, FALSE, LPVOID, TRUE};
+    use winapi::shared::winerror::{ERROR_CALL_NOT_IMPLEMENTED, NO_ERROR};
+    use winapi::um::winnt::{
+        DELETE, LPWSTR, SERVICE_AUTO_START, SERVICE_ERROR_NORMAL, SERVICE_WIN32_OWN_PROCESS,
+    };
+    use winapi::um::winsvc::*;
+
+    use crate::errors::*;
+
+    /// The name of the service.
+    const SERVICE_NAME: &str = "sccache";
+    const DISPLAY_NAME: &str = "sccache server";
+    const DESCRIPTION: &str = "Caches the compilations of the sccache clients of this machine.";
+    /// The argument the service control manager runs sccache with.
+    const RUN_SERVICE_ARG: &str = "--run-service";
+    /// How long the service control manager waits before restarting the
+    /// server after a crash, in milliseconds.
+    const RESTART_DELAY: DWORD = 5000;
+
+    /// The handle the status of the service is reported with.
+    static STATUS_HANDLE: AtomicUsize = AtomicUsize::new(0);
+    /// Stops the server, once the service control manager asks to.
+    static STOP: Mutex<Option<oneshot::Sender<()>>> = Mutex::new(None);
+
+    fn wide(s: impl AsRef<OsStr>) -> Vec<u16> {
+        s.as_ref().encode_wide().chain(Some(0)).collect()
+    }
+
+    /// A handle of the service control manager or of a service, closed when
+    /// dropped.
+    struct ScHandle(SC_HANDLE);
+
+    impl ScHandle {
+        fn new(handle: SC_HANDLE) -> io::Result<ScHandle> {
+            if handle.is_null() {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(ScHandle(handle))
+            }
+        }
+    }
+
+    impl Drop for ScHandle {
+        fn drop(&mut self) {
+            unsafe {
+                CloseServiceHandle(self.0);
+            }
+        }
+    }
+
+    fn open_manager(access: DWORD) -> Result<ScHandle> {
+        ScHandle::new(unsafe { OpenSCManagerW(ptr::null(), ptr::null(), access) })
+            .context("failed to open the service control manager, which needs administrator rights")
+    }
+
+    /// Install the service running the current executable, started at boot
+    /// and now, and restarted when it crashes.
+    pub fn install() -> Result<()> {
+        let exe = env::current_exe()?;
+        let command = wide(format!("\"{}\" {}", exe.display(), RUN_SERVICE_ARG));
+        let name = wide(SERVICE_NAME);
+        let display_name = wide(DISPLAY_NAME);
+        let manager = open_manager(SC_MANAGER_CREATE_SERVICE)?;
+        let service = ScHandle::new(unsafe {
+            CreateServiceW(
+                manager.0,
+                name.as_ptr(),
+                display_name.as_ptr(),
+                SERVICE_ALL_ACCESS,
+                SERVICE_WIN32_OWN_PROCESS,
+                SERVICE_AUTO_START,
+                SERVICE_ERROR_NORMAL,
+                command.as_ptr(),
+                ptr::null(),
+                ptr::null_mut(),
+                ptr::null(),
+                // Run as LocalSystem.
+                ptr::null(),
+                ptr::null(),
+            )
+        })
+        .context("failed to create the service")?;
+
+        let mut description = wide(DESCRIPTION);
+        let mut info = SERVICE_DESCRIPTIONW {
+            lpDescription: description.as_mut_ptr(),
+        };
+        if unsafe {
+            ChangeServiceConfig2W(
+                service.0,
+                SERVICE_CONFIG_DESCRIPTION,
+                &mut info as *mut _ as LPVOID,
+            )
+        } == FALSE
+        {
+            warn!(
+                "Failed to set the description of the service: {}",
+                io::Error::last_os_error()
+            );
+        }
+
+        // Restart after the first crashes, forgetting about them after a day.
+        let mut actions = [SC_ACTION {
+            Type: SC_ACTION_RESTART,
+            Delay: RESTART_DELAY,
+        }; 3];
+        let mut failure_actions = SERVICE_FAILURE_ACTIONSW {
+            dwResetPeriod: 24 * 60 * 60,
+            lpRebootMsg: ptr::null_mut(),
+            lpCommand: ptr::null_mut(),
+            cActions: actions.len() as DWORD,
+            lpsaActions: actions.as_mut_ptr(),
+        };
+        if unsafe {
+            ChangeServiceConfig2W(
+                service.0,
+                SERVICE_CONFIG_FAILURE_ACTIONS,
+                &mut failure_actions as *mut _ as LPVOID,
+            )
+        } == FALSE
+        {
+            return Err(io::Error::last_os_error())
+                .context("failed to set the service to restart on failure");
+        }
+
+        // Also restart when the server stops with an error, e.g. its storage
+        // being unreachable while booting.
+        let mut flag = SERVICE_FAILURE_ACTIONS_FLAG {
+            fFailureActionsOnNonCrashFailures: TRUE,
+        };
+        if unsafe {
+            ChangeServiceConfig2W(
+                service.0,
+                SERVICE_CONFIG_FAILURE_ACTIONS_FLAG,
+                &mut flag as *mut _ as LPVOID,
+            )
+        } == FALSE
+        {
+            warn!(
+                "Failed to set the service to restart when it fails: {}",
+                io::Error::last_os_error()
+            );
+        }
+
+        if unsafe { StartServiceW(service.0, 0, ptr::null_mut()) } == FALSE {
+            return Err(io::Error::last_os_error()).context("failed to start the service");
+        }
+        Ok(())
+    }
+
+    /// Stop and remove the service.
+    pub fn uninstall() -> Result<()> {
+        let name = wide(SERVICE_NAME);
+        let manager = open_manager(SC_MANAGER_CONNECT)?;
+        let service = ScHandle::new(unsafe {
+            OpenServiceW(
+                manager.0,
+                name.as_ptr(),
+                SERVICE_STOP | SERVICE_QUERY_STATUS | DELETE,
+            )
+        })
+        .context("failed to open the service")?;
+        let mut status: SERVICE_STATUS = unsafe { mem::zeroed() };
+        // Fails if it isn't running, which is fine.
+        unsafe {
+            ControlService(service.0, SERVICE_CONTROL_STOP, &mut status);
+        }
+        if unsafe { DeleteService(service.0) } == FALSE {
+            return Err(io::Error::last_os_error()).context("failed to delete the service");
+        }
+        Ok(())
+    }
+
+    /// Run the server as the service, as the service control manager starts
+    /// it. Returns once the service stopped.
+    pub fn run() -> Result<()> {
+        let name = wide(SERVICE_NAME);
+        let table = [
+            SERVICE_TABLE_ENTRYW {
+                lpServiceName: name.as_ptr(),
+                lpServiceProc: Some(service_main),
+            },
+            SERVICE_TABLE_ENTRYW {
+                lpServiceName: ptr::null(),
+                lpServiceProc: None,
+            },
+        ];
+        if unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) } == FALSE {
+            return Err(io::Error::last_os_error())
+                .context("failed to connect to the service control manager, sccache --run-service is only run by it");
+        }
+        Ok(())
+    }
+
+    fn set_status(state: DWORD, exit_code: DWORD) {
+        let handle = STATUS_HANDLE.load(Ordering::SeqCst) as SERVICE_STATUS_HANDLE;
+        let mut status = SERVICE_STATUS {
+            dwServiceType: SERVICE_WIN32_OWN_PROCESS,
+            dwCurrentState: state,
+            dwControlsAccepted: if state == SERVICE_RUNNING {
+                SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN
+            } else {
+                0
+            },
+            dwWin32ExitCode: exit_code,
+            dwServiceSpecificExitCode: 0,
+            dwCheckPoint: 0,
+            // Running compilations are waited for when stopping.
+            dwWaitHint: if state == SERVICE_STOP_PENDING {
+                (crate::server::get_shutdown_timeout() as DWORD + 5) * 1000
+            } else {
+                0
+            },
+        };
+        unsafe {
+            SetServiceStatus(handle, &mut status);
+        }
+    }
+
+    unsafe extern "system" fn control_handler(
+        control: DWORD,
+        _event_type: DWORD,
+        _event_data: LPVOID,
+        _context: LPVOID,
+    ) -> DWORD {
+        match control {
+            SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+                info!("stopping the service");
+                set_status(SERVICE_STOP_PENDING, NO_ERROR);
+                if let Some(stop) = STOP.lock().unwrap().take() {
+                    let _ = stop.send(());
+                }
+                NO_ERROR
+            }
+            SERVICE_CONTROL_INTERROGATE => NO_ERROR,
+            _ => ERROR_CALL_NOT_IMPLEMENTED,
+        }
+    }
+
+    unsafe extern "system" fn service_main(_argc: DWORD, _argv: *mut LPWSTR) {
+        let name = wide(SERVICE_NAME);
+        let handle =
+            RegisterServiceCtrlHandlerExW(name.as_ptr(), Some(control_handler), ptr::null_mut());
+        if handle.is_null() {
+            error!(
+                "Failed to register the service control handler: {}",
+                io::Error::last_os_error()
+            );
+            return;
+        }
+        STATUS_HANDLE.store(handle as usize, Ordering::SeqCst);
+        set_status(SERVICE_START_PENDING, NO_ERROR);
+
+        let (stop, stopped) = oneshot::channel();
+        *STOP.lock().unwrap() = Some(stop);
+        let res = crate::commands::run_service_server(async {
+            set_status(SERVICE_RUNNING, NO_ERROR);
+            let _ = stopped.await;
+        });
+        let exit_code = match res {
+            Ok(()) => NO_ERROR,
+            Err(e) => {
+                error!("The service failed: {:?}", e);
+                // Which makes the service control manager restart it.
+                winapi::shared::winerror::ERROR_SERVICE_SPECIFIC_ERROR
+            }
+        };
+        set_status(SERVICE_STOPPED, exit_code);
+    }
+}
@@ -0,0 +1,297 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The metrics of each compilation pushed over UDP to StatsD, or DogStatsD,
+//! at `SCCACHE_STATSD_ADDR`, for the monitoring which isn't scraping the
+//! stats.
+//!
+//! Each compilation counts as `<prefix>.compile.<result>`, with the results of
+//! the result log, e.g. `sccache.compile.hit`, and times
+//! `<prefix>.compile.duration` and the phases it went through, e.g.
+//! `sccache.hash.duration`, in milliseconds. The tags of
+//! `SCCACHE_STATSD_TAGS`, `language,backend` by default, are appended in the
+//! DogStatsD format, and none are with an empty list, for plain StatsD.
+//!
+//! The metrics are sent by a thread, which packs them into datagrams. They
+//! are dropped rather than waited for when it falls behind, so that a slow or
+//! missing endpoint never holds up a compilation.
+
+use once_cell::sync::Lazy;
+use std::env;
+use std::fmt::Write as _;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::Duration;
+
+use crate::errors::*;
+use crate::result_log::CompileRecord;
+
+/// How many compilations may wait for their metrics to be sent.
+const QUEUE_LENGTH: usize = 1024;
+
+/// The largest datagram sent, which fits in the MTU of most networks.
+const MAX_DATAGRAM: usize = 1432;
+
+/// The StatsD client of the server, if `SCCACHE_STATSD_ADDR` is set.
+pub static STATSD: Lazy<Option<Statsd>> = Lazy::new(|| {
+    let addr = env::var("SCCACHE_STATSD_ADDR").ok()?;
+    let prefix = env::var("SCCACHE_STATSD_PREFIX").unwrap_or_else(|_| "sccache".to_owned());
+    let tags = env::var("SCCACHE_STATSD_TAGS").unwrap_or_else(|_| "language,backend".to_owned());
+    match Statsd::new(&addr, &prefix, &tags) {
+        Ok(statsd) => Some(statsd),
+        Err(e) => {
+            warn!("Not sending metrics to StatsD: {:#}", e);
+            None
+        }
+    }
+});
+
+/// A tag appended to the metrics.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Tag {
+    Language,
+    Compiler,
+    Backend,
+    /// A `name:value` of the configuration, the same for all compilations.
+    Constant(String),
+}
+
+fn parse_tags(tags: &str) -> Result<Vec<Tag>> {
+    tags.split(',')
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .map(|tag| match tag {
+            "language" => Ok(Tag::Language),
+            "compiler" => Ok(Tag::Compiler),
+            "backend" => Ok(Tag::Backend),
+            tag if tag.contains(':') => Ok(Tag::Constant(tag.to_owned())),
+            tag => bail!(
+                "unknown tag {:?}, expected language, compiler, backend or name:value",
+                tag
+            ),
+        })
+        .collect()
+}
+
+/// Make a tag value of `value`, which DogStatsD doesn't allow `,`, `|` and
+/// `#` in.
+fn tag_value(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            ',' | '|' | '#' => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// Format the metrics of the compilation `record`, which took `duration`,
+/// one per line.
+fn format_metrics(
+    prefix: &str,
+    tags: &[Tag],
+    record: &CompileRecord,
+    duration: Duration,
+) -> String {
+    let mut suffix = String::new();
+    for (i, tag) in tags.iter().enumerate() {
+        suffix.push_str(if i == 0 { "|#" } else { "," });
+        match tag {
+            Tag::Language => write!(suffix, "language:{}", tag_value(&record.language)),
+            Tag::Compiler => write!(suffix, "compiler:{}", tag_value(&record.compiler)),
+            Tag::Backend => write!(suffix, "backend:{}", tag_value(&record.backend)),
+            Tag::Constant(tag) => write!(suffix, "{}", tag_value(tag)),
+        }
+        .unwrap();
+    }
+    let result = if record.result.is_empty() {
+        "unknown"
+    } else {
+        &record.result
+    };
+    let mut metrics = format!("{}.compile.{}:1|c{}\n", prefix, result, suffix);
+    let ms = |secs: f64| secs * 1000.0;
+    for (name, secs) in [
+        ("compile.duration", Some(duration.as_secs_f64())),
+        ("hash.duration", record.hash_secs),
+        ("lookup.duration", record.lookup_secs),
+        ("compiler.duration", record.compile_secs),
+        ("write.duration", record.write_secs),
+    ] {
+        if let Some(secs) = secs {
+            writeln!(metrics, "{}.{}:{:.3}|ms{}", prefix, name, ms(secs), suffix).unwrap();
+        }
+    }
+    metrics
+}
+
+/// Where the metrics are sent.
+pub struct Statsd {
+    prefix: String,
+    tags: Vec<Tag>,
+    sender: SyncSender<String>,
+    dropped: AtomicU64,
+}
+
+impl Statsd {
+    /// Send the metrics to `addr`, a `host:port`, named under `prefix`, with
+    /// the comma-separated `tags`.
+    pub fn new(addr: &str, prefix: &str, tags: &str) -> Result<Statsd> {
+        let tags = parse_tags(tags).context("invalid SCCACHE_STATSD_TAGS")?;
+        let addr = addr
+            .to_socket_addrs()
+            .with_context(|| format!("failed to resolve {:?}", addr))?
+            .next()
+            .with_context(|| format!("{:?} resolved to no address", addr))?;
+        let local: SocketAddr = if addr.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        let socket = UdpSocket::bind(local).context("failed to bind a UDP socket")?;
+        socket
+            .connect(addr)
+            .with_context(|| format!("failed to connect to {}", addr))?;
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_LENGTH);
+        thread::Builder::new()
+            .name("sccache-statsd".to_owned())
+            .spawn(move || send_metrics(socket, receiver))
+            .context("failed to start the StatsD thread")?;
+        Ok(Statsd {
+            prefix: prefix.trim_end_matches('.').to_owned(),
+            tags,
+            sender,
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// Send the metrics of the compilation `record`, which took `duration`,
+    /// unless too many are waiting to be sent already.
+    pub fn emit(&self, record: &CompileRecord, duration: Duration) {
+        let metrics = format_metrics(&self.prefix, &self.tags, record, duration);
+        match self.sender.try_send(metrics) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                if dropped.is_power_of_two() {
+                    warn!(
+                        "Dropped the metrics of {} compilations, StatsD is behind",
+                        dropped
+                    );
+                }
+            }
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
+
+/// Send the metrics `receiver` gets as they come, as many per datagram as fit.
+fn send_metrics(socket: UdpSocket, receiver: Receiver<String>) {
+    let mut datagram = String::with_capacity(MAX_DATAGRAM);
+    while let Ok(metrics) = receiver.recv() {
+        let mut next = Some(metrics);
+        while let Some(metrics) = next.take() {
+            for line in metrics.lines() {
+                if !datagram.is_empty() && datagram.len() + 1 + line.len() > MAX_DATAGRAM {
+                    send(&socket, &datagram);
+                    datagram.clear();
+                }
+                if !datagram.is_empty() {
+                    datagram.push('\n');
+                }
+                datagram.push_str(line);
+            }
+            next = receiver.try_recv().ok();
+        }
+        send(&socket, &datagram);
+        datagram.clear();
+    }
+}
+
+fn send(socket: &UdpSocket, datagram: &str) {
+    // Nobody listening is only known from the error of a later send, and
+    // doesn't matter to the compilations.
+    if let Err(e) = socket.send(datagram.as_bytes()) {
+        trace!("Failed to send metrics to StatsD: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record() -> CompileRecord {
+        CompileRecord {
+            compiler: "c [gcc]".into(),
+            language: "c".into(),
+            result: "hit".into(),
+            backend: "S3, bucket: b".into(),
+            hash_secs: Some(0.012),
+            lookup_secs: Some(0.1),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_format_metrics() {
+        let tags = parse_tags("language, backend,team:infra").unwrap();
+        assert_eq!(
+            format_metrics("sccache", &tags, &record(), Duration::from_millis(250)),
+            "sccache.compile.hit:1|c|#language:c,backend:S3_ bucket: b,team:infra\n\
+             sccache.compile.duration:250.000|ms|#language:c,backend:S3_ bucket: b,team:infra\n\
+             sccache.hash.duration:12.000|ms|#language:c,backend:S3_ bucket: b,team:infra\n\
+             sccache.lookup.duration:100.000|ms|#language:c,backend:S3_ bucket: b,team:infra\n"
+        );
+        // Without tags, for plain StatsD.
+        assert_eq!(
+            format_metrics("ci", &[], &record(), Duration::from_millis(1)),
+            "ci.compile.hit:1|c\n\
+             ci.compile.duration:1.000|ms\n\
+             ci.hash.duration:12.000|ms\n\
+             ci.lookup.duration:100.000|ms\n"
+        );
+        assert_eq!(parse_tags("").unwrap(), vec![]);
+        assert_eq!(parse_tags("compiler").unwrap(), vec![Tag::Compiler]);
+        assert!(parse_tags("language,host").is_err());
+    }
+
+    #[test]
+    fn test_statsd() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+        let statsd = Statsd::new(&addr, "sccache.", "language").unwrap();
+        statsd.emit(&record(), Duration::from_millis(5));
+        let mut buf = [0; MAX_DATAGRAM];
+        let len = server.recv(&mut buf).unwrap();
+        let datagram = std::str::from_utf8(&buf[..len]).unwrap();
+        assert!(
+            datagram.starts_with("sccache.compile.hit:1|c|#language:c\n"),
+            "{}",
+            datagram
+        );
+        assert!(datagram.contains("sccache.compile.duration:5.000|ms|#language:c"));
+
+        // Nobody listening doesn't hold up the compilations.
+        drop(server);
+        for _ in 0..QUEUE_LENGTH * 2 {
+            statsd.emit(&record(), Duration::from_millis(5));
+        }
+
+        assert!(Statsd::new(&addr, "sccache", "host").is_err());
+        assert!(Statsd::new("no port", "sccache", "").is_err());
+    }
+}
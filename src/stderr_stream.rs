@@ -0,0 +1,51 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The stderr of the compilers, which the server streams to the clients as
+//! the compilers write it, so that the diagnostics of a long compilation show
+//! up as they come rather than once it finishes. The whole stderr is still
+//! kept, to be cached, and the cache hits send it at once.
+//!
+//! The chunks go through a channel of one chunk, then through the connection
+//! of the client: a slow client fills them, which stops reading the stderr of
+//! the compiler until it catches up, rather than buffering it in the server.
+
+use std::future::Future;
+
+use tokio::sync::mpsc;
+
+tokio::task_local! {
+    static SINK: Sender;
+}
+
+/// Where the chunks of stderr of a compilation are sent.
+pub type Sender = mpsc::Sender<Vec<u8>>;
+
+/// Where the chunks of stderr of a compilation are received.
+pub type Receiver = mpsc::Receiver<Vec<u8>>;
+
+/// Make the channel the stderr of a compilation is streamed through.
+pub fn channel() -> (Sender, Receiver) {
+    mpsc::channel(1)
+}
+
+/// Run `task`, streaming the stderr of the compiler it runs to `sink`. The
+/// stream ends once `task` finishes or is dropped.
+pub async fn scope<F: Future>(sink: Sender, task: F) -> F::Output {
+    SINK.scope(sink, task).await
+}
+
+/// Get where to stream the stderr of the compiler the current task runs, if
+/// anywhere.
+pub fn of_task() -> Option<Sender> {
+    SINK.try_with(Clone::clone).ok()
+}
@@ -0,0 +1,214 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+/// A request received by a `MockBucket`.
+pub struct Received {
+    pub method: String,
+    pub path: String,
+    /// The headers, by their lowercase names.
+    pub headers: HashMap<String, String>,
+}
+
+/// A bucket of objects over HTTP/1.1, storing the body of a `PUT` at its
+/// path, answering a `GET` or a `HEAD` with it and removing it on a `DELETE`,
+/// recording the requests. A `GET` with `list-type=2` lists the objects under
+/// its path whose keys start with its `prefix`, as `ListObjectsV2` does, in
+/// a single page.
+///
+/// The `GET`s may read a `Range: bytes=<first>-<last>` of the object, and
+/// the objects have an ETag, their length, which `If-Match` is checked
+/// against. The responses have a `Date`, and the requests signed more than 15
+/// minutes away from it are rejected, as S3 does.
+pub struct MockBucket {
+    /// The URL of the server, without a trailing `/`.
+    pub endpoint: String,
+    objects: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+    received: Arc<Mutex<Vec<Received>>>,
+}
+
+impl MockBucket {
+    pub async fn start() -> MockBucket {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let bucket = MockBucket {
+            endpoint: format!("http://{}", listener.local_addr().unwrap()),
+            objects: Arc::new(Mutex::new(HashMap::new())),
+            received: Arc::new(Mutex::new(vec![])),
+        };
+        let (objects, received) = (bucket.objects.clone(), bucket.received.clone());
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (objects, received) = (objects.clone(), received.clone());
+                tokio::spawn(serve(stream, objects, received));
+            }
+        });
+        bucket
+    }
+
+    /// Store `object` at `path`, e.g. `/bucket/key`.
+    pub fn insert(&self, path: &str, object: &[u8]) {
+        self.objects
+            .lock()
+            .unwrap()
+            .insert(path.to_owned(), object.to_vec());
+    }
+
+    /// Take the requests received so far.
+    pub fn requests(&self) -> Vec<Received> {
+        std::mem::take(&mut self.received.lock().unwrap())
+    }
+}
+
+async fn serve(
+    stream: tokio::net::TcpStream,
+    objects: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+    received: Arc<Mutex<Vec<Received>>>,
+) {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await.unwrap_or(0) == 0 {
+            return;
+        }
+        let mut parts = line.split_whitespace();
+        let method = parts.next().unwrap().to_owned();
+        let path = parts.next().unwrap().to_owned();
+        let mut headers = HashMap::new();
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).await.unwrap();
+            let Some((name, value)) = header.trim_end().split_once(": ") else {
+                break;
+            };
+            headers.insert(name.to_ascii_lowercase(), value.to_owned());
+        }
+        let length = headers
+            .get("content-length")
+            .map_or(0, |length| length.parse().unwrap());
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).await.unwrap();
+        let now = chrono::Utc::now();
+        let mut extra = format!("date: {}\r\n", now.format("%a, %d %b %Y %H:%M:%S GMT"));
+        let skewed = headers.get("x-amz-date").is_some_and(|date| {
+            chrono::NaiveDateTime::parse_from_str(date, "%Y%m%dT%H%M%SZ").map_or(true, |date| {
+                (now.naive_utc() - date).num_minutes().abs() > 15
+            })
+        });
+        let (base, query) = path.split_once('?').unwrap_or((&path, ""));
+        let query: HashMap<_, _> = query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(name, value)| (name, decode(value)))
+            .collect();
+        let (status, body) = match method.as_str() {
+            _ if skewed => (403, b"RequestTimeTooSkewed".to_vec()),
+            "GET" if query.get("list-type").is_some_and(|t| t == "2") => {
+                let prefix = query.get("prefix").cloned().unwrap_or_default();
+                let mut list = String::from("<ListBucketResult><IsTruncated>false</IsTruncated>");
+                let objects = objects.lock().unwrap();
+                let mut keys: Vec<_> = objects
+                    .iter()
+                    .filter_map(|(path, object)| {
+                        let key = path.strip_prefix(base)?;
+                        key.starts_with(&prefix).then_some((key, object.len()))
+                    })
+                    .collect();
+                keys.sort_unstable();
+                for (key, size) in keys {
+                    list.push_str(&format!(
+                        "<Contents><Key>{}</Key><Size>{}</Size>\
+                         <LastModified>2023-11-14T22:13:20.000Z</LastModified></Contents>",
+                        key, size
+                    ));
+                }
+                list.push_str("</ListBucketResult>");
+                (200, list.into_bytes())
+            }
+            "DELETE" => {
+                objects.lock().unwrap().remove(&path);
+                (204, vec![])
+            }
+            "PUT" => {
+                objects.lock().unwrap().insert(path.clone(), body);
+                (200, vec![])
+            }
+            "GET" | "HEAD" => match objects.lock().unwrap().get(&path) {
+                Some(object) => {
+                    let etag = format!("\"{}\"", object.len());
+                    extra.push_str(&format!("etag: {}\r\n", etag));
+                    let range = headers.get("range").and_then(|range| {
+                        let (first, last) = range.strip_prefix("bytes=")?.split_once('-')?;
+                        Some((first.parse::<usize>().ok()?, last.parse::<usize>().ok()?))
+                    });
+                    match range {
+                        _ if headers.get("if-match").is_some_and(|tag| *tag != etag) => {
+                            (412, vec![])
+                        }
+                        Some((first, last)) if first <= last && first < object.len() => {
+                            let last = last.min(object.len() - 1);
+                            extra.push_str(&format!(
+                                "content-range: bytes {}-{}/{}\r\n",
+                                first,
+                                last,
+                                object.len()
+                            ));
+                            (206, object[first..=last].to_vec())
+                        }
+                        Some(_) => (416, vec![]),
+                        None => (200, object.clone()),
+                    }
+                }
+                None => (404, vec![]),
+            },
+            _ => (405, vec![]),
+        };
+        let head = format!(
+            "HTTP/1.1 {} Mock\r\ncontent-length: {}\r\n{}\r\n",
+            status,
+            body.len(),
+            extra
+        );
+        let head_only = method == "HEAD";
+        received.lock().unwrap().push(Received {
+            method,
+            path,
+            headers,
+        });
+        writer.write_all(head.as_bytes()).await.unwrap();
+        if !head_only {
+            writer.write_all(&body).await.unwrap();
+        }
+    }
+}
+
+/// Decode the `%XX` of the value of a query.
+fn decode(value: &str) -> String {
+    let mut decoded = vec![];
+    let mut bytes = value.bytes();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'%' => {
+                let hex: String = bytes.by_ref().take(2).map(char::from).collect();
+                decoded.push(u8::from_str_radix(&hex, 16).unwrap());
+            }
+            b'+' => decoded.push(b' '),
+            byte => decoded.push(byte),
+        }
+    }
+    String::from_utf8(decoded).unwrap()
+}
@@ -81,3 +81,38 @@ impl Storage for MockStorage {
         }
     }
 }
+
+/// A storage writing to another one `delay` late, like a slow remote cache.
+pub struct SlowWrites {
+    storage: Arc<dyn Storage>,
+    delay: Duration,
+}
+
+impl SlowWrites {
+    pub(crate) fn new(storage: Arc<dyn Storage>, delay: Duration) -> SlowWrites {
+        SlowWrites { storage, delay }
+    }
+}
+
+#[async_trait]
+impl Storage for SlowWrites {
+    async fn get(&self, key: &str) -> Result<Cache> {
+        self.storage.get(key).await
+    }
+    async fn put(&self, key: &str, entry: CacheWrite) -> Result<Duration> {
+        sleep(self.delay).await;
+        self.storage.put(key, entry).await
+    }
+    fn location(&self) -> String {
+        self.storage.location()
+    }
+    async fn current_size(&self) -> Result<Option<u64>> {
+        self.storage.current_size().await
+    }
+    async fn max_size(&self) -> Result<Option<u64>> {
+        self.storage.max_size().await
+    }
+    fn preprocessor_cache_mode_config(&self) -> PreprocessorCacheModeConfig {
+        self.storage.preprocessor_cache_mode_config()
+    }
+}
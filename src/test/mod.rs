@@ -12,6 +12,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#[cfg(any(feature = "gcs", feature = "s3"))]
+pub mod mock_bucket;
 pub mod mock_storage;
 #[macro_use]
 pub mod utils;
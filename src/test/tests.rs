@@ -12,38 +12,87 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::admin::AdminAddr;
 use crate::cache::disk::DiskCache;
-use crate::cache::{CacheMode, PreprocessorCacheModeConfig};
-use crate::client::connect_to_server;
-use crate::commands::{do_compile, request_shutdown, request_stats};
+use crate::cache::overrides::{CacheOverrides, CACHE_OVERRIDE_ENV_VAR};
+use crate::cache::{CacheMode, PreprocessorCacheModeConfig, Storage};
+use crate::client::{connect_to_server, query_stats, ServerConnection, PROTOCOL_VERSION};
+use crate::clock::FakeClock;
+use crate::commands::{
+    do_compile, do_explain, request_flush, request_list_entries, request_remove_entry,
+    request_shutdown, request_stats,
+};
+use crate::compiler::{CompilerPathMap, CompilerWrapper, Language};
+use crate::errors::SpawnError;
 use crate::jobserver::Client;
 use crate::mock_command::*;
-use crate::server::{DistClientContainer, SccacheServer, ServerMessage};
+use crate::protocol::{Compile, CompileResponse, FlushSummary, Request, Response};
+use crate::selftest;
+use crate::server::{
+    DistClientContainer, LanguageFilter, SccacheRequest, SccacheServer, ServerInfo, ServerMessage,
+};
+use crate::test::mock_storage::SlowWrites;
 use crate::test::utils::*;
+use crate::util;
 use fs::File;
 use fs_err as fs;
 use futures::channel::oneshot::{self, Sender};
 #[cfg(not(target_os = "macos"))]
 use serial_test::serial;
-use std::io::{Cursor, Write};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io::{self, Cursor, Write};
 #[cfg(not(target_os = "macos"))]
 use std::net::TcpListener;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 #[cfg(not(target_os = "macos"))]
 use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 use std::u64;
 use tokio::runtime::Runtime;
+use tower::util::MapRequestLayer;
 
 /// Options for running the server in tests.
 #[derive(Default)]
 struct ServerOptions {
     /// The server's idle shutdown timeout.
     idle_timeout: Option<u64>,
+    /// The clock timing the idle shutdown.
+    clock: Option<Arc<FakeClock>>,
     /// The maximum size of the disk cache.
     cache_size: Option<u64>,
+    /// How late the disk cache is written to.
+    write_delay: Option<Duration>,
+    /// How long to wait for running compilations when shutting down.
+    shutdown_timeout: Option<u64>,
+    /// How many compilations run at once.
+    max_jobs: Option<usize>,
+    /// A compiler wrapper the server sees through.
+    compiler_wrapper: Option<CompilerWrapper>,
+    /// The compilers the server runs for those of the clients.
+    compiler_map: Option<CompilerPathMap>,
+    /// The token the clients must authenticate with.
+    token: Option<&'static str>,
+    /// The admin socket, which takes the admin requests only.
+    admin: Option<AdminAddr>,
+    /// Which languages are cached.
+    languages: Option<LanguageFilter>,
+    /// Whether the health probes are closed quietly.
+    allow_probes: bool,
+    /// The URL of a cache the compilations may choose, and the directory of
+    /// the disk cache standing for it.
+    cache_override: Option<(&'static str, PathBuf)>,
+    /// A compiler detected when the server starts, with the output of its
+    /// detection.
+    prewarm: Option<(PathBuf, &'static str)>,
+    /// Counts the requests the service gets, through a layer.
+    request_count: Option<Arc<AtomicUsize>>,
+    /// Keeps the environment of the compile requests the service gets,
+    /// through a layer.
+    compile_env: Option<Arc<Mutex<Vec<(OsString, OsString)>>>>,
 }
 
 /// Run a server on a background thread, and return a tuple of useful things.
@@ -80,13 +129,29 @@ where
     let handle = thread::spawn(move || {
         let runtime = Runtime::new().unwrap();
         let dist_client = DistClientContainer::new_disabled();
-        let storage = Arc::new(DiskCache::new(
+        let mut storage: Arc<dyn Storage> = Arc::new(DiskCache::new(
             &cache_dir,
             cache_size,
             runtime.handle(),
             PreprocessorCacheModeConfig::default(),
             CacheMode::ReadWrite,
         ));
+        if let Some(delay) = options.as_ref().and_then(|o| o.write_delay) {
+            storage = Arc::new(SlowWrites::new(storage, delay));
+        }
+        let cache_overrides = options
+            .as_ref()
+            .and_then(|o| o.cache_override.as_ref())
+            .map(|(url, dir)| {
+                let storage: Arc<dyn Storage> = Arc::new(DiskCache::new(
+                    dir,
+                    u64::MAX,
+                    runtime.handle(),
+                    PreprocessorCacheModeConfig::default(),
+                    CacheMode::ReadWrite,
+                ));
+                CacheOverrides::new(HashMap::from([(url.to_string(), storage)]))
+            });
 
         let client = unsafe { Client::new() };
         let srv = SccacheServer::new(0, runtime, client, dist_client, storage).unwrap();
@@ -96,6 +161,54 @@ where
             if let Some(timeout) = options.idle_timeout {
                 srv.set_idle_timeout(Duration::from_millis(timeout));
             }
+            if let Some(clock) = options.clock {
+                srv.set_clock(clock);
+            }
+            if let Some(timeout) = options.shutdown_timeout {
+                srv.set_shutdown_timeout(Duration::from_millis(timeout));
+            }
+            if let Some(max_jobs) = options.max_jobs {
+                srv.set_max_jobs(max_jobs, 4);
+            }
+            srv.set_compiler_wrappers(options.compiler_wrapper);
+            if let Some(compiler_map) = options.compiler_map {
+                srv.set_compiler_map(compiler_map);
+            }
+            srv.set_token(options.token);
+            if let Some(admin) = options.admin {
+                srv.listen_admin(&admin).unwrap();
+            }
+            srv.set_allow_probes(options.allow_probes);
+            if let Some(cache_overrides) = cache_overrides {
+                srv.set_cache_overrides(cache_overrides);
+            }
+            if let Some(languages) = options.languages {
+                srv.set_languages(languages);
+            }
+            if let Some((compiler, detection)) = options.prewarm {
+                srv.command_creator()
+                    .lock()
+                    .unwrap()
+                    .next_command_spawns(Ok(MockChild::new(exit_status(0), detection, "")));
+                srv.prewarm(vec![compiler]);
+            }
+            if let Some(count) = options.request_count {
+                srv.layer(MapRequestLayer::new(move |request| {
+                    count.fetch_add(1, Ordering::SeqCst);
+                    request
+                }));
+            }
+            if let Some(compile_env) = options.compile_env {
+                srv.layer(MapRequestLayer::new(move |request: SccacheRequest| {
+                    if let Request::Compile(compile) = request.get_ref() {
+                        compile_env
+                            .lock()
+                            .unwrap()
+                            .extend(compile.env_vars.iter().cloned());
+                    }
+                    request
+                }));
+            }
         }
         let port = srv.port();
         let creator = srv.command_creator().clone();
@@ -118,6 +231,36 @@ fn test_server_shutdown() {
     child.join().unwrap();
 }
 
+#[test]
+fn test_server_layer() {
+    let f = TestFixture::new();
+    let count = Arc::new(AtomicUsize::new(0));
+    let (port, _sender, _storage, child) = run_server_thread(
+        f.tempdir.path(),
+        ServerOptions {
+            token: Some("secret"),
+            request_count: Some(count.clone()),
+            ..Default::default()
+        },
+    );
+    // The requests rejected for lack of the token don't get to the service.
+    let mut conn = connect_to_server(port).unwrap();
+    assert!(conn.request(Request::GetStats).is_err());
+    assert_eq!(0, count.load(Ordering::SeqCst));
+
+    let mut conn = connect_to_server(port).unwrap();
+    conn.authenticate("secret").unwrap();
+    for _ in 0..2 {
+        assert!(matches!(
+            conn.request(Request::GetStats).unwrap(),
+            Response::Stats(_)
+        ));
+    }
+    assert_eq!(2, count.load(Ordering::SeqCst));
+    request_shutdown(conn).unwrap();
+    child.join().unwrap();
+}
+
 /// The server will shutdown when requested when the idle timeout is disabled.
 #[test]
 fn test_server_shutdown_no_idle() {
@@ -136,6 +279,242 @@ fn test_server_shutdown_no_idle() {
     child.join().unwrap();
 }
 
+#[test]
+fn test_server_jobs_leave_cache_hits() {
+    let f = TestFixture::new();
+    let gcc = f.mk_bin("gcc").unwrap();
+    let (port, _sender, server_creator, child) = run_server_thread(
+        &f.tempdir.path().join("cache"),
+        ServerOptions {
+            max_jobs: Some(1),
+            ..Default::default()
+        },
+    );
+    let compile = |name: &str| {
+        let conn = connect_to_server(port).unwrap();
+        let (gcc, cwd, path) = (gcc.clone(), f.tempdir.path().to_path_buf(), f.paths.clone());
+        let cmdline = vec!["-c".into(), format!("{}.c", name).into()];
+        thread::spawn(move || {
+            do_compile(
+                new_creator(),
+                &mut Runtime::new().unwrap(),
+                conn,
+                &gcc,
+                cmdline,
+                &cwd,
+                Some(path),
+                vec![],
+                &mut io::empty(),
+                &mut Cursor::new(Vec::new()),
+                &mut Cursor::new(Vec::new()),
+            )
+            .unwrap()
+        })
+    };
+    for name in ["file", "other"] {
+        std::fs::write(f.tempdir.path().join(format!("{}.c", name)), name).unwrap();
+    }
+    {
+        let mut c = server_creator.lock().unwrap();
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")));
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "other preprocessed", "")));
+        let obj = f.tempdir.path().join("other.o");
+        c.next_command_calls(move |_| {
+            std::fs::write(&obj, b"other contents")?;
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+    }
+    assert_eq!(0, compile("other").join().unwrap());
+    request_flush(connect_to_server(port).unwrap()).unwrap();
+
+    // A compiler holds the only job...
+    let (started_tx, started_rx) = mpsc::channel();
+    {
+        let mut c = server_creator.lock().unwrap();
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "file preprocessed", "")));
+        let obj = f.tempdir.path().join("file.o");
+        c.next_command_calls(move |_| {
+            started_tx.send(()).unwrap();
+            tokio::task::block_in_place(|| thread::sleep(Duration::from_secs(1)));
+            std::fs::write(&obj, b"file contents")?;
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+    }
+    let slow = compile("file");
+    started_rx.recv().unwrap();
+
+    // ...which the cache hits don't wait for.
+    server_creator
+        .lock()
+        .unwrap()
+        .next_command_spawns(Ok(MockChild::new(exit_status(0), "other preprocessed", "")));
+    assert_eq!(0, compile("other").join().unwrap());
+    assert!(!slow.is_finished());
+    let info = query_stats(("127.0.0.1", port)).unwrap();
+    assert_eq!(1, info.stats.cache_hits.all());
+
+    assert_eq!(0, slow.join().unwrap());
+    request_shutdown(connect_to_server(port).unwrap()).unwrap();
+    child.join().unwrap();
+}
+
+/// Start a compilation that takes `duration` on the server, and return once
+/// the compiler is running.
+fn start_slow_compile(
+    f: &TestFixture,
+    port: u16,
+    server_creator: &Arc<Mutex<MockCommandCreator>>,
+    duration: Duration,
+) -> thread::JoinHandle<i32> {
+    let gcc = f.mk_bin("gcc").unwrap();
+    std::fs::write(f.tempdir.path().join("file.c"), "whatever").unwrap();
+    let (started_tx, started_rx) = mpsc::channel();
+    {
+        let mut c = server_creator.lock().unwrap();
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")));
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "preprocessed", "")));
+        let obj = f.tempdir.path().join("file.o");
+        c.next_command_calls(move |_| {
+            started_tx.send(()).unwrap();
+            // Let the other tasks of the server run in the meantime.
+            tokio::task::block_in_place(|| thread::sleep(duration));
+            std::fs::write(&obj, b"file contents")?;
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+    }
+    let conn = connect_to_server(port).unwrap();
+    let cwd = f.tempdir.path().to_path_buf();
+    let path = Some(f.paths.clone());
+    let compile = thread::spawn(move || {
+        let cmdline = vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()];
+        do_compile(
+            new_creator(),
+            &mut Runtime::new().unwrap(),
+            conn,
+            &gcc,
+            cmdline,
+            &cwd,
+            path,
+            vec![],
+            &mut io::empty(),
+            &mut Cursor::new(Vec::new()),
+            &mut Cursor::new(Vec::new()),
+        )
+        .unwrap()
+    });
+    started_rx.recv().unwrap();
+    compile
+}
+
+#[test]
+fn test_server_shutdown_waits_for_compiles() {
+    let f = TestFixture::new();
+    let (port, _sender, server_creator, child) = run_server_thread(f.tempdir.path(), None);
+    let compile = start_slow_compile(&f, port, &server_creator, Duration::from_millis(500));
+
+    let conn = connect_to_server(port).unwrap();
+    let (info, abandoned) = request_shutdown(conn).unwrap();
+    // The compilation finished before the server replied.
+    assert_eq!(0, abandoned);
+    assert_eq!(1, info.stats.cache_misses.all());
+    assert_eq!(0, compile.join().unwrap());
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_shutdown_abandons_compiles() {
+    let f = TestFixture::new();
+    let (port, _sender, server_creator, child) = run_server_thread(
+        f.tempdir.path(),
+        ServerOptions {
+            shutdown_timeout: Some(0),
+            ..Default::default()
+        },
+    );
+    let compile = start_slow_compile(&f, port, &server_creator, Duration::from_millis(500));
+
+    let conn = connect_to_server(port).unwrap();
+    let (info, abandoned) = request_shutdown(conn).unwrap();
+    assert_eq!(1, abandoned);
+    assert_eq!(0, info.stats.cache_misses.all());
+    child.join().unwrap();
+    // The client may or may not get the result of the abandoned compilation.
+    let _ = compile.join();
+}
+
+#[test]
+fn test_server_queues_compiles() {
+    let f = TestFixture::new();
+    let (port, _sender, server_creator, child) = run_server_thread(
+        f.tempdir.path(),
+        ServerOptions {
+            max_jobs: Some(1),
+            ..Default::default()
+        },
+    );
+    let first = start_slow_compile(&f, port, &server_creator, Duration::from_millis(500));
+
+    // The second compilation waits for the first one to finish.
+    std::fs::write(f.tempdir.path().join("other.c"), "whatever").unwrap();
+    {
+        let mut c = server_creator.lock().unwrap();
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "other preprocessed", "")));
+        let obj = f.tempdir.path().join("other.o");
+        c.next_command_calls(move |_| {
+            std::fs::write(&obj, b"file contents")?;
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+    }
+    let conn = connect_to_server(port).unwrap();
+    let gcc = f.tempdir.path().join("gcc");
+    let cwd = f.tempdir.path().to_path_buf();
+    let path = Some(f.paths.clone());
+    let second = thread::spawn(move || {
+        let cmdline = vec!["-c".into(), "other.c".into(), "-o".into(), "other.o".into()];
+        do_compile(
+            new_creator(),
+            &mut Runtime::new().unwrap(),
+            conn,
+            &gcc,
+            cmdline,
+            &cwd,
+            path,
+            vec![],
+            &mut io::empty(),
+            &mut Cursor::new(Vec::new()),
+            &mut Cursor::new(Vec::new()),
+        )
+        .unwrap()
+    });
+    let queued = (0..50)
+        .map(|_| {
+            thread::sleep(Duration::from_millis(5));
+            query_stats(("127.0.0.1", port)).unwrap().queued_compiles
+        })
+        .find(|&queued| queued == Some(1));
+    assert_eq!(Some(Some(1)), queued);
+
+    assert_eq!(0, first.join().unwrap());
+    assert_eq!(0, second.join().unwrap());
+    let info = query_stats(("127.0.0.1", port)).unwrap();
+    assert_eq!(Some(0), info.queued_compiles);
+    assert_eq!(2, info.stats.cache_misses.all());
+    // The compact encoding of the stats decodes to the same, only with the
+    // same version.
+    let mut bytes = info.to_bincode().unwrap();
+    let decoded = ServerInfo::from_bincode(&bytes).unwrap();
+    assert_eq!(
+        serde_json::to_value(&info).unwrap(),
+        serde_json::to_value(&decoded).unwrap()
+    );
+    bytes[..4].copy_from_slice(&(PROTOCOL_VERSION + 1).to_le_bytes());
+    assert!(ServerInfo::from_bincode(&bytes).is_err());
+    assert!(ServerInfo::from_bincode(&bytes[..2]).is_err());
+    let conn = connect_to_server(port).unwrap();
+    request_shutdown(conn).unwrap();
+    child.join().unwrap();
+}
+
 #[test]
 fn test_server_idle_timeout() {
     let f = TestFixture::new();
@@ -154,6 +533,33 @@ fn test_server_idle_timeout() {
     child.join().unwrap();
 }
 
+#[test]
+fn test_server_idle_timeout_reset() {
+    let f = TestFixture::new();
+    let clock = FakeClock::new();
+    let (port, _sender, _storage, child) = run_server_thread(
+        f.tempdir.path(),
+        ServerOptions {
+            idle_timeout: Some(60_000),
+            clock: Some(clock.clone()),
+            ..Default::default()
+        },
+    );
+    let running = |child: &thread::JoinHandle<()>| {
+        // Leave the server the time to shut down, if it were to.
+        thread::sleep(Duration::from_millis(100));
+        !child.is_finished()
+    };
+    clock.advance(Duration::from_secs(30));
+    assert!(running(&child));
+    // A request restarts the timeout.
+    request_stats(connect_to_server(port).unwrap()).unwrap();
+    clock.advance(Duration::from_secs(45));
+    assert!(running(&child));
+    clock.advance(Duration::from_secs(60));
+    child.join().unwrap();
+}
+
 #[test]
 fn test_server_stats() {
     let f = TestFixture::new();
@@ -171,6 +577,161 @@ fn test_server_stats() {
     child.join().unwrap();
 }
 
+#[test]
+fn test_server_query_stats() {
+    let f = TestFixture::new();
+    let (port, sender, _storage, child) = run_server_thread(f.tempdir.path(), None);
+    let info = query_stats(("127.0.0.1", port)).unwrap();
+    assert_eq!(0, info.stats.compile_requests);
+    assert_eq!(PROTOCOL_VERSION, info.protocol_version);
+    // The statistics can be re-emitted as they are printed by sccache.
+    let json = serde_json::to_value(&info).unwrap();
+    assert_eq!(json["protocol_version"], PROTOCOL_VERSION);
+    assert_eq!(json["stats"]["compile_requests"], 0);
+    // Or in the Prometheus text format.
+    let metrics = info.to_prometheus();
+    assert!(
+        metrics.contains("# TYPE sccache_cache_hits_total counter\nsccache_cache_hits_total 0\n")
+    );
+    assert!(metrics.contains("sccache_compile_requests_total 0\n"));
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_async_connection() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let f = TestFixture::new();
+    let (port, sender, _storage, child) = run_server_thread(f.tempdir.path(), None);
+    let conn = connect_to_server(port).unwrap();
+    let runtime = Runtime::new().unwrap();
+    runtime.block_on(async {
+        let mut conn = conn.into_async();
+        let request = bincode::serialize(&Request::GetStats).unwrap();
+        conn.write_u32(request.len() as u32).await.unwrap();
+        conn.write_all(&request).await.unwrap();
+        let len = conn.read_u32().await.unwrap();
+        let mut response = vec![0; len as usize];
+        conn.read_exact(&mut response).await.unwrap();
+        match bincode::deserialize(&response).unwrap() {
+            Response::Stats(info) => assert_eq!(0, info.stats.compile_requests),
+            r => panic!("Unexpected response {:?}", r),
+        }
+        // The server closes the connection once it has seen its end.
+        conn.shutdown().await.unwrap();
+        let mut rest = vec![];
+        conn.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    });
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_health_probes() {
+    let f = TestFixture::new();
+    let options = ServerOptions {
+        allow_probes: true,
+        ..Default::default()
+    };
+    let (port, sender, _storage, child) = run_server_thread(f.tempdir.path(), options);
+    // A bare connect and close, and a probe which is no request.
+    drop(std::net::TcpStream::connect(("127.0.0.1", port)).unwrap());
+    let mut probe = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+    probe.write_all(b"PING\r\n").unwrap();
+    probe.shutdown(std::net::Shutdown::Write).unwrap();
+    let mut response = vec![];
+    std::io::Read::read_to_end(&mut probe, &mut response).unwrap();
+    assert!(response.is_empty());
+    // The server serves the clients still.
+    let info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    assert_eq!(0, info.stats.compile_requests);
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_token() {
+    let f = TestFixture::new();
+    let options = ServerOptions {
+        token: Some("secret"),
+        ..Default::default()
+    };
+    let (port, sender, _storage, child) = run_server_thread(f.tempdir.path(), options);
+    // Without a token.
+    let err = request_stats(connect_to_server(port).unwrap()).unwrap_err();
+    assert!(format!("{:#}", err).contains("requires a token"));
+    // With the wrong one, after which the connection is closed.
+    let mut conn = connect_to_server(port).unwrap();
+    assert!(conn.authenticate("secreT").is_err());
+    assert!(conn.authenticate("secret").is_err());
+    // With the right one.
+    let mut conn = connect_to_server(port).unwrap();
+    conn.authenticate("secret").unwrap();
+    let info = request_stats(conn).unwrap();
+    assert_eq!(0, info.stats.compile_requests);
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+#[cfg(unix)]
+fn test_server_admin_socket() {
+    let f = TestFixture::new();
+    let path = f.tempdir.path().join("admin.sock");
+    let options = ServerOptions {
+        admin: Some(AdminAddr::Unix(path.clone())),
+        ..Default::default()
+    };
+    let (port, _sender, _storage, child) = run_server_thread(f.tempdir.path(), options);
+    let connect_admin = || {
+        ServerConnection::from_unix(std::os::unix::net::UnixStream::connect(&path).unwrap())
+            .unwrap()
+    };
+    // Only the user of the server may connect to the admin socket.
+    use std::os::unix::fs::PermissionsExt;
+    assert_eq!(
+        fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+        0o600
+    );
+
+    // The socket of the clients refuses the admin requests, and closes the
+    // connection.
+    let mut conn = connect_to_server(port).unwrap();
+    let err = conn.request(Request::GetStats).unwrap_err();
+    assert!(
+        format!("{:#}", err).contains("SCCACHE_ADMIN_ADDR"),
+        "{:#}",
+        err
+    );
+    assert!(conn.request(Request::GetStats).is_err());
+
+    // And the admin socket refuses the compilations.
+    let err = connect_admin()
+        .request(Request::Compile(Compile {
+            exe: "/usr/bin/gcc".into(),
+            exe_digest: None,
+            cwd: f.tempdir.path().into(),
+            args: vec![],
+            env_vars: vec![],
+            stdin: None,
+            deadline: None,
+            umask: None,
+        }))
+        .unwrap_err();
+    assert!(
+        format!("{:#}", err).contains("doesn't take compilations"),
+        "{:#}",
+        err
+    );
+
+    let info = request_stats(connect_admin()).unwrap();
+    assert_eq!(0, info.stats.compile_requests);
+    request_shutdown(connect_admin()).unwrap();
+    child.join().unwrap();
+}
+
 #[test]
 fn test_server_unsupported_compiler() {
     let f = TestFixture::new();
@@ -206,6 +767,7 @@ fn test_server_unsupported_compiler() {
         cwd,
         path,
         vec![],
+        &mut io::empty(),
         &mut stdout,
         &mut stderr,
     );
@@ -278,6 +840,7 @@ fn test_server_compile() {
             cwd,
             path,
             vec![],
+            &mut io::empty(),
             &mut stdout,
             &mut stderr
         )
@@ -293,6 +856,1466 @@ fn test_server_compile() {
     child.join().unwrap();
 }
 
+#[test]
+fn test_server_compile_streams_stderr() {
+    let _ = env_logger::try_init();
+    let f = TestFixture::new();
+    let gcc = f.mk_bin("gcc").unwrap();
+    let (port, sender, server_creator, child) = run_server_thread(f.tempdir.path(), None);
+    std::fs::write(f.tempdir.path().join("file.c"), "whatever").unwrap();
+    // Larger than a chunk read from the compiler, to be streamed in several.
+    let stderr: Vec<u8> = b"file.c:1:1: warning: something\n"
+        .iter()
+        .copied()
+        .cycle()
+        .take(200_000)
+        .collect();
+    {
+        let mut c = server_creator.lock().unwrap();
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")));
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "preprocessed", "")));
+        let obj = f.tempdir.path().join("file.o");
+        let stderr = stderr.clone();
+        c.next_command_calls(move |_| {
+            std::fs::write(&obj, b"file contents")?;
+            Ok(MockChild::new(exit_status(0), "", &stderr))
+        });
+        // The cache hit.
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "preprocessed", "")));
+    }
+    let compile = || {
+        let mut conn = connect_to_server(port).unwrap();
+        let response = conn
+            .request(Request::Compile(Compile {
+                exe: gcc.clone().into(),
+                exe_digest: None,
+                cwd: f.tempdir.path().into(),
+                args: ["-c", "file.c", "-o", "file.o"]
+                    .iter()
+                    .map(OsString::from)
+                    .collect(),
+                env_vars: vec![],
+                stdin: None,
+                deadline: None,
+                umask: None,
+            }))
+            .unwrap();
+        assert!(
+            matches!(response, Response::Compile(CompileResponse::CompileStarted)),
+            "{:?}",
+            response
+        );
+        let mut chunks = vec![];
+        loop {
+            match conn.read_one_response().unwrap() {
+                Response::CompileStderr(chunk, _) => chunks.push(chunk),
+                Response::CompileFinished(res) => break (chunks, res),
+                response => panic!("unexpected response {:?}", response),
+            }
+        }
+    };
+
+    // The stderr of the compiler comes in chunks, before the result, which
+    // has none of it left.
+    let (chunks, res) = compile();
+    assert!(chunks.len() > 1, "{} chunks", chunks.len());
+    assert_eq!(chunks.concat(), stderr);
+    assert_eq!(res.retcode, Some(0));
+    assert!(res.stderr.is_empty());
+
+    // The cache hit sends it at once.
+    request_flush(connect_to_server(port).unwrap()).unwrap();
+    let (chunks, res) = compile();
+    assert!(chunks.is_empty());
+    assert_eq!(res.stderr, stderr);
+    assert_eq!(0, server_creator.lock().unwrap().children.len());
+
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_compile_forwarded_env() {
+    let f = TestFixture::new();
+    let gcc = f.mk_bin("gcc").unwrap();
+    let compile_env = Arc::new(Mutex::new(vec![]));
+    let options = ServerOptions {
+        compile_env: Some(compile_env.clone()),
+        ..Default::default()
+    };
+    let (port, sender, server_creator, child) = run_server_thread(f.tempdir.path(), options);
+    std::fs::write(f.tempdir.path().join("file.c"), "whatever").unwrap();
+    {
+        let mut c = server_creator.lock().unwrap();
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")));
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "preprocessed", "")));
+        let obj = f.tempdir.path().join("file.o");
+        c.next_command_calls(move |_| {
+            File::create(&obj)?.write_all(b"file contents")?;
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+    }
+    let cmdline = vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()];
+    let env_vars = vec![
+        ("PATH".into(), f.paths.clone()),
+        ("SOURCE_DATE_EPOCH".into(), "0".into()),
+        ("AWS_SECRET_ACCESS_KEY".into(), "hunter2".into()),
+    ];
+    let mut runtime = Runtime::new().unwrap();
+    let res = do_compile(
+        new_creator(),
+        &mut runtime,
+        connect_to_server(port).unwrap(),
+        &gcc,
+        cmdline,
+        f.tempdir.path(),
+        Some(f.paths.clone()),
+        env_vars,
+        &mut io::empty(),
+        &mut Cursor::new(Vec::new()),
+        &mut Cursor::new(Vec::new()),
+    );
+    assert_eq!(0, res.unwrap());
+    // The variables unrelated to the compilation stay on the client.
+    let names: Vec<_> = compile_env
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(k, _)| k.clone())
+        .collect();
+    assert_eq!(names, ["PATH", "SOURCE_DATE_EPOCH"]);
+
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_flush() {
+    let _ = env_logger::try_init();
+    let f = TestFixture::new();
+    let gcc = f.mk_bin("gcc").unwrap();
+    let cache_dir = f.tempdir.path().join("cache");
+    let options = ServerOptions {
+        write_delay: Some(Duration::from_secs(2)),
+        ..Default::default()
+    };
+    let (port, sender, server_creator, child) = run_server_thread(&cache_dir, options);
+    std::fs::write(f.tempdir.path().join("file.c"), "whatever").unwrap();
+    {
+        let mut c = server_creator.lock().unwrap();
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")));
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "preprocessed", "")));
+        let obj = f.tempdir.path().join("file.o");
+        c.next_command_calls(move |_| {
+            File::create(&obj)?.write_all(b"file contents")?;
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+    }
+    let cmdline = vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()];
+    let mut runtime = Runtime::new().unwrap();
+    let res = do_compile(
+        new_creator(),
+        &mut runtime,
+        connect_to_server(port).unwrap(),
+        &gcc,
+        cmdline,
+        f.tempdir.path(),
+        Some(f.paths.clone()),
+        vec![],
+        &mut io::empty(),
+        &mut Cursor::new(Vec::new()),
+        &mut Cursor::new(Vec::new()),
+    );
+    assert_eq!(0, res.unwrap());
+    let cached_files = || {
+        walkdir::WalkDir::new(&cache_dir)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_file())
+            .count()
+    };
+    // The compilation was answered before it was written.
+    assert_eq!(cached_files(), 0);
+
+    let summary = request_flush(connect_to_server(port).unwrap()).unwrap();
+    assert_eq!(
+        summary,
+        FlushSummary {
+            pending: 1,
+            written: 1,
+            ..Default::default()
+        }
+    );
+    assert!(cached_files() > 0);
+    // Nothing is left to wait for.
+    let summary = request_flush(connect_to_server(port).unwrap()).unwrap();
+    assert_eq!(summary, FlushSummary::default());
+
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_list_and_remove_entries() {
+    let _ = env_logger::try_init();
+    let f = TestFixture::new();
+    let gcc = f.mk_bin("gcc").unwrap();
+    let (port, sender, server_creator, child) =
+        run_server_thread(&f.tempdir.path().join("cache"), None);
+    std::fs::write(f.tempdir.path().join("file.c"), "whatever").unwrap();
+    {
+        let mut c = server_creator.lock().unwrap();
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")));
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "preprocessed", "")));
+        let obj = f.tempdir.path().join("file.o");
+        c.next_command_calls(move |_| {
+            File::create(&obj)?.write_all(b"file contents")?;
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+    }
+    let cmdline = vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()];
+    let mut runtime = Runtime::new().unwrap();
+    let res = do_compile(
+        new_creator(),
+        &mut runtime,
+        connect_to_server(port).unwrap(),
+        &gcc,
+        cmdline,
+        f.tempdir.path(),
+        Some(f.paths.clone()),
+        vec![],
+        &mut io::empty(),
+        &mut Cursor::new(Vec::new()),
+        &mut Cursor::new(Vec::new()),
+    );
+    assert_eq!(0, res.unwrap());
+    request_flush(connect_to_server(port).unwrap()).unwrap();
+    let list = |prefix: &str| {
+        let mut out = vec![];
+        let count =
+            request_list_entries(connect_to_server(port).unwrap(), &mut out, prefix).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(count as usize, out.lines().count());
+        out
+    };
+    let listed = list("");
+    let key = listed.split_whitespace().next().unwrap().to_owned();
+    assert_eq!(listed.lines().count(), 1, "{}", listed);
+    assert_eq!(list(&key[..4]), listed);
+    let size = || {
+        request_stats(connect_to_server(port).unwrap())
+            .unwrap()
+            .cache_size
+    };
+    assert!(size().unwrap() > 0);
+
+    assert!(request_remove_entry(connect_to_server(port).unwrap(), &key).unwrap());
+    assert!(!request_remove_entry(connect_to_server(port).unwrap(), &key).unwrap());
+    assert_eq!(list(""), "");
+    // The server's index of the cache forgot the entry.
+    assert_eq!(size(), Some(0));
+
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_selftest() {
+    let _ = env_logger::try_init();
+    let f = TestFixture::new();
+    let gcc = f.mk_bin("gcc").unwrap();
+    let dir = f.tempdir.path().join("selftest");
+    fs::create_dir(&dir).unwrap();
+    let (port, sender, server_creator, child) =
+        run_server_thread(&f.tempdir.path().join("cache"), None);
+    {
+        let mut c = server_creator.lock().unwrap();
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")));
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "preprocessed", "")));
+        let obj = dir.join("selftest-c.o");
+        c.next_command_calls(move |_| {
+            File::create(&obj)?.write_all(b"file contents")?;
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+        // The second compilation is a hit.
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "preprocessed", "")));
+    }
+    let programs = &selftest::PROGRAMS[..1];
+    let env_vars = vec![(OsString::from("CC"), OsString::from(&gcc))];
+    let mut out = Vec::new();
+    let status = selftest::run(port, programs, &dir, None, &env_vars, &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert_eq!(status, 0, "{}", out);
+    let lines: Vec<_> = out.lines().collect();
+    assert_eq!(lines.len(), 2, "{}", out);
+    assert!(lines[0].starts_with("cache   ok"), "{}", out);
+    assert!(lines[1].starts_with("C       ok"), "{}", out);
+    assert!(dir.join("selftest.c").exists());
+
+    // The compiler asked for must be found.
+    let env_vars = vec![(OsString::from("CC"), OsString::from("no-such-cc"))];
+    let mut out = Vec::new();
+    let status = selftest::run(port, programs, &dir, None, &env_vars, &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert_eq!(status, 1, "{}", out);
+    assert!(
+        out.contains("C       FAILED  the compiler \"no-such-cc\""),
+        "{}",
+        out
+    );
+
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_compile_large_command_line() {
+    let f = TestFixture::new();
+    let gcc = f.mk_bin("gcc").unwrap();
+    let (port, sender, server_creator, child) = run_server_thread(f.tempdir.path(), None);
+    let conn = connect_to_server(port).unwrap();
+    std::fs::write(f.tempdir.path().join("file.c"), "whatever").unwrap();
+    {
+        let mut c = server_creator.lock().unwrap();
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")));
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "preprocessed", "")));
+        let obj = f.tempdir.path().join("file.o");
+        c.next_command_calls(move |_| {
+            File::create(&obj)?.write_all(b"file contents")?;
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+    }
+    // A megabyte of defines, as generated builds may have.
+    let mut cmdline: Vec<OsString> = (0..32 * 1024)
+        .map(|i| format!("-DGENERATED_DEFINE_{:08}=1234567", i).into())
+        .collect();
+    cmdline.extend(["-c", "file.c", "-o", "file.o"].iter().map(OsString::from));
+    let mut stdout = Cursor::new(Vec::new());
+    let mut stderr = Cursor::new(Vec::new());
+    let mut runtime = Runtime::new().unwrap();
+    assert_eq!(
+        0,
+        do_compile(
+            new_creator(),
+            &mut runtime,
+            conn,
+            &gcc,
+            cmdline,
+            f.tempdir.path(),
+            Some(f.paths),
+            vec![],
+            &mut io::empty(),
+            &mut stdout,
+            &mut stderr
+        )
+        .unwrap()
+    );
+    // The server ran the compilation.
+    assert_eq!(0, server_creator.lock().unwrap().children.len());
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_compile_request_too_large() {
+    let f = TestFixture::new();
+    let gcc = f.mk_bin("gcc").unwrap();
+    let (port, sender, server_creator, child) = run_server_thread(f.tempdir.path(), None);
+    let conn = connect_to_server(port).unwrap();
+    let mut cmdline: Vec<OsString> = vec![format!("-DHUGE={}", "x".repeat(33 << 20)).into()];
+    cmdline.extend(["-c", "file.c", "-o", "file.o"].iter().map(OsString::from));
+    // The request isn't sent, and the client runs the compiler itself.
+    let client_creator = new_creator();
+    client_creator
+        .lock()
+        .unwrap()
+        .next_command_spawns(Ok(MockChild::new(exit_status(0), "", "")));
+    let mut stdout = Cursor::new(Vec::new());
+    let mut stderr = Cursor::new(Vec::new());
+    let mut runtime = Runtime::new().unwrap();
+    assert_eq!(
+        0,
+        do_compile(
+            client_creator.clone(),
+            &mut runtime,
+            conn,
+            &gcc,
+            cmdline,
+            f.tempdir.path(),
+            Some(f.paths),
+            vec![],
+            &mut io::empty(),
+            &mut stdout,
+            &mut stderr
+        )
+        .unwrap()
+    );
+    assert_eq!(0, client_creator.lock().unwrap().children.len());
+    let info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    assert_eq!(0, info.stats.compile_requests);
+    assert_eq!(0, server_creator.lock().unwrap().children.len());
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_compile_multiple_inputs() {
+    let f = TestFixture::new();
+    let gcc = f.mk_bin("gcc").unwrap();
+    let (port, sender, server_creator, child) = run_server_thread(f.tempdir.path(), None);
+    let cwd = f.tempdir.path();
+    std::fs::write(cwd.join("a.c"), "a").unwrap();
+    std::fs::write(cwd.join("b.c"), "b").unwrap();
+    let compiles = |source: &'static str| {
+        let obj = cwd.join(Path::new(source).with_extension("o"));
+        move |_: &[OsString]| {
+            File::create(&obj)?.write_all(source.as_bytes())?;
+            Ok(MockChild::new(exit_status(0), source, ""))
+        }
+    };
+    let compile = |cmdline: &[&str]| {
+        let mut stdout = Cursor::new(Vec::new());
+        let status = do_compile(
+            new_creator(),
+            &mut Runtime::new().unwrap(),
+            connect_to_server(port).unwrap(),
+            &gcc,
+            cmdline.iter().map(OsString::from).collect(),
+            cwd,
+            Some(f.paths.clone()),
+            vec![],
+            &mut io::empty(),
+            &mut stdout,
+            &mut Cursor::new(Vec::new()),
+        )
+        .unwrap();
+        (status, stdout.into_inner())
+    };
+    {
+        let mut c = server_creator.lock().unwrap();
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")));
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "a preprocessed", "")));
+        c.next_command_calls(compiles("a.c"));
+    }
+    assert_eq!((0, b"a.c".to_vec()), compile(&["-c", "a.c", "-Wall"]));
+    fs::remove_file(cwd.join("a.o")).unwrap();
+
+    // a.c is found in the cache, and only b.c is compiled.
+    {
+        let mut c = server_creator.lock().unwrap();
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "a preprocessed", "")));
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "b preprocessed", "")));
+        c.next_command_calls(compiles("b.c"));
+    }
+    assert_eq!(
+        (0, b"a.cb.c".to_vec()),
+        compile(&["-c", "a.c", "b.c", "-Wall"])
+    );
+    assert_eq!(0, server_creator.lock().unwrap().children.len());
+    assert_eq!(b"a.c", &*fs::read(cwd.join("a.o")).unwrap());
+    assert_eq!(b"b.c", &*fs::read(cwd.join("b.o")).unwrap());
+    let info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    assert_eq!(1, info.stats.requests_split);
+    assert_eq!(1, info.stats.cache_hits.all());
+    assert_eq!(2, info.stats.cache_misses.all());
+
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_compile_stdin() {
+    let f = TestFixture::new();
+    let gcc = f.mk_bin("gcc").unwrap();
+    let (port, sender, server_creator, child) = run_server_thread(f.tempdir.path(), None);
+    let cwd = f.tempdir.path();
+    // More than a chunk of stdin.
+    let source = "int x;\n".repeat(40_000);
+    let obj = cwd.join("file.o");
+    {
+        let mut c = server_creator.lock().unwrap();
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")));
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "preprocessed", "")));
+        let obj = obj.clone();
+        c.next_command_calls(move |_| {
+            File::create(&obj)?.write_all(b"file contents")?;
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "preprocessed", "")));
+    }
+    for _ in 0..2 {
+        let status = do_compile(
+            new_creator(),
+            &mut Runtime::new().unwrap(),
+            connect_to_server(port).unwrap(),
+            &gcc,
+            ["-x", "c", "-c", "-", "-o", "file.o"]
+                .iter()
+                .map(OsString::from)
+                .collect(),
+            cwd,
+            Some(f.paths.clone()),
+            vec![],
+            &mut Cursor::new(source.as_bytes()),
+            &mut Cursor::new(Vec::new()),
+            &mut Cursor::new(Vec::new()),
+        )
+        .unwrap();
+        assert_eq!(0, status);
+        assert_eq!(b"file contents", &*fs::read(&obj).unwrap());
+        fs::remove_file(&obj).unwrap();
+    }
+    assert_eq!(0, server_creator.lock().unwrap().children.len());
+    let info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    assert_eq!(1, info.stats.cache_misses.all());
+    assert_eq!(1, info.stats.cache_hits.all());
+
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_compile_client_disconnect() {
+    let f = TestFixture::new();
+    let gcc = f.mk_bin("gcc").unwrap();
+    let (port, sender, server_creator, child) = run_server_thread(f.tempdir.path(), None);
+    std::fs::write(f.tempdir.path().join("file.c"), "whatever").unwrap();
+    let (spawned_tx, spawned_rx) = mpsc::channel();
+    let (alive_tx, alive_rx) = mpsc::channel();
+    {
+        let mut c = server_creator.lock().unwrap();
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")));
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "preprocessed", "")));
+        // A compiler which never exits.
+        let alive = Mutex::new(Some(alive_tx));
+        c.next_command_calls(move |_| {
+            spawned_tx.send(()).unwrap();
+            Ok(MockChild::running(alive.lock().unwrap().take().unwrap()))
+        });
+    }
+    let mut conn = connect_to_server(port).unwrap();
+    let response = conn
+        .request(Request::Compile(Compile {
+            exe: gcc.into(),
+            exe_digest: None,
+            cwd: f.tempdir.path().into(),
+            args: ["-c", "file.c", "-o", "file.o"]
+                .iter()
+                .map(OsString::from)
+                .collect(),
+            env_vars: vec![],
+            stdin: None,
+            deadline: None,
+            umask: None,
+        }))
+        .unwrap();
+    assert!(matches!(
+        response,
+        Response::Compile(CompileResponse::CompileStarted)
+    ));
+    spawned_rx.recv_timeout(Duration::from_secs(10)).unwrap();
+    // Closing the connection kills the compiler, and nothing is cached.
+    drop(conn);
+    assert_eq!(
+        Err(mpsc::RecvTimeoutError::Disconnected),
+        alive_rx.recv_timeout(Duration::from_secs(10))
+    );
+    let info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    assert_eq!(0, info.stats.cache_misses.all());
+    assert_eq!(0, info.stats.cache_writes);
+
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_compile_deadline() {
+    let f = TestFixture::new();
+    let gcc = f.mk_bin("gcc").unwrap();
+    let (port, sender, server_creator, child) = run_server_thread(f.tempdir.path(), None);
+    std::fs::write(f.tempdir.path().join("file.c"), "whatever").unwrap();
+    let obj = f.tempdir.path().join("file.o");
+    let (alive_tx, alive_rx) = mpsc::channel();
+    {
+        let mut c = server_creator.lock().unwrap();
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")));
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "preprocessed", "")));
+        // A compiler which writes part of its output, and hangs.
+        let alive = Mutex::new(Some(alive_tx));
+        let obj = obj.clone();
+        c.next_command_calls(move |_| {
+            File::create(&obj)?.write_all(b"partial")?;
+            Ok(MockChild::running(alive.lock().unwrap().take().unwrap()))
+        });
+    }
+    let mut runtime = Runtime::new().unwrap();
+    let mut stderr = Cursor::new(Vec::new());
+    let res = do_compile(
+        new_creator(),
+        &mut runtime,
+        connect_to_server(port).unwrap(),
+        &gcc,
+        vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()],
+        f.tempdir.path(),
+        Some(f.paths.clone()),
+        vec![("SCCACHE_COMPILE_TIMEOUT".into(), "0.5".into())],
+        &mut io::empty(),
+        &mut Cursor::new(Vec::new()),
+        &mut stderr,
+    );
+    // The compiler was killed, its partial output removed, and the client
+    // failed.
+    assert_eq!(124, res.unwrap());
+    let stderr = String::from_utf8(stderr.into_inner()).unwrap();
+    assert!(stderr.contains("deadline"), "{stderr}");
+    assert_eq!(
+        Err(mpsc::RecvTimeoutError::Disconnected),
+        alive_rx.recv_timeout(Duration::from_secs(10))
+    );
+    assert!(!obj.exists());
+    let info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    assert_eq!(1, info.stats.deadlines_exceeded);
+    assert_eq!(0, info.stats.cache_writes);
+
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_cache_override() {
+    let f = TestFixture::new();
+    let gcc = f.mk_bin("gcc").unwrap();
+    let url = "s3://experimental";
+    let options = ServerOptions {
+        cache_override: Some((url, f.tempdir.path().join("experimental"))),
+        ..Default::default()
+    };
+    let (port, sender, server_creator, child) =
+        run_server_thread(&f.tempdir.path().join("cache"), options);
+    std::fs::write(f.tempdir.path().join("file.c"), "whatever").unwrap();
+    {
+        let mut c = server_creator.lock().unwrap();
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")));
+        // A compilation with the allowed cache, and one with the cache of
+        // the server.
+        for _ in 0..2 {
+            c.next_command_spawns(Ok(MockChild::new(exit_status(0), "preprocessed", "")));
+            let obj = f.tempdir.path().join("file.o");
+            c.next_command_calls(move |_| {
+                File::create(&obj)?.write_all(b"file contents")?;
+                Ok(MockChild::new(exit_status(0), "", ""))
+            });
+        }
+    }
+    let mut runtime = Runtime::new().unwrap();
+    for (cache_override, expected_code, expected_stderr) in [
+        (Some(url), 0, ""),
+        (None, 0, ""),
+        (
+            Some("s3://elsewhere"),
+            -4,
+            "sccache: error: cache failure: SCCACHE_CACHE_OVERRIDE=s3://elsewhere: the server doesn't allow this cache",
+        ),
+    ] {
+        let cmdline = vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()];
+        let env_vars = cache_override
+            .map(|url| (CACHE_OVERRIDE_ENV_VAR.into(), url.into()))
+            .into_iter()
+            .collect();
+        let mut stderr = Cursor::new(Vec::new());
+        let res = do_compile(
+            new_creator(),
+            &mut runtime,
+            connect_to_server(port).unwrap(),
+            &gcc,
+            cmdline,
+            f.tempdir.path(),
+            Some(f.paths.clone()),
+            env_vars,
+            &mut io::empty(),
+            &mut Cursor::new(Vec::new()),
+            &mut stderr,
+        );
+        assert_eq!(expected_code, res.unwrap());
+        let stderr = String::from_utf8(stderr.into_inner()).unwrap();
+        assert!(stderr.contains(expected_stderr), "{stderr}");
+    }
+    assert_eq!(0, server_creator.lock().unwrap().children.len());
+    // Only the compilation with the override is attributed to it.
+    let stats = request_stats(connect_to_server(port).unwrap())
+        .unwrap()
+        .stats;
+    assert_eq!(stats.cache_misses.all(), 2);
+    assert_eq!(
+        stats.cache_override_misses,
+        HashMap::from([(url.to_owned(), 1)])
+    );
+    assert!(stats.cache_override_hits.is_empty());
+    assert_eq!(stats.cache_errors.all(), 1);
+
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_listen_backlog() {
+    let runtime = Runtime::new().unwrap();
+    let listener = runtime
+        .block_on(crate::server::bind_listener(
+            "127.0.0.1:0".parse().unwrap(),
+            256,
+        ))
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    // Nothing accepts them, they are all queued.
+    let conns: Vec<_> = (0..200)
+        .map(|_| std::net::TcpStream::connect_timeout(&addr, Duration::from_secs(5)))
+        .collect();
+    assert!(conns.iter().all(|conn| conn.is_ok()));
+}
+
+#[test]
+fn test_server_prewarm() {
+    let _ = env_logger::try_init();
+    let f = TestFixture::new();
+    let gcc = f.mk_bin("gcc").unwrap();
+    let options = ServerOptions {
+        prewarm: Some((gcc.clone(), "compiler_id=gcc")),
+        ..Default::default()
+    };
+    let (port, sender, server_creator, child) = run_server_thread(f.tempdir.path(), options);
+    let conn = connect_to_server(port).unwrap();
+    std::fs::write(f.tempdir.path().join("file.c"), "whatever").unwrap();
+    {
+        // The compiler is detected already, or being, so only the
+        // preprocessor and the compiler run.
+        let mut c = server_creator.lock().unwrap();
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "preprocessed", "")));
+        let obj = f.tempdir.path().join("file.o");
+        c.next_command_calls(move |_| {
+            File::create(&obj)?.write_all(b"file contents")?;
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+    }
+    let cmdline = vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()];
+    let mut stdout = Cursor::new(Vec::new());
+    let mut stderr = Cursor::new(Vec::new());
+    let mut runtime = Runtime::new().unwrap();
+    assert_eq!(
+        0,
+        do_compile(
+            new_creator(),
+            &mut runtime,
+            conn,
+            &gcc,
+            cmdline,
+            f.tempdir.path(),
+            Some(f.paths.clone()),
+            vec![],
+            &mut io::empty(),
+            &mut stdout,
+            &mut stderr
+        )
+        .unwrap()
+    );
+    assert_eq!(0, server_creator.lock().unwrap().children.len());
+    let stats = request_stats(connect_to_server(port).unwrap())
+        .unwrap()
+        .stats;
+    assert_eq!(stats.cache_misses.all(), 1);
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_language_filter() {
+    let all = LanguageFilter::new(None, None);
+    assert!(all.allows(Language::C) && all.allows(Language::Rust));
+    let no_rust = LanguageFilter::new(None, Some("Rust"));
+    assert!(no_rust.allows(Language::Cxx));
+    assert!(!no_rust.allows(Language::Rust));
+    let c_only = LanguageFilter::new(Some("c, c++"), None);
+    assert!(c_only.allows(Language::CHeader));
+    assert!(c_only.allows(Language::Cxx));
+    assert!(!c_only.allows(Language::Cuda));
+    // What is disabled wins.
+    let neither = LanguageFilter::new(Some("c"), Some("c"));
+    assert!(!neither.allows(Language::C));
+}
+
+#[test]
+fn test_server_compile_disabled_language() {
+    let f = TestFixture::new();
+    let gcc = f.mk_bin("gcc").unwrap();
+    let options = ServerOptions {
+        languages: Some(LanguageFilter::new(None, Some("c"))),
+        ..Default::default()
+    };
+    let (port, sender, server_creator, child) = run_server_thread(f.tempdir.path(), options);
+    // The server only detects the compiler.
+    server_creator
+        .lock()
+        .unwrap()
+        .next_command_spawns(Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")));
+    // And the client runs it.
+    let client_creator = new_creator();
+    client_creator
+        .lock()
+        .unwrap()
+        .next_command_spawns(Ok(MockChild::new(exit_status(0), "compiled", "")));
+    let cmdline = vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()];
+    let mut stdout = Cursor::new(Vec::new());
+    let mut stderr = Cursor::new(Vec::new());
+    let mut runtime = Runtime::new().unwrap();
+    let status = do_compile(
+        client_creator.clone(),
+        &mut runtime,
+        connect_to_server(port).unwrap(),
+        &gcc,
+        cmdline,
+        f.tempdir.path(),
+        Some(f.paths.clone()),
+        vec![],
+        &mut io::empty(),
+        &mut stdout,
+        &mut stderr,
+    )
+    .unwrap();
+    assert_eq!(0, status);
+    assert_eq!(0, client_creator.lock().unwrap().children.len());
+    let info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    assert_eq!(1, info.stats.requests_not_cacheable);
+    assert_eq!(Some(&1), info.stats.not_cached.get("disabled by config"));
+    assert_eq!(0, info.stats.requests_executed);
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_cache_hit_replays_streams() {
+    let _ = env_logger::try_init();
+    let f = TestFixture::new();
+    let gcc = f.mk_bin("gcc").unwrap();
+    let (port, sender, server_creator, child) = run_server_thread(f.tempdir.path(), None);
+    server_creator
+        .lock()
+        .unwrap()
+        .next_command_spawns(Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")));
+    let mut runtime = Runtime::new().unwrap();
+    for (input, compiler_stdout, compiler_stderr) in [
+        ("stdout.c", &b"only stdout"[..], &b""[..]),
+        ("stderr.c", &b""[..], &b"only stderr"[..]),
+    ] {
+        // The first compilation is a miss, the second one a hit.
+        for hit in [false, true] {
+            {
+                let mut c = server_creator.lock().unwrap();
+                c.next_command_spawns(Ok(MockChild::new(exit_status(0), input, "")));
+                if !hit {
+                    let obj = f.tempdir.path().join(input).with_extension("o");
+                    c.next_command_calls(move |_| {
+                        let mut f = File::create(&obj)?;
+                        f.write_all(b"file contents")?;
+                        Ok(MockChild::new(
+                            exit_status(0),
+                            compiler_stdout,
+                            compiler_stderr,
+                        ))
+                    });
+                }
+            }
+            let obj = Path::new(input).with_extension("o");
+            let cmdline = vec!["-c".into(), input.into(), "-o".into(), obj.into()];
+            let mut stdout = Cursor::new(Vec::new());
+            let mut stderr = Cursor::new(Vec::new());
+            assert_eq!(
+                0,
+                do_compile(
+                    new_creator(),
+                    &mut runtime,
+                    connect_to_server(port).unwrap(),
+                    &gcc,
+                    cmdline,
+                    f.tempdir.path(),
+                    Some(f.paths.clone()),
+                    vec![],
+                    &mut io::empty(),
+                    &mut stdout,
+                    &mut stderr
+                )
+                .unwrap()
+            );
+            assert_eq!(0, server_creator.lock().unwrap().children.len());
+            assert_eq!(compiler_stdout, stdout.into_inner().as_slice());
+            assert_eq!(compiler_stderr, stderr.into_inner().as_slice());
+        }
+    }
+    let info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    assert_eq!(2, info.stats.cache_hits.all());
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_compile_through_wrapper() {
+    let f = TestFixture::new();
+    let gcc = f.mk_bin("gcc").unwrap();
+    let ccache = f.mk_bin("ccache").unwrap();
+    let (port, sender, server_creator, child) = run_server_thread(
+        f.tempdir.path(),
+        ServerOptions {
+            compiler_wrapper: Some(CompilerWrapper {
+                path: ccache.clone(),
+                args: vec!["gcc".into()],
+                compiler: gcc,
+            }),
+            ..Default::default()
+        },
+    );
+    std::fs::write(f.tempdir.path().join("file.c"), "whatever").unwrap();
+    let (args_tx, args_rx) = mpsc::channel();
+    {
+        let mut c = server_creator.lock().unwrap();
+        // The compiler run by the wrapper is detected and preprocesses the
+        // input.
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")));
+        let preprocessor_args = args_tx.clone();
+        c.next_command_calls(move |args| {
+            preprocessor_args.send(args.to_vec()).unwrap();
+            Ok(MockChild::new(exit_status(0), "preprocessed", ""))
+        });
+        // The wrapper compiles it.
+        let obj = f.tempdir.path().join("file.o");
+        c.next_command_calls(move |args| {
+            args_tx.send(args.to_vec()).unwrap();
+            std::fs::write(&obj, b"file contents")?;
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+    }
+    let conn = connect_to_server(port).unwrap();
+    let cmdline = vec![
+        "gcc".into(),
+        "-c".into(),
+        "file.c".into(),
+        "-o".into(),
+        "file.o".into(),
+    ];
+    assert_eq!(
+        0,
+        do_compile(
+            new_creator(),
+            &mut Runtime::new().unwrap(),
+            conn,
+            &ccache,
+            cmdline,
+            f.tempdir.path(),
+            Some(f.paths.clone()),
+            vec![],
+            &mut io::empty(),
+            &mut Cursor::new(Vec::new()),
+            &mut Cursor::new(Vec::new()),
+        )
+        .unwrap()
+    );
+    let preprocessor_args = args_rx.recv().unwrap();
+    assert!(!preprocessor_args.contains(&"gcc".into()));
+    let compiler_args = args_rx.recv().unwrap();
+    assert_eq!(compiler_args[0], "gcc");
+    assert!(compiler_args[1..].contains(&"file.c".into()));
+    let info = query_stats(("127.0.0.1", port)).unwrap();
+    assert_eq!(1, info.stats.cache_misses.all());
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_server_compile_compiler_map() {
+    let f = TestFixture::new();
+    let gcc = f.mk_bin("gcc").unwrap();
+    // The client sees the compiler in a directory the server doesn't.
+    let client_bin = f.tempdir.path().join("client-bin");
+    let map = format!(
+        "{}={}",
+        client_bin.display(),
+        gcc.parent().unwrap().display()
+    );
+    let (port, sender, server_creator, child) = run_server_thread(
+        f.tempdir.path(),
+        ServerOptions {
+            compiler_map: Some(CompilerPathMap::parse(&map).unwrap()),
+            ..Default::default()
+        },
+    );
+    std::fs::write(f.tempdir.path().join("file.c"), "whatever").unwrap();
+    {
+        let mut c = server_creator.lock().unwrap();
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")));
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "preprocessed", "")));
+        let obj = f.tempdir.path().join("file.o");
+        c.next_command_calls(move |_| {
+            std::fs::write(&obj, b"file contents")?;
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+    }
+    let digest = util::Digest::reader_sync(File::open(&gcc).unwrap()).unwrap();
+    let compile = |exe: PathBuf, exe_digest: Option<&str>| {
+        Request::Compile(Compile {
+            exe: exe.into(),
+            exe_digest: exe_digest.map(ToOwned::to_owned),
+            cwd: f.tempdir.path().into(),
+            args: ["-c", "file.c", "-o", "file.o"]
+                .iter()
+                .map(OsString::from)
+                .collect(),
+            env_vars: vec![],
+            stdin: None,
+            deadline: None,
+            umask: None,
+        })
+    };
+
+    // The compiler of the client is that of the server.
+    let mut conn = connect_to_server(port).unwrap();
+    let response = conn
+        .request(compile(client_bin.join("gcc"), Some(&digest)))
+        .unwrap();
+    assert!(
+        matches!(response, Response::Compile(CompileResponse::CompileStarted)),
+        "{:?}",
+        response
+    );
+    match conn.read_one_response().unwrap() {
+        Response::CompileFinished(res) => assert_eq!(res.retcode, Some(0)),
+        response => panic!("unexpected response {:?}", response),
+    }
+
+    // The compilers it doesn't map are rejected.
+    let response = connect_to_server(port)
+        .unwrap()
+        .request(compile(gcc.clone(), None))
+        .unwrap();
+    match response {
+        Response::Compile(CompileResponse::UnsupportedCompiler(msg)) => {
+            let msg = msg.into_string().unwrap();
+            assert!(msg.contains("is not in SCCACHE_COMPILER_MAP"), "{}", msg);
+        }
+        response => panic!("unexpected response {:?}", response),
+    }
+    // As are those which differ from the client's.
+    let response = connect_to_server(port)
+        .unwrap()
+        .request(compile(client_bin.join("gcc"), Some("0123")))
+        .unwrap();
+    match response {
+        Response::Compile(CompileResponse::UnsupportedCompiler(msg)) => {
+            let msg = msg.into_string().unwrap();
+            assert!(msg.contains("differs from that of the client"), "{}", msg);
+        }
+        response => panic!("unexpected response {:?}", response),
+    }
+    let info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    assert_eq!(1, info.stats.cache_misses.all());
+    assert_eq!(2, info.stats.requests_unsupported_compiler);
+
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_compile_errors() {
+    let f = TestFixture::new();
+    let gcc = f.mk_bin("gcc").unwrap();
+    let (port, sender, server_creator, child) = run_server_thread(f.tempdir.path(), None);
+    std::fs::write(f.tempdir.path().join("file.c"), "whatever").unwrap();
+    // Exit codes 2 and 3 on Unix.
+    let preprocessor_status = exit_status(2 << 8);
+    let compiler_status = exit_status(3 << 8);
+    {
+        let mut c = server_creator.lock().unwrap();
+        // The server will check the compiler. Pretend it's GCC.
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")));
+        // The preprocessor fails.
+        c.next_command_spawns(Ok(MockChild::new(preprocessor_status, "", "bad include")));
+        // The compiler fails.
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "preprocessed", "")));
+        c.next_command_spawns(Ok(MockChild::new(compiler_status, "", "syntax error")));
+        // The compiler can't be executed.
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "preprocessed", "")));
+        c.next_command_spawns(Err(SpawnError("gcc".to_owned()).into()));
+    }
+    let mut runtime = Runtime::new().unwrap();
+    for (expected_code, expected_stderr) in [
+        (preprocessor_status.code().unwrap(), "bad include"),
+        (compiler_status.code().unwrap(), "syntax error"),
+        (
+            127,
+            "sccache: error: failed to execute the compiler: failed to spawn gcc",
+        ),
+    ] {
+        let cmdline = vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()];
+        let mut stderr = Cursor::new(Vec::new());
+        let res = do_compile(
+            new_creator(),
+            &mut runtime,
+            connect_to_server(port).unwrap(),
+            &gcc,
+            cmdline,
+            f.tempdir.path(),
+            Some(f.paths.clone()),
+            vec![],
+            &mut io::empty(),
+            &mut Cursor::new(Vec::new()),
+            &mut stderr,
+        );
+        assert_eq!(expected_code, res.unwrap());
+        let stderr = String::from_utf8(stderr.into_inner()).unwrap();
+        assert!(stderr.contains(expected_stderr), "{stderr}");
+    }
+    assert_eq!(0, server_creator.lock().unwrap().children.len());
+    // The failures of the preprocessor and of the compiler are told apart.
+    let stats = request_stats(connect_to_server(port).unwrap())
+        .unwrap()
+        .stats;
+    assert_eq!(stats.preprocessor_errors, 1);
+    assert_eq!(stats.compile_fails, 1);
+    assert_eq!(stats.cache_errors.all(), 0);
+
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_compile_output_too_large() {
+    let f = TestFixture::new();
+    let gcc = f.mk_bin("gcc").unwrap();
+    let (port, sender, server_creator, child) = run_server_thread(f.tempdir.path(), None);
+    std::fs::write(f.tempdir.path().join("file.c"), "whatever").unwrap();
+    let compiler_stdout = vec![b'o'; util::DEFAULT_MAX_OUTPUT_SIZE as usize + 1];
+    let compiler_stderr = vec![b'x'; util::DEFAULT_MAX_OUTPUT_SIZE as usize + 1];
+    {
+        let mut c = server_creator.lock().unwrap();
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")));
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "preprocessed", "")));
+        c.next_command_spawns(Ok(MockChild::new(
+            exit_status(0),
+            &compiler_stdout,
+            &compiler_stderr,
+        )));
+    }
+    // The output is streamed to the client, which doesn't run the compiler
+    // again.
+    let client_creator = new_creator();
+    let cmdline = vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()];
+    let mut runtime = Runtime::new().unwrap();
+    let mut stdout = Cursor::new(Vec::new());
+    let mut stderr = Cursor::new(Vec::new());
+    let res = do_compile(
+        client_creator.clone(),
+        &mut runtime,
+        connect_to_server(port).unwrap(),
+        &gcc,
+        cmdline,
+        f.tempdir.path(),
+        Some(f.paths.clone()),
+        vec![],
+        &mut io::empty(),
+        &mut stdout,
+        &mut stderr,
+    );
+    assert_eq!(0, res.unwrap());
+    assert!(stdout.into_inner() == compiler_stdout);
+    assert!(stderr.into_inner() == compiler_stderr);
+    assert_eq!(0, server_creator.lock().unwrap().children.len());
+    assert_eq!(0, client_creator.lock().unwrap().children.len());
+    let info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    assert_eq!(1, info.stats.too_large_compilations);
+    assert_eq!(0, info.stats.cache_writes);
+
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_explain() {
+    let f = TestFixture::new();
+    let gcc = f.mk_bin("gcc").unwrap();
+    let (port, sender, server_creator, child) = run_server_thread(f.tempdir.path(), None);
+    const PREPROCESSOR_STDOUT: &[u8] = b"preprocessor stdout";
+    std::fs::write(f.tempdir.path().join("file.c"), "whatever").unwrap();
+    let obj = f.tempdir.path().join("file.o");
+    {
+        let mut c = server_creator.lock().unwrap();
+        // The server will check the compiler. Pretend it's GCC.
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")));
+        // Preprocessor invocations for the first explains, with and without
+        // extraction.
+        for _ in 0..2 {
+            c.next_command_spawns(Ok(MockChild::new(exit_status(0), PREPROCESSOR_STDOUT, "")));
+        }
+        // Preprocessor and compiler invocations for the compilation.
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), PREPROCESSOR_STDOUT, "")));
+        let obj = obj.clone();
+        c.next_command_calls(move |_| {
+            let mut f = File::create(&obj)?;
+            f.write_all(b"file contents")?;
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+        // Preprocessor invocations for the second explain, and the
+        // extraction.
+        for _ in 0..2 {
+            c.next_command_spawns(Ok(MockChild::new(exit_status(0), PREPROCESSOR_STDOUT, "")));
+        }
+    }
+    let cmdline: Vec<std::ffi::OsString> =
+        vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()];
+    let cwd = f.tempdir.path();
+    let extracted = cwd.join("extracted");
+    let explain = |json, extract_to: Option<&Path>| {
+        let mut stdout = Cursor::new(Vec::new());
+        do_explain(
+            connect_to_server(port).unwrap(),
+            &gcc,
+            cmdline.clone(),
+            cwd,
+            Some(f.paths.clone()),
+            vec![],
+            extract_to.is_none(),
+            extract_to,
+            json,
+            &mut stdout,
+        )
+        .unwrap();
+        String::from_utf8(stdout.into_inner()).unwrap()
+    };
+
+    // Nothing is extracted on a miss.
+    let text = explain(false, Some(&extracted));
+    assert!(text.contains("Cached: no"), "{text}");
+    assert!(!text.contains("Extracted"), "{text}");
+    assert!(!text.contains("Inputs"), "{text}");
+    assert!(!extracted.exists());
+    let text = explain(false, None);
+    assert!(text.contains("language"), "{text}");
+    assert!(text.contains("preprocessor output"), "{text}");
+    // Nothing was compiled.
+    assert!(!obj.exists());
+
+    let mut runtime = Runtime::new().unwrap();
+    let res = do_compile(
+        new_creator(),
+        &mut runtime,
+        connect_to_server(port).unwrap(),
+        &gcc,
+        cmdline.clone(),
+        cwd,
+        Some(f.paths.clone()),
+        vec![],
+        &mut io::empty(),
+        &mut Cursor::new(Vec::new()),
+        &mut Cursor::new(Vec::new()),
+    );
+    assert_eq!(0, res.unwrap());
+    // Wait for the cache write to finish.
+    for _ in 0..100 {
+        let info = request_stats(connect_to_server(port).unwrap()).unwrap();
+        if info.stats.cache_writes > 0 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    let json: serde_json::Value = serde_json::from_str(&explain(true, None)).unwrap();
+    assert_eq!(json["cached"], true);
+    assert!(json["key"].is_string());
+    assert!(json["inputs"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|input| input["kind"] == "language" && input["value"] == "c"));
+
+    // The outputs of a hit are written into the directory, not their paths.
+    fs::remove_file(&obj).unwrap();
+    let text = explain(false, Some(&extracted));
+    assert!(text.contains("Cached: yes"), "{text}");
+    assert!(
+        text.contains(&format!(
+            "Extracted:\n    {}\n",
+            extracted.join("file.o").display()
+        )),
+        "{text}"
+    );
+    assert_eq!(
+        b"file contents",
+        &*fs::read(extracted.join("file.o")).unwrap()
+    );
+    assert!(!obj.exists());
+    assert_eq!(0, server_creator.lock().unwrap().children.len());
+
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_dry_run() {
+    let f = TestFixture::new();
+    let gcc = f.mk_bin("gcc").unwrap();
+    let (port, sender, server_creator, child) = run_server_thread(f.tempdir.path(), None);
+    std::fs::write(f.tempdir.path().join("file.c"), "whatever").unwrap();
+    {
+        let mut c = server_creator.lock().unwrap();
+        // The server will check the compiler. Pretend it's GCC.
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")));
+        // Preprocessor invocation, the compiler must not be invoked.
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "preprocessed", "")));
+    }
+    let cmdline = vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()];
+    let env_vars = vec![
+        ("SCCACHE_DRYRUN".into(), "1".into()),
+        ("SCCACHE_DRYRUN_TOUCH_OUTPUTS".into(), "1".into()),
+    ];
+    let mut stdout = Cursor::new(Vec::new());
+    let mut stderr = Cursor::new(Vec::new());
+    let mut runtime = Runtime::new().unwrap();
+    let res = do_compile(
+        new_creator(),
+        &mut runtime,
+        connect_to_server(port).unwrap(),
+        &gcc,
+        cmdline,
+        f.tempdir.path(),
+        Some(f.paths),
+        env_vars,
+        &mut io::empty(),
+        &mut stdout,
+        &mut stderr,
+    );
+    assert_eq!(0, res.unwrap());
+    assert_eq!(0, server_creator.lock().unwrap().children.len());
+    let stderr = String::from_utf8(stderr.into_inner()).unwrap();
+    assert!(stderr.contains("cache miss"), "{stderr}");
+    // The output was touched, but nothing was compiled.
+    let obj = f.tempdir.path().join("file.o");
+    assert_eq!(0, fs::metadata(obj).unwrap().len());
+
+    let info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    assert_eq!(1, info.stats.dry_run_misses);
+    assert_eq!(0, info.stats.dry_run_hits);
+    assert_eq!(0, info.stats.cache_misses.all());
+    assert_eq!(0, info.stats.cache_writes);
+
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_no_cache() {
+    let f = TestFixture::new();
+    let gcc = f.mk_bin("gcc").unwrap();
+    let (port, sender, server_creator, child) = run_server_thread(f.tempdir.path(), None);
+    std::fs::write(f.tempdir.path().join("file.c"), "whatever").unwrap();
+    std::fs::write(
+        f.tempdir.path().join("version.c"),
+        "// sccache: no-cache\nconst char *version = \"1\";",
+    )
+    .unwrap();
+    {
+        let mut c = server_creator.lock().unwrap();
+        // The server will check the compiler. Pretend it's GCC.
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")));
+    }
+    // Both compilations run on the client, without touching the cache.
+    let client_creator = new_creator();
+    for _ in 0..2 {
+        next_command(
+            &client_creator,
+            Ok(MockChild::new(exit_status(0), "compiled", "")),
+        );
+    }
+    let mut runtime = Runtime::new().unwrap();
+    for (input, env_vars) in [
+        ("version.c", vec![]),
+        ("file.c", vec![("SCCACHE_NOCACHE".into(), "1".into())]),
+    ] {
+        let cmdline = vec!["-c".into(), input.into(), "-o".into(), "file.o".into()];
+        let res = do_compile(
+            client_creator.clone(),
+            &mut runtime,
+            connect_to_server(port).unwrap(),
+            &gcc,
+            cmdline,
+            f.tempdir.path(),
+            Some(f.paths.clone()),
+            env_vars,
+            &mut io::empty(),
+            &mut Cursor::new(Vec::new()),
+            &mut Cursor::new(Vec::new()),
+        );
+        assert_eq!(0, res.unwrap());
+    }
+    assert_eq!(0, server_creator.lock().unwrap().children.len());
+    assert_eq!(0, client_creator.lock().unwrap().children.len());
+
+    let info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    assert_eq!(2, info.stats.requests_not_cacheable);
+    assert_eq!(Some(&2), info.stats.not_cached.get("explicit"));
+    assert_eq!(0, info.stats.cache_misses.all());
+
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_recache_only() {
+    let f = TestFixture::new();
+    let gcc = f.mk_bin("gcc").unwrap();
+    let (port, sender, server_creator, child) = run_server_thread(f.tempdir.path(), None);
+    std::fs::write(f.tempdir.path().join("file.c"), "whatever").unwrap();
+    {
+        let mut c = server_creator.lock().unwrap();
+        // The server will check the compiler. Pretend it's GCC.
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "compiler_id=gcc", "")));
+        for _ in 0..2 {
+            // Preprocessor invocation.
+            c.next_command_spawns(Ok(MockChild::new(exit_status(0), "preprocessed", "")));
+            // Compiler invocation.
+            let obj = f.tempdir.path().join("file.o");
+            c.next_command_calls(move |_| {
+                File::create(&obj)?.write_all(b"file contents")?;
+                Ok(MockChild::new(exit_status(0), "", ""))
+            });
+        }
+    }
+    let mut runtime = Runtime::new().unwrap();
+    // Only the second compilation matches the filter.
+    for only in ["other.o", "foo.o, file.o"] {
+        let cmdline = vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()];
+        let env_vars = vec![("SCCACHE_RECACHE_ONLY".into(), only.into())];
+        let res = do_compile(
+            new_creator(),
+            &mut runtime,
+            connect_to_server(port).unwrap(),
+            &gcc,
+            cmdline,
+            f.tempdir.path(),
+            Some(f.paths.clone()),
+            env_vars,
+            &mut io::empty(),
+            &mut Cursor::new(Vec::new()),
+            &mut Cursor::new(Vec::new()),
+        );
+        assert_eq!(0, res.unwrap());
+    }
+    assert_eq!(0, server_creator.lock().unwrap().children.len());
+
+    let info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    assert_eq!(2, info.stats.cache_misses.all());
+    assert_eq!(1, info.stats.forced_recaches);
+
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
 #[test]
 #[serial]
 // test fails intermittently on macos:
@@ -0,0 +1,79 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The umask of the clients, which the client passes along with each
+//! compilation, for the outputs restored from the cache to get the mode the
+//! compiler would create them with for the client, rather than the mode they
+//! were stored with, under the umask of another user or machine.
+
+use std::future::Future;
+
+tokio::task_local! {
+    static UMASK: u32;
+}
+
+/// Get the umask of this process, on Unix.
+#[cfg(unix)]
+pub fn current() -> Option<u32> {
+    // The umask can only be read by setting it: the client does so before
+    // starting any thread which could create files meanwhile.
+    let umask = unsafe { libc::umask(0o077) };
+    unsafe { libc::umask(umask) };
+    Some(umask as u32)
+}
+
+#[cfg(not(unix))]
+pub fn current() -> Option<u32> {
+    None
+}
+
+/// Run `task`, restoring the outputs of the compilation it runs with
+/// `umask`, if any. Without it, they keep the mode they were stored with.
+pub async fn scope<F: Future>(umask: Option<u32>, task: F) -> F::Output {
+    match umask {
+        Some(umask) => UMASK.scope(umask, task).await,
+        None => task.await,
+    }
+}
+
+/// Get the umask of the compilation the current task runs, if known.
+pub fn of_task() -> Option<u32> {
+    UMASK.try_with(|umask| *umask).ok()
+}
+
+/// Get the mode of an output restored from an entry which stored it with
+/// `mode`: the one a compiler creates it with under `umask`, executable if it
+/// was.
+pub fn restored_mode(mode: u32, umask: u32) -> u32 {
+    let created = if mode & 0o111 != 0 { 0o777 } else { 0o666 };
+    created & !umask
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_restored_mode() {
+        assert_eq!(restored_mode(0o100644, 0o022), 0o644);
+        assert_eq!(restored_mode(0o100644, 0o077), 0o600);
+        assert_eq!(restored_mode(0o100600, 0o002), 0o664);
+        assert_eq!(restored_mode(0o100755, 0o027), 0o750);
+    }
+
+    #[tokio::test]
+    async fn test_scope() {
+        assert_eq!(of_task(), None);
+        assert_eq!(scope(Some(0o077), async { of_task() }).await, Some(0o077));
+        assert_eq!(scope(None, async { of_task() }).await, None);
+    }
+}
@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use crate::mock_command::{CommandChild, RunCommand};
+use crate::stderr_stream;
 use blake3::Hasher as blake3_Hasher;
 use byteorder::{BigEndian, ByteOrder};
 use fs::File;
@@ -303,6 +304,12 @@ impl TimeMacroFinder {
     }
 }
 
+/// Compare `a` and `b` in a time which doesn't depend on where they differ,
+/// so that the comparison doesn't tell how much of a guessed secret is right.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 pub fn hex(bytes: &[u8]) -> String {
     let mut s = String::with_capacity(bytes.len() * 2);
     for &byte in bytes {
@@ -397,20 +404,187 @@ fn hash_regular_archive(m: &mut Digest, data: &[u8]) -> Result<()> {
     Ok(())
 }
 
+/// Write the file at `path` with `write`, to a temporary file next to it
+/// which is then moved over it, so that the readers of `path`, e.g. a build
+/// tool, only ever see the previous file or the whole new one.
+pub fn write_atomically<T, F>(path: &Path, write: F) -> Result<T>
+where
+    F: FnOnce(&mut std::fs::File) -> Result<T>,
+{
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut builder = tempfile::Builder::new();
+    // With the mode `File::create` gives, rather than only the user's.
+    #[cfg(unix)]
+    builder.permissions(std::os::unix::fs::PermissionsExt::from_mode(0o666));
+    let mut tmp = builder
+        .tempfile_in(dir)
+        .with_context(|| format!("failed to create a file in {}", dir.display()))?;
+    let res = write(tmp.as_file_mut())?;
+    tmp.persist(path)
+        .map_err(|e| e.error)
+        .with_context(|| format!("failed to write {}", path.display()))?;
+    Ok(res)
+}
+
 /// Format `duration` as seconds with a fractional component.
 pub fn fmt_duration_as_secs(duration: &Duration) -> String {
     format!("{}.{:03} s", duration.as_secs(), duration.subsec_millis())
 }
 
+/// The default size of compiler output that is buffered in memory.
+pub const DEFAULT_OUTPUT_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// The default size of compiler output beyond which a compilation is not
+/// cached, and its output is streamed to the client. The output of both
+/// streams must fit in a single response to the client otherwise, whose
+/// default maximum length is 8 MiB.
+pub const DEFAULT_MAX_OUTPUT_SIZE: u64 = 4 * 1024 * 1024;
+
+/// How much of the output of a process is kept, per stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputLimits {
+    /// Output beyond this size is spilled to a temporary file until the
+    /// process exits, instead of growing the in-memory buffer.
+    pub buffer_size: usize,
+    /// Output beyond this size is left in the temporary file, and the
+    /// process fails with `OutputTooLarge`.
+    pub max_size: u64,
+}
+
+impl OutputLimits {
+    /// Keep all the output in memory.
+    pub fn unlimited() -> OutputLimits {
+        OutputLimits {
+            buffer_size: usize::MAX,
+            max_size: u64::MAX,
+        }
+    }
+
+    /// Read the limits from `SCCACHE_OUTPUT_BUFFER_SIZE` and
+    /// `SCCACHE_MAX_OUTPUT_SIZE`.
+    pub fn from_env() -> OutputLimits {
+        fn from_env_var<T: str::FromStr>(name: &str, default: T) -> T {
+            match std::env::var(name) {
+                Ok(s) => s.parse().unwrap_or_else(|_| {
+                    warn!("Content of {} is not a valid number, using default", name);
+                    default
+                }),
+                Err(_) => default,
+            }
+        }
+        OutputLimits {
+            buffer_size: from_env_var("SCCACHE_OUTPUT_BUFFER_SIZE", DEFAULT_OUTPUT_BUFFER_SIZE),
+            max_size: from_env_var("SCCACHE_MAX_OUTPUT_SIZE", DEFAULT_MAX_OUTPUT_SIZE),
+        }
+    }
+}
+
+/// The output of a process, on one of its streams.
+#[derive(Debug)]
+pub enum Captured {
+    /// The output, in memory.
+    Kept(Vec<u8>),
+    /// The output, larger than `OutputLimits::max_size`, left in a temporary
+    /// file to be streamed from its start.
+    Spilled(File),
+}
+
+impl Captured {
+    /// All of the output, reading it back from its file if it was spilled.
+    pub fn into_bytes(self) -> Result<Vec<u8>> {
+        match self {
+            Captured::Kept(bytes) => Ok(bytes),
+            Captured::Spilled(mut file) => {
+                let mut bytes = Vec::new();
+                file.read_to_end(&mut bytes)?;
+                Ok(bytes)
+            }
+        }
+    }
+}
+
+/// Read all of `reader` within `limits`. The output beyond
+/// `limits.buffer_size` is spilled to a temporary file, which is read back
+/// once the output ends, unless there was more than `limits.max_size`: the
+/// file is then returned, to stream the output from it. `on_chunk` is called
+/// with all of the output as it is read, and it is sent to `live` too, if any,
+/// reading on once it is.
+async fn read_output<R, F>(
+    mut reader: R,
+    limits: OutputLimits,
+    mut on_chunk: F,
+    mut live: Option<stderr_stream::Sender>,
+) -> Result<Captured>
+where
+    R: tokio::io::AsyncRead + Unpin,
+    F: FnMut(&[u8]),
+{
+    use tokio::io::AsyncReadExt;
+    let mut buf = Vec::new();
+    let mut spill: Option<File> = None;
+    let mut size = 0u64;
+    let mut chunk = vec![0; 64 * 1024];
+    loop {
+        let n = reader.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        on_chunk(&chunk[..n]);
+        if let Some(sink) = &live {
+            // The output is still read to the end when nobody listens anymore.
+            if sink.send(chunk[..n].to_vec()).await.is_err() {
+                live = None;
+            }
+        }
+        size += n as u64;
+        if let Some(file) = spill.as_mut() {
+            file.write_all(&chunk[..n])?;
+        } else if buf.len() + n > limits.buffer_size || size > limits.max_size {
+            let mut file = File::from_parts(
+                tempfile::tempfile_in(crate::config::scratch_dir())
+                    .context("failed to create a file for the output")?,
+                "<output>",
+            );
+            file.write_all(&buf)?;
+            file.write_all(&chunk[..n])?;
+            buf = Vec::new();
+            spill = Some(file);
+        } else {
+            buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+    let Some(mut file) = spill else {
+        return Ok(Captured::Kept(buf));
+    };
+    file.rewind()?;
+    if size > limits.max_size {
+        return Ok(Captured::Spilled(file));
+    }
+    buf.reserve_exact(size as usize);
+    file.read_to_end(&mut buf)?;
+    Ok(Captured::Kept(buf))
+}
+
 /// If `input`, write it to `child`'s stdin while also reading `child`'s stdout and stderr, then wait on `child` and return its status and output.
 ///
 /// This was lifted from `std::process::Child::wait_with_output` and modified
-/// to also write to stdin.
-async fn wait_with_input_output<T>(mut child: T, input: Option<Vec<u8>>) -> Result<process::Output>
+/// to also write to stdin, to bound the output, to pass the stdout to
+/// `on_stdout` as it is read, and to stream the stderr to `live_stderr`.
+async fn wait_with_input_output<T, F>(
+    mut child: T,
+    input: Option<Vec<u8>>,
+    limits: OutputLimits,
+    on_stdout: F,
+    live_stderr: Option<stderr_stream::Sender>,
+) -> Result<process::Output>
 where
     T: CommandChild + 'static,
+    F: FnMut(&[u8]) + Send,
 {
-    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::io::AsyncWriteExt;
     let stdin = input.and_then(|i| {
         child.take_stdin().map(|mut stdin| async move {
             stdin.write_all(&i).await.context("failed to write stdin")
@@ -419,30 +593,20 @@ where
     let stdout = child.take_stdout();
     let stdout = async move {
         match stdout {
-            Some(mut stdout) => {
-                let mut buf = Vec::new();
-                stdout
-                    .read_to_end(&mut buf)
-                    .await
-                    .context("failed to read stdout")?;
-                Result::Ok(Some(buf))
-            }
-            None => Ok(None),
+            Some(stdout) => read_output(stdout, limits, on_stdout, None)
+                .await
+                .context("failed to read stdout"),
+            None => Ok(Captured::Kept(Vec::new())),
         }
     };
 
     let stderr = child.take_stderr();
     let stderr = async move {
         match stderr {
-            Some(mut stderr) => {
-                let mut buf = Vec::new();
-                stderr
-                    .read_to_end(&mut buf)
-                    .await
-                    .context("failed to read stderr")?;
-                Result::Ok(Some(buf))
-            }
-            None => Ok(None),
+            Some(stderr) => read_output(stderr, limits, |_| {}, live_stderr)
+                .await
+                .context("failed to read stderr"),
+            None => Ok(Captured::Kept(Vec::new())),
         }
     };
 
@@ -457,20 +621,88 @@ where
 
     let (status, stdout, stderr) = futures::future::try_join3(status, stdout, stderr).await?;
 
-    Ok(process::Output {
-        status,
-        stdout: stdout.unwrap_or_default(),
-        stderr: stderr.unwrap_or_default(),
-    })
+    match (stdout, stderr) {
+        (Captured::Kept(stdout), Captured::Kept(stderr)) => Ok(process::Output {
+            status,
+            stdout,
+            stderr,
+        }),
+        (stdout, stderr) => Err(OutputTooLarge {
+            max_size: limits.max_size,
+            status,
+            stdout,
+            stderr,
+        }
+        .into()),
+    }
 }
 
 /// Run `command`, writing `input` to its stdin if it is `Some` and return the exit status and output.
 ///
 /// If the command returns a non-successful exit status, an error of `SccacheError::ProcessError`
 /// will be returned containing the process output.
-pub async fn run_input_output<C>(mut command: C, input: Option<Vec<u8>>) -> Result<process::Output>
+pub async fn run_input_output<C>(command: C, input: Option<Vec<u8>>) -> Result<process::Output>
 where
     C: RunCommand,
+{
+    run_input_output_with_limits(command, input, OutputLimits::unlimited()).await
+}
+
+/// Like `run_input_output`, but also passing the stdout of `command` to
+/// `on_stdout` as it is read, so that it can be processed while `command` is
+/// still running. `on_stdout` has been called with the partial output when
+/// `command` fails.
+pub async fn run_input_output_streaming<C, F>(
+    command: C,
+    input: Option<Vec<u8>>,
+    on_stdout: F,
+) -> Result<process::Output>
+where
+    C: RunCommand,
+    F: FnMut(&[u8]) + Send,
+{
+    run_with(command, input, OutputLimits::unlimited(), on_stdout, None).await
+}
+
+/// Like `run_input_output`, but keeping at most `limits.max_size` of each of
+/// the output streams in memory. An error of `OutputTooLarge`, with the
+/// output left in files, is returned if there was more, whatever the exit
+/// status.
+pub async fn run_input_output_with_limits<C>(
+    command: C,
+    input: Option<Vec<u8>>,
+    limits: OutputLimits,
+) -> Result<process::Output>
+where
+    C: RunCommand,
+{
+    run_with(command, input, limits, |_| {}, None).await
+}
+
+/// Like `run_input_output_with_limits`, but also streaming the stderr of
+/// `command` to `live_stderr`, if any, as it is read, see `stderr_stream`.
+pub async fn run_input_output_live<C>(
+    command: C,
+    input: Option<Vec<u8>>,
+    limits: OutputLimits,
+    live_stderr: Option<stderr_stream::Sender>,
+) -> Result<process::Output>
+where
+    C: RunCommand,
+{
+    run_with(command, input, limits, |_| {}, live_stderr).await
+}
+
+async fn run_with<C, F>(
+    mut command: C,
+    input: Option<Vec<u8>>,
+    limits: OutputLimits,
+    on_stdout: F,
+    live_stderr: Option<stderr_stream::Sender>,
+) -> Result<process::Output>
+where
+    C: RunCommand,
+    F: FnMut(&[u8]) + Send,
 {
     let child = command
         .stdin(if input.is_some() {
@@ -483,7 +715,7 @@ where
         .spawn()
         .await?;
 
-    wait_with_input_output(child, input)
+    wait_with_input_output(child, input, limits, on_stdout, live_stderr)
         .await
         .and_then(|output| {
             if output.status.success() {
@@ -611,6 +843,20 @@ impl OsStrExt for OsStr {
     }
 }
 
+/// Get the status of a process that exited with `code`.
+#[cfg(unix)]
+pub fn exit_status_from_code(code: i32) -> process::ExitStatus {
+    use std::os::unix::process::ExitStatusExt;
+    process::ExitStatus::from_raw((code & 0xff) << 8)
+}
+
+/// Get the status of a process that exited with `code`.
+#[cfg(windows)]
+pub fn exit_status_from_code(code: i32) -> process::ExitStatus {
+    use std::os::windows::process::ExitStatusExt;
+    process::ExitStatus::from_raw(code as u32)
+}
+
 #[cfg(unix)]
 pub fn encode_path(dst: &mut dyn Write, path: &Path) -> std::io::Result<()> {
     use std::os::unix::prelude::*;
@@ -946,7 +1192,10 @@ pub fn new_reqwest_blocking_client() -> reqwest::blocking::Client {
 
 #[cfg(test)]
 mod tests {
-    use super::{OsStrExt, TimeMacroFinder};
+    use super::{
+        constant_time_eq, read_output, write_atomically, Captured, Digest, OsStrExt, OutputLimits,
+        TimeMacroFinder, HASH_BUFFER_SIZE,
+    };
     use std::ffi::{OsStr, OsString};
 
     #[test]
@@ -1054,4 +1303,106 @@ mod tests {
         finder.find_time_macros(b"TIMESTAMP__ This is larger than the haystack");
         assert!(finder.found_timestamp());
     }
+
+    #[test]
+    fn test_read_output() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let output: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        let read = |buffer_size, max_size| {
+            let limits = OutputLimits {
+                buffer_size,
+                max_size,
+            };
+            runtime
+                .block_on(read_output(output.as_slice(), limits, |_| {}, None))
+                .unwrap()
+        };
+        // In memory, spilled to a file after the first chunk, and too large,
+        // whether it was spilled already or not.
+        assert!(matches!(read(usize::MAX, u64::MAX), Captured::Kept(read) if read == output));
+        assert!(matches!(read(1000, 200_000), Captured::Kept(read) if read == output));
+        for buffer_size in [1000, usize::MAX] {
+            let read = read(buffer_size, 199_999);
+            assert!(matches!(read, Captured::Spilled(_)));
+            assert_eq!(read.into_bytes().unwrap(), output);
+        }
+
+        // Streamed a chunk at a time, and read to the end once nobody listens.
+        let (sink, mut chunks) = crate::stderr_stream::channel();
+        let (read, streamed) = runtime.block_on(async {
+            let read = read_output(
+                output.as_slice(),
+                OutputLimits::unlimited(),
+                |_| {},
+                Some(sink),
+            );
+            let streamed = async {
+                let mut streamed = vec![];
+                while let Some(chunk) = chunks.recv().await {
+                    streamed.push(chunk);
+                    if streamed.len() == 2 {
+                        chunks.close();
+                    }
+                }
+                streamed
+            };
+            futures::join!(read, streamed)
+        });
+        assert_eq!(read.unwrap().into_bytes().unwrap(), output);
+        assert_eq!(streamed.concat(), &output[..2 * 64 * 1024]);
+    }
+
+    #[test]
+    fn test_streamed_digest_matches_buffered() {
+        // A few chunks, the last one partial.
+        let data: Vec<u8> = (0..HASH_BUFFER_SIZE * 3 + 17).map(|i| i as u8).collect();
+        let mut buffered = Digest::new();
+        buffered.update(&data);
+        let buffered = buffered.finish();
+        assert_eq!(Digest::reader_sync(data.as_slice()).unwrap(), buffered);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("input");
+        std::fs::write(&path, &data).unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let pool = runtime.handle().clone();
+        assert_eq!(
+            runtime.block_on(Digest::file(&path, &pool)).unwrap(),
+            buffered
+        );
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(constant_time_eq(b"", b""));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secrets"));
+        assert!(!constant_time_eq(b"", b"s"));
+    }
+
+    #[test]
+    fn test_write_atomically() {
+        use std::io::Write;
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("foo.o");
+        std::fs::write(&path, b"previous").unwrap();
+        // A failed write leaves the previous file, and no other.
+        let res: anyhow::Result<()> = write_atomically(&path, |file| {
+            file.write_all(b"part")?;
+            anyhow::bail!("failed");
+        });
+        assert!(res.is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"previous");
+        assert_eq!(std::fs::read_dir(tempdir.path()).unwrap().count(), 1);
+
+        write_atomically(&path, |file| Ok(file.write_all(b"new")?)).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+        assert_eq!(std::fs::read_dir(tempdir.path()).unwrap().count(), 1);
+    }
 }
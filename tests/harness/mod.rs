@@ -1,6 +1,6 @@
 use fs_err as fs;
 #[cfg(any(feature = "dist-client", feature = "dist-server"))]
-use sccache::config::HTTPUrl;
+use sccache::config::{HTTPUrl, INSECURE_DIST_CLIENT_TOKEN};
 use sccache::dist::{self, SchedulerStatusResult, ServerId};
 use sccache::server::ServerInfo;
 use std::env;
@@ -155,6 +155,8 @@ pub fn sccache_client_cfg(
             s3: None,
             webdav: None,
             oss: None,
+            http: None,
+            custom: None,
         },
         dist: sccache::config::DistConfig {
             auth: Default::default(), // dangerously_insecure
@@ -165,6 +167,7 @@ pub fn sccache_client_cfg(
             rewrite_includes_only: false, // TODO
         },
         server_startup_timeout_ms: None,
+        compiler_wrappers: Default::default(),
     }
 }
 
@@ -176,6 +179,7 @@ fn sccache_scheduler_cfg() -> sccache::config::scheduler::Config {
         server_auth: sccache::config::scheduler::ServerAuth::Token {
             token: DIST_SERVER_TOKEN.to_owned(),
         },
+        toolchain_affinity: sccache::config::scheduler::DEFAULT_TOOLCHAIN_AFFINITY,
     }
 }
 
@@ -320,8 +324,9 @@ impl DistSystem {
                     status,
                     SchedulerStatusResult {
                         num_servers: 0,
-                        num_cpus: _,
-                        in_progress: 0
+                        running: 0,
+                        queued: 0,
+                        ..
                     }
                 ) {
                     Ok(())
@@ -463,8 +468,9 @@ impl DistSystem {
                     status,
                     SchedulerStatusResult {
                         num_servers: 1,
-                        num_cpus: _,
-                        in_progress: 0
+                        running: 0,
+                        queued: 0,
+                        ..
                     }
                 ) {
                     Ok(())
@@ -483,10 +489,13 @@ impl DistSystem {
     }
 
     fn scheduler_status(&self) -> SchedulerStatusResult {
-        let res = reqwest::blocking::get(dist::http::urls::scheduler_status(
-            &self.scheduler_url().to_url(),
-        ))
-        .unwrap();
+        let res = reqwest::blocking::Client::new()
+            .get(dist::http::urls::scheduler_status(
+                &self.scheduler_url().to_url(),
+            ))
+            .bearer_auth(INSECURE_DIST_CLIENT_TOKEN)
+            .send()
+            .unwrap();
         assert!(res.status().is_success());
         bincode::deserialize_from(res).unwrap()
     }
@@ -60,6 +60,7 @@ fn config_with_dist_auth(
             rewrite_includes_only: true,
         },
         server_startup_timeout_ms: None,
+        compiler_wrappers: Default::default(),
     }
 }
 
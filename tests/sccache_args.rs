@@ -91,3 +91,483 @@ fn test_s3_invalid_args() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_check_config() -> Result<()> {
+    let tempdir = tempfile::Builder::new()
+        .prefix("sccache_test_check_config")
+        .tempdir()?;
+    let cache_dir = tempdir.path().join("cache");
+
+    let mut cmd = Command::new(SCCACHE_BIN.as_os_str());
+    cmd.arg("--check-config")
+        .env("SCCACHE_DIR", &cache_dir)
+        .env("SCCACHE_CONF", tempdir.path().join("missing"));
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::is_match(r"(?m)^cache\s+ok\s+Local disk").unwrap());
+    // Nothing is written, not even the cache directory.
+    assert!(!cache_dir.exists());
+
+    let config = tempdir.path().join("config");
+    std::fs::write(&config, "[cache.disk\n")?;
+    let mut cmd = Command::new(SCCACHE_BIN.as_os_str());
+    cmd.arg("--check-config").env("SCCACHE_CONF", &config);
+    cmd.assert()
+        .failure()
+        .stdout(predicate::str::is_match(r"(?m)^config\s+FAILED").unwrap());
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "webdav")]
+fn test_error_if_cache_unreachable() -> Result<()> {
+    let tempdir = tempfile::Builder::new()
+        .prefix("sccache_test_error_if_cache_unreachable")
+        .tempdir()?;
+    // Nothing listens on port 1.
+    let config = tempdir.path().join("config");
+    std::fs::write(
+        &config,
+        "[cache.webdav]\nendpoint = \"http://127.0.0.1:1\"\n",
+    )?;
+
+    let mut cmd = Command::new(SCCACHE_BIN.as_os_str());
+    cmd.args(["true", "-c", "foo.c"])
+        .env("SCCACHE_NO_SERVER", "1")
+        .env("SCCACHE_ERROR_IF_CACHE_UNREACHABLE", "1")
+        .env("SCCACHE_REMOTE_RETRIES", "0")
+        .env("SCCACHE_CONF", &config)
+        .current_dir(tempdir.path());
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("the cache can't be reached"));
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "dist-client")]
+fn test_dist_status() -> Result<()> {
+    use sccache::dist::{
+        JobId, JobOutcome, JobStatusResult, SchedulerStatusResult, ServerId, ServerStatusResult,
+    };
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    // A scheduler answering a single status request, there is no sccache
+    // server involved.
+    let server_id = ServerId::new("10.0.0.2:10501".parse()?);
+    let status = SchedulerStatusResult {
+        num_servers: 1,
+        num_cpus: 8,
+        running: 2,
+        queued: 1,
+        servers: vec![ServerStatusResult {
+            server_id,
+            num_cpus: 8,
+            jobs_assigned: 2,
+            toolchain_hits: 3,
+            toolchain_misses: 1,
+        }],
+        recent_jobs: vec![JobStatusResult {
+            job_id: JobId(7),
+            server_id,
+            outcome: JobOutcome::Lost,
+        }],
+    };
+    let body = bincode::serialize(&status)?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let scheduler = std::thread::spawn(move || -> Result<(String, Vec<String>)> {
+        let (stream, _) = listener.accept()?;
+        let mut reader = BufReader::new(stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        let mut headers = vec![];
+        let mut line = String::new();
+        while reader.read_line(&mut line)? > 2 {
+            headers.push(line.trim_end().to_lowercase());
+            line.clear();
+        }
+        let mut stream = reader.into_inner();
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        )?;
+        stream.write_all(&body)?;
+        Ok((request_line, headers))
+    });
+
+    let tempdir = tempfile::Builder::new()
+        .prefix("sccache_test_dist_status")
+        .tempdir()?;
+    let config = tempdir.path().join("config");
+    std::fs::write(
+        &config,
+        format!("[dist]\nscheduler_url = \"http://{}\"\n", addr),
+    )?;
+    let mut cmd = Command::new(SCCACHE_BIN.as_os_str());
+    cmd.arg("--dist-status").env("SCCACHE_CONF", &config);
+    cmd.assert().success().stdout(
+        predicate::str::contains("Jobs: 2 running, 1 queued")
+            .and(
+                predicate::str::is_match(
+                    r"(?m)^  10\.0\.0\.2:10501\s+8 CPUs\s+2 jobs  load 0\.25  toolchain hits 75%$",
+                )
+                .unwrap(),
+            )
+            .and(predicate::str::is_match(r"(?m)^  7\s+10\.0\.0\.2:10501\s+lost$").unwrap()),
+    );
+    let (request_line, headers) = scheduler.join().unwrap()?;
+    assert!(request_line.starts_with("GET /api/v1/scheduler/status "));
+    // The status is asked with the token of the client, the default one here.
+    let authorization = format!(
+        "authorization: bearer {}",
+        sccache::config::INSECURE_DIST_CLIENT_TOKEN
+    );
+    assert!(headers.contains(&authorization), "{:?}", headers);
+
+    // `--json` only goes with the commands that support it.
+    let mut cmd = Command::new(SCCACHE_BIN.as_os_str());
+    cmd.args(["--zero-stats", "--json"]);
+    cmd.assert().failure();
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn test_foreground_server() -> Result<()> {
+    use std::time::{Duration, Instant};
+
+    let tempdir = tempfile::Builder::new()
+        .prefix("sccache_test_foreground")
+        .tempdir()?;
+    let port = std::net::TcpListener::bind("127.0.0.1:0")?
+        .local_addr()?
+        .port()
+        .to_string();
+    let mut server = Command::new(SCCACHE_BIN.as_os_str())
+        .arg("--foreground")
+        .env("SCCACHE_DIR", tempdir.path().join("cache"))
+        .env("SCCACHE_CONF", tempdir.path().join("missing"))
+        .env("SCCACHE_SERVER_PORT", &port)
+        .env_remove("SCCACHE_IDLE_TIMEOUT")
+        .spawn()?;
+
+    // It stays in the foreground, listening on the port.
+    let start = Instant::now();
+    while std::net::TcpStream::connect(format!("127.0.0.1:{}", port)).is_err() {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "server didn't start"
+        );
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    Command::new(SCCACHE_BIN.as_os_str())
+        .arg("--zero-stats")
+        .env("SCCACHE_SERVER_PORT", &port)
+        .assert()
+        .success();
+    assert!(server.try_wait()?.is_none());
+
+    // And shuts down gracefully when the service manager stops it.
+    Command::new("kill")
+        .arg("-TERM")
+        .arg(server.id().to_string())
+        .assert()
+        .success();
+    assert!(server.wait()?.success());
+
+    Ok(())
+}
+
+#[test]
+fn test_server_addr() -> Result<()> {
+    let tempdir = tempfile::Builder::new()
+        .prefix("sccache_test_server_addr")
+        .tempdir()?;
+    let port = std::net::TcpListener::bind("127.0.0.1:0")?
+        .local_addr()?
+        .port();
+    let sccache = |arg| {
+        let mut cmd = Command::new(SCCACHE_BIN.as_os_str());
+        cmd.arg(arg)
+            .env("SCCACHE_DIR", tempdir.path().join("cache"))
+            .env("SCCACHE_CONF", tempdir.path().join("missing"))
+            .env("SCCACHE_SERVER_ADDR", format!("0.0.0.0:{}", port))
+            .env_remove("SCCACHE_SERVER_PORT");
+        cmd
+    };
+
+    // Binding all the interfaces is deliberate, but not quiet.
+    sccache("--start-server")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("other hosts may reach"));
+    // The clients reach it on loopback.
+    sccache("--show-stats").assert().success();
+    assert!(std::net::TcpStream::connect(("127.0.0.1", port)).is_ok());
+    sccache("--stop-server").assert().success();
+
+    Ok(())
+}
+
+#[test]
+fn test_stats_on_exit() -> Result<()> {
+    let tempdir = tempfile::Builder::new()
+        .prefix("sccache_test_stats_on_exit")
+        .tempdir()?;
+    let stats_path = tempdir.path().join("stats.json");
+    let port = std::net::TcpListener::bind("127.0.0.1:0")?
+        .local_addr()?
+        .port()
+        .to_string();
+    let sccache = |arg| {
+        let mut cmd = Command::new(SCCACHE_BIN.as_os_str());
+        cmd.arg(arg)
+            .env("SCCACHE_DIR", tempdir.path().join("cache"))
+            .env("SCCACHE_CONF", tempdir.path().join("missing"))
+            .env("SCCACHE_SERVER_PORT", &port)
+            .env("SCCACHE_STATS_ON_EXIT", &stats_path);
+        cmd
+    };
+
+    sccache("--start-server").assert().success();
+    assert!(!stats_path.exists());
+    sccache("--stop-server").assert().success();
+    // The server writes the stats as it exits, after answering.
+    let start = std::time::Instant::now();
+    while !stats_path.exists() {
+        assert!(
+            start.elapsed() < std::time::Duration::from_secs(10),
+            "no stats written"
+        );
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    let stats: serde_json::Value = serde_json::from_slice(&std::fs::read(&stats_path)?)?;
+    assert_eq!(stats["stats"]["compile_requests"], 0);
+    assert!(stats["cache_location"]
+        .as_str()
+        .unwrap()
+        .starts_with("Local disk"));
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn test_socket_activation() -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixListener;
+    use std::os::unix::process::CommandExt;
+
+    let tempdir = tempfile::Builder::new()
+        .prefix("sccache_test_socket_activation")
+        .tempdir()?;
+    let socket = tempdir.path().join("sccache.sock");
+    let listener = UnixListener::bind(&socket)?;
+    let fd = listener.as_raw_fd();
+
+    // Pass the socket as systemd does, as the file descriptor 3 of a process
+    // told its own pid.
+    let mut server = Command::new("sh");
+    server
+        .arg("-c")
+        .arg(r#"LISTEN_PID=$$ LISTEN_FDS=1 exec "$0" --foreground"#)
+        .arg(SCCACHE_BIN.as_os_str())
+        .env("SCCACHE_DIR", tempdir.path().join("cache"))
+        .env("SCCACHE_CONF", tempdir.path().join("missing"))
+        // Nothing listens on this port.
+        .env("SCCACHE_SERVER_PORT", "1");
+    unsafe {
+        server.pre_exec(move || {
+            // Which is inherited, unlike the listener.
+            let res = if fd == 3 {
+                libc::fcntl(fd, libc::F_SETFD, 0)
+            } else {
+                libc::dup2(fd, 3)
+            };
+            if res == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut server = server.spawn()?;
+    drop(listener);
+
+    // The server accepts the connections of the clients on the socket, which
+    // can be connected to before it even started.
+    Command::new(SCCACHE_BIN.as_os_str())
+        .arg("--zero-stats")
+        .env("SCCACHE_SERVER_UDS", &socket)
+        .assert()
+        .success();
+    Command::new(SCCACHE_BIN.as_os_str())
+        .arg("--stop-server")
+        .env("SCCACHE_SERVER_UDS", &socket)
+        .assert()
+        .success();
+    assert!(server.wait()?.success());
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn test_inherited_listener() -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::process::CommandExt;
+
+    let tempdir = tempfile::Builder::new()
+        .prefix("sccache_test_inherited_listener")
+        .tempdir()?;
+    let server = |fd: i32| {
+        let mut server = Command::new(SCCACHE_BIN.as_os_str());
+        server
+            .arg("--foreground")
+            .env("SCCACHE_LISTEN_FD", fd.to_string())
+            .env("SCCACHE_DIR", tempdir.path().join("cache"))
+            .env("SCCACHE_CONF", tempdir.path().join("missing"))
+            .env_remove("SCCACHE_SERVER_UDS");
+        unsafe {
+            // The socket is inherited, as a supervisor passes it.
+            server.pre_exec(move || {
+                if libc::fcntl(fd, libc::F_SETFD, 0) == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        server
+    };
+
+    // A socket which doesn't listen is refused.
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0")?;
+    server(socket.as_raw_fd())
+        .env("SCCACHE_SERVER_PORT", "1")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("is not a stream socket"));
+    drop(socket);
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let port = listener.local_addr()?.port().to_string();
+    let mut child = server(listener.as_raw_fd())
+        .env("SCCACHE_SERVER_PORT", &port)
+        .spawn()?;
+    // The supervisor may close its copy once the server has it.
+    drop(listener);
+    Command::new(SCCACHE_BIN.as_os_str())
+        .arg("--zero-stats")
+        .env("SCCACHE_SERVER_PORT", &port)
+        .assert()
+        .success();
+    Command::new(SCCACHE_BIN.as_os_str())
+        .arg("--stop-server")
+        .env("SCCACHE_SERVER_PORT", &port)
+        .assert()
+        .success();
+    assert!(child.wait()?.success());
+
+    Ok(())
+}
+
+#[test]
+fn test_no_server() -> Result<()> {
+    let Ok(gcc) = which::which("gcc") else {
+        warn!("No gcc, skipping the test");
+        return Ok(());
+    };
+    let tempdir = tempfile::Builder::new()
+        .prefix("sccache_test_no_server")
+        .tempdir()?;
+    std::fs::write(
+        tempdir.path().join("foo.c"),
+        "int foo(void) { return 42; }\n",
+    )?;
+    // Nothing listens on this port, and no server is started on it.
+    let port = std::net::TcpListener::bind("127.0.0.1:0")?
+        .local_addr()?
+        .port()
+        .to_string();
+    let compile = || {
+        let mut cmd = Command::new(SCCACHE_BIN.as_os_str());
+        cmd.arg("--dump-stats-on-exit")
+            .arg(tempdir.path().join("stats.json"))
+            .arg(&gcc)
+            .args(["-c", "foo.c", "-o", "foo.o"])
+            .current_dir(tempdir.path())
+            .env("SCCACHE_NO_SERVER", "1")
+            .env("SCCACHE_SERVER_PORT", &port)
+            .env("SCCACHE_DIR", tempdir.path().join("cache"))
+            .env("SCCACHE_CONF", tempdir.path().join("missing"));
+        cmd
+    };
+
+    compile().assert().success();
+    let obj = std::fs::read(tempdir.path().join("foo.o"))?;
+    std::fs::remove_file(tempdir.path().join("foo.o"))?;
+    // Restored from the cache the first compilation wrote.
+    compile().assert().success();
+    assert_eq!(std::fs::read(tempdir.path().join("foo.o"))?, obj);
+    let entries = walkdir::WalkDir::new(tempdir.path().join("cache"))
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .count();
+    assert!(entries > 0);
+    assert!(std::net::TcpStream::connect(format!("127.0.0.1:{}", port)).is_err());
+
+    // The stats of both compilations add up.
+    let stats: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(tempdir.path().join("stats.json"))?)?;
+    assert_eq!(stats["stats"]["compile_requests"], 2);
+    assert_eq!(stats["stats"]["cache_hits"]["counts"]["C/C++"], 1);
+    assert_eq!(stats["stats"]["cache_misses"]["counts"]["C/C++"], 1);
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn test_compiler_recursion() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let tempdir = tempfile::Builder::new()
+        .prefix("sccache_test_compiler_recursion")
+        .tempdir()?;
+    // A wrapper running `sccache gcc`, found first in the PATH as gcc.
+    let bin = tempdir.path().join("bin");
+    std::fs::create_dir(&bin)?;
+    let gcc = bin.join("gcc");
+    std::fs::write(
+        &gcc,
+        format!("#!/bin/sh\nexec {} gcc \"$@\"\n", SCCACHE_BIN.display()),
+    )?;
+    std::fs::set_permissions(&gcc, std::fs::Permissions::from_mode(0o755))?;
+    std::fs::write(tempdir.path().join("foo.c"), "int foo;\n")?;
+    let path = std::env::join_paths(
+        std::iter::once(bin).chain(std::env::split_paths(&std::env::var_os("PATH").unwrap())),
+    )?;
+
+    let mut cmd = Command::new(&gcc);
+    cmd.args(["-c", "foo.c", "-o", "foo.o"])
+        .current_dir(tempdir.path())
+        .env("PATH", path)
+        .env("SCCACHE_NO_SERVER", "1")
+        .env("SCCACHE_DIR", tempdir.path().join("cache"))
+        .env("SCCACHE_CONF", tempdir.path().join("missing"));
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains(format!(
+            "sccache invoked itself; check your CC/compiler path: {} resolves to sccache",
+            gcc.display()
+        )));
+
+    Ok(())
+}
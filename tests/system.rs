@@ -490,6 +490,95 @@ fn test_compile_with_define(compiler: Compiler, tempdir: &Path) {
         .stderr(predicates::str::contains("warning:").from_utf8().not());
 }
 
+fn test_gcc_clang_ignore_comments(compiler: Compiler, tempdir: &Path) {
+    let Compiler {
+        name,
+        exe,
+        env_vars,
+    } = compiler;
+    trace!("test SCCACHE_IGNORE_COMMENTS: {}", name);
+    zero_stats();
+    const SRC: &str = "ignore_comments.c";
+    let compile = |source: &str| {
+        write_source(tempdir, SRC, source);
+        sccache_command()
+            .args(compile_cmdline(name, exe.clone(), SRC, OUTPUT, Vec::new()))
+            .current_dir(tempdir)
+            .envs(env_vars.clone())
+            .env("SCCACHE_IGNORE_COMMENTS", "1")
+            .assert()
+            .success();
+    };
+    compile("/* Copyright 2016 */\n#include <stdlib.h>\nint main(void) { return EXIT_SUCCESS; }\n");
+    // A longer license header moves all the lines, and still hits.
+    compile(
+        "/*\n * Copyright 2016\n *\n * Licensed under the Apache License, Version 2.0\n */\n\n\
+         #include <stdlib.h>\n// The entry point.\nint main(void) { return EXIT_SUCCESS; }\n",
+    );
+    get_stats(|info| {
+        assert_eq!(1, info.stats.cache_hits.all());
+        assert_eq!(1, info.stats.cache_misses.all());
+    });
+    // Editing the code misses.
+    compile("/* Copyright 2016 */\n#include <stdlib.h>\nint main(void) { return EXIT_FAILURE; }\n");
+    get_stats(|info| {
+        assert_eq!(1, info.stats.cache_hits.all());
+        assert_eq!(2, info.stats.cache_misses.all());
+    });
+}
+
+fn test_gcc_clang_assembler(compiler: Compiler, tempdir: &Path) {
+    let Compiler {
+        name,
+        exe,
+        env_vars,
+    } = compiler;
+    trace!("test assembler sources: {}", name);
+    zero_stats();
+    const HEADER: &str = "assembler.h";
+    // The sources of the assembler aren't preprocessed, so that they can't
+    // include the header, while the preprocessed ones do.
+    const SRC: &str = "assembler.s";
+    const SRC_CPP: &str = "assembler_cpp.S";
+    write_source(tempdir, SRC, ".globl value\n.data\nvalue:\n.long 1\n");
+    write_source(
+        tempdir,
+        SRC_CPP,
+        "#include \"assembler.h\"\n.globl value\n.data\nvalue:\n.long VALUE\n",
+    );
+    let compile = |source: &str| {
+        sccache_command()
+            .args(compile_cmdline(
+                name,
+                exe.clone(),
+                source,
+                OUTPUT,
+                Vec::new(),
+            ))
+            .current_dir(tempdir)
+            .envs(env_vars.clone())
+            .assert()
+            .success();
+    };
+    write_source(tempdir, HEADER, "#define VALUE 1\n");
+    compile(SRC);
+    compile(SRC_CPP);
+    compile(SRC);
+    compile(SRC_CPP);
+    get_stats(|info| {
+        assert_eq!(2, info.stats.cache_hits.all());
+        assert_eq!(2, info.stats.cache_misses.all());
+    });
+    // Editing the header only misses the source including it.
+    write_source(tempdir, HEADER, "#define VALUE 2\n");
+    compile(SRC);
+    compile(SRC_CPP);
+    get_stats(|info| {
+        assert_eq!(3, info.stats.cache_hits.all());
+        assert_eq!(3, info.stats.cache_misses.all());
+    });
+}
+
 fn run_sccache_command_tests(compiler: Compiler, tempdir: &Path, preprocessor_cache_mode: bool) {
     if compiler.name != "clang++" {
         test_basic_compile(compiler.clone(), tempdir);
@@ -505,6 +594,8 @@ fn run_sccache_command_tests(compiler: Compiler, tempdir: &Path, preprocessor_ca
     }
     if compiler.name == "clang" || compiler.name == "gcc" {
         test_gcc_clang_no_warnings_from_macro_expansion(compiler.clone(), tempdir);
+        test_gcc_clang_ignore_comments(compiler.clone(), tempdir);
+        test_gcc_clang_assembler(compiler.clone(), tempdir);
     }
     if compiler.name == "clang++" {
         test_clang_multicall(compiler.clone(), tempdir);